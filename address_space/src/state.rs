@@ -18,7 +18,8 @@ use std::sync::Arc;
 use anyhow::{Context, Result};
 
 use migration::{
-    error::MigrationError, DeviceStateDesc, FieldDesc, MemBlock, MigrationHook, StateTransfer,
+    error::MigrationError, DeviceStateDesc, FieldDesc, MemBlock, MigrationHook, RamRange,
+    StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
 use util::byte_code::ByteCode;
@@ -82,11 +83,7 @@ impl StateTransfer for AddressSpace {
 
 impl MigrationHook for AddressSpace {
     fn save_memory(&self, fd: &mut dyn Write) -> Result<()> {
-        let ram_state = self.get_state_vec()?;
-        fd.write_all(&ram_state)?;
-        let padding_buffer =
-            [0].repeat(memory_offset() - MIGRATION_HEADER_LENGTH - size_of::<AddressSpaceState>());
-        fd.write_all(&padding_buffer)?;
+        self.save_memory_layout(fd)?;
 
         for region in self.root().subregions().iter() {
             if let Some(base_addr) = region.start_addr() {
@@ -99,6 +96,30 @@ impl MigrationHook for AddressSpace {
         Ok(())
     }
 
+    fn save_memory_layout(&self, fd: &mut dyn Write) -> Result<Vec<RamRange>> {
+        let ram_state = self.get_state_vec()?;
+        fd.write_all(&ram_state)?;
+        let padding_buffer =
+            [0].repeat(memory_offset() - MIGRATION_HEADER_LENGTH - size_of::<AddressSpaceState>());
+        fd.write_all(&padding_buffer)?;
+
+        let mut ranges = Vec::new();
+        let mut offset = memory_offset() as u64;
+        for region in self.root().subregions().iter() {
+            if let Some(start_addr) = region.start_addr() {
+                ranges.push(RamRange {
+                    gpa: start_addr.0,
+                    hva: region.get_host_address().unwrap_or_default(),
+                    len: region.size(),
+                    offset,
+                });
+                offset += region.size();
+            }
+        }
+
+        Ok(ranges)
+    }
+
     fn restore_memory(&self, memory: Option<&File>, state: &[u8]) -> Result<()> {
         let address_space_state: &AddressSpaceState =
             AddressSpaceState::from_bytes(&state[0..size_of::<AddressSpaceState>()])
@@ -136,3 +136,10 @@ pub fn create_block_backend<T: Clone + 'static + Send + Sync>(
         }
     }
 }
+
+/// Release the residual resources of the block backend which is removed at runtime,
+/// such as the medium ejected from CD-ROM.
+pub fn remove_block_backend(id: &str) {
+    QCOW2_LIST.lock().unwrap().remove(id);
+    TempCleaner::remove_exit_notifier(id);
+}
@@ -0,0 +1,750 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! File backend for vCamera device, which plays the frames of a MJPEG/YUY2 file, a directory
+//! of JPEG images, or the generated test pattern in a loop.
+
+use std::collections::HashMap;
+use std::fs::{read, read_dir, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{error, info};
+use once_cell::sync::Lazy;
+
+use machine_manager::config::{CamBackendType, CameraDevConfig};
+use util::aio::{iov_from_buf_direct, Iovec};
+
+use super::INTERVALS_PER_SEC;
+use crate::camera_backend::{
+    get_video_frame_size, CamBasicFmt, CameraBrokenCallback, CameraFormatList, CameraFrame,
+    CameraHostdevOps, CameraNotifyCallback, FmtType,
+};
+
+/// Frame rate of the file source.
+const FILE_SOURCE_FPS: u32 = 30;
+
+// JPEG markers.
+const JPEG_SOI: u8 = 0xd8;
+const JPEG_EOI: u8 = 0xd9;
+const JPEG_SOS: u8 = 0xda;
+const JPEG_DHT: u8 = 0xc4;
+const JPEG_JPG: u8 = 0xc8;
+const JPEG_DAC: u8 = 0xcc;
+
+/// Colors of the bars in test pattern: white, yellow, cyan, green, magenta, red, blue, black.
+const TEST_PATTERN_BARS: [(i32, i32, i32); 8] = [
+    (0xbf, 0xbf, 0xbf),
+    (0xbf, 0xbf, 0x0),
+    (0x0, 0xbf, 0xbf),
+    (0x0, 0xbf, 0x0),
+    (0xbf, 0x0, 0xbf),
+    (0xbf, 0x0, 0x0),
+    (0x0, 0x0, 0xbf),
+    (0x0, 0x0, 0x0),
+];
+
+/// Players of the file camera backends, indexed by cameradev id.
+static FILE_CAMERA_PLAYERS: Lazy<Mutex<HashMap<String, Weak<Mutex<FramePlayer>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Change the source of the file camera backend which uses the cameradev `id`.
+///
+/// # Arguments
+///
+/// * `id` - The cameradev id.
+/// * `config` - Config of the new source. None means detaching the source.
+///
+/// Return false if no file camera backend uses the cameradev.
+pub fn swap_file_camera_source(id: &str, config: Option<&CameraDevConfig>) -> Result<bool> {
+    let player = match FILE_CAMERA_PLAYERS
+        .lock()
+        .unwrap()
+        .get(id)
+        .and_then(|p| p.upgrade())
+    {
+        Some(p) => p,
+        None => return Ok(false),
+    };
+
+    let source = match config {
+        Some(conf) => PlayerSource::new(conf)?,
+        None => PlayerSource::Detached,
+    };
+    let mut locked_player = player.lock().unwrap();
+    if !source.is_compatible(&locked_player.format_list) {
+        bail!(
+            "The new source of cameradev {} does not match the formats of camera",
+            id
+        );
+    }
+    info!("File camera backend {} changes source", id);
+    locked_player.source = source;
+    locked_player.frame_idx = 0;
+    Ok(true)
+}
+
+/// Location of a frame in the source files.
+#[derive(Debug)]
+struct FrameLocation {
+    path: PathBuf,
+    offset: u64,
+    len: usize,
+}
+
+/// Frames read from a MJPEG/YUY2 file or a directory of JPEG images.
+#[derive(Debug)]
+struct FileSource {
+    format: FmtType,
+    width: u32,
+    height: u32,
+    frames: Vec<FrameLocation>,
+}
+
+impl FileSource {
+    fn load(path: &str) -> Result<Self> {
+        let path = Path::new(path);
+        if path.is_dir() {
+            return Self::load_jpeg_dir(path);
+        }
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match ext.as_str() {
+            "mjpg" | "mjpeg" => Self::load_mjpg(path),
+            "yuy2" | "yuv" => Self::load_yuy2(path),
+            _ => bail!("Unsupported camera source file {:?}", path),
+        }
+    }
+
+    /// Load the MJPEG stream which is made up of consecutive JPEG images.
+    fn load_mjpg(path: &Path) -> Result<Self> {
+        let data = read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let mut source = FileSource {
+            format: FmtType::Mjpg,
+            width: 0,
+            height: 0,
+            frames: Vec::new(),
+        };
+        let mut offset = 0;
+        while let Some(start) = find_jpeg_start(&data[offset..]) {
+            offset += start;
+            let (len, width, height) = parse_jpeg(&data[offset..])
+                .with_context(|| format!("Invalid JPEG at offset {} of {:?}", offset, path))?;
+            source.add_frame(path, offset as u64, len, width, height)?;
+            offset += len;
+        }
+        source.check(path)
+    }
+
+    /// Load the raw YUY2 frames, whose resolution is specified by the file name,
+    /// e.g. "video_640x480.yuy2".
+    fn load_yuy2(path: &Path) -> Result<Self> {
+        let (width, height) = parse_resolution(path)?;
+        let frame_size = get_video_frame_size(width, height)? as u64;
+        let file_size = path
+            .metadata()
+            .with_context(|| format!("Failed to get metadata of {:?}", path))?
+            .len();
+        let frames = (0..file_size / frame_size)
+            .map(|i| FrameLocation {
+                path: path.to_path_buf(),
+                offset: i * frame_size,
+                len: frame_size as usize,
+            })
+            .collect();
+        FileSource {
+            format: FmtType::Yuy2,
+            width,
+            height,
+            frames,
+        }
+        .check(path)
+    }
+
+    /// Load the JPEG images in the directory, which are played in the order of file name.
+    fn load_jpeg_dir(path: &Path) -> Result<Self> {
+        let mut files: Vec<PathBuf> = read_dir(path)
+            .with_context(|| format!("Failed to read directory {:?}", path))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| {
+                let ext = p.extension().and_then(|e| e.to_str()).unwrap_or_default();
+                p.is_file() && matches!(ext.to_lowercase().as_str(), "jpg" | "jpeg")
+            })
+            .collect();
+        files.sort();
+
+        let mut source = FileSource {
+            format: FmtType::Mjpg,
+            width: 0,
+            height: 0,
+            frames: Vec::new(),
+        };
+        for file in files {
+            let data = read(&file).with_context(|| format!("Failed to read {:?}", file))?;
+            let (len, width, height) =
+                parse_jpeg(&data).with_context(|| format!("Invalid JPEG image {:?}", file))?;
+            source.add_frame(&file, 0, len, width, height)?;
+        }
+        source.check(path)
+    }
+
+    fn add_frame(
+        &mut self,
+        path: &Path,
+        offset: u64,
+        len: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        if self.frames.is_empty() {
+            self.width = width;
+            self.height = height;
+        } else if self.width != width || self.height != height {
+            bail!(
+                "Resolution {}x{} of {:?} is different from {}x{}",
+                width,
+                height,
+                path,
+                self.width,
+                self.height
+            );
+        }
+        self.frames.push(FrameLocation {
+            path: path.to_path_buf(),
+            offset,
+            len,
+        });
+        Ok(())
+    }
+
+    fn check(self, path: &Path) -> Result<Self> {
+        if self.frames.is_empty() {
+            bail!("No frame is found in {:?}", path);
+        }
+        if self.width == 0 || self.height == 0 || self.width % 2 != 0 {
+            bail!(
+                "Unsupported resolution {}x{} of {:?}",
+                self.width,
+                self.height,
+                path
+            );
+        }
+        Ok(self)
+    }
+
+    fn read_frame(&self, index: usize) -> Result<Vec<u8>> {
+        let location = &self.frames[index % self.frames.len()];
+        let mut file = File::open(&location.path)
+            .with_context(|| format!("Failed to open {:?}", location.path))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut frame = vec![0; location.len];
+        file.read_exact(&mut frame)
+            .with_context(|| format!("Failed to read frame {} of {:?}", index, location.path))?;
+        Ok(frame)
+    }
+}
+
+/// Find the offset of the next JPEG SOI marker.
+fn find_jpeg_start(data: &[u8]) -> Option<usize> {
+    data.windows(2).position(|w| w == [0xff, JPEG_SOI])
+}
+
+/// Parse the JPEG image at the beginning of data, return its length, width and height.
+fn parse_jpeg(data: &[u8]) -> Result<(usize, u32, u32)> {
+    if data.len() < 4 || data[0] != 0xff || data[1] != JPEG_SOI {
+        bail!("No SOI marker");
+    }
+    let mut resolution = None;
+    let mut pos = 2;
+    loop {
+        if pos + 2 > data.len() {
+            bail!("Truncated image");
+        }
+        if data[pos] != 0xff {
+            bail!("Invalid marker at offset {}", pos);
+        }
+        let marker = data[pos + 1];
+        match marker {
+            // Fill byte.
+            0xff => {
+                pos += 1;
+                continue;
+            }
+            JPEG_EOI => {
+                let (width, height) = resolution.with_context(|| "No SOF marker")?;
+                return Ok((pos + 2, width, height));
+            }
+            _ => {}
+        }
+
+        if pos + 4 > data.len() {
+            bail!("Truncated image");
+        }
+        let seg_len = BigEndian::read_u16(&data[pos + 2..pos + 4]) as usize;
+        if seg_len < 2 || pos + 2 + seg_len > data.len() {
+            bail!("Invalid segment length {} at offset {}", seg_len, pos);
+        }
+        // SOFn markers except DHT, JPG and DAC: precision(1), height(2), width(2).
+        if (0xc0..=0xcf).contains(&marker)
+            && !matches!(marker, JPEG_DHT | JPEG_JPG | JPEG_DAC)
+            && seg_len >= 7
+        {
+            let height = BigEndian::read_u16(&data[pos + 5..pos + 7]) as u32;
+            let width = BigEndian::read_u16(&data[pos + 7..pos + 9]) as u32;
+            resolution = Some((width, height));
+        }
+        pos += 2 + seg_len;
+
+        if marker == JPEG_SOS {
+            // Skip the entropy-coded data, in which 0xff is followed by 0x00 or RSTn markers.
+            loop {
+                if pos + 2 > data.len() {
+                    bail!("Truncated image");
+                }
+                if data[pos] == 0xff
+                    && data[pos + 1] != 0
+                    && !(0xd0..=0xd7).contains(&data[pos + 1])
+                {
+                    break;
+                }
+                pos += 1;
+            }
+        }
+    }
+}
+
+/// Parse the resolution from the file name like "video_640x480.yuy2".
+fn parse_resolution(path: &Path) -> Result<(u32, u32)> {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .with_context(|| format!("Invalid file name {:?}", path))?;
+    let resolution = stem.rsplit('_').next().unwrap_or_default();
+    let (width, height) = resolution
+        .split_once('x')
+        .with_context(|| format!("No resolution in file name {:?}", path))?;
+    Ok((
+        width
+            .parse()
+            .with_context(|| format!("Invalid width in file name {:?}", path))?,
+        height
+            .parse()
+            .with_context(|| format!("Invalid height in file name {:?}", path))?,
+    ))
+}
+
+/// Build the YUY2 frame of the test pattern: color bars, and a white block moving
+/// along the bottom gray band frame by frame.
+fn build_test_pattern(width: u32, height: u32, frame_idx: u64) -> Vec<u8> {
+    fn rgb_to_yuv((r, g, b): (i32, i32, i32)) -> (u8, u8, u8) {
+        let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+        let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+        let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+        (y as u8, u as u8, v as u8)
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    let band_top = height * 3 / 4;
+    let block_width = (width / 8).max(2);
+    let block_start = (frame_idx as usize * 8) % width;
+    let mut frame = vec![0; width * height * 2];
+    for (row, line) in frame.chunks_exact_mut(width * 2).enumerate() {
+        for (col, pixels) in line.chunks_exact_mut(4).enumerate() {
+            let x = col * 2;
+            let rgb = if row < band_top {
+                TEST_PATTERN_BARS[x * TEST_PATTERN_BARS.len() / width]
+            } else if (x + width - block_start) % width < block_width {
+                (0xff, 0xff, 0xff)
+            } else {
+                (0x40, 0x40, 0x40)
+            };
+            let (y, u, v) = rgb_to_yuv(rgb);
+            pixels.copy_from_slice(&[y, u, y, v]);
+        }
+    }
+    frame
+}
+
+enum PlayerSource {
+    /// No source, e.g. the cameradev is deleted. No frame is produced.
+    Detached,
+    /// Generated test pattern.
+    TestPattern,
+    /// Frames read from files.
+    File(FileSource),
+}
+
+impl PlayerSource {
+    fn new(config: &CameraDevConfig) -> Result<Self> {
+        match config.backend {
+            CamBackendType::TestPattern => Ok(PlayerSource::TestPattern),
+            CamBackendType::File => {
+                let path = config
+                    .path
+                    .as_ref()
+                    .with_context(|| "No path configured for file camera")?;
+                Ok(PlayerSource::File(FileSource::load(path)?))
+            }
+            _ => bail!("File camera backend only supports file or testpattern source"),
+        }
+    }
+
+    fn format_list(&self) -> Vec<CameraFormatList> {
+        let interval = INTERVALS_PER_SEC / FILE_SOURCE_FPS;
+        match self {
+            PlayerSource::File(src) => vec![CameraFormatList {
+                format: src.format,
+                fmt_index: 1,
+                frame: vec![CameraFrame {
+                    width: src.width,
+                    height: src.height,
+                    index: 1,
+                    interval,
+                }],
+            }],
+            _ => vec![CameraFormatList {
+                format: FmtType::Yuy2,
+                fmt_index: 1,
+                frame: [(1280, 720), (640, 480), (320, 240)]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &(width, height))| CameraFrame {
+                        width,
+                        height,
+                        index: i as u8 + 1,
+                        interval,
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    /// Whether the source can produce the frames of all the formats.
+    fn is_compatible(&self, format_list: &[CameraFormatList]) -> bool {
+        format_list.iter().all(|fmt| match self {
+            PlayerSource::Detached => true,
+            PlayerSource::TestPattern => fmt.format == FmtType::Yuy2,
+            PlayerSource::File(src) => {
+                fmt.format == src.format
+                    && fmt
+                        .frame
+                        .iter()
+                        .all(|frm| frm.width == src.width && frm.height == src.height)
+            }
+        })
+    }
+}
+
+struct FramePlayer {
+    source: PlayerSource,
+    /// Format list provided to the frontend, which can't be changed after the device is realized.
+    format_list: Vec<CameraFormatList>,
+    frame_idx: u64,
+}
+
+impl FramePlayer {
+    /// Build the next frame, return None if no frame is available.
+    fn next_frame(&mut self, fmt: &CamBasicFmt) -> Result<Option<Vec<u8>>> {
+        let frame = match &self.source {
+            PlayerSource::Detached => None,
+            PlayerSource::TestPattern => {
+                Some(build_test_pattern(fmt.width, fmt.height, self.frame_idx))
+            }
+            PlayerSource::File(src) => Some(src.read_frame(self.frame_idx as usize)?),
+        };
+        self.skip_frame();
+        Ok(frame)
+    }
+
+    fn skip_frame(&mut self) {
+        self.frame_idx = self.frame_idx.wrapping_add(1);
+    }
+}
+
+#[derive(Default)]
+struct FrameImage {
+    image: Vec<u8>,
+    used_len: u64,
+}
+
+/// File camera backend, which plays frames at the negotiated frame interval.
+pub struct FileCamera {
+    /// Cameradev id.
+    id: String,
+    /// Source of the frames, which can be swapped at runtime.
+    player: Arc<Mutex<FramePlayer>>,
+    /// Frame image data.
+    frame_image: Arc<Mutex<FrameImage>>,
+    /// Callback to used to notify when data is coming.
+    notify_cb: Option<CameraNotifyCallback>,
+    /// Callback to used to notify the broken.
+    broken_cb: Option<CameraBrokenCallback>,
+    /// Current format.
+    cur_format: CamBasicFmt,
+    /// Whether the worker thread is running.
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl FileCamera {
+    pub fn new(config: &CameraDevConfig) -> Result<Self> {
+        let id = config.id.clone().unwrap();
+        let source = PlayerSource::new(config)?;
+        let player = Arc::new(Mutex::new(FramePlayer {
+            format_list: source.format_list(),
+            source,
+            frame_idx: 0,
+        }));
+        FILE_CAMERA_PLAYERS
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Arc::downgrade(&player));
+
+        Ok(FileCamera {
+            id,
+            player,
+            frame_image: Arc::new(Mutex::new(FrameImage::default())),
+            notify_cb: None,
+            broken_cb: None,
+            cur_format: CamBasicFmt::default(),
+            running: Arc::new(AtomicBool::new(false)),
+            worker: None,
+        })
+    }
+
+    fn start_worker(&mut self) -> Result<()> {
+        let player = self.player.clone();
+        let frame_image = self.frame_image.clone();
+        let notify_cb = self.notify_cb.clone();
+        let broken_cb = self.broken_cb.clone();
+        let running = self.running.clone();
+        let fmt = self.cur_format;
+        let interval = Duration::from_nanos(
+            fmt.get_frame_intervals()? as u64 * (1_000_000_000 / INTERVALS_PER_SEC as u64),
+        );
+
+        let worker = std::thread::Builder::new()
+            .name(format!("file camera {}", self.id))
+            .spawn(move || {
+                let mut deadline = Instant::now();
+                while running.load(Ordering::Acquire) {
+                    let mut locked_frame = frame_image.lock().unwrap();
+                    let mut locked_player = player.lock().unwrap();
+                    if locked_frame.used_len != 0 {
+                        // Drop the frame when the last one has not been read completely,
+                        // to keep playing in real time.
+                        locked_player.skip_frame();
+                    } else {
+                        match locked_player.next_frame(&fmt) {
+                            Ok(Some(image)) => {
+                                locked_frame.used_len = image.len() as u64;
+                                locked_frame.image = image;
+                                if let Some(notify) = notify_cb.as_ref() {
+                                    notify();
+                                }
+                            }
+                            Ok(None) => (),
+                            Err(e) => {
+                                error!("Failed to read frame {:?}", e);
+                                if let Some(broken) = broken_cb.as_ref() {
+                                    broken();
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    drop(locked_player);
+                    drop(locked_frame);
+
+                    deadline += interval;
+                    let now = Instant::now();
+                    if deadline > now {
+                        std::thread::sleep(deadline - now);
+                    } else {
+                        deadline = now;
+                    }
+                }
+            })?;
+        self.worker = Some(worker);
+        Ok(())
+    }
+
+    fn stop_worker(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("File camera backend {} worker panicked", self.id);
+            }
+        }
+    }
+}
+
+impl Drop for FileCamera {
+    fn drop(&mut self) {
+        self.stop_worker();
+    }
+}
+
+impl CameraHostdevOps for FileCamera {
+    fn set_fmt(&mut self, cam_fmt: &CamBasicFmt) -> Result<()> {
+        self.cur_format = *cam_fmt;
+        info!("File camera backend set format {:?}", cam_fmt);
+        Ok(())
+    }
+
+    fn set_ctl(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn video_stream_on(&mut self) -> Result<()> {
+        if self.running.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        info!("File camera backend {} stream on", self.id);
+        self.running.store(true, Ordering::Release);
+        self.start_worker().map_err(|e| {
+            self.running.store(false, Ordering::Release);
+            e
+        })
+    }
+
+    fn video_stream_off(&mut self) -> Result<()> {
+        info!("File camera backend {} stream off", self.id);
+        self.stop_worker();
+        Ok(())
+    }
+
+    fn list_format(&mut self) -> Result<Vec<CameraFormatList>> {
+        Ok(self.player.lock().unwrap().format_list.clone())
+    }
+
+    fn reset(&mut self) {
+        info!("File camera backend {} reset", self.id);
+        self.stop_worker();
+        self.frame_image.lock().unwrap().used_len = 0;
+    }
+
+    fn get_frame_size(&self) -> usize {
+        self.frame_image.lock().unwrap().used_len as usize
+    }
+
+    fn next_frame(&mut self) -> Result<()> {
+        self.frame_image.lock().unwrap().used_len = 0;
+        Ok(())
+    }
+
+    fn get_frame(&self, iovecs: &[Iovec], frame_offset: usize, len: usize) -> Result<usize> {
+        let locked_frame = self.frame_image.lock().unwrap();
+        if frame_offset + len > locked_frame.used_len as usize {
+            bail!("Invalid frame offset {} or len {}", frame_offset, len);
+        }
+        iov_from_buf_direct(
+            iovecs,
+            &locked_frame.image[frame_offset..frame_offset + len],
+        )
+    }
+
+    fn get_format_by_index(&self, format_index: u8, frame_index: u8) -> Result<CamBasicFmt> {
+        let locked_player = self.player.lock().unwrap();
+        let fmt = locked_player
+            .format_list
+            .iter()
+            .find(|fmt| fmt.fmt_index == format_index)
+            .with_context(|| format!("format with idx {} is not found", format_index))?;
+        let frm = fmt
+            .frame
+            .iter()
+            .find(|frm| frm.index == frame_index)
+            .with_context(|| format!("frame with idx {} is not found", frame_index))?;
+        Ok(CamBasicFmt {
+            width: frm.width,
+            height: frm.height,
+            fps: INTERVALS_PER_SEC / frm.interval,
+            fmttype: fmt.format,
+        })
+    }
+
+    fn register_notify_cb(&mut self, cb: CameraNotifyCallback) {
+        self.notify_cb = Some(cb);
+    }
+
+    fn register_broken_cb(&mut self, cb: CameraBrokenCallback) {
+        self.broken_cb = Some(cb);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // SOI, SOF0(8 bits, 16x8, 1 component), SOS(1 component), data with stuffed 0xff, EOI.
+    const TEST_JPEG: [u8; 35] = [
+        0xff, 0xd8, 0xff, 0xc0, 0x00, 0x0b, 0x08, 0x00, 0x08, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00,
+        0xff, 0xda, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3f, 0x00, 0x12, 0xff, 0x00, 0x34, 0xff,
+        0xd0, 0x56, 0x78, 0xff, 0xd9,
+    ];
+
+    #[test]
+    fn test_parse_jpeg() {
+        assert_eq!(parse_jpeg(&TEST_JPEG).unwrap(), (TEST_JPEG.len(), 16, 8));
+        assert!(parse_jpeg(&TEST_JPEG[..TEST_JPEG.len() - 1]).is_err());
+        assert!(parse_jpeg(&TEST_JPEG[2..]).is_err());
+
+        assert_eq!(
+            parse_resolution(Path::new("/tmp/video_640x480.yuy2")).unwrap(),
+            (640, 480)
+        );
+        assert!(parse_resolution(Path::new("/tmp/video.yuy2")).is_err());
+    }
+
+    #[test]
+    fn test_file_source() {
+        let path = std::env::temp_dir().join(format!("file_camera_{}.mjpg", std::process::id()));
+        let stream = [&TEST_JPEG[..], &[0, 0], &TEST_JPEG[..]].concat();
+        std::fs::write(&path, stream).unwrap();
+        let source = FileSource::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(source.format, FmtType::Mjpg);
+        assert_eq!((source.width, source.height), (16, 8));
+        assert_eq!(source.frames.len(), 2);
+        assert_eq!(source.frames[1].offset, TEST_JPEG.len() as u64 + 2);
+        assert_eq!(source.frames[1].len, TEST_JPEG.len());
+
+        let source = PlayerSource::File(source);
+        let format_list = source.format_list();
+        assert!(source.is_compatible(&format_list));
+        assert!(!PlayerSource::TestPattern.is_compatible(&format_list));
+        assert!(PlayerSource::Detached.is_compatible(&format_list));
+    }
+
+    #[test]
+    fn test_test_pattern() {
+        let frame = build_test_pattern(16, 4, 0);
+        assert_eq!(frame.len(), 16 * 4 * 2);
+        // The first bar is white, and the last bar is black.
+        assert_eq!(frame[0..4], [180, 128, 180, 128]);
+        assert_eq!(frame[28..32], [16, 128, 16, 128]);
+        // The moving block starts from the left of the bottom band.
+        assert_eq!(frame[96..100], [235, 128, 235, 128]);
+        assert_eq!(frame[100..104], [71, 128, 71, 128]);
+    }
+}
@@ -11,9 +11,10 @@
 // See the Mulan PSL v2 for more details.
 
 //! The abstract layer that connects different frontend & backend camera devices.
-//! Backend devices, such as v4l2, usb, or demo device, etc., shall implement trait CameraHostdevOps.
+//! Backend devices, such as v4l2, usb, file, or demo device, etc., shall implement trait CameraHostdevOps.
 
 pub mod demo;
+pub mod file;
 pub mod v4l2;
 
 use anyhow::{bail, Context, Result};
@@ -22,7 +23,7 @@ use std::sync::{Arc, Mutex};
 use machine_manager::config::{CamBackendType, ConfigError, UsbCameraConfig};
 use util::aio::Iovec;
 
-use self::{demo::DemoCamera, v4l2::V4l2CameraBackend};
+use self::{demo::DemoCamera, file::FileCamera, v4l2::V4l2CameraBackend};
 
 /// Frame interval in 100ns units.
 pub const INTERVALS_PER_SEC: u32 = 10_000_000;
@@ -191,6 +192,9 @@ pub fn camera_ops(config: UsbCameraConfig) -> Result<Arc<Mutex<dyn CameraHostdev
                 ConfigError::FieldIsMissing("path".to_string(), "Demo".to_string())
             })?,
         )?)),
+        CamBackendType::File | CamBackendType::TestPattern => {
+            Arc::new(Mutex::new(FileCamera::new(&config.drive)?))
+        }
     };
 
     Ok(cam)
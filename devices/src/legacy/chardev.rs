@@ -10,22 +10,32 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::fs::{read_link, File, OpenOptions};
-use std::io::{Stdin, Stdout};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::io::{Read, Stdin, Stdout, Write};
+use std::mem::size_of;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use libc::{cfmakeraw, tcgetattr, tcsetattr, termios};
-use log::{error, info};
+use log::{error, info, warn};
 use machine_manager::machine::{PathInfo, PTY_PATH};
 use machine_manager::{
     config::{ChardevConfig, ChardevType},
+    event_loop::EventLoop,
     temp_cleaner::TempCleaner,
 };
+use once_cell::sync::Lazy;
+#[cfg(not(target_env = "musl"))]
+use ui::clipboard::register_clipboard_agent;
 use util::file::clear_file;
 use util::loop_context::{
     gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
@@ -34,6 +44,10 @@ use util::set_termi_raw_mode;
 use util::unix::limit_permission;
 use vmm_sys_util::epoll::EventSet;
 
+#[cfg(not(target_env = "musl"))]
+use super::clipboard::{ClipboardAgent, ClipboardChannel};
+use super::mux::{get_mux_chardev, MuxChardev, MuxFrontend, MuxOutput};
+
 /// Provide the trait that helps handle the input data.
 pub trait InputReceiver: Send {
     fn input_handle(&mut self, buffer: &[u8]);
@@ -51,7 +65,28 @@ pub enum ChardevStatus {
     Open,
 }
 
-type ReceFn = Option<Arc<dyn Fn(&[u8]) + Send + Sync>>;
+// Telnet commands and options, see RFC 854, RFC 856, RFC 857 and RFC 858.
+const TELNET_SE: u8 = 240;
+const TELNET_SB: u8 = 250;
+const TELNET_WILL: u8 = 251;
+const TELNET_DO: u8 = 253;
+const TELNET_DONT: u8 = 254;
+const TELNET_IAC: u8 = 255;
+const TELNET_OPT_BINARY: u8 = 0;
+const TELNET_OPT_ECHO: u8 = 1;
+const TELNET_OPT_SGA: u8 = 3;
+
+/// Timeout of connecting to tcp server.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) type InputCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+pub(crate) type RemainSizeCallback = Arc<dyn Fn() -> usize + Send + Sync>;
+type ReceFn = Option<InputCallback>;
+type OutputFn = Option<Arc<Mutex<dyn CommunicatOutInterface>>>;
+
+/// Ring buffers of ringbuf chardevs, which can be read by qmp.
+static RINGBUF_CHARDEVS: Lazy<Mutex<HashMap<String, Arc<Mutex<RingbufChannel>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 /// Character device structure.
 pub struct Chardev {
@@ -61,10 +96,12 @@ pub struct Chardev {
     pub backend: ChardevType,
     /// UnixListener for socket-type chardev.
     pub listener: Option<UnixListener>,
+    /// TcpListener for tcp server chardev.
+    pub tcp_listener: Option<TcpListener>,
     /// Chardev input.
     pub input: Option<Arc<Mutex<dyn CommunicatInInterface>>>,
     /// Chardev output.
-    pub output: Option<Arc<Mutex<dyn CommunicatOutInterface>>>,
+    pub output: OutputFn,
     /// Fd of socket stream.
     pub stream_fd: Option<i32>,
     /// Device is deactivated or not.
@@ -72,9 +109,17 @@ pub struct Chardev {
     /// Handle the input data and trigger interrupt if necessary.
     receive: ReceFn,
     /// Return the remain space size of receiver buffer.
-    get_remain_space_size: Option<Arc<dyn Fn() -> usize + Send + Sync>>,
+    get_remain_space_size: Option<RemainSizeCallback>,
     /// Used to notify device the socket is opened or closed.
     dev: Option<Arc<Mutex<dyn ChardevNotifyDevice>>>,
+    /// Backend is shared with other frontends.
+    mux: bool,
+    /// Multiplexed chardev which this frontend is attached to.
+    mux_chardev: Option<Arc<Mutex<MuxChardev>>>,
+    /// Path of file which the output is also written to.
+    logfile: Option<String>,
+    /// Output with log file, the real output is inside it.
+    log: Option<Arc<Mutex<ChardevLog>>>,
 }
 
 impl Chardev {
@@ -83,6 +128,7 @@ impl Chardev {
             id: chardev_cfg.id,
             backend: chardev_cfg.backend,
             listener: None,
+            tcp_listener: None,
             input: None,
             output: None,
             stream_fd: None,
@@ -90,10 +136,28 @@ impl Chardev {
             receive: None,
             get_remain_space_size: None,
             dev: None,
+            mux: chardev_cfg.mux,
+            mux_chardev: None,
+            logfile: chardev_cfg.logfile,
+            log: None,
         }
     }
 
     pub fn realize(&mut self) -> Result<()> {
+        if self.mux {
+            // Backend is realized only once, and shared by all frontends.
+            let mux_chardev = get_mux_chardev(ChardevConfig {
+                id: self.id.clone(),
+                backend: self.backend.clone(),
+                mux: false,
+                logfile: self.logfile.clone(),
+            })?;
+            let backend = mux_chardev.lock().unwrap().backend();
+            self.output = Some(Arc::new(Mutex::new(MuxOutput::new(backend))));
+            self.mux_chardev = Some(mux_chardev);
+            return Ok(());
+        }
+
         match &self.backend {
             ChardevType::Stdio => {
                 set_termi_raw_mode().with_context(|| "Failed to set terminal to raw mode")?;
@@ -138,6 +202,40 @@ impl Chardev {
                     )
                 })?;
             }
+            ChardevType::TcpSocket {
+                host,
+                port,
+                server,
+                nowait,
+                telnet,
+                reconnect,
+            } => {
+                if *server {
+                    if !*nowait {
+                        bail!(
+                            "Argument \'nowait\' is required for tcp server chardev \'{}\'",
+                            self.id
+                        );
+                    }
+                    let listener =
+                        TcpListener::bind((host.as_str(), *port)).with_context(|| {
+                            format!("Failed to bind tcp socket for chardev, {}:{}", host, port)
+                        })?;
+                    self.tcp_listener = Some(listener);
+                } else {
+                    match tcp_connect(host, *port) {
+                        Ok(stream) => {
+                            let telnet = *telnet;
+                            self.set_tcp_stream(stream, telnet, false)?;
+                        }
+                        // Connect later if reconnect is enabled.
+                        Err(e) if *reconnect > 0 => {
+                            warn!("Chardev {} failed to connect: {:?}", self.id, e);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
             ChardevType::File(path) => {
                 let file = Arc::new(Mutex::new(
                     OpenOptions::new()
@@ -148,10 +246,49 @@ impl Chardev {
                 ));
                 self.output = Some(file);
             }
+            #[cfg(not(target_env = "musl"))]
+            ChardevType::Clipboard => {
+                self.output = Some(Arc::new(Mutex::new(ClipboardChannel::default())));
+            }
+            #[cfg(target_env = "musl")]
+            ChardevType::Clipboard => {
+                bail!("Clipboard chardev is not supported");
+            }
+            ChardevType::Ringbuf(size) => {
+                let ringbuf = Arc::new(Mutex::new(RingbufChannel::new(*size as usize)));
+                RINGBUF_CHARDEVS
+                    .lock()
+                    .unwrap()
+                    .insert(self.id.clone(), ringbuf.clone());
+                self.output = Some(ringbuf);
+            }
         };
+
+        if let Some(logfile) = &self.logfile {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(logfile)
+                .with_context(|| format!("Failed to open log file {} for chardev", logfile))?;
+            let log = Arc::new(Mutex::new(ChardevLog {
+                file,
+                output: self.output.take(),
+            }));
+            self.output = Some(log.clone());
+            self.log = Some(log);
+        }
         Ok(())
     }
 
+    /// Set the output of chardev, which is written together with log file if any.
+    fn set_output(&mut self, output: OutputFn) {
+        match &self.log {
+            Some(log) => log.lock().unwrap().output = output,
+            None => self.output = output,
+        }
+    }
+
     pub fn set_input_callback<T: 'static + InputReceiver>(&mut self, dev: &Arc<Mutex<T>>) {
         let cloned_dev = dev.clone();
         self.receive = Some(Arc::new(move |data: &[u8]| {
@@ -166,6 +303,36 @@ impl Chardev {
     pub fn set_device(&mut self, dev: Arc<Mutex<dyn ChardevNotifyDevice>>) {
         self.dev = Some(dev.clone());
     }
+
+    /// Use the connected tcp stream as input and output, return its fd.
+    fn set_tcp_stream(&mut self, stream: TcpStream, telnet: bool, server: bool) -> Result<RawFd> {
+        let mut stream = TcpChardevStream::new(stream, telnet);
+        // Telnet server negotiates character mode with client.
+        if telnet && server {
+            stream
+                .telnet_init()
+                .with_context(|| "Failed to negotiate telnet options")?;
+        }
+        let stream_fd = stream.as_raw_fd();
+        let stream_arc = Arc::new(Mutex::new(stream));
+        self.input = Some(stream_arc.clone());
+        self.set_output(Some(stream_arc));
+        self.stream_fd = Some(stream_fd);
+        if let Some(dev) = &self.dev {
+            dev.lock().unwrap().chardev_notify(ChardevStatus::Open);
+        }
+        Ok(stream_fd)
+    }
+
+    /// Get the callbacks used to send data to the device.
+    pub(crate) fn get_input_callback(&self) -> Option<(InputCallback, RemainSizeCallback)> {
+        match (&self.receive, &self.get_remain_space_size) {
+            (Some(receive), Some(get_remain_space_size)) => {
+                Some((receive.clone(), get_remain_space_size.clone()))
+            }
+            _ => None,
+        }
+    }
 }
 
 fn set_pty_raw_mode() -> Result<(i32, PathBuf)> {
@@ -217,6 +384,283 @@ fn set_pty_raw_mode() -> Result<(i32, PathBuf)> {
     Ok((master, path))
 }
 
+fn tcp_connect(host: &str, port: u16) -> Result<TcpStream> {
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {}:{}", host, port))?;
+    for addr in addrs {
+        if let Ok(stream) = TcpStream::connect_timeout(&addr, TCP_CONNECT_TIMEOUT) {
+            return Ok(stream);
+        }
+    }
+    bail!("Failed to connect to {}:{}", host, port);
+}
+
+/// Start connecting to `addr` without blocking, the connection is finished
+/// when the socket is writable.
+fn tcp_connect_start(addr: &SocketAddr) -> Result<TcpStream> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: It only creates a new socket.
+    let fd = unsafe {
+        libc::socket(
+            domain,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        bail!(
+            "Failed to create socket, error is {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // SAFETY: The fd is a new socket owned by nobody else.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: The sockaddr is valid with its size.
+            unsafe {
+                libc::connect(
+                    fd,
+                    &sockaddr as *const _ as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: The sockaddr is valid with its size.
+            unsafe {
+                libc::connect(
+                    fd,
+                    &sockaddr as *const _ as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            bail!("Failed to connect to {}, error is {}", addr, err);
+        }
+    }
+    Ok(stream)
+}
+
+/// Try to connect to tcp server again, until it succeeds. The connection is
+/// not blocking, so that the event loop isn't stalled while server is down.
+fn tcp_chardev_reconnect(chardev: Arc<Mutex<Chardev>>) {
+    let locked_chardev = chardev.lock().unwrap();
+    let (host, port, reconnect) = match &locked_chardev.backend {
+        ChardevType::TcpSocket {
+            host,
+            port,
+            reconnect,
+            ..
+        } => (host.clone(), *port, *reconnect),
+        _ => return,
+    };
+    drop(locked_chardev);
+
+    let addrs = match (host.as_str(), port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => {
+            tcp_chardev_reconnect_later(chardev, reconnect);
+            return;
+        }
+    };
+    if let Some(notifier) = tcp_chardev_connect_next(chardev, addrs) {
+        if let Err(e) = EventLoop::update_event(vec![notifier], None) {
+            error!("Failed to add event for tcp chardev, {:?}", e);
+        }
+    }
+}
+
+/// Start connecting to the next address, and get the notifier waiting for the
+/// connection. Connect later if no address is left.
+fn tcp_chardev_connect_next(
+    chardev: Arc<Mutex<Chardev>>,
+    mut addrs: VecDeque<SocketAddr>,
+) -> Option<EventNotifier> {
+    while let Some(addr) = addrs.pop_front() {
+        if let Ok(stream) = tcp_connect_start(&addr) {
+            return Some(tcp_connecting_notifier(chardev, stream, addrs));
+        }
+    }
+
+    let reconnect = match chardev.lock().unwrap().backend {
+        ChardevType::TcpSocket { reconnect, .. } => reconnect,
+        _ => return None,
+    };
+    tcp_chardev_reconnect_later(chardev, reconnect);
+    None
+}
+
+fn tcp_connecting_notifier(
+    chardev: Arc<Mutex<Chardev>>,
+    stream: TcpStream,
+    addrs: VecDeque<SocketAddr>,
+) -> EventNotifier {
+    let stream_fd = stream.as_raw_fd();
+    let connecting = RefCell::new(Some((stream, addrs)));
+    let handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+        let (stream, addrs) = connecting.borrow_mut().take()?;
+        let mut notifiers = gen_delete_notifiers(&[stream_fd]);
+        let connected = matches!(stream.take_error(), Ok(None))
+            && stream.peer_addr().is_ok()
+            && stream.set_nonblocking(false).is_ok();
+        if !connected {
+            drop(stream);
+            notifiers.extend(tcp_chardev_connect_next(chardev.clone(), addrs));
+            return Some(notifiers);
+        }
+
+        let mut locked_chardev = chardev.lock().unwrap();
+        let (host, port, telnet, reconnect) = match &locked_chardev.backend {
+            ChardevType::TcpSocket {
+                host,
+                port,
+                telnet,
+                reconnect,
+                ..
+            } => (host.clone(), *port, *telnet, *reconnect),
+            _ => return Some(notifiers),
+        };
+        match locked_chardev.set_tcp_stream(stream, telnet, false) {
+            Ok(fd) => {
+                info!(
+                    "Chardev {} reconnected to {}:{}",
+                    locked_chardev.id, host, port
+                );
+                drop(locked_chardev);
+                notifiers.push(tcp_stream_notifier(chardev.clone(), fd, None));
+            }
+            Err(e) => {
+                error!("Chardev {} failed to reconnect: {:?}", locked_chardev.id, e);
+                drop(locked_chardev);
+                tcp_chardev_reconnect_later(chardev.clone(), reconnect);
+            }
+        }
+        Some(notifiers)
+    });
+    EventNotifier::new(
+        NotifierOperation::AddShared,
+        stream_fd,
+        None,
+        EventSet::OUT | EventSet::HANG_UP,
+        vec![handler],
+    )
+}
+
+fn tcp_chardev_reconnect_later(chardev: Arc<Mutex<Chardev>>, reconnect: u64) {
+    let func = Box::new(move || tcp_chardev_reconnect(chardev.clone()));
+    EventLoop::get_ctx(None)
+        .unwrap()
+        .timer_add(func, Duration::from_secs(reconnect));
+}
+
+fn tcp_chardev_read(chardev: &Arc<Mutex<Chardev>>) -> Result<()> {
+    let locked_chardev = chardev.lock().unwrap();
+    if locked_chardev.deactivated {
+        return Ok(());
+    }
+    let (receive, get_remain_space_size) = match locked_chardev.get_input_callback() {
+        Some(callbacks) => callbacks,
+        None => return Ok(()),
+    };
+    let input = locked_chardev.input.clone();
+    drop(locked_chardev);
+
+    let buff_size = get_remain_space_size();
+    if buff_size == 0 {
+        return Ok(());
+    }
+    let mut buffer = vec![0_u8; buff_size];
+    if let Some(input) = input {
+        let len = input.lock().unwrap().chr_read_raw(&mut buffer)?;
+        if len > 0 {
+            receive(&buffer[..len]);
+        }
+    }
+    Ok(())
+}
+
+fn tcp_chardev_disconnect(
+    chardev: &Arc<Mutex<Chardev>>,
+    stream_fd: RawFd,
+) -> Option<Vec<EventNotifier>> {
+    let mut locked_chardev = chardev.lock().unwrap();
+    // The stream is replaced already, don't touch the current connection.
+    if locked_chardev.stream_fd != Some(stream_fd) {
+        return Some(gen_delete_notifiers(&[stream_fd]));
+    }
+    info!("Chardev {} is disconnected", locked_chardev.id);
+    if let Some(dev) = &locked_chardev.dev {
+        dev.lock().unwrap().chardev_notify(ChardevStatus::Close);
+    }
+    locked_chardev.input = None;
+    locked_chardev.set_output(None);
+    locked_chardev.stream_fd = None;
+    if let ChardevType::TcpSocket {
+        server: false,
+        reconnect,
+        ..
+    } = locked_chardev.backend
+    {
+        if reconnect > 0 {
+            tcp_chardev_reconnect_later(chardev.clone(), reconnect);
+        }
+    }
+    Some(gen_delete_notifiers(&[stream_fd]))
+}
+
+fn tcp_stream_notifier(
+    chardev: Arc<Mutex<Chardev>>,
+    stream_fd: RawFd,
+    listener_fd: Option<RawFd>,
+) -> EventNotifier {
+    let handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
+        if event & EventSet::IN == EventSet::IN {
+            if let Err(e) = tcp_chardev_read(&chardev) {
+                info!("Failed to read tcp chardev: {:?}", e);
+                return tcp_chardev_disconnect(&chardev, stream_fd);
+            }
+        }
+        if event & EventSet::HANG_UP == EventSet::HANG_UP {
+            return tcp_chardev_disconnect(&chardev, stream_fd);
+        }
+        None
+    });
+    EventNotifier::new(
+        NotifierOperation::AddShared,
+        stream_fd,
+        listener_fd,
+        EventSet::IN | EventSet::HANG_UP,
+        vec![handler],
+    )
+}
+
 fn get_notifier_handler(
     chardev: Arc<Mutex<Chardev>>,
     backend: ChardevType,
@@ -261,7 +705,7 @@ fn get_notifier_handler(
             locked_chardev.stream_fd = Some(stream_fd);
             let stream_arc = Arc::new(Mutex::new(stream));
             locked_chardev.input = Some(stream_arc.clone());
-            locked_chardev.output = Some(stream_arc);
+            locked_chardev.set_output(Some(stream_arc));
 
             if let Some(dev) = &locked_chardev.dev {
                 dev.lock().unwrap().chardev_notify(ChardevStatus::Open);
@@ -283,9 +727,13 @@ fn get_notifier_handler(
                         return None;
                     }
                     let mut buffer = vec![0_u8; buff_size];
-                    if let Some(input) = locked_chardev.input.clone() {
+                    let input_h = locked_chardev.input.clone();
+                    let receive = locked_chardev.receive.clone();
+                    // Receiver may write to this chardev, such as multiplexed chardev.
+                    drop(locked_chardev);
+                    if let Some(input) = input_h {
                         if let Ok(index) = input.lock().unwrap().chr_read_raw(&mut buffer) {
-                            locked_chardev.receive.as_ref().unwrap()(&mut buffer[..index]);
+                            receive.as_ref().unwrap()(&mut buffer[..index]);
                         } else {
                             error!("Failed to read input data");
                         }
@@ -299,7 +747,7 @@ fn get_notifier_handler(
                         dev.lock().unwrap().chardev_notify(ChardevStatus::Close);
                     }
                     locked_chardev.input = None;
-                    locked_chardev.output = None;
+                    locked_chardev.set_output(None);
                     locked_chardev.stream_fd = None;
                     Some(gen_delete_notifiers(&[stream_fd]))
                 } else {
@@ -314,13 +762,56 @@ fn get_notifier_handler(
                 vec![inner_handler],
             )])
         }),
-        ChardevType::File(_) => Rc::new(move |_, _| None),
+        ChardevType::TcpSocket { telnet, .. } => Rc::new(move |_, _| {
+            let mut locked_chardev = chardev.lock().unwrap();
+            if locked_chardev.deactivated {
+                return None;
+            }
+            let listener = locked_chardev.tcp_listener.as_ref().unwrap();
+            let listener_fd = listener.as_raw_fd();
+            let (stream, addr) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to accept tcp connection: {:?}", e);
+                    return None;
+                }
+            };
+            // Only one client is served at a time, the extra one is closed by dropping it.
+            if locked_chardev.stream_fd.is_some() {
+                warn!(
+                    "Chardev {} is busy, connection from {} is refused",
+                    locked_chardev.id, addr
+                );
+                return None;
+            }
+            info!("Chardev {} is connected by {}", locked_chardev.id, addr);
+            let stream_fd = match locked_chardev.set_tcp_stream(stream, telnet, true) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    error!("Failed to set tcp stream for chardev: {:?}", e);
+                    return None;
+                }
+            };
+            drop(locked_chardev);
+            Some(vec![tcp_stream_notifier(
+                chardev.clone(),
+                stream_fd,
+                Some(listener_fd),
+            )])
+        }),
+        ChardevType::File(_) | ChardevType::Clipboard | ChardevType::Ringbuf(_) => {
+            Rc::new(move |_, _| None)
+        }
     }
 }
 
 impl EventNotifierHelper for Chardev {
     fn internal_notifiers(chardev: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut notifiers = Vec::new();
+        let mux_chardev = chardev.lock().unwrap().mux_chardev.clone();
+        if let Some(mux_chardev) = mux_chardev {
+            return MuxChardev::add_frontend(&mux_chardev, MuxFrontend::Device(chardev));
+        }
         let backend = chardev.lock().unwrap().backend.clone();
         let cloned_chardev = chardev.clone();
         match backend {
@@ -354,7 +845,33 @@ impl EventNotifierHelper for Chardev {
                     ));
                 }
             }
-            ChardevType::File(_) => (),
+            ChardevType::TcpSocket { reconnect, .. } => {
+                let locked_chardev = chardev.lock().unwrap();
+                if let Some(listener) = locked_chardev.tcp_listener.as_ref() {
+                    notifiers.push(EventNotifier::new(
+                        NotifierOperation::AddShared,
+                        listener.as_raw_fd(),
+                        None,
+                        EventSet::IN,
+                        vec![get_notifier_handler(cloned_chardev, backend)],
+                    ));
+                } else if let Some(stream_fd) = locked_chardev.stream_fd {
+                    notifiers.push(tcp_stream_notifier(cloned_chardev, stream_fd, None));
+                } else if reconnect > 0 {
+                    drop(locked_chardev);
+                    tcp_chardev_reconnect_later(cloned_chardev, reconnect);
+                }
+            }
+            ChardevType::File(_) | ChardevType::Ringbuf(_) => (),
+            ChardevType::Clipboard => {
+                // Clipboard chardev has no fd to listen, just register the agent.
+                #[cfg(not(target_env = "musl"))]
+                {
+                    let id = chardev.lock().unwrap().id.clone();
+                    let agent = ClipboardAgent::new(Arc::downgrade(&chardev));
+                    register_clipboard_agent(&id, Arc::new(Mutex::new(agent)));
+                }
+            }
         }
         notifiers
     }
@@ -383,3 +900,302 @@ impl CommunicatInInterface for Stdin {}
 impl CommunicatOutInterface for UnixStream {}
 impl CommunicatOutInterface for File {}
 impl CommunicatOutInterface for Stdout {}
+
+/// Output which is also written to log file.
+struct ChardevLog {
+    file: File,
+    output: OutputFn,
+}
+
+impl Write for ChardevLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Err(e) = self.file.write_all(buf) {
+            error!("Failed to write chardev log: {:?}", e);
+        }
+        // Output is dropped if it is not connected.
+        if let Some(output) = &self.output {
+            output.lock().unwrap().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(output) = &self.output {
+            output.lock().unwrap().flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl CommunicatOutInterface for ChardevLog {}
+
+/// Output of ringbuf chardev, the oldest data is dropped when it is full.
+struct RingbufChannel {
+    buf: VecDeque<u8>,
+    size: usize,
+}
+
+impl RingbufChannel {
+    fn new(size: usize) -> Self {
+        RingbufChannel {
+            buf: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+}
+
+impl Write for RingbufChannel {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let data = &buf[buf.len().saturating_sub(self.size)..];
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.size);
+        self.buf.drain(..overflow);
+        self.buf.extend(data);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CommunicatOutInterface for RingbufChannel {}
+
+/// Read and remove at most `size` bytes of the oldest data from ringbuf chardev.
+///
+/// # Arguments
+///
+/// * `id` - Id of ringbuf chardev.
+/// * `size` - Max size to read.
+/// * `format` - "utf8" or "base64". Invalid utf8 sequences are replaced in "utf8" format.
+pub fn ringbuf_read(id: &str, size: usize, format: &str) -> Result<String> {
+    if format != "utf8" && format != "base64" {
+        bail!("Invalid format {} for reading ringbuf", format);
+    }
+    let ringbuf = RINGBUF_CHARDEVS
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .with_context(|| format!("Ringbuf chardev {} not found", id))?;
+    let mut locked_ringbuf = ringbuf.lock().unwrap();
+    let len = std::cmp::min(size, locked_ringbuf.buf.len());
+    let data: Vec<u8> = locked_ringbuf.buf.drain(..len).collect();
+    match format {
+        "base64" => Ok(STANDARD.encode(data)),
+        _ => Ok(String::from_utf8_lossy(&data).into_owned()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum TelnetState {
+    #[default]
+    Data,
+    /// Received IAC.
+    Iac,
+    /// Received IAC and option command, the option is to be skipped.
+    Option,
+    /// In subnegotiation.
+    Sub,
+    /// Received IAC in subnegotiation.
+    SubIac,
+}
+
+/// Stream of tcp chardev. If telnet is enabled, telnet commands are removed
+/// from the input and 0xff in the output is escaped.
+struct TcpChardevStream {
+    stream: TcpStream,
+    telnet: Option<TelnetState>,
+}
+
+impl TcpChardevStream {
+    fn new(stream: TcpStream, telnet: bool) -> Self {
+        TcpChardevStream {
+            stream,
+            telnet: telnet.then(TelnetState::default),
+        }
+    }
+
+    /// Ask telnet client to work in character mode and not to echo locally.
+    fn telnet_init(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(&[
+            TELNET_IAC,
+            TELNET_WILL,
+            TELNET_OPT_ECHO,
+            TELNET_IAC,
+            TELNET_WILL,
+            TELNET_OPT_SGA,
+            TELNET_IAC,
+            TELNET_WILL,
+            TELNET_OPT_BINARY,
+            TELNET_IAC,
+            TELNET_DO,
+            TELNET_OPT_BINARY,
+        ])
+    }
+}
+
+/// Remove telnet commands from `buf` in place, and return the length of remaining data.
+fn telnet_filter(state: &mut TelnetState, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    for i in 0..buf.len() {
+        let c = buf[i];
+        *state = match (*state, c) {
+            (TelnetState::Data, TELNET_IAC) => TelnetState::Iac,
+            // Escaped 0xff is data.
+            (TelnetState::Data, _) | (TelnetState::Iac, TELNET_IAC) => {
+                buf[len] = c;
+                len += 1;
+                TelnetState::Data
+            }
+            (TelnetState::Iac, TELNET_WILL..=TELNET_DONT) => TelnetState::Option,
+            (TelnetState::Iac, TELNET_SB) => TelnetState::Sub,
+            (TelnetState::Iac, _) | (TelnetState::Option, _) => TelnetState::Data,
+            (TelnetState::Sub, TELNET_IAC) => TelnetState::SubIac,
+            (TelnetState::SubIac, TELNET_SE) => TelnetState::Data,
+            (TelnetState::Sub, _) | (TelnetState::SubIac, _) => TelnetState::Sub,
+        };
+    }
+    len
+}
+
+impl AsRawFd for TcpChardevStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl CommunicatInInterface for TcpChardevStream {
+    fn chr_read_raw(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.stream.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            bail!("Connection is closed by peer");
+        }
+        match self.telnet.as_mut() {
+            Some(state) => Ok(telnet_filter(state, &mut buf[..len])),
+            None => Ok(len),
+        }
+    }
+}
+
+impl Write for TcpChardevStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.telnet.is_none() {
+            return self.stream.write(buf);
+        }
+        let mut escaped = Vec::with_capacity(buf.len());
+        for c in buf {
+            escaped.push(*c);
+            if *c == TELNET_IAC {
+                escaped.push(TELNET_IAC);
+            }
+        }
+        self.stream.write_all(&escaped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl CommunicatOutInterface for TcpChardevStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_telnet_filter() {
+        let mut state = TelnetState::default();
+        // Option negotiation, escaped 0xff and two-byte command.
+        let mut buf = vec![
+            b'a',
+            TELNET_IAC,
+            TELNET_DO,
+            TELNET_OPT_ECHO,
+            b'b',
+            TELNET_IAC,
+            TELNET_IAC,
+            TELNET_IAC,
+            241,
+            b'c',
+        ];
+        let len = telnet_filter(&mut state, &mut buf);
+        assert_eq!(&buf[..len], &[b'a', b'b', TELNET_IAC, b'c']);
+        assert_eq!(state, TelnetState::Data);
+
+        // Subnegotiation split into chunks.
+        let mut buf = vec![b'd', TELNET_IAC, TELNET_SB, 24, 0];
+        let len = telnet_filter(&mut state, &mut buf);
+        assert_eq!(&buf[..len], b"d");
+        assert_eq!(state, TelnetState::Sub);
+        let mut buf = vec![b'x', TELNET_IAC, TELNET_SE, b'e'];
+        let len = telnet_filter(&mut state, &mut buf);
+        assert_eq!(&buf[..len], b"e");
+        assert_eq!(state, TelnetState::Data);
+    }
+
+    #[test]
+    fn test_tcp_chardev_second_client() {
+        let backend = ChardevType::TcpSocket {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            server: true,
+            nowait: true,
+            telnet: false,
+            reconnect: 0,
+        };
+        let mut chardev = Chardev::new(ChardevConfig {
+            id: "tcp0".to_string(),
+            backend: backend.clone(),
+            mux: false,
+            logfile: None,
+        });
+        chardev.realize().unwrap();
+        let addr = chardev.tcp_listener.as_ref().unwrap().local_addr().unwrap();
+        let chardev = Arc::new(Mutex::new(chardev));
+        let accept = get_notifier_handler(chardev.clone(), backend);
+
+        let _client1 = TcpStream::connect(addr).unwrap();
+        assert!(accept(EventSet::IN, 0).is_some());
+        let stream_fd = chardev.lock().unwrap().stream_fd;
+        assert!(stream_fd.is_some());
+
+        // The second client is closed, and the first one is still connected.
+        let mut client2 = TcpStream::connect(addr).unwrap();
+        assert!(accept(EventSet::IN, 0).is_none());
+        let mut buf = [0_u8; 1];
+        assert_eq!(client2.read(&mut buf).unwrap(), 0);
+        assert_eq!(chardev.lock().unwrap().stream_fd, stream_fd);
+
+        // Notifier of a stale stream doesn't clear the current one.
+        assert!(tcp_chardev_disconnect(&chardev, stream_fd.unwrap() + 1).is_some());
+        assert_eq!(chardev.lock().unwrap().stream_fd, stream_fd);
+        assert!(chardev.lock().unwrap().input.is_some());
+    }
+
+    #[test]
+    fn test_ringbuf_chardev() {
+        let mut chardev = Chardev::new(ChardevConfig {
+            id: "ringbuf0".to_string(),
+            backend: ChardevType::Ringbuf(8),
+            mux: false,
+            logfile: None,
+        });
+        chardev.realize().unwrap();
+        let output = chardev.output.clone().unwrap();
+        output.lock().unwrap().write_all(b"hello").unwrap();
+        assert_eq!(ringbuf_read("ringbuf0", 2, "utf8").unwrap(), "he");
+        // The oldest data is dropped when it is full.
+        output.lock().unwrap().write_all(b" world").unwrap();
+        assert_eq!(ringbuf_read("ringbuf0", 100, "utf8").unwrap(), "lo world");
+        assert!(ringbuf_read("ringbuf0", 100, "utf8").unwrap().is_empty());
+        output.lock().unwrap().write_all(b"0123456789").unwrap();
+        assert_eq!(
+            ringbuf_read("ringbuf0", 100, "base64").unwrap(),
+            "MjM0NTY3ODk="
+        );
+        assert!(ringbuf_read("ringbuf0", 100, "hex").is_err());
+        assert!(ringbuf_read("ringbuf1", 100, "utf8").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, warn};
+use machine_manager::event_loop::EventLoop;
+use ui::clipboard::{clipboard_update_from_guest, ClipboardAgentOpts, CLIPBOARD_MAX_SIZE};
+
+use super::chardev::{Chardev, CommunicatOutInterface};
+
+/// Clipboard message is made of header and payload. The header contains
+/// type and size of payload, both are u32 in little endian.
+const CLIPBOARD_MSG_HEADER_SIZE: usize = 8;
+/// UTF-8 text of clipboard, in both directions.
+const CLIPBOARD_MSG_TEXT: u32 = 1;
+/// Interval to retry sending message if guest agent has no space to receive it.
+const CLIPBOARD_SEND_RETRY_MS: u64 = 50;
+
+fn clipboard_msg(msg_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CLIPBOARD_MSG_HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&msg_type.to_le_bytes());
+    msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    msg.extend_from_slice(payload);
+    msg
+}
+
+/// Receive clipboard messages from guest agent.
+#[derive(Default)]
+pub struct ClipboardChannel {
+    /// Data of messages which are not received completely.
+    recv_buf: Vec<u8>,
+    /// Bytes of the message being discarded.
+    discard: usize,
+}
+
+impl ClipboardChannel {
+    fn handle_messages(&mut self) {
+        loop {
+            if self.discard > 0 {
+                let len = std::cmp::min(self.discard, self.recv_buf.len());
+                self.recv_buf.drain(..len);
+                self.discard -= len;
+                if self.discard > 0 {
+                    return;
+                }
+            }
+            if self.recv_buf.len() < CLIPBOARD_MSG_HEADER_SIZE {
+                return;
+            }
+
+            let mut bytes = [0_u8; 4];
+            bytes.copy_from_slice(&self.recv_buf[0..4]);
+            let msg_type = u32::from_le_bytes(bytes);
+            bytes.copy_from_slice(&self.recv_buf[4..8]);
+            let size = u32::from_le_bytes(bytes) as usize;
+            if size > CLIPBOARD_MAX_SIZE {
+                warn!("Clipboard message is too large: {}", size);
+                self.recv_buf.drain(..CLIPBOARD_MSG_HEADER_SIZE);
+                self.discard = size;
+                continue;
+            }
+            if self.recv_buf.len() < CLIPBOARD_MSG_HEADER_SIZE + size {
+                return;
+            }
+
+            let msg: Vec<u8> = self
+                .recv_buf
+                .drain(..CLIPBOARD_MSG_HEADER_SIZE + size)
+                .skip(CLIPBOARD_MSG_HEADER_SIZE)
+                .collect();
+            match msg_type {
+                CLIPBOARD_MSG_TEXT => {
+                    let text = String::from_utf8_lossy(&msg);
+                    clipboard_update_from_guest(&text)
+                        .unwrap_or_else(|e| error!("Failed to update clipboard: {:?}", e));
+                }
+                _ => warn!("Unknown clipboard message type {}", msg_type),
+            }
+        }
+    }
+}
+
+impl Write for ClipboardChannel {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.recv_buf.extend_from_slice(buf);
+        self.handle_messages();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CommunicatOutInterface for ClipboardChannel {}
+
+/// Messages waiting for guest agent to receive.
+#[derive(Default)]
+struct ClipboardSendQueue {
+    /// Remaining bytes of the message being sent. It must be sent completely,
+    /// otherwise the framing of guest agent is broken.
+    sending: Vec<u8>,
+    /// The latest message following the one being sent, older ones are replaced.
+    next: Option<Vec<u8>>,
+    /// Whether the timer to retry sending is added.
+    retrying: bool,
+}
+
+/// Send the queued messages as many as guest agent can receive, and retry
+/// later if they are not sent completely.
+fn clipboard_flush(chardev: &Weak<Mutex<Chardev>>, queue: &Arc<Mutex<ClipboardSendQueue>>) {
+    let mut locked_queue = queue.lock().unwrap();
+    let callbacks = chardev.upgrade().and_then(|c| {
+        let locked_chardev = c.lock().unwrap();
+        if locked_chardev.deactivated {
+            return None;
+        }
+        locked_chardev.get_input_callback()
+    });
+    let (receive, get_remain_space_size) = match callbacks {
+        Some(cb) => cb,
+        None => {
+            // Guest agent is gone, the new one starts with a new message.
+            locked_queue.sending.clear();
+            locked_queue.next = None;
+            return;
+        }
+    };
+
+    loop {
+        if locked_queue.sending.is_empty() {
+            match locked_queue.next.take() {
+                Some(msg) => locked_queue.sending = msg,
+                None => return,
+            }
+        }
+        let len = std::cmp::min(get_remain_space_size(), locked_queue.sending.len());
+        if len == 0 {
+            break;
+        }
+        receive(&locked_queue.sending[..len]);
+        locked_queue.sending.drain(..len);
+    }
+
+    if !locked_queue.retrying {
+        locked_queue.retrying = true;
+        let chardev = chardev.clone();
+        let queue = queue.clone();
+        let func = Box::new(move || {
+            queue.lock().unwrap().retrying = false;
+            clipboard_flush(&chardev, &queue);
+        });
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            ctx.timer_add(func, Duration::from_millis(CLIPBOARD_SEND_RETRY_MS));
+        }
+    }
+}
+
+/// Send clipboard of host to guest agent by the chardev.
+pub struct ClipboardAgent {
+    chardev: Weak<Mutex<Chardev>>,
+    queue: Arc<Mutex<ClipboardSendQueue>>,
+}
+
+impl ClipboardAgent {
+    pub fn new(chardev: Weak<Mutex<Chardev>>) -> Self {
+        ClipboardAgent {
+            chardev,
+            queue: Arc::new(Mutex::new(ClipboardSendQueue::default())),
+        }
+    }
+}
+
+impl ClipboardAgentOpts for ClipboardAgent {
+    fn set_guest_clipboard(&mut self, text: &str) -> Result<()> {
+        self.queue.lock().unwrap().next = Some(clipboard_msg(CLIPBOARD_MSG_TEXT, text.as_bytes()));
+        clipboard_flush(&self.chardev, &self.queue);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy::chardev::InputReceiver;
+    use machine_manager::config::{ChardevConfig, ChardevType};
+    use ui::clipboard::{
+        get_clipboard_text, register_clipboard_frontend, unregister_clipboard_frontend,
+        ClipboardFrontendOpts,
+    };
+
+    #[derive(Default)]
+    struct TestFrontend {
+        texts: Vec<String>,
+    }
+
+    impl ClipboardFrontendOpts for TestFrontend {
+        fn set_host_clipboard(&mut self, text: &str) -> Result<()> {
+            self.texts.push(text.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_clipboard_channel() {
+        let frontend = Arc::new(Mutex::new(TestFrontend::default()));
+        register_clipboard_frontend("TestChannelFrontend", frontend.clone());
+        let mut channel = ClipboardChannel::default();
+
+        // Message is split into several writes.
+        let msg = clipboard_msg(CLIPBOARD_MSG_TEXT, "guest 文本".as_bytes());
+        channel.write_all(&msg[..5]).unwrap();
+        channel.write_all(&msg[5..10]).unwrap();
+        assert!(frontend.lock().unwrap().texts.is_empty());
+        channel.write_all(&msg[10..]).unwrap();
+        assert_eq!(frontend.lock().unwrap().texts, vec!["guest 文本"]);
+        assert_eq!(get_clipboard_text(), "guest 文本");
+
+        // Too large message is discarded, and the following one is handled.
+        let mut msg = CLIPBOARD_MSG_TEXT.to_le_bytes().to_vec();
+        msg.extend_from_slice(&(CLIPBOARD_MAX_SIZE as u32 + 1).to_le_bytes());
+        msg.extend_from_slice(&vec![b'a'; CLIPBOARD_MAX_SIZE + 1]);
+        msg.append(&mut clipboard_msg(0xff, b"unknown"));
+        msg.append(&mut clipboard_msg(CLIPBOARD_MSG_TEXT, b"next"));
+        channel.write_all(&msg).unwrap();
+        assert_eq!(frontend.lock().unwrap().texts, vec!["guest 文本", "next"]);
+
+        unregister_clipboard_frontend("TestChannelFrontend");
+    }
+
+    #[derive(Default)]
+    struct TestReceiver {
+        space: usize,
+        data: Vec<u8>,
+    }
+
+    impl InputReceiver for TestReceiver {
+        fn input_handle(&mut self, buffer: &[u8]) {
+            self.data.extend_from_slice(buffer);
+            self.space -= buffer.len();
+        }
+
+        fn get_remain_space_size(&mut self) -> usize {
+            self.space
+        }
+    }
+
+    #[test]
+    fn test_clipboard_agent_queue() {
+        EventLoop::object_init(&None).unwrap();
+        let chardev = Arc::new(Mutex::new(Chardev::new(ChardevConfig {
+            id: "clipboard0".to_string(),
+            backend: ChardevType::Clipboard,
+            mux: false,
+            logfile: None,
+        })));
+        let receiver = Arc::new(Mutex::new(TestReceiver {
+            space: 5,
+            data: Vec::new(),
+        }));
+        chardev.lock().unwrap().set_input_callback(&receiver);
+        let mut agent = ClipboardAgent::new(Arc::downgrade(&chardev));
+
+        // Message is sent partly, the rest is kept for the framing.
+        agent.set_guest_clipboard("first").unwrap();
+        let first = clipboard_msg(CLIPBOARD_MSG_TEXT, b"first");
+        assert_eq!(receiver.lock().unwrap().data, first[..5]);
+
+        // Only the latest message is kept after the one being sent.
+        agent.set_guest_clipboard("second").unwrap();
+        agent.set_guest_clipboard("third").unwrap();
+        receiver.lock().unwrap().space = 100;
+        clipboard_flush(&agent.chardev, &agent.queue);
+        let mut expected = first;
+        expected.append(&mut clipboard_msg(CLIPBOARD_MSG_TEXT, b"third"));
+        assert_eq!(receiver.lock().unwrap().data, expected);
+    }
+}
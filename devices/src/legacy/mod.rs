@@ -26,8 +26,11 @@
 //! - `aarch64`
 
 mod chardev;
+#[cfg(not(target_env = "musl"))]
+mod clipboard;
 pub mod error;
 mod fwcfg;
+mod mux;
 mod pflash;
 #[cfg(target_arch = "aarch64")]
 mod pl011;
@@ -41,13 +44,14 @@ mod serial;
 #[cfg(target_arch = "x86_64")]
 pub use self::rtc::{RTC, RTC_PORT_INDEX};
 pub use anyhow::Result;
-pub use chardev::{Chardev, ChardevNotifyDevice, ChardevStatus, InputReceiver};
+pub use chardev::{ringbuf_read, Chardev, ChardevNotifyDevice, ChardevStatus, InputReceiver};
 pub use error::LegacyError;
 #[cfg(target_arch = "x86_64")]
 pub use fwcfg::FwCfgIO;
 #[cfg(target_arch = "aarch64")]
 pub use fwcfg::FwCfgMem;
 pub use fwcfg::{FwCfgEntryType, FwCfgOps};
+pub use mux::mux_attach_monitor;
 pub use pflash::PFlash;
 #[cfg(target_arch = "aarch64")]
 pub use pl011::PL011;
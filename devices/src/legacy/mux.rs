@@ -0,0 +1,382 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{error, warn};
+use machine_manager::{
+    config::ChardevConfig,
+    event,
+    event_loop::EventLoop,
+    qmp::{qmp_schema, QmpChannel},
+    temp_cleaner::TempCleaner,
+};
+use once_cell::sync::Lazy;
+use util::loop_context::{
+    gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::set_termi_canon_mode;
+use vmm_sys_util::epoll::EventSet;
+
+use super::chardev::{Chardev, CommunicatOutInterface, InputReceiver};
+
+/// Escape key of multiplexed chardev, Ctrl-a.
+const MUX_ESCAPE_KEY: u8 = 0x01;
+/// Size of input buffer of monitor.
+const MUX_MONITOR_BUF_SIZE: usize = 4096;
+/// Size of input buffer of the focused device, input beyond it is dropped.
+const MUX_DEVICE_BUF_SIZE: usize = 4096;
+/// Interval to retry sending buffered input to the focused device.
+const MUX_DEVICE_RETRY_MS: u64 = 50;
+const MUX_HELP: &[u8] = b"\r\n\
+C-a h    print this help\r\n\
+C-a x    exit StratoVirt\r\n\
+C-a c    switch between console and monitor\r\n\
+C-a C-a  send C-a\r\n";
+
+/// Multiplexed chardevs, which are shared by multiple frontends.
+static MUX_CHARDEVS: Lazy<Mutex<HashMap<String, Arc<Mutex<MuxChardev>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Frontend of multiplexed chardev.
+pub enum MuxFrontend {
+    /// Device such as serial and virtio console.
+    Device(Arc<Mutex<Chardev>>),
+    /// Qmp monitor connected by unix stream.
+    Monitor(Arc<Mutex<UnixStream>>),
+}
+
+/// Multiplexed chardev. Input of backend is sent to the focused frontend,
+/// and output of all frontends is written to backend.
+pub struct MuxChardev {
+    /// Id of backend chardev.
+    id: String,
+    backend: Arc<Mutex<Chardev>>,
+    frontends: Vec<MuxFrontend>,
+    /// Index of the frontend receiving input.
+    focus: usize,
+    /// Escape key is received.
+    escape: bool,
+    /// Notifiers of backend are registered.
+    registered: bool,
+    /// Line being edited for monitor.
+    monitor_line: Vec<u8>,
+    /// Input waiting for the focused device to receive. Backend is always
+    /// read, so that escape keys work even if the device doesn't receive.
+    device_buf: VecDeque<u8>,
+    /// Timer to retry sending buffered input is added.
+    retrying: bool,
+}
+
+/// Get the multiplexed chardev, its backend is created and realized at the
+/// first time.
+pub(crate) fn get_mux_chardev(cfg: ChardevConfig) -> Result<Arc<Mutex<MuxChardev>>> {
+    let mut mux_chardevs = MUX_CHARDEVS.lock().unwrap();
+    if let Some(mux_chardev) = mux_chardevs.get(&cfg.id) {
+        return Ok(mux_chardev.clone());
+    }
+
+    let id = cfg.id.clone();
+    let backend = Arc::new(Mutex::new(Chardev::new(cfg)));
+    backend
+        .lock()
+        .unwrap()
+        .realize()
+        .with_context(|| format!("Failed to realize backend of multiplexed chardev {}", id))?;
+    let mux_chardev = Arc::new(Mutex::new(MuxChardev {
+        id: id.clone(),
+        backend: backend.clone(),
+        frontends: Vec::new(),
+        focus: 0,
+        escape: false,
+        registered: false,
+        monitor_line: Vec::new(),
+        device_buf: VecDeque::new(),
+        retrying: false,
+    }));
+    backend.lock().unwrap().set_input_callback(&mux_chardev);
+    mux_chardevs.insert(id, mux_chardev.clone());
+    Ok(mux_chardev)
+}
+
+/// Attach qmp monitor to multiplexed chardev.
+///
+/// # Arguments
+///
+/// * `cfg` - Config of multiplexed chardev.
+/// * `stream` - Stream connected to qmp socket.
+pub fn mux_attach_monitor(cfg: ChardevConfig, stream: UnixStream) -> Result<()> {
+    let mux_chardev = get_mux_chardev(cfg)?;
+    let backend = mux_chardev.lock().unwrap().backend();
+    let stream_fd = stream.as_raw_fd();
+    let stream = Arc::new(Mutex::new(stream));
+
+    let cloned_stream = stream.clone();
+    let handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
+        if event & EventSet::IN == EventSet::IN {
+            let mut buf = [0_u8; MUX_MONITOR_BUF_SIZE];
+            match cloned_stream.lock().unwrap().read(&mut buf) {
+                Ok(len) if len > 0 => {
+                    // Terminal is in raw mode, carriage return is needed.
+                    let mut data = Vec::with_capacity(len);
+                    for c in &buf[..len] {
+                        if *c == b'\n' {
+                            data.push(b'\r');
+                        }
+                        data.push(*c);
+                    }
+                    mux_write_backend(&backend, &data);
+                }
+                _ => return Some(gen_delete_notifiers(&[stream_fd])),
+            }
+        }
+        if event & EventSet::HANG_UP == EventSet::HANG_UP {
+            return Some(gen_delete_notifiers(&[stream_fd]));
+        }
+        None
+    });
+    let mut notifiers = MuxChardev::add_frontend(&mux_chardev, MuxFrontend::Monitor(stream));
+    notifiers.push(EventNotifier::new(
+        NotifierOperation::AddShared,
+        stream_fd,
+        None,
+        EventSet::IN | EventSet::HANG_UP,
+        vec![handler],
+    ));
+    EventLoop::update_event(notifiers, None)
+        .with_context(|| "Failed to add event for monitor of multiplexed chardev")
+}
+
+fn mux_write_backend(backend: &Arc<Mutex<Chardev>>, data: &[u8]) {
+    let output = backend.lock().unwrap().output.clone();
+    if let Some(output) = output {
+        let mut locked_output = output.lock().unwrap();
+        if let Err(e) = locked_output.write_all(data) {
+            error!("Failed to write to multiplexed chardev: {:?}", e);
+            return;
+        }
+        if let Err(e) = locked_output.flush() {
+            error!("Failed to flush multiplexed chardev: {:?}", e);
+        }
+    }
+}
+
+impl MuxChardev {
+    pub(crate) fn backend(&self) -> Arc<Mutex<Chardev>> {
+        self.backend.clone()
+    }
+
+    /// Add frontend, and return the notifiers of backend when the first
+    /// frontend is added.
+    pub(crate) fn add_frontend(
+        mux_chardev: &Arc<Mutex<Self>>,
+        frontend: MuxFrontend,
+    ) -> Vec<EventNotifier> {
+        let mut locked_mux = mux_chardev.lock().unwrap();
+        locked_mux.frontends.push(frontend);
+        if locked_mux.registered {
+            return Vec::new();
+        }
+        locked_mux.registered = true;
+        let backend = locked_mux.backend.clone();
+        drop(locked_mux);
+        EventNotifierHelper::internal_notifiers(backend)
+    }
+
+    fn exit(&self) {
+        mux_write_backend(&self.backend, b"\r\nStratoVirt: terminated\r\n");
+        let shutdown_msg = qmp_schema::Shutdown {
+            guest: false,
+            reason: "host-mux-quit".to_string(),
+        };
+        event!(Shutdown; shutdown_msg);
+        TempCleaner::clean();
+        set_termi_canon_mode().expect("Failed to set terminal to canonical mode.");
+        std::process::exit(0);
+    }
+
+    /// Edit the line of monitor, and send it to qmp when it is finished.
+    fn monitor_input(&mut self, stream: &Arc<Mutex<UnixStream>>, data: &[u8]) {
+        let mut echo = Vec::new();
+        for c in data {
+            match *c {
+                b'\r' | b'\n' => {
+                    echo.extend_from_slice(b"\r\n");
+                    self.monitor_line.push(b'\n');
+                    let line = std::mem::take(&mut self.monitor_line);
+                    if let Err(e) = stream.lock().unwrap().write_all(&line) {
+                        error!("Failed to send command to monitor: {:?}", e);
+                    }
+                }
+                // Backspace and delete.
+                0x08 | 0x7f => {
+                    if self.monitor_line.pop().is_some() {
+                        echo.extend_from_slice(b"\x08 \x08");
+                    }
+                }
+                _ => {
+                    echo.push(*c);
+                    self.monitor_line.push(*c);
+                }
+            }
+        }
+        mux_write_backend(&self.backend, &echo);
+    }
+
+    /// Send the buffered input to the focused device as much as it can receive,
+    /// and retry later for the rest.
+    fn flush_device_input(&mut self) {
+        let callbacks = match self.frontends.get(self.focus) {
+            Some(MuxFrontend::Device(chardev)) => {
+                let locked_chardev = chardev.lock().unwrap();
+                match locked_chardev.deactivated {
+                    true => None,
+                    false => locked_chardev.get_input_callback(),
+                }
+            }
+            _ => None,
+        };
+        // Input is dropped if the device is not ready.
+        let (receive, get_remain_space_size) = match callbacks {
+            Some(cb) => cb,
+            None => {
+                self.device_buf.clear();
+                return;
+            }
+        };
+
+        while !self.device_buf.is_empty() {
+            let len = std::cmp::min(get_remain_space_size(), self.device_buf.len());
+            if len == 0 {
+                break;
+            }
+            let data: Vec<u8> = self.device_buf.drain(..len).collect();
+            receive(&data);
+        }
+
+        if !self.device_buf.is_empty() && !self.retrying {
+            self.retrying = true;
+            let id = self.id.clone();
+            let func = Box::new(move || {
+                let mux_chardev = MUX_CHARDEVS.lock().unwrap().get(&id).cloned();
+                if let Some(mux_chardev) = mux_chardev {
+                    let mut locked_mux = mux_chardev.lock().unwrap();
+                    locked_mux.retrying = false;
+                    locked_mux.flush_device_input();
+                }
+            });
+            if let Some(ctx) = EventLoop::get_ctx(None) {
+                ctx.timer_add(func, Duration::from_millis(MUX_DEVICE_RETRY_MS));
+            }
+        }
+    }
+
+    fn send_to_focus(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        match self.frontends.get(self.focus) {
+            Some(MuxFrontend::Device(_)) => {
+                let len = std::cmp::min(MUX_DEVICE_BUF_SIZE - self.device_buf.len(), data.len());
+                if len < data.len() {
+                    warn!(
+                        "Input of multiplexed chardev {} is dropped, device is busy",
+                        self.id
+                    );
+                }
+                self.device_buf.extend(&data[..len]);
+                self.flush_device_input();
+            }
+            Some(MuxFrontend::Monitor(stream)) => {
+                let stream = stream.clone();
+                self.monitor_input(&stream, data);
+            }
+            None => (),
+        }
+    }
+}
+
+impl InputReceiver for MuxChardev {
+    fn input_handle(&mut self, buffer: &[u8]) {
+        let mut data = Vec::with_capacity(buffer.len());
+        for c in buffer {
+            if !self.escape {
+                match *c {
+                    MUX_ESCAPE_KEY => self.escape = true,
+                    _ => data.push(*c),
+                }
+                continue;
+            }
+            self.escape = false;
+            match *c {
+                b'c' => {
+                    self.send_to_focus(&data);
+                    data.clear();
+                    // Input not received by the device is not sent to the next frontend.
+                    self.device_buf.clear();
+                    if !self.frontends.is_empty() {
+                        self.focus = (self.focus + 1) % self.frontends.len();
+                    }
+                }
+                b'h' => mux_write_backend(&self.backend, MUX_HELP),
+                b'x' => self.exit(),
+                MUX_ESCAPE_KEY => data.push(*c),
+                _ => (),
+            }
+        }
+        self.send_to_focus(&data);
+    }
+
+    fn get_remain_space_size(&mut self) -> usize {
+        // Backend is always read to handle escape keys, input of the focused
+        // device is buffered separately.
+        MUX_MONITOR_BUF_SIZE
+    }
+}
+
+/// Output of frontend, which is written to backend of multiplexed chardev.
+pub struct MuxOutput {
+    backend: Arc<Mutex<Chardev>>,
+}
+
+impl MuxOutput {
+    pub fn new(backend: Arc<Mutex<Chardev>>) -> Self {
+        MuxOutput { backend }
+    }
+}
+
+impl Write for MuxOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let output = self.backend.lock().unwrap().output.clone();
+        if let Some(output) = output {
+            output.lock().unwrap().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let output = self.backend.lock().unwrap().output.clone();
+        if let Some(output) = output {
+            output.lock().unwrap().flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl CommunicatOutInterface for MuxOutput {}
@@ -453,6 +453,8 @@ mod test {
         let chardev_cfg = ChardevConfig {
             id: "chardev".to_string(),
             backend: ChardevType::Stdio,
+            mux: false,
+            logfile: None,
         };
         let mut pl011_dev = PL011::new(SerialConfig {
             chardev: chardev_cfg,
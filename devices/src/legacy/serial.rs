@@ -467,6 +467,8 @@ mod test {
         let chardev_cfg = ChardevConfig {
             id: "chardev".to_string(),
             backend: ChardevType::Stdio,
+            mux: false,
+            logfile: None,
         };
         let mut usart = Serial::new(SerialConfig {
             chardev: chardev_cfg.clone(),
@@ -522,6 +524,8 @@ mod test {
         let chardev_cfg = ChardevConfig {
             id: "chardev".to_string(),
             backend: ChardevType::Stdio,
+            mux: false,
+            logfile: None,
         };
         let mut usart = Serial::new(SerialConfig {
             chardev: chardev_cfg,
@@ -33,3 +33,5 @@ pub use interrupt_controller::{
 pub use legacy::error::LegacyError as LegacyErrs;
 pub use scsi::bus as ScsiBus;
 pub use scsi::disk as ScsiDisk;
+pub use scsi::generic as ScsiGeneric;
+pub use scsi::pr as ScsiPr;
@@ -13,6 +13,7 @@
 mod alsa;
 mod audio_demo;
 mod pulseaudio;
+mod wav;
 
 use std::{
     mem,
@@ -28,11 +29,16 @@ use anyhow::{bail, Context, Result};
 use core::time;
 use log::{error, warn};
 
-use self::{alsa::AlsaStreamData, audio_demo::AudioDemo};
+use self::{
+    alsa::AlsaStreamData,
+    audio_demo::AudioDemo,
+    wav::{WavSink, WavSource},
+};
 use super::ivshmem::Ivshmem;
 use machine_manager::config::scream::ScreamConfig;
 use pci::{PciBus, PciDevOps};
 use pulseaudio::{PulseStreamData, TARGET_LATENCY_MS};
+use ui::vnc::vnc_audio_play;
 
 pub const AUDIO_SAMPLE_RATE_44KHZ: u32 = 44100;
 pub const AUDIO_SAMPLE_RATE_48KHZ: u32 = 48000;
@@ -138,6 +144,53 @@ pub struct ShmemStreamFmt {
     pad2: u32,
 }
 
+impl ShmemStreamFmt {
+    /// Create audio stream format. The channels are mapped to the positions in
+    /// order, e.g. FL and FR for stereo.
+    ///
+    /// # Arguments
+    ///
+    /// * `fmt_generation` - Generation which is changed when the stream is reconfigured.
+    /// * `rate` - Audio sampling rate in Hz, which must be a multiple of 44.1KHz or 48KHz.
+    /// * `size` - Number of audio sampling bits.
+    /// * `channels` - Number of audio channel.
+    pub fn new(fmt_generation: u32, rate: u32, size: u8, channels: u8) -> Result<Self> {
+        let (base, multiple) = if rate % AUDIO_SAMPLE_RATE_48KHZ == 0 {
+            (0, rate / AUDIO_SAMPLE_RATE_48KHZ)
+        } else if rate % AUDIO_SAMPLE_RATE_44KHZ == 0 {
+            (WINDOWS_SAMPLE_BASE_RATE, rate / AUDIO_SAMPLE_RATE_44KHZ)
+        } else {
+            (0, 0)
+        };
+        if multiple == 0 || multiple >= WINDOWS_SAMPLE_BASE_RATE as u32 {
+            bail!("Unsupported audio sampling rate {}", rate);
+        }
+        if channels == 0 || channels > 32 {
+            bail!("Unsupported audio channels {}", channels);
+        }
+
+        Ok(Self {
+            fmt_generation,
+            rate: base + multiple as u8,
+            size,
+            channels,
+            pad: 0,
+            channel_map: u32::MAX >> (32 - channels as u32),
+            pad2: 0,
+        })
+    }
+
+    /// Get the audio sampling rate in Hz.
+    pub fn get_rate(&self) -> u32 {
+        let base = if self.rate >= WINDOWS_SAMPLE_BASE_RATE {
+            AUDIO_SAMPLE_RATE_44KHZ
+        } else {
+            AUDIO_SAMPLE_RATE_48KHZ
+        };
+        base * (self.rate % WINDOWS_SAMPLE_BASE_RATE) as u32
+    }
+}
+
 impl Default for ShmemStreamFmt {
     fn default() -> Self {
         Self {
@@ -252,9 +305,20 @@ impl StreamData {
 
             self.update_buffer_by_chunk_idx(hva, play);
             interface.lock().unwrap().send(self);
+            self.vnc_audio_play();
         }
     }
 
+    /// Stream the played audio to vnc clients as well.
+    fn vnc_audio_play(&self) {
+        // SAFETY: audio_base is in the range of shared memory, which is
+        // verified by the header check.
+        let data = unsafe {
+            std::slice::from_raw_parts(self.audio_base as *const u8, self.audio_size as usize)
+        };
+        vnc_audio_play(self.fmt.get_rate(), self.fmt.size, self.fmt.channels, data);
+    }
+
     fn capture_trans(&mut self, hva: u64, interface: Arc<Mutex<dyn AudioInterface>>) {
         // SAFETY: hva is the shared memory base address. It already verifies the validity
         // of the address range during the header check.
@@ -297,22 +361,7 @@ impl Scream {
     }
 
     fn interface_init(&self, name: &str, dir: ScreamDirection) -> Arc<Mutex<dyn AudioInterface>> {
-        match self.interface.as_str() {
-            "ALSA" => Arc::new(Mutex::new(AlsaStreamData::init(name, dir))),
-            "PulseAudio" => Arc::new(Mutex::new(PulseStreamData::init(name, dir))),
-            "Demo" => Arc::new(Mutex::new(AudioDemo::init(
-                dir,
-                self.playback.clone(),
-                self.record.clone(),
-            ))),
-            _ => {
-                error!(
-                    "Unsupported audio interface {}, falling back to ALSA",
-                    self.interface
-                );
-                Arc::new(Mutex::new(AlsaStreamData::init(name, dir)))
-            }
-        }
+        create_audio_interface(&self.interface, name, dir, &self.playback, &self.record)
     }
 
     fn start_play_thread_fn(&self) -> Result<()> {
@@ -398,6 +447,53 @@ impl Scream {
     }
 }
 
+/// Create the host audio interface of one direction.
+///
+/// # Arguments
+///
+/// * `interface` - Type of the interface: ALSA, PulseAudio, Demo, Wav or Null.
+/// * `name` - Application name shown in the host audio server.
+/// * `dir` - Direction of the audio stream.
+/// * `playback` - File to write the played audio for Demo and Wav interface.
+/// * `record` - File to read the recorded audio for Demo and Wav interface.
+pub fn create_audio_interface(
+    interface: &str,
+    name: &str,
+    dir: ScreamDirection,
+    playback: &str,
+    record: &str,
+) -> Arc<Mutex<dyn AudioInterface>> {
+    match interface {
+        "ALSA" => Arc::new(Mutex::new(AlsaStreamData::init(name, dir))),
+        "PulseAudio" => Arc::new(Mutex::new(PulseStreamData::init(name, dir))),
+        "Demo" => Arc::new(Mutex::new(AudioDemo::init(
+            dir,
+            playback.to_string(),
+            record.to_string(),
+        ))),
+        "Wav" | "Null" => {
+            let path = |path: &str| {
+                if interface == "Wav" && !path.is_empty() {
+                    Some(path.to_string())
+                } else {
+                    None
+                }
+            };
+            match dir {
+                ScreamDirection::Playback => Arc::new(Mutex::new(WavSink::init(path(playback)))),
+                ScreamDirection::Record => Arc::new(Mutex::new(WavSource::init(path(record)))),
+            }
+        }
+        _ => {
+            error!(
+                "Unsupported audio interface {}, falling back to ALSA",
+                interface
+            );
+            Arc::new(Mutex::new(AlsaStreamData::init(name, dir)))
+        }
+    }
+}
+
 pub trait AudioInterface: Send {
     fn send(&mut self, recv_data: &StreamData);
     fn receive(&mut self, recv_data: &StreamData) -> bool;
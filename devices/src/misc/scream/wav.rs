@@ -0,0 +1,448 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{error, warn};
+
+use super::{AudioInterface, ShmemStreamFmt, StreamData};
+
+const WAV_HEADER_SIZE: u32 = 44;
+const WAV_FMT_CHUNK_SIZE: u32 = 16;
+const WAV_FORMAT_PCM: u16 = 1;
+/// Offset of the RIFF chunk size in the WAV header.
+const WAV_RIFF_SIZE_OFFSET: u64 = 4;
+/// Offset of the data chunk size in the WAV header.
+const WAV_DATA_SIZE_OFFSET: u64 = 40;
+/// Delay of receiving when the stream format is invalid.
+const INVALID_FMT_DELAY_MS: u64 = 20;
+
+/// PCM format of the WAV file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WavFmt {
+    rate: u32,
+    bits: u16,
+    channels: u16,
+}
+
+impl WavFmt {
+    fn from_stream_fmt(fmt: &ShmemStreamFmt) -> Self {
+        WavFmt {
+            rate: fmt.get_rate(),
+            bits: fmt.size as u16,
+            channels: fmt.channels as u16,
+        }
+    }
+
+    /// Bytes of one frame, `None` if it doesn't fit in the WAV header.
+    fn block_align(&self) -> Option<u16> {
+        let align = (self.bits as u32).div_ceil(8) * self.channels as u32;
+        u16::try_from(align).ok()
+    }
+
+    /// Bytes of one second, `None` if it doesn't fit in the WAV header.
+    fn byte_rate(&self) -> Option<u32> {
+        let align = self.block_align()?;
+        u32::try_from(self.rate as u64 * align as u64).ok()
+    }
+
+    fn header(&self, data_size: u32) -> Result<Vec<u8>> {
+        let (byte_rate, block_align) = match (self.byte_rate(), self.block_align()) {
+            (Some(byte_rate), Some(block_align)) => (byte_rate, block_align),
+            _ => bail!("Unsupported WAV format {:?}", self),
+        };
+        let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&WAV_FMT_CHUNK_SIZE.to_le_bytes());
+        header.extend_from_slice(&WAV_FORMAT_PCM.to_le_bytes());
+        header.extend_from_slice(&self.channels.to_le_bytes());
+        header.extend_from_slice(&self.rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&self.bits.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+        Ok(header)
+    }
+}
+
+/// Parse the WAV file, and return the PCM format and data of it.
+fn parse_wav(buf: &[u8]) -> Result<(WavFmt, Vec<u8>)> {
+    if buf.len() < 12 || &buf[0..4] != b"RIFF" || &buf[8..12] != b"WAVE" {
+        bail!("Not a RIFF WAVE file");
+    }
+
+    let mut fmt = None;
+    let mut pos = 12;
+    while pos + 8 <= buf.len() {
+        let id = &buf[pos..pos + 4];
+        let size = u32::from_le_bytes(buf[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let start = pos + 8;
+        let end = start.saturating_add(size).min(buf.len());
+        match id {
+            b"fmt " => {
+                if end - start < WAV_FMT_CHUNK_SIZE as usize {
+                    bail!("Invalid fmt chunk size {}", size);
+                }
+                let chunk = &buf[start..end];
+                let format = u16::from_le_bytes([chunk[0], chunk[1]]);
+                if format != WAV_FORMAT_PCM {
+                    bail!("Unsupported WAV format {}, only PCM is supported", format);
+                }
+                fmt = Some(WavFmt {
+                    channels: u16::from_le_bytes([chunk[2], chunk[3]]),
+                    rate: u32::from_le_bytes(chunk[4..8].try_into().unwrap()),
+                    bits: u16::from_le_bytes([chunk[14], chunk[15]]),
+                });
+            }
+            b"data" => {
+                let fmt = fmt.with_context(|| "No fmt chunk before data chunk")?;
+                return Ok((fmt, buf[start..end].to_vec()));
+            }
+            _ => (),
+        }
+        // Chunks are aligned to 2 bytes.
+        pos = start.saturating_add(size + (size & 1));
+    }
+    bail!("No data chunk found");
+}
+
+/// Clock to pace the audio stream in real time.
+#[derive(Default)]
+struct StreamClock {
+    start: Option<Instant>,
+    bytes: u64,
+}
+
+impl StreamClock {
+    /// Wait until the time to transfer the next `size` bytes of audio.
+    fn wait(&mut self, size: u32, byte_rate: u32) {
+        if byte_rate == 0 {
+            thread::sleep(Duration::from_millis(INVALID_FMT_DELAY_MS));
+            return;
+        }
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.bytes += size as u64;
+        let target = start + Duration::from_micros(self.bytes * 1_000_000 / byte_rate as u64);
+        let now = Instant::now();
+        if target > now {
+            thread::sleep(target - now);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.start = None;
+        self.bytes = 0;
+    }
+}
+
+/// Playback backend which records the audio to a WAV file, or discards it
+/// when there is no file.
+pub struct WavSink {
+    path: Option<String>,
+    file: Option<File>,
+    fmt: Option<WavFmt>,
+    data_size: u32,
+}
+
+impl WavSink {
+    pub fn init(path: Option<String>) -> Self {
+        Self {
+            path,
+            file: None,
+            fmt: None,
+            data_size: 0,
+        }
+    }
+
+    /// Create the WAV file. The file is truncated if the audio format is changed,
+    /// as a WAV file has only one format.
+    fn create_file(&mut self, fmt: WavFmt) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.fmt.is_some() {
+            warn!(
+                "Audio format is changed to {:?}, the WAV file {} is rewritten",
+                fmt, path
+            );
+        }
+        self.fmt = Some(fmt);
+        self.data_size = 0;
+        self.file = None;
+        // Audio of unsupported format is not written.
+        let header = fmt.header(0)?;
+        let mut file =
+            File::create(path).with_context(|| format!("Failed to create WAV file {}", path))?;
+        file.write_all(&header)?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let size = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| self.data_size.checked_add(len))
+            .filter(|size| size.checked_add(WAV_HEADER_SIZE).is_some())
+            .with_context(|| "WAV file exceeds the maximum size")?;
+        file.write_all(data)?;
+        self.data_size = size;
+
+        // Update the sizes in header, so that the file is always valid.
+        file.seek(SeekFrom::Start(WAV_RIFF_SIZE_OFFSET))?;
+        file.write_all(&(WAV_HEADER_SIZE - 8 + size).to_le_bytes())?;
+        file.seek(SeekFrom::Start(WAV_DATA_SIZE_OFFSET))?;
+        file.write_all(&size.to_le_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl AudioInterface for WavSink {
+    fn send(&mut self, recv_data: &StreamData) {
+        let fmt = WavFmt::from_stream_fmt(&recv_data.fmt);
+        if self.fmt != Some(fmt) {
+            if let Err(e) = self.create_file(fmt) {
+                error!("Failed to create WAV file for playback: {:?}", e);
+            }
+        }
+
+        // SAFETY: audio_base is the shared memory or the buffer of stream. It
+        // already verifies the validity of the address range.
+        let data = unsafe {
+            std::slice::from_raw_parts(
+                recv_data.audio_base as *const u8,
+                recv_data.audio_size as usize,
+            )
+        };
+        if let Err(e) = self.write_data(data) {
+            error!("Failed to write WAV file for playback: {:?}", e);
+            self.file = None;
+        }
+    }
+
+    fn receive(&mut self, _recv_data: &StreamData) -> bool {
+        false
+    }
+
+    fn destroy(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            file.flush()
+                .unwrap_or_else(|e| error!("Failed to flush WAV file: {:?}", e));
+        }
+    }
+}
+
+/// Capture backend which loops the audio of a WAV file as input, or
+/// provides silence when there is no file.
+pub struct WavSource {
+    wav: Option<(WavFmt, Vec<u8>)>,
+    pos: usize,
+    clock: StreamClock,
+    /// Stream format which is warned to mismatch the WAV file.
+    mismatched_fmt: Option<WavFmt>,
+}
+
+impl WavSource {
+    pub fn init(path: Option<String>) -> Self {
+        let wav = path.and_then(|path| {
+            let mut buf = Vec::new();
+            File::open(&path)
+                .and_then(|mut file| file.read_to_end(&mut buf))
+                .with_context(|| format!("Failed to read WAV file {}", path))
+                .and_then(|_| parse_wav(&buf))
+                .map_err(|e| error!("Invalid WAV file for record, use silence: {:?}", e))
+                .ok()
+        });
+
+        Self {
+            wav,
+            pos: 0,
+            clock: StreamClock::default(),
+            mismatched_fmt: None,
+        }
+    }
+
+    fn fill(&mut self, fmt: WavFmt, data: &mut [u8]) {
+        data.fill(0);
+        let (wav_fmt, wav_data) = match self.wav.as_ref() {
+            Some(wav) if !wav.1.is_empty() => wav,
+            _ => return,
+        };
+        if *wav_fmt != fmt {
+            if self.mismatched_fmt != Some(fmt) {
+                warn!(
+                    "Record format {:?} mismatches WAV file format {:?}, use silence",
+                    fmt, wav_fmt
+                );
+                self.mismatched_fmt = Some(fmt);
+            }
+            return;
+        }
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = (data.len() - offset).min(wav_data.len() - self.pos);
+            data[offset..offset + len].copy_from_slice(&wav_data[self.pos..self.pos + len]);
+            offset += len;
+            self.pos = (self.pos + len) % wav_data.len();
+        }
+    }
+}
+
+impl AudioInterface for WavSource {
+    fn send(&mut self, _recv_data: &StreamData) {}
+
+    fn receive(&mut self, recv_data: &StreamData) -> bool {
+        let fmt = WavFmt::from_stream_fmt(&recv_data.fmt);
+        self.clock
+            .wait(recv_data.audio_size, fmt.byte_rate().unwrap_or(0));
+
+        // SAFETY: audio_base is the shared memory or the buffer of stream. It
+        // already verifies the validity of the address range.
+        let data = unsafe {
+            std::slice::from_raw_parts_mut(
+                recv_data.audio_base as *mut u8,
+                recv_data.audio_size as usize,
+            )
+        };
+        self.fill(fmt, data);
+        true
+    }
+
+    fn destroy(&mut self) {
+        self.clock.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    fn stream_data(fmt: ShmemStreamFmt, buf: &mut [u8]) -> StreamData {
+        let mut data = StreamData::default();
+        data.fmt = fmt;
+        data.audio_base = buf.as_mut_ptr() as u64;
+        data.audio_size = buf.len() as u32;
+        data
+    }
+
+    #[test]
+    fn test_wav_sink_and_source() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        let fmt = ShmemStreamFmt::new(1, 48000, 16, 2).unwrap();
+
+        let mut sink = WavSink::init(Some(path.clone()));
+        sink.send(&stream_data(fmt, &mut [1, 2, 3, 4]));
+        sink.send(&stream_data(fmt, &mut [5, 6, 7, 8]));
+        sink.destroy();
+
+        let mut buf = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 52);
+        assert_eq!(&buf[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(buf[4..8].try_into().unwrap()), 44);
+        assert_eq!(u32::from_le_bytes(buf[24..28].try_into().unwrap()), 48000);
+        assert_eq!(u32::from_le_bytes(buf[28..32].try_into().unwrap()), 192000);
+        assert_eq!(u32::from_le_bytes(buf[40..44].try_into().unwrap()), 8);
+        let (wav_fmt, wav_data) = parse_wav(&buf).unwrap();
+        assert_eq!(wav_fmt, WavFmt::from_stream_fmt(&fmt));
+        assert_eq!(wav_data, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // The WAV file is looped as input.
+        let mut source = WavSource::init(Some(path.clone()));
+        let mut input = [0_u8; 12];
+        assert!(source.receive(&stream_data(fmt, &mut input)));
+        assert_eq!(input, [1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4]);
+
+        // Silence for mismatched format.
+        let fmt_44k = ShmemStreamFmt::new(2, 44100, 16, 2).unwrap();
+        assert!(source.receive(&stream_data(fmt_44k, &mut input)));
+        assert_eq!(input, [0; 12]);
+
+        // File is rewritten when format changes.
+        sink.send(&stream_data(fmt_44k, &mut [9, 10, 11, 12]));
+        let (wav_fmt, wav_data) = parse_wav(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(wav_fmt.rate, 44100);
+        assert_eq!(wav_data, [9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn test_wav_max_format() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        std::fs::remove_file(&path).unwrap();
+        // Max rate, sample size and channels of stream.
+        let fmt = ShmemStreamFmt {
+            rate: 127,
+            size: u8::MAX,
+            channels: u8::MAX,
+            ..Default::default()
+        };
+        let wav_fmt = WavFmt::from_stream_fmt(&fmt);
+        assert_eq!(wav_fmt.block_align(), Some(8160));
+        assert_eq!(wav_fmt.byte_rate(), None);
+        assert!(wav_fmt.header(0).is_err());
+
+        // Audio of the format is skipped.
+        let mut sink = WavSink::init(Some(path.clone()));
+        sink.send(&stream_data(fmt, &mut [1, 2, 3, 4]));
+        assert!(sink.file.is_none());
+        assert!(!std::path::Path::new(&path).exists());
+
+        let mut source = WavSource::init(None);
+        let mut input = [1_u8; 4];
+        assert!(source.receive(&stream_data(fmt, &mut input)));
+        assert_eq!(input, [0; 4]);
+
+        // Frame of WAV file doesn't fit in block align.
+        let wav_fmt = WavFmt {
+            rate: 48000,
+            bits: u16::MAX,
+            channels: u16::MAX,
+        };
+        assert_eq!(wav_fmt.block_align(), None);
+        assert_eq!(wav_fmt.byte_rate(), None);
+    }
+
+    #[test]
+    fn test_wav_null() {
+        let fmt = ShmemStreamFmt::new(1, 48000, 16, 2).unwrap();
+        let mut sink = WavSink::init(None);
+        sink.send(&stream_data(fmt, &mut [1, 2, 3, 4]));
+        assert!(sink.file.is_none());
+
+        let mut source = WavSource::init(None);
+        let mut input = [1_u8; 4];
+        assert!(source.receive(&stream_data(fmt, &mut input)));
+        assert_eq!(input, [0; 4]);
+
+        assert!(parse_wav(b"RIFF\0\0\0\0WAVEdata\0\0\0\0").is_err());
+    }
+}
@@ -20,10 +20,11 @@ use byteorder::{BigEndian, ByteOrder};
 use log::{debug, error, info};
 
 use crate::ScsiDisk::{
-    ScsiDevice, DEFAULT_SECTOR_SIZE, SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-    SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT, SCSI_DISK_F_DPOFUA, SCSI_DISK_F_REMOVABLE, SCSI_TYPE_DISK,
-    SCSI_TYPE_ROM, SECTOR_SHIFT,
+    ScsiDevice, DEFAULT_SECTOR_SIZE, SCSI_DISK_F_DPOFUA, SCSI_TYPE_DISK, SCSI_TYPE_ROM,
+    SCSI_TYPE_TAPE, SECTOR_SHIFT,
 };
+use crate::ScsiGeneric::{ScsiPassthroughMode, SgIoResponse};
+use crate::ScsiPr::PrError;
 use util::aio::{AioCb, AioReqResult, Iovec};
 use util::AsAny;
 
@@ -170,6 +171,8 @@ pub const STATUS_MASK: u8 = 0x3e;
 /// Scsi cdb length will be 6/10/12/16 bytes.
 pub const SCSI_CMD_BUF_SIZE: usize = 16;
 pub const SCSI_SENSE_BUF_SIZE: usize = 252;
+/// Length of fixed format sense data.
+pub const SCSI_FIXED_SENSE_LEN: usize = 18;
 
 /// SERVICE ACTION IN subcodes.
 pub const SUBCODE_READ_CAPACITY_16: u8 = 0x10;
@@ -211,6 +214,7 @@ pub const SCSI_SENSE_INVALID_FIELD: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x24
 pub const SCSI_SENSE_INVALID_PARAM: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x00);
 pub const SCSI_SENSE_INVALID_PARAM_VALUE: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x01);
 pub const SCSI_SENSE_INVALID_PARAM_LEN: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x1a, 0x00);
+pub const SCSI_SENSE_INVALID_RELEASE: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x04);
 pub const SCSI_SENSE_LUN_NOT_SUPPORTED: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x25, 0x00);
 pub const SCSI_SENSE_SAVING_PARAMS_NOT_SUPPORTED: ScsiSense =
     scsisense!(ILLEGAL_REQUEST, 0x39, 0x00);
@@ -238,7 +242,7 @@ pub const SCSI_SENSE_DEVICE_INTERNAL_RESET: ScsiSense = scsisense!(UNIT_ATTENTIO
 pub const SCSI_SENSE_WRITE_PROTECTED: ScsiSense = scsisense!(DATA_PROTECT, 0x27, 0x00);
 pub const SCSI_SENSE_SPACE_ALLOC_FAILED: ScsiSense = scsisense!(DATA_PROTECT, 0x27, 0x07);
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct ScsiSense {
     /// Sense key.
     pub key: u8,
@@ -248,6 +252,34 @@ pub struct ScsiSense {
     pub ascq: u8,
 }
 
+impl ScsiSense {
+    /// Build the fixed format sense data.
+    pub fn to_fixed_format(&self) -> Vec<u8> {
+        // Byte0: Bit7: VALID. Bits[0-6]: Response Code(70h).
+        // Byte2: Bits[0-3]: Sense Key.
+        // Byte7: Additional Sense Length(n - 7).
+        // Byte12: Additional Sense Code.
+        // Byte13: Additional Sense Code Qualifier.
+        let mut buf = vec![0; SCSI_FIXED_SENSE_LEN];
+        buf[0] = 0x70;
+        buf[2] = self.key;
+        buf[7] = (SCSI_FIXED_SENSE_LEN - 8) as u8;
+        buf[12] = self.asc;
+        buf[13] = self.ascq;
+        buf
+    }
+
+    /// Parse the sense data in fixed format or descriptor format.
+    pub fn from_raw(buf: &[u8]) -> Option<ScsiSense> {
+        // Byte0: Bits[0-6]: Response Code. 70h/71h: fixed format, 72h/73h: descriptor format.
+        match buf.first()? & 0x7f {
+            0x70 | 0x71 if buf.len() >= 14 => Some(scsisense!(buf[2] & 0xf, buf[12], buf[13])),
+            0x72 | 0x73 if buf.len() >= 4 => Some(scsisense!(buf[1] & 0xf, buf[2], buf[3])),
+            _ => None,
+        }
+    }
+}
+
 /// Mode page codes for mode sense/set.
 pub const MODE_PAGE_R_W_ERROR: u8 = 0x01;
 pub const MODE_PAGE_HD_GEOMETRY: u8 = 0x04;
@@ -340,6 +372,8 @@ pub const CD_MAX_BYTES: u32 = CD_MINS * CD_SECS * CD_FRAMES * CD_FRAME_SIZE;
 pub const CD_MAX_SECTORS: u32 = CD_MAX_BYTES / DEFAULT_SECTOR_SIZE;
 
 /// Profile Number for GET CONFIGURATION command in MMC-6.
+/// No current profile.
+const GC_PROFILE_NONE: u16 = 0x0000;
 /// Read only Compact Disc capable.
 const GC_PROFILE_CD_ROM: u16 = 0x0008;
 /// Read only DVD.
@@ -411,7 +445,7 @@ impl ScsiBus {
     }
 }
 
-fn scsi_bus_parse_req_cdb(
+pub fn scsi_bus_parse_req_cdb(
     cdb: [u8; SCSI_CMD_BUF_SIZE],
     dev: Arc<Mutex<ScsiDevice>>,
 ) -> Option<ScsiCommand> {
@@ -480,6 +514,17 @@ pub fn aio_complete_cb(aiocb: &AioCb<ScsiCompleteCb>, mut ret: i64) -> Result<()
 pub trait ScsiRequestOps: Send + Sync + AsAny {
     // Will be called in the end of this scsi instruction execution.
     fn scsi_request_complete_cb(&mut self, status: u8, scsisense: Option<ScsiSense>) -> Result<()>;
+
+    // Will be called in the end of the passthrough scsi instruction, with the raw sense data
+    // from the host device and the residual bytes of the data transfer.
+    fn scsi_request_complete_raw_cb(
+        &mut self,
+        status: u8,
+        sense: &[u8],
+        _resid: u32,
+    ) -> Result<()> {
+        self.scsi_request_complete_cb(status, ScsiSense::from_raw(sense))
+    }
 }
 
 pub struct ScsiRequest {
@@ -507,17 +552,26 @@ impl ScsiRequest {
     ) -> Result<Self> {
         let cmd = scsi_bus_parse_req_cdb(cdb, scsidevice.clone()).with_context(|| "Error cdb!")?;
         let op = cmd.op;
-        let opstype = scsi_operation_type(op);
+        let mut opstype = scsi_operation_type(op);
+
+        let dev_lock = scsidevice.lock().unwrap();
+        if let Some(mode) = dev_lock.passthrough {
+            // Target requests are emulated in the scsi bus, and READ/WRITE commands of scsi-block
+            // use the block backend. Others are passed through to the host device.
+            if req_lun != dev_lock.config.lun || op == REPORT_LUNS {
+                opstype = EMULATE_SCSI_OPS;
+            } else if mode == ScsiPassthroughMode::Generic || opstype != NON_EMULATE_SCSI_OPS {
+                opstype = PASSTHROUGH_SCSI_OPS;
+            }
+        }
+        drop(dev_lock);
 
-        if op == WRITE_10 || op == READ_10 {
+        // Request to the device without medium will be terminated in execution.
+        if (op == WRITE_10 || op == READ_10) && scsidevice.lock().unwrap().medium_available() {
             let dev_lock = scsidevice.lock().unwrap();
             let disk_size = dev_lock.disk_sectors << SECTOR_SHIFT;
-            let disk_type = dev_lock.scsi_type;
+            let offset_shift = dev_lock.block_size.trailing_zeros();
             drop(dev_lock);
-            let offset_shift = match disk_type {
-                SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-                _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-            };
             let offset = cmd
                 .lba
                 .checked_shl(offset_shift)
@@ -545,7 +599,92 @@ impl ScsiRequest {
         })
     }
 
+    /// Check the condition which terminates the request before executing it, and
+    /// return the sense of the condition.
+    fn check_condition(&self) -> Option<ScsiSense> {
+        let mut dev_lock = self.dev.lock().unwrap();
+        // Unit attention condition is reported to the first command except the commands
+        // which are used to get the device information.
+        if ![
+            INQUIRY,
+            REPORT_LUNS,
+            REQUEST_SENSE,
+            GET_CONFIGURATION,
+            GET_EVENT_STATUS_NOTIFICATION,
+        ]
+        .contains(&self.cmd.op)
+        {
+            if let Some(sense) = dev_lock.unit_attention.take() {
+                return Some(sense);
+            }
+        }
+
+        let medium_required = self.opstype == NON_EMULATE_SCSI_OPS
+            || [
+                TEST_UNIT_READY,
+                READ_CAPACITY_10,
+                SERVICE_ACTION_IN_16,
+                READ_TOC,
+                READ_DISC_INFORMATION,
+            ]
+            .contains(&self.cmd.op);
+        if medium_required && !dev_lock.medium_available() {
+            return Some(SCSI_SENSE_NO_MEDIUM);
+        }
+
+        None
+    }
+
+    fn complete_with_sense(mut self, sense: ScsiSense) -> Result<Arc<Mutex<ScsiRequest>>> {
+        debug!(
+            "scsi command {:#x} check condition, sense {:?}",
+            self.cmd.op, sense
+        );
+        self.dev.lock().unwrap().sense = Some(sense);
+        self.upper_req
+            .as_mut()
+            .scsi_request_complete_cb(CHECK_CONDITION, Some(sense))?;
+
+        Ok(Arc::new(Mutex::new(self)))
+    }
+
+    /// Check whether the command conflicts with the persistent reservation.
+    fn reservation_conflict(&self) -> bool {
+        let dev_lock = self.dev.lock().unwrap();
+        let pr = match dev_lock.pr.as_ref() {
+            Some(pr) => pr,
+            None => return false,
+        };
+        pr.check_conflict(self.cmd.op).unwrap_or_else(|e| {
+            // Other VMs may hold the reservation, so the command can not be executed.
+            error!(
+                "Failed to check reservation of scsi device {}: {:?}",
+                dev_lock.config.id, e
+            );
+            true
+        })
+    }
+
+    fn complete_with_conflict(mut self) -> Result<Arc<Mutex<ScsiRequest>>> {
+        debug!("scsi command {:#x} reservation conflict", self.cmd.op);
+        self.upper_req
+            .as_mut()
+            .scsi_request_complete_cb(RESERVATION_CONFLICT, None)?;
+
+        Ok(Arc::new(Mutex::new(self)))
+    }
+
     pub fn execute(self) -> Result<Arc<Mutex<ScsiRequest>>> {
+        if self.opstype == PASSTHROUGH_SCSI_OPS {
+            return self.passthrough_execute();
+        }
+        if let Some(sense) = self.check_condition() {
+            return self.complete_with_sense(sense);
+        }
+        if self.reservation_conflict() {
+            return self.complete_with_conflict();
+        }
+
         let mode = self.cmd.mode.clone();
         let op = self.cmd.op;
         let dev = self.dev.clone();
@@ -556,10 +695,7 @@ impl ScsiRequest {
         let s_req = Arc::new(Mutex::new(self));
 
         let scsicompletecb = ScsiCompleteCb { req: s_req.clone() };
-        let offset_bits = match locked_dev.scsi_type {
-            SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-            _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-        };
+        let offset_bits = locked_dev.block_size.trailing_zeros();
         let locked_req = s_req.lock().unwrap();
         let iovecs = locked_req.iovec.clone();
         let offset = (locked_req.cmd.lba << offset_bits) as usize;
@@ -594,6 +730,51 @@ impl ScsiRequest {
         Ok(s_req)
     }
 
+    /// Pass the command through to the host scsi device. SG_IO is executed by the worker
+    /// thread of the host device, and the request is completed in the event loop.
+    fn passthrough_execute(self) -> Result<Arc<Mutex<ScsiRequest>>> {
+        let dev = self.dev.clone();
+        let cdb = self.cmd.buf[..self.cmd.len as usize].to_vec();
+        let mode = self.cmd.mode.clone();
+        let iovec = self.iovec.clone();
+        let xfer = self.cmd.xfer;
+        let s_req = Arc::new(Mutex::new(self));
+        let req = s_req.clone();
+        let complete = Box::new(move |resp: SgIoResponse| {
+            if let Err(e) = req.lock().unwrap().passthrough_complete(resp) {
+                error!("Failed to complete passthrough scsi request: {:?}", e);
+            }
+        });
+
+        let locked_dev = dev.lock().unwrap();
+        // SAFETY: the sg device is assigned after passthrough device realized.
+        let sg = locked_dev.sg.as_ref().unwrap();
+        sg.submit(&cdb, &mode, iovec, xfer, complete)?;
+        Ok(s_req)
+    }
+
+    fn passthrough_complete(&mut self, resp: SgIoResponse) -> Result<()> {
+        if resp.status == GOOD {
+            let mut locked_dev = self.dev.lock().unwrap();
+            // SAFETY: the sg device is assigned after passthrough device realized.
+            let sg = locked_dev.sg.as_ref().unwrap();
+            let len = self.cmd.xfer - resp.resid;
+            let block_size = sg.snoop_block_size(&self.cmd, locked_dev.scsi_type, &self.iovec, len);
+            sg.fix_block_limits(&self.cmd, &self.iovec, len, locked_dev.block_size);
+            if let Some(block_size) = block_size {
+                debug!(
+                    "scsi device {} block size {}",
+                    locked_dev.config.id, block_size
+                );
+                locked_dev.block_size = block_size;
+            }
+        }
+
+        self.upper_req
+            .as_mut()
+            .scsi_request_complete_raw_cb(resp.status, &resp.sense, resp.resid)
+    }
+
     fn emulate_target_execute(
         &self,
         not_supported_flag: &mut bool,
@@ -626,21 +807,19 @@ impl ScsiRequest {
         match self.cmd.op {
             REQUEST_SENSE => {
                 *sense = Some(SCSI_SENSE_NO_SENSE);
-                Ok(Vec::new())
+                scsi_command_emulate_request_sense(&self.cmd, &self.dev)
             }
-            TEST_UNIT_READY => {
-                let dev_lock = self.dev.lock().unwrap();
-                if dev_lock.block_backend.is_none() {
-                    Err(anyhow!("No scsi backend!"))
-                } else {
-                    Ok(Vec::new())
+            // Medium has been checked before executing.
+            TEST_UNIT_READY => Ok(Vec::new()),
+            START_STOP => scsi_command_emulate_start_stop(&self.cmd, &self.dev, sense),
+            ALLOW_MEDIUM_REMOVAL => {
+                // Byte4: Bits[0-1]: Prevent.
+                let mut dev_lock = self.dev.lock().unwrap();
+                if dev_lock.is_removable() {
+                    dev_lock.tray_locked = self.cmd.buf[4] & 1 != 0;
                 }
+                Ok(Vec::new())
             }
-            // Do not support SCSI_DISK_F_REMOVABLE now.
-            // Return Ok is enough for START_STOP/ALLOW_MEDIUM_REMOVAL.
-            // TODO: implement SCSI_DISK_F_REMOVABLE.
-            START_STOP => Ok(Vec::new()),
-            ALLOW_MEDIUM_REMOVAL => Ok(Vec::new()),
             INQUIRY => scsi_command_emulate_inquiry(&self.cmd, &self.dev),
             READ_CAPACITY_10 => scsi_command_emulate_read_capacity_10(&self.cmd, &self.dev),
             MODE_SENSE | MODE_SENSE_10 => scsi_command_emulate_mode_sense(&self.cmd, &self.dev),
@@ -653,6 +832,17 @@ impl ScsiRequest {
             }
             READ_TOC => scsi_command_emulate_read_toc(&self.cmd, &self.dev),
             GET_CONFIGURATION => scsi_command_emulate_get_configuration(&self.cmd, &self.dev),
+            PERSISTENT_RESERVE_IN if self.dev.lock().unwrap().pr.is_some() => {
+                scsi_command_emulate_persistent_reserve_in(&self.cmd, &self.dev, sense)
+            }
+            PERSISTENT_RESERVE_OUT if self.dev.lock().unwrap().pr.is_some() => {
+                scsi_command_emulate_persistent_reserve_out(
+                    &self.cmd,
+                    &self.dev,
+                    &self.iovec,
+                    sense,
+                )
+            }
             _ => {
                 *not_supported_flag = true;
                 Err(anyhow!("Emulation scsi command is not supported now!"))
@@ -671,6 +861,8 @@ impl ScsiRequest {
         // REPORT LUNS is also a target request command.
         let result = if self.req_lun != found_lun || self.cmd.op == REPORT_LUNS {
             self.emulate_target_execute(&mut not_supported_flag, &mut sense)
+        } else if let Some(condition) = self.check_condition() {
+            return self.complete_with_sense(condition);
         } else {
             // It's not a target request.
             self.emulate_device_execute(&mut not_supported_flag, &mut sense)
@@ -685,14 +877,18 @@ impl ScsiRequest {
                     debug!("emulation scsi command {:#x} is no supported", self.cmd.op);
                     status = CHECK_CONDITION;
                     sense = Some(SCSI_SENSE_INVALID_OPCODE);
+                } else if let Some(PrError::Conflict) = e.downcast_ref::<PrError>() {
+                    debug!("scsi command {:#x} reservation conflict", self.cmd.op);
+                    status = RESERVATION_CONFLICT;
                 } else {
                     error!(
                         "Error in processing scsi command {:#x}, err is {:?}",
                         self.cmd.op, e
                     );
                     status = CHECK_CONDITION;
-                    sense = Some(SCSI_SENSE_INVALID_FIELD);
+                    sense = Some(sense.unwrap_or(SCSI_SENSE_INVALID_FIELD));
                 }
+                self.dev.lock().unwrap().sense = sense;
             }
         }
 
@@ -742,6 +938,8 @@ fn outbuf_to_iov(command: u8, outbuf: &[u8], iovec: &[Iovec]) -> Result<()> {
 pub const EMULATE_SCSI_OPS: u32 = 0;
 // Scsi Commands which will do something(eg: read and write) to the backend.
 pub const NON_EMULATE_SCSI_OPS: u32 = 1;
+// Scsi Commands which are passed through to the host scsi device.
+pub const PASSTHROUGH_SCSI_OPS: u32 = 2;
 
 fn scsi_operation_type(op: u8) -> u32 {
     match op {
@@ -776,8 +974,15 @@ fn scsi_cdb_length(cdb: &[u8; SCSI_CMD_BUF_SIZE]) -> i32 {
 fn scsi_cdb_xfer(cdb: &[u8; SCSI_CMD_BUF_SIZE], dev: Arc<Mutex<ScsiDevice>>) -> i32 {
     let dev_lock = dev.lock().unwrap();
     let block_size = dev_lock.block_size as i32;
+    let scsi_type = dev_lock.scsi_type;
     drop(dev_lock);
 
+    if scsi_type == SCSI_TYPE_TAPE {
+        if let Some(xfer) = scsi_stream_cdb_xfer(cdb, block_size) {
+            return xfer;
+        }
+    }
+
     let mut xfer = match cdb[0] >> 5 {
         // Group Code  |  Transfer length. |
         // 000b        |  Byte[4].         |
@@ -793,19 +998,40 @@ fn scsi_cdb_xfer(cdb: &[u8; SCSI_CMD_BUF_SIZE], dev: Arc<Mutex<ScsiDevice>>) ->
     };
 
     match cdb[0] {
-        TEST_UNIT_READY | START_STOP | SYNCHRONIZE_CACHE | SYNCHRONIZE_CACHE_16 => {
+        TEST_UNIT_READY | REWIND | START_STOP | SYNCHRONIZE_CACHE | SYNCHRONIZE_CACHE_16
+        | SET_CAPACITY | WRITE_FILEMARKS | WRITE_FILEMARKS_16 | SPACE | RESERVE | RELEASE
+        | ERASE | ALLOW_MEDIUM_REMOVAL | SEEK_10 | LOCATE_16 => {
             xfer = 0;
         }
         READ_CAPACITY_10 => {
             xfer = 8;
         }
+        READ_BLOCK_LIMITS => {
+            xfer = 6;
+        }
+        VERIFY_10 | VERIFY_12 | VERIFY_16 => {
+            // Byte1: Bit1: BYTCHK. No data is transferred if the medium is verified only.
+            if cdb[1] & 0x2 == 0 {
+                xfer = 0;
+            }
+            xfer *= block_size;
+        }
+        WRITE_SAME_10 | WRITE_SAME_16 => {
+            // Byte1: Bit0: NDOB(No Data-Out Buffer).
+            xfer = if cdb[1] & 0x1 != 0 { 0 } else { block_size };
+        }
+        PERSISTENT_RESERVE_OUT => {
+            // Bytes[5-8]: Parameter List Length.
+            xfer = BigEndian::read_u32(&cdb[5..]) as i32;
+        }
         WRITE_6 | READ_6 => {
             // length 0 means 256 blocks.
             if xfer == 0 {
                 xfer = 256 * block_size;
             }
         }
-        WRITE_10 | WRITE_12 | WRITE_16 | READ_10 | READ_12 | READ_16 => {
+        WRITE_10 | WRITE_12 | WRITE_16 | WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16
+        | READ_10 | READ_12 | READ_16 => {
             xfer *= block_size;
         }
         INQUIRY => {
@@ -816,6 +1042,49 @@ fn scsi_cdb_xfer(cdb: &[u8; SCSI_CMD_BUF_SIZE], dev: Arc<Mutex<ScsiDevice>>) ->
     xfer
 }
 
+/// Transfer length of the sequential-access device(tape) commands which differ from the
+/// direct-access device's.
+fn scsi_stream_cdb_xfer(cdb: &[u8; SCSI_CMD_BUF_SIZE], block_size: i32) -> Option<i32> {
+    let xfer = match cdb[0] {
+        // Byte1: Bit0: FIXED. Transfer length is in blocks if fixed, otherwise in bytes.
+        // 6 bytes commands: Bytes[2-4]: Transfer Length.
+        READ_6 | READ_REVERSE | RECOVER_BUFFERED_DATA | WRITE_6 => {
+            let len = (BigEndian::read_u32(&cdb[1..]) & 0xff_ffff) as i32;
+            if cdb[1] & 0x1 != 0 {
+                len * block_size
+            } else {
+                len
+            }
+        }
+        // 16 bytes commands: Bytes[12-14]: Transfer Length.
+        READ_16 | READ_REVERSE_16 | VERIFY_16 | WRITE_16 => {
+            let len = (BigEndian::read_u32(&cdb[11..]) & 0xff_ffff) as i32;
+            if cdb[1] & 0x1 != 0 {
+                len * block_size
+            } else {
+                len
+            }
+        }
+        REWIND | LOAD_UNLOAD | ERASE | ERASE_16 | SPACE | LOCATE_10 | LOCATE_16 => 0,
+        // Bytes[12-13]: Parameter Length.
+        SPACE_16 => BigEndian::read_u16(&cdb[12..]) as i32,
+        // Byte1: Bits[0-4]: Service Action.
+        READ_POSITION => match cdb[1] & 0x1f {
+            // Short form.
+            0x00 | 0x01 => 20,
+            // Long form.
+            0x06 => 32,
+            // Extended form, Bytes[7-8]: Allocation Length.
+            0x08 => BigEndian::read_u16(&cdb[7..]) as i32,
+            _ => return None,
+        },
+        // Bytes[3-4]: Parameter List Length.
+        FORMAT_UNIT => BigEndian::read_u16(&cdb[3..]) as i32,
+        _ => return None,
+    };
+    Some(xfer)
+}
+
 fn scsi_cdb_lba(cdb: &[u8; SCSI_CMD_BUF_SIZE]) -> i64 {
     match cdb[0] >> 5 {
         // Group Code  |  Logical Block Address.       |
@@ -932,10 +1201,11 @@ fn scsi_command_emulate_vpd_page(
         }
         0x83 => {
             // Device Identification.
-            let mut len: u8 = dev_lock.state.device_id.len() as u8;
-            if len > (255 - 8) {
-                len = 255 - 8;
+            let mut max_len = 255 - 8;
+            if dev_lock.config.wwn.is_some() {
+                max_len -= 12;
             }
+            let len = cmp::min(dev_lock.state.device_id.len(), max_len) as u8;
 
             if len > 0 {
                 // 0x2: Code Set: ASCII, Protocol Identifier: reserved.
@@ -948,6 +1218,15 @@ fn scsi_command_emulate_vpd_page(
                 device_id_vec.truncate(len as usize);
                 outbuf.append(&mut device_id_vec);
             }
+
+            if let Some(wwn) = dev_lock.config.wwn {
+                // 0x1: Code Set: Binary, Protocol Identifier: reserved.
+                // 0x3: Identifier Type: NAA, Association: logical unit.
+                // 0: Reserved.
+                // 8: identifier length.
+                outbuf.append(&mut [0x1_u8, 0x3_u8, 0_u8, 8_u8].to_vec());
+                outbuf.extend_from_slice(&wwn.to_be_bytes());
+            }
             buflen = outbuf.len();
         }
         0xb0 => {
@@ -1088,10 +1367,10 @@ fn scsi_command_emulate_inquiry(
     let dev_lock = dev.lock().unwrap();
 
     outbuf[0] = (dev_lock.scsi_type & 0x1f) as u8;
-    outbuf[1] = match dev_lock.state.features & SCSI_DISK_F_REMOVABLE {
-        1 => 0x80,
-        _ => 0,
-    };
+    // Byte1: bit7: RMB(Removable Medium).
+    if dev_lock.is_removable() {
+        outbuf[1] = 0x80;
+    }
 
     let product_bytes = dev_lock.state.product.as_bytes();
     let product_len = cmp::min(product_bytes.len(), SCSI_INQUIRY_PRODUCT_MAX_LEN);
@@ -1125,6 +1404,92 @@ fn scsi_command_emulate_inquiry(
     Ok(outbuf)
 }
 
+fn scsi_command_emulate_request_sense(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<Vec<u8>> {
+    let mut dev_lock = dev.lock().unwrap();
+    // Pending unit attention condition is reported and cleared by REQUEST SENSE.
+    let sense = dev_lock
+        .unit_attention
+        .take()
+        .or_else(|| dev_lock.sense.take())
+        .unwrap_or(SCSI_SENSE_NO_SENSE);
+    drop(dev_lock);
+
+    let mut outbuf = sense.to_fixed_format();
+    outbuf.truncate(cmd.xfer as usize);
+
+    Ok(outbuf)
+}
+
+fn scsi_command_emulate_start_stop(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+    sense: &mut Option<ScsiSense>,
+) -> Result<Vec<u8>> {
+    // Byte4: Bit1: LOEJ(Load Eject). Bit0: START.
+    let loej = cmd.buf[4] & 0x2 != 0;
+    let start = cmd.buf[4] & 0x1 != 0;
+    let mut dev_lock = dev.lock().unwrap();
+    if !loej || !dev_lock.is_removable() {
+        return Ok(Vec::new());
+    }
+
+    if !start && dev_lock.tray_locked {
+        *sense = Some(if dev_lock.block_backend.is_some() {
+            SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED
+        } else {
+            SCSI_SENSE_NOT_READY_REMOVAL_PREVENTED
+        });
+        bail!("Medium removal is prevented");
+    }
+    // Open the tray if START is 0, otherwise close the tray. The medium is kept in the tray.
+    dev_lock.tray_open = !start;
+
+    Ok(Vec::new())
+}
+
+fn scsi_command_emulate_persistent_reserve_in(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+    sense: &mut Option<ScsiSense>,
+) -> Result<Vec<u8>> {
+    let dev_lock = dev.lock().unwrap();
+    // SAFETY: the persistent reservation has been checked before executing.
+    let pr = dev_lock.pr.as_ref().unwrap();
+    let mut outbuf = pr
+        .reserve_in(&cmd.buf)
+        .map_err(|e| pr_error_sense(e, sense))?;
+    // Bytes[7-8]: Allocation Length.
+    outbuf.truncate(cmd.xfer as usize);
+
+    Ok(outbuf)
+}
+
+fn scsi_command_emulate_persistent_reserve_out(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+    iovec: &[Iovec],
+    sense: &mut Option<ScsiSense>,
+) -> Result<Vec<u8>> {
+    let dev_lock = dev.lock().unwrap();
+    // SAFETY: the persistent reservation has been checked before executing.
+    let pr = dev_lock.pr.as_ref().unwrap();
+    pr.reserve_out(&cmd.buf, iovec)
+        .map_err(|e| pr_error_sense(e, sense))?;
+
+    Ok(Vec::new())
+}
+
+/// Get the sense of the persistent reservation command failure.
+fn pr_error_sense(err: anyhow::Error, sense: &mut Option<ScsiSense>) -> anyhow::Error {
+    if let Some(PrError::CheckCondition(s)) = err.downcast_ref::<PrError>() {
+        *sense = Some(*s);
+    }
+    err
+}
+
 fn scsi_command_emulate_read_capacity_10(
     cmd: &ScsiCommand,
     dev: &Arc<Mutex<ScsiDevice>>,
@@ -1163,6 +1528,7 @@ fn scsi_command_emulate_mode_sense(
     let mut nb_sectors = dev_lock.disk_sectors as u32;
     let scsi_type = dev_lock.scsi_type;
     let block_size = dev_lock.block_size;
+    let tray_locked = dev_lock.tray_locked;
     nb_sectors /= block_size / DEFAULT_SECTOR_SIZE;
 
     debug!(
@@ -1227,10 +1593,22 @@ fn scsi_command_emulate_mode_sense(
     if page_code == 0x3f {
         // 3Fh Return all pages not including subpages.
         for pg in 0..page_code {
-            let _ = scsi_command_emulate_mode_sense_page(pg, page_control, &mut outbuf, scsi_type);
+            let _ = scsi_command_emulate_mode_sense_page(
+                pg,
+                page_control,
+                &mut outbuf,
+                scsi_type,
+                tray_locked,
+            );
         }
     } else {
-        scsi_command_emulate_mode_sense_page(page_code, page_control, &mut outbuf, scsi_type)?;
+        scsi_command_emulate_mode_sense_page(
+            page_code,
+            page_control,
+            &mut outbuf,
+            scsi_type,
+            tray_locked,
+        )?;
     }
 
     // The Mode Data Length field indicates the length in bytes of the following data
@@ -1252,6 +1630,7 @@ fn scsi_command_emulate_mode_sense_page(
     page_control: u8,
     outbuf: &mut Vec<u8>,
     scsi_type: u32,
+    tray_locked: bool,
 ) -> Result<Vec<u8>> {
     if scsi_type == SCSI_TYPE_DISK
         && ![
@@ -1340,8 +1719,11 @@ fn scsi_command_emulate_mode_sense_page(
             outbuf[buflen + 2] = 0x3b;
             outbuf[buflen + 4] = 0x7f;
             outbuf[buflen + 5] = 0xff;
-            // Stratovirt does not implement tray for CD, so set "Lock State" to 0.
             outbuf[buflen + 6] = 0x2d;
+            if tray_locked {
+                // Lock State.
+                outbuf[buflen + 6] |= 0x2;
+            }
             BigEndian::write_u16(&mut outbuf[(buflen + 10)..(buflen + 12)], 2);
             BigEndian::write_u16(&mut outbuf[(buflen + 12)..(buflen + 14)], 2048);
         }
@@ -1554,7 +1936,9 @@ fn scsi_command_emulate_get_configuration(
     // Bytes[4-5]: Reserved.
     // Bytes[6-7]: Current Profile.
     BigEndian::write_u32(&mut outbuf[0..4], 36);
-    let current = if dev_lock.disk_sectors > CD_MAX_SECTORS as u64 {
+    let current = if !dev_lock.medium_available() {
+        GC_PROFILE_NONE
+    } else if dev_lock.disk_sectors > CD_MAX_SECTORS as u64 {
         GC_PROFILE_DVD_ROM
     } else {
         GC_PROFILE_CD_ROM
@@ -1617,7 +2001,7 @@ fn scsi_command_emulate_get_event_status_notification(
 ) -> Result<Vec<u8>> {
     // Byte4: Notification Class Request.
     let notification_class_request = cmd.buf[4];
-    let dev_lock = dev.lock().unwrap();
+    let mut dev_lock = dev.lock().unwrap();
 
     if dev_lock.scsi_type != SCSI_TYPE_ROM {
         bail!("Invalid scsi type {}", dev_lock.scsi_type);
@@ -1650,11 +2034,14 @@ fn scsi_command_emulate_get_event_status_notification(
         // Byte5: Media Status. Bits[2-7] reserved. Bit 1: Media Present. Bit 0: Door or Tray open.
         // Byte6: Start Slot.
         // Byte7: End Slot.
-
-        // Do not support hot-plug/hot-unplug scsi cd which will be present all the time once vm starts.
-        // To do: this outbuf event code and media status should be changed after allowing hot-plug.
-        outbuf[4] = GESN_EC_NOCHG;
-        outbuf[5] = 1 << GESN_MS_MEDIA_PRESENT_BIT;
+        outbuf[4] = dev_lock.media_event;
+        dev_lock.media_event = GESN_EC_NOCHG;
+        if dev_lock.tray_open {
+            outbuf[5] |= 1 << GESN_MS_DOOR_OR_TRAY_OPEN_BIT;
+        }
+        if dev_lock.block_backend.is_some() {
+            outbuf[5] |= 1 << GESN_MS_MEDIA_PRESENT_BIT;
+        }
     } else {
         // NCE = 1.
         outbuf[2] = 0x80;
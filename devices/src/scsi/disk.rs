@@ -13,11 +13,18 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, Weak};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
 
-use crate::ScsiBus::{aio_complete_cb, ScsiBus, ScsiCompleteCb};
-use block_backend::{create_block_backend, BlockDriverOps, BlockProperty};
-use machine_manager::config::{DriveFile, ScsiDevConfig, VmConfig};
+use crate::ScsiBus::{
+    aio_complete_cb, ScsiBus, ScsiCompleteCb, ScsiSense, GESN_EC_EJECTREQUEST,
+    GESN_EC_MEDIAREMOVAL, GESN_EC_NEWMEDIA, GESN_EC_NOCHG, SCSI_SENSE_MEDIUM_CHANGED,
+    SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM,
+};
+use crate::ScsiGeneric::{ScsiPassthroughMode, SgDevice};
+use crate::ScsiPr::PersistentReservation;
+use block_backend::{create_block_backend, remove_block_backend, BlockDriverOps, BlockProperty};
+use machine_manager::config::{DiskFormat, DriveFile, ScsiDevConfig, VmConfig};
 use util::aio::{Aio, WriteZeroesState};
 
 /// SCSI DEVICE TYPES.
@@ -55,6 +62,31 @@ pub const SCSI_DISK_DEFAULT_BLOCK_SIZE: u32 = 1 << SCSI_DISK_DEFAULT_BLOCK_SIZE_
 pub const SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT: u32 = 11;
 pub const SCSI_CDROM_DEFAULT_BLOCK_SIZE: u32 = 1 << SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT;
 
+/// Removable scsi devices whose medium can be ejected or changed by QMP, indexed by drive id.
+static REMOVABLE_DEVICES: Lazy<Mutex<HashMap<String, Weak<Mutex<ScsiDevice>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn register_removable_device(dev: &Arc<Mutex<ScsiDevice>>) {
+    let id = dev.lock().unwrap().config.id.clone();
+    REMOVABLE_DEVICES
+        .lock()
+        .unwrap()
+        .insert(id, Arc::downgrade(dev));
+}
+
+pub fn unregister_removable_device(id: &str) {
+    REMOVABLE_DEVICES.lock().unwrap().remove(id);
+}
+
+/// Get the removable scsi device by drive id.
+pub fn get_removable_device(id: &str) -> Option<Arc<Mutex<ScsiDevice>>> {
+    REMOVABLE_DEVICES
+        .lock()
+        .unwrap()
+        .get(id)
+        .and_then(|dev| dev.upgrade())
+}
+
 #[derive(Clone, Default)]
 pub struct ScsiDevState {
     /// Features which the scsi device supports.
@@ -84,6 +116,15 @@ impl ScsiDevState {
     }
 }
 
+/// Block backend of a medium opened from image file.
+struct Medium {
+    backend: Arc<Mutex<dyn BlockDriverOps<ScsiCompleteCb>>>,
+    drive_id: String,
+    req_align: u32,
+    buf_align: u32,
+    disk_sectors: u64,
+}
+
 pub struct ScsiDevice {
     /// Configuration of the scsi device.
     pub config: ScsiDevConfig,
@@ -105,8 +146,28 @@ pub struct ScsiDevice {
     pub parent_bus: Weak<Mutex<ScsiBus>>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// Drive id of the block backend.
+    drive_id: String,
     /// Aio context.
     pub aio: Option<Arc<Mutex<Aio<ScsiCompleteCb>>>>,
+    /// Iothread which the block backend uses.
+    iothread: Option<String>,
+    /// The tray of removable device is open, and the medium can not be accessed.
+    pub tray_open: bool,
+    /// Medium removal is prevented by the guest.
+    pub tray_locked: bool,
+    /// Media event code reported by GET EVENT STATUS NOTIFICATION.
+    pub media_event: u8,
+    /// Unit attention condition which will be reported to the next command.
+    pub unit_attention: Option<ScsiSense>,
+    /// Sense of the last failed command, reported by REQUEST SENSE.
+    pub sense: Option<ScsiSense>,
+    /// The host scsi device is passed through to guest in this mode.
+    pub passthrough: Option<ScsiPassthroughMode>,
+    /// Host scsi device which the passthrough commands are sent to.
+    pub sg: Option<SgDevice>,
+    /// Persistent reservation shared by all the users of the image.
+    pub pr: Option<PersistentReservation>,
 }
 
 // SAFETY: the devices attached in one scsi controller will process IO in the same thread.
@@ -130,11 +191,32 @@ impl ScsiDevice {
             scsi_type,
             parent_bus: Weak::new(),
             drive_files,
+            drive_id: String::new(),
             aio: None,
+            iothread: None,
+            tray_open: false,
+            tray_locked: false,
+            media_event: GESN_EC_NOCHG,
+            unit_attention: None,
+            sense: None,
+            passthrough: None,
+            sg: None,
+            pr: None,
         }
     }
 
     pub fn realize(&mut self, iothread: Option<String>) -> Result<()> {
+        if let Some(mode) = self.passthrough {
+            if self.config.pr_file.is_some() {
+                bail!(
+                    "Passthrough scsi device {} does not support pr-file, reservations are \
+                    handled by the host device",
+                    self.config.id
+                );
+            }
+            return self.realize_passthrough(mode, iothread);
+        }
+
         match self.scsi_type {
             SCSI_TYPE_DISK => {
                 self.block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE;
@@ -143,6 +225,7 @@ impl ScsiDevice {
             SCSI_TYPE_ROM => {
                 self.block_size = SCSI_CDROM_DEFAULT_BLOCK_SIZE;
                 self.state.product = "STRA CDROM".to_string();
+                self.state.features |= 1 << SCSI_DISK_F_REMOVABLE;
             }
             _ => {
                 bail!("Scsi type {} does not support now", self.scsi_type);
@@ -152,24 +235,108 @@ impl ScsiDevice {
         if let Some(serial) = &self.config.serial {
             self.state.serial = serial.clone();
         }
+        // Device id is reported in the device identification VPD page, which is used by
+        // multipath to find the paths of the same logical unit. It's empty if neither
+        // serial nor wwn is configured.
+        if let Some(serial) = &self.config.serial {
+            self.state.device_id = serial.clone();
+        } else if self.config.wwn.is_some() {
+            self.state.device_id = self.config.id.clone();
+        }
+
+        if let Some(pr_file) = &self.config.pr_file {
+            if self.scsi_type != SCSI_TYPE_DISK {
+                bail!(
+                    "Persistent reservation is only supported by scsi-hd, device {}",
+                    self.config.id
+                );
+            }
+            let nexus = self.config.pr_id.with_context(|| {
+                format!(
+                    "pr-id is required by pr-file of scsi device {}",
+                    self.config.id
+                )
+            })?;
+            self.pr = Some(PersistentReservation::new(pr_file, nexus)?);
+        }
+
+        self.iothread = iothread;
+        self.open_block_backend()
+    }
+
+    /// Pass through the host scsi device in `config.path_on_host`. The device type and
+    /// block size are the host device's.
+    fn realize_passthrough(
+        &mut self,
+        mode: ScsiPassthroughMode,
+        iothread: Option<String>,
+    ) -> Result<()> {
+        if self.config.read_only {
+            bail!(
+                "Passthrough scsi device {} does not support read only drive",
+                self.config.id
+            );
+        }
+        if self.config.format != DiskFormat::Raw {
+            bail!(
+                "Passthrough scsi device {} only supports raw format",
+                self.config.id
+            );
+        }
 
         let drive_files = self.drive_files.lock().unwrap();
-        // File path can not be empty string. And it has also been checked in CmdParser::parse.
         let file = VmConfig::fetch_drive_file(&drive_files, &self.config.path_on_host)?;
+        drop(drive_files);
+        let sg = SgDevice::new(file).with_context(|| {
+            format!(
+                "Failed to pass through host scsi device {}",
+                self.config.path_on_host
+            )
+        })?;
+        self.scsi_type = sg.device_type()?;
+
+        if mode == ScsiPassthroughMode::Block {
+            if self.scsi_type != SCSI_TYPE_DISK && self.scsi_type != SCSI_TYPE_ROM {
+                bail!(
+                    "scsi-block does not support scsi type {}, use scsi-generic instead",
+                    self.scsi_type
+                );
+            }
+            self.iothread = iothread;
+            self.open_block_backend()?;
+        }
+
+        self.block_size = sg.block_size(self.scsi_type);
+        self.sg = Some(sg);
+        Ok(())
+    }
 
-        let alignments = VmConfig::fetch_drive_align(&drive_files, &self.config.path_on_host)?;
-        self.req_align = alignments.0;
-        self.buf_align = alignments.1;
-        let drive_id = VmConfig::get_drive_id(&drive_files, &self.config.path_on_host)?;
+    /// Open the block backend with the image file in `config.path_on_host`.
+    fn open_block_backend(&mut self) -> Result<()> {
+        let medium = self.open_medium(&self.config.path_on_host, self.config.format)?;
+        self.insert_medium(medium);
+
+        Ok(())
+    }
+
+    /// Open the image file `path` as block backend, the file must have been registered
+    /// in drive files.
+    fn open_medium(&self, path: &str, format: DiskFormat) -> Result<Medium> {
+        let drive_files = self.drive_files.lock().unwrap();
+        // File path can not be empty string. And it has also been checked in CmdParser::parse.
+        let file = VmConfig::fetch_drive_file(&drive_files, path)?;
+
+        let (req_align, buf_align) = VmConfig::fetch_drive_align(&drive_files, path)?;
+        let drive_id = VmConfig::get_drive_id(&drive_files, path)?;
 
         let aio = Aio::new(Arc::new(aio_complete_cb), self.config.aio_type)?;
         let conf = BlockProperty {
-            id: drive_id,
-            format: self.config.format,
-            iothread,
+            id: drive_id.clone(),
+            format,
+            iothread: self.iothread.clone(),
             direct: self.config.direct,
-            req_align: self.req_align,
-            buf_align: self.buf_align,
+            req_align,
+            buf_align,
             discard: false,
             write_zeroes: WriteZeroesState::Off,
             l2_cache_size: self.config.l2_cache_size,
@@ -177,9 +344,120 @@ impl ScsiDevice {
         };
         let backend = create_block_backend(file, aio, conf)?;
         let disk_size = backend.lock().unwrap().disk_size()?;
-        self.block_backend = Some(backend);
-        self.disk_sectors = disk_size >> SECTOR_SHIFT;
 
+        Ok(Medium {
+            backend,
+            drive_id,
+            req_align,
+            buf_align,
+            disk_sectors: disk_size >> SECTOR_SHIFT,
+        })
+    }
+
+    fn insert_medium(&mut self, medium: Medium) {
+        self.block_backend = Some(medium.backend);
+        self.drive_id = medium.drive_id;
+        self.req_align = medium.req_align;
+        self.buf_align = medium.buf_align;
+        self.disk_sectors = medium.disk_sectors;
+    }
+
+    pub fn is_removable(&self) -> bool {
+        self.state.features & (1 << SCSI_DISK_F_REMOVABLE) != 0
+    }
+
+    /// Whether the medium is inserted and can be accessed.
+    pub fn medium_available(&self) -> bool {
+        self.block_backend.is_some() && !self.tray_open
+    }
+
+    /// Eject the medium of removable device.
+    ///
+    /// # Arguments
+    ///
+    /// * `force` - Eject the medium even if the guest has locked the tray.
+    ///
+    /// Return the image file path of the ejected medium.
+    pub fn eject(&mut self, force: bool) -> Result<Option<String>> {
+        self.check_tray(force)?;
+        self.tray_open = true;
+        self.remove_medium(None)
+    }
+
+    /// Check the medium of device can be ejected or changed.
+    fn check_tray(&mut self, force: bool) -> Result<()> {
+        if !self.is_removable() {
+            bail!("Device {} is not removable", self.config.id);
+        }
+        if self.tray_locked && !force {
+            // Tell the guest that the user requests to eject the medium.
+            self.media_event = GESN_EC_EJECTREQUEST;
+            bail!("Device {} is locked, use force to eject it", self.config.id);
+        }
         Ok(())
     }
+
+    /// Close the block backend of current medium, and return its image file path.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_drive_id` - Drive id of the new medium which replaces this one, the
+    ///   block backend registered with this id belongs to the new medium.
+    fn remove_medium(&mut self, new_drive_id: Option<&str>) -> Result<Option<String>> {
+        let backend = match self.block_backend.take() {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        let mut locked_backend = backend.lock().unwrap();
+        // Must drain requests before unregister.
+        locked_backend.drain_request();
+        locked_backend.flush_request()?;
+        locked_backend.unregister_io_event()?;
+        drop(locked_backend);
+        if new_drive_id != Some(self.drive_id.as_str()) {
+            remove_block_backend(&self.drive_id);
+        }
+
+        self.disk_sectors = 0;
+        self.media_event = GESN_EC_MEDIAREMOVAL;
+        self.unit_attention = Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM);
+        Ok(Some(std::mem::take(&mut self.config.path_on_host)))
+    }
+
+    /// Replace the medium of removable device with a new one and close the tray.
+    /// The new image file is opened before the old medium is ejected, so the old
+    /// one is kept if the new one can't be opened. The image file must have been
+    /// registered in drive files.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Image file path of the new medium.
+    /// * `format` - Image file format of the new medium.
+    /// * `force` - Eject the old medium even if the guest has locked the tray.
+    ///
+    /// Return the image file path of the ejected medium.
+    pub fn change_medium(
+        &mut self,
+        path: &str,
+        format: DiskFormat,
+        force: bool,
+    ) -> Result<Option<String>> {
+        self.check_tray(force)?;
+        let medium = self.open_medium(path, format)?;
+        let old_path = match self.remove_medium(Some(&medium.drive_id)) {
+            Ok(old_path) => old_path,
+            Err(e) => {
+                remove_block_backend(&medium.drive_id);
+                return Err(e);
+            }
+        };
+
+        self.insert_medium(medium);
+        self.config.path_on_host = path.to_string();
+        self.config.format = format;
+        self.tray_open = false;
+        self.media_event = GESN_EC_NEWMEDIA;
+        self.unit_attention = Some(SCSI_SENSE_MEDIUM_CHANGED);
+        Ok(old_path)
+    }
 }
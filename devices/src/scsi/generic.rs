@@ -0,0 +1,737 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Passthrough of the host scsi device by the SG_IO ioctl.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{debug, error, warn};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+use crate::ScsiBus::{
+    ScsiCommand, ScsiSense, ScsiXferMode, BUSY, CHECK_CONDITION, GOOD, INQUIRY, MODE_SELECT,
+    MODE_SENSE, MODE_SENSE_10, READ_CAPACITY_10, RESERVATION_CONFLICT, SCSI_CMD_BUF_SIZE,
+    SCSI_SENSE_BUF_SIZE, SCSI_SENSE_COMMAND_ABORTED, SCSI_SENSE_COMMAND_TIMEOUT,
+    SCSI_SENSE_INVALID_FIELD, SCSI_SENSE_IO_ERROR, SCSI_SENSE_I_T_NEXUS_LOSS,
+    SCSI_SENSE_LUN_NOT_RESPONDING, SCSI_SENSE_NO_MEDIUM, SCSI_SENSE_RESET,
+    SCSI_SENSE_TARGET_FAILURE, SERVICE_ACTION_IN_16, SUBCODE_READ_CAPACITY_16,
+};
+use crate::ScsiDisk::{
+    SCSI_CDROM_DEFAULT_BLOCK_SIZE, SCSI_DISK_DEFAULT_BLOCK_SIZE, SCSI_TYPE_ROM, SCSI_TYPE_TAPE,
+};
+use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use util::aio::{iov_from_buf_direct, iov_to_buf_direct, Iovec};
+use util::loop_context::{read_fd, EventNotifier, NotifierCallback, NotifierOperation};
+
+ioctl_io_nr!(SG_GET_VERSION_NUM, 0x22, 0x82);
+ioctl_io_nr!(SG_IO, 0x22, 0x85);
+ioctl_io_nr!(BLKSECTGET, 0x12, 103);
+
+/// Interface id of sg_io_hdr, 'S' for SCSI generic.
+const SG_INTERFACE_ID: i32 = b'S' as i32;
+/// Data transfer direction of SG_IO.
+const SG_DXFER_NONE: i32 = -1;
+const SG_DXFER_TO_DEV: i32 = -2;
+const SG_DXFER_FROM_DEV: i32 = -3;
+/// Max number of iovecs in one SG_IO request, see UIO_MAXIOV in <linux/uio.h>.
+const SG_MAX_IOVEC_COUNT: usize = 1024;
+/// The sg driver older than 3.0 does not support SG_IO.
+const SG_MIN_VERSION: i32 = 30000;
+/// Timeout of SG_IO in milliseconds. Tape commands such as ERASE may take hours, and
+/// they are executed by the worker thread, so the timeout is long.
+const SG_TIMEOUT_MS: u32 = 24 * 60 * 60 * 1000;
+/// Timeout of SG_IO in milliseconds for the commands which probe the host device.
+const SG_PROBE_TIMEOUT_MS: u32 = 30 * 1000;
+
+/// Host status of SG_IO, see DID_* in <scsi/scsi_status.h>.
+const SG_ERR_DID_OK: u16 = 0x00;
+const SG_ERR_DID_NO_CONNECT: u16 = 0x01;
+const SG_ERR_DID_BUS_BUSY: u16 = 0x02;
+const SG_ERR_DID_TIME_OUT: u16 = 0x03;
+const SG_ERR_DID_BAD_TARGET: u16 = 0x04;
+const SG_ERR_DID_ABORT: u16 = 0x05;
+const SG_ERR_DID_RESET: u16 = 0x08;
+const SG_ERR_DID_TRANSPORT_DISRUPTED: u16 = 0x0e;
+const SG_ERR_DID_TARGET_FAILURE: u16 = 0x10;
+const SG_ERR_DID_NEXUS_FAILURE: u16 = 0x11;
+
+/// Driver status of SG_IO, see DRIVER_* in <scsi/scsi_status.h>.
+const SG_ERR_DRIVER_TIMEOUT: u16 = 0x06;
+const SG_ERR_DRIVER_SENSE: u16 = 0x08;
+const SG_ERR_DRIVER_MASK: u16 = 0x0f;
+
+/// Length of the standard INQUIRY data used to probe the device type.
+const SG_INQUIRY_LEN: usize = 36;
+/// VPD page code of Block Limits.
+const VPD_PAGE_BLOCK_LIMITS: u8 = 0xb0;
+
+/// See struct sg_io_hdr in <scsi/sg.h>.
+#[repr(C)]
+#[derive(Default)]
+struct SgIoHdr {
+    /// 'S' for SCSI generic (required).
+    interface_id: i32,
+    /// Data transfer direction.
+    dxfer_direction: i32,
+    /// SCSI command length (<= 16 bytes).
+    cmd_len: u8,
+    /// Max length to write to sbp.
+    mx_sb_len: u8,
+    /// 0 implies no scatter gather.
+    iovec_count: u16,
+    /// Byte count of data transfer.
+    dxfer_len: u32,
+    /// Points to data transfer memory or scatter gather list.
+    dxferp: u64,
+    /// Points to command to perform.
+    cmdp: u64,
+    /// Points to sense buffer memory.
+    sbp: u64,
+    /// MAX_UINT -> no timeout (unit: millisec).
+    timeout: u32,
+    flags: u32,
+    pack_id: i32,
+    usr_ptr: u64,
+    /// SCSI status.
+    status: u8,
+    /// Shifted, masked scsi status.
+    masked_status: u8,
+    /// Messaging level data (optional).
+    msg_status: u8,
+    /// Byte count actually written to sbp.
+    sb_len_wr: u8,
+    /// Errors from host adapter.
+    host_status: u16,
+    /// Errors from software driver.
+    driver_status: u16,
+    /// dxfer_len - actual_transferred.
+    resid: i32,
+    /// Time taken by cmd (unit: millisec).
+    duration: u32,
+    /// Auxiliary information.
+    info: u32,
+}
+
+/// Result of a command which is passed through to the host device.
+pub struct SgIoResponse {
+    /// SAM status of the command.
+    pub status: u8,
+    /// Sense data returned by the host device, or built for the host errors.
+    pub sense: Vec<u8>,
+    /// Residual bytes of the data transfer.
+    pub resid: u32,
+}
+
+/// How the scsi device passes through the host scsi device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScsiPassthroughMode {
+    /// scsi-generic: all the commands are sent to the host device by SG_IO.
+    Generic,
+    /// scsi-block: READ/WRITE commands use the block backend, others are sent by SG_IO.
+    Block,
+}
+
+/// Callback which completes the command in the event loop with the result of SG_IO.
+pub type SgIoCompleteFunc = Box<dyn FnOnce(SgIoResponse) + Send>;
+
+/// Command which is sent to the worker thread of the host device.
+struct SgIoJob {
+    cdb: Vec<u8>,
+    mode: ScsiXferMode,
+    iovec: Vec<Iovec>,
+    xfer: u32,
+    complete: SgIoCompleteFunc,
+}
+
+/// Commands executed by the worker thread, which wait to be completed in the event loop.
+struct SgIoCompletions {
+    done: Mutex<Vec<(SgIoCompleteFunc, SgIoResponse)>>,
+    evt: EventFd,
+}
+
+/// The host scsi device, /dev/sgN for scsi-generic or /dev/sdX for scsi-block.
+pub struct SgDevice {
+    file: Arc<File>,
+    /// Max transfer length in bytes which the host device supports, 0 means no limit.
+    pub max_xfer_len: u32,
+    /// SG_IO may block for a long time, so the commands are executed by the worker thread,
+    /// and completed in the event loop by the eventfd of the completions.
+    sender: Sender<SgIoJob>,
+    completions: Arc<SgIoCompletions>,
+    /// Iothread in which the commands are completed.
+    iothread: Option<String>,
+    delete_evts: Vec<RawFd>,
+}
+
+impl SgDevice {
+    pub fn new(file: File) -> Result<Self> {
+        let mut version: i32 = 0;
+        // SAFETY: file is valid and version is a valid i32.
+        let ret = unsafe { ioctl_with_mut_ref(&file, SG_GET_VERSION_NUM(), &mut version) };
+        if ret < 0 || version < SG_MIN_VERSION {
+            bail!(
+                "Not a scsi generic device or the sg driver is too old: version {}, error {:?}",
+                version,
+                io::Error::last_os_error()
+            );
+        }
+
+        let max_xfer_len = Self::get_max_xfer_len(&file);
+        debug!(
+            "sg driver version {}, max transfer length {}",
+            version, max_xfer_len
+        );
+
+        let file = Arc::new(file);
+        let completions = Arc::new(SgIoCompletions {
+            done: Mutex::new(Vec::new()),
+            evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        });
+        let (sender, receiver) = channel();
+        let worker_file = file.clone();
+        let worker_completions = completions.clone();
+        thread::Builder::new()
+            .name("scsi generic worker".to_string())
+            .spawn(move || sg_io_worker(&worker_file, receiver, &worker_completions))
+            .with_context(|| "Failed to create the worker thread of scsi generic device")?;
+
+        Ok(SgDevice {
+            file,
+            max_xfer_len,
+            sender,
+            completions,
+            iothread: None,
+            delete_evts: Vec::new(),
+        })
+    }
+
+    fn get_max_xfer_len(file: &File) -> u32 {
+        let is_block = file
+            .metadata()
+            .map(|m| m.file_type().is_block_device())
+            .unwrap_or(false);
+        // BLKSECTGET returns the max sectors in unsigned short for block device,
+        // and the max bytes in int for sg device.
+        if is_block {
+            let mut max_sectors: u16 = 0;
+            // SAFETY: file is valid and max_sectors is a valid u16.
+            let ret = unsafe { ioctl_with_mut_ref(file, BLKSECTGET(), &mut max_sectors) };
+            if ret == 0 {
+                return (max_sectors as u32) << 9;
+            }
+        } else {
+            let mut max_bytes: i32 = 0;
+            // SAFETY: file is valid and max_bytes is a valid i32.
+            let ret = unsafe { ioctl_with_mut_ref(file, BLKSECTGET(), &mut max_bytes) };
+            if ret == 0 && max_bytes > 0 {
+                return max_bytes as u32;
+            }
+        }
+        0
+    }
+
+    /// Register the eventfd of the completions in the event loop of `iothread`.
+    pub fn register_io_event(&mut self, iothread: Option<&String>) -> Result<()> {
+        let notifiers = completion_notifiers(self.completions.clone());
+        self.iothread = iothread.cloned();
+        register_event_helper(notifiers, self.iothread.as_ref(), &mut self.delete_evts)
+    }
+
+    pub fn unregister_io_event(&mut self) -> Result<()> {
+        unregister_event_helper(self.iothread.as_ref(), &mut self.delete_evts)
+    }
+
+    /// Send the command to the worker thread, and `complete` is called in the event loop
+    /// after the host device completes the command.
+    pub fn submit(
+        &self,
+        cdb: &[u8],
+        mode: &ScsiXferMode,
+        iovec: Vec<Iovec>,
+        xfer: u32,
+        complete: SgIoCompleteFunc,
+    ) -> Result<()> {
+        let job = SgIoJob {
+            cdb: cdb.to_vec(),
+            mode: mode.clone(),
+            iovec,
+            xfer,
+            complete,
+        };
+        if self.sender.send(job).is_err() {
+            bail!("The worker thread of scsi generic device exited");
+        }
+        Ok(())
+    }
+
+    /// Execute the command which reads data from the host device into `buf`.
+    fn read_command(&self, cdb: &[u8], buf: &mut [u8]) -> Result<()> {
+        let mut sense = [0_u8; SCSI_SENSE_BUF_SIZE];
+        let mut hdr = SgIoHdr {
+            interface_id: SG_INTERFACE_ID,
+            dxfer_direction: SG_DXFER_FROM_DEV,
+            cmd_len: cdb.len() as u8,
+            mx_sb_len: sense.len() as u8,
+            dxfer_len: buf.len() as u32,
+            dxferp: buf.as_mut_ptr() as u64,
+            cmdp: cdb.as_ptr() as u64,
+            sbp: sense.as_mut_ptr() as u64,
+            timeout: SG_PROBE_TIMEOUT_MS,
+            ..Default::default()
+        };
+        sg_io(&self.file, &mut hdr)
+            .with_context(|| format!("Failed to execute scsi command {:#x}", cdb[0]))?;
+        let (status, _) = sg_io_status(hdr.status, hdr.host_status, hdr.driver_status);
+        if status != GOOD {
+            bail!(
+                "Scsi command {:#x} failed, status {:#x}, host status {:#x}, driver status {:#x}",
+                cdb[0],
+                hdr.status,
+                hdr.host_status,
+                hdr.driver_status
+            );
+        }
+        Ok(())
+    }
+    /// Get the peripheral device type of the host device by INQUIRY.
+    pub fn device_type(&self) -> Result<u32> {
+        let cdb = [INQUIRY, 0, 0, 0, SG_INQUIRY_LEN as u8, 0];
+        let mut buf = [0_u8; SG_INQUIRY_LEN];
+        self.read_command(&cdb, &mut buf)?;
+        // Byte0: Bits[5-7]: Peripheral Qualifier. Bits[0-4]: Peripheral Device Type.
+        if buf[0] >> 5 != 0 {
+            bail!("No peripheral device is connected to the host scsi device");
+        }
+        Ok((buf[0] & 0x1f) as u32)
+    }
+
+    /// Get the block size of the host device. Use the default block size of the device type
+    /// if the block size can not be read, e.g. the medium is not inserted.
+    pub fn block_size(&self, scsi_type: u32) -> u32 {
+        let mut cdb = [0_u8; SCSI_CMD_BUF_SIZE];
+        let mut buf = [0_u8; 12];
+        let (cdb_len, data_len) = if scsi_type == SCSI_TYPE_TAPE {
+            // MODE SENSE(6) returns the block descriptor of the sequential-access device.
+            cdb[0] = MODE_SENSE;
+            cdb[4] = buf.len() as u8;
+            (6, buf.len())
+        } else {
+            cdb[0] = READ_CAPACITY_10;
+            (10, 8)
+        };
+
+        let default = match scsi_type {
+            SCSI_TYPE_TAPE => 0,
+            SCSI_TYPE_ROM => SCSI_CDROM_DEFAULT_BLOCK_SIZE,
+            _ => SCSI_DISK_DEFAULT_BLOCK_SIZE,
+        };
+        if let Err(e) = self.read_command(&cdb[..cdb_len], &mut buf[..data_len]) {
+            debug!("Use default block size {}: {:?}", default, e);
+            return default;
+        }
+        block_size_from_data(&cdb, scsi_type, &buf[..data_len]).unwrap_or(default)
+    }
+
+    /// Get the new block size of the host device from the completed command.
+    pub fn snoop_block_size(
+        &self,
+        cmd: &ScsiCommand,
+        scsi_type: u32,
+        iovec: &[Iovec],
+        len: u32,
+    ) -> Option<u32> {
+        if !block_size_reported(&cmd.buf, scsi_type) {
+            return None;
+        }
+        let mut buf = vec![0_u8; std::cmp::min(len, 32) as usize];
+        let size = iov_to_buf_direct(iovec, 0, &mut buf).ok()?;
+        block_size_from_data(&cmd.buf, scsi_type, &buf[..size])
+    }
+
+    /// Limit the max transfer length in the Block Limits VPD page to the host device's.
+    pub fn fix_block_limits(&self, cmd: &ScsiCommand, iovec: &[Iovec], len: u32, block_size: u32) {
+        // Byte1: Bit0: EVPD. Byte2: Page Code.
+        if cmd.op != INQUIRY
+            || cmd.buf[1] & 1 == 0
+            || cmd.buf[2] != VPD_PAGE_BLOCK_LIMITS
+            || self.max_xfer_len == 0
+            || block_size == 0
+            || len < 16
+        {
+            return;
+        }
+
+        let mut buf = [0_u8; 16];
+        if iov_to_buf_direct(iovec, 0, &mut buf).map_or(true, |size| size < buf.len()) {
+            return;
+        }
+        limit_block_limits(&mut buf, self.max_xfer_len / block_size);
+        if let Err(e) = iov_from_buf_direct(iovec, &buf) {
+            warn!("Failed to fix block limits vpd page: {:?}", e);
+        }
+    }
+}
+
+/// Build the notifier which completes the executed commands in the event loop.
+fn completion_notifiers(completions: Arc<SgIoCompletions>) -> Vec<EventNotifier> {
+    let fd = completions.evt.as_raw_fd();
+    let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+        read_fd(fd);
+        let done = std::mem::take(&mut *completions.done.lock().unwrap());
+        for (complete, resp) in done {
+            complete(resp);
+        }
+        None
+    });
+    vec![EventNotifier::new(
+        NotifierOperation::AddShared,
+        fd,
+        None,
+        EventSet::IN,
+        vec![handler],
+    )]
+}
+
+/// Execute the commands until the device is dropped, which closes the channel.
+fn sg_io_worker(file: &File, receiver: Receiver<SgIoJob>, completions: &SgIoCompletions) {
+    while let Ok(job) = receiver.recv() {
+        let resp = sg_execute(file, &job.cdb, &job.mode, &job.iovec, job.xfer);
+        completions.done.lock().unwrap().push((job.complete, resp));
+        if let Err(e) = completions.evt.write(1) {
+            error!("Failed to notify the completion of scsi command: {:?}", e);
+        }
+    }
+}
+
+fn sg_io(file: &File, hdr: &mut SgIoHdr) -> io::Result<()> {
+    // SAFETY: file is valid, and the buffers which hdr points to are valid until
+    // the ioctl returns.
+    let ret = unsafe { ioctl_with_mut_ref(file, SG_IO(), hdr) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Execute the command in the host device and wait for its completion.
+fn sg_execute(
+    file: &File,
+    cdb: &[u8],
+    mode: &ScsiXferMode,
+    iovec: &[Iovec],
+    xfer: u32,
+) -> SgIoResponse {
+    let mut sense = [0_u8; SCSI_SENSE_BUF_SIZE];
+    let iov: Vec<libc::iovec> = iovec
+        .iter()
+        .map(|iov| libc::iovec {
+            iov_base: iov.iov_base as *mut libc::c_void,
+            iov_len: iov.iov_len as libc::size_t,
+        })
+        .collect();
+    let dxfer_direction = match mode {
+        _ if xfer == 0 || iov.is_empty() => SG_DXFER_NONE,
+        ScsiXferMode::ScsiXferToDev => SG_DXFER_TO_DEV,
+        ScsiXferMode::ScsiXferFromDev => SG_DXFER_FROM_DEV,
+        ScsiXferMode::ScsiXferNone => SG_DXFER_NONE,
+    };
+    if dxfer_direction != SG_DXFER_NONE && iov.len() > SG_MAX_IOVEC_COUNT {
+        warn!(
+            "Too many iovecs {} for scsi command {:#x}",
+            iov.len(),
+            cdb[0]
+        );
+        return SgIoResponse {
+            status: CHECK_CONDITION,
+            sense: SCSI_SENSE_INVALID_FIELD.to_fixed_format(),
+            resid: xfer,
+        };
+    }
+    let mut hdr = SgIoHdr {
+        interface_id: SG_INTERFACE_ID,
+        dxfer_direction,
+        cmd_len: cdb.len() as u8,
+        mx_sb_len: sense.len() as u8,
+        timeout: SG_TIMEOUT_MS,
+        cmdp: cdb.as_ptr() as u64,
+        sbp: sense.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    if dxfer_direction != SG_DXFER_NONE {
+        hdr.iovec_count = iov.len() as u16;
+        hdr.dxfer_len = xfer;
+        hdr.dxferp = iov.as_ptr() as u64;
+    }
+
+    let (status, host_sense) = match sg_io(file, &mut hdr) {
+        Ok(()) => sg_io_status(hdr.status, hdr.host_status, hdr.driver_status),
+        Err(e) => {
+            warn!("Failed to execute scsi command {:#x}: {:?}", cdb[0], e);
+            sense_from_errno(e.raw_os_error().unwrap_or(0))
+        }
+    };
+    let sense = match host_sense {
+        Some(s) => s.to_fixed_format(),
+        None if status == CHECK_CONDITION => sense[..hdr.sb_len_wr as usize].to_vec(),
+        None => Vec::new(),
+    };
+
+    SgIoResponse {
+        status,
+        sense,
+        resid: clamp_resid(hdr.resid, xfer),
+    }
+}
+
+fn clamp_resid(resid: i32, xfer: u32) -> u32 {
+    std::cmp::min(std::cmp::max(resid, 0) as u32, xfer)
+}
+
+/// Translate the status of SG_IO into SAM status. The sense is built for the errors
+/// of the host adapter or driver, in which case the device provides no sense.
+fn sg_io_status(status: u8, host_status: u16, driver_status: u16) -> (u8, Option<ScsiSense>) {
+    match host_status {
+        SG_ERR_DID_OK => {}
+        SG_ERR_DID_NO_CONNECT | SG_ERR_DID_BAD_TARGET => {
+            return (CHECK_CONDITION, Some(SCSI_SENSE_LUN_NOT_RESPONDING))
+        }
+        SG_ERR_DID_BUS_BUSY => return (BUSY, None),
+        SG_ERR_DID_TIME_OUT => return (CHECK_CONDITION, Some(SCSI_SENSE_COMMAND_TIMEOUT)),
+        SG_ERR_DID_ABORT => return (CHECK_CONDITION, Some(SCSI_SENSE_COMMAND_ABORTED)),
+        SG_ERR_DID_RESET => return (CHECK_CONDITION, Some(SCSI_SENSE_RESET)),
+        SG_ERR_DID_TRANSPORT_DISRUPTED => {
+            return (CHECK_CONDITION, Some(SCSI_SENSE_I_T_NEXUS_LOSS))
+        }
+        SG_ERR_DID_TARGET_FAILURE => return (CHECK_CONDITION, Some(SCSI_SENSE_TARGET_FAILURE)),
+        SG_ERR_DID_NEXUS_FAILURE => return (RESERVATION_CONFLICT, None),
+        _ => return (CHECK_CONDITION, Some(SCSI_SENSE_IO_ERROR)),
+    }
+
+    if driver_status & SG_ERR_DRIVER_MASK == SG_ERR_DRIVER_TIMEOUT {
+        (CHECK_CONDITION, Some(SCSI_SENSE_COMMAND_TIMEOUT))
+    } else if status != GOOD {
+        (status, None)
+    } else if driver_status & SG_ERR_DRIVER_SENSE != 0 {
+        (CHECK_CONDITION, None)
+    } else {
+        (GOOD, None)
+    }
+}
+
+fn sense_from_errno(errno: i32) -> (u8, Option<ScsiSense>) {
+    match errno {
+        libc::EBUSY => (BUSY, None),
+        libc::ENOMEDIUM => (CHECK_CONDITION, Some(SCSI_SENSE_NO_MEDIUM)),
+        libc::ENOMEM => (CHECK_CONDITION, Some(SCSI_SENSE_TARGET_FAILURE)),
+        libc::EINVAL => (CHECK_CONDITION, Some(SCSI_SENSE_INVALID_FIELD)),
+        _ => (CHECK_CONDITION, Some(SCSI_SENSE_IO_ERROR)),
+    }
+}
+
+/// Whether the data of the command reports the block size of the device.
+fn block_size_reported(cdb: &[u8; SCSI_CMD_BUF_SIZE], scsi_type: u32) -> bool {
+    match cdb[0] {
+        READ_CAPACITY_10 => true,
+        SERVICE_ACTION_IN_16 => cdb[1] & 0x1f == SUBCODE_READ_CAPACITY_16,
+        MODE_SENSE | MODE_SENSE_10 | MODE_SELECT => scsi_type == SCSI_TYPE_TAPE,
+        _ => false,
+    }
+}
+
+fn block_size_from_data(cdb: &[u8; SCSI_CMD_BUF_SIZE], scsi_type: u32, data: &[u8]) -> Option<u32> {
+    if !block_size_reported(cdb, scsi_type) {
+        return None;
+    }
+    match cdb[0] {
+        // Bytes[4-7]: Logical Block Length In Bytes.
+        READ_CAPACITY_10 if data.len() >= 8 => Some(BigEndian::read_u32(&data[4..])),
+        // Bytes[8-11]: Logical Block Length In Bytes.
+        SERVICE_ACTION_IN_16 if data.len() >= 12 => Some(BigEndian::read_u32(&data[8..])),
+        // Mode parameter header(6) is 4 bytes, Byte3: Block Descriptor Length.
+        // Block descriptor Bytes[5-7]: Block Length.
+        MODE_SENSE | MODE_SELECT if data.len() >= 12 && data[3] >= 8 => {
+            Some(BigEndian::read_u32(&data[8..]) & 0xff_ffff)
+        }
+        // Mode parameter header(10) is 8 bytes, Bytes[6-7]: Block Descriptor Length.
+        MODE_SENSE_10 if data.len() >= 16 && BigEndian::read_u16(&data[6..]) >= 8 => {
+            Some(BigEndian::read_u32(&data[12..]) & 0xff_ffff)
+        }
+        _ => None,
+    }
+}
+
+fn limit_block_limits(page: &mut [u8; 16], max_blocks: u32) {
+    // Bytes[8-11]: Maximum Transfer Length. Bytes[12-15]: Optimal Transfer Length.
+    BigEndian::write_u32(&mut page[8..], max_blocks);
+    let optimal = BigEndian::read_u32(&page[12..]);
+    if optimal == 0 || optimal > max_blocks {
+        BigEndian::write_u32(&mut page[12..], max_blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScsiBus::{MEDIUM_ERROR, UNIT_ATTENTION};
+    use crate::ScsiDisk::SCSI_TYPE_DISK;
+
+    #[test]
+    fn test_sg_io_status() {
+        assert_eq!(sg_io_status(GOOD, 0, 0), (GOOD, None));
+        assert_eq!(
+            sg_io_status(CHECK_CONDITION, 0, SG_ERR_DRIVER_SENSE),
+            (CHECK_CONDITION, None)
+        );
+        assert_eq!(
+            sg_io_status(GOOD, 0, SG_ERR_DRIVER_SENSE),
+            (CHECK_CONDITION, None)
+        );
+        assert_eq!(
+            sg_io_status(RESERVATION_CONFLICT, 0, 0),
+            (RESERVATION_CONFLICT, None)
+        );
+        assert_eq!(
+            sg_io_status(GOOD, SG_ERR_DID_NEXUS_FAILURE, 0),
+            (RESERVATION_CONFLICT, None)
+        );
+        assert_eq!(sg_io_status(GOOD, SG_ERR_DID_BUS_BUSY, 0), (BUSY, None));
+        let (status, sense) = sg_io_status(GOOD, SG_ERR_DID_TIME_OUT, 0);
+        assert_eq!(status, CHECK_CONDITION);
+        assert_eq!(sense.unwrap(), SCSI_SENSE_COMMAND_TIMEOUT);
+        let (status, sense) = sg_io_status(GOOD, 0, SG_ERR_DRIVER_TIMEOUT);
+        assert_eq!(status, CHECK_CONDITION);
+        assert_eq!(sense.unwrap(), SCSI_SENSE_COMMAND_TIMEOUT);
+        let (_, sense) = sg_io_status(GOOD, 0x7f, 0);
+        assert_eq!(sense.unwrap(), SCSI_SENSE_IO_ERROR);
+    }
+
+    #[test]
+    fn test_sense_from_raw() {
+        // Fixed format, medium error.
+        let mut fixed = [0_u8; 18];
+        fixed[0] = 0x70;
+        fixed[2] = MEDIUM_ERROR;
+        fixed[7] = 10;
+        fixed[12] = 0x11;
+        fixed[13] = 0x01;
+        let sense = ScsiSense::from_raw(&fixed).unwrap();
+        assert_eq!(
+            (sense.key, sense.asc, sense.ascq),
+            (MEDIUM_ERROR, 0x11, 0x01)
+        );
+
+        // Descriptor format, unit attention.
+        let desc = [0x72, UNIT_ATTENTION, 0x29, 0x00, 0, 0, 0, 0];
+        let sense = ScsiSense::from_raw(&desc).unwrap();
+        assert_eq!(sense, SCSI_SENSE_RESET);
+
+        assert!(ScsiSense::from_raw(&[]).is_none());
+        assert!(ScsiSense::from_raw(&fixed[..8]).is_none());
+        assert!(ScsiSense::from_raw(&[0x7f; 18]).is_none());
+    }
+
+    #[test]
+    fn test_block_size_from_data() {
+        let mut cdb = [0_u8; SCSI_CMD_BUF_SIZE];
+        cdb[0] = READ_CAPACITY_10;
+        let data = [0, 0, 0xff, 0xff, 0, 0, 0x10, 0];
+        assert_eq!(
+            block_size_from_data(&cdb, SCSI_TYPE_DISK, &data),
+            Some(4096)
+        );
+        assert_eq!(block_size_from_data(&cdb, SCSI_TYPE_DISK, &data[..4]), None);
+
+        cdb[0] = SERVICE_ACTION_IN_16;
+        cdb[1] = SUBCODE_READ_CAPACITY_16;
+        let mut data = [0_u8; 32];
+        data[10] = 0x02;
+        assert_eq!(block_size_from_data(&cdb, SCSI_TYPE_DISK, &data), Some(512));
+
+        // Block descriptor of the tape is only used for the sequential-access device.
+        cdb = [0; SCSI_CMD_BUF_SIZE];
+        cdb[0] = MODE_SENSE;
+        let data = [11, 0, 0x10, 8, 0x00, 0, 0, 0, 0, 0x01, 0x00, 0x00];
+        assert_eq!(block_size_from_data(&cdb, SCSI_TYPE_DISK, &data), None);
+        assert_eq!(
+            block_size_from_data(&cdb, SCSI_TYPE_TAPE, &data),
+            Some(0x10000)
+        );
+        let mut no_desc = data;
+        no_desc[3] = 0;
+        assert_eq!(block_size_from_data(&cdb, SCSI_TYPE_TAPE, &no_desc), None);
+
+        cdb[0] = MODE_SENSE_10;
+        let mut data = [0_u8; 16];
+        data[7] = 8;
+        data[14] = 0x08;
+        assert_eq!(
+            block_size_from_data(&cdb, SCSI_TYPE_TAPE, &data),
+            Some(2048)
+        );
+    }
+
+    #[test]
+    fn test_limit_block_limits() {
+        let mut page = [0_u8; 16];
+        page[1] = VPD_PAGE_BLOCK_LIMITS;
+        BigEndian::write_u32(&mut page[12..], 64);
+        limit_block_limits(&mut page, 256);
+        assert_eq!(BigEndian::read_u32(&page[8..]), 256);
+        assert_eq!(BigEndian::read_u32(&page[12..]), 64);
+
+        BigEndian::write_u32(&mut page[12..], 1024);
+        limit_block_limits(&mut page, 128);
+        assert_eq!(BigEndian::read_u32(&page[8..]), 128);
+        assert_eq!(BigEndian::read_u32(&page[12..]), 128);
+    }
+
+    #[test]
+    fn test_resid() {
+        assert_eq!(clamp_resid(-1, 512), 0);
+        assert_eq!(clamp_resid(100, 512), 100);
+        assert_eq!(clamp_resid(1024, 512), 512);
+    }
+
+    #[test]
+    fn test_sg_execute_too_many_iovecs() {
+        // The request is failed before SG_IO, so the file is never used.
+        let file = File::open("/dev/null").unwrap();
+        let mut buf = [0_u8; SG_MAX_IOVEC_COUNT + 1];
+        let iovec: Vec<Iovec> = buf
+            .iter_mut()
+            .map(|b| Iovec::new(b as *mut u8 as u64, 1))
+            .collect();
+        let cdb = [0x28_u8, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let resp = sg_execute(
+            &file,
+            &cdb,
+            &ScsiXferMode::ScsiXferFromDev,
+            &iovec,
+            buf.len() as u32,
+        );
+        assert_eq!(resp.status, CHECK_CONDITION);
+        assert_eq!(resp.sense, SCSI_SENSE_INVALID_FIELD.to_fixed_format());
+        assert_eq!(resp.resid, buf.len() as u32);
+    }
+}
@@ -12,3 +12,5 @@
 
 pub mod bus;
 pub mod disk;
+pub mod generic;
+pub mod pr;
@@ -0,0 +1,830 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Emulation of SCSI persistent reservations (SPC-4 5.12).
+//!
+//! The reservation state of an image is stored in a state file shared by all the devices
+//! which use the image, whether they are in the same VM or not. Every device is an I_T nexus
+//! identified by the configured SAS address, so that the registrations and the reservation
+//! are kept after the VM restarts. Commands which modify the state hold the exclusive flock
+//! of the state file while reading and writing it.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::ScsiBus::{
+    ScsiSense, READ_10, READ_12, READ_16, READ_6, SCSI_SENSE_INVALID_FIELD,
+    SCSI_SENSE_INVALID_PARAM, SCSI_SENSE_INVALID_PARAM_LEN, SCSI_SENSE_INVALID_RELEASE,
+    SYNCHRONIZE_CACHE, SYNCHRONIZE_CACHE_16, WRITE_10, WRITE_12, WRITE_16, WRITE_6,
+    WRITE_VERIFY_10, WRITE_VERIFY_12, WRITE_VERIFY_16,
+};
+use util::aio::{iov_to_buf_direct, Iovec};
+
+/// Service actions of PERSISTENT RESERVE IN.
+const PR_IN_READ_KEYS: u8 = 0x00;
+const PR_IN_READ_RESERVATION: u8 = 0x01;
+const PR_IN_REPORT_CAPABILITIES: u8 = 0x02;
+const PR_IN_READ_FULL_STATUS: u8 = 0x03;
+
+/// Service actions of PERSISTENT RESERVE OUT.
+const PR_OUT_REGISTER: u8 = 0x00;
+const PR_OUT_RESERVE: u8 = 0x01;
+const PR_OUT_RELEASE: u8 = 0x02;
+const PR_OUT_CLEAR: u8 = 0x03;
+const PR_OUT_PREEMPT: u8 = 0x04;
+const PR_OUT_PREEMPT_AND_ABORT: u8 = 0x05;
+const PR_OUT_REGISTER_AND_IGNORE: u8 = 0x06;
+
+/// Persistent reservation types.
+pub const PR_TYPE_WRITE_EXCLUSIVE: u8 = 0x01;
+pub const PR_TYPE_EXCLUSIVE_ACCESS: u8 = 0x03;
+pub const PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY: u8 = 0x05;
+pub const PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY: u8 = 0x06;
+pub const PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS: u8 = 0x07;
+pub const PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS: u8 = 0x08;
+
+/// Length of the PERSISTENT RESERVE OUT parameter list without transport IDs.
+const PR_OUT_PARAM_LEN: usize = 24;
+/// Byte20 of PERSISTENT RESERVE OUT parameter list. Bit3: SPEC_I_PT(Specify Initiator Ports).
+const PR_OUT_SPEC_I_PT: u8 = 0x08;
+
+/// Length of the SAS TransportID.
+const PR_TRANSPORT_ID_LEN: usize = 24;
+/// Protocol identifier of SAS serial SCSI protocol.
+const PR_PROTOCOL_ID_SAS: u8 = 0x06;
+/// Only one target port in the scsi controller.
+const PR_RELATIVE_TARGET_PORT: u16 = 1;
+/// Length of the change counter at the beginning of the state file, which is followed by
+/// the state in JSON. The counter is increased by every change of the state.
+const PR_FILE_COUNTER_LEN: usize = 8;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PrError {
+    #[error("Reservation conflict")]
+    Conflict,
+    #[error("Invalid persistent reservation command, sense {0:?}")]
+    CheckCondition(ScsiSense),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct PrRegistrant {
+    /// SAS address of the I_T nexus.
+    nexus: u64,
+    /// Registered reservation key.
+    key: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct PrReservation {
+    /// The I_T nexus which creates the reservation.
+    holder: u64,
+    pr_type: u8,
+}
+
+/// Persistent reservation state of the logical unit, which is stored in the state file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct PrState {
+    /// PRgeneration, increased by the commands which change the registrations.
+    generation: u32,
+    registrants: Vec<PrRegistrant>,
+    reservation: Option<PrReservation>,
+}
+
+fn valid_pr_type(pr_type: u8) -> bool {
+    matches!(
+        pr_type,
+        PR_TYPE_WRITE_EXCLUSIVE
+            | PR_TYPE_EXCLUSIVE_ACCESS
+            | PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY
+            | PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY
+            | PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS
+            | PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS
+    )
+}
+
+/// All the registrants are the reservation holders for "All Registrants" types.
+fn all_registrants_type(pr_type: u8) -> bool {
+    pr_type == PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS || pr_type == PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS
+}
+
+/// The commands which are only allowed for the holders of the Exclusive Access types.
+fn is_read_op(op: u8) -> bool {
+    matches!(
+        op,
+        READ_6 | READ_10 | READ_12 | READ_16 | SYNCHRONIZE_CACHE | SYNCHRONIZE_CACHE_16
+    )
+}
+
+fn is_write_op(op: u8) -> bool {
+    matches!(
+        op,
+        WRITE_6
+            | WRITE_10
+            | WRITE_12
+            | WRITE_16
+            | WRITE_VERIFY_10
+            | WRITE_VERIFY_12
+            | WRITE_VERIFY_16
+    )
+}
+
+impl PrState {
+    fn key_of(&self, nexus: u64) -> Option<u64> {
+        self.registrants
+            .iter()
+            .find(|r| r.nexus == nexus)
+            .map(|r| r.key)
+    }
+
+    fn is_holder(&self, nexus: u64) -> bool {
+        match &self.reservation {
+            Some(res) if all_registrants_type(res.pr_type) => self.key_of(nexus).is_some(),
+            Some(res) => res.holder == nexus,
+            None => false,
+        }
+    }
+
+    /// Key of the reservation reported to the initiators. It's zero for "All Registrants" types.
+    fn reservation_key(&self) -> u64 {
+        match &self.reservation {
+            Some(res) if !all_registrants_type(res.pr_type) => self.key_of(res.holder).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Whether the command `op` from `nexus` conflicts with the reservation.
+    fn is_conflict(&self, nexus: u64, op: u8) -> bool {
+        let res = match &self.reservation {
+            Some(res) => res,
+            None => return false,
+        };
+        let write = is_write_op(op);
+        let read = is_read_op(op);
+        let registered = self.key_of(nexus).is_some();
+        match res.pr_type {
+            PR_TYPE_WRITE_EXCLUSIVE => write && res.holder != nexus,
+            PR_TYPE_EXCLUSIVE_ACCESS => (write || read) && res.holder != nexus,
+            PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY | PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS => {
+                write && !registered
+            }
+            PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY | PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS => {
+                (write || read) && !registered
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the registrations which match `pred`, and release the reservation if
+    /// no holder is left. Return the number of the removed registrations.
+    fn unregister<F: Fn(&PrRegistrant) -> bool>(&mut self, pred: F) -> usize {
+        let old_len = self.registrants.len();
+        self.registrants.retain(|r| !pred(r));
+        if let Some(res) = &self.reservation {
+            let released = if all_registrants_type(res.pr_type) {
+                self.registrants.is_empty()
+            } else {
+                self.key_of(res.holder).is_none()
+            };
+            if released {
+                self.reservation = None;
+            }
+        }
+        old_len - self.registrants.len()
+    }
+
+    /// Check that `nexus` is registered with `key`.
+    fn check_key(&self, nexus: u64, key: u64) -> Result<(), PrError> {
+        match self.key_of(nexus) {
+            Some(k) if k == key => Ok(()),
+            _ => Err(PrError::Conflict),
+        }
+    }
+
+    fn register(&mut self, nexus: u64, key: u64, sa_key: u64, ignore: bool) -> Result<(), PrError> {
+        match self.key_of(nexus) {
+            None => {
+                if !ignore && key != 0 {
+                    return Err(PrError::Conflict);
+                }
+                if sa_key == 0 {
+                    // Nothing to do for unregistering an unregistered I_T nexus.
+                    return Ok(());
+                }
+                self.registrants.push(PrRegistrant { nexus, key: sa_key });
+            }
+            Some(old_key) => {
+                if !ignore && key != old_key {
+                    return Err(PrError::Conflict);
+                }
+                if sa_key == 0 {
+                    self.unregister(|r| r.nexus == nexus);
+                } else {
+                    for r in self.registrants.iter_mut().filter(|r| r.nexus == nexus) {
+                        r.key = sa_key;
+                    }
+                }
+            }
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    fn reserve(&mut self, nexus: u64, key: u64, pr_type: u8) -> Result<(), PrError> {
+        self.check_key(nexus, key)?;
+        match &self.reservation {
+            Some(res) if self.is_holder(nexus) && res.pr_type == pr_type => Ok(()),
+            Some(_) => Err(PrError::Conflict),
+            None => {
+                self.reservation = Some(PrReservation {
+                    holder: nexus,
+                    pr_type,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn release(&mut self, nexus: u64, key: u64, pr_type: u8) -> Result<(), PrError> {
+        self.check_key(nexus, key)?;
+        match &self.reservation {
+            Some(res) if self.is_holder(nexus) => {
+                if res.pr_type != pr_type {
+                    return Err(PrError::CheckCondition(SCSI_SENSE_INVALID_RELEASE));
+                }
+                self.reservation = None;
+                Ok(())
+            }
+            // Releasing the reservation held by others or no reservation is not an error.
+            _ => Ok(()),
+        }
+    }
+
+    fn clear(&mut self, nexus: u64, key: u64) -> Result<(), PrError> {
+        self.check_key(nexus, key)?;
+        self.registrants.clear();
+        self.reservation = None;
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    fn preempt(&mut self, nexus: u64, key: u64, sa_key: u64, pr_type: u8) -> Result<(), PrError> {
+        self.check_key(nexus, key)?;
+        let preempt_reservation = match &self.reservation {
+            Some(res) if all_registrants_type(res.pr_type) => sa_key == 0,
+            Some(_) => sa_key == self.reservation_key(),
+            None => false,
+        };
+
+        if preempt_reservation {
+            if !valid_pr_type(pr_type) {
+                return Err(PrError::CheckCondition(SCSI_SENSE_INVALID_FIELD));
+            }
+            if sa_key == 0 {
+                self.unregister(|r| r.nexus != nexus);
+            } else {
+                self.unregister(|r| r.nexus != nexus && r.key == sa_key);
+            }
+            self.reservation = Some(PrReservation {
+                holder: nexus,
+                pr_type,
+            });
+        } else {
+            if sa_key == 0 {
+                return Err(PrError::CheckCondition(SCSI_SENSE_INVALID_PARAM));
+            }
+            if self.unregister(|r| r.nexus != nexus && r.key == sa_key) == 0 {
+                return Err(PrError::Conflict);
+            }
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Execute PERSISTENT RESERVE OUT `action` from `nexus`.
+    fn reserve_out(
+        &mut self,
+        nexus: u64,
+        action: u8,
+        pr_type: u8,
+        key: u64,
+        sa_key: u64,
+    ) -> Result<(), PrError> {
+        let type_required = matches!(action, PR_OUT_RESERVE | PR_OUT_RELEASE);
+        if type_required && !valid_pr_type(pr_type) {
+            return Err(PrError::CheckCondition(SCSI_SENSE_INVALID_FIELD));
+        }
+
+        match action {
+            PR_OUT_REGISTER => self.register(nexus, key, sa_key, false),
+            PR_OUT_REGISTER_AND_IGNORE => self.register(nexus, key, sa_key, true),
+            PR_OUT_RESERVE => self.reserve(nexus, key, pr_type),
+            PR_OUT_RELEASE => self.release(nexus, key, pr_type),
+            PR_OUT_CLEAR => self.clear(nexus, key),
+            // Tasks of other I_T nexuses can not be aborted, they are completed by their VMs.
+            PR_OUT_PREEMPT | PR_OUT_PREEMPT_AND_ABORT => self.preempt(nexus, key, sa_key, pr_type),
+            // REGISTER AND MOVE is not supported, as the TransportIDs of other VMs are unknown.
+            _ => Err(PrError::CheckCondition(SCSI_SENSE_INVALID_FIELD)),
+        }
+    }
+
+    /// Build the parameter data of PERSISTENT RESERVE IN `action`.
+    fn reserve_in(&self, action: u8) -> Result<Vec<u8>, PrError> {
+        let mut outbuf: Vec<u8> = vec![0; 8];
+        match action {
+            PR_IN_READ_KEYS => {
+                // Bytes[0-3]: PRgeneration. Bytes[4-7]: Additional length.
+                BigEndian::write_u32(&mut outbuf[0..4], self.generation);
+                for r in self.registrants.iter() {
+                    outbuf.extend_from_slice(&r.key.to_be_bytes());
+                }
+            }
+            PR_IN_READ_RESERVATION => {
+                BigEndian::write_u32(&mut outbuf[0..4], self.generation);
+                if let Some(res) = &self.reservation {
+                    // Bytes[8-15]: Reservation key. Bytes[16-19]: Obsolete.
+                    // Byte20: Reserved. Byte21: Bits[4-7]: Scope. Bits[0-3]: Type.
+                    // Bytes[22-23]: Obsolete.
+                    outbuf.extend_from_slice(&self.reservation_key().to_be_bytes());
+                    outbuf.extend_from_slice(&[0, 0, 0, 0, 0, res.pr_type & 0xf, 0, 0]);
+                }
+            }
+            PR_IN_REPORT_CAPABILITIES => {
+                // Bytes[0-1]: Length.
+                BigEndian::write_u16(&mut outbuf[0..2], 8);
+                // Byte2: Bit0: PTPL_C(Persist Through Power Loss Capable).
+                outbuf[2] = 0x01;
+                // Byte3: Bit7: TMV(Type Mask Valid). Bit0: PTPL_A(Persist Through Power Loss
+                // Activated). The state file always persists.
+                outbuf[3] = 0x81;
+                // Bytes[4-5]: Persistent reservation type mask.
+                // Byte4: Bit7: WR_EX_AR. Bit6: EX_AC_RO. Bit5: WR_EX_RO. Bit3: EX_AC. Bit1: WR_EX.
+                // Byte5: Bit0: EX_AC_AR.
+                outbuf[4] = 0xea;
+                outbuf[5] = 0x01;
+                return Ok(outbuf);
+            }
+            PR_IN_READ_FULL_STATUS => {
+                BigEndian::write_u32(&mut outbuf[0..4], self.generation);
+                for r in self.registrants.iter() {
+                    let mut desc = vec![0_u8; 24 + PR_TRANSPORT_ID_LEN];
+                    // Bytes[0-7]: Reservation key.
+                    BigEndian::write_u64(&mut desc[0..8], r.key);
+                    // Byte12: Bit1: ALL_TG_PT. Bit0: R_HOLDER.
+                    // Byte13: Bits[4-7]: Scope. Bits[0-3]: Type, valid if R_HOLDER is set.
+                    if self.is_holder(r.nexus) {
+                        desc[12] = 0x01;
+                        desc[13] = self.reservation.as_ref().unwrap().pr_type & 0xf;
+                    }
+                    // Bytes[18-19]: Relative target port identifier.
+                    BigEndian::write_u16(&mut desc[18..20], PR_RELATIVE_TARGET_PORT);
+                    // Bytes[20-23]: Additional descriptor length.
+                    BigEndian::write_u32(&mut desc[20..24], PR_TRANSPORT_ID_LEN as u32);
+                    // TransportID: Byte0: Bits[6-7]: Format code. Bits[0-3]: Protocol identifier.
+                    // Bytes[4-11]: SAS address.
+                    desc[24] = PR_PROTOCOL_ID_SAS;
+                    BigEndian::write_u64(&mut desc[28..36], r.nexus);
+                    outbuf.append(&mut desc);
+                }
+            }
+            _ => return Err(PrError::CheckCondition(SCSI_SENSE_INVALID_FIELD)),
+        }
+        // Bytes[4-7]: Additional length.
+        let len = (outbuf.len() - 8) as u32;
+        BigEndian::write_u32(&mut outbuf[4..8], len);
+        Ok(outbuf)
+    }
+}
+
+/// Holding the flock of the reservation state file until dropped.
+struct PrFileLock<'a> {
+    file: &'a File,
+}
+
+impl<'a> PrFileLock<'a> {
+    fn new(file: &'a File, path: &str, exclusive: bool) -> Result<Self> {
+        let op = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // SAFETY: the file has a valid raw fd.
+        let ret = unsafe { libc::flock(file.as_raw_fd(), op) };
+        if ret < 0 {
+            bail!(
+                "Failed to lock persistent reservation file {}: {}",
+                path,
+                io::Error::last_os_error()
+            );
+        }
+        Ok(PrFileLock { file })
+    }
+}
+
+impl<'a> Drop for PrFileLock<'a> {
+    fn drop(&mut self) {
+        // SAFETY: the file has a valid raw fd.
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Persistent reservation of a scsi disk, shared with all the I_T nexuses which use the
+/// same state file.
+pub struct PersistentReservation {
+    /// The reservation state file.
+    file: File,
+    path: String,
+    /// SAS address which identifies the I_T nexus of this device.
+    nexus: u64,
+    /// The state read from the state file, and the change counter of the state file.
+    cache: Mutex<Option<(u64, PrState)>>,
+}
+
+impl PersistentReservation {
+    pub fn new(path: &str, nexus: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open persistent reservation file {}", path))?;
+        let pr = PersistentReservation {
+            file,
+            path: path.to_string(),
+            nexus,
+            cache: Mutex::new(None),
+        };
+        // Check that the existing state file is valid.
+        let lock = PrFileLock::new(&pr.file, path, false)?;
+        pr.read_state()?;
+        drop(lock);
+        Ok(pr)
+    }
+
+    /// Read the change counter of the state file, which does not need the flock.
+    fn read_counter(&self) -> Result<u64> {
+        let mut buf = [0_u8; PR_FILE_COUNTER_LEN];
+        if self.file.metadata()?.len() < PR_FILE_COUNTER_LEN as u64 {
+            return Ok(0);
+        }
+        self.file.read_exact_at(&mut buf, 0)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Read the change counter and the state of the state file. The flock of it must be held.
+    fn read_state(&self) -> Result<(u64, PrState)> {
+        let len = self.file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok((0, PrState::default()));
+        }
+        if len < PR_FILE_COUNTER_LEN {
+            bail!("Invalid persistent reservation file {}", self.path);
+        }
+        let mut buf = vec![0_u8; len];
+        self.file.read_exact_at(&mut buf, 0)?;
+        let mut counter = [0_u8; PR_FILE_COUNTER_LEN];
+        counter.copy_from_slice(&buf[..PR_FILE_COUNTER_LEN]);
+        let state = serde_json::from_slice(&buf[PR_FILE_COUNTER_LEN..]).with_context(|| {
+            format!("Invalid state in persistent reservation file {}", self.path)
+        })?;
+        Ok((u64::from_le_bytes(counter), state))
+    }
+
+    /// Write the state file. The exclusive flock of it must be held.
+    fn write_state(&self, counter: u64, state: &PrState) -> Result<()> {
+        let mut buf = counter.to_le_bytes().to_vec();
+        buf.append(&mut serde_json::to_vec(state)?);
+        self.file.write_all_at(&buf, 0)?;
+        self.file.set_len(buf.len() as u64)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Whether the command `op` conflicts with the reservation held by other I_T nexuses.
+    /// The state file may be changed by other VMs at any time, so the cached state is used
+    /// only if the change counter of the state file is not changed.
+    pub fn check_conflict(&self, op: u8) -> Result<bool> {
+        if !is_read_op(op) && !is_write_op(op) {
+            return Ok(false);
+        }
+        let counter = self.read_counter()?;
+        let mut cache = self.cache.lock().unwrap();
+        match cache.as_ref() {
+            Some((c, state)) if *c == counter => Ok(state.is_conflict(self.nexus, op)),
+            _ => {
+                let _lock = PrFileLock::new(&self.file, &self.path, false)?;
+                let (counter, state) = self.read_state()?;
+                let conflict = state.is_conflict(self.nexus, op);
+                *cache = Some((counter, state));
+                Ok(conflict)
+            }
+        }
+    }
+
+    /// Emulate PERSISTENT RESERVE IN with command descriptor block `cdb`.
+    pub fn reserve_in(&self, cdb: &[u8]) -> Result<Vec<u8>> {
+        // Byte1: Bits[0-4]: Service action.
+        let action = cdb[1] & 0x1f;
+        let _lock = PrFileLock::new(&self.file, &self.path, false)?;
+        Ok(self.read_state()?.1.reserve_in(action)?)
+    }
+
+    /// Emulate PERSISTENT RESERVE OUT with command descriptor block `cdb`. The parameter
+    /// list is in `iovec`.
+    pub fn reserve_out(&self, cdb: &[u8], iovec: &[Iovec]) -> Result<()> {
+        // Byte1: Bits[0-4]: Service action. Byte2: Bits[4-7]: Scope. Bits[0-3]: Type.
+        let action = cdb[1] & 0x1f;
+        let scope = cdb[2] >> 4;
+        let pr_type = cdb[2] & 0xf;
+        if scope != 0 {
+            bail!(PrError::CheckCondition(SCSI_SENSE_INVALID_FIELD));
+        }
+        // Bytes[5-8]: Parameter list length. Transport IDs are not supported, so the
+        // parameter list has fixed length.
+        let mut param = [0_u8; PR_OUT_PARAM_LEN];
+        if BigEndian::read_u32(&cdb[5..9]) as usize != PR_OUT_PARAM_LEN
+            || iov_to_buf_direct(iovec, 0, &mut param)? != PR_OUT_PARAM_LEN
+        {
+            bail!(PrError::CheckCondition(SCSI_SENSE_INVALID_PARAM_LEN));
+        }
+        // Bytes[0-7]: Reservation key. Bytes[8-15]: Service action reservation key.
+        // Byte20: Bit3: SPEC_I_PT. Bit2: ALL_TG_PT. Bit0: APTPL.
+        // There is only one target port, and the reservation always persists through
+        // power loss. So ALL_TG_PT and APTPL are ignored.
+        let key = BigEndian::read_u64(&param[0..8]);
+        let sa_key = BigEndian::read_u64(&param[8..16]);
+        if param[20] & PR_OUT_SPEC_I_PT != 0 {
+            bail!(PrError::CheckCondition(SCSI_SENSE_INVALID_PARAM));
+        }
+
+        let _lock = PrFileLock::new(&self.file, &self.path, true)?;
+        let (counter, mut state) = self.read_state()?;
+        state.reserve_out(self.nexus, action, pr_type, key, sa_key)?;
+        let counter = counter.wrapping_add(1);
+        self.write_state(counter, &state)?;
+        *self.cache.lock().unwrap() = Some((counter, state));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScsiBus::INQUIRY;
+
+    const NEXUS_A: u64 = 0x5000_0000_0000_000a;
+    const NEXUS_B: u64 = 0x5000_0000_0000_000b;
+    const NEXUS_C: u64 = 0x5000_0000_0000_000c;
+
+    fn registered_state() -> PrState {
+        let mut state = PrState::default();
+        state.register(NEXUS_A, 0, 0xa, false).unwrap();
+        state.register(NEXUS_B, 0, 0xb, false).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_pr_register() {
+        let mut state = PrState::default();
+        // Unregistered I_T nexus must use zero reservation key.
+        assert_eq!(
+            state.register(NEXUS_A, 1, 0xa, false),
+            Err(PrError::Conflict)
+        );
+        state.register(NEXUS_A, 0, 0xa, false).unwrap();
+        assert_eq!(state.key_of(NEXUS_A), Some(0xa));
+        assert_eq!(state.generation, 1);
+
+        // Change the key.
+        assert_eq!(
+            state.register(NEXUS_A, 0xb, 0xc, false),
+            Err(PrError::Conflict)
+        );
+        state.register(NEXUS_A, 0xb, 0xc, true).unwrap();
+        assert_eq!(state.key_of(NEXUS_A), Some(0xc));
+        assert_eq!(state.generation, 2);
+
+        // Unregister releases the reservation.
+        state
+            .reserve_out(NEXUS_A, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, 0xc, 0)
+            .unwrap();
+        state.register(NEXUS_A, 0xc, 0, false).unwrap();
+        assert!(state.registrants.is_empty());
+        assert!(state.reservation.is_none());
+        assert_eq!(state.generation, 3);
+    }
+
+    #[test]
+    fn test_pr_reserve_release() {
+        let mut state = registered_state();
+        assert_eq!(
+            state.reserve_out(NEXUS_C, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, 0, 0),
+            Err(PrError::Conflict)
+        );
+        assert_eq!(
+            state.reserve_out(NEXUS_A, PR_OUT_RESERVE, 0x2, 0xa, 0),
+            Err(PrError::CheckCondition(SCSI_SENSE_INVALID_FIELD))
+        );
+        state
+            .reserve_out(NEXUS_A, PR_OUT_RESERVE, PR_TYPE_EXCLUSIVE_ACCESS, 0xa, 0)
+            .unwrap();
+        // Reserve again by the holder is allowed.
+        state
+            .reserve_out(NEXUS_A, PR_OUT_RESERVE, PR_TYPE_EXCLUSIVE_ACCESS, 0xa, 0)
+            .unwrap();
+        assert_eq!(
+            state.reserve_out(NEXUS_B, PR_OUT_RESERVE, PR_TYPE_EXCLUSIVE_ACCESS, 0xb, 0),
+            Err(PrError::Conflict)
+        );
+        assert!(state.is_conflict(NEXUS_B, READ_10));
+        assert!(state.is_conflict(NEXUS_B, WRITE_10));
+        assert!(!state.is_conflict(NEXUS_A, WRITE_10));
+
+        // Release by others does nothing.
+        state
+            .reserve_out(NEXUS_B, PR_OUT_RELEASE, PR_TYPE_EXCLUSIVE_ACCESS, 0xb, 0)
+            .unwrap();
+        assert!(state.reservation.is_some());
+        assert_eq!(
+            state.reserve_out(NEXUS_A, PR_OUT_RELEASE, PR_TYPE_WRITE_EXCLUSIVE, 0xa, 0),
+            Err(PrError::CheckCondition(SCSI_SENSE_INVALID_RELEASE))
+        );
+        state
+            .reserve_out(NEXUS_A, PR_OUT_RELEASE, PR_TYPE_EXCLUSIVE_ACCESS, 0xa, 0)
+            .unwrap();
+        assert!(state.reservation.is_none());
+        assert_eq!(state.generation, 2);
+    }
+
+    #[test]
+    fn test_pr_conflict() {
+        let mut state = registered_state();
+        assert!(!state.is_conflict(NEXUS_C, WRITE_16));
+
+        state
+            .reserve_out(NEXUS_A, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, 0xa, 0)
+            .unwrap();
+        assert!(!state.is_conflict(NEXUS_B, READ_16));
+        assert!(!state.is_conflict(NEXUS_B, SYNCHRONIZE_CACHE));
+        assert!(state.is_conflict(NEXUS_B, WRITE_VERIFY_10));
+        assert!(!state.is_conflict(NEXUS_B, INQUIRY));
+
+        state.reservation.as_mut().unwrap().pr_type = PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY;
+        assert!(!state.is_conflict(NEXUS_B, WRITE_6));
+        assert!(!state.is_conflict(NEXUS_C, READ_6));
+        assert!(state.is_conflict(NEXUS_C, WRITE_6));
+
+        state.reservation.as_mut().unwrap().pr_type = PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS;
+        assert!(!state.is_conflict(NEXUS_B, READ_12));
+        assert!(state.is_conflict(NEXUS_C, READ_12));
+        assert!(!state.is_conflict(NEXUS_B, SYNCHRONIZE_CACHE_16));
+        assert!(state.is_conflict(NEXUS_C, SYNCHRONIZE_CACHE_16));
+        // All registrants are holders, the reservation is kept until all of them unregister.
+        state.register(NEXUS_A, 0xa, 0, false).unwrap();
+        assert!(state.reservation.is_some());
+        state.register(NEXUS_B, 0xb, 0, false).unwrap();
+        assert!(state.reservation.is_none());
+    }
+
+    #[test]
+    fn test_pr_preempt() {
+        let mut state = registered_state();
+        state.register(NEXUS_C, 0, 0xb, false).unwrap();
+        state
+            .reserve_out(NEXUS_B, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, 0xb, 0)
+            .unwrap();
+
+        // Preempt the holder: all registrations with the key are removed.
+        state
+            .reserve_out(
+                NEXUS_A,
+                PR_OUT_PREEMPT,
+                PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY,
+                0xa,
+                0xb,
+            )
+            .unwrap();
+        assert_eq!(state.registrants.len(), 1);
+        assert!(state.is_holder(NEXUS_A));
+        assert_eq!(state.reservation.as_ref().unwrap().pr_type, 0x6);
+        assert!(state.is_conflict(NEXUS_B, READ_10));
+        assert_eq!(state.generation, 4);
+
+        // Preempt a key which is not registered.
+        assert_eq!(
+            state.reserve_out(NEXUS_A, PR_OUT_PREEMPT, 0x6, 0xa, 0xb),
+            Err(PrError::Conflict)
+        );
+        state.register(NEXUS_B, 0, 0xb, false).unwrap();
+        assert_eq!(
+            state.reserve_out(NEXUS_A, PR_OUT_PREEMPT_AND_ABORT, 0x6, 0xa, 0),
+            Err(PrError::CheckCondition(SCSI_SENSE_INVALID_PARAM))
+        );
+        // Preempt the registration only, the reservation is kept.
+        state
+            .reserve_out(NEXUS_A, PR_OUT_PREEMPT_AND_ABORT, 0x6, 0xa, 0xb)
+            .unwrap();
+        assert_eq!(state.key_of(NEXUS_B), None);
+        assert!(state.is_holder(NEXUS_A));
+
+        state.reserve_out(NEXUS_A, PR_OUT_CLEAR, 0, 0xa, 0).unwrap();
+        assert_eq!(
+            state,
+            PrState {
+                generation: 7,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_pr_reserve_in() {
+        let mut state = registered_state();
+        state
+            .reserve_out(NEXUS_B, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, 0xb, 0)
+            .unwrap();
+
+        let keys = state.reserve_in(PR_IN_READ_KEYS).unwrap();
+        assert_eq!(keys.len(), 24);
+        assert_eq!(BigEndian::read_u32(&keys[0..4]), 2);
+        assert_eq!(BigEndian::read_u32(&keys[4..8]), 16);
+        assert_eq!(BigEndian::read_u64(&keys[8..16]), 0xa);
+        assert_eq!(BigEndian::read_u64(&keys[16..24]), 0xb);
+
+        let res = state.reserve_in(PR_IN_READ_RESERVATION).unwrap();
+        assert_eq!(res.len(), 24);
+        assert_eq!(BigEndian::read_u32(&res[4..8]), 16);
+        assert_eq!(BigEndian::read_u64(&res[8..16]), 0xb);
+        assert_eq!(res[21], PR_TYPE_WRITE_EXCLUSIVE);
+
+        let caps = state.reserve_in(PR_IN_REPORT_CAPABILITIES).unwrap();
+        assert_eq!(caps, [0, 8, 0x01, 0x81, 0xea, 0x01, 0, 0]);
+
+        let status = state.reserve_in(PR_IN_READ_FULL_STATUS).unwrap();
+        assert_eq!(status.len(), 8 + 2 * 48);
+        assert_eq!(BigEndian::read_u32(&status[4..8]), 96);
+        // The first descriptor is not the holder.
+        assert_eq!(status[8 + 12], 0);
+        assert_eq!(status[8 + 24], PR_PROTOCOL_ID_SAS);
+        assert_eq!(BigEndian::read_u64(&status[8 + 28..8 + 36]), NEXUS_A);
+        // The second descriptor is the holder.
+        assert_eq!(BigEndian::read_u64(&status[56..64]), 0xb);
+        assert_eq!(status[56 + 12], 0x01);
+        assert_eq!(status[56 + 13], PR_TYPE_WRITE_EXCLUSIVE);
+
+        assert_eq!(
+            state.reserve_in(0x4),
+            Err(PrError::CheckCondition(SCSI_SENSE_INVALID_FIELD))
+        );
+    }
+
+    #[test]
+    fn test_pr_state_file() {
+        let path = std::env::temp_dir().join(format!("scsi_pr_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let pr_a = PersistentReservation::new(path, NEXUS_A).unwrap();
+        let pr_b = PersistentReservation::new(path, NEXUS_B).unwrap();
+        assert!(!pr_a.check_conflict(WRITE_10).unwrap());
+
+        // PERSISTENT RESERVE OUT with parameter list length 24.
+        let out = |pr: &PersistentReservation, action: u8, pr_type: u8, key: u64, sa_key: u64| {
+            let cdb = [0x5f, action, pr_type, 0, 0, 0, 0, 0, 24, 0];
+            let mut param = [0_u8; PR_OUT_PARAM_LEN];
+            BigEndian::write_u64(&mut param[0..8], key);
+            BigEndian::write_u64(&mut param[8..16], sa_key);
+            let iovec = [Iovec::new(param.as_ptr() as u64, param.len() as u64)];
+            pr.reserve_out(&cdb, &iovec)
+        };
+        out(&pr_b, PR_OUT_REGISTER, 0, 0, 0xb).unwrap();
+        out(&pr_b, PR_OUT_RESERVE, PR_TYPE_EXCLUSIVE_ACCESS, 0xb, 0).unwrap();
+        // The state cached by pr_a is stale after the change by pr_b.
+        assert!(pr_a.check_conflict(READ_10).unwrap());
+        assert!(!pr_b.check_conflict(READ_10).unwrap());
+
+        // The reservation is kept for the same I_T nexus after restart.
+        drop(pr_b);
+        let pr_b = PersistentReservation::new(path, NEXUS_B).unwrap();
+        assert!(!pr_b.check_conflict(WRITE_10).unwrap());
+        out(&pr_b, PR_OUT_RELEASE, PR_TYPE_EXCLUSIVE_ACCESS, 0xb, 0).unwrap();
+        assert!(!pr_a.check_conflict(READ_10).unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,807 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    sync::{
+        mpsc::{channel, Receiver, Sender, TryRecvError},
+        Arc, Mutex, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+
+use machine_manager::config::UsbAudioConfig;
+use ui::vnc::vnc_audio_play;
+
+use super::descriptor::{
+    UsbConfigDescriptor, UsbDescConfig, UsbDescDevice, UsbDescEndpoint, UsbDescIface, UsbDescOther,
+    UsbDescriptorOps, UsbDeviceDescriptor, UsbEndpointDescriptor, UsbInterfaceDescriptor,
+};
+use super::xhci::xhci_controller::XhciDevice;
+use super::{config::*, USB_DEVICE_BUFFER_DEFAULT_LEN};
+use super::{UsbDevice, UsbDeviceOps, UsbDeviceRequest, UsbEndpoint, UsbPacket, UsbPacketStatus};
+use crate::misc::scream::{
+    create_audio_interface, AudioInterface, ScreamDirection, ShmemStreamFmt, StreamData,
+};
+
+const USB_AUDIO_VENDOR_ID: u16 = 0x46f4;
+const USB_AUDIO_PRODUCT_ID: u16 = 0x0002;
+
+/// String descriptor index
+const STR_MANUFACTURER_INDEX: u8 = 1;
+const STR_PRODUCT_AUDIO_INDEX: u8 = 2;
+const STR_SERIAL_AUDIO_INDEX: u8 = 3;
+const STR_PLAYBACK_INDEX: u8 = 4;
+const STR_CAPTURE_INDEX: u8 = 5;
+
+/// String descriptor
+const DESC_STRINGS: [&str; 6] = [
+    "",
+    "StratoVirt",
+    "StratoVirt USB Audio",
+    "5",
+    "Audio Output",
+    "Audio Input",
+];
+
+/// Audio interface subclass.
+const USB_SUBCLASS_AUDIO_CONTROL: u8 = 1;
+const USB_SUBCLASS_AUDIO_STREAMING: u8 = 2;
+
+/// Class specific descriptor type.
+const USB_DT_CS_INTERFACE: u8 = 0x24;
+const USB_DT_CS_ENDPOINT: u8 = 0x25;
+/// Audio endpoint descriptor has two more fields than the standard one.
+const USB_DT_ENDPOINT_AUDIO_SIZE: u8 = 9;
+
+/// Audio control interface descriptor subtype.
+const UAC_HEADER: u8 = 0x01;
+const UAC_INPUT_TERMINAL: u8 = 0x02;
+const UAC_OUTPUT_TERMINAL: u8 = 0x03;
+/// Audio streaming interface descriptor subtype.
+const UAC_AS_GENERAL: u8 = 0x01;
+const UAC_FORMAT_TYPE: u8 = 0x02;
+/// Audio streaming endpoint descriptor subtype.
+const UAC_EP_GENERAL: u8 = 0x01;
+
+const UAC_FORMAT_TYPE_I: u8 = 0x01;
+const UAC_FORMAT_TYPE_I_PCM: u16 = 0x0001;
+
+/// Terminal types.
+const UAC_TERMINAL_STREAMING: u16 = 0x0101;
+const UAC_INPUT_TERMINAL_MICROPHONE: u16 = 0x0201;
+const UAC_OUTPUT_TERMINAL_SPEAKER: u16 = 0x0301;
+
+/// Terminal id. The playback path is from the USB streaming to the speaker, and the
+/// capture path is from the microphone to the USB streaming.
+const PLAYBACK_INPUT_TERMINAL_ID: u8 = 1;
+const PLAYBACK_OUTPUT_TERMINAL_ID: u8 = 2;
+const CAPTURE_INPUT_TERMINAL_ID: u8 = 3;
+const CAPTURE_OUTPUT_TERMINAL_ID: u8 = 4;
+
+const INTERFACE_ID_CONTROL: u8 = 0;
+const INTERFACE_ID_PLAYBACK: u8 = 1;
+const INTERFACE_ID_CAPTURE: u8 = 2;
+
+const ENDPOINT_ID_PLAYBACK: u8 = 1;
+const ENDPOINT_ID_CAPTURE: u8 = 2;
+
+/// Isochronous endpoint synchronization type.
+const USB_ENDPOINT_SYNC_ASYNC: u8 = 1 << 2;
+const USB_ENDPOINT_SYNC_ADAPTIVE: u8 = 2 << 2;
+/// Endpoint supports the sampling frequency control.
+const UAC_EP_CS_ATTR_SAMPLE_RATE: u8 = 0x01;
+const UAC_EP_SAMPLING_FREQ_CONTROL: u8 = 0x01;
+
+/// Audio class specific request code.
+const UAC_SET_CUR: u8 = 0x01;
+const UAC_GET_CUR: u8 = 0x81;
+const UAC_GET_MIN: u8 = 0x82;
+const UAC_GET_MAX: u8 = 0x83;
+const UAC_GET_RES: u8 = 0x84;
+
+const USB_ENDPOINT_CLASS_IN_REQUEST: u8 =
+    USB_DIRECTION_DEVICE_TO_HOST | USB_TYPE_CLASS | USB_RECIPIENT_ENDPOINT;
+const USB_ENDPOINT_CLASS_OUT_REQUEST: u8 =
+    USB_DIRECTION_HOST_TO_DEVICE | USB_TYPE_CLASS | USB_RECIPIENT_ENDPOINT;
+
+/// Only 16 bits signed PCM is supported.
+const SAMPLE_SIZE: u8 = 16;
+const SAMPLE_BYTES: usize = SAMPLE_SIZE as usize / 8;
+/// The sample rate is encoded in 3 bytes.
+const SAMPLE_RATE_LEN: usize = 3;
+/// Full speed isochronous endpoint transfers one packet per 1ms frame.
+const PACKETS_PER_SECOND: u32 = 1000;
+/// The played audio is sent to the host audio interface per 10ms, and the captured
+/// audio is received from the host audio interface per 10ms too.
+const CHUNKS_PER_SECOND: u32 = 100;
+/// At most 100ms captured audio is buffered, the oldest data is dropped if the guest
+/// does not read it in time.
+const CAPTURE_BUFFER_CHUNKS: usize = 10;
+
+fn gen_ac_header_descriptor() -> Vec<u8> {
+    let total_len: u16 = 10 + 2 * (12 + 9);
+    let mut desc = vec![10, USB_DT_CS_INTERFACE, UAC_HEADER];
+    // bcdADC 1.0.
+    desc.extend_from_slice(&0x0100_u16.to_le_bytes());
+    desc.extend_from_slice(&total_len.to_le_bytes());
+    desc.extend_from_slice(&[2, INTERFACE_ID_PLAYBACK, INTERFACE_ID_CAPTURE]);
+    desc
+}
+
+fn gen_input_terminal_descriptor(id: u8, terminal_type: u16, channels: u8, name: u8) -> Vec<u8> {
+    let mut desc = vec![12, USB_DT_CS_INTERFACE, UAC_INPUT_TERMINAL, id];
+    desc.extend_from_slice(&terminal_type.to_le_bytes());
+    desc.extend_from_slice(&[0, channels]);
+    desc.extend_from_slice(&channel_config(channels).to_le_bytes());
+    desc.extend_from_slice(&[0, name]);
+    desc
+}
+
+fn gen_output_terminal_descriptor(id: u8, terminal_type: u16, source: u8, name: u8) -> Vec<u8> {
+    let mut desc = vec![9, USB_DT_CS_INTERFACE, UAC_OUTPUT_TERMINAL, id];
+    desc.extend_from_slice(&terminal_type.to_le_bytes());
+    desc.extend_from_slice(&[0, source, name]);
+    desc
+}
+
+/// Spatial locations of the channels, which are left front, right front, center
+/// front and so on. Mono channel has no spatial location.
+fn channel_config(channels: u8) -> u16 {
+    if channels == 1 {
+        0
+    } else {
+        (1_u32 << channels) as u16 - 1
+    }
+}
+
+fn gen_desc_interface_control(channels: u8) -> Arc<UsbDescIface> {
+    let other_desc = vec![
+        gen_ac_header_descriptor(),
+        gen_input_terminal_descriptor(
+            PLAYBACK_INPUT_TERMINAL_ID,
+            UAC_TERMINAL_STREAMING,
+            channels,
+            0,
+        ),
+        gen_output_terminal_descriptor(
+            PLAYBACK_OUTPUT_TERMINAL_ID,
+            UAC_OUTPUT_TERMINAL_SPEAKER,
+            PLAYBACK_INPUT_TERMINAL_ID,
+            STR_PLAYBACK_INDEX,
+        ),
+        gen_input_terminal_descriptor(
+            CAPTURE_INPUT_TERMINAL_ID,
+            UAC_INPUT_TERMINAL_MICROPHONE,
+            channels,
+            STR_CAPTURE_INDEX,
+        ),
+        gen_output_terminal_descriptor(
+            CAPTURE_OUTPUT_TERMINAL_ID,
+            UAC_TERMINAL_STREAMING,
+            CAPTURE_INPUT_TERMINAL_ID,
+            0,
+        ),
+    ];
+    Arc::new(UsbDescIface {
+        interface_desc: UsbInterfaceDescriptor {
+            bLength: USB_DT_INTERFACE_SIZE,
+            bDescriptorType: USB_DT_INTERFACE,
+            bInterfaceNumber: INTERFACE_ID_CONTROL,
+            bAlternateSetting: 0,
+            bNumEndpoints: 0,
+            bInterfaceClass: USB_CLASS_AUDIO,
+            bInterfaceSubClass: USB_SUBCLASS_AUDIO_CONTROL,
+            bInterfaceProtocol: 0,
+            iInterface: 0,
+        },
+        other_desc: other_desc
+            .into_iter()
+            .map(|data| Arc::new(UsbDescOther { data }))
+            .collect(),
+        endpoints: vec![],
+    })
+}
+
+fn gen_format_type_descriptor(channels: u8, rates: &[u32]) -> Vec<u8> {
+    let mut desc = vec![
+        (8 + SAMPLE_RATE_LEN * rates.len()) as u8,
+        USB_DT_CS_INTERFACE,
+        UAC_FORMAT_TYPE,
+        UAC_FORMAT_TYPE_I,
+        channels,
+        SAMPLE_BYTES as u8,
+        SAMPLE_SIZE,
+        rates.len() as u8,
+    ];
+    for rate in rates {
+        desc.extend_from_slice(&rate.to_le_bytes()[..SAMPLE_RATE_LEN]);
+    }
+    desc
+}
+
+/// Generate the audio streaming interface, whose alternate setting 0 has no endpoint
+/// and alternate setting 1 streams the audio.
+fn gen_desc_interface_streaming(
+    dir: ScreamDirection,
+    channels: u8,
+    rates: &[u32],
+) -> Vec<Arc<UsbDescIface>> {
+    let (nif, terminal, ep_addr, sync) = match dir {
+        ScreamDirection::Playback => (
+            INTERFACE_ID_PLAYBACK,
+            PLAYBACK_INPUT_TERMINAL_ID,
+            USB_DIRECTION_HOST_TO_DEVICE | ENDPOINT_ID_PLAYBACK,
+            USB_ENDPOINT_SYNC_ADAPTIVE,
+        ),
+        ScreamDirection::Record => (
+            INTERFACE_ID_CAPTURE,
+            CAPTURE_OUTPUT_TERMINAL_ID,
+            USB_DIRECTION_DEVICE_TO_HOST | ENDPOINT_ID_CAPTURE,
+            USB_ENDPOINT_SYNC_ASYNC,
+        ),
+    };
+    let interface_desc = |alt: u8, num_endpoints: u8| UsbInterfaceDescriptor {
+        bLength: USB_DT_INTERFACE_SIZE,
+        bDescriptorType: USB_DT_INTERFACE,
+        bInterfaceNumber: nif,
+        bAlternateSetting: alt,
+        bNumEndpoints: num_endpoints,
+        bInterfaceClass: USB_CLASS_AUDIO,
+        bInterfaceSubClass: USB_SUBCLASS_AUDIO_STREAMING,
+        bInterfaceProtocol: 0,
+        iInterface: 0,
+    };
+
+    let mut as_general = vec![7, USB_DT_CS_INTERFACE, UAC_AS_GENERAL, terminal, 1];
+    as_general.extend_from_slice(&UAC_FORMAT_TYPE_I_PCM.to_le_bytes());
+    let max_rate = rates.iter().max().copied().unwrap_or_default();
+    vec![
+        Arc::new(UsbDescIface {
+            interface_desc: interface_desc(0, 0),
+            other_desc: vec![],
+            endpoints: vec![],
+        }),
+        Arc::new(UsbDescIface {
+            interface_desc: interface_desc(1, 1),
+            other_desc: vec![
+                Arc::new(UsbDescOther { data: as_general }),
+                Arc::new(UsbDescOther {
+                    data: gen_format_type_descriptor(channels, rates),
+                }),
+            ],
+            endpoints: vec![Arc::new(UsbDescEndpoint {
+                endpoint_desc: UsbEndpointDescriptor {
+                    bLength: USB_DT_ENDPOINT_AUDIO_SIZE,
+                    bDescriptorType: USB_DT_ENDPOINT,
+                    bEndpointAddress: ep_addr,
+                    bmAttributes: USB_ENDPOINT_ATTR_ISOC | sync,
+                    wMaxPacketSize: max_packet_size(max_rate, channels) as u16,
+                    bInterval: 1,
+                },
+                // bRefresh and bSynchAddress, followed by the class specific endpoint descriptor.
+                extra: vec![
+                    0,
+                    0,
+                    7,
+                    USB_DT_CS_ENDPOINT,
+                    UAC_EP_GENERAL,
+                    UAC_EP_CS_ATTR_SAMPLE_RATE,
+                    0,
+                    0,
+                    0,
+                ],
+            })],
+        }),
+    ]
+}
+
+fn gen_desc_device_audio(channels: u8, rates: &[u32]) -> Arc<UsbDescDevice> {
+    let mut interfaces = vec![gen_desc_interface_control(channels)];
+    interfaces.append(&mut gen_desc_interface_streaming(
+        ScreamDirection::Playback,
+        channels,
+        rates,
+    ));
+    interfaces.append(&mut gen_desc_interface_streaming(
+        ScreamDirection::Record,
+        channels,
+        rates,
+    ));
+    Arc::new(UsbDescDevice {
+        device_desc: UsbDeviceDescriptor {
+            bLength: USB_DT_DEVICE_SIZE,
+            bDescriptorType: USB_DT_DEVICE,
+            idVendor: USB_AUDIO_VENDOR_ID,
+            idProduct: USB_AUDIO_PRODUCT_ID,
+            bcdDevice: 0,
+            iManufacturer: STR_MANUFACTURER_INDEX,
+            iProduct: STR_PRODUCT_AUDIO_INDEX,
+            iSerialNumber: STR_SERIAL_AUDIO_INDEX,
+            bcdUSB: 0x0200,
+            bDeviceClass: 0,
+            bDeviceSubClass: 0,
+            bDeviceProtocol: 0,
+            bMaxPacketSize0: 64,
+            bNumConfigurations: 1,
+        },
+        configs: vec![Arc::new(UsbDescConfig {
+            config_desc: UsbConfigDescriptor {
+                bLength: USB_DT_CONFIG_SIZE,
+                bDescriptorType: USB_DT_CONFIGURATION,
+                wTotalLength: 0,
+                bNumInterfaces: 3,
+                bConfigurationValue: 1,
+                iConfiguration: 0,
+                bmAttributes: USB_CONFIGURATION_ATTR_ONE,
+                bMaxPower: 50,
+            },
+            iad_desc: vec![],
+            interfaces,
+        })],
+    })
+}
+
+/// Max size of the audio data transferred in one packet.
+fn max_packet_size(rate: u32, channels: u8) -> usize {
+    ((rate + PACKETS_PER_SECOND - 1) / PACKETS_PER_SECOND) as usize * frame_size(channels)
+}
+
+fn frame_size(channels: u8) -> usize {
+    channels as usize * SAMPLE_BYTES
+}
+
+fn stream_data(fmt: ShmemStreamFmt, data: &mut [u8]) -> StreamData {
+    let mut stream_data = StreamData::default();
+    stream_data.fmt = fmt;
+    stream_data.audio_size = data.len() as u32;
+    stream_data.audio_base = data.as_mut_ptr() as u64;
+    stream_data
+}
+
+/// Send the played audio to the host audio interface until the stream is stopped.
+fn playback_worker(
+    fmt: ShmemStreamFmt,
+    interface: Arc<Mutex<dyn AudioInterface>>,
+    receiver: Receiver<Vec<u8>>,
+) {
+    while let Ok(mut data) = receiver.recv() {
+        interface.lock().unwrap().send(&stream_data(fmt, &mut data));
+        vnc_audio_play(fmt.get_rate(), fmt.size, fmt.channels, &data);
+    }
+    interface.lock().unwrap().destroy();
+}
+
+/// Receive the captured audio from the host audio interface until the stream is stopped.
+fn capture_worker(
+    fmt: ShmemStreamFmt,
+    interface: Arc<Mutex<dyn AudioInterface>>,
+    receiver: Receiver<Vec<u8>>,
+    buffer: Arc<Mutex<VecDeque<u8>>>,
+) {
+    let chunk_size = (fmt.get_rate() / CHUNKS_PER_SECOND) as usize * frame_size(fmt.channels);
+    let mut data = vec![0_u8; chunk_size];
+    while let Err(TryRecvError::Empty) = receiver.try_recv() {
+        if !interface
+            .lock()
+            .unwrap()
+            .receive(&stream_data(fmt, &mut data))
+        {
+            thread::sleep(Duration::from_millis((1000 / CHUNKS_PER_SECOND).into()));
+            continue;
+        }
+        let mut locked_buffer = buffer.lock().unwrap();
+        locked_buffer.extend(&data);
+        let max_len = chunk_size * CAPTURE_BUFFER_CHUNKS;
+        if locked_buffer.len() > max_len {
+            let drop_len = locked_buffer.len() - max_len;
+            locked_buffer.drain(..drop_len);
+        }
+    }
+    interface.lock().unwrap().destroy();
+}
+
+/// Audio stream of one direction, which transfers the audio data between the
+/// isochronous endpoint and the host audio interface.
+struct UsbAudioStream {
+    dir: ScreamDirection,
+    interface: Arc<Mutex<dyn AudioInterface>>,
+    channels: u8,
+    /// Sample rate selected by the guest.
+    rate: u32,
+    /// Generation of the stream format, which is changed when the stream is started.
+    fmt_generation: u32,
+    /// The played audio waiting to be sent to the worker, or the captured audio
+    /// waiting for the guest to read.
+    buffer: Arc<Mutex<VecDeque<u8>>>,
+    /// Fraction of the frame carried over to the next packet, in unit of 1/1000 frame.
+    frame_remainder: u32,
+    sender: Option<Sender<Vec<u8>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl UsbAudioStream {
+    fn new(dir: ScreamDirection, config: &UsbAudioConfig) -> Self {
+        let name = match dir {
+            ScreamDirection::Playback => "UsbAudioPlay",
+            ScreamDirection::Record => "UsbAudioCapt",
+        };
+        Self {
+            dir,
+            interface: create_audio_interface(
+                &config.interface,
+                name,
+                dir,
+                &config.playback,
+                &config.record,
+            ),
+            channels: config.channels,
+            rate: config.rates[0],
+            fmt_generation: 0,
+            buffer: Arc::new(Mutex::new(VecDeque::new())),
+            frame_remainder: 0,
+            sender: None,
+            worker: None,
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.fmt_generation = self.fmt_generation.wrapping_add(1);
+        let fmt = ShmemStreamFmt::new(self.fmt_generation, self.rate, SAMPLE_SIZE, self.channels)?;
+        let (sender, receiver) = channel();
+        let interface = self.interface.clone();
+        let worker = match self.dir {
+            ScreamDirection::Playback => thread::Builder::new()
+                .name("usb-audio-play".to_string())
+                .spawn(move || playback_worker(fmt, interface, receiver)),
+            ScreamDirection::Record => {
+                let buffer = self.buffer.clone();
+                thread::Builder::new()
+                    .name("usb-audio-capt".to_string())
+                    .spawn(move || capture_worker(fmt, interface, receiver, buffer))
+            }
+        }
+        .with_context(|| "Failed to create usb audio worker")?;
+        self.sender = Some(sender);
+        self.worker = Some(worker);
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Worker of usb audio stream panicked");
+            }
+        }
+        self.buffer.lock().unwrap().clear();
+        self.frame_remainder = 0;
+    }
+
+    fn set_rate(&mut self, rate: u32) -> Result<()> {
+        if self.rate == rate {
+            return Ok(());
+        }
+        self.rate = rate;
+        if self.is_started() {
+            self.stop();
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    /// Number of frames transferred in the next packet, which varies if the sample
+    /// rate is not a multiple of 1000, e.g. 44 or 45 frames for 44.1KHz.
+    fn next_packet_frames(&mut self) -> usize {
+        self.frame_remainder += self.rate;
+        let frames = self.frame_remainder / PACKETS_PER_SECOND;
+        self.frame_remainder %= PACKETS_PER_SECOND;
+        frames as usize
+    }
+
+    /// Queue the played audio, and send it to the worker per chunk.
+    fn play(&mut self, data: &[u8]) {
+        let sender = match self.sender.as_ref() {
+            Some(sender) => sender,
+            None => return,
+        };
+        let chunk_size = (self.rate / CHUNKS_PER_SECOND) as usize * frame_size(self.channels);
+        let mut locked_buffer = self.buffer.lock().unwrap();
+        locked_buffer.extend(data);
+        while locked_buffer.len() >= chunk_size {
+            let chunk = locked_buffer.drain(..chunk_size).collect();
+            if sender.send(chunk).is_err() {
+                error!("Worker of usb audio playback exits");
+                locked_buffer.clear();
+                break;
+            }
+        }
+    }
+
+    /// Get the captured audio of the next packet. Silence is filled if the captured
+    /// audio is not enough.
+    fn capture(&mut self, max_len: usize) -> Vec<u8> {
+        let len = min(
+            self.next_packet_frames() * frame_size(self.channels),
+            max_len,
+        );
+        let mut data = vec![0_u8; len];
+        let mut locked_buffer = self.buffer.lock().unwrap();
+        let copied = min(len, locked_buffer.len());
+        for (dst, src) in data.iter_mut().zip(locked_buffer.drain(..copied)) {
+            *dst = src;
+        }
+        data
+    }
+}
+
+/// USB audio device, which presents a USB Audio Class 1.0 sound card with both
+/// playback and capture to the guest.
+pub struct UsbAudio {
+    usb_device: UsbDevice,
+    rates: Vec<u32>,
+    playback: UsbAudioStream,
+    capture: UsbAudioStream,
+}
+
+impl UsbAudio {
+    pub fn new(config: UsbAudioConfig) -> Self {
+        Self {
+            usb_device: UsbDevice::new(config.id.clone().unwrap(), USB_DEVICE_BUFFER_DEFAULT_LEN),
+            rates: config.rates.clone(),
+            playback: UsbAudioStream::new(ScreamDirection::Playback, &config),
+            capture: UsbAudioStream::new(ScreamDirection::Record, &config),
+        }
+    }
+
+    /// Start or stop the streams according to the alternate setting of the interfaces.
+    fn update_streams(&mut self) {
+        let altsetting = &self.usb_device.descriptor.altsetting;
+        let playback_alt = altsetting[INTERFACE_ID_PLAYBACK as usize];
+        let capture_alt = altsetting[INTERFACE_ID_CAPTURE as usize];
+        for (stream, alt) in [
+            (&mut self.playback, playback_alt),
+            (&mut self.capture, capture_alt),
+        ] {
+            if alt != 0 && !stream.is_started() {
+                info!(
+                    "USB audio {:?} stream start, rate {}",
+                    stream.dir, stream.rate
+                );
+                if let Err(e) = stream.start() {
+                    error!("Failed to start usb audio stream: {:?}", e);
+                }
+            } else if alt == 0 && stream.is_started() {
+                info!("USB audio {:?} stream stop", stream.dir);
+                stream.stop();
+            }
+        }
+    }
+
+    fn get_stream(&mut self, ep_addr: u8) -> Option<&mut UsbAudioStream> {
+        let in_direction = ep_addr & USB_DIRECTION_DEVICE_TO_HOST == USB_DIRECTION_DEVICE_TO_HOST;
+        match (in_direction, ep_addr & USB_ENDPOINT_ADDRESS_NUMBER_MASK) {
+            (false, ENDPOINT_ID_PLAYBACK) => Some(&mut self.playback),
+            (true, ENDPOINT_ID_CAPTURE) => Some(&mut self.capture),
+            _ => None,
+        }
+    }
+
+    /// Handle the sampling frequency control of the endpoint, return the length of the data.
+    fn handle_endpoint_request(&mut self, device_req: &UsbDeviceRequest) -> Result<u32> {
+        if device_req.request_type != USB_ENDPOINT_CLASS_IN_REQUEST
+            && device_req.request_type != USB_ENDPOINT_CLASS_OUT_REQUEST
+        {
+            bail!("Unsupported usb audio request {:?}", device_req);
+        }
+        let cs = (device_req.value >> 8) as u8;
+        if cs != UAC_EP_SAMPLING_FREQ_CONTROL {
+            bail!("Unsupported usb audio endpoint control selector {}", cs);
+        }
+        let mut bytes = [0_u8; 4];
+        bytes[..SAMPLE_RATE_LEN].copy_from_slice(&self.usb_device.data_buf[..SAMPLE_RATE_LEN]);
+        let new_rate = u32::from_le_bytes(bytes);
+        let rates = self.rates.clone();
+        let stream = self
+            .get_stream(device_req.index as u8)
+            .with_context(|| format!("Invalid usb audio endpoint {}", device_req.index))?;
+
+        let rate = match (device_req.request_type, device_req.request) {
+            (USB_ENDPOINT_CLASS_OUT_REQUEST, UAC_SET_CUR) => {
+                if (device_req.length as usize) < SAMPLE_RATE_LEN {
+                    bail!("Invalid length {} to set sample rate", device_req.length);
+                }
+                if !rates.contains(&new_rate) {
+                    bail!("Unsupported usb audio sample rate {}", new_rate);
+                }
+                stream.set_rate(new_rate)?;
+                return Ok(0);
+            }
+            (USB_ENDPOINT_CLASS_IN_REQUEST, UAC_GET_CUR) => stream.rate,
+            (USB_ENDPOINT_CLASS_IN_REQUEST, UAC_GET_MIN) => *rates.iter().min().unwrap(),
+            (USB_ENDPOINT_CLASS_IN_REQUEST, UAC_GET_MAX) => *rates.iter().max().unwrap(),
+            (USB_ENDPOINT_CLASS_IN_REQUEST, UAC_GET_RES) => 1,
+            _ => bail!("Unsupported usb audio request {:?}", device_req),
+        };
+        self.usb_device.data_buf[..SAMPLE_RATE_LEN]
+            .copy_from_slice(&rate.to_le_bytes()[..SAMPLE_RATE_LEN]);
+        Ok(SAMPLE_RATE_LEN as u32)
+    }
+}
+
+impl UsbDeviceOps for UsbAudio {
+    fn realize(mut self) -> Result<Arc<Mutex<dyn UsbDeviceOps>>> {
+        self.usb_device.reset_usb_endpoint();
+        self.usb_device.speed = USB_SPEED_FULL;
+        let mut s: Vec<String> = DESC_STRINGS.iter().map(|&s| s.to_string()).collect();
+        let prefix = &s[STR_SERIAL_AUDIO_INDEX as usize];
+        s[STR_SERIAL_AUDIO_INDEX as usize] = self.usb_device.generate_serial_number(prefix);
+        let device_desc = gen_desc_device_audio(self.playback.channels, &self.rates);
+        self.usb_device.init_descriptor(device_desc, s)?;
+
+        let audio = Arc::new(Mutex::new(self));
+        Ok(audio)
+    }
+
+    fn unrealize(&mut self) -> Result<()> {
+        info!("USB audio {} unrealize", self.device_id());
+        self.playback.stop();
+        self.capture.stop();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        info!("USB audio {} device reset", self.device_id());
+        self.usb_device.remote_wakeup = 0;
+        self.usb_device.addr = 0;
+        self.playback.stop();
+        self.capture.stop();
+        self.playback.rate = self.rates[0];
+        self.capture.rate = self.rates[0];
+    }
+
+    fn handle_control(&mut self, packet: &Arc<Mutex<UsbPacket>>, device_req: &UsbDeviceRequest) {
+        let mut locked_packet = packet.lock().unwrap();
+        match self
+            .usb_device
+            .handle_control_for_descriptor(&mut locked_packet, device_req)
+        {
+            Ok(handled) => {
+                if handled {
+                    debug!("USB audio control handled by descriptor, return directly.");
+                    self.update_streams();
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("USB audio descriptor error {:?}", e);
+                locked_packet.status = UsbPacketStatus::Stall;
+                return;
+            }
+        }
+
+        match self.handle_endpoint_request(device_req) {
+            Ok(len) => locked_packet.actual_length = len,
+            Err(e) => {
+                error!("USB audio request error {:?}", e);
+                locked_packet.status = UsbPacketStatus::Stall;
+            }
+        }
+    }
+
+    fn handle_data(&mut self, packet: &Arc<Mutex<UsbPacket>>) {
+        let mut locked_packet = packet.lock().unwrap();
+        let mut ep_addr = locked_packet.ep_number;
+        if locked_packet.pid as u8 == USB_TOKEN_IN {
+            ep_addr |= USB_DIRECTION_DEVICE_TO_HOST;
+        }
+        let size = locked_packet.get_iovecs_size() as usize;
+        match self.get_stream(ep_addr) {
+            Some(stream) if stream.dir == ScreamDirection::Playback => {
+                let mut data = vec![0_u8; size];
+                locked_packet.transfer_packet(&mut data, size);
+                stream.play(&data);
+            }
+            Some(stream) => {
+                let mut data = stream.capture(size);
+                let len = data.len();
+                locked_packet.transfer_packet(&mut data, len);
+            }
+            None => {
+                error!("Invalid usb audio endpoint {:#x}", ep_addr);
+                locked_packet.status = UsbPacketStatus::Stall;
+            }
+        }
+    }
+
+    fn set_controller(&mut self, _cntlr: Weak<Mutex<XhciDevice>>) {}
+
+    fn get_controller(&self) -> Option<Weak<Mutex<XhciDevice>>> {
+        None
+    }
+
+    fn get_wakeup_endpoint(&self) -> &UsbEndpoint {
+        self.usb_device.get_endpoint(true, ENDPOINT_ID_CAPTURE)
+    }
+
+    fn get_usb_device(&self) -> &UsbDevice {
+        &self.usb_device
+    }
+
+    fn get_mut_usb_device(&mut self) -> &mut UsbDevice {
+        &mut self.usb_device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_config(rates: Vec<u32>) -> UsbAudioConfig {
+        UsbAudioConfig {
+            id: Some("audio0".to_string()),
+            interface: "Null".to_string(),
+            playback: String::new(),
+            record: String::new(),
+            channels: 2,
+            rates,
+        }
+    }
+
+    #[test]
+    fn test_usb_audio_descriptor() {
+        let desc = gen_format_type_descriptor(2, &[44100, 48000]);
+        assert_eq!(
+            desc,
+            [14, 0x24, 0x02, 0x01, 2, 2, 16, 2, 0x44, 0xac, 0x00, 0x80, 0xbb, 0x00]
+        );
+        assert_eq!(channel_config(1), 0);
+        assert_eq!(channel_config(2), 0x3);
+        assert_eq!(max_packet_size(44100, 2), 180);
+
+        // The total length in the header covers all the audio control descriptors.
+        let iface = gen_desc_interface_control(2);
+        let total_len: usize = iface.other_desc.iter().map(|d| d.data.len()).sum();
+        assert_eq!(
+            u16::from_le_bytes([iface.other_desc[0].data[5], iface.other_desc[0].data[6]]) as usize,
+            total_len
+        );
+    }
+
+    #[test]
+    fn test_usb_audio_packet_frames() {
+        let config = create_config(vec![44100, 48000]);
+        let mut stream = UsbAudioStream::new(ScreamDirection::Record, &config);
+        let frames: Vec<usize> = (0..10).map(|_| stream.next_packet_frames()).collect();
+        assert_eq!(frames, [44, 44, 44, 44, 44, 44, 44, 44, 44, 45]);
+        let total: usize = (10..1000).map(|_| stream.next_packet_frames()).sum();
+        assert_eq!(total + 441, 44100);
+
+        // Silence is filled if no audio is captured.
+        stream.rate = 48000;
+        stream.buffer.lock().unwrap().extend([1_u8; 100]);
+        let data = stream.capture(1024);
+        assert_eq!(data.len(), 192);
+        assert_eq!(data[99], 1);
+        assert_eq!(data[100], 0);
+        assert_eq!(stream.capture(64).len(), 64);
+    }
+}
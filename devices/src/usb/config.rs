@@ -237,7 +237,12 @@ pub const USB_CONFIGURATION_ATTR_SELF_POWER: u8 = 1 << 6;
 pub const USB_CONFIGURATION_ATTR_REMOTE_WAKEUP: u8 = 1 << 5;
 
 // USB Class
+pub const USB_CLASS_AUDIO: u8 = 1;
+pub const USB_CLASS_COMM: u8 = 2;
 pub const USB_CLASS_HID: u8 = 3;
 pub const USB_CLASS_MASS_STORAGE: u8 = 8;
+pub const USB_CLASS_HUB: u8 = 9;
+pub const USB_CLASS_CDC_DATA: u8 = 0xa;
 pub const USB_CLASS_VIDEO: u8 = 0xe;
 pub const USB_CLASS_MISCELLANEOUS: u8 = 0xef;
+pub const USB_CLASS_VENDOR_SPEC: u8 = 0xff;
@@ -0,0 +1,1186 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use machine_manager::config::EhciConfig;
+use machine_manager::event_loop::EventLoop;
+use pci::config::{
+    PciConfig, RegionType, DEVICE_ID, PCI_CLASS_SERIAL_USB, PCI_CONFIG_SPACE_SIZE,
+    PCI_DEVICE_ID_INTEL_ICH9_EHCI1, PCI_VENDOR_ID_INTEL, REVISION_ID, SUB_CLASS_CODE, VENDOR_ID,
+};
+use pci::{init_intx, init_multifunction, le_write_u16, PciBus, PciDevOps};
+use util::num_ops::{read_data_u32, write_data_u32};
+
+use super::config::*;
+use super::uhci::UhciDevice;
+use super::xhci::xhci_controller::{dma_read_u32, dma_write_u32, UsbPort};
+use super::{PolledPacket, UsbControlPipe, UsbDeviceOps, UsbPacketStatus};
+
+/// Number of the root ports, which are the same as ICH9 EHCI1.
+pub const EHCI_PORTS: usize = 6;
+/// Number of the ports per companion controller.
+pub const EHCI_PORTS_PER_COMPANION: usize = 2;
+const EHCI_COMPANIONS: usize = EHCI_PORTS / EHCI_PORTS_PER_COMPANION;
+
+/// PCI configuration registers.
+const PCI_CLASS_PI: usize = 0x09;
+const PCI_CLASS_PI_EHCI: u8 = 0x20;
+const PCI_SERIAL_BUS_RELEASE_NUMBER: usize = 0x60;
+const PCI_FRAME_LENGTH_ADJUSTMENT: usize = 0x61;
+const PCI_SERIAL_BUS_RELEASE_VERSION_2_0: u8 = 0x20;
+const PCI_FRAME_LENGTH_ADJUSTMENT_DEFAULT: u8 = 0x20;
+const EHCI_MMIO_SIZE: u64 = 0x1000;
+
+/// 2.2 Host Controller Capability Registers.
+const EHCI_CAP_LENGTH: u64 = 0x20;
+const EHCI_VERSION: u32 = 0x0100;
+const EHCI_CAP_REG_CAPLENGTH: u64 = 0x00;
+const EHCI_CAP_REG_HCSPARAMS: u64 = 0x04;
+const EHCI_CAP_REG_HCCPARAMS: u64 = 0x08;
+const HCSPARAMS_NPCC_SHIFT: u32 = 8;
+const HCSPARAMS_NCC_SHIFT: u32 = 12;
+
+/// 2.3 Host Controller Operational Registers.
+const EHCI_OPER_REG_USBCMD: u64 = 0x00;
+const EHCI_OPER_REG_USBSTS: u64 = 0x04;
+const EHCI_OPER_REG_USBINTR: u64 = 0x08;
+const EHCI_OPER_REG_FRINDEX: u64 = 0x0c;
+const EHCI_OPER_REG_CTRLDSSEGMENT: u64 = 0x10;
+const EHCI_OPER_REG_PERIODICLISTBASE: u64 = 0x14;
+const EHCI_OPER_REG_ASYNCLISTADDR: u64 = 0x18;
+const EHCI_OPER_REG_CONFIGFLAG: u64 = 0x40;
+const EHCI_OPER_REG_PORTSC: u64 = 0x44;
+
+/// USB Command Register.
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_HCRESET: u32 = 1 << 1;
+const USBCMD_PSE: u32 = 1 << 4;
+const USBCMD_ASE: u32 = 1 << 5;
+const USBCMD_IAAD: u32 = 1 << 6;
+const USBCMD_ITC_DEFAULT: u32 = 0x08 << 16;
+
+/// USB Status Register.
+const USBSTS_INT: u32 = 1 << 0;
+const USBSTS_ERRINT: u32 = 1 << 1;
+const USBSTS_PCD: u32 = 1 << 2;
+const USBSTS_FLR: u32 = 1 << 3;
+const USBSTS_HSE: u32 = 1 << 4;
+const USBSTS_IAA: u32 = 1 << 5;
+const USBSTS_HALT: u32 = 1 << 12;
+const USBSTS_PSS: u32 = 1 << 14;
+const USBSTS_ASS: u32 = 1 << 15;
+const USBSTS_INTR_MASK: u32 = 0x3f;
+
+/// Port Status and Control Register.
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_CSC: u32 = 1 << 1;
+const PORTSC_PED: u32 = 1 << 2;
+const PORTSC_PEDC: u32 = 1 << 3;
+const PORTSC_OCC: u32 = 1 << 5;
+const PORTSC_FPR: u32 = 1 << 6;
+const PORTSC_SUSPEND: u32 = 1 << 7;
+const PORTSC_PRESET: u32 = 1 << 8;
+const PORTSC_PP: u32 = 1 << 12;
+const PORTSC_POWNER: u32 = 1 << 13;
+const PORTSC_RWC: u32 = PORTSC_CSC | PORTSC_PEDC | PORTSC_OCC;
+/// Port indicator, test control and wake enable bits.
+const PORTSC_RW_MASK: u32 = 0x007f_c000 | PORTSC_FPR | PORTSC_SUSPEND | PORTSC_PRESET;
+
+/// Frame index register counts micro-frames.
+const FRINDEX_MASK: u32 = 0x3fff;
+const FRINDEX_ROLLOVER: u32 = 1 << 13;
+const MICROFRAMES_PER_FRAME: u32 = 8;
+const FRAME_LIST_SIZE: u32 = 1024;
+const FRAME_INTERVAL: Duration = Duration::from_millis(1);
+
+/// 3.1 Periodic Frame List Element Pointer.
+const LINK_TERMINATE: u32 = 1 << 0;
+const LINK_TYPE_SHIFT: u32 = 1;
+const LINK_TYPE_MASK: u32 = 0x3;
+const LINK_TYPE_QH: u32 = 1;
+const LINK_ADDR_MASK: u32 = !0x1f;
+
+/// 3.6 Queue Head, in dwords.
+const QH_DWORDS: usize = 12;
+const QH_LINK: usize = 0;
+const QH_EPCHAR: usize = 1;
+const QH_CURRENT_QTD: usize = 3;
+const QH_NEXT_QTD: usize = 4;
+const QH_ALT_NEXT_QTD: usize = 5;
+const QH_TOKEN: usize = 6;
+const QH_BUFFER: usize = 7;
+const QH_EPCHAR_DEVADDR_MASK: u32 = 0x7f;
+const QH_EPCHAR_EP_SHIFT: u32 = 8;
+const QH_EPCHAR_EP_MASK: u32 = 0xf;
+const QH_EPCHAR_DTC: u32 = 1 << 14;
+const QH_EPCHAR_MPL_SHIFT: u32 = 16;
+const QH_EPCHAR_MPL_MASK: u32 = 0x7ff;
+
+/// 3.5 Queue Element Transfer Descriptor, in dwords.
+const QTD_DWORDS: usize = 8;
+const QTD_TOKEN: usize = 2;
+const QTD_BUFFERS: usize = 5;
+const QTD_BUFFER_PAGE_SIZE: u32 = 0x1000;
+const QTD_TOKEN_ACTIVE: u32 = 1 << 7;
+const QTD_TOKEN_HALTED: u32 = 1 << 6;
+const QTD_TOKEN_BABBLE: u32 = 1 << 4;
+const QTD_TOKEN_XACTERR: u32 = 1 << 3;
+const QTD_TOKEN_PID_SHIFT: u32 = 8;
+const QTD_TOKEN_PID_MASK: u32 = 0x3;
+const QTD_TOKEN_CERR_SHIFT: u32 = 10;
+const QTD_TOKEN_CERR_MASK: u32 = 0x3;
+const QTD_TOKEN_CPAGE_SHIFT: u32 = 12;
+const QTD_TOKEN_CPAGE_MASK: u32 = 0x7;
+const QTD_TOKEN_IOC: u32 = 1 << 15;
+const QTD_TOKEN_BYTES_SHIFT: u32 = 16;
+const QTD_TOKEN_BYTES_MASK: u32 = 0x7fff;
+const QTD_TOKEN_DT: u32 = 1 << 31;
+const QTD_PID_OUT: u32 = 0;
+const QTD_PID_IN: u32 = 1;
+const QTD_PID_SETUP: u32 = 2;
+
+/// Limits of the schedule walk in one frame, which protect against the loops
+/// in the guest lists.
+const MAX_PERIODIC_ELEMENTS: usize = 128;
+const MAX_ASYNC_QHS: usize = 64;
+const MAX_QTDS_PER_QH: usize = 32;
+
+/// Result of the qTD execution.
+#[derive(Debug, PartialEq, Eq)]
+enum QtdResult {
+    /// The qTD is retired.
+    Done,
+    /// The device has no data, retry in the next frame.
+    Nak,
+    /// The packet is handled by the device asynchronously.
+    Async,
+}
+
+/// Packet which is still handled by the device, keyed by its queue head.
+struct EhciInflight {
+    qtd: u32,
+    packet: PolledPacket,
+}
+
+/// EHCI root port, which is shared with the companion controller.
+pub struct EhciPort {
+    usb_port: Arc<Mutex<UsbPort>>,
+    portsc: u32,
+    pipe: UsbControlPipe,
+}
+
+impl EhciPort {
+    fn new(port_id: u8) -> Self {
+        let mut usb_port = UsbPort::new(&Weak::new(), port_id);
+        usb_port.speed_mask = USB_SPEED_MASK_LOW | USB_SPEED_MASK_FULL | USB_SPEED_MASK_HIGH;
+        Self {
+            usb_port: Arc::new(Mutex::new(usb_port)),
+            portsc: PORTSC_PP | PORTSC_POWNER,
+            pipe: UsbControlPipe::default(),
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.usb_port
+            .lock()
+            .unwrap()
+            .dev
+            .as_ref()
+            .map_or(false, |dev| dev.lock().unwrap().connected())
+    }
+}
+
+/// EHCI controller, which hands the full and low speed devices over to the
+/// UHCI companion controllers.
+pub struct EhciDevice {
+    mem_space: Arc<AddressSpace>,
+    usbcmd: u32,
+    usbsts: u32,
+    usbintr: u32,
+    frindex: u32,
+    ctrldssegment: u32,
+    periodiclistbase: u32,
+    asynclistaddr: u32,
+    configflag: u32,
+    pub ports: Vec<EhciPort>,
+    companions: Vec<Option<Weak<Mutex<UhciDevice>>>>,
+    inflight: HashMap<u32, EhciInflight>,
+    timer_id: Option<u64>,
+    interrupt_cb: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+    weak_self: Weak<Mutex<EhciDevice>>,
+}
+
+impl EhciDevice {
+    pub fn new(mem_space: &Arc<AddressSpace>) -> Arc<Mutex<Self>> {
+        let ehci = Arc::new(Mutex::new(Self {
+            mem_space: mem_space.clone(),
+            usbcmd: USBCMD_ITC_DEFAULT,
+            usbsts: USBSTS_HALT,
+            usbintr: 0,
+            frindex: 0,
+            ctrldssegment: 0,
+            periodiclistbase: 0,
+            asynclistaddr: 0,
+            configflag: 0,
+            ports: (0..EHCI_PORTS)
+                .map(|i| EhciPort::new(i as u8 + 1))
+                .collect(),
+            companions: vec![None; EHCI_COMPANIONS],
+            inflight: HashMap::new(),
+            timer_id: None,
+            interrupt_cb: None,
+            weak_self: Weak::new(),
+        }));
+        ehci.lock().unwrap().weak_self = Arc::downgrade(&ehci);
+        ehci
+    }
+
+    pub fn set_interrupt_ops(&mut self, cb: Arc<dyn Fn(u32) + Send + Sync>) {
+        self.interrupt_cb = Some(cb);
+    }
+
+    pub fn reset(&mut self) {
+        info!("ehci reset");
+        self.stop_frame_timer();
+        self.usbcmd = USBCMD_ITC_DEFAULT;
+        self.usbsts = USBSTS_HALT;
+        self.usbintr = 0;
+        self.frindex = 0;
+        self.ctrldssegment = 0;
+        self.periodiclistbase = 0;
+        self.asynclistaddr = 0;
+        self.configflag = 0;
+        self.inflight.clear();
+        for i in 0..self.ports.len() {
+            self.ports[i].portsc = PORTSC_PP | PORTSC_POWNER;
+            self.ports[i].pipe.reset();
+            self.port_update(i);
+        }
+        self.update_irq();
+    }
+
+    fn running(&self) -> bool {
+        self.usbsts & USBSTS_HALT == 0
+    }
+
+    fn update_irq(&self) {
+        let level = (self.usbsts & self.usbintr & USBSTS_INTR_MASK != 0) as u32;
+        if let Some(cb) = self.interrupt_cb.as_ref() {
+            cb(level);
+        }
+    }
+
+    fn read_cap(&self, offset: u64) -> u32 {
+        match offset {
+            EHCI_CAP_REG_CAPLENGTH => EHCI_VERSION << 16 | EHCI_CAP_LENGTH as u32,
+            EHCI_CAP_REG_HCSPARAMS => {
+                (EHCI_COMPANIONS as u32) << HCSPARAMS_NCC_SHIFT
+                    | (EHCI_PORTS_PER_COMPANION as u32) << HCSPARAMS_NPCC_SHIFT
+                    | EHCI_PORTS as u32
+            }
+            // No 64-bit addressing, 1024 entries in the frame list.
+            EHCI_CAP_REG_HCCPARAMS => 0,
+            _ => 0,
+        }
+    }
+
+    fn read_oper(&self, offset: u64) -> u32 {
+        match offset {
+            EHCI_OPER_REG_USBCMD => self.usbcmd,
+            EHCI_OPER_REG_USBSTS => self.usbsts,
+            EHCI_OPER_REG_USBINTR => self.usbintr,
+            EHCI_OPER_REG_FRINDEX => self.frindex,
+            EHCI_OPER_REG_CTRLDSSEGMENT => self.ctrldssegment,
+            EHCI_OPER_REG_PERIODICLISTBASE => self.periodiclistbase,
+            EHCI_OPER_REG_ASYNCLISTADDR => self.asynclistaddr,
+            EHCI_OPER_REG_CONFIGFLAG => self.configflag,
+            _ => match port_index(offset) {
+                Some(idx) => self.ports[idx].portsc,
+                None => {
+                    error!("Failed to read ehci oper register: offset 0x{:x}", offset);
+                    0
+                }
+            },
+        }
+    }
+
+    fn write_oper(&mut self, offset: u64, value: u32) {
+        match offset {
+            EHCI_OPER_REG_USBCMD => self.write_usbcmd(value),
+            EHCI_OPER_REG_USBSTS => {
+                self.usbsts &= !(value & USBSTS_INTR_MASK);
+                self.update_irq();
+            }
+            EHCI_OPER_REG_USBINTR => {
+                self.usbintr = value & USBSTS_INTR_MASK;
+                self.update_irq();
+            }
+            EHCI_OPER_REG_FRINDEX => {
+                // Only writable when the controller is halted.
+                if !self.running() {
+                    self.frindex = value & FRINDEX_MASK;
+                }
+            }
+            EHCI_OPER_REG_CTRLDSSEGMENT => self.ctrldssegment = value,
+            EHCI_OPER_REG_PERIODICLISTBASE => {
+                self.periodiclistbase = value & !(QTD_BUFFER_PAGE_SIZE - 1)
+            }
+            EHCI_OPER_REG_ASYNCLISTADDR => self.asynclistaddr = value & LINK_ADDR_MASK,
+            EHCI_OPER_REG_CONFIGFLAG => self.write_configflag(value & 1),
+            _ => match port_index(offset) {
+                Some(idx) => self.write_portsc(idx, value),
+                None => error!("Failed to write ehci oper register: offset 0x{:x}", offset),
+            },
+        }
+    }
+
+    fn write_usbcmd(&mut self, value: u32) {
+        if value & USBCMD_HCRESET != 0 {
+            self.reset();
+            return;
+        }
+        let old = self.usbcmd;
+        self.usbcmd = value;
+        if (old ^ value) & USBCMD_RUN != 0 {
+            if value & USBCMD_RUN != 0 {
+                self.usbsts &= !USBSTS_HALT;
+                self.start_frame_timer();
+            } else {
+                self.usbsts |= USBSTS_HALT;
+                self.stop_frame_timer();
+            }
+        }
+        // The schedules are fetched in every frame, so the status follows the
+        // enable bits immediately.
+        self.usbsts &= !(USBSTS_PSS | USBSTS_ASS);
+        if value & USBCMD_PSE != 0 {
+            self.usbsts |= USBSTS_PSS;
+        }
+        if value & USBCMD_ASE != 0 {
+            self.usbsts |= USBSTS_ASS;
+        }
+    }
+
+    fn write_configflag(&mut self, value: u32) {
+        if self.configflag == value {
+            return;
+        }
+        self.configflag = value;
+        // All the ports are routed to EHCI once the configure flag is set.
+        for i in 0..self.ports.len() {
+            self.set_port_owner(i, value == 0);
+        }
+    }
+
+    fn write_portsc(&mut self, idx: usize, value: u32) {
+        let old = self.ports[idx].portsc;
+        let mut portsc = old & !(value & PORTSC_RWC);
+        // The port can only be disabled by software.
+        if value & PORTSC_PED == 0 {
+            portsc &= !PORTSC_PED;
+        }
+        portsc = (portsc & !PORTSC_RW_MASK) | (value & PORTSC_RW_MASK);
+        if old & PORTSC_FPR != 0 && value & PORTSC_FPR == 0 {
+            portsc &= !PORTSC_SUSPEND;
+        }
+        if value & PORTSC_PRESET != 0 {
+            portsc &= !PORTSC_PED;
+        }
+        self.ports[idx].portsc = portsc;
+
+        if old & PORTSC_PRESET != 0 && value & PORTSC_PRESET == 0 {
+            self.port_reset(idx);
+        }
+        if (old ^ value) & PORTSC_POWNER != 0 && self.configflag != 0 {
+            self.set_port_owner(idx, value & PORTSC_POWNER != 0);
+        }
+    }
+
+    /// Finish the port reset, the port is enabled only for the high speed
+    /// device, the others are handed over to the companion by the driver.
+    fn port_reset(&mut self, idx: usize) {
+        let port = &mut self.ports[idx];
+        port.pipe.reset();
+        if port.portsc & PORTSC_CCS == 0 {
+            return;
+        }
+        let locked_port = port.usb_port.lock().unwrap();
+        if let Some(dev) = locked_port.dev.as_ref() {
+            let mut locked_dev = dev.lock().unwrap();
+            locked_dev.reset();
+            if locked_dev.speed() == USB_SPEED_HIGH {
+                port.portsc |= PORTSC_PED;
+            }
+        }
+    }
+
+    fn set_port_owner(&mut self, idx: usize, companion: bool) {
+        let owned = self.ports[idx].portsc & PORTSC_POWNER != 0;
+        if owned == companion {
+            return;
+        }
+        if companion {
+            self.ports[idx].portsc |= PORTSC_POWNER;
+        } else {
+            self.ports[idx].portsc &= !PORTSC_POWNER;
+        }
+        self.port_update(idx);
+    }
+
+    fn companion(&self, idx: usize) -> Option<(Arc<Mutex<UhciDevice>>, usize)> {
+        let companion = self.companions[idx / EHCI_PORTS_PER_COMPANION].as_ref()?;
+        companion
+            .upgrade()
+            .map(|uhci| (uhci, idx % EHCI_PORTS_PER_COMPANION))
+    }
+
+    /// Update the connection of the port for its owner after the device is
+    /// attached or detached, or the port is handed over.
+    fn port_update(&mut self, idx: usize) {
+        let companion_owned = self.ports[idx].portsc & PORTSC_POWNER != 0;
+        if let Some((uhci, companion_idx)) = self.companion(idx) {
+            uhci.lock()
+                .unwrap()
+                .port_update(companion_idx, companion_owned);
+        }
+
+        let connected = !companion_owned && self.ports[idx].connected();
+        let port = &mut self.ports[idx];
+        if connected == (port.portsc & PORTSC_CCS != 0) {
+            return;
+        }
+        if connected {
+            port.portsc |= PORTSC_CCS;
+        } else {
+            if port.portsc & PORTSC_PED != 0 {
+                port.portsc |= PORTSC_PEDC;
+            }
+            port.portsc &= !(PORTSC_CCS | PORTSC_PED);
+        }
+        port.portsc |= PORTSC_CSC;
+        port.pipe.reset();
+        self.usbsts |= USBSTS_PCD;
+        self.update_irq();
+    }
+
+    /// Get the ports which are routed to the companion with the first port.
+    pub fn companion_ports(&self, firstport: usize) -> Result<Vec<Arc<Mutex<UsbPort>>>> {
+        if firstport % EHCI_PORTS_PER_COMPANION != 0
+            || firstport + EHCI_PORTS_PER_COMPANION > self.ports.len()
+        {
+            bail!("Invalid first port {} of the companion", firstport);
+        }
+        if self.companions[firstport / EHCI_PORTS_PER_COMPANION].is_some() {
+            bail!("The companion of port {} already exists", firstport);
+        }
+        Ok(self.ports[firstport..firstport + EHCI_PORTS_PER_COMPANION]
+            .iter()
+            .map(|port| port.usb_port.clone())
+            .collect())
+    }
+
+    /// Register the companion controller of the ports from the first port.
+    pub fn register_companion(&mut self, firstport: usize, uhci: Weak<Mutex<UhciDevice>>) {
+        self.companions[firstport / EHCI_PORTS_PER_COMPANION] = Some(uhci);
+        for i in firstport..firstport + EHCI_PORTS_PER_COMPANION {
+            self.port_update(i);
+        }
+    }
+
+    /// Attach the device to a free root port. The full and low speed devices
+    /// need the port with a companion controller.
+    pub fn attach_device(&mut self, dev: &Arc<Mutex<dyn UsbDeviceOps>>) -> Result<()> {
+        let speed = dev.lock().unwrap().speed();
+        let idx = (0..self.ports.len())
+            .find(|&i| {
+                let locked_port = self.ports[i].usb_port.lock().unwrap();
+                !locked_port.used
+                    && locked_port.speed_supported(speed)
+                    && (speed == USB_SPEED_HIGH
+                        || self.companions[i / EHCI_PORTS_PER_COMPANION].is_some())
+            })
+            .with_context(|| "No available USB port.")?;
+
+        let usb_port = self.ports[idx].usb_port.clone();
+        let mut locked_port = usb_port.lock().unwrap();
+        locked_port.used = true;
+        locked_port.dev = Some(dev.clone());
+        drop(locked_port);
+        let mut locked_dev = dev.lock().unwrap();
+        debug!(
+            "Attach usb device: ehci port id {} device id {}",
+            idx + 1,
+            locked_dev.device_id()
+        );
+        locked_dev.set_usb_port(Some(Arc::downgrade(&usb_port)));
+        locked_dev.handle_attach()?;
+        drop(locked_dev);
+        self.port_update(idx);
+        Ok(())
+    }
+
+    pub fn find_port_by_id(&self, id: &str) -> Option<usize> {
+        self.ports.iter().position(|port| {
+            port.usb_port
+                .lock()
+                .unwrap()
+                .dev
+                .as_ref()
+                .map_or(false, |dev| dev.lock().unwrap().device_id() == id)
+        })
+    }
+
+    pub fn detach_device(&mut self, id: &str) -> Result<()> {
+        let idx = self
+            .find_port_by_id(id)
+            .with_context(|| format!("Failed to detach device: id {} not found", id))?;
+        let mut locked_port = self.ports[idx].usb_port.lock().unwrap();
+        locked_port.used = false;
+        let dev = locked_port.dev.take();
+        drop(locked_port);
+        self.port_update(idx);
+
+        if let Some(dev) = dev {
+            let mut locked_dev = dev.lock().unwrap();
+            locked_dev.get_mut_usb_device().unplugged = true;
+            locked_dev.unrealize()?;
+        }
+        Ok(())
+    }
+
+    fn start_frame_timer(&mut self) {
+        if self.timer_id.is_some() {
+            return;
+        }
+        let weak_ehci = self.weak_self.clone();
+        let frame_timer = Box::new(move || {
+            if let Some(ehci) = weak_ehci.upgrade() {
+                let mut locked_ehci = ehci.lock().unwrap();
+                locked_ehci.timer_id = None;
+                locked_ehci.run_frame();
+                if locked_ehci.running() {
+                    locked_ehci.start_frame_timer();
+                }
+            }
+        });
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            self.timer_id = Some(ctx.timer_add(frame_timer, FRAME_INTERVAL));
+        }
+    }
+
+    fn stop_frame_timer(&mut self) {
+        if let Some(timer_id) = self.timer_id.take() {
+            if let Some(ctx) = EventLoop::get_ctx(None) {
+                ctx.timer_del(timer_id);
+            }
+        }
+    }
+
+    /// Process the schedules of one frame.
+    fn run_frame(&mut self) {
+        let old = self.frindex;
+        self.frindex = (self.frindex + MICROFRAMES_PER_FRAME) & FRINDEX_MASK;
+        if (old ^ self.frindex) & FRINDEX_ROLLOVER != 0 {
+            self.usbsts |= USBSTS_FLR;
+        }
+
+        let mut result = Ok(());
+        if self.usbcmd & USBCMD_PSE != 0 {
+            result = self.process_periodic();
+        }
+        if result.is_ok() && self.usbcmd & USBCMD_ASE != 0 {
+            result = self.process_async();
+        }
+        if let Err(e) = result {
+            error!("Ehci host system error: {:?}", e);
+            self.usbsts |= USBSTS_HSE | USBSTS_HALT;
+            self.usbcmd &= !USBCMD_RUN;
+        }
+
+        // The schedule has been walked, the unlinked queue heads are no longer
+        // referenced.
+        if self.usbcmd & USBCMD_IAAD != 0 {
+            self.usbcmd &= !USBCMD_IAAD;
+            self.usbsts |= USBSTS_IAA;
+        }
+        self.update_irq();
+    }
+
+    fn process_periodic(&mut self) -> Result<()> {
+        let frame = (self.frindex / MICROFRAMES_PER_FRAME) % FRAME_LIST_SIZE;
+        let mut link = self.read_u32(self.periodiclistbase + frame * 4)?;
+        for _ in 0..MAX_PERIODIC_ELEMENTS {
+            if link & LINK_TERMINATE != 0 {
+                break;
+            }
+            let addr = link & LINK_ADDR_MASK;
+            if (link >> LINK_TYPE_SHIFT) & LINK_TYPE_MASK == LINK_TYPE_QH {
+                link = self.process_qh(addr, true)?;
+            } else {
+                // Isochronous transfer is not supported, skip the iTD, siTD
+                // and FSTN through their next link.
+                link = self.read_u32(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn process_async(&mut self) -> Result<()> {
+        let head = self.asynclistaddr;
+        let mut addr = head;
+        for _ in 0..MAX_ASYNC_QHS {
+            let link = self.process_qh(addr, false)?;
+            if link & LINK_TERMINATE != 0 {
+                break;
+            }
+            addr = link & LINK_ADDR_MASK;
+            if addr == head {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Process the qTDs of the queue head, returns its horizontal link.
+    fn process_qh(&mut self, addr: u32, periodic: bool) -> Result<u32> {
+        let mut qh = [0_u32; QH_DWORDS];
+        self.read_dwords(addr, &mut qh)?;
+        for _ in 0..MAX_QTDS_PER_QH {
+            let result = if let Some(inflight) = self.inflight.get(&addr) {
+                if !inflight.packet.completed() {
+                    break;
+                }
+                let inflight = self.inflight.remove(&addr).unwrap();
+                if inflight.qtd != qh[QH_CURRENT_QTD] || qh[QH_TOKEN] & QTD_TOKEN_ACTIVE == 0 {
+                    // The queue has been changed by the driver, drop the result.
+                    continue;
+                }
+                let locked_packet = inflight.packet.packet.lock().unwrap();
+                let (status, len) = (locked_packet.status, locked_packet.actual_length);
+                drop(locked_packet);
+                self.complete_qtd(addr, &mut qh, status, len)?;
+                QtdResult::Done
+            } else {
+                if qh[QH_TOKEN] & QTD_TOKEN_HALTED != 0 {
+                    break;
+                }
+                if qh[QH_TOKEN] & QTD_TOKEN_ACTIVE == 0 && !self.fetch_qtd(addr, &mut qh)? {
+                    break;
+                }
+                self.execute_qtd(addr, &mut qh)?
+            };
+            // The interrupt endpoint is polled once per frame.
+            if result != QtdResult::Done || periodic {
+                break;
+            }
+        }
+        Ok(qh[QH_LINK])
+    }
+
+    /// Load the next active qTD into the overlay of the queue head.
+    fn fetch_qtd(&mut self, addr: u32, qh: &mut [u32; QH_DWORDS]) -> Result<bool> {
+        let next = qh[QH_NEXT_QTD];
+        if next & LINK_TERMINATE != 0 {
+            return Ok(false);
+        }
+        let next = next & LINK_ADDR_MASK;
+        let mut qtd = [0_u32; QTD_DWORDS];
+        self.read_dwords(next, &mut qtd)?;
+        if qtd[QTD_TOKEN] & QTD_TOKEN_ACTIVE == 0 {
+            return Ok(false);
+        }
+
+        let toggle = qh[QH_TOKEN] & QTD_TOKEN_DT;
+        qh[QH_CURRENT_QTD] = next;
+        qh[QH_NEXT_QTD..].copy_from_slice(&qtd);
+        // The data toggle is kept in the queue head unless the qTD controls it.
+        if qh[QH_EPCHAR] & QH_EPCHAR_DTC == 0 {
+            qh[QH_TOKEN] = (qh[QH_TOKEN] & !QTD_TOKEN_DT) | toggle;
+        }
+        self.write_dwords(addr + (QH_CURRENT_QTD * 4) as u32, &qh[QH_CURRENT_QTD..])?;
+        Ok(true)
+    }
+
+    fn execute_qtd(&mut self, addr: u32, qh: &mut [u32; QH_DWORDS]) -> Result<QtdResult> {
+        let epchar = qh[QH_EPCHAR];
+        let devaddr = (epchar & QH_EPCHAR_DEVADDR_MASK) as u8;
+        let ep_number = ((epchar >> QH_EPCHAR_EP_SHIFT) & QH_EPCHAR_EP_MASK) as u8;
+        let pid = match (qh[QH_TOKEN] >> QTD_TOKEN_PID_SHIFT) & QTD_TOKEN_PID_MASK {
+            QTD_PID_OUT => USB_TOKEN_OUT,
+            QTD_PID_IN => USB_TOKEN_IN,
+            QTD_PID_SETUP => USB_TOKEN_SETUP,
+            pid => {
+                error!("Invalid qTD pid {}", pid);
+                self.complete_qtd(addr, qh, UsbPacketStatus::Stall, 0)?;
+                return Ok(QtdResult::Done);
+            }
+        };
+        let (idx, dev) = match self.find_device(devaddr) {
+            Some(found) => found,
+            None => {
+                debug!("No usb device found with address {}", devaddr);
+                self.complete_qtd(addr, qh, UsbPacketStatus::NoDev, 0)?;
+                return Ok(QtdResult::Done);
+            }
+        };
+
+        let iovecs = self.qtd_iovecs(qh)?;
+        let packet = PolledPacket::new(pid as u32, ep_number, iovecs);
+        if ep_number == 0 {
+            let mut locked_packet = packet.packet.lock().unwrap();
+            self.ports[idx].pipe.handle_token(&dev, &mut locked_packet);
+        } else {
+            dev.lock().unwrap().handle_packet(&packet.packet);
+        }
+
+        let locked_packet = packet.packet.lock().unwrap();
+        if locked_packet.is_async {
+            drop(locked_packet);
+            let qtd = qh[QH_CURRENT_QTD];
+            self.inflight.insert(addr, EhciInflight { qtd, packet });
+            return Ok(QtdResult::Async);
+        }
+        let (status, len) = (locked_packet.status, locked_packet.actual_length);
+        drop(locked_packet);
+        if status == UsbPacketStatus::Nak {
+            return Ok(QtdResult::Nak);
+        }
+        self.complete_qtd(addr, qh, status, len)?;
+        Ok(QtdResult::Done)
+    }
+
+    /// Map the buffer of the qTD in the overlay from the current page and offset.
+    fn qtd_iovecs(&self, qh: &[u32; QH_DWORDS]) -> Result<Vec<util::aio::Iovec>> {
+        let token = qh[QH_TOKEN];
+        let mut left = (token >> QTD_TOKEN_BYTES_SHIFT) & QTD_TOKEN_BYTES_MASK;
+        let mut page = ((token >> QTD_TOKEN_CPAGE_SHIFT) & QTD_TOKEN_CPAGE_MASK) as usize;
+        let mut offset = qh[QH_BUFFER] & (QTD_BUFFER_PAGE_SIZE - 1);
+        let mut iovecs = Vec::new();
+        while left > 0 {
+            if page >= QTD_BUFFERS {
+                bail!("qTD buffer overflow, {} bytes left", left);
+            }
+            let base = qh[QH_BUFFER + page] & !(QTD_BUFFER_PAGE_SIZE - 1);
+            let len = min(left, QTD_BUFFER_PAGE_SIZE - offset);
+            let mut hvas = self
+                .mem_space
+                .get_address_map(GuestAddress((base + offset) as u64), len as u64)?;
+            iovecs.append(&mut hvas);
+            left -= len;
+            offset = 0;
+            page += 1;
+        }
+        Ok(iovecs)
+    }
+
+    /// Retire the qTD in the overlay and write back its token.
+    fn complete_qtd(
+        &mut self,
+        addr: u32,
+        qh: &mut [u32; QH_DWORDS],
+        status: UsbPacketStatus,
+        actual_length: u32,
+    ) -> Result<()> {
+        let (token, short) = qtd_token_complete(qh, status, actual_length);
+        qh[QH_TOKEN] = token;
+        if short && qh[QH_ALT_NEXT_QTD] & LINK_TERMINATE == 0 {
+            qh[QH_NEXT_QTD] = qh[QH_ALT_NEXT_QTD];
+        }
+
+        let qtd = qh[QH_CURRENT_QTD] & LINK_ADDR_MASK;
+        self.write_dwords(qtd + (QTD_TOKEN * 4) as u32, &[token])?;
+        self.write_dwords(addr + (QH_NEXT_QTD * 4) as u32, &qh[QH_NEXT_QTD..])?;
+
+        if token & QTD_TOKEN_HALTED != 0 {
+            self.usbsts |= USBSTS_ERRINT;
+        } else if token & QTD_TOKEN_IOC != 0 || short {
+            self.usbsts |= USBSTS_INT;
+        }
+        Ok(())
+    }
+
+    /// Find the device with the address on the enabled ports owned by EHCI.
+    fn find_device(&self, devaddr: u8) -> Option<(usize, Arc<Mutex<dyn UsbDeviceOps>>)> {
+        for (i, port) in self.ports.iter().enumerate() {
+            if port.portsc & (PORTSC_POWNER | PORTSC_PED) != PORTSC_PED {
+                continue;
+            }
+            if let Some(dev) = port.usb_port.lock().unwrap().dev.as_ref() {
+                if dev.lock().unwrap().get_usb_device().addr == devaddr {
+                    return Some((i, dev.clone()));
+                }
+            }
+        }
+        None
+    }
+
+    fn read_u32(&self, addr: u32) -> Result<u32> {
+        let mut value = [0_u32; 1];
+        self.read_dwords(addr, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_dwords(&self, addr: u32, buf: &mut [u32]) -> Result<()> {
+        dma_read_u32(&self.mem_space, GuestAddress(addr as u64), buf)
+    }
+
+    fn write_dwords(&self, addr: u32, buf: &[u32]) -> Result<()> {
+        dma_write_u32(&self.mem_space, GuestAddress(addr as u64), buf)
+    }
+}
+
+fn port_index(offset: u64) -> Option<usize> {
+    if offset < EHCI_OPER_REG_PORTSC || offset % 4 != 0 {
+        return None;
+    }
+    let idx = ((offset - EHCI_OPER_REG_PORTSC) / 4) as usize;
+    if idx < EHCI_PORTS {
+        Some(idx)
+    } else {
+        None
+    }
+}
+
+/// Get the token of the retired qTD in the overlay, and whether a short
+/// packet is received.
+fn qtd_token_complete(
+    qh: &mut [u32; QH_DWORDS],
+    status: UsbPacketStatus,
+    actual_length: u32,
+) -> (u32, bool) {
+    let mut token = qh[QH_TOKEN] & !QTD_TOKEN_ACTIVE;
+    match status {
+        UsbPacketStatus::Success => {
+            let bytes = (token >> QTD_TOKEN_BYTES_SHIFT) & QTD_TOKEN_BYTES_MASK;
+            let actual = min(actual_length, bytes);
+            let left = bytes - actual;
+            token &= !(QTD_TOKEN_BYTES_MASK << QTD_TOKEN_BYTES_SHIFT);
+            token |= left << QTD_TOKEN_BYTES_SHIFT;
+
+            // Move the current page and offset forward.
+            let offset = (qh[QH_BUFFER] & (QTD_BUFFER_PAGE_SIZE - 1)) + actual;
+            let cpage = (token >> QTD_TOKEN_CPAGE_SHIFT) & QTD_TOKEN_CPAGE_MASK;
+            let cpage = min(
+                cpage + offset / QTD_BUFFER_PAGE_SIZE,
+                QTD_BUFFERS as u32 - 1,
+            );
+            token &= !(QTD_TOKEN_CPAGE_MASK << QTD_TOKEN_CPAGE_SHIFT);
+            token |= cpage << QTD_TOKEN_CPAGE_SHIFT;
+            qh[QH_BUFFER] = (qh[QH_BUFFER] & !(QTD_BUFFER_PAGE_SIZE - 1))
+                | (offset & (QTD_BUFFER_PAGE_SIZE - 1));
+
+            // The data toggle flips once per packet.
+            let mps = (qh[QH_EPCHAR] >> QH_EPCHAR_MPL_SHIFT) & QH_EPCHAR_MPL_MASK;
+            let packets = if mps == 0 || actual == 0 {
+                1
+            } else {
+                (actual + mps - 1) / mps
+            };
+            if packets % 2 == 1 {
+                token ^= QTD_TOKEN_DT;
+            }
+            let pid = (token >> QTD_TOKEN_PID_SHIFT) & QTD_TOKEN_PID_MASK;
+            return (token, pid == QTD_PID_IN && left > 0);
+        }
+        UsbPacketStatus::Stall => token |= QTD_TOKEN_HALTED,
+        UsbPacketStatus::Babble => token |= QTD_TOKEN_HALTED | QTD_TOKEN_BABBLE,
+        _ => {
+            token &= !(QTD_TOKEN_CERR_MASK << QTD_TOKEN_CERR_SHIFT);
+            token |= QTD_TOKEN_HALTED | QTD_TOKEN_XACTERR;
+        }
+    }
+    (token, false)
+}
+
+fn build_ehci_ops(ehci: &Arc<Mutex<EhciDevice>>) -> RegionOps {
+    let ehci_dev = ehci.clone();
+    let ehci_read = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+        let locked_ehci = ehci_dev.lock().unwrap();
+        let aligned = offset & !3;
+        let value = if aligned < EHCI_CAP_LENGTH {
+            locked_ehci.read_cap(aligned)
+        } else {
+            locked_ehci.read_oper(aligned - EHCI_CAP_LENGTH)
+        };
+        // The capability registers may be accessed by bytes.
+        write_data_u32(data, value >> ((offset & 3) * 8))
+    };
+
+    let ehci_dev = ehci.clone();
+    let ehci_write = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+        let mut value = 0;
+        if !read_data_u32(data, &mut value) {
+            return false;
+        }
+        if offset < EHCI_CAP_LENGTH {
+            error!("Failed to write ehci cap register: offset 0x{:x}", offset);
+            return true;
+        }
+        // The operational registers are accessed by dwords.
+        if data.len() != 4 || offset % 4 != 0 {
+            error!(
+                "Invalid ehci oper register access: offset 0x{:x} len {}",
+                offset,
+                data.len()
+            );
+            return true;
+        }
+        let mut locked_ehci = ehci_dev.lock().unwrap();
+        locked_ehci.write_oper(offset - EHCI_CAP_LENGTH, value);
+        true
+    };
+
+    RegionOps {
+        read: Arc::new(ehci_read),
+        write: Arc::new(ehci_write),
+    }
+}
+
+/// EHCI pci device which can be attached to PCI bus.
+pub struct EhciPciDevice {
+    pci_config: PciConfig,
+    devfn: u8,
+    pub ehci: Arc<Mutex<EhciDevice>>,
+    dev_id: Arc<AtomicU16>,
+    name: String,
+    parent_bus: Weak<Mutex<PciBus>>,
+    multi_func: bool,
+}
+
+impl EhciPciDevice {
+    pub fn new(
+        config: &EhciConfig,
+        devfn: u8,
+        parent_bus: Weak<Mutex<PciBus>>,
+        mem_space: &Arc<AddressSpace>,
+        multi_func: bool,
+    ) -> Self {
+        Self {
+            pci_config: PciConfig::new(PCI_CONFIG_SPACE_SIZE, 1),
+            devfn,
+            ehci: EhciDevice::new(mem_space),
+            dev_id: Arc::new(AtomicU16::new(0)),
+            name: config.id.clone().unwrap(),
+            parent_bus,
+            multi_func,
+        }
+    }
+
+    pub fn attach_device(&self, dev: &Arc<Mutex<dyn UsbDeviceOps>>) -> Result<()> {
+        self.ehci.lock().unwrap().attach_device(dev)
+    }
+
+    pub fn detach_device(&self, id: String) -> Result<()> {
+        self.ehci.lock().unwrap().detach_device(&id)
+    }
+}
+
+impl PciDevOps for EhciPciDevice {
+    fn init_write_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_mask()
+    }
+
+    fn init_write_clear_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_clear_mask()
+    }
+
+    fn realize(mut self) -> pci::Result<()> {
+        self.init_write_mask()?;
+        self.init_write_clear_mask()?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            VENDOR_ID as usize,
+            PCI_VENDOR_ID_INTEL,
+        )?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            DEVICE_ID as usize,
+            PCI_DEVICE_ID_INTEL_ICH9_EHCI1,
+        )?;
+        self.pci_config.config[REVISION_ID] = 0x3;
+        le_write_u16(
+            &mut self.pci_config.config,
+            SUB_CLASS_CODE as usize,
+            PCI_CLASS_SERIAL_USB,
+        )?;
+        self.pci_config.config[PCI_CLASS_PI] = PCI_CLASS_PI_EHCI;
+        self.pci_config.config[PCI_SERIAL_BUS_RELEASE_NUMBER] = PCI_SERIAL_BUS_RELEASE_VERSION_2_0;
+        self.pci_config.config[PCI_FRAME_LENGTH_ADJUSTMENT] = PCI_FRAME_LENGTH_ADJUSTMENT_DEFAULT;
+
+        #[cfg(target_arch = "aarch64")]
+        self.pci_config.set_interrupt_pin();
+
+        init_multifunction(
+            self.multi_func,
+            &mut self.pci_config.config,
+            self.devfn,
+            self.parent_bus.clone(),
+        )?;
+        self.dev_id.store(self.devfn as u16, Ordering::SeqCst);
+
+        let mem_region =
+            Region::init_io_region(EHCI_MMIO_SIZE, build_ehci_ops(&self.ehci), "EhciPciRegion");
+        self.pci_config.register_bar(
+            0_usize,
+            mem_region,
+            RegionType::Mem32Bit,
+            false,
+            EHCI_MMIO_SIZE,
+        )?;
+
+        init_intx(
+            self.name.clone(),
+            &mut self.pci_config,
+            self.parent_bus.clone(),
+            self.devfn,
+        )?;
+        // EHCI has no MSI, the interrupt is delivered through INTx.
+        let cloned_intx = self.pci_config.intx.as_ref().unwrap().clone();
+        self.ehci
+            .lock()
+            .unwrap()
+            .set_interrupt_ops(Arc::new(move |level: u32| {
+                cloned_intx.lock().unwrap().notify(level as u8);
+            }));
+
+        let devfn = self.devfn;
+        let dev = Arc::new(Mutex::new(self));
+        // Attach to the PCI bus.
+        let pci_bus = dev.lock().unwrap().parent_bus.upgrade().unwrap();
+        let mut locked_pci_bus = pci_bus.lock().unwrap();
+        let pci_device = locked_pci_bus.devices.get(&devfn);
+        if pci_device.is_none() {
+            locked_pci_bus.devices.insert(devfn, dev);
+        } else {
+            bail!(
+                "Devfn {:?} has been used by {:?}",
+                &devfn,
+                pci_device.unwrap().lock().unwrap().name()
+            );
+        }
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> pci::Result<()> {
+        Ok(())
+    }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+
+    fn read_config(&mut self, offset: usize, data: &mut [u8]) {
+        self.pci_config.read(offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+
+        self.pci_config.write(
+            offset,
+            data,
+            self.dev_id.load(Ordering::Acquire),
+            #[cfg(target_arch = "x86_64")]
+            Some(&locked_parent_bus.io_region),
+            Some(&locked_parent_bus.mem_region),
+        );
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn reset(&mut self, _reset_child_device: bool) -> pci::Result<()> {
+        self.ehci.lock().unwrap().reset();
+
+        self.pci_config.reset()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qh_with_token(token: u32, buffer: u32, mps: u32) -> [u32; QH_DWORDS] {
+        let mut qh = [0_u32; QH_DWORDS];
+        qh[QH_EPCHAR] = mps << QH_EPCHAR_MPL_SHIFT;
+        qh[QH_TOKEN] = token;
+        qh[QH_BUFFER] = buffer;
+        qh
+    }
+
+    #[test]
+    fn test_qtd_token_complete() {
+        // IN qTD of 0x2000 bytes from offset 0x800 in page 0, fully transferred.
+        let token = QTD_TOKEN_ACTIVE | QTD_PID_IN << QTD_TOKEN_PID_SHIFT | 0x2000 << 16;
+        let mut qh = qh_with_token(token, 0x1000_0800, 512);
+        let (token, short) = qtd_token_complete(&mut qh, UsbPacketStatus::Success, 0x2000);
+        assert!(!short);
+        assert_eq!(token & QTD_TOKEN_ACTIVE, 0);
+        assert_eq!((token >> QTD_TOKEN_BYTES_SHIFT) & QTD_TOKEN_BYTES_MASK, 0);
+        assert_eq!((token >> QTD_TOKEN_CPAGE_SHIFT) & QTD_TOKEN_CPAGE_MASK, 2);
+        assert_eq!(qh[QH_BUFFER], 0x1000_0800);
+        // 16 packets keep the data toggle.
+        assert_eq!(token & QTD_TOKEN_DT, 0);
+
+        // Short packet of 13 bytes flips the toggle once.
+        let token = QTD_TOKEN_ACTIVE | QTD_PID_IN << QTD_TOKEN_PID_SHIFT | 64 << 16;
+        let mut qh = qh_with_token(token, 0x2000_0000, 64);
+        let (token, short) = qtd_token_complete(&mut qh, UsbPacketStatus::Success, 13);
+        assert!(short);
+        assert_eq!((token >> QTD_TOKEN_BYTES_SHIFT) & QTD_TOKEN_BYTES_MASK, 51);
+        assert_eq!(token & QTD_TOKEN_DT, QTD_TOKEN_DT);
+        assert_eq!(qh[QH_BUFFER], 0x2000_000d);
+
+        // Stall halts the queue and keeps the length.
+        let token = QTD_TOKEN_ACTIVE | QTD_PID_OUT << QTD_TOKEN_PID_SHIFT | 31 << 16;
+        let mut qh = qh_with_token(token, 0, 64);
+        let (token, short) = qtd_token_complete(&mut qh, UsbPacketStatus::Stall, 0);
+        assert!(!short);
+        assert_eq!(
+            token & (QTD_TOKEN_ACTIVE | QTD_TOKEN_HALTED),
+            QTD_TOKEN_HALTED
+        );
+        assert_eq!((token >> QTD_TOKEN_BYTES_SHIFT) & QTD_TOKEN_BYTES_MASK, 31);
+
+        // Missing device is a transaction error.
+        let token = QTD_TOKEN_ACTIVE | 3 << QTD_TOKEN_CERR_SHIFT;
+        let mut qh = qh_with_token(token, 0, 64);
+        let (token, _) = qtd_token_complete(&mut qh, UsbPacketStatus::NoDev, 0);
+        assert_eq!(
+            token & (QTD_TOKEN_HALTED | QTD_TOKEN_XACTERR),
+            QTD_TOKEN_HALTED | QTD_TOKEN_XACTERR
+        );
+        assert_eq!((token >> QTD_TOKEN_CERR_SHIFT) & QTD_TOKEN_CERR_MASK, 0);
+    }
+
+    #[test]
+    fn test_ehci_port_index() {
+        assert_eq!(port_index(EHCI_OPER_REG_PORTSC), Some(0));
+        assert_eq!(port_index(EHCI_OPER_REG_PORTSC + 4 * 5), Some(5));
+        assert_eq!(port_index(EHCI_OPER_REG_PORTSC + 4 * 6), None);
+        assert_eq!(port_index(EHCI_OPER_REG_CONFIGFLAG), None);
+        assert_eq!(port_index(EHCI_OPER_REG_PORTSC + 2), None);
+    }
+}
@@ -0,0 +1,574 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::Result;
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+
+use machine_manager::config::UsbHubConfig;
+
+use super::descriptor::{
+    UsbConfigDescriptor, UsbDescConfig, UsbDescDevice, UsbDescEndpoint, UsbDescIface,
+    UsbDescriptorOps, UsbDeviceDescriptor, UsbEndpointDescriptor, UsbInterfaceDescriptor,
+};
+use super::xhci::xhci_controller::{UsbPort, XhciDevice};
+use super::{config::*, USB_DEVICE_BUFFER_DEFAULT_LEN};
+use super::{UsbDevice, UsbDeviceOps, UsbDeviceRequest, UsbEndpoint, UsbPacket, UsbPacketStatus};
+
+/// Hub device descriptor
+static DESC_DEVICE_HUB: Lazy<Arc<UsbDescDevice>> = Lazy::new(|| {
+    Arc::new(UsbDescDevice {
+        device_desc: UsbDeviceDescriptor {
+            bLength: USB_DT_DEVICE_SIZE,
+            bDescriptorType: USB_DT_DEVICE,
+            idVendor: 0x0409,
+            idProduct: 0x55aa,
+            bcdDevice: 0x0101,
+            iManufacturer: STR_MANUFACTURER_INDEX,
+            iProduct: STR_PRODUCT_HUB_INDEX,
+            iSerialNumber: STR_SERIAL_HUB_INDEX,
+            bcdUSB: 0x0200,
+            bDeviceClass: USB_CLASS_HUB,
+            bDeviceSubClass: 0,
+            // Single transaction translator.
+            bDeviceProtocol: 1,
+            bMaxPacketSize0: 64,
+            bNumConfigurations: 1,
+        },
+        configs: vec![Arc::new(UsbDescConfig {
+            config_desc: UsbConfigDescriptor {
+                bLength: USB_DT_CONFIG_SIZE,
+                bDescriptorType: USB_DT_CONFIGURATION,
+                wTotalLength: 0,
+                bNumInterfaces: 1,
+                bConfigurationValue: 1,
+                iConfiguration: 0,
+                bmAttributes: USB_CONFIGURATION_ATTR_ONE
+                    | USB_CONFIGURATION_ATTR_SELF_POWER
+                    | USB_CONFIGURATION_ATTR_REMOTE_WAKEUP,
+                bMaxPower: 0,
+            },
+            iad_desc: vec![],
+            interfaces: vec![DESC_IFACE_HUB.clone()],
+        })],
+    })
+});
+
+/// Hub interface descriptor
+static DESC_IFACE_HUB: Lazy<Arc<UsbDescIface>> = Lazy::new(|| {
+    Arc::new(UsbDescIface {
+        interface_desc: UsbInterfaceDescriptor {
+            bLength: USB_DT_INTERFACE_SIZE,
+            bDescriptorType: USB_DT_INTERFACE,
+            bInterfaceNumber: 0,
+            bAlternateSetting: 0,
+            bNumEndpoints: 1,
+            bInterfaceClass: USB_CLASS_HUB,
+            bInterfaceSubClass: 0,
+            bInterfaceProtocol: 0,
+            iInterface: 0,
+        },
+        other_desc: vec![],
+        endpoints: vec![Arc::new(UsbDescEndpoint {
+            endpoint_desc: UsbEndpointDescriptor {
+                bLength: USB_DT_ENDPOINT_SIZE,
+                bDescriptorType: USB_DT_ENDPOINT,
+                bEndpointAddress: USB_DIRECTION_DEVICE_TO_HOST | 0x1,
+                bmAttributes: USB_ENDPOINT_ATTR_INT,
+                // Enough for the status change bitmap of the max ports.
+                wMaxPacketSize: 2,
+                bInterval: 0xc,
+            },
+            extra: Vec::new(),
+        })],
+    })
+});
+
+/// String descriptor index
+const STR_MANUFACTURER_INDEX: u8 = 1;
+const STR_PRODUCT_HUB_INDEX: u8 = 2;
+const STR_SERIAL_HUB_INDEX: u8 = 3;
+
+/// String descriptor
+const DESC_STRINGS: [&str; 4] = ["", "StratoVirt", "StratoVirt USB Hub", "3"];
+
+/// Hub class descriptor type.
+const USB_DT_HUB: u8 = 0x29;
+
+/// Hub class request type.
+const USB_HUB_IN_REQUEST: u8 = USB_DIRECTION_DEVICE_TO_HOST | USB_TYPE_CLASS | USB_RECIPIENT_DEVICE;
+const USB_HUB_OUT_REQUEST: u8 =
+    USB_DIRECTION_HOST_TO_DEVICE | USB_TYPE_CLASS | USB_RECIPIENT_DEVICE;
+const USB_PORT_IN_REQUEST: u8 = USB_DIRECTION_DEVICE_TO_HOST | USB_TYPE_CLASS | USB_RECIPIENT_OTHER;
+const USB_PORT_OUT_REQUEST: u8 =
+    USB_DIRECTION_HOST_TO_DEVICE | USB_TYPE_CLASS | USB_RECIPIENT_OTHER;
+
+/// Transaction translator requests.
+const USB_HUB_REQUEST_CLEAR_TT_BUFFER: u8 = 8;
+const USB_HUB_REQUEST_RESET_TT: u8 = 9;
+const USB_HUB_REQUEST_STOP_TT: u8 = 11;
+
+/// Port features.
+const USB_PORT_FEAT_ENABLE: u16 = 1;
+const USB_PORT_FEAT_SUSPEND: u16 = 2;
+const USB_PORT_FEAT_RESET: u16 = 4;
+const USB_PORT_FEAT_POWER: u16 = 8;
+const USB_PORT_FEAT_C_CONNECTION: u16 = 16;
+const USB_PORT_FEAT_C_ENABLE: u16 = 17;
+const USB_PORT_FEAT_C_SUSPEND: u16 = 18;
+const USB_PORT_FEAT_C_OVER_CURRENT: u16 = 19;
+const USB_PORT_FEAT_C_RESET: u16 = 20;
+const USB_PORT_FEAT_TEST: u16 = 21;
+const USB_PORT_FEAT_INDICATOR: u16 = 22;
+
+/// Port status.
+const USB_PORT_STAT_CONNECTION: u16 = 0x0001;
+const USB_PORT_STAT_ENABLE: u16 = 0x0002;
+const USB_PORT_STAT_SUSPEND: u16 = 0x0004;
+const USB_PORT_STAT_POWER: u16 = 0x0100;
+const USB_PORT_STAT_LOW_SPEED: u16 = 0x0200;
+const USB_PORT_STAT_HIGH_SPEED: u16 = 0x0400;
+
+/// Port status change.
+const USB_PORT_STAT_C_CONNECTION: u16 = 0x0001;
+const USB_PORT_STAT_C_ENABLE: u16 = 0x0002;
+const USB_PORT_STAT_C_SUSPEND: u16 = 0x0004;
+const USB_PORT_STAT_C_OVERCURRENT: u16 = 0x0008;
+const USB_PORT_STAT_C_RESET: u16 = 0x0010;
+
+/// Downstream port of the hub.
+struct UsbHubPort {
+    port: Arc<Mutex<UsbPort>>,
+    status: u16,
+    change: u16,
+}
+
+impl UsbHubPort {
+    fn new(port_id: u8) -> Self {
+        let mut port = UsbPort::new(&Weak::new(), port_id);
+        port.speed_mask = USB_SPEED_MASK_LOW | USB_SPEED_MASK_FULL | USB_SPEED_MASK_HIGH;
+        Self {
+            port: Arc::new(Mutex::new(port)),
+            status: USB_PORT_STAT_POWER,
+            change: 0,
+        }
+    }
+
+    /// Update the port status by the attached device.
+    fn update(&mut self, detach: bool) {
+        let locked_port = self.port.lock().unwrap();
+        let dev = locked_port.dev.as_ref().filter(|_| !detach);
+        match dev {
+            Some(dev) if dev.lock().unwrap().connected() => {
+                let speed = dev.lock().unwrap().speed();
+                self.status |= USB_PORT_STAT_CONNECTION;
+                self.status &= !(USB_PORT_STAT_LOW_SPEED | USB_PORT_STAT_HIGH_SPEED);
+                if speed == USB_SPEED_LOW {
+                    self.status |= USB_PORT_STAT_LOW_SPEED;
+                } else if speed == USB_SPEED_HIGH {
+                    self.status |= USB_PORT_STAT_HIGH_SPEED;
+                }
+                self.change |= USB_PORT_STAT_C_CONNECTION;
+            }
+            _ => {
+                if self.status & USB_PORT_STAT_CONNECTION == 0 {
+                    return;
+                }
+                if self.status & USB_PORT_STAT_ENABLE != 0 {
+                    self.change |= USB_PORT_STAT_C_ENABLE;
+                }
+                self.status &= !(USB_PORT_STAT_CONNECTION
+                    | USB_PORT_STAT_ENABLE
+                    | USB_PORT_STAT_SUSPEND
+                    | USB_PORT_STAT_LOW_SPEED
+                    | USB_PORT_STAT_HIGH_SPEED);
+                self.change |= USB_PORT_STAT_C_CONNECTION;
+            }
+        }
+    }
+}
+
+/// USB hub device, which extends the downstream ports of the controller.
+pub struct UsbHub {
+    usb_device: UsbDevice,
+    /// USB controller used to notify controller to transfer data.
+    cntlr: Option<Weak<Mutex<XhciDevice>>>,
+    ports: Vec<UsbHubPort>,
+}
+
+impl UsbHub {
+    pub fn new(config: UsbHubConfig) -> Self {
+        Self {
+            usb_device: UsbDevice::new(config.id.unwrap(), USB_DEVICE_BUFFER_DEFAULT_LEN),
+            cntlr: None,
+            ports: (1..=config.ports).map(UsbHubPort::new).collect(),
+        }
+    }
+
+    /// Length of the bitmap with one bit for the hub and each port.
+    fn bitmap_len(&self) -> usize {
+        (self.ports.len() + 1 + 7) / 8
+    }
+
+    fn hub_descriptor(&self) -> Vec<u8> {
+        let bitmap_len = self.bitmap_len();
+        let mut desc = vec![
+            (7 + bitmap_len * 2) as u8,
+            USB_DT_HUB,
+            self.ports.len() as u8,
+            // Per-port power switching and over-current protection.
+            0x09,
+            0x00,
+            // Power on to power good time in 2ms.
+            0x01,
+            0x00,
+        ];
+        // All the devices are removable.
+        desc.resize(desc.len() + bitmap_len, 0);
+        desc.resize(desc.len() + bitmap_len, 0xff);
+        desc
+    }
+
+    fn get_port(&mut self, index: u16) -> Option<&mut UsbHubPort> {
+        if index == 0 {
+            return None;
+        }
+        self.ports.get_mut(index as usize - 1)
+    }
+
+    fn set_port_feature(&mut self, index: u16, feature: u16) -> Result<(), UsbPacketStatus> {
+        let port = self.get_port(index).ok_or(UsbPacketStatus::Stall)?;
+        match feature {
+            USB_PORT_FEAT_SUSPEND => port.status |= USB_PORT_STAT_SUSPEND,
+            USB_PORT_FEAT_RESET => {
+                if let Some(dev) = port.port.lock().unwrap().dev.as_ref() {
+                    dev.lock().unwrap().reset();
+                }
+                if port.status & USB_PORT_STAT_CONNECTION != 0 {
+                    port.status |= USB_PORT_STAT_ENABLE;
+                    port.change |= USB_PORT_STAT_C_RESET;
+                }
+            }
+            USB_PORT_FEAT_POWER | USB_PORT_FEAT_TEST | USB_PORT_FEAT_INDICATOR => {}
+            _ => {
+                debug!("Unsupported hub port feature {}", feature);
+                return Err(UsbPacketStatus::Stall);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear_port_feature(&mut self, index: u16, feature: u16) -> Result<(), UsbPacketStatus> {
+        let port = self.get_port(index).ok_or(UsbPacketStatus::Stall)?;
+        match feature {
+            USB_PORT_FEAT_ENABLE => port.status &= !USB_PORT_STAT_ENABLE,
+            USB_PORT_FEAT_SUSPEND => {
+                if port.status & USB_PORT_STAT_SUSPEND != 0 {
+                    port.status &= !USB_PORT_STAT_SUSPEND;
+                    port.change |= USB_PORT_STAT_C_SUSPEND;
+                }
+            }
+            USB_PORT_FEAT_C_CONNECTION => port.change &= !USB_PORT_STAT_C_CONNECTION,
+            USB_PORT_FEAT_C_ENABLE => port.change &= !USB_PORT_STAT_C_ENABLE,
+            USB_PORT_FEAT_C_SUSPEND => port.change &= !USB_PORT_STAT_C_SUSPEND,
+            USB_PORT_FEAT_C_OVER_CURRENT => port.change &= !USB_PORT_STAT_C_OVERCURRENT,
+            USB_PORT_FEAT_C_RESET => port.change &= !USB_PORT_STAT_C_RESET,
+            USB_PORT_FEAT_POWER | USB_PORT_FEAT_INDICATOR => {}
+            _ => {
+                debug!("Unsupported hub port feature {}", feature);
+                return Err(UsbPacketStatus::Stall);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the hub class request, return the length of the data.
+    fn handle_hub_request(
+        &mut self,
+        device_req: &UsbDeviceRequest,
+    ) -> Result<u32, UsbPacketStatus> {
+        match (device_req.request_type, device_req.request) {
+            (USB_HUB_IN_REQUEST, USB_REQUEST_GET_STATUS) => {
+                self.usb_device.data_buf[..4].fill(0);
+                Ok(4)
+            }
+            (USB_HUB_IN_REQUEST, USB_REQUEST_GET_DESCRIPTOR) => {
+                if (device_req.value >> 8) as u8 != USB_DT_HUB {
+                    return Err(UsbPacketStatus::Stall);
+                }
+                let desc = self.hub_descriptor();
+                self.usb_device.data_buf[..desc.len()].copy_from_slice(&desc);
+                Ok(desc.len() as u32)
+            }
+            (USB_HUB_OUT_REQUEST, USB_REQUEST_CLEAR_FEATURE)
+            | (USB_HUB_OUT_REQUEST, USB_REQUEST_SET_FEATURE) => Ok(0),
+            (USB_PORT_IN_REQUEST, USB_REQUEST_GET_STATUS) => {
+                let port = self
+                    .get_port(device_req.index)
+                    .ok_or(UsbPacketStatus::Stall)?;
+                let status = port.status.to_le_bytes();
+                let change = port.change.to_le_bytes();
+                self.usb_device.data_buf[..2].copy_from_slice(&status);
+                self.usb_device.data_buf[2..4].copy_from_slice(&change);
+                Ok(4)
+            }
+            (USB_PORT_OUT_REQUEST, USB_REQUEST_SET_FEATURE) => {
+                self.set_port_feature(device_req.index, device_req.value)?;
+                Ok(0)
+            }
+            (USB_PORT_OUT_REQUEST, USB_REQUEST_CLEAR_FEATURE) => {
+                self.clear_port_feature(device_req.index, device_req.value)?;
+                Ok(0)
+            }
+            (USB_PORT_OUT_REQUEST, USB_HUB_REQUEST_CLEAR_TT_BUFFER)
+            | (USB_PORT_OUT_REQUEST, USB_HUB_REQUEST_RESET_TT)
+            | (USB_PORT_OUT_REQUEST, USB_HUB_REQUEST_STOP_TT) => {
+                // Transaction translator is not emulated.
+                Ok(0)
+            }
+            _ => {
+                error!("Unsupported hub request {:?}", device_req);
+                Err(UsbPacketStatus::Stall)
+            }
+        }
+    }
+}
+
+impl UsbDeviceOps for UsbHub {
+    fn realize(mut self) -> Result<Arc<Mutex<dyn UsbDeviceOps>>> {
+        self.usb_device.reset_usb_endpoint();
+        self.usb_device.speed = USB_SPEED_HIGH;
+        let mut s: Vec<String> = DESC_STRINGS.iter().map(|&s| s.to_string()).collect();
+        let prefix = &s[STR_SERIAL_HUB_INDEX as usize];
+        s[STR_SERIAL_HUB_INDEX as usize] = self.usb_device.generate_serial_number(prefix);
+        self.usb_device
+            .init_descriptor(DESC_DEVICE_HUB.clone(), s)?;
+
+        let ports: Vec<Arc<Mutex<UsbPort>>> = self.get_child_ports();
+        let hub: Arc<Mutex<dyn UsbDeviceOps>> = Arc::new(Mutex::new(self));
+        for port in ports {
+            port.lock().unwrap().hub = Some(Arc::downgrade(&hub));
+        }
+        Ok(hub)
+    }
+
+    fn reset(&mut self) {
+        info!("Hub device reset");
+        self.usb_device.remote_wakeup = 0;
+        self.usb_device.addr = 0;
+        for port in self.ports.iter_mut() {
+            port.status = USB_PORT_STAT_POWER;
+            port.change = 0;
+            port.update(false);
+        }
+    }
+
+    fn get_child_ports(&self) -> Vec<Arc<Mutex<UsbPort>>> {
+        self.ports.iter().map(|port| port.port.clone()).collect()
+    }
+
+    fn child_port_update(&mut self, port: &Arc<Mutex<UsbPort>>, detach: bool) {
+        if let Some(hub_port) = self
+            .ports
+            .iter_mut()
+            .find(|hub_port| Arc::ptr_eq(&hub_port.port, port))
+        {
+            hub_port.update(detach);
+        }
+    }
+
+    fn handle_control(&mut self, packet: &Arc<Mutex<UsbPacket>>, device_req: &UsbDeviceRequest) {
+        debug!("handle_control request {:?}", device_req);
+        let mut locked_packet = packet.lock().unwrap();
+        match self
+            .usb_device
+            .handle_control_for_descriptor(&mut locked_packet, device_req)
+        {
+            Ok(handled) => {
+                if handled {
+                    debug!("Hub control handled by descriptor, return directly.");
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("Hub descriptor error {:?}", e);
+                locked_packet.status = UsbPacketStatus::Stall;
+                return;
+            }
+        }
+        match self.handle_hub_request(device_req) {
+            Ok(len) => locked_packet.actual_length = len,
+            Err(status) => locked_packet.status = status,
+        }
+    }
+
+    fn handle_data(&mut self, packet: &Arc<Mutex<UsbPacket>>) {
+        let mut locked_packet = packet.lock().unwrap();
+        if locked_packet.ep_number != 1 || locked_packet.pid as u8 != USB_TOKEN_IN {
+            locked_packet.status = UsbPacketStatus::Stall;
+            return;
+        }
+        // Bit 0 is for the hub, and bit N is for the port N.
+        let mut bitmap = vec![0_u8; self.bitmap_len()];
+        for (i, port) in self.ports.iter().enumerate() {
+            if port.change != 0 {
+                bitmap[(i + 1) / 8] |= 1 << ((i + 1) % 8);
+            }
+        }
+        if bitmap.iter().all(|&b| b == 0) {
+            locked_packet.status = UsbPacketStatus::Nak;
+            return;
+        }
+        let len = bitmap.len();
+        locked_packet.transfer_packet(&mut bitmap, len);
+    }
+
+    fn get_usb_device(&self) -> &UsbDevice {
+        &self.usb_device
+    }
+
+    fn get_mut_usb_device(&mut self) -> &mut UsbDevice {
+        &mut self.usb_device
+    }
+
+    fn set_controller(&mut self, cntlr: Weak<Mutex<XhciDevice>>) {
+        for port in &self.ports {
+            port.port.lock().unwrap().xhci = cntlr.clone();
+        }
+        self.cntlr = Some(cntlr);
+    }
+
+    fn get_controller(&self) -> Option<Weak<Mutex<XhciDevice>>> {
+        self.cntlr.clone()
+    }
+
+    fn get_wakeup_endpoint(&self) -> &UsbEndpoint {
+        self.usb_device.get_endpoint(true, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::keyboard::UsbKeyboard;
+    use util::aio::Iovec;
+
+    fn hub_request(hub: &mut UsbHub, request_type: u8, request: u8, value: u16, index: u16) {
+        let packet = Arc::new(Mutex::new(UsbPacket::default()));
+        let device_req = UsbDeviceRequest {
+            request_type,
+            request,
+            value,
+            index,
+            length: 4,
+        };
+        hub.handle_control(&packet, &device_req);
+        assert_eq!(packet.lock().unwrap().status, UsbPacketStatus::Success);
+    }
+
+    fn port_status(hub: &mut UsbHub, index: u16) -> (u16, u16) {
+        hub_request(hub, USB_PORT_IN_REQUEST, USB_REQUEST_GET_STATUS, 0, index);
+        let buf = &hub.usb_device.data_buf;
+        (
+            u16::from_le_bytes([buf[0], buf[1]]),
+            u16::from_le_bytes([buf[2], buf[3]]),
+        )
+    }
+
+    #[test]
+    fn test_usb_hub_port_status() {
+        let config = UsbHubConfig {
+            id: Some("hub0".to_string()),
+            ports: 8,
+        };
+        let mut hub = UsbHub::new(config);
+        hub.usb_device.reset_usb_endpoint();
+        assert_eq!(
+            hub.hub_descriptor(),
+            [11, 0x29, 8, 9, 0, 1, 0, 0, 0, 0xff, 0xff]
+        );
+        assert_eq!(port_status(&mut hub, 3), (USB_PORT_STAT_POWER, 0));
+
+        // Attach a full speed device to port 3.
+        let kbd = UsbKeyboard::new("kbd0".to_string()).realize().unwrap();
+        let port = hub.get_child_ports()[2].clone();
+        port.lock().unwrap().dev = Some(kbd);
+        port.lock().unwrap().used = true;
+        hub.child_port_update(&port, false);
+        assert_eq!(
+            port_status(&mut hub, 3),
+            (
+                USB_PORT_STAT_POWER | USB_PORT_STAT_CONNECTION,
+                USB_PORT_STAT_C_CONNECTION
+            )
+        );
+
+        // The status change bitmap reports port 3.
+        let buf = [0_u8; 2];
+        let iovecs = vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)];
+        let packet = Arc::new(Mutex::new(UsbPacket::new(
+            USB_TOKEN_IN as u32,
+            1,
+            iovecs,
+            None,
+        )));
+        hub.handle_data(&packet);
+        assert_eq!(packet.lock().unwrap().actual_length, 2);
+        assert_eq!(buf, [1 << 3, 0]);
+
+        // Reset the port to enable it.
+        hub_request(
+            &mut hub,
+            USB_PORT_OUT_REQUEST,
+            USB_REQUEST_CLEAR_FEATURE,
+            USB_PORT_FEAT_C_CONNECTION,
+            3,
+        );
+        hub_request(
+            &mut hub,
+            USB_PORT_OUT_REQUEST,
+            USB_REQUEST_SET_FEATURE,
+            USB_PORT_FEAT_RESET,
+            3,
+        );
+        let (status, change) = port_status(&mut hub, 3);
+        assert_ne!(status & USB_PORT_STAT_ENABLE, 0);
+        assert_eq!(change, USB_PORT_STAT_C_RESET);
+        hub_request(
+            &mut hub,
+            USB_PORT_OUT_REQUEST,
+            USB_REQUEST_CLEAR_FEATURE,
+            USB_PORT_FEAT_C_RESET,
+            3,
+        );
+        let packet = Arc::new(Mutex::new(UsbPacket::new(
+            USB_TOKEN_IN as u32,
+            1,
+            Vec::new(),
+            None,
+        )));
+        hub.handle_data(&packet);
+        assert_eq!(packet.lock().unwrap().status, UsbPacketStatus::Nak);
+
+        // Detach the device.
+        hub.child_port_update(&port, true);
+        assert_eq!(
+            port_status(&mut hub, 3),
+            (
+                USB_PORT_STAT_POWER,
+                USB_PORT_STAT_C_CONNECTION | USB_PORT_STAT_C_ENABLE
+            )
+        );
+    }
+}
@@ -15,23 +15,33 @@ pub use anyhow::Result;
 pub use error::UsbError;
 use util::byte_code::ByteCode;
 
+#[cfg(not(target_env = "musl"))]
+pub mod audio;
 #[cfg(not(target_env = "musl"))]
 pub mod camera;
 #[cfg(not(target_env = "musl"))]
 pub mod camera_media_type_guid;
 pub mod config;
 mod descriptor;
+pub mod ehci;
 #[cfg(not(target_env = "musl"))]
 pub mod hid;
+#[cfg(not(target_env = "musl"))]
+pub mod hub;
 
 #[cfg(not(target_env = "musl"))]
 pub mod keyboard;
 #[cfg(not(target_env = "musl"))]
+pub mod serial;
+#[cfg(not(target_env = "musl"))]
 pub mod storage;
 #[cfg(not(target_env = "musl"))]
 pub mod tablet;
+pub mod uhci;
 #[cfg(not(target_env = "musl"))]
 pub mod usbhost;
+#[cfg(not(target_env = "musl"))]
+pub mod usbredir;
 pub mod xhci;
 
 use std::cmp::min;
@@ -351,6 +361,12 @@ pub trait UsbDeviceOps: Send + Sync {
     /// Reset the USB device.
     fn reset(&mut self);
 
+    /// Whether the device is present on the port. A redirected device is absent
+    /// until the remote side connects it.
+    fn connected(&self) -> bool {
+        true
+    }
+
     /// Set the controller which the USB device attached.
     /// USB device need to kick controller in some cases.
     fn set_controller(&mut self, cntlr: Weak<Mutex<XhciDevice>>);
@@ -361,6 +377,15 @@ pub trait UsbDeviceOps: Send + Sync {
     /// Get the endpoint to wakeup.
     fn get_wakeup_endpoint(&self) -> &UsbEndpoint;
 
+    /// Get the downstream ports of the hub, which is empty for the other devices.
+    fn get_child_ports(&self) -> Vec<Arc<Mutex<UsbPort>>> {
+        Vec::new()
+    }
+
+    /// Update the status of the downstream port of the hub when the device is
+    /// attached or detached.
+    fn child_port_update(&mut self, _port: &Arc<Mutex<UsbPort>>, _detach: bool) {}
+
     /// Set the attached USB port.
     fn set_usb_port(&mut self, port: Option<Weak<Mutex<UsbPort>>>) {
         let usb_dev = self.get_mut_usb_device();
@@ -389,6 +414,14 @@ pub trait UsbDeviceOps: Send + Sync {
     /// Handle data pakcet.
     fn handle_data(&mut self, packet: &Arc<Mutex<UsbPacket>>);
 
+    /// Allocate streams for the bulk endpoint, stream 0 is reserved and not included.
+    fn alloc_streams(&mut self, _ep_addr: u8, _streams: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Free the streams of the bulk endpoint.
+    fn free_streams(&mut self, _ep_addr: u8) {}
+
     /// Unique device id.
     fn device_id(&self) -> &str {
         &self.get_usb_device().id
@@ -447,6 +480,10 @@ pub fn notify_controller(dev: &Arc<Mutex<dyn UsbDeviceOps>>) -> Result<()> {
     let locked_dev = dev.lock().unwrap();
     let xhci = if let Some(cntlr) = &locked_dev.get_controller() {
         cntlr.upgrade().unwrap()
+    } else if locked_dev.get_usb_device().port.is_some() {
+        // The device is attached to EHCI or UHCI, which polls the endpoints in
+        // the frame schedule, so there is nothing to kick.
+        return Ok(());
     } else {
         bail!("USB controller not found");
     };
@@ -486,6 +523,214 @@ pub trait TransferOps: Send + Sync {
     fn submit_transfer(&mut self);
 }
 
+/// Completion of the packet whose owner polls it in the frame schedule.
+struct PolledTransfer {
+    packet: Arc<Mutex<UsbPacket>>,
+}
+
+impl TransferOps for PolledTransfer {
+    fn submit_transfer(&mut self) {
+        self.packet.lock().unwrap().is_async = false;
+    }
+}
+
+/// USB packet of the host controllers which check the completion of the
+/// asynchronous packets in the frame schedule, such as EHCI and UHCI.
+pub struct PolledPacket {
+    pub packet: Arc<Mutex<UsbPacket>>,
+    _xfer: Arc<Mutex<PolledTransfer>>,
+}
+
+impl PolledPacket {
+    pub fn new(pid: u32, ep_number: u8, iovecs: Vec<Iovec>) -> Self {
+        let packet = Arc::new(Mutex::new(UsbPacket::new(pid, ep_number, iovecs, None)));
+        let xfer = Arc::new(Mutex::new(PolledTransfer {
+            packet: packet.clone(),
+        }));
+        let xfer_ops = Arc::downgrade(&xfer) as Weak<Mutex<dyn TransferOps>>;
+        packet.lock().unwrap().xfer_ops = Some(xfer_ops);
+        Self {
+            packet,
+            _xfer: xfer,
+        }
+    }
+
+    /// Whether the device has finished the packet.
+    pub fn completed(&self) -> bool {
+        !self.packet.lock().unwrap().is_async
+    }
+}
+
+/// Length of the setup packet of the control transfer.
+const USB_SETUP_PACKET_LEN: usize = 8;
+/// Max length of the data stage of the control transfer.
+const USB_CONTROL_BUFFER_LEN: usize = u16::MAX as usize;
+
+/// Control transfer of the host controllers which send the setup, data and
+/// status stage as separate tokens, such as EHCI and UHCI. The request is
+/// handed to the device as a whole, like the xHCI control TD.
+pub struct UsbControlPipe {
+    /// Whether a setup token has been received.
+    active: bool,
+    /// Whether the request has been handed to the device.
+    executed: bool,
+    in_direction: bool,
+    parameter: u64,
+    /// Data stage buffer, which keeps its address while the request is in flight.
+    buf: Vec<u8>,
+    /// Length of the valid data in the buffer.
+    len: usize,
+    /// Length of the data transferred in the data stage.
+    pos: usize,
+    status: UsbPacketStatus,
+    request: Option<PolledPacket>,
+}
+
+impl Default for UsbControlPipe {
+    fn default() -> Self {
+        Self {
+            active: false,
+            executed: false,
+            in_direction: false,
+            parameter: 0,
+            buf: vec![0_u8; USB_CONTROL_BUFFER_LEN],
+            len: 0,
+            pos: 0,
+            status: UsbPacketStatus::Success,
+            request: None,
+        }
+    }
+}
+
+impl UsbControlPipe {
+    /// Abort the current control transfer, e.g. when the port is reset.
+    pub fn reset(&mut self) {
+        // The buffer is never reallocated, so the device may still complete
+        // the dropped request into it safely.
+        self.request = None;
+        self.active = false;
+        self.executed = false;
+        self.len = 0;
+        self.pos = 0;
+        self.status = UsbPacketStatus::Success;
+    }
+
+    /// Handle one token of the control transfer on endpoint 0. The packet
+    /// carries the guest buffer, its status is set to Nak while the device is
+    /// still handling the request.
+    pub fn handle_token(&mut self, dev: &Arc<Mutex<dyn UsbDeviceOps>>, packet: &mut UsbPacket) {
+        packet.status = UsbPacketStatus::Success;
+        packet.actual_length = 0;
+        match packet.pid as u8 {
+            USB_TOKEN_SETUP => self.do_token_setup(dev, packet),
+            USB_TOKEN_IN => self.do_token_in(dev, packet),
+            USB_TOKEN_OUT => self.do_token_out(packet),
+            _ => packet.status = UsbPacketStatus::Stall,
+        }
+    }
+
+    fn request_length(&self) -> usize {
+        (self.parameter >> 48) as usize
+    }
+
+    fn do_token_setup(&mut self, dev: &Arc<Mutex<dyn UsbDeviceOps>>, packet: &mut UsbPacket) {
+        let mut setup = [0_u8; USB_SETUP_PACKET_LEN];
+        packet.transfer_packet(&mut setup, USB_SETUP_PACKET_LEN);
+        if packet.actual_length as usize != USB_SETUP_PACKET_LEN {
+            packet.status = UsbPacketStatus::Stall;
+            return;
+        }
+        self.reset();
+        self.active = true;
+        self.parameter = u64::from_le_bytes(setup);
+        self.in_direction = setup[0] & USB_DIRECTION_DEVICE_TO_HOST != 0;
+        // The data of the IN request is prepared before the data stage. The
+        // OUT request is executed in the status stage once all the data and
+        // the new address of SET_ADDRESS only take effect after that.
+        if self.in_direction {
+            self.execute(dev, USB_TOKEN_IN);
+            if self.poll() && self.status != UsbPacketStatus::Success {
+                packet.status = self.status;
+            }
+        }
+    }
+
+    fn do_token_in(&mut self, dev: &Arc<Mutex<dyn UsbDeviceOps>>, packet: &mut UsbPacket) {
+        if !self.active {
+            packet.status = UsbPacketStatus::Stall;
+            return;
+        }
+        if !self.in_direction && !self.executed {
+            self.execute(dev, USB_TOKEN_OUT);
+        }
+        if !self.poll() {
+            packet.status = UsbPacketStatus::Nak;
+            return;
+        }
+        if self.status != UsbPacketStatus::Success {
+            packet.status = self.status;
+            self.active = false;
+            return;
+        }
+        if self.in_direction {
+            // Data stage.
+            let len = self.len;
+            packet.transfer_packet(&mut self.buf[self.pos..len], len - self.pos);
+            self.pos += packet.actual_length as usize;
+        } else {
+            // Status stage.
+            self.active = false;
+        }
+    }
+
+    fn do_token_out(&mut self, packet: &mut UsbPacket) {
+        if !self.active {
+            packet.status = UsbPacketStatus::Stall;
+            return;
+        }
+        if self.in_direction {
+            // Status stage.
+            if !self.poll() {
+                packet.status = UsbPacketStatus::Nak;
+                return;
+            }
+            packet.status = self.status;
+            self.active = false;
+        } else {
+            // Data stage.
+            let len = self.request_length();
+            let pos = min(self.pos, len);
+            packet.transfer_packet(&mut self.buf[pos..len], len - pos);
+            self.pos = pos + packet.actual_length as usize;
+        }
+    }
+
+    fn execute(&mut self, dev: &Arc<Mutex<dyn UsbDeviceOps>>, pid: u8) {
+        let len = self.request_length();
+        let iovecs = vec![Iovec::new(self.buf.as_ptr() as u64, len as u64)];
+        let req = PolledPacket::new(pid as u32, 0, iovecs);
+        req.packet.lock().unwrap().parameter = self.parameter;
+        dev.lock().unwrap().handle_packet(&req.packet);
+        self.executed = true;
+        self.request = Some(req);
+    }
+
+    /// Fetch the result of the request, returns false if it is still in flight.
+    fn poll(&mut self) -> bool {
+        if let Some(req) = self.request.as_ref() {
+            let locked_packet = req.packet.lock().unwrap();
+            if locked_packet.is_async {
+                return false;
+            }
+            self.status = locked_packet.status;
+            self.len = min(locked_packet.actual_length as usize, self.request_length());
+            drop(locked_packet);
+            self.request = None;
+        }
+        true
+    }
+}
+
 /// Usb packet used for device transfer data.
 pub struct UsbPacket {
     /// USB packet id.
@@ -500,6 +745,8 @@ pub struct UsbPacket {
     pub actual_length: u32,
     /// Endpoint number.
     pub ep_number: u8,
+    /// Stream id of the bulk endpoint which uses streams, or 0 if streams are not used.
+    pub stream: u32,
     /// Transfer for complete packet.
     pub xfer_ops: Option<Weak<Mutex<dyn TransferOps>>>,
 }
@@ -529,6 +776,7 @@ impl UsbPacket {
             status: UsbPacketStatus::Success,
             actual_length: 0,
             ep_number,
+            stream: 0,
             xfer_ops,
         }
     }
@@ -597,6 +845,7 @@ impl Default for UsbPacket {
             status: UsbPacketStatus::NoDev,
             actual_length: 0,
             ep_number: 0,
+            stream: 0,
             xfer_ops: None,
         }
     }
@@ -719,4 +968,101 @@ mod tests {
         assert_eq!(packet.actual_length, 2);
         assert_eq!(data, [1, 2]);
     }
+
+    struct TestUsbDevice {
+        usb_device: UsbDevice,
+        written: Vec<u8>,
+    }
+
+    impl UsbDeviceOps for TestUsbDevice {
+        fn realize(self) -> Result<Arc<Mutex<dyn UsbDeviceOps>>> {
+            Ok(Arc::new(Mutex::new(self)))
+        }
+
+        fn reset(&mut self) {}
+
+        fn set_controller(&mut self, _cntlr: Weak<Mutex<XhciDevice>>) {}
+
+        fn get_controller(&self) -> Option<Weak<Mutex<XhciDevice>>> {
+            None
+        }
+
+        fn get_wakeup_endpoint(&self) -> &UsbEndpoint {
+            &self.usb_device.ep_ctl
+        }
+
+        fn handle_control(
+            &mut self,
+            packet: &Arc<Mutex<UsbPacket>>,
+            device_req: &UsbDeviceRequest,
+        ) {
+            let mut locked_packet = packet.lock().unwrap();
+            if device_req.request_type & USB_DIRECTION_DEVICE_TO_HOST != 0 {
+                self.usb_device.data_buf[..4].copy_from_slice(&[1, 2, 3, 4]);
+                locked_packet.actual_length = 4;
+            } else {
+                let len = device_req.length as usize;
+                self.written = self.usb_device.data_buf[..len].to_vec();
+            }
+        }
+
+        fn handle_data(&mut self, _packet: &Arc<Mutex<UsbPacket>>) {}
+
+        fn get_usb_device(&self) -> &UsbDevice {
+            &self.usb_device
+        }
+
+        fn get_mut_usb_device(&mut self) -> &mut UsbDevice {
+            &mut self.usb_device
+        }
+    }
+
+    fn control_token(
+        pipe: &mut UsbControlPipe,
+        dev: &Arc<Mutex<dyn UsbDeviceOps>>,
+        pid: u8,
+        buf: &mut [u8],
+    ) -> UsbPacket {
+        let iovecs = vec![Iovec::new(buf.as_mut_ptr() as u64, buf.len() as u64)];
+        let mut packet = UsbPacket::new(pid as u32, 0, iovecs, None);
+        pipe.handle_token(dev, &mut packet);
+        packet
+    }
+
+    #[test]
+    fn test_usb_control_pipe() {
+        let test_dev = Arc::new(Mutex::new(TestUsbDevice {
+            usb_device: UsbDevice::new("test".to_string(), 64),
+            written: Vec::new(),
+        }));
+        let dev = test_dev.clone() as Arc<Mutex<dyn UsbDeviceOps>>;
+        let mut pipe = UsbControlPipe::default();
+
+        // IN request of 8 bytes, the device returns 4 bytes in two packets.
+        let mut setup = [0xc0, 0x01, 0, 0, 0, 0, 8, 0];
+        let packet = control_token(&mut pipe, &dev, USB_TOKEN_SETUP, &mut setup);
+        assert_eq!(packet.status, UsbPacketStatus::Success);
+        let mut data = [0_u8; 2];
+        let packet = control_token(&mut pipe, &dev, USB_TOKEN_IN, &mut data);
+        assert_eq!(packet.actual_length, 2);
+        assert_eq!(data, [1, 2]);
+        let packet = control_token(&mut pipe, &dev, USB_TOKEN_IN, &mut data);
+        assert_eq!(packet.actual_length, 2);
+        assert_eq!(data, [3, 4]);
+        let packet = control_token(&mut pipe, &dev, USB_TOKEN_OUT, &mut []);
+        assert_eq!(packet.status, UsbPacketStatus::Success);
+        // The status stage has finished the request.
+        let packet = control_token(&mut pipe, &dev, USB_TOKEN_IN, &mut data);
+        assert_eq!(packet.status, UsbPacketStatus::Stall);
+
+        // OUT request of 3 bytes is executed in the status stage.
+        let mut setup = [0x40, 0x02, 0, 0, 0, 0, 3, 0];
+        control_token(&mut pipe, &dev, USB_TOKEN_SETUP, &mut setup);
+        control_token(&mut pipe, &dev, USB_TOKEN_OUT, &mut [5, 6]);
+        control_token(&mut pipe, &dev, USB_TOKEN_OUT, &mut [7]);
+        assert!(test_dev.lock().unwrap().written.is_empty());
+        let packet = control_token(&mut pipe, &dev, USB_TOKEN_IN, &mut []);
+        assert_eq!(packet.status, UsbPacketStatus::Success);
+        assert_eq!(test_dev.lock().unwrap().written, vec![5, 6, 7]);
+    }
 }
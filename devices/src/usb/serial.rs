@@ -0,0 +1,607 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+
+use machine_manager::config::{UsbSerialConfig, UsbSerialProtocol};
+use machine_manager::event_loop::EventLoop;
+use util::loop_context::EventNotifierHelper;
+
+use super::descriptor::{
+    UsbConfigDescriptor, UsbDescConfig, UsbDescDevice, UsbDescEndpoint, UsbDescIface, UsbDescOther,
+    UsbDescriptorOps, UsbDeviceDescriptor, UsbEndpointDescriptor, UsbInterfaceDescriptor,
+};
+use super::xhci::xhci_controller::XhciDevice;
+use super::{config::*, USB_DEVICE_BUFFER_DEFAULT_LEN};
+use super::{
+    notify_controller, UsbDevice, UsbDeviceOps, UsbDeviceRequest, UsbEndpoint, UsbPacket,
+    UsbPacketStatus,
+};
+use crate::legacy::{Chardev, InputReceiver};
+
+/// FTDI FT232 device descriptor
+static DESC_DEVICE_FTDI: Lazy<Arc<UsbDescDevice>> = Lazy::new(|| {
+    Arc::new(UsbDescDevice {
+        device_desc: UsbDeviceDescriptor {
+            bLength: USB_DT_DEVICE_SIZE,
+            bDescriptorType: USB_DT_DEVICE,
+            idVendor: 0x0403,
+            idProduct: 0x6001,
+            bcdDevice: 0x0400,
+            iManufacturer: STR_MANUFACTURER_INDEX,
+            iProduct: STR_PRODUCT_SERIAL_INDEX,
+            iSerialNumber: STR_SERIAL_SERIAL_INDEX,
+            bcdUSB: 0x0200,
+            bDeviceClass: 0,
+            bDeviceSubClass: 0,
+            bDeviceProtocol: 0,
+            bMaxPacketSize0: 8,
+            bNumConfigurations: 1,
+        },
+        configs: vec![Arc::new(UsbDescConfig {
+            config_desc: UsbConfigDescriptor {
+                bLength: USB_DT_CONFIG_SIZE,
+                bDescriptorType: USB_DT_CONFIGURATION,
+                wTotalLength: 0,
+                bNumInterfaces: 1,
+                bConfigurationValue: 1,
+                iConfiguration: 0,
+                bmAttributes: USB_CONFIGURATION_ATTR_ONE | USB_CONFIGURATION_ATTR_REMOTE_WAKEUP,
+                bMaxPower: 50,
+            },
+            iad_desc: vec![],
+            interfaces: vec![DESC_IFACE_FTDI.clone()],
+        })],
+    })
+});
+
+/// FTDI FT232 interface descriptor
+static DESC_IFACE_FTDI: Lazy<Arc<UsbDescIface>> = Lazy::new(|| {
+    Arc::new(UsbDescIface {
+        interface_desc: UsbInterfaceDescriptor {
+            bLength: USB_DT_INTERFACE_SIZE,
+            bDescriptorType: USB_DT_INTERFACE,
+            bInterfaceNumber: 0,
+            bAlternateSetting: 0,
+            bNumEndpoints: 2,
+            bInterfaceClass: USB_CLASS_VENDOR_SPEC,
+            bInterfaceSubClass: USB_CLASS_VENDOR_SPEC,
+            bInterfaceProtocol: USB_CLASS_VENDOR_SPEC,
+            iInterface: 0,
+        },
+        other_desc: vec![],
+        endpoints: bulk_endpoints(),
+    })
+});
+
+/// CDC-ACM device descriptor
+static DESC_DEVICE_ACM: Lazy<Arc<UsbDescDevice>> = Lazy::new(|| {
+    Arc::new(UsbDescDevice {
+        device_desc: UsbDeviceDescriptor {
+            bLength: USB_DT_DEVICE_SIZE,
+            bDescriptorType: USB_DT_DEVICE,
+            idVendor: 0x0627,
+            idProduct: 0x0002,
+            bcdDevice: 0,
+            iManufacturer: STR_MANUFACTURER_INDEX,
+            iProduct: STR_PRODUCT_SERIAL_INDEX,
+            iSerialNumber: STR_SERIAL_SERIAL_INDEX,
+            bcdUSB: 0x0200,
+            bDeviceClass: USB_CLASS_COMM,
+            bDeviceSubClass: 0,
+            bDeviceProtocol: 0,
+            bMaxPacketSize0: 64,
+            bNumConfigurations: 1,
+        },
+        configs: vec![Arc::new(UsbDescConfig {
+            config_desc: UsbConfigDescriptor {
+                bLength: USB_DT_CONFIG_SIZE,
+                bDescriptorType: USB_DT_CONFIGURATION,
+                wTotalLength: 0,
+                bNumInterfaces: 2,
+                bConfigurationValue: 1,
+                iConfiguration: 0,
+                bmAttributes: USB_CONFIGURATION_ATTR_ONE | USB_CONFIGURATION_ATTR_REMOTE_WAKEUP,
+                bMaxPower: 50,
+            },
+            iad_desc: vec![],
+            interfaces: vec![DESC_IFACE_ACM_COMM.clone(), DESC_IFACE_ACM_DATA.clone()],
+        })],
+    })
+});
+
+/// CDC-ACM communication interface descriptor
+static DESC_IFACE_ACM_COMM: Lazy<Arc<UsbDescIface>> = Lazy::new(|| {
+    Arc::new(UsbDescIface {
+        interface_desc: UsbInterfaceDescriptor {
+            bLength: USB_DT_INTERFACE_SIZE,
+            bDescriptorType: USB_DT_INTERFACE,
+            bInterfaceNumber: 0,
+            bAlternateSetting: 0,
+            bNumEndpoints: 1,
+            bInterfaceClass: USB_CLASS_COMM,
+            // Abstract control model.
+            bInterfaceSubClass: 2,
+            // AT commands.
+            bInterfaceProtocol: 1,
+            iInterface: 0,
+        },
+        other_desc: vec![
+            // Header functional descriptor, CDC 1.10.
+            Arc::new(UsbDescOther {
+                data: vec![0x05, USB_DT_CS_INTERFACE, 0x00, 0x10, 0x01],
+            }),
+            // Call management functional descriptor, data interface 1.
+            Arc::new(UsbDescOther {
+                data: vec![0x05, USB_DT_CS_INTERFACE, 0x01, 0x00, 0x01],
+            }),
+            // ACM functional descriptor, support line coding and break.
+            Arc::new(UsbDescOther {
+                data: vec![0x04, USB_DT_CS_INTERFACE, 0x02, 0x06],
+            }),
+            // Union functional descriptor, control interface 0 and data interface 1.
+            Arc::new(UsbDescOther {
+                data: vec![0x05, USB_DT_CS_INTERFACE, 0x06, 0x00, 0x01],
+            }),
+        ],
+        endpoints: vec![Arc::new(UsbDescEndpoint {
+            endpoint_desc: UsbEndpointDescriptor {
+                bLength: USB_DT_ENDPOINT_SIZE,
+                bDescriptorType: USB_DT_ENDPOINT,
+                bEndpointAddress: USB_DIRECTION_DEVICE_TO_HOST | SERIAL_EP_NOTIFY,
+                bmAttributes: USB_ENDPOINT_ATTR_INT,
+                wMaxPacketSize: 8,
+                bInterval: 0xff,
+            },
+            extra: Vec::new(),
+        })],
+    })
+});
+
+/// CDC-ACM data interface descriptor
+static DESC_IFACE_ACM_DATA: Lazy<Arc<UsbDescIface>> = Lazy::new(|| {
+    Arc::new(UsbDescIface {
+        interface_desc: UsbInterfaceDescriptor {
+            bLength: USB_DT_INTERFACE_SIZE,
+            bDescriptorType: USB_DT_INTERFACE,
+            bInterfaceNumber: 1,
+            bAlternateSetting: 0,
+            bNumEndpoints: 2,
+            bInterfaceClass: USB_CLASS_CDC_DATA,
+            bInterfaceSubClass: 0,
+            bInterfaceProtocol: 0,
+            iInterface: 0,
+        },
+        other_desc: vec![],
+        endpoints: bulk_endpoints(),
+    })
+});
+
+fn bulk_endpoints() -> Vec<Arc<UsbDescEndpoint>> {
+    vec![
+        Arc::new(UsbDescEndpoint {
+            endpoint_desc: UsbEndpointDescriptor {
+                bLength: USB_DT_ENDPOINT_SIZE,
+                bDescriptorType: USB_DT_ENDPOINT,
+                bEndpointAddress: USB_DIRECTION_DEVICE_TO_HOST | SERIAL_EP_IN,
+                bmAttributes: USB_ENDPOINT_ATTR_BULK,
+                wMaxPacketSize: SERIAL_MAX_PACKET_SIZE as u16,
+                bInterval: 0,
+            },
+            extra: Vec::new(),
+        }),
+        Arc::new(UsbDescEndpoint {
+            endpoint_desc: UsbEndpointDescriptor {
+                bLength: USB_DT_ENDPOINT_SIZE,
+                bDescriptorType: USB_DT_ENDPOINT,
+                bEndpointAddress: USB_DIRECTION_HOST_TO_DEVICE | SERIAL_EP_OUT,
+                bmAttributes: USB_ENDPOINT_ATTR_BULK,
+                wMaxPacketSize: SERIAL_MAX_PACKET_SIZE as u16,
+                bInterval: 0,
+            },
+            extra: Vec::new(),
+        }),
+    ]
+}
+
+/// String descriptor index
+const STR_MANUFACTURER_INDEX: u8 = 1;
+const STR_PRODUCT_SERIAL_INDEX: u8 = 2;
+const STR_SERIAL_SERIAL_INDEX: u8 = 3;
+
+/// String descriptor
+const DESC_STRINGS: [&str; 4] = ["", "StratoVirt", "StratoVirt USB Serial", "4"];
+
+/// Class specific interface descriptor type.
+const USB_DT_CS_INTERFACE: u8 = 0x24;
+
+const SERIAL_EP_IN: u8 = 1;
+const SERIAL_EP_OUT: u8 = 2;
+const SERIAL_EP_NOTIFY: u8 = 3;
+const SERIAL_MAX_PACKET_SIZE: usize = 64;
+/// Size of the buffer for the data received from chardev.
+const SERIAL_RECV_BUF_SIZE: usize = 4096;
+
+/// FTDI vendor request type.
+const FTDI_IN_REQUEST: u8 = USB_DIRECTION_DEVICE_TO_HOST | USB_TYPE_VENDOR | USB_RECIPIENT_DEVICE;
+const FTDI_OUT_REQUEST: u8 = USB_DIRECTION_HOST_TO_DEVICE | USB_TYPE_VENDOR | USB_RECIPIENT_DEVICE;
+
+/// FTDI vendor requests.
+const FTDI_RESET: u8 = 0;
+const FTDI_SET_MDM_CTRL: u8 = 1;
+const FTDI_SET_FLOW_CTRL: u8 = 2;
+const FTDI_SET_BAUD: u8 = 3;
+const FTDI_SET_DATA: u8 = 4;
+const FTDI_GET_MDM_ST: u8 = 5;
+const FTDI_SET_EVENT_CHR: u8 = 6;
+const FTDI_SET_ERROR_CHR: u8 = 7;
+const FTDI_SET_LATENCY: u8 = 9;
+const FTDI_GET_LATENCY: u8 = 10;
+
+/// FTDI reset values.
+const FTDI_RESET_SIO: u16 = 0;
+const FTDI_RESET_RX: u16 = 1;
+
+/// FTDI modem status: CTS and DSR are always asserted, bit 0 is always set.
+const FTDI_MODEM_STATUS: u8 = 0x31;
+/// FTDI line status: transmitter holding register and transmitter are empty.
+const FTDI_LINE_STATUS: u8 = 0x60;
+/// Length of the status header of each FTDI packet.
+const FTDI_HEADER_LEN: usize = 2;
+const FTDI_DEFAULT_LATENCY: u8 = 16;
+
+/// CDC-ACM class requests.
+const ACM_SET_LINE_CODING: u8 = 0x20;
+const ACM_GET_LINE_CODING: u8 = 0x21;
+const ACM_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const ACM_SEND_BREAK: u8 = 0x23;
+
+/// Length of line coding: rate(4 bytes), stop bits, parity and data bits.
+const ACM_LINE_CODING_LEN: usize = 7;
+/// 115200 baud, 1 stop bit, no parity, 8 data bits.
+const ACM_DEFAULT_LINE_CODING: [u8; ACM_LINE_CODING_LEN] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+
+/// USB serial device, which transfers the data between the guest and the chardev.
+pub struct UsbSerial {
+    usb_device: UsbDevice,
+    /// USB controller used to notify controller to transfer data.
+    cntlr: Option<Weak<Mutex<XhciDevice>>>,
+    protocol: UsbSerialProtocol,
+    chardev: Arc<Mutex<Chardev>>,
+    /// Data received from chardev, waiting for guest to read.
+    recv_buf: VecDeque<u8>,
+    /// Latency timer of FTDI, which is not emulated.
+    latency: u8,
+    /// Line coding of CDC-ACM, which is not emulated.
+    line_coding: [u8; ACM_LINE_CODING_LEN],
+}
+
+impl UsbSerial {
+    pub fn new(config: UsbSerialConfig) -> Self {
+        Self {
+            usb_device: UsbDevice::new(config.id.unwrap(), USB_DEVICE_BUFFER_DEFAULT_LEN),
+            cntlr: None,
+            protocol: config.protocol,
+            chardev: Arc::new(Mutex::new(Chardev::new(config.chardev))),
+            recv_buf: VecDeque::with_capacity(SERIAL_RECV_BUF_SIZE),
+            latency: FTDI_DEFAULT_LATENCY,
+            line_coding: ACM_DEFAULT_LINE_CODING,
+        }
+    }
+
+    fn handle_ftdi_request(
+        &mut self,
+        device_req: &UsbDeviceRequest,
+    ) -> Result<u32, UsbPacketStatus> {
+        match (device_req.request_type, device_req.request) {
+            (FTDI_OUT_REQUEST, FTDI_RESET) => {
+                if device_req.value == FTDI_RESET_SIO || device_req.value == FTDI_RESET_RX {
+                    self.recv_buf.clear();
+                }
+                Ok(0)
+            }
+            (FTDI_OUT_REQUEST, FTDI_SET_LATENCY) => {
+                self.latency = device_req.value as u8;
+                Ok(0)
+            }
+            (FTDI_OUT_REQUEST, FTDI_SET_MDM_CTRL)
+            | (FTDI_OUT_REQUEST, FTDI_SET_FLOW_CTRL)
+            | (FTDI_OUT_REQUEST, FTDI_SET_BAUD)
+            | (FTDI_OUT_REQUEST, FTDI_SET_DATA)
+            | (FTDI_OUT_REQUEST, FTDI_SET_EVENT_CHR)
+            | (FTDI_OUT_REQUEST, FTDI_SET_ERROR_CHR) => Ok(0),
+            (FTDI_IN_REQUEST, FTDI_GET_MDM_ST) => {
+                self.usb_device.data_buf[0] = FTDI_MODEM_STATUS;
+                self.usb_device.data_buf[1] = FTDI_LINE_STATUS;
+                Ok(2)
+            }
+            (FTDI_IN_REQUEST, FTDI_GET_LATENCY) => {
+                self.usb_device.data_buf[0] = self.latency;
+                Ok(1)
+            }
+            _ => {
+                error!("Unsupported ftdi request {:?}", device_req);
+                Err(UsbPacketStatus::Stall)
+            }
+        }
+    }
+
+    fn handle_acm_request(
+        &mut self,
+        device_req: &UsbDeviceRequest,
+    ) -> Result<u32, UsbPacketStatus> {
+        match (device_req.request_type, device_req.request) {
+            (USB_INTERFACE_CLASS_OUT_REQUEST, ACM_SET_LINE_CODING) => {
+                let len = min(device_req.length as usize, ACM_LINE_CODING_LEN);
+                self.line_coding[..len].copy_from_slice(&self.usb_device.data_buf[..len]);
+                Ok(0)
+            }
+            (USB_INTERFACE_CLASS_IN_REQUEST, ACM_GET_LINE_CODING) => {
+                self.usb_device.data_buf[..ACM_LINE_CODING_LEN].copy_from_slice(&self.line_coding);
+                Ok(ACM_LINE_CODING_LEN as u32)
+            }
+            (USB_INTERFACE_CLASS_OUT_REQUEST, ACM_SET_CONTROL_LINE_STATE)
+            | (USB_INTERFACE_CLASS_OUT_REQUEST, ACM_SEND_BREAK) => Ok(0),
+            _ => {
+                error!("Unsupported cdc-acm request {:?}", device_req);
+                Err(UsbPacketStatus::Stall)
+            }
+        }
+    }
+
+    /// Build the data for bulk IN transfer. The FTDI packet starts with two bytes
+    /// of modem and line status.
+    fn build_in_data(&mut self, size: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(size);
+        match self.protocol {
+            UsbSerialProtocol::Ftdi => {
+                while data.len() + FTDI_HEADER_LEN < size && !self.recv_buf.is_empty() {
+                    let packet_len = min(size - data.len(), SERIAL_MAX_PACKET_SIZE);
+                    data.push(FTDI_MODEM_STATUS);
+                    data.push(FTDI_LINE_STATUS);
+                    let len = min(packet_len - FTDI_HEADER_LEN, self.recv_buf.len());
+                    data.extend(self.recv_buf.drain(..len));
+                }
+            }
+            UsbSerialProtocol::CdcAcm => {
+                let len = min(size, self.recv_buf.len());
+                data.extend(self.recv_buf.drain(..len));
+            }
+        }
+        data
+    }
+
+    fn write_chardev(&self, data: &[u8]) {
+        let output = self.chardev.lock().unwrap().output.clone();
+        if let Some(output) = output {
+            let mut locked_output = output.lock().unwrap();
+            if let Err(e) = locked_output
+                .write_all(data)
+                .and_then(|_| locked_output.flush())
+            {
+                error!("Failed to write usb serial data to chardev: {:?}", e);
+            }
+        } else {
+            debug!("Drop usb serial data, chardev is not connected");
+        }
+    }
+}
+
+impl UsbDeviceOps for UsbSerial {
+    fn realize(mut self) -> Result<Arc<Mutex<dyn UsbDeviceOps>>> {
+        self.usb_device.reset_usb_endpoint();
+        self.usb_device.speed = USB_SPEED_FULL;
+        let mut s: Vec<String> = DESC_STRINGS.iter().map(|&s| s.to_string()).collect();
+        let prefix = &s[STR_SERIAL_SERIAL_INDEX as usize];
+        s[STR_SERIAL_SERIAL_INDEX as usize] = self.usb_device.generate_serial_number(prefix);
+        let desc = match self.protocol {
+            UsbSerialProtocol::Ftdi => DESC_DEVICE_FTDI.clone(),
+            UsbSerialProtocol::CdcAcm => DESC_DEVICE_ACM.clone(),
+        };
+        self.usb_device.init_descriptor(desc, s)?;
+
+        let chardev = self.chardev.clone();
+        chardev
+            .lock()
+            .unwrap()
+            .realize()
+            .with_context(|| "Failed to realize chardev")?;
+        let serial = Arc::new(Mutex::new(self));
+        let receiver = Arc::new(Mutex::new(UsbSerialReceiver {
+            dev: Arc::downgrade(&serial),
+        }));
+        chardev.lock().unwrap().set_input_callback(&receiver);
+        EventLoop::update_event(EventNotifierHelper::internal_notifiers(chardev), None)?;
+
+        Ok(serial)
+    }
+
+    fn unrealize(&mut self) -> Result<()> {
+        self.chardev.lock().unwrap().deactivated = true;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        info!("Serial device reset");
+        self.usb_device.remote_wakeup = 0;
+        self.usb_device.addr = 0;
+        self.recv_buf.clear();
+        self.latency = FTDI_DEFAULT_LATENCY;
+        self.line_coding = ACM_DEFAULT_LINE_CODING;
+    }
+
+    fn handle_control(&mut self, packet: &Arc<Mutex<UsbPacket>>, device_req: &UsbDeviceRequest) {
+        debug!("handle_control request {:?}", device_req);
+        let mut locked_packet = packet.lock().unwrap();
+        match self
+            .usb_device
+            .handle_control_for_descriptor(&mut locked_packet, device_req)
+        {
+            Ok(handled) => {
+                if handled {
+                    debug!("Serial control handled by descriptor, return directly.");
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("Serial descriptor error {:?}", e);
+                locked_packet.status = UsbPacketStatus::Stall;
+                return;
+            }
+        }
+        let result = match self.protocol {
+            UsbSerialProtocol::Ftdi => self.handle_ftdi_request(device_req),
+            UsbSerialProtocol::CdcAcm => self.handle_acm_request(device_req),
+        };
+        match result {
+            Ok(len) => locked_packet.actual_length = len,
+            Err(status) => locked_packet.status = status,
+        }
+    }
+
+    fn handle_data(&mut self, packet: &Arc<Mutex<UsbPacket>>) {
+        let mut locked_packet = packet.lock().unwrap();
+        let in_direction = locked_packet.pid as u8 == USB_TOKEN_IN;
+        match (locked_packet.ep_number, in_direction) {
+            (SERIAL_EP_IN, true) => {
+                let size = locked_packet.get_iovecs_size() as usize;
+                let mut data = self.build_in_data(size);
+                if data.is_empty() {
+                    locked_packet.status = UsbPacketStatus::Nak;
+                    return;
+                }
+                let len = data.len();
+                locked_packet.transfer_packet(&mut data, len);
+            }
+            (SERIAL_EP_OUT, false) => {
+                let size = locked_packet.get_iovecs_size() as usize;
+                let mut data = vec![0_u8; size];
+                locked_packet.transfer_packet(&mut data, size);
+                self.write_chardev(&data);
+            }
+            (SERIAL_EP_NOTIFY, true) if self.protocol == UsbSerialProtocol::CdcAcm => {
+                // The line state never changes.
+                locked_packet.status = UsbPacketStatus::Nak;
+            }
+            _ => {
+                error!(
+                    "Unsupported serial endpoint {} in {}",
+                    locked_packet.ep_number, in_direction
+                );
+                locked_packet.status = UsbPacketStatus::Stall;
+            }
+        }
+    }
+
+    fn get_usb_device(&self) -> &UsbDevice {
+        &self.usb_device
+    }
+
+    fn get_mut_usb_device(&mut self) -> &mut UsbDevice {
+        &mut self.usb_device
+    }
+
+    fn set_controller(&mut self, cntlr: Weak<Mutex<XhciDevice>>) {
+        self.cntlr = Some(cntlr);
+    }
+
+    fn get_controller(&self) -> Option<Weak<Mutex<XhciDevice>>> {
+        self.cntlr.clone()
+    }
+
+    fn get_wakeup_endpoint(&self) -> &UsbEndpoint {
+        self.usb_device.get_endpoint(true, SERIAL_EP_IN)
+    }
+}
+
+/// Receive the data from chardev, and notify the controller without holding the
+/// device lock.
+struct UsbSerialReceiver {
+    dev: Weak<Mutex<UsbSerial>>,
+}
+
+impl InputReceiver for UsbSerialReceiver {
+    fn input_handle(&mut self, buffer: &[u8]) {
+        let dev = match self.dev.upgrade() {
+            Some(dev) => dev,
+            None => return,
+        };
+        let mut locked_dev = dev.lock().unwrap();
+        let len = min(
+            buffer.len(),
+            SERIAL_RECV_BUF_SIZE - locked_dev.recv_buf.len(),
+        );
+        locked_dev.recv_buf.extend(&buffer[..len]);
+        drop(locked_dev);
+        if let Err(e) = notify_controller(&(dev as Arc<Mutex<dyn UsbDeviceOps>>)) {
+            debug!("Failed to notify controller: {:?}", e);
+        }
+    }
+
+    fn get_remain_space_size(&mut self) -> usize {
+        self.dev.upgrade().map_or(0, |dev| {
+            SERIAL_RECV_BUF_SIZE - dev.lock().unwrap().recv_buf.len()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine_manager::config::{ChardevConfig, ChardevType};
+
+    fn create_serial(protocol: UsbSerialProtocol) -> UsbSerial {
+        UsbSerial::new(UsbSerialConfig {
+            id: Some("serial0".to_string()),
+            protocol,
+            chardev: ChardevConfig {
+                id: "chardev0".to_string(),
+                backend: ChardevType::Pty,
+                mux: false,
+                logfile: None,
+            },
+        })
+    }
+
+    #[test]
+    fn test_usb_serial_in_data() {
+        let mut serial = create_serial(UsbSerialProtocol::Ftdi);
+        assert!(serial.build_in_data(64).is_empty());
+
+        // Each packet of 64 bytes carries 62 bytes data.
+        serial.recv_buf.extend(0..100_u8);
+        let data = serial.build_in_data(512);
+        assert_eq!(data.len(), 104);
+        assert_eq!(data[..3], [FTDI_MODEM_STATUS, FTDI_LINE_STATUS, 0]);
+        assert_eq!(data[63], 61);
+        assert_eq!(data[64..67], [FTDI_MODEM_STATUS, FTDI_LINE_STATUS, 62]);
+        assert!(serial.recv_buf.is_empty());
+
+        serial.recv_buf.extend(0..100_u8);
+        let data = serial.build_in_data(32);
+        assert_eq!(data.len(), 32);
+        assert_eq!(serial.recv_buf.len(), 70);
+
+        let mut serial = create_serial(UsbSerialProtocol::CdcAcm);
+        serial.recv_buf.extend(0..100_u8);
+        assert_eq!(serial.build_in_data(64), (0..64_u8).collect::<Vec<u8>>());
+        assert_eq!(serial.build_in_data(64), (64..100_u8).collect::<Vec<u8>>());
+    }
+}
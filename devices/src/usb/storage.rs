@@ -11,12 +11,13 @@
 // See the Mulan PSL v2 for more details.
 
 use std::{
+    cmp::min,
     collections::HashMap,
     sync::{Arc, Mutex, Weak},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
-use byteorder::{ByteOrder, LittleEndian};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
 
@@ -25,17 +26,23 @@ use machine_manager::config::{DriveFile, UsbStorageConfig};
 use super::descriptor::{
     UsbConfigDescriptor, UsbDescConfig, UsbDescDevice, UsbDescEndpoint, UsbDescIface,
     UsbDescriptorOps, UsbDeviceDescriptor, UsbEndpointDescriptor, UsbInterfaceDescriptor,
+    UsbSuperSpeedEndpointCompDescriptor,
 };
 use super::xhci::xhci_controller::XhciDevice;
 use super::{config::*, USB_DEVICE_BUFFER_DEFAULT_LEN};
 use super::{UsbDevice, UsbDeviceOps, UsbDeviceRequest, UsbEndpoint, UsbPacket, UsbPacketStatus};
 use crate::{
     ScsiBus::{
-        ScsiBus, ScsiRequest, ScsiRequestOps, ScsiSense, ScsiXferMode, EMULATE_SCSI_OPS, GOOD,
-        SCSI_CMD_BUF_SIZE,
+        scsi_bus_parse_req_cdb, ScsiBus, ScsiRequest, ScsiRequestOps, ScsiSense, ScsiXferMode,
+        CHECK_CONDITION, EMULATE_SCSI_OPS, GOOD, SCSI_CMD_BUF_SIZE, SCSI_SENSE_INVALID_FIELD,
+    },
+    ScsiDisk::{
+        register_removable_device, unregister_removable_device, ScsiDevice, SCSI_TYPE_DISK,
+        SCSI_TYPE_ROM,
     },
-    ScsiDisk::{ScsiDevice, SCSI_TYPE_DISK, SCSI_TYPE_ROM},
 };
+use util::aio::Iovec;
+use util::byte_code::ByteCode;
 
 // Storage device descriptor
 static DESC_DEVICE_STORAGE: Lazy<Arc<UsbDescDevice>> = Lazy::new(|| {
@@ -115,6 +122,122 @@ static DESC_IFACE_STORAGE: Lazy<Arc<UsbDescIface>> = Lazy::new(|| {
     })
 });
 
+// UAS device descriptor
+static DESC_DEVICE_UAS: Lazy<Arc<UsbDescDevice>> = Lazy::new(|| {
+    Arc::new(UsbDescDevice {
+        device_desc: UsbDeviceDescriptor {
+            bLength: USB_DT_DEVICE_SIZE,
+            bDescriptorType: USB_DT_DEVICE,
+            idVendor: USB_STORAGE_VENDOR_ID,
+            idProduct: 0x0003,
+            bcdDevice: 0,
+            iManufacturer: STR_MANUFACTURER_INDEX,
+            iProduct: STR_PRODUCT_UAS_INDEX,
+            iSerialNumber: STR_SERIAL_STORAGE_INDEX,
+            bcdUSB: 0x0300,
+            bDeviceClass: 0,
+            bDeviceSubClass: 0,
+            bDeviceProtocol: 0,
+            // 2^9 = 512 bytes for super speed.
+            bMaxPacketSize0: 9,
+            bNumConfigurations: 1,
+        },
+        configs: vec![Arc::new(UsbDescConfig {
+            config_desc: UsbConfigDescriptor {
+                bLength: USB_DT_CONFIG_SIZE,
+                bDescriptorType: USB_DT_CONFIGURATION,
+                wTotalLength: 0,
+                bNumInterfaces: 1,
+                bConfigurationValue: 1,
+                iConfiguration: STR_CONFIG_STORAGE_SUPER_INDEX,
+                bmAttributes: USB_CONFIGURATION_ATTR_ONE | USB_CONFIGURATION_ATTR_SELF_POWER,
+                bMaxPower: 50,
+            },
+            iad_desc: vec![],
+            // Alternate setting 0 is Bulk-Only Transport, and 1 is UAS.
+            interfaces: vec![DESC_IFACE_STORAGE_SUPER.clone(), DESC_IFACE_UAS.clone()],
+        })],
+    })
+});
+
+// Super speed storage interface descriptor
+static DESC_IFACE_STORAGE_SUPER: Lazy<Arc<UsbDescIface>> = Lazy::new(|| {
+    Arc::new(UsbDescIface {
+        interface_desc: DESC_IFACE_STORAGE.interface_desc,
+        other_desc: vec![],
+        endpoints: vec![
+            Arc::new(super_speed_bulk_endpoint(
+                USB_DIRECTION_DEVICE_TO_HOST | 0x01,
+                0,
+            )),
+            Arc::new(super_speed_bulk_endpoint(
+                USB_DIRECTION_HOST_TO_DEVICE | 0x02,
+                0,
+            )),
+        ],
+    })
+});
+
+// UAS interface descriptor
+static DESC_IFACE_UAS: Lazy<Arc<UsbDescIface>> = Lazy::new(|| {
+    Arc::new(UsbDescIface {
+        interface_desc: UsbInterfaceDescriptor {
+            bLength: USB_DT_INTERFACE_SIZE,
+            bDescriptorType: USB_DT_INTERFACE,
+            bInterfaceNumber: 0,
+            bAlternateSetting: UAS_ALT_SETTING,
+            bNumEndpoints: 4,
+            bInterfaceClass: USB_CLASS_MASS_STORAGE,
+            bInterfaceSubClass: 0x06, // SCSI
+            bInterfaceProtocol: 0x62, // UAS
+            iInterface: 0,
+        },
+        other_desc: vec![],
+        endpoints: vec![
+            uas_pipe_endpoint(USB_DIRECTION_HOST_TO_DEVICE, UAS_PIPE_ID_COMMAND),
+            uas_pipe_endpoint(USB_DIRECTION_DEVICE_TO_HOST, UAS_PIPE_ID_STATUS),
+            uas_pipe_endpoint(USB_DIRECTION_DEVICE_TO_HOST, UAS_PIPE_ID_DATA_IN),
+            uas_pipe_endpoint(USB_DIRECTION_HOST_TO_DEVICE, UAS_PIPE_ID_DATA_OUT),
+        ],
+    })
+});
+
+/// Super speed bulk endpoint, which supports 2^`max_streams` streams if `max_streams` is not 0.
+fn super_speed_bulk_endpoint(address: u8, max_streams: u8) -> UsbDescEndpoint {
+    UsbDescEndpoint {
+        endpoint_desc: UsbEndpointDescriptor {
+            bLength: USB_DT_ENDPOINT_SIZE,
+            bDescriptorType: USB_DT_ENDPOINT,
+            bEndpointAddress: address,
+            bmAttributes: USB_ENDPOINT_ATTR_BULK,
+            wMaxPacketSize: 1024,
+            bInterval: 0,
+        },
+        extra: UsbSuperSpeedEndpointCompDescriptor {
+            bLength: USB_DT_SS_EP_COMP_SIZE,
+            bDescriptorType: USB_DT_ENDPOINT_COMPANION,
+            bMaxBurst: 15,
+            bmAttributes: max_streams,
+            wBytesPerInterval: 0,
+        }
+        .as_bytes()
+        .to_vec(),
+    }
+}
+
+/// Bulk endpoint of UAS pipe, the endpoint number is the same as pipe id.
+fn uas_pipe_endpoint(direction: u8, pipe_id: u8) -> Arc<UsbDescEndpoint> {
+    // The status and data pipes use the stream whose id is the tag of the command.
+    let max_streams = match pipe_id {
+        UAS_PIPE_ID_COMMAND => 0,
+        _ => UAS_MAX_STREAMS_SHIFT,
+    };
+    let mut ep = super_speed_bulk_endpoint(direction | pipe_id, max_streams);
+    // Pipe Usage descriptor.
+    ep.extra.extend([4, UAS_DT_PIPE_USAGE, pipe_id, 0]);
+    Arc::new(ep)
+}
+
 // CRC16 of "STRATOVIRT"
 const USB_STORAGE_VENDOR_ID: u16 = 0xB74C;
 
@@ -123,9 +246,11 @@ const STR_MANUFACTURER_INDEX: u8 = 1;
 const STR_PRODUCT_STORAGE_INDEX: u8 = 2;
 const STR_SERIAL_STORAGE_INDEX: u8 = 3;
 const STR_CONFIG_STORAGE_HIGH_INDEX: u8 = 5;
+const STR_CONFIG_STORAGE_SUPER_INDEX: u8 = 6;
+const STR_PRODUCT_UAS_INDEX: u8 = 7;
 
 // String descriptor
-const DESC_STRINGS: [&str; 7] = [
+const DESC_STRINGS: [&str; 8] = [
     "",
     "StratoVirt",
     "StratoVirt USB Storage",
@@ -133,6 +258,7 @@ const DESC_STRINGS: [&str; 7] = [
     "Full speed config (usb 1.1)",
     "High speed config (usb 2.0)",
     "Super speed config (usb 3.0)",
+    "StratoVirt USB Attached SCSI",
 ];
 
 pub const GET_MAX_LUN: u8 = 0xfe;
@@ -144,9 +270,51 @@ pub const CBW_FLAG_IN: u8 = 1 << 7;
 pub const CBW_FLAG_OUT: u8 = 0;
 pub const CBW_SIZE: u8 = 31;
 pub const CSW_SIZE: u8 = 13;
-
-// USB-storage has only target 0 and lun 0.
-const USB_STORAGE_SCSI_LUN_ID: u8 = 0;
+/// Max length of the data phase which is split into several packets, as the whole
+/// data phase is staged in host memory.
+const USB_STORAGE_MAX_STAGED_LEN: usize = 4 * 1024 * 1024;
+
+// USB-storage has only target 0, and the logical units are lun 0 to lun n-1.
+const USB_STORAGE_SCSI_TARGET_ID: u8 = 0;
+
+/// Alternate setting of the interface which uses UAS.
+const UAS_ALT_SETTING: u8 = 1;
+/// Descriptor type of Pipe Usage descriptor.
+const UAS_DT_PIPE_USAGE: u8 = 0x24;
+
+/// UAS pipe id.
+const UAS_PIPE_ID_COMMAND: u8 = 1;
+const UAS_PIPE_ID_STATUS: u8 = 2;
+const UAS_PIPE_ID_DATA_IN: u8 = 3;
+const UAS_PIPE_ID_DATA_OUT: u8 = 4;
+
+/// The status and data pipes support 2^UAS_MAX_STREAMS_SHIFT streams, and the valid
+/// stream ids and tags are 1 to UAS_MAX_STREAMS.
+const UAS_MAX_STREAMS_SHIFT: u8 = 4;
+const UAS_MAX_STREAMS: u16 = 1 << UAS_MAX_STREAMS_SHIFT;
+
+/// UAS information unit id.
+const UAS_IU_ID_COMMAND: u8 = 0x01;
+const UAS_IU_ID_SENSE: u8 = 0x03;
+const UAS_IU_ID_RESPONSE: u8 = 0x04;
+const UAS_IU_ID_TASK_MGMT: u8 = 0x05;
+
+/// UAS Command IU is 32 bytes when there is no additional CDB.
+const UAS_COMMAND_IU_SIZE: usize = 32;
+/// UAS Task Management IU is 16 bytes.
+const UAS_TASK_MGMT_IU_SIZE: usize = 16;
+/// Header length of UAS Sense IU.
+const UAS_SENSE_IU_HEADER_SIZE: usize = 16;
+
+/// UAS task management function.
+const UAS_TMF_ABORT_TASK: u8 = 0x01;
+const UAS_TMF_LOGICAL_UNIT_RESET: u8 = 0x08;
+
+/// UAS response code.
+const UAS_RC_TMF_COMPLETE: u8 = 0x00;
+const UAS_RC_INVALID_IU: u8 = 0x02;
+const UAS_RC_TMF_NOT_SUPPORTED: u8 = 0x04;
+const UAS_RC_INCORRECT_LUN: u8 = 0x09;
 
 struct UsbStorageState {
     mode: UsbMsdMode,
@@ -154,6 +322,10 @@ struct UsbStorageState {
     csw: UsbMsdCsw,
     cdb: Option<[u8; SCSI_CMD_BUF_SIZE]>,
     iovec_len: u32,
+    /// Staged data of the data phase which is split into several packets.
+    data: Vec<u8>,
+    /// Length of the staged data transferred.
+    data_pos: usize,
 }
 
 impl ScsiRequestOps for UsbMsdCsw {
@@ -173,6 +345,8 @@ impl UsbStorageState {
             csw: UsbMsdCsw::new(),
             cdb: None,
             iovec_len: 0,
+            data: Vec::new(),
+            data_pos: 0,
         }
     }
 
@@ -222,22 +396,86 @@ impl UsbStorageState {
     }
 }
 
+/// SCSI command received from the UAS command pipe, which is waiting for its data phase.
+#[derive(Clone, Debug)]
+struct UasCommand {
+    tag: u16,
+    lun: u8,
+    cdb: [u8; SCSI_CMD_BUF_SIZE],
+    data_in: bool,
+}
+
+/// State of USB Attached SCSI protocol.
+///
+/// The status and data pipes use the stream whose id is the tag of the command, so the
+/// commands are processed out of order. The packets which arrive before the command or
+/// the IU of their stream are completed asynchronously.
+#[derive(Default)]
+struct UasState {
+    /// Commands waiting for the data packet, indexed by tag.
+    commands: HashMap<u16, UasCommand>,
+    /// Data packets waiting for the command, indexed by stream id.
+    data_packets: HashMap<u16, Arc<Mutex<UsbPacket>>>,
+    /// IUs waiting for the status packet, indexed by stream id.
+    status_ius: HashMap<u16, Vec<u8>>,
+    /// Status packets waiting for the IU, indexed by stream id.
+    status_packets: HashMap<u16, Arc<Mutex<UsbPacket>>>,
+}
+
+/// Completion of the SCSI request executed by UAS, reported by Sense IU.
+#[derive(Default)]
+struct UasStatus {
+    status: u8,
+    sense: Option<ScsiSense>,
+}
+
+impl ScsiRequestOps for UasStatus {
+    fn scsi_request_complete_cb(&mut self, status: u8, sense: Option<ScsiSense>) -> Result<()> {
+        self.status = status;
+        self.sense = sense;
+        Ok(())
+    }
+}
+
+fn uas_response_iu(tag: u16, code: u8) -> Vec<u8> {
+    let mut iu = vec![0; 8];
+    iu[0] = UAS_IU_ID_RESPONSE;
+    BigEndian::write_u16(&mut iu[2..4], tag);
+    iu[7] = code;
+    iu
+}
+
+fn uas_sense_iu(tag: u16, status: u8, sense: Option<ScsiSense>) -> Vec<u8> {
+    let mut iu = vec![0; UAS_SENSE_IU_HEADER_SIZE];
+    iu[0] = UAS_IU_ID_SENSE;
+    BigEndian::write_u16(&mut iu[2..4], tag);
+    iu[6] = status;
+    if status != GOOD {
+        let sense_data = sense.unwrap_or(SCSI_SENSE_INVALID_FIELD).to_fixed_format();
+        BigEndian::write_u16(&mut iu[14..16], sense_data.len() as u16);
+        iu.extend(sense_data);
+    }
+    iu
+}
+
 /// USB storage device.
 pub struct UsbStorage {
     usb_device: UsbDevice,
     state: UsbStorageState,
+    /// State of USB Attached SCSI protocol.
+    uas: UasState,
     /// USB controller used to notify controller to transfer data.
     cntlr: Option<Weak<Mutex<XhciDevice>>>,
     /// Configuration of the USB storage device.
     pub config: UsbStorageConfig,
     /// Scsi bus attached to this usb-storage device.
     scsi_bus: Arc<Mutex<ScsiBus>>,
-    /// Effective scsi backend.
+    /// Effective scsi backends, indexed by the lun id.
     // Note: scsi device should attach to scsi bus. Logically, scsi device should not be placed in UsbStorage.
-    // But scsi device is needed in processing scsi request. Because the three (usb-storage/scsi bus/scsi device)
-    // correspond one-to-one, add scsi device member here for the execution efficiency (No need to find a unique
+    // But scsi device is needed in processing scsi request. Because the usb-storage and its scsi bus correspond
+    // one-to-one, add scsi devices member here for the execution efficiency (No need to find a unique
     // device from the hash table of the unique bus).
-    scsi_dev: Arc<Mutex<ScsiDevice>>,
+    scsi_devs: Vec<Arc<Mutex<ScsiDevice>>>,
 }
 
 #[derive(Debug)]
@@ -308,22 +546,30 @@ impl UsbStorage {
         config: UsbStorageConfig,
         drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
     ) -> Self {
-        let scsi_type = match &config.media as &str {
-            "disk" => SCSI_TYPE_DISK,
-            _ => SCSI_TYPE_ROM,
-        };
+        let scsi_devs = config
+            .luns
+            .iter()
+            .map(|lun| {
+                let scsi_type = match &lun.media as &str {
+                    "disk" => SCSI_TYPE_DISK,
+                    _ => SCSI_TYPE_ROM,
+                };
+                Arc::new(Mutex::new(ScsiDevice::new(
+                    lun.scsi_cfg.clone(),
+                    scsi_type,
+                    drive_files.clone(),
+                )))
+            })
+            .collect();
 
         Self {
             usb_device: UsbDevice::new(config.id.clone().unwrap(), USB_DEVICE_BUFFER_DEFAULT_LEN),
             state: UsbStorageState::new(),
+            uas: UasState::default(),
             cntlr: None,
-            config: config.clone(),
+            config,
             scsi_bus: Arc::new(Mutex::new(ScsiBus::new("".to_string()))),
-            scsi_dev: Arc::new(Mutex::new(ScsiDevice::new(
-                config.scsi_cfg,
-                scsi_type,
-                drive_files,
-            ))),
+            scsi_devs,
         }
     }
 
@@ -342,8 +588,7 @@ impl UsbStorage {
             }
             USB_INTERFACE_CLASS_IN_REQUEST => {
                 if device_req.request == GET_MAX_LUN {
-                    // TODO: Now only supports 1 LUN.
-                    let maxlun = USB_STORAGE_SCSI_LUN_ID;
+                    let maxlun = self.scsi_devs.len() as u8 - 1;
                     self.usb_device.data_buf[0] = maxlun;
                     packet.actual_length = 1;
                     return;
@@ -376,17 +621,18 @@ impl UsbStorage {
                 if self.state.cbw.sig != CBW_SIGNATURE {
                     bail!("Bad signature {:x}", self.state.cbw.sig);
                 }
-                if self.state.cbw.lun != USB_STORAGE_SCSI_LUN_ID {
+                if self.state.cbw.lun as usize >= self.scsi_devs.len() {
                     bail!(
-                        "Bad lun id {:x}. Usb-storage only supports lun id 0!",
-                        self.state.cbw.lun
+                        "Bad lun id {:x}. Usb-storage only has {} luns!",
+                        self.state.cbw.lun,
+                        self.scsi_devs.len()
                     );
                 }
 
                 self.state.cdb = Some(self.state.cbw.cmd);
 
                 if self.state.cbw.data_len == 0 {
-                    self.handle_scsi_request(packet)?;
+                    self.handle_scsi_request(packet.iovecs.clone())?;
                     self.state.mode = UsbMsdMode::Csw;
                 } else if self.state.cbw.flags & CBW_FLAG_IN == CBW_FLAG_IN {
                     self.state.mode = UsbMsdMode::DataIn;
@@ -447,41 +693,107 @@ impl UsbStorage {
 
     fn handle_data_inout_packet(&mut self, packet: &mut UsbPacket, mode: UsbMsdMode) -> Result<()> {
         self.state.check_cdb_exist(true)?;
-        self.state.check_iovec_empty(true)?;
 
         let iovec_len = packet.get_iovecs_size() as u32;
-        if iovec_len < self.state.cbw.data_len {
-            bail!(
-                "Insufficient transmission buffer, transfer size {}, buffer size {}, MSD mode {:?}!",
-                self.state.cbw.data_len,
-                iovec_len,
-                mode,
-            );
+        if iovec_len < self.state.cbw.data_len || !self.state.data.is_empty() {
+            return self.handle_split_data_packet(packet, mode);
         }
+        self.state.check_iovec_empty(true)?;
 
         self.state.iovec_len = iovec_len;
         debug!("Storage: iovec_len {}.", iovec_len);
-        self.handle_scsi_request(packet)?;
+        self.handle_scsi_request(packet.iovecs.clone())?;
         packet.actual_length = iovec_len;
         self.state.mode = UsbMsdMode::Csw;
 
         Ok(())
     }
 
+    /// Handle the data phase which is split into several packets, such as the
+    /// qTDs of EHCI, through the staged data of the SCSI command.
+    fn handle_split_data_packet(&mut self, packet: &mut UsbPacket, mode: UsbMsdMode) -> Result<()> {
+        let data_len = self.state.cbw.data_len as usize;
+        if self.state.data.is_empty() {
+            self.state.check_iovec_empty(true)?;
+            if data_len > USB_STORAGE_MAX_STAGED_LEN {
+                bail!(
+                    "Split data phase length {} exceeds the max staged length {}",
+                    data_len,
+                    USB_STORAGE_MAX_STAGED_LEN
+                );
+            }
+            self.state.data = vec![0_u8; data_len];
+            self.state.data_pos = 0;
+            self.state.iovec_len = data_len as u32;
+            if let UsbMsdMode::DataIn = mode {
+                self.handle_staged_scsi_request()?;
+            }
+        }
+
+        let pos = self.state.data_pos;
+        packet.transfer_packet(&mut self.state.data[pos..], data_len - pos);
+        self.state.data_pos += packet.actual_length as usize;
+        debug!(
+            "Storage: staged data {}/{}, MSD mode {:?}.",
+            self.state.data_pos, data_len, mode
+        );
+        if self.state.data_pos < data_len {
+            return Ok(());
+        }
+
+        if let UsbMsdMode::DataOut = mode {
+            self.handle_staged_scsi_request()?;
+        }
+        self.state.data = Vec::new();
+        self.state.mode = UsbMsdMode::Csw;
+        Ok(())
+    }
+
+    fn handle_staged_scsi_request(&mut self) -> Result<()> {
+        let iovec = Iovec::new(
+            self.state.data.as_ptr() as u64,
+            self.state.data.len() as u64,
+        );
+        self.handle_scsi_request(vec![iovec])
+    }
+
     // Handle scsi request and save result in self.csw for next CSW packet.
-    fn handle_scsi_request(&mut self, packet: &mut UsbPacket) -> Result<()> {
+    fn handle_scsi_request(&mut self, iovecs: Vec<Iovec>) -> Result<()> {
         self.state
             .cdb
             .with_context(|| "No scsi CDB can be executed")?;
 
         let csw = Box::new(UsbMsdCsw::new());
-        let sreq = ScsiRequest::new(
+        let sreq_h = self.execute_scsi_request(
             self.state.cdb.unwrap(),
-            0,
-            packet.iovecs.clone(),
+            self.state.cbw.lun,
+            iovecs,
             self.state.iovec_len,
-            self.scsi_dev.clone(),
             csw,
+        )?;
+
+        let csw_h = &sreq_h.lock().unwrap().upper_req;
+        let csw = csw_h.as_ref().as_any().downcast_ref::<UsbMsdCsw>().unwrap();
+        self.state.csw = *csw;
+
+        Ok(())
+    }
+
+    fn execute_scsi_request(
+        &self,
+        cdb: [u8; SCSI_CMD_BUF_SIZE],
+        lun: u8,
+        iovecs: Vec<Iovec>,
+        iovec_len: u32,
+        upper_req: Box<dyn ScsiRequestOps>,
+    ) -> Result<Arc<Mutex<ScsiRequest>>> {
+        let sreq = ScsiRequest::new(
+            cdb,
+            lun as u16,
+            iovecs,
+            iovec_len,
+            self.scsi_devs[lun as usize].clone(),
+            upper_req,
         )
         .with_context(|| "Error in creating scsirequest.")?;
 
@@ -495,49 +807,293 @@ impl UsbStorage {
             );
         }
 
-        let sreq_h = match sreq.opstype {
+        match sreq.opstype {
             EMULATE_SCSI_OPS => sreq.emulate_execute(),
             _ => sreq.execute(),
         }
-        .with_context(|| "Error in executing scsi request.")?;
+        .with_context(|| "Error in executing scsi request.")
+    }
 
-        let csw_h = &sreq_h.lock().unwrap().upper_req;
-        let csw = csw_h.as_ref().as_any().downcast_ref::<UsbMsdCsw>().unwrap();
-        self.state.csw = *csw;
+    /// Execute the UAS command and queue its Sense IU.
+    fn execute_uas_command(&mut self, cmd: &UasCommand, mut packet: Option<&mut UsbPacket>) {
+        let (iovecs, iovec_len) = match packet.as_deref_mut() {
+            Some(p) => (p.iovecs.clone(), p.get_iovecs_size() as u32),
+            None => (Vec::new(), 0),
+        };
+        let result = self.execute_scsi_request(
+            cmd.cdb,
+            cmd.lun,
+            iovecs,
+            iovec_len,
+            Box::<UasStatus>::default(),
+        );
 
+        let iu = match result {
+            Ok(sreq_h) => {
+                let locked_sreq = sreq_h.lock().unwrap();
+                let status = locked_sreq
+                    .upper_req
+                    .as_ref()
+                    .as_any()
+                    .downcast_ref::<UasStatus>()
+                    .unwrap();
+                if let Some(p) = packet {
+                    p.actual_length = min(locked_sreq.cmd.xfer, iovec_len);
+                }
+                uas_sense_iu(cmd.tag, status.status, status.sense)
+            }
+            Err(e) => {
+                warn!(
+                    "USB-storage {}: UAS command {:x} failed: {:?}",
+                    self.device_id(),
+                    cmd.tag,
+                    e
+                );
+                if let Some(p) = packet {
+                    p.status = UsbPacketStatus::Stall;
+                }
+                uas_sense_iu(cmd.tag, CHECK_CONDITION, Some(SCSI_SENSE_INVALID_FIELD))
+            }
+        };
+        self.queue_uas_iu(cmd.tag, iu);
+    }
+
+    /// Execute the UAS command with the packet of its data phase.
+    fn execute_uas_data_command(&mut self, cmd: &UasCommand, packet: &mut UsbPacket) -> Result<()> {
+        if cmd.data_in != (packet.pid as u8 == USB_TOKEN_IN) {
+            self.queue_uas_iu(
+                cmd.tag,
+                uas_sense_iu(cmd.tag, CHECK_CONDITION, Some(SCSI_SENSE_INVALID_FIELD)),
+            );
+            bail!("Data direction of UAS command {:x} is mismatched", cmd.tag);
+        }
+        self.execute_uas_command(cmd, Some(packet));
         Ok(())
     }
+
+    /// Execute the UAS command with the data packet waiting for it, and complete the packet.
+    fn complete_uas_data_packet(&mut self, cmd: UasCommand, packet: Arc<Mutex<UsbPacket>>) {
+        let mut locked_packet = packet.lock().unwrap();
+        // The transfer has been cancelled, wait for another data packet.
+        let ops = match locked_packet
+            .xfer_ops
+            .as_ref()
+            .and_then(|ops| ops.upgrade())
+        {
+            Some(ops) => ops,
+            None => {
+                self.uas.commands.insert(cmd.tag, cmd);
+                return;
+            }
+        };
+        if let Err(e) = self.execute_uas_data_command(&cmd, &mut locked_packet) {
+            warn!(
+                "USB-storage {}: handle UAS data error: {:?}",
+                self.device_id(),
+                e
+            );
+            locked_packet.status = UsbPacketStatus::Stall;
+        }
+        drop(locked_packet);
+        ops.lock().unwrap().submit_transfer();
+    }
+
+    fn handle_uas_command_iu(&mut self, packet: &mut UsbPacket) -> Result<()> {
+        let mut iu = [0_u8; UAS_COMMAND_IU_SIZE];
+        let len = min(packet.get_iovecs_size() as usize, UAS_COMMAND_IU_SIZE);
+        packet.transfer_packet(&mut iu, len);
+        let tag = BigEndian::read_u16(&iu[2..4]);
+        if tag == 0 || tag > UAS_MAX_STREAMS {
+            bail!("Bad UAS tag {}", tag);
+        }
+
+        match iu[0] {
+            UAS_IU_ID_COMMAND => {
+                if len < UAS_COMMAND_IU_SIZE {
+                    bail!("Bad UAS Command IU size {}", len);
+                }
+                let lun = iu[9];
+                if lun as usize >= self.scsi_devs.len() {
+                    self.queue_uas_iu(tag, uas_response_iu(tag, UAS_RC_INCORRECT_LUN));
+                    return Ok(());
+                }
+                let mut cdb = [0_u8; SCSI_CMD_BUF_SIZE];
+                cdb.copy_from_slice(&iu[16..32]);
+                let cmd = UasCommand {
+                    tag,
+                    lun,
+                    cdb,
+                    data_in: true,
+                };
+
+                let dev = self.scsi_devs[lun as usize].clone();
+                match scsi_bus_parse_req_cdb(cdb, dev) {
+                    Some(scsi_cmd)
+                        if scsi_cmd.mode != ScsiXferMode::ScsiXferNone && scsi_cmd.xfer != 0 =>
+                    {
+                        let cmd = UasCommand {
+                            data_in: scsi_cmd.mode == ScsiXferMode::ScsiXferFromDev,
+                            ..cmd
+                        };
+                        match self.uas.data_packets.remove(&tag) {
+                            Some(data_packet) => self.complete_uas_data_packet(cmd, data_packet),
+                            None => {
+                                self.uas.commands.insert(tag, cmd);
+                            }
+                        }
+                    }
+                    Some(_) => self.execute_uas_command(&cmd, None),
+                    None => {
+                        self.queue_uas_iu(
+                            tag,
+                            uas_sense_iu(tag, CHECK_CONDITION, Some(SCSI_SENSE_INVALID_FIELD)),
+                        );
+                    }
+                }
+            }
+            UAS_IU_ID_TASK_MGMT => {
+                if len < UAS_TASK_MGMT_IU_SIZE {
+                    bail!("Bad UAS Task Management IU size {}", len);
+                }
+                let lun = iu[9];
+                let code = if lun as usize >= self.scsi_devs.len() {
+                    UAS_RC_INCORRECT_LUN
+                } else {
+                    match iu[4] {
+                        UAS_TMF_ABORT_TASK => {
+                            let task_tag = BigEndian::read_u16(&iu[6..8]);
+                            self.uas.commands.remove(&task_tag);
+                            UAS_RC_TMF_COMPLETE
+                        }
+                        UAS_TMF_LOGICAL_UNIT_RESET => {
+                            self.uas.commands.retain(|_, cmd| cmd.lun != lun);
+                            UAS_RC_TMF_COMPLETE
+                        }
+                        _ => UAS_RC_TMF_NOT_SUPPORTED,
+                    }
+                };
+                self.queue_uas_iu(tag, uas_response_iu(tag, code));
+            }
+            _ => {
+                self.queue_uas_iu(tag, uas_response_iu(tag, UAS_RC_INVALID_IU));
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_uas_data(&mut self, packet: &Arc<Mutex<UsbPacket>>) -> Result<()> {
+        let mut locked_packet = packet.lock().unwrap();
+        let pid = locked_packet.pid as u8;
+        let ep_number = locked_packet.ep_number;
+        if ep_number == UAS_PIPE_ID_COMMAND {
+            if pid != USB_TOKEN_OUT {
+                bail!("Error UAS pid {} ep_number {}!", pid, ep_number);
+            }
+            return self.handle_uas_command_iu(&mut locked_packet);
+        }
+
+        let stream = locked_packet.stream;
+        if stream == 0 || stream > UAS_MAX_STREAMS as u32 {
+            bail!("Bad UAS stream {} of ep_number {}", stream, ep_number);
+        }
+        let stream = stream as u16;
+        match (pid, ep_number) {
+            (USB_TOKEN_IN, UAS_PIPE_ID_STATUS) => {
+                if let Some(mut iu) = self.uas.status_ius.remove(&stream) {
+                    let len = iu.len();
+                    locked_packet.transfer_packet(&mut iu, len);
+                    return Ok(());
+                }
+                // Wait for the IU, and complete the packet asynchronously.
+                locked_packet.is_async = true;
+                self.uas.status_packets.insert(stream, packet.clone());
+            }
+            (USB_TOKEN_IN, UAS_PIPE_ID_DATA_IN) | (USB_TOKEN_OUT, UAS_PIPE_ID_DATA_OUT) => {
+                match self.uas.commands.remove(&stream) {
+                    Some(cmd) => self.execute_uas_data_command(&cmd, &mut locked_packet)?,
+                    None => {
+                        // Wait for the command, and complete the packet asynchronously.
+                        locked_packet.is_async = true;
+                        self.uas.data_packets.insert(stream, packet.clone());
+                    }
+                }
+            }
+            (pid, ep_number) => bail!("Error UAS pid {} ep_number {}!", pid, ep_number),
+        }
+        Ok(())
+    }
+
+    /// Queue the IU of the stream, and complete the status packet waiting for it.
+    fn queue_uas_iu(&mut self, stream: u16, mut iu: Vec<u8>) {
+        if let Some(packet) = self.uas.status_packets.remove(&stream) {
+            let mut locked_packet = packet.lock().unwrap();
+            // The transfer has not been cancelled.
+            if let Some(ops) = locked_packet
+                .xfer_ops
+                .as_ref()
+                .and_then(|ops| ops.upgrade())
+            {
+                let len = iu.len();
+                locked_packet.transfer_packet(&mut iu, len);
+                drop(locked_packet);
+                ops.lock().unwrap().submit_transfer();
+                return;
+            }
+        }
+        self.uas.status_ius.insert(stream, iu);
+    }
 }
 
 impl UsbDeviceOps for UsbStorage {
     fn realize(mut self) -> Result<Arc<Mutex<dyn UsbDeviceOps>>> {
         self.usb_device.reset_usb_endpoint();
-        self.usb_device.speed = USB_SPEED_HIGH;
+        self.usb_device.speed = match self.config.uas {
+            true => USB_SPEED_SUPER,
+            false => USB_SPEED_HIGH,
+        };
         let mut s: Vec<String> = DESC_STRINGS.iter().map(|&s| s.to_string()).collect();
         let prefix = &s[STR_SERIAL_STORAGE_INDEX as usize];
         s[STR_SERIAL_STORAGE_INDEX as usize] = self.usb_device.generate_serial_number(prefix);
-        self.usb_device
-            .init_descriptor(DESC_DEVICE_STORAGE.clone(), s)?;
+        let desc = match self.config.uas {
+            true => DESC_DEVICE_UAS.clone(),
+            false => DESC_DEVICE_STORAGE.clone(),
+        };
+        self.usb_device.init_descriptor(desc, s)?;
 
         // NOTE: "aio=off,direct=false" must be configured and other aio/direct values are not supported.
-        let mut locked_scsi_dev = self.scsi_dev.lock().unwrap();
-        locked_scsi_dev.realize(None)?;
-        drop(locked_scsi_dev);
-        self.scsi_bus
-            .lock()
-            .unwrap()
-            .devices
-            .insert((0, 0), self.scsi_dev.clone());
+        for (lun, scsi_dev) in self.scsi_devs.iter().enumerate() {
+            let mut locked_scsi_dev = scsi_dev.lock().unwrap();
+            locked_scsi_dev.realize(None)?;
+            locked_scsi_dev.parent_bus = Arc::downgrade(&self.scsi_bus);
+            let removable = locked_scsi_dev.is_removable();
+            drop(locked_scsi_dev);
+            self.scsi_bus
+                .lock()
+                .unwrap()
+                .devices
+                .insert((USB_STORAGE_SCSI_TARGET_ID, lun as u16), scsi_dev.clone());
+            if removable {
+                register_removable_device(scsi_dev);
+            }
+        }
 
         let storage: Arc<Mutex<UsbStorage>> = Arc::new(Mutex::new(self));
         Ok(storage)
     }
 
+    fn unrealize(&mut self) -> Result<()> {
+        for lun in self.config.luns.iter() {
+            unregister_removable_device(&lun.scsi_cfg.id);
+        }
+        Ok(())
+    }
+
     fn reset(&mut self) {
         info!("Storage device reset");
         self.usb_device.remote_wakeup = 0;
         self.usb_device.addr = 0;
         self.state = UsbStorageState::new();
+        self.uas = UasState::default();
     }
 
     fn handle_control(&mut self, packet: &Arc<Mutex<UsbPacket>>, device_req: &UsbDeviceRequest) {
@@ -549,6 +1105,11 @@ impl UsbDeviceOps for UsbStorage {
         {
             Ok(handled) => {
                 if handled {
+                    if device_req.request == USB_REQUEST_SET_INTERFACE {
+                        // Switching between Bulk-Only Transport and UAS drops all the requests.
+                        self.state = UsbStorageState::new();
+                        self.uas = UasState::default();
+                    }
                     debug!("Storage control handled by descriptor, return directly.");
                     return;
                 }
@@ -562,6 +1123,18 @@ impl UsbDeviceOps for UsbStorage {
     }
 
     fn handle_data(&mut self, packet: &Arc<Mutex<UsbPacket>>) {
+        if self.usb_device.descriptor.altsetting[0] == UAS_ALT_SETTING as u32 {
+            if let Err(e) = self.handle_uas_data(packet) {
+                warn!(
+                    "USB-storage {}: handle UAS data error: {:?}",
+                    self.device_id(),
+                    e
+                );
+                packet.lock().unwrap().status = UsbPacketStatus::Stall;
+            }
+            return;
+        }
+
         let mut locked_packet = packet.lock().unwrap();
         debug!(
             "Storage device handle_data endpoint {}, mode {:?}",
@@ -604,3 +1177,36 @@ impl UsbDeviceOps for UsbStorage {
         self.usb_device.get_endpoint(true, 1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScsiBus::{SCSI_FIXED_SENSE_LEN, SCSI_SENSE_NO_MEDIUM};
+
+    #[test]
+    fn test_uas_iu() {
+        let iu = uas_response_iu(2, UAS_RC_INCORRECT_LUN);
+        assert_eq!(iu, vec![UAS_IU_ID_RESPONSE, 0, 0, 2, 0, 0, 0, 9]);
+
+        // No sense data is reported in GOOD status.
+        let iu = uas_sense_iu(3, GOOD, None);
+        assert_eq!(iu.len(), UAS_SENSE_IU_HEADER_SIZE);
+        assert_eq!(iu[0], UAS_IU_ID_SENSE);
+        assert_eq!(BigEndian::read_u16(&iu[2..4]), 3);
+        assert_eq!(iu[6], GOOD);
+        assert_eq!(BigEndian::read_u16(&iu[14..16]), 0);
+
+        let iu = uas_sense_iu(4, CHECK_CONDITION, Some(SCSI_SENSE_NO_MEDIUM));
+        assert_eq!(iu.len(), UAS_SENSE_IU_HEADER_SIZE + SCSI_FIXED_SENSE_LEN);
+        assert_eq!(iu[6], CHECK_CONDITION);
+        assert_eq!(
+            BigEndian::read_u16(&iu[14..16]) as usize,
+            SCSI_FIXED_SENSE_LEN
+        );
+        let sense = &iu[UAS_SENSE_IU_HEADER_SIZE..];
+        assert_eq!(sense[0], 0x70);
+        assert_eq!(sense[2], SCSI_SENSE_NO_MEDIUM.key);
+        assert_eq!(sense[12], SCSI_SENSE_NO_MEDIUM.asc);
+        assert_eq!(sense[13], SCSI_SENSE_NO_MEDIUM.ascq);
+    }
+}
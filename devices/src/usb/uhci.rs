@@ -0,0 +1,888 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use machine_manager::config::UhciConfig;
+use machine_manager::event_loop::EventLoop;
+use pci::config::{
+    PciConfig, RegionType, DEVICE_ID, PCI_CLASS_SERIAL_USB, PCI_CONFIG_SPACE_SIZE,
+    PCI_DEVICE_ID_INTEL_ICH9_UHCI1, PCI_VENDOR_ID_INTEL, REVISION_ID, SUB_CLASS_CODE, VENDOR_ID,
+};
+use pci::{init_intx, init_multifunction, le_write_u16, PciBus, PciDevOps};
+
+use super::config::*;
+use super::ehci::EhciDevice;
+use super::xhci::xhci_controller::{dma_read_u32, dma_write_u32, UsbPort};
+use super::{PolledPacket, UsbControlPipe, UsbDeviceOps, UsbPacketStatus};
+
+/// PCI configuration registers.
+const PCI_CLASS_PI: usize = 0x09;
+const PCI_CLASS_PI_UHCI: u8 = 0x00;
+const PCI_SERIAL_BUS_RELEASE_NUMBER: usize = 0x60;
+const PCI_SERIAL_BUS_RELEASE_VERSION_1_0: u8 = 0x10;
+const PCI_LEGACY_SUPPORT: usize = 0xc0;
+/// USB PIRQ enable, which is the default of the legacy support register.
+const PCI_LEGACY_SUPPORT_DEFAULT: u16 = 0x2000;
+const UHCI_IO_BAR: usize = 4;
+const UHCI_IO_SIZE: u64 = 0x20;
+
+/// 2.1 USB I/O Registers.
+const UHCI_REG_USBCMD: u64 = 0x00;
+const UHCI_REG_USBSTS: u64 = 0x02;
+const UHCI_REG_USBINTR: u64 = 0x04;
+const UHCI_REG_FRNUM: u64 = 0x06;
+const UHCI_REG_FLBASEADD_LOW: u64 = 0x08;
+const UHCI_REG_FLBASEADD_HIGH: u64 = 0x0a;
+const UHCI_REG_SOFMOD: u64 = 0x0c;
+const UHCI_REG_PORTSC: u64 = 0x10;
+
+/// USB Command Register.
+const USBCMD_RS: u16 = 1 << 0;
+const USBCMD_HCRESET: u16 = 1 << 1;
+const USBCMD_GRESET: u16 = 1 << 2;
+const USBCMD_EGSM: u16 = 1 << 3;
+
+/// USB Status Register.
+const USBSTS_USBINT: u16 = 1 << 0;
+const USBSTS_ERROR: u16 = 1 << 1;
+const USBSTS_RD: u16 = 1 << 2;
+const USBSTS_HSE: u16 = 1 << 3;
+const USBSTS_HCPE: u16 = 1 << 4;
+const USBSTS_HCH: u16 = 1 << 5;
+const USBSTS_WC_MASK: u16 = 0x1f;
+
+/// USB Interrupt Enable Register.
+const USBINTR_TOCRC: u16 = 1 << 0;
+const USBINTR_RESUME: u16 = 1 << 1;
+const USBINTR_IOC: u16 = 1 << 2;
+const USBINTR_SP: u16 = 1 << 3;
+const USBINTR_MASK: u16 = 0xf;
+
+/// Port Status and Control Register.
+const PORTSC_CCS: u16 = 1 << 0;
+const PORTSC_CSC: u16 = 1 << 1;
+const PORTSC_PE: u16 = 1 << 2;
+const PORTSC_PEC: u16 = 1 << 3;
+const PORTSC_RD: u16 = 1 << 6;
+/// Reserved bit which always reads as 1.
+const PORTSC_ALWAYS_ONE: u16 = 1 << 7;
+const PORTSC_LSDA: u16 = 1 << 8;
+const PORTSC_PR: u16 = 1 << 9;
+const PORTSC_SUSP: u16 = 1 << 12;
+const PORTSC_WC_MASK: u16 = PORTSC_CSC | PORTSC_PEC;
+const PORTSC_RW_MASK: u16 = PORTSC_PE | PORTSC_RD | PORTSC_PR | PORTSC_SUSP;
+
+const FRNUM_MASK: u16 = 0x7ff;
+const FRAME_LIST_SIZE: u32 = 1024;
+const FLBASEADD_MASK: u32 = !0xfff;
+const SOFMOD_DEFAULT: u8 = 0x40;
+const FRAME_INTERVAL: Duration = Duration::from_millis(1);
+
+/// 3.1 Frame List Pointer and the link pointers of TD and QH.
+const LINK_TERMINATE: u32 = 1 << 0;
+const LINK_QH: u32 = 1 << 1;
+const LINK_DEPTH_FIRST: u32 = 1 << 2;
+const LINK_ADDR_MASK: u32 = !0xf;
+
+/// 3.2 Transfer Descriptor, in dwords.
+const TD_DWORDS: usize = 4;
+const TD_LINK: usize = 0;
+const TD_CTRL: usize = 1;
+const TD_TOKEN: usize = 2;
+const TD_BUFFER: usize = 3;
+const TD_CTRL_ACTLEN_MASK: u32 = 0x7ff;
+const TD_CTRL_CRCTIMEOUT: u32 = 1 << 18;
+const TD_CTRL_NAK: u32 = 1 << 19;
+const TD_CTRL_BABBLE: u32 = 1 << 20;
+const TD_CTRL_DBE: u32 = 1 << 21;
+const TD_CTRL_STALLED: u32 = 1 << 22;
+const TD_CTRL_ACTIVE: u32 = 1 << 23;
+const TD_CTRL_IOC: u32 = 1 << 24;
+const TD_CTRL_ISO: u32 = 1 << 25;
+const TD_CTRL_CERR_SHIFT: u32 = 27;
+const TD_CTRL_CERR_MASK: u32 = 0x3;
+const TD_CTRL_SPD: u32 = 1 << 29;
+const TD_CTRL_STATUS_MASK: u32 = TD_CTRL_ACTIVE
+    | TD_CTRL_STALLED
+    | TD_CTRL_DBE
+    | TD_CTRL_BABBLE
+    | TD_CTRL_NAK
+    | TD_CTRL_CRCTIMEOUT
+    | 1 << 17;
+const TD_TOKEN_PID_MASK: u32 = 0xff;
+const TD_TOKEN_DEVADDR_SHIFT: u32 = 8;
+const TD_TOKEN_DEVADDR_MASK: u32 = 0x7f;
+const TD_TOKEN_EP_SHIFT: u32 = 15;
+const TD_TOKEN_EP_MASK: u32 = 0xf;
+const TD_TOKEN_MAXLEN_SHIFT: u32 = 21;
+/// The length is encoded as n - 1, and 0x7ff means zero length.
+const TD_LEN_MASK: u32 = 0x7ff;
+
+/// 3.3 Queue Head, in dwords.
+const QH_DWORDS: usize = 2;
+const QH_HEAD: usize = 0;
+const QH_ELEMENT: usize = 1;
+
+/// Limits of the schedule walk in one frame, which protect against the loops
+/// in the guest lists.
+const MAX_FRAME_ELEMENTS: usize = 256;
+const MAX_FRAME_QHS: usize = 64;
+
+/// Result of the TD processing.
+#[derive(Debug, PartialEq, Eq)]
+enum TdResult {
+    /// The TD is retired without error.
+    Success,
+    /// The TD is retired with a short packet and the short packet detect is set.
+    Short,
+    /// The TD is retired with error.
+    Error,
+    /// The device has no data, retry in the next frame.
+    Nak,
+    /// The packet is handled by the device asynchronously.
+    Async,
+    /// The TD is not active.
+    Inactive,
+}
+
+/// UHCI root port, which is routed from the EHCI port.
+struct UhciPort {
+    usb_port: Arc<Mutex<UsbPort>>,
+    portsc: u16,
+    /// Whether the port is handed over to UHCI by EHCI.
+    owned: bool,
+    pipe: UsbControlPipe,
+}
+
+/// UHCI controller, which only works as the companion of the EHCI controller.
+pub struct UhciDevice {
+    mem_space: Arc<AddressSpace>,
+    usbcmd: u16,
+    usbsts: u16,
+    usbintr: u16,
+    frnum: u16,
+    flbaseadd: u32,
+    sofmod: u8,
+    ports: Vec<UhciPort>,
+    inflight: HashMap<u32, PolledPacket>,
+    timer_id: Option<u64>,
+    interrupt_cb: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+    weak_self: Weak<Mutex<UhciDevice>>,
+}
+
+impl UhciDevice {
+    pub fn new(mem_space: &Arc<AddressSpace>, ports: Vec<Arc<Mutex<UsbPort>>>) -> Arc<Mutex<Self>> {
+        let uhci = Arc::new(Mutex::new(Self {
+            mem_space: mem_space.clone(),
+            usbcmd: 0,
+            usbsts: USBSTS_HCH,
+            usbintr: 0,
+            frnum: 0,
+            flbaseadd: 0,
+            sofmod: SOFMOD_DEFAULT,
+            ports: ports
+                .into_iter()
+                .map(|usb_port| UhciPort {
+                    usb_port,
+                    portsc: 0,
+                    owned: false,
+                    pipe: UsbControlPipe::default(),
+                })
+                .collect(),
+            inflight: HashMap::new(),
+            timer_id: None,
+            interrupt_cb: None,
+            weak_self: Weak::new(),
+        }));
+        uhci.lock().unwrap().weak_self = Arc::downgrade(&uhci);
+        uhci
+    }
+
+    pub fn set_interrupt_ops(&mut self, cb: Arc<dyn Fn(u32) + Send + Sync>) {
+        self.interrupt_cb = Some(cb);
+    }
+
+    pub fn reset(&mut self) {
+        info!("uhci reset");
+        self.stop_frame_timer();
+        self.usbcmd = 0;
+        self.usbsts = USBSTS_HCH;
+        self.usbintr = 0;
+        self.frnum = 0;
+        self.flbaseadd = 0;
+        self.sofmod = SOFMOD_DEFAULT;
+        self.inflight.clear();
+        for i in 0..self.ports.len() {
+            self.ports[i].portsc = 0;
+            self.ports[i].pipe.reset();
+            let owned = self.ports[i].owned;
+            self.port_update(i, owned);
+        }
+        self.update_irq();
+    }
+
+    fn running(&self) -> bool {
+        self.usbcmd & USBCMD_RS != 0
+    }
+
+    fn update_irq(&self) {
+        let sts = self.usbsts;
+        let intr = self.usbintr;
+        let level = (sts & USBSTS_USBINT != 0 && intr & (USBINTR_IOC | USBINTR_SP) != 0)
+            || (sts & USBSTS_ERROR != 0 && intr & USBINTR_TOCRC != 0)
+            || (sts & USBSTS_RD != 0 && intr & USBINTR_RESUME != 0)
+            || sts & (USBSTS_HSE | USBSTS_HCPE) != 0;
+        if let Some(cb) = self.interrupt_cb.as_ref() {
+            cb(level as u32);
+        }
+    }
+
+    /// Update the connection of the port, which is only visible when the
+    /// port is handed over by EHCI.
+    pub fn port_update(&mut self, idx: usize, owned: bool) {
+        let port = &mut self.ports[idx];
+        port.owned = owned;
+        let dev = if owned {
+            port.usb_port.lock().unwrap().dev.clone()
+        } else {
+            None
+        };
+        let (connected, low_speed) = dev.map_or((false, false), |dev| {
+            let locked_dev = dev.lock().unwrap();
+            (locked_dev.connected(), locked_dev.speed() == USB_SPEED_LOW)
+        });
+        if connected == (port.portsc & PORTSC_CCS != 0) {
+            return;
+        }
+        if connected {
+            port.portsc |= PORTSC_CCS | PORTSC_CSC;
+            if low_speed {
+                port.portsc |= PORTSC_LSDA;
+            }
+        } else {
+            if port.portsc & PORTSC_PE != 0 {
+                port.portsc |= PORTSC_PEC;
+            }
+            port.portsc &= !(PORTSC_CCS | PORTSC_PE | PORTSC_LSDA);
+            port.portsc |= PORTSC_CSC;
+        }
+        port.pipe.reset();
+        // The suspended controller is woken up by the connection change.
+        if self.usbcmd & (USBCMD_RS | USBCMD_EGSM) == USBCMD_EGSM {
+            self.usbsts |= USBSTS_RD;
+            self.update_irq();
+        }
+    }
+
+    fn read_reg(&self, offset: u64) -> u16 {
+        match offset {
+            UHCI_REG_USBCMD => self.usbcmd,
+            UHCI_REG_USBSTS => self.usbsts,
+            UHCI_REG_USBINTR => self.usbintr,
+            UHCI_REG_FRNUM => self.frnum,
+            UHCI_REG_FLBASEADD_LOW => self.flbaseadd as u16,
+            UHCI_REG_FLBASEADD_HIGH => (self.flbaseadd >> 16) as u16,
+            UHCI_REG_SOFMOD => self.sofmod as u16,
+            _ => match port_index(offset) {
+                Some(idx) if idx < self.ports.len() => self.ports[idx].portsc | PORTSC_ALWAYS_ONE,
+                _ => {
+                    debug!("Read uhci unknown register: offset 0x{:x}", offset);
+                    0
+                }
+            },
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u16) {
+        match offset {
+            UHCI_REG_USBCMD => self.write_usbcmd(value),
+            UHCI_REG_USBSTS => {
+                self.usbsts &= !(value & USBSTS_WC_MASK);
+                self.update_irq();
+            }
+            UHCI_REG_USBINTR => {
+                self.usbintr = value & USBINTR_MASK;
+                self.update_irq();
+            }
+            UHCI_REG_FRNUM => {
+                // Only writable when the controller is halted.
+                if self.usbsts & USBSTS_HCH != 0 {
+                    self.frnum = value & FRNUM_MASK;
+                }
+            }
+            UHCI_REG_FLBASEADD_LOW => {
+                self.flbaseadd = (self.flbaseadd & 0xffff_0000) | (value as u32 & FLBASEADD_MASK)
+            }
+            UHCI_REG_FLBASEADD_HIGH => {
+                self.flbaseadd = (self.flbaseadd & 0xffff) | (value as u32) << 16
+            }
+            UHCI_REG_SOFMOD => self.sofmod = value as u8,
+            _ => match port_index(offset) {
+                Some(idx) if idx < self.ports.len() => self.write_portsc(idx, value),
+                _ => error!("Failed to write uhci register: offset 0x{:x}", offset),
+            },
+        }
+    }
+
+    fn write_usbcmd(&mut self, value: u16) {
+        if value & (USBCMD_HCRESET | USBCMD_GRESET) != 0 {
+            self.reset();
+            // The global reset lasts until the driver clears it.
+            self.usbcmd = value & USBCMD_GRESET;
+            return;
+        }
+        let old = self.usbcmd;
+        self.usbcmd = value;
+        if (old ^ value) & USBCMD_RS != 0 {
+            if value & USBCMD_RS != 0 {
+                self.usbsts &= !USBSTS_HCH;
+                self.start_frame_timer();
+            } else {
+                self.usbsts |= USBSTS_HCH;
+                self.stop_frame_timer();
+            }
+        }
+    }
+
+    fn write_portsc(&mut self, idx: usize, value: u16) {
+        let port = &mut self.ports[idx];
+        let old = port.portsc;
+        let mut portsc = old & !(value & PORTSC_WC_MASK);
+        portsc = (portsc & !PORTSC_RW_MASK) | (value & PORTSC_RW_MASK);
+        // The port can not be enabled without the device.
+        if portsc & PORTSC_CCS == 0 {
+            portsc &= !PORTSC_PE;
+        }
+        port.portsc = portsc;
+
+        if old & PORTSC_PR != 0 && value & PORTSC_PR == 0 {
+            port.pipe.reset();
+            if let Some(dev) = port.usb_port.lock().unwrap().dev.as_ref() {
+                dev.lock().unwrap().reset();
+            }
+        }
+    }
+
+    fn start_frame_timer(&mut self) {
+        if self.timer_id.is_some() {
+            return;
+        }
+        let weak_uhci = self.weak_self.clone();
+        let frame_timer = Box::new(move || {
+            if let Some(uhci) = weak_uhci.upgrade() {
+                let mut locked_uhci = uhci.lock().unwrap();
+                locked_uhci.timer_id = None;
+                locked_uhci.run_frame();
+                if locked_uhci.running() {
+                    locked_uhci.start_frame_timer();
+                }
+            }
+        });
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            self.timer_id = Some(ctx.timer_add(frame_timer, FRAME_INTERVAL));
+        }
+    }
+
+    fn stop_frame_timer(&mut self) {
+        if let Some(timer_id) = self.timer_id.take() {
+            if let Some(ctx) = EventLoop::get_ctx(None) {
+                ctx.timer_del(timer_id);
+            }
+        }
+    }
+
+    /// Process the schedule of one frame.
+    fn run_frame(&mut self) {
+        self.frnum = (self.frnum + 1) & FRNUM_MASK;
+        if let Err(e) = self.process_frame() {
+            error!("Uhci host system error: {:?}", e);
+            self.usbsts |= USBSTS_HSE | USBSTS_HCH;
+            self.usbcmd &= !USBCMD_RS;
+        }
+        self.update_irq();
+    }
+
+    fn process_frame(&mut self) -> Result<()> {
+        let frame = self.frnum as u32 % FRAME_LIST_SIZE;
+        let mut link = self.read_u32(self.flbaseadd + frame * 4)?;
+        let mut visited_qhs = Vec::new();
+        for _ in 0..MAX_FRAME_ELEMENTS {
+            if link & LINK_TERMINATE != 0 {
+                break;
+            }
+            let addr = link & LINK_ADDR_MASK;
+            if link & LINK_QH == 0 {
+                let mut td = [0_u32; TD_DWORDS];
+                self.read_dwords(addr, &mut td)?;
+                self.process_td(addr, &mut td)?;
+                link = td[TD_LINK];
+                continue;
+            }
+
+            // The reclaimed queues loop back to the first queue head.
+            if visited_qhs.contains(&addr) || visited_qhs.len() >= MAX_FRAME_QHS {
+                break;
+            }
+            visited_qhs.push(addr);
+            link = self.process_qh(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Process the TDs of the queue head, returns its horizontal link.
+    fn process_qh(&mut self, addr: u32) -> Result<u32> {
+        let mut qh = [0_u32; QH_DWORDS];
+        self.read_dwords(addr, &mut qh)?;
+        for _ in 0..MAX_FRAME_ELEMENTS {
+            let element = qh[QH_ELEMENT];
+            // Nested queue head is not supported.
+            if element & (LINK_TERMINATE | LINK_QH) != 0 {
+                break;
+            }
+            let td_addr = element & LINK_ADDR_MASK;
+            let mut td = [0_u32; TD_DWORDS];
+            self.read_dwords(td_addr, &mut td)?;
+            if self.process_td(td_addr, &mut td)? != TdResult::Success {
+                break;
+            }
+            qh[QH_ELEMENT] = td[TD_LINK];
+            self.write_dwords(addr + (QH_ELEMENT * 4) as u32, &qh[QH_ELEMENT..])?;
+            if td[TD_LINK] & LINK_DEPTH_FIRST == 0 {
+                break;
+            }
+        }
+        Ok(qh[QH_HEAD])
+    }
+
+    fn process_td(&mut self, addr: u32, td: &mut [u32; TD_DWORDS]) -> Result<TdResult> {
+        if td[TD_CTRL] & TD_CTRL_ACTIVE == 0 {
+            self.inflight.remove(&addr);
+            return Ok(TdResult::Inactive);
+        }
+        if let Some(packet) = self.inflight.get(&addr) {
+            if !packet.completed() {
+                return Ok(TdResult::Async);
+            }
+            let packet = self.inflight.remove(&addr).unwrap();
+            let locked_packet = packet.packet.lock().unwrap();
+            let (status, len) = (locked_packet.status, locked_packet.actual_length);
+            drop(locked_packet);
+            return self.complete_td(addr, td, status, len);
+        }
+        if td[TD_CTRL] & TD_CTRL_ISO != 0 {
+            error!("Isochronous transfer is not supported by uhci");
+            return self.complete_td(addr, td, UsbPacketStatus::Stall, 0);
+        }
+
+        let token = td[TD_TOKEN];
+        let pid = token & TD_TOKEN_PID_MASK;
+        let devaddr = ((token >> TD_TOKEN_DEVADDR_SHIFT) & TD_TOKEN_DEVADDR_MASK) as u8;
+        let ep_number = ((token >> TD_TOKEN_EP_SHIFT) & TD_TOKEN_EP_MASK) as u8;
+        let maxlen = td_len_decode(token >> TD_TOKEN_MAXLEN_SHIFT);
+        if pid != USB_TOKEN_SETUP as u32
+            && pid != USB_TOKEN_IN as u32
+            && pid != USB_TOKEN_OUT as u32
+        {
+            error!("Invalid uhci TD pid 0x{:x}", pid);
+            self.usbsts |= USBSTS_HCPE | USBSTS_HCH;
+            self.usbcmd &= !USBCMD_RS;
+            return Ok(TdResult::Error);
+        }
+        let (idx, dev) = match self.find_device(devaddr) {
+            Some(found) => found,
+            None => {
+                debug!("No usb device found with address {}", devaddr);
+                return self.complete_td(addr, td, UsbPacketStatus::NoDev, 0);
+            }
+        };
+
+        let iovecs = if maxlen > 0 {
+            self.mem_space
+                .get_address_map(GuestAddress(td[TD_BUFFER] as u64), maxlen as u64)?
+        } else {
+            Vec::new()
+        };
+        let packet = PolledPacket::new(pid, ep_number, iovecs);
+        if ep_number == 0 {
+            let mut locked_packet = packet.packet.lock().unwrap();
+            self.ports[idx].pipe.handle_token(&dev, &mut locked_packet);
+        } else {
+            dev.lock().unwrap().handle_packet(&packet.packet);
+        }
+
+        let locked_packet = packet.packet.lock().unwrap();
+        if locked_packet.is_async {
+            drop(locked_packet);
+            self.inflight.insert(addr, packet);
+            return Ok(TdResult::Async);
+        }
+        let (status, len) = (locked_packet.status, locked_packet.actual_length);
+        drop(locked_packet);
+        if status == UsbPacketStatus::Nak {
+            td[TD_CTRL] |= TD_CTRL_NAK;
+            self.write_dwords(addr + (TD_CTRL * 4) as u32, &td[TD_CTRL..TD_TOKEN])?;
+            return Ok(TdResult::Nak);
+        }
+        self.complete_td(addr, td, status, len)
+    }
+
+    /// Retire the TD and write back its control and status.
+    fn complete_td(
+        &mut self,
+        addr: u32,
+        td: &mut [u32; TD_DWORDS],
+        status: UsbPacketStatus,
+        actual_length: u32,
+    ) -> Result<TdResult> {
+        let (ctrl, result) = td_ctrl_complete(td[TD_CTRL], td[TD_TOKEN], status, actual_length);
+        td[TD_CTRL] = ctrl;
+        self.write_dwords(addr + (TD_CTRL * 4) as u32, &td[TD_CTRL..TD_TOKEN])?;
+
+        if result == TdResult::Error {
+            self.usbsts |= USBSTS_ERROR;
+        }
+        if ctrl & TD_CTRL_IOC != 0 || result == TdResult::Short {
+            self.usbsts |= USBSTS_USBINT;
+        }
+        Ok(result)
+    }
+
+    /// Find the device with the address on the enabled ports owned by UHCI.
+    fn find_device(&self, devaddr: u8) -> Option<(usize, Arc<Mutex<dyn UsbDeviceOps>>)> {
+        for (i, port) in self.ports.iter().enumerate() {
+            if !port.owned || port.portsc & PORTSC_PE == 0 {
+                continue;
+            }
+            if let Some(dev) = port.usb_port.lock().unwrap().dev.as_ref() {
+                if dev.lock().unwrap().get_usb_device().addr == devaddr {
+                    return Some((i, dev.clone()));
+                }
+            }
+        }
+        None
+    }
+
+    fn read_u32(&self, addr: u32) -> Result<u32> {
+        let mut value = [0_u32; 1];
+        self.read_dwords(addr, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_dwords(&self, addr: u32, buf: &mut [u32]) -> Result<()> {
+        dma_read_u32(&self.mem_space, GuestAddress(addr as u64), buf)
+    }
+
+    fn write_dwords(&self, addr: u32, buf: &[u32]) -> Result<()> {
+        dma_write_u32(&self.mem_space, GuestAddress(addr as u64), buf)
+    }
+}
+
+fn port_index(offset: u64) -> Option<usize> {
+    if offset < UHCI_REG_PORTSC || offset % 2 != 0 {
+        return None;
+    }
+    Some(((offset - UHCI_REG_PORTSC) / 2) as usize)
+}
+
+/// Decode the length which is encoded as n - 1.
+fn td_len_decode(value: u32) -> u32 {
+    (value + 1) & TD_LEN_MASK
+}
+
+/// Get the control and status of the retired TD.
+fn td_ctrl_complete(
+    ctrl: u32,
+    token: u32,
+    status: UsbPacketStatus,
+    actual_length: u32,
+) -> (u32, TdResult) {
+    let mut ctrl = ctrl & !(TD_CTRL_STATUS_MASK | TD_CTRL_ACTLEN_MASK);
+    match status {
+        UsbPacketStatus::Success => {
+            let maxlen = td_len_decode(token >> TD_TOKEN_MAXLEN_SHIFT);
+            let actual = actual_length.min(maxlen);
+            ctrl |= actual.wrapping_sub(1) & TD_CTRL_ACTLEN_MASK;
+            let pid = token & TD_TOKEN_PID_MASK;
+            if pid == USB_TOKEN_IN as u32 && actual < maxlen && ctrl & TD_CTRL_SPD != 0 {
+                return (ctrl, TdResult::Short);
+            }
+            return (ctrl, TdResult::Success);
+        }
+        UsbPacketStatus::Stall => ctrl |= TD_CTRL_STALLED | TD_LEN_MASK,
+        UsbPacketStatus::Babble => ctrl |= TD_CTRL_STALLED | TD_CTRL_BABBLE | TD_LEN_MASK,
+        _ => {
+            ctrl &= !(TD_CTRL_CERR_MASK << TD_CTRL_CERR_SHIFT);
+            ctrl |= TD_CTRL_STALLED | TD_CTRL_CRCTIMEOUT | TD_LEN_MASK;
+        }
+    }
+    (ctrl, TdResult::Error)
+}
+
+fn build_uhci_ops(uhci: &Arc<Mutex<UhciDevice>>) -> RegionOps {
+    let uhci_dev = uhci.clone();
+    let uhci_read = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+        let locked_uhci = uhci_dev.lock().unwrap();
+        // The registers are 16 bits except SOFMOD, and FLBASEADD may be read
+        // as a dword.
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u64;
+            let value = locked_uhci.read_reg(offset & !1);
+            *byte = (value >> ((offset & 1) * 8)) as u8;
+        }
+        true
+    };
+
+    let uhci_dev = uhci.clone();
+    let uhci_write = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+        let mut locked_uhci = uhci_dev.lock().unwrap();
+        match data.len() {
+            1 if offset == UHCI_REG_SOFMOD => locked_uhci.write_reg(offset, data[0] as u16),
+            2 => locked_uhci.write_reg(offset, u16::from_le_bytes([data[0], data[1]])),
+            4 => {
+                locked_uhci.write_reg(offset, u16::from_le_bytes([data[0], data[1]]));
+                locked_uhci.write_reg(offset + 2, u16::from_le_bytes([data[2], data[3]]));
+            }
+            _ => error!(
+                "Invalid uhci register access: offset 0x{:x} len {}",
+                offset,
+                data.len()
+            ),
+        }
+        true
+    };
+
+    RegionOps {
+        read: Arc::new(uhci_read),
+        write: Arc::new(uhci_write),
+    }
+}
+
+/// UHCI pci device which can be attached to PCI bus.
+pub struct UhciPciDevice {
+    pci_config: PciConfig,
+    devfn: u8,
+    pub uhci: Arc<Mutex<UhciDevice>>,
+    dev_id: Arc<AtomicU16>,
+    name: String,
+    parent_bus: Weak<Mutex<PciBus>>,
+    multi_func: bool,
+    ehci: Weak<Mutex<EhciDevice>>,
+    firstport: usize,
+}
+
+impl UhciPciDevice {
+    pub fn new(
+        config: &UhciConfig,
+        devfn: u8,
+        parent_bus: Weak<Mutex<PciBus>>,
+        mem_space: &Arc<AddressSpace>,
+        ehci: &Arc<Mutex<EhciDevice>>,
+    ) -> Result<Self> {
+        let firstport = config.firstport as usize;
+        let ports = ehci
+            .lock()
+            .unwrap()
+            .companion_ports(firstport)
+            .with_context(|| format!("Failed to add uhci companion of {}", config.masterbus))?;
+        Ok(Self {
+            pci_config: PciConfig::new(PCI_CONFIG_SPACE_SIZE, UHCI_IO_BAR as u8 + 1),
+            devfn,
+            uhci: UhciDevice::new(mem_space, ports),
+            dev_id: Arc::new(AtomicU16::new(0)),
+            name: config.id.clone().unwrap(),
+            parent_bus,
+            multi_func: config.multifunction,
+            ehci: Arc::downgrade(ehci),
+            firstport,
+        })
+    }
+}
+
+impl PciDevOps for UhciPciDevice {
+    fn init_write_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_mask()
+    }
+
+    fn init_write_clear_mask(&mut self) -> pci::Result<()> {
+        self.pci_config.init_common_write_clear_mask()
+    }
+
+    fn realize(mut self) -> pci::Result<()> {
+        self.init_write_mask()?;
+        self.init_write_clear_mask()?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            VENDOR_ID as usize,
+            PCI_VENDOR_ID_INTEL,
+        )?;
+        le_write_u16(
+            &mut self.pci_config.config,
+            DEVICE_ID as usize,
+            PCI_DEVICE_ID_INTEL_ICH9_UHCI1,
+        )?;
+        self.pci_config.config[REVISION_ID] = 0x3;
+        le_write_u16(
+            &mut self.pci_config.config,
+            SUB_CLASS_CODE as usize,
+            PCI_CLASS_SERIAL_USB,
+        )?;
+        self.pci_config.config[PCI_CLASS_PI] = PCI_CLASS_PI_UHCI;
+        self.pci_config.config[PCI_SERIAL_BUS_RELEASE_NUMBER] = PCI_SERIAL_BUS_RELEASE_VERSION_1_0;
+        le_write_u16(
+            &mut self.pci_config.config,
+            PCI_LEGACY_SUPPORT,
+            PCI_LEGACY_SUPPORT_DEFAULT,
+        )?;
+
+        #[cfg(target_arch = "aarch64")]
+        self.pci_config.set_interrupt_pin();
+
+        init_multifunction(
+            self.multi_func,
+            &mut self.pci_config.config,
+            self.devfn,
+            self.parent_bus.clone(),
+        )?;
+        self.dev_id.store(self.devfn as u16, Ordering::SeqCst);
+
+        let io_region =
+            Region::init_io_region(UHCI_IO_SIZE, build_uhci_ops(&self.uhci), "UhciPciRegion");
+        self.pci_config.register_bar(
+            UHCI_IO_BAR,
+            io_region,
+            RegionType::Io,
+            false,
+            UHCI_IO_SIZE,
+        )?;
+
+        init_intx(
+            self.name.clone(),
+            &mut self.pci_config,
+            self.parent_bus.clone(),
+            self.devfn,
+        )?;
+        let cloned_intx = self.pci_config.intx.as_ref().unwrap().clone();
+        self.uhci
+            .lock()
+            .unwrap()
+            .set_interrupt_ops(Arc::new(move |level: u32| {
+                cloned_intx.lock().unwrap().notify(level as u8);
+            }));
+
+        let ehci = self.ehci.upgrade().unwrap();
+        ehci.lock()
+            .unwrap()
+            .register_companion(self.firstport, Arc::downgrade(&self.uhci));
+
+        let devfn = self.devfn;
+        let dev = Arc::new(Mutex::new(self));
+        // Attach to the PCI bus.
+        let pci_bus = dev.lock().unwrap().parent_bus.upgrade().unwrap();
+        let mut locked_pci_bus = pci_bus.lock().unwrap();
+        let pci_device = locked_pci_bus.devices.get(&devfn);
+        if pci_device.is_none() {
+            locked_pci_bus.devices.insert(devfn, dev);
+        } else {
+            bail!(
+                "Devfn {:?} has been used by {:?}",
+                &devfn,
+                pci_device.unwrap().lock().unwrap().name()
+            );
+        }
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> pci::Result<()> {
+        Ok(())
+    }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+
+    fn read_config(&mut self, offset: usize, data: &mut [u8]) {
+        self.pci_config.read(offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+
+        self.pci_config.write(
+            offset,
+            data,
+            self.dev_id.load(Ordering::Acquire),
+            #[cfg(target_arch = "x86_64")]
+            Some(&locked_parent_bus.io_region),
+            Some(&locked_parent_bus.mem_region),
+        );
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn reset(&mut self, _reset_child_device: bool) -> pci::Result<()> {
+        self.uhci.lock().unwrap().reset();
+
+        self.pci_config.reset()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_td_ctrl_complete() {
+        let active = TD_CTRL_ACTIVE | 3 << TD_CTRL_CERR_SHIFT | TD_CTRL_ACTLEN_MASK;
+        // IN TD of 8 bytes.
+        let token = 7 << TD_TOKEN_MAXLEN_SHIFT | USB_TOKEN_IN as u32;
+        let (ctrl, result) = td_ctrl_complete(active, token, UsbPacketStatus::Success, 8);
+        assert_eq!(result, TdResult::Success);
+        assert_eq!(ctrl & TD_CTRL_ACTIVE, 0);
+        assert_eq!(ctrl & TD_CTRL_ACTLEN_MASK, 7);
+
+        // Short packet is only reported with short packet detect.
+        let (ctrl, result) = td_ctrl_complete(active, token, UsbPacketStatus::Success, 3);
+        assert_eq!(result, TdResult::Success);
+        assert_eq!(ctrl & TD_CTRL_ACTLEN_MASK, 2);
+        let (_, result) =
+            td_ctrl_complete(active | TD_CTRL_SPD, token, UsbPacketStatus::Success, 3);
+        assert_eq!(result, TdResult::Short);
+
+        // Zero length packet.
+        let token = TD_LEN_MASK << TD_TOKEN_MAXLEN_SHIFT | USB_TOKEN_OUT as u32;
+        let (ctrl, result) = td_ctrl_complete(active, token, UsbPacketStatus::Success, 0);
+        assert_eq!(result, TdResult::Success);
+        assert_eq!(ctrl & TD_CTRL_ACTLEN_MASK, TD_LEN_MASK);
+
+        let (ctrl, result) = td_ctrl_complete(active, token, UsbPacketStatus::Stall, 0);
+        assert_eq!(result, TdResult::Error);
+        assert_eq!(ctrl & (TD_CTRL_ACTIVE | TD_CTRL_STALLED), TD_CTRL_STALLED);
+
+        let (ctrl, result) = td_ctrl_complete(active, token, UsbPacketStatus::NoDev, 0);
+        assert_eq!(result, TdResult::Error);
+        assert_ne!(ctrl & TD_CTRL_CRCTIMEOUT, 0);
+        assert_eq!((ctrl >> TD_CTRL_CERR_SHIFT) & TD_CTRL_CERR_MASK, 0);
+    }
+}
@@ -253,6 +253,7 @@ pub fn fill_transfer_by_type(
 ) {
     let packet = request.lock().unwrap().packet.clone();
     let size = packet.lock().unwrap().get_iovecs_size();
+    let stream = packet.lock().unwrap().stream;
     let buffer_ptr = request.lock().unwrap().buffer.as_mut_ptr();
 
     if handle.is_none() {
@@ -289,6 +290,7 @@ pub fn fill_transfer_by_type(
                 (Arc::into_raw(request) as *mut Mutex<UsbHostRequest>).cast::<libc::c_void>(),
                 BULK_TIMEOUT,
             );
+            libusb1_sys::libusb_transfer_set_stream_id(transfer, stream);
         },
         TransferType::Interrupt => unsafe {
             libusb1_sys::libusb_fill_interrupt_transfer(
@@ -352,3 +354,30 @@ pub fn free_host_transfer(transfer: *mut libusb_transfer) {
     // SAFETY: have checked the validity of transfer before call libusb_free_transfer.
     unsafe { libusb1_sys::libusb_free_transfer(transfer) };
 }
+
+/// Allocate streams for the bulk endpoint, return the number of allocated streams.
+pub fn alloc_host_streams(
+    handle: &mut DeviceHandle<Context>,
+    ep_addr: u8,
+    streams: u32,
+) -> Result<u32> {
+    let mut endpoints = [ep_addr];
+    // SAFETY: the handle is valid and the endpoints array contains one endpoint.
+    let ret = unsafe {
+        libusb1_sys::libusb_alloc_streams(handle.as_raw(), streams, endpoints.as_mut_ptr(), 1)
+    };
+    if ret < 0 {
+        return Err(from_libusb(ret));
+    }
+    Ok(ret as u32)
+}
+
+pub fn free_host_streams(handle: &mut DeviceHandle<Context>, ep_addr: u8) -> Result<()> {
+    let mut endpoints = [ep_addr];
+    try_unsafe!(libusb1_sys::libusb_free_streams(
+        handle.as_raw(),
+        endpoints.as_mut_ptr(),
+        1
+    ));
+    Ok(())
+}
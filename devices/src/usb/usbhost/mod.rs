@@ -1130,6 +1130,29 @@ impl UsbDeviceOps for UsbHost {
         self.submit_host_transfer(host_transfer, packet);
     }
 
+    fn alloc_streams(&mut self, ep_addr: u8, streams: u32) -> Result<()> {
+        if self.handle.is_none() {
+            bail!("Device {} is not opened", self.device_id());
+        }
+        let allocated = alloc_host_streams(self.handle.as_mut().unwrap(), ep_addr, streams)
+            .map_err(|e| anyhow!("Failed to alloc streams: {:?}", e))?;
+        if allocated < streams {
+            warn!(
+                "Only {} of {} streams are allocated for endpoint {:#x}",
+                allocated, streams, ep_addr
+            );
+        }
+        Ok(())
+    }
+
+    fn free_streams(&mut self, ep_addr: u8) {
+        if let Some(handle) = self.handle.as_mut() {
+            free_host_streams(handle, ep_addr).unwrap_or_else(|e| {
+                warn!("Failed to free streams of endpoint {:#x}: {:?}", ep_addr, e)
+            });
+        }
+    }
+
     fn get_usb_device(&self) -> &UsbDevice {
         &self.usb_device
     }
@@ -0,0 +1,1091 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::{
+    cmp::min,
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+
+use crate::legacy::{Chardev, ChardevNotifyDevice, ChardevStatus, InputReceiver};
+use crate::usb::{
+    config::{
+        USB_DEVICE_IN_REQUEST, USB_DEVICE_OUT_REQUEST, USB_DIRECTION_DEVICE_TO_HOST, USB_DT_DEVICE,
+        USB_ENDPOINT_ATTR_BULK, USB_ENDPOINT_ATTR_INT, USB_ENDPOINT_ATTR_INVALID,
+        USB_ENDPOINT_ATTR_ISOC, USB_INTERFACE_OUT_REQUEST, USB_REQUEST_GET_DESCRIPTOR,
+        USB_REQUEST_SET_ADDRESS, USB_REQUEST_SET_CONFIGURATION, USB_REQUEST_SET_INTERFACE,
+        USB_SPEED_FULL, USB_SPEED_HIGH, USB_SPEED_LOW, USB_TOKEN_IN,
+    },
+    notify_controller,
+    xhci::xhci_controller::XhciDevice,
+    UsbDevice, UsbDeviceOps, UsbDeviceRequest, UsbEndpoint, UsbPacket, UsbPacketStatus,
+};
+use machine_manager::{config::UsbRedirConfig, event_loop::EventLoop};
+use protocol::*;
+use util::{byte_code::ByteCode, loop_context::EventNotifierHelper};
+
+pub mod protocol;
+
+const USB_REDIR_BUFFER_LEN: usize = 64 * 1024;
+/// Max size of data read from chardev at a time.
+const USB_REDIR_READ_LEN: usize = 64 * 1024;
+/// Max number of packets buffered for interrupt and iso IN endpoint.
+const USB_REDIR_MAX_BUFFERED: usize = 64;
+/// Number of urbs used by peer for iso stream.
+const USB_REDIR_ISO_URBS: u8 = 3;
+
+/// State of the endpoint of the remote device.
+struct RedirEndpoint {
+    ep_type: u8,
+    interval: u8,
+    interface: u8,
+    max_packet_size: u16,
+    /// The interrupt receiving or the iso stream is started.
+    started: bool,
+    /// Status and data received from peer, used by interrupt and iso IN endpoint.
+    bufs: VecDeque<(u8, Vec<u8>)>,
+}
+
+impl Default for RedirEndpoint {
+    fn default() -> Self {
+        Self {
+            ep_type: USB_ENDPOINT_ATTR_INVALID,
+            interval: 0,
+            interface: 0,
+            max_packet_size: 0,
+            started: false,
+            bufs: VecDeque::new(),
+        }
+    }
+}
+
+/// The packet waiting for the response of peer.
+struct RedirRequest {
+    packet: Arc<Mutex<UsbPacket>>,
+    /// Setup data of control transfer.
+    device_req: Option<UsbDeviceRequest>,
+}
+
+/// The work which is done after releasing the device lock.
+enum RedirEvent {
+    /// The asynchronous packet is completed.
+    Complete(Arc<Mutex<UsbPacket>>),
+    /// Data of interrupt IN endpoint is received.
+    Wakeup(UsbEndpoint),
+    /// The remote device is connected or disconnected.
+    PortUpdate(bool),
+}
+
+/// USB device redirected from the peer by usbredir protocol over chardev.
+pub struct UsbRedir {
+    usb_device: UsbDevice,
+    cntlr: Option<Weak<Mutex<XhciDevice>>>,
+    chardev: Arc<Mutex<Chardev>>,
+    parser: UsbRedirParser,
+    /// The remote device is connected.
+    device_connected: bool,
+    /// The remote device is super speed, but presented as high speed.
+    downgraded: bool,
+    endpoints: Vec<RedirEndpoint>,
+    /// Asynchronous packets indexed by the packet id.
+    requests: HashMap<u32, RedirRequest>,
+    next_id: u32,
+    /// The endpoint which has data to be fetched by guest.
+    wakeup_ep: UsbEndpoint,
+}
+
+impl UsbRedir {
+    pub fn new(config: UsbRedirConfig) -> Self {
+        let mut usb_device = UsbDevice::new(config.id.unwrap(), USB_REDIR_BUFFER_LEN);
+        // The speed is updated when remote device is connected.
+        usb_device.speed = USB_SPEED_HIGH;
+        let mut endpoints = Vec::with_capacity(USB_REDIR_MAX_ENDPOINTS);
+        endpoints.resize_with(USB_REDIR_MAX_ENDPOINTS, RedirEndpoint::default);
+        Self {
+            usb_device,
+            cntlr: None,
+            chardev: Arc::new(Mutex::new(Chardev::new(config.chardev))),
+            parser: UsbRedirParser::default(),
+            device_connected: false,
+            downgraded: false,
+            endpoints,
+            requests: HashMap::new(),
+            next_id: 0,
+            wakeup_ep: UsbEndpoint::default(),
+        }
+    }
+
+    fn send_packet(&mut self, ptype: u32, id: u32, header: &[u8], data: &[u8]) {
+        let packet = self.parser.build_packet(ptype, id, header, data);
+        let output = self.chardev.lock().unwrap().output.clone();
+        if let Some(output) = output {
+            let mut locked_output = output.lock().unwrap();
+            if let Err(e) = locked_output
+                .write_all(&packet)
+                .and_then(|_| locked_output.flush())
+            {
+                error!(
+                    "usb-redir {}: failed to send packet type {}: {:?}",
+                    self.usb_device.id, ptype, e
+                );
+            }
+        } else {
+            debug!(
+                "usb-redir {}: drop packet type {}, chardev is not connected",
+                self.usb_device.id, ptype
+            );
+        }
+    }
+
+    /// Send the packet to peer and wait for the response asynchronously.
+    fn submit_request(
+        &mut self,
+        packet: &Arc<Mutex<UsbPacket>>,
+        locked_packet: &mut UsbPacket,
+        ptype: u32,
+        header: &[u8],
+        data: &[u8],
+        device_req: Option<UsbDeviceRequest>,
+    ) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.requests.insert(
+            id,
+            RedirRequest {
+                packet: packet.clone(),
+                device_req,
+            },
+        );
+        locked_packet.is_async = true;
+        self.send_packet(ptype, id, header, data);
+    }
+
+    /// Complete the asynchronous packet with the response of peer.
+    fn complete_request(
+        &mut self,
+        id: u32,
+        status: u8,
+        length: usize,
+        data: &mut [u8],
+        events: &mut Vec<RedirEvent>,
+    ) {
+        let request = match self.requests.remove(&id) {
+            Some(request) => request,
+            None => {
+                warn!(
+                    "usb-redir {}: no request found for packet id {}",
+                    self.usb_device.id, id
+                );
+                return;
+            }
+        };
+        let mut locked_packet = request.packet.lock().unwrap();
+        locked_packet.status = redir_status_to_packet_status(status);
+        if locked_packet.pid as u8 == USB_TOKEN_IN {
+            if let Some(device_req) = request.device_req.as_ref() {
+                self.fixup_descriptor(device_req, data);
+            }
+            locked_packet.actual_length = 0;
+            locked_packet.transfer_packet(data, data.len());
+        } else {
+            locked_packet.actual_length = min(locked_packet.actual_length, length as u32);
+        }
+        drop(locked_packet);
+        events.push(RedirEvent::Complete(request.packet));
+    }
+
+    /// Super speed device is presented as high speed device, which uses 64 bytes
+    /// max packet size of control endpoint.
+    fn fixup_descriptor(&self, device_req: &UsbDeviceRequest, data: &mut [u8]) {
+        if !self.downgraded
+            || device_req.request_type != USB_DEVICE_IN_REQUEST
+            || device_req.request != USB_REQUEST_GET_DESCRIPTOR
+            || (device_req.value >> 8) as u8 != USB_DT_DEVICE
+            || data.len() < 8
+        {
+            return;
+        }
+        // bcdUSB 2.10 and bMaxPacketSize0 64.
+        data[2] = 0x10;
+        data[3] = 0x02;
+        data[7] = 64;
+    }
+
+    /// Cancel all the asynchronous packets, and drop the buffered data.
+    fn cancel_requests(&mut self, status: UsbPacketStatus, events: &mut Vec<RedirEvent>) {
+        for (_, request) in self.requests.drain() {
+            request.packet.lock().unwrap().status = status;
+            events.push(RedirEvent::Complete(request.packet));
+        }
+        for ep in self.endpoints.iter_mut() {
+            ep.bufs.clear();
+        }
+    }
+
+    fn chardev_opened(&mut self) {
+        info!("usb-redir {}: chardev is connected", self.usb_device.id);
+        self.parser.reset();
+        let hello = self.parser.build_hello();
+        let output = self.chardev.lock().unwrap().output.clone();
+        if let Some(output) = output {
+            if let Err(e) = output.lock().unwrap().write_all(&hello) {
+                error!(
+                    "usb-redir {}: failed to send hello: {:?}",
+                    self.usb_device.id, e
+                );
+            }
+        }
+    }
+
+    fn chardev_closed(&mut self) -> Vec<RedirEvent> {
+        info!("usb-redir {}: chardev is disconnected", self.usb_device.id);
+        let mut events = Vec::new();
+        self.device_disconnect(&mut events);
+        self.parser.reset();
+        events
+    }
+
+    /// Handle the bytes received from peer.
+    fn receive(&mut self, data: &[u8]) -> Vec<RedirEvent> {
+        let mut events = Vec::new();
+        self.parser.feed(data);
+        loop {
+            match self.parser.next_packet() {
+                Ok(Some(packet)) => self.handle_redir_packet(packet, &mut events),
+                Ok(None) => break,
+                Err(e) => {
+                    error!("usb-redir {}: protocol error: {:?}", self.usb_device.id, e);
+                    self.device_disconnect(&mut events);
+                    self.parser.reset();
+                    break;
+                }
+            }
+        }
+        events
+    }
+
+    fn handle_redir_packet(&mut self, mut packet: UsbRedirPacket, events: &mut Vec<RedirEvent>) {
+        match packet.ptype {
+            USB_REDIR_HELLO => {
+                let (version, caps) = parse_hello(&packet);
+                info!(
+                    "usb-redir {}: peer version {} caps {:#x}",
+                    self.usb_device.id, version, caps
+                );
+                self.parser.set_peer_caps(caps);
+            }
+            USB_REDIR_DEVICE_CONNECT => {
+                self.device_connect(packet.header::<UsbRedirDeviceConnect>(), events);
+            }
+            USB_REDIR_DEVICE_DISCONNECT => {
+                self.device_disconnect(events);
+                if self.parser.have_cap(USB_REDIR_CAP_DEVICE_DISCONNECT_ACK) {
+                    self.send_packet(USB_REDIR_DEVICE_DISCONNECT_ACK, 0, &[], &[]);
+                }
+            }
+            USB_REDIR_INTERFACE_INFO => {
+                let count = packet.header::<UsbRedirInterfaceInfo>().interface_count;
+                debug!("usb-redir {}: {} interfaces", self.usb_device.id, count);
+            }
+            USB_REDIR_EP_INFO => self.ep_info(packet.header::<UsbRedirEpInfo>()),
+            USB_REDIR_CONFIGURATION_STATUS => {
+                let status = packet.header::<UsbRedirConfigurationStatus>();
+                self.complete_request(packet.id, status.status, 0, &mut [], events);
+            }
+            USB_REDIR_ALT_SETTING_STATUS => {
+                let status = packet.header::<UsbRedirAltSettingStatus>();
+                self.complete_request(packet.id, status.status, 0, &mut [], events);
+            }
+            USB_REDIR_ISO_STREAM_STATUS | USB_REDIR_INTERRUPT_RECEIVING_STATUS => {
+                let status = packet.header::<UsbRedirStreamStatus>();
+                if status.status != USB_REDIR_SUCCESS {
+                    warn!(
+                        "usb-redir {}: stream of endpoint {:#x} stopped, status {}",
+                        self.usb_device.id, status.endpoint, status.status
+                    );
+                    self.endpoints[ep_addr_to_index(status.endpoint)].started = false;
+                }
+            }
+            USB_REDIR_CONTROL_PACKET => {
+                let header = packet.header::<UsbRedirControlPacket>();
+                self.complete_request(
+                    packet.id,
+                    header.status,
+                    header.length as usize,
+                    &mut packet.data,
+                    events,
+                );
+            }
+            USB_REDIR_BULK_PACKET => {
+                let header = packet.header::<UsbRedirBulkPacket>();
+                let length = header.length as usize | (header.length_high as usize) << 16;
+                self.complete_request(packet.id, header.status, length, &mut packet.data, events);
+            }
+            USB_REDIR_INTERRUPT_PACKET | USB_REDIR_ISO_PACKET => {
+                let header = packet.header::<UsbRedirDataPacket>();
+                if header.endpoint & USB_DIRECTION_DEVICE_TO_HOST == 0 {
+                    // Response of interrupt OUT transfer.
+                    if packet.ptype == USB_REDIR_INTERRUPT_PACKET {
+                        self.complete_request(
+                            packet.id,
+                            header.status,
+                            header.length as usize,
+                            &mut [],
+                            events,
+                        );
+                    }
+                    return;
+                }
+                self.buffer_in_data(header.endpoint, header.status, packet.data, events);
+            }
+            USB_REDIR_FILTER_FILTER | USB_REDIR_FILTER_REJECT => {}
+            _ => {
+                debug!(
+                    "usb-redir {}: unexpected packet type {}",
+                    self.usb_device.id, packet.ptype
+                );
+            }
+        }
+    }
+
+    fn device_connect(&mut self, info: UsbRedirDeviceConnect, events: &mut Vec<RedirEvent>) {
+        if self.device_connected {
+            warn!(
+                "usb-redir {}: device is already connected",
+                self.usb_device.id
+            );
+            return;
+        }
+        self.downgraded = false;
+        self.usb_device.speed = match info.speed {
+            USB_REDIR_SPEED_LOW => USB_SPEED_LOW,
+            USB_REDIR_SPEED_HIGH => USB_SPEED_HIGH,
+            USB_REDIR_SPEED_SUPER => {
+                self.downgraded = true;
+                USB_SPEED_HIGH
+            }
+            _ => USB_SPEED_FULL,
+        };
+        info!(
+            "usb-redir {}: device {:04x}:{:04x} connected, speed {}",
+            self.usb_device.id,
+            { info.vendor_id },
+            { info.product_id },
+            info.speed
+        );
+        self.device_connected = true;
+        events.push(RedirEvent::PortUpdate(false));
+    }
+
+    fn device_disconnect(&mut self, events: &mut Vec<RedirEvent>) {
+        if !self.device_connected {
+            return;
+        }
+        info!("usb-redir {}: device disconnected", self.usb_device.id);
+        self.device_connected = false;
+        self.cancel_requests(UsbPacketStatus::NoDev, events);
+        for ep in self.endpoints.iter_mut() {
+            *ep = RedirEndpoint::default();
+        }
+        self.usb_device.reset_usb_endpoint();
+        events.push(RedirEvent::PortUpdate(true));
+    }
+
+    fn ep_info(&mut self, info: UsbRedirEpInfo) {
+        self.usb_device.reset_usb_endpoint();
+        for (i, ep) in self.endpoints.iter_mut().enumerate() {
+            if ep.ep_type != info.ep_type[i] {
+                ep.started = false;
+                ep.bufs.clear();
+            }
+            ep.ep_type = info.ep_type[i];
+            ep.interval = info.interval[i];
+            ep.interface = info.interface[i];
+            ep.max_packet_size = info.max_packet_size[i];
+
+            let ep_addr = ep_index_to_addr(i);
+            let ep_number = ep_addr & 0x0f;
+            if ep_number == 0 || ep.ep_type == USB_ENDPOINT_ATTR_INVALID {
+                continue;
+            }
+            let usb_ep = self
+                .usb_device
+                .get_mut_endpoint(ep_addr & USB_DIRECTION_DEVICE_TO_HOST != 0, ep_number);
+            usb_ep.ep_type = ep.ep_type;
+            usb_ep.ifnum = ep.interface;
+            usb_ep.halted = false;
+            usb_ep.set_max_packet_size(ep.max_packet_size);
+        }
+    }
+
+    /// Buffer the data received from interrupt or iso IN endpoint.
+    fn buffer_in_data(
+        &mut self,
+        ep_addr: u8,
+        status: u8,
+        data: Vec<u8>,
+        events: &mut Vec<RedirEvent>,
+    ) {
+        let ep = &mut self.endpoints[ep_addr_to_index(ep_addr)];
+        if !ep.started {
+            return;
+        }
+        if ep.bufs.len() >= USB_REDIR_MAX_BUFFERED {
+            debug!(
+                "usb-redir {}: endpoint {:#x} buffer is full",
+                self.usb_device.id, ep_addr
+            );
+            ep.bufs.pop_front();
+        }
+        ep.bufs.push_back((status, data));
+        if ep.ep_type == USB_ENDPOINT_ATTR_INT && ep.bufs.len() == 1 {
+            let usb_ep = self.usb_device.get_endpoint(true, ep_addr & 0x0f);
+            events.push(RedirEvent::Wakeup(usb_ep.clone()));
+        }
+    }
+
+    /// Fetch the data buffered for interrupt or iso IN endpoint.
+    fn fetch_in_data(&mut self, ep_addr: u8, locked_packet: &mut UsbPacket) -> bool {
+        let index = ep_addr_to_index(ep_addr);
+        if !self.endpoints[index].started {
+            if self.endpoints[index].ep_type == USB_ENDPOINT_ATTR_INT {
+                let header = UsbRedirEndpoint { endpoint: ep_addr };
+                self.send_packet(
+                    USB_REDIR_START_INTERRUPT_RECEIVING,
+                    0,
+                    header.as_bytes(),
+                    &[],
+                );
+            } else {
+                self.start_iso_stream(ep_addr);
+            }
+            self.endpoints[index].started = true;
+        }
+        match self.endpoints[index].bufs.pop_front() {
+            Some((status, mut data)) => {
+                locked_packet.status = redir_status_to_packet_status(status);
+                let len = data.len();
+                locked_packet.transfer_packet(&mut data, len);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn start_iso_stream(&mut self, ep_addr: u8) {
+        let ep = &self.endpoints[ep_addr_to_index(ep_addr)];
+        // The interval of iso endpoint is 2^(interval - 1) (micro)frames.
+        let frames_per_sec: u32 = if self.usb_device.speed == USB_SPEED_HIGH {
+            8000
+        } else {
+            1000
+        };
+        let interval = min(ep.interval.saturating_sub(1), 15);
+        // Each urb contains 10ms packets.
+        let pkts_per_urb = ((frames_per_sec >> interval) / 100).clamp(1, 32);
+        let header = UsbRedirStartIsoStream {
+            endpoint: ep_addr,
+            pkts_per_urb: pkts_per_urb as u8,
+            no_urbs: USB_REDIR_ISO_URBS,
+        };
+        self.send_packet(USB_REDIR_START_ISO_STREAM, 0, header.as_bytes(), &[]);
+    }
+
+    fn handle_bulk(
+        &mut self,
+        packet: &Arc<Mutex<UsbPacket>>,
+        locked_packet: &mut UsbPacket,
+        ep_addr: u8,
+    ) {
+        let size = locked_packet.get_iovecs_size() as usize;
+        if size > u16::MAX as usize && !self.parser.have_cap(USB_REDIR_CAP_32BITS_BULK_LENGTH) {
+            warn!(
+                "usb-redir {}: bulk transfer {} is too large",
+                self.usb_device.id, size
+            );
+            locked_packet.status = UsbPacketStatus::Stall;
+            return;
+        }
+        let header = UsbRedirBulkPacket {
+            endpoint: ep_addr,
+            status: USB_REDIR_SUCCESS,
+            length: size as u16,
+            stream_id: 0,
+            length_high: (size >> 16) as u16,
+        };
+        let mut data = Vec::new();
+        if ep_addr & USB_DIRECTION_DEVICE_TO_HOST == 0 {
+            data = vec![0_u8; size];
+            locked_packet.transfer_packet(&mut data, size);
+        }
+        self.submit_request(
+            packet,
+            locked_packet,
+            USB_REDIR_BULK_PACKET,
+            header.as_bytes(),
+            &data,
+            None,
+        );
+    }
+
+    fn handle_out_data(
+        &mut self,
+        packet: &Arc<Mutex<UsbPacket>>,
+        locked_packet: &mut UsbPacket,
+        ep_addr: u8,
+        ep_type: u8,
+    ) {
+        let size = min(locked_packet.get_iovecs_size() as usize, u16::MAX as usize);
+        let mut data = vec![0_u8; size];
+        locked_packet.transfer_packet(&mut data, size);
+        let header = UsbRedirDataPacket {
+            endpoint: ep_addr,
+            status: USB_REDIR_SUCCESS,
+            length: size as u16,
+        };
+        if ep_type == USB_ENDPOINT_ATTR_INT {
+            self.submit_request(
+                packet,
+                locked_packet,
+                USB_REDIR_INTERRUPT_PACKET,
+                header.as_bytes(),
+                &data,
+                None,
+            );
+            return;
+        }
+        // Iso OUT transfer is completed immediately.
+        let index = ep_addr_to_index(ep_addr);
+        if !self.endpoints[index].started {
+            self.start_iso_stream(ep_addr);
+            self.endpoints[index].started = true;
+        }
+        self.send_packet(USB_REDIR_ISO_PACKET, 0, header.as_bytes(), &data);
+    }
+}
+
+impl UsbDeviceOps for UsbRedir {
+    fn realize(self) -> Result<Arc<Mutex<dyn UsbDeviceOps>>> {
+        let chardev = self.chardev.clone();
+        chardev
+            .lock()
+            .unwrap()
+            .realize()
+            .with_context(|| "Failed to realize chardev")?;
+
+        let usbredir = Arc::new(Mutex::new(self));
+        let receiver = Arc::new(Mutex::new(UsbRedirReceiver {
+            dev: Arc::downgrade(&usbredir),
+        }));
+        let mut locked_chardev = chardev.lock().unwrap();
+        locked_chardev.set_input_callback(&receiver);
+        locked_chardev.set_device(receiver);
+        // Tcp client chardev may be connected in realize.
+        let connected = locked_chardev.stream_fd.is_some();
+        drop(locked_chardev);
+        if connected {
+            usbredir.lock().unwrap().chardev_opened();
+        }
+        EventLoop::update_event(EventNotifierHelper::internal_notifiers(chardev), None)?;
+
+        Ok(usbredir)
+    }
+
+    fn unrealize(&mut self) -> Result<()> {
+        self.chardev.lock().unwrap().deactivated = true;
+        self.requests.clear();
+        info!("usb-redir {} is unrealized", self.usb_device.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        info!("usb-redir {} reset", self.usb_device.id);
+        if !self.device_connected {
+            return;
+        }
+        // The pending packets are dropped by the controller on reset.
+        self.requests.clear();
+        for ep in self.endpoints.iter_mut() {
+            ep.bufs.clear();
+        }
+        self.send_packet(USB_REDIR_RESET, 0, &[], &[]);
+    }
+
+    fn connected(&self) -> bool {
+        self.device_connected
+    }
+
+    fn set_controller(&mut self, cntlr: Weak<Mutex<XhciDevice>>) {
+        self.cntlr = Some(cntlr);
+    }
+
+    fn get_controller(&self) -> Option<Weak<Mutex<XhciDevice>>> {
+        self.cntlr.clone()
+    }
+
+    fn get_wakeup_endpoint(&self) -> &UsbEndpoint {
+        &self.wakeup_ep
+    }
+
+    fn handle_control(&mut self, packet: &Arc<Mutex<UsbPacket>>, device_req: &UsbDeviceRequest) {
+        let mut locked_packet = packet.lock().unwrap();
+        if !self.device_connected {
+            locked_packet.status = UsbPacketStatus::NoDev;
+            return;
+        }
+        match (device_req.request_type, device_req.request) {
+            (USB_DEVICE_OUT_REQUEST, USB_REQUEST_SET_ADDRESS) => {
+                self.usb_device.addr = device_req.value as u8;
+            }
+            (USB_DEVICE_OUT_REQUEST, USB_REQUEST_SET_CONFIGURATION) => {
+                let header = UsbRedirSetConfiguration {
+                    configuration: device_req.value as u8,
+                };
+                self.submit_request(
+                    packet,
+                    &mut locked_packet,
+                    USB_REDIR_SET_CONFIGURATION,
+                    header.as_bytes(),
+                    &[],
+                    None,
+                );
+            }
+            (USB_INTERFACE_OUT_REQUEST, USB_REQUEST_SET_INTERFACE) => {
+                let header = UsbRedirSetAltSetting {
+                    interface: device_req.index as u8,
+                    alt: device_req.value as u8,
+                };
+                self.submit_request(
+                    packet,
+                    &mut locked_packet,
+                    USB_REDIR_SET_ALT_SETTING,
+                    header.as_bytes(),
+                    &[],
+                    None,
+                );
+            }
+            _ => {
+                let length = device_req.length as usize;
+                let in_direction = device_req.request_type & USB_DIRECTION_DEVICE_TO_HOST != 0;
+                let header = UsbRedirControlPacket {
+                    endpoint: device_req.request_type & USB_DIRECTION_DEVICE_TO_HOST,
+                    request: device_req.request,
+                    requesttype: device_req.request_type,
+                    status: USB_REDIR_SUCCESS,
+                    value: device_req.value,
+                    index: device_req.index,
+                    length: device_req.length,
+                };
+                let data = if in_direction {
+                    Vec::new()
+                } else {
+                    self.usb_device.data_buf[..length].to_vec()
+                };
+                self.submit_request(
+                    packet,
+                    &mut locked_packet,
+                    USB_REDIR_CONTROL_PACKET,
+                    header.as_bytes(),
+                    &data,
+                    Some(*device_req),
+                );
+            }
+        }
+    }
+
+    fn handle_data(&mut self, packet: &Arc<Mutex<UsbPacket>>) {
+        let mut locked_packet = packet.lock().unwrap();
+        if !self.device_connected {
+            locked_packet.status = UsbPacketStatus::NoDev;
+            return;
+        }
+        let in_direction = locked_packet.pid as u8 == USB_TOKEN_IN;
+        let mut ep_addr = locked_packet.ep_number & 0x0f;
+        if in_direction {
+            ep_addr |= USB_DIRECTION_DEVICE_TO_HOST;
+        }
+        let ep_type = self.endpoints[ep_addr_to_index(ep_addr)].ep_type;
+        match ep_type {
+            USB_ENDPOINT_ATTR_BULK => self.handle_bulk(packet, &mut locked_packet, ep_addr),
+            USB_ENDPOINT_ATTR_INT if in_direction => {
+                if !self.fetch_in_data(ep_addr, &mut locked_packet) {
+                    locked_packet.status = UsbPacketStatus::Nak;
+                }
+            }
+            USB_ENDPOINT_ATTR_ISOC if in_direction => {
+                // Iso transfer can not be retried, return no data instead.
+                if !self.fetch_in_data(ep_addr, &mut locked_packet) {
+                    locked_packet.actual_length = 0;
+                }
+            }
+            USB_ENDPOINT_ATTR_INT | USB_ENDPOINT_ATTR_ISOC => {
+                self.handle_out_data(packet, &mut locked_packet, ep_addr, ep_type);
+            }
+            _ => {
+                warn!(
+                    "usb-redir {}: invalid endpoint {:#x}",
+                    self.usb_device.id, ep_addr
+                );
+                locked_packet.status = UsbPacketStatus::Stall;
+            }
+        }
+    }
+
+    fn get_usb_device(&self) -> &UsbDevice {
+        &self.usb_device
+    }
+
+    fn get_mut_usb_device(&mut self) -> &mut UsbDevice {
+        &mut self.usb_device
+    }
+}
+
+/// Receive the data and the connection status from chardev. The events of the device
+/// are handled without holding the device lock, as the controller may lock it.
+struct UsbRedirReceiver {
+    dev: Weak<Mutex<UsbRedir>>,
+}
+
+impl InputReceiver for UsbRedirReceiver {
+    fn input_handle(&mut self, buffer: &[u8]) {
+        if let Some(dev) = self.dev.upgrade() {
+            let events = dev.lock().unwrap().receive(buffer);
+            handle_redir_events(&dev, events);
+        }
+    }
+
+    fn get_remain_space_size(&mut self) -> usize {
+        USB_REDIR_READ_LEN
+    }
+}
+
+impl ChardevNotifyDevice for UsbRedirReceiver {
+    fn chardev_notify(&mut self, status: ChardevStatus) {
+        // The chardev is locked during notifying, so handle it later.
+        let dev = self.dev.clone();
+        let func: Box<dyn Fn()> = match status {
+            ChardevStatus::Open => Box::new(move || {
+                if let Some(dev) = dev.upgrade() {
+                    dev.lock().unwrap().chardev_opened();
+                }
+            }),
+            ChardevStatus::Close => Box::new(move || {
+                if let Some(dev) = dev.upgrade() {
+                    let events = dev.lock().unwrap().chardev_closed();
+                    handle_redir_events(&dev, events);
+                }
+            }),
+        };
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            ctx.timer_add(func, Duration::ZERO);
+        }
+    }
+}
+
+fn handle_redir_events(dev: &Arc<Mutex<UsbRedir>>, events: Vec<RedirEvent>) {
+    for event in events {
+        match event {
+            RedirEvent::Complete(packet) => {
+                let locked_packet = packet.lock().unwrap();
+                if !locked_packet.is_async {
+                    continue;
+                }
+                let xfer_ops = locked_packet.xfer_ops.clone();
+                drop(locked_packet);
+                if let Some(ops) = xfer_ops.and_then(|ops| ops.upgrade()) {
+                    ops.lock().unwrap().submit_transfer();
+                }
+            }
+            RedirEvent::Wakeup(ep) => {
+                dev.lock().unwrap().wakeup_ep = ep;
+                let usb_dev = dev.clone() as Arc<Mutex<dyn UsbDeviceOps>>;
+                if let Err(e) = notify_controller(&usb_dev) {
+                    debug!("Failed to notify controller: {:?}", e);
+                }
+            }
+            RedirEvent::PortUpdate(detach) => {
+                if let Err(e) = redir_port_update(dev, detach) {
+                    warn!("Failed to update port of usb-redir device: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Notify the guest that the remote device is connected or disconnected.
+fn redir_port_update(dev: &Arc<Mutex<UsbRedir>>, detach: bool) -> Result<()> {
+    let locked_dev = dev.lock().unwrap();
+    let xhci = locked_dev
+        .cntlr
+        .as_ref()
+        .and_then(|cntlr| cntlr.upgrade())
+        .with_context(|| "USB controller not found")?;
+    let port = locked_dev
+        .usb_device
+        .port
+        .as_ref()
+        .and_then(|port| port.upgrade())
+        .with_context(|| "No usb port found")?;
+    drop(locked_dev);
+
+    let mut locked_xhci = xhci.lock().unwrap();
+    if detach {
+        let slot_id = port.lock().unwrap().slot_id;
+        locked_xhci.detach_slot(slot_id)?;
+    }
+    locked_xhci.port_update(&port, detach)
+}
+
+fn redir_status_to_packet_status(status: u8) -> UsbPacketStatus {
+    match status {
+        USB_REDIR_SUCCESS => UsbPacketStatus::Success,
+        USB_REDIR_STALL => UsbPacketStatus::Stall,
+        USB_REDIR_BABBLE => UsbPacketStatus::Babble,
+        _ => UsbPacketStatus::IoError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::usb::config::{USB_ENDPOINT_ATTR_CONTROL, USB_TOKEN_OUT};
+    use machine_manager::config::{ChardevConfig, ChardevType};
+    use util::aio::Iovec;
+
+    /// Stand-in of the usbredir peer, which emulates a device with a bulk IN,
+    /// a bulk OUT and an interrupt IN endpoint.
+    struct TestPeer {
+        stream: UnixStream,
+        parser: UsbRedirParser,
+    }
+
+    impl TestPeer {
+        fn build(&self, ptype: u32, id: u32, header: &[u8], data: &[u8]) -> Vec<u8> {
+            self.parser.build_packet(ptype, id, header, data)
+        }
+
+        fn recv(&mut self) -> UsbRedirPacket {
+            loop {
+                if let Some(packet) = self.parser.next_packet().unwrap() {
+                    return packet;
+                }
+                let mut buf = [0_u8; 1024];
+                let len = self.stream.read(&mut buf).unwrap();
+                assert_ne!(len, 0);
+                self.parser.feed(&buf[..len]);
+            }
+        }
+
+        fn connect_device(&mut self, redir: &mut UsbRedir) {
+            redir.chardev_opened();
+            let hello = self.recv();
+            assert_eq!(hello.ptype, USB_REDIR_HELLO);
+            let (_, caps) = parse_hello(&hello);
+            self.parser.set_peer_caps(caps);
+
+            let mut ep_info = UsbRedirEpInfo::default();
+            ep_info.ep_type = [USB_ENDPOINT_ATTR_INVALID; USB_REDIR_MAX_ENDPOINTS];
+            ep_info.ep_type[0] = USB_ENDPOINT_ATTR_CONTROL;
+            ep_info.ep_type[16] = USB_ENDPOINT_ATTR_CONTROL;
+            ep_info.ep_type[ep_addr_to_index(0x81)] = USB_ENDPOINT_ATTR_BULK;
+            ep_info.ep_type[ep_addr_to_index(0x02)] = USB_ENDPOINT_ATTR_BULK;
+            ep_info.ep_type[ep_addr_to_index(0x83)] = USB_ENDPOINT_ATTR_INT;
+            ep_info.max_packet_size[ep_addr_to_index(0x81)] = 512;
+            let connect = UsbRedirDeviceConnect {
+                speed: USB_REDIR_SPEED_SUPER,
+                vendor_id: 0x1234,
+                product_id: 0x5678,
+                ..Default::default()
+            };
+            let mut stream = self.parser.build_hello();
+            stream.extend(self.build(USB_REDIR_EP_INFO, 0, ep_info.as_bytes(), &[]));
+            stream.extend(self.build(USB_REDIR_DEVICE_CONNECT, 0, connect.as_bytes(), &[]));
+            let events = redir.receive(&stream);
+            assert!(matches!(events[..], [RedirEvent::PortUpdate(false)]));
+        }
+    }
+
+    fn create_redir() -> (UsbRedir, TestPeer) {
+        let config = UsbRedirConfig {
+            id: Some("redir0".to_string()),
+            chardev: ChardevConfig {
+                id: "chardev0".to_string(),
+                backend: ChardevType::Socket {
+                    path: "/tmp/usbredir0.sock".to_string(),
+                    server: true,
+                    nowait: true,
+                },
+                mux: false,
+                logfile: None,
+            },
+        };
+        let (stream, peer_stream) = UnixStream::pair().unwrap();
+        peer_stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let redir = UsbRedir::new(config);
+        redir.chardev.lock().unwrap().output = Some(Arc::new(Mutex::new(stream)));
+        let peer = TestPeer {
+            stream: peer_stream,
+            parser: UsbRedirParser::default(),
+        };
+        (redir, peer)
+    }
+
+    fn create_packet(pid: u8, ep_number: u8, buf: &[u8]) -> Arc<Mutex<UsbPacket>> {
+        let iovecs = vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)];
+        Arc::new(Mutex::new(UsbPacket::new(
+            pid as u32, ep_number, iovecs, None,
+        )))
+    }
+
+    #[test]
+    fn test_usbredir_device_connect() {
+        let (mut redir, mut peer) = create_redir();
+        assert!(!redir.connected());
+        peer.connect_device(&mut redir);
+        assert!(redir.connected());
+        // Super speed device is presented as high speed.
+        assert_eq!(redir.speed(), USB_SPEED_HIGH);
+        assert!(redir.downgraded);
+        let ep = redir.usb_device.get_endpoint(true, 1);
+        assert_eq!(ep.ep_type, USB_ENDPOINT_ATTR_BULK);
+        assert_eq!(ep.max_packet_size, 512);
+        assert_eq!(
+            redir.usb_device.get_endpoint(true, 3).ep_type,
+            USB_ENDPOINT_ATTR_INT
+        );
+
+        // Pending packets are completed when device is disconnected.
+        let buf = [0_u8; 16];
+        let packet = create_packet(USB_TOKEN_IN, 1, &buf);
+        redir.handle_packet(&packet);
+        assert!(packet.lock().unwrap().is_async);
+        assert_eq!(peer.recv().ptype, USB_REDIR_BULK_PACKET);
+        let events = redir.receive(&peer.build(USB_REDIR_DEVICE_DISCONNECT, 0, &[], &[]));
+        assert!(!redir.connected());
+        assert!(matches!(
+            events[..],
+            [RedirEvent::Complete(_), RedirEvent::PortUpdate(true)]
+        ));
+        assert_eq!(packet.lock().unwrap().status, UsbPacketStatus::NoDev);
+        assert_eq!(peer.recv().ptype, USB_REDIR_DEVICE_DISCONNECT_ACK);
+
+        let packet = create_packet(USB_TOKEN_IN, 1, &buf);
+        redir.handle_packet(&packet);
+        assert_eq!(packet.lock().unwrap().status, UsbPacketStatus::NoDev);
+    }
+
+    #[test]
+    fn test_usbredir_transfer() {
+        let (mut redir, mut peer) = create_redir();
+        peer.connect_device(&mut redir);
+
+        // Control transfer: get device descriptor.
+        let buf = [0_u8; 18];
+        let packet = create_packet(USB_TOKEN_IN, 0, &buf);
+        packet.lock().unwrap().parameter = 0x0012_0000_0100_0680;
+        redir.handle_packet(&packet);
+        assert!(packet.lock().unwrap().is_async);
+        let request = peer.recv();
+        assert_eq!(request.ptype, USB_REDIR_CONTROL_PACKET);
+        let header = request.header::<UsbRedirControlPacket>();
+        assert_eq!(header.endpoint, 0x80);
+        assert_eq!(header.request, USB_REQUEST_GET_DESCRIPTOR);
+        assert_eq!({ header.value }, 0x0100);
+        assert_eq!({ header.length }, 18);
+        let mut desc = [0_u8; 18];
+        desc[..8].copy_from_slice(&[18, 1, 0x20, 0x03, 0, 0, 0, 9]);
+        let response = UsbRedirControlPacket {
+            length: 18,
+            ..header
+        };
+        let events = redir.receive(&peer.build(
+            USB_REDIR_CONTROL_PACKET,
+            request.id,
+            response.as_bytes(),
+            &desc,
+        ));
+        assert_eq!(events.len(), 1);
+        let locked_packet = packet.lock().unwrap();
+        assert_eq!(locked_packet.status, UsbPacketStatus::Success);
+        assert_eq!(locked_packet.actual_length, 18);
+        // Descriptor of super speed device is fixed up.
+        assert_eq!(buf[..8], [18, 1, 0x10, 0x02, 0, 0, 0, 64]);
+        drop(locked_packet);
+
+        // Bulk OUT transfer.
+        let buf = [1_u8, 2, 3, 4];
+        let packet = create_packet(USB_TOKEN_OUT, 2, &buf);
+        redir.handle_packet(&packet);
+        let request = peer.recv();
+        assert_eq!(request.ptype, USB_REDIR_BULK_PACKET);
+        assert_eq!(request.header::<UsbRedirBulkPacket>().endpoint, 0x02);
+        assert_eq!(request.data, buf);
+        let response = UsbRedirBulkPacket {
+            endpoint: 0x02,
+            status: USB_REDIR_STALL,
+            ..Default::default()
+        };
+        redir.receive(&peer.build(USB_REDIR_BULK_PACKET, request.id, response.as_bytes(), &[]));
+        assert_eq!(packet.lock().unwrap().status, UsbPacketStatus::Stall);
+
+        // Bulk IN transfer.
+        let buf = [0_u8; 8];
+        let packet = create_packet(USB_TOKEN_IN, 1, &buf);
+        redir.handle_packet(&packet);
+        let request = peer.recv();
+        assert_eq!({ request.header::<UsbRedirBulkPacket>().length }, 8);
+        assert!(request.data.is_empty());
+        let response = UsbRedirBulkPacket {
+            endpoint: 0x81,
+            length: 3,
+            ..Default::default()
+        };
+        redir.receive(&peer.build(
+            USB_REDIR_BULK_PACKET,
+            request.id,
+            response.as_bytes(),
+            &[5, 6, 7],
+        ));
+        assert_eq!(packet.lock().unwrap().actual_length, 3);
+        assert_eq!(buf[..4], [5, 6, 7, 0]);
+
+        // Interrupt IN transfer is NAKed until peer sends data.
+        let buf = [0_u8; 4];
+        let packet = create_packet(USB_TOKEN_IN, 3, &buf);
+        redir.handle_packet(&packet);
+        assert_eq!(packet.lock().unwrap().status, UsbPacketStatus::Nak);
+        let request = peer.recv();
+        assert_eq!(request.ptype, USB_REDIR_START_INTERRUPT_RECEIVING);
+        assert_eq!(request.header::<UsbRedirEndpoint>().endpoint, 0x83);
+        let data = UsbRedirDataPacket {
+            endpoint: 0x83,
+            status: USB_REDIR_SUCCESS,
+            length: 2,
+        };
+        let events =
+            redir.receive(&peer.build(USB_REDIR_INTERRUPT_PACKET, 0, data.as_bytes(), &[0xa, 0xb]));
+        assert!(matches!(events[..], [RedirEvent::Wakeup(_)]));
+        redir.handle_packet(&packet);
+        assert_eq!(packet.lock().unwrap().status, UsbPacketStatus::Success);
+        assert_eq!(packet.lock().unwrap().actual_length, 2);
+        assert_eq!(buf[..2], [0xa, 0xb]);
+    }
+}
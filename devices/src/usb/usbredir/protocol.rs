@@ -0,0 +1,496 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! The usbredir protocol, see usbredirproto.h of the usbredir project.
+
+use std::mem::size_of;
+
+use anyhow::{bail, Result};
+
+use util::byte_code::ByteCode;
+
+pub const USB_REDIR_VERSION: &str = "stratovirt usbredir 1.0";
+const USB_REDIR_VERSION_LEN: usize = 64;
+
+// Packet types.
+pub const USB_REDIR_HELLO: u32 = 0;
+pub const USB_REDIR_DEVICE_CONNECT: u32 = 1;
+pub const USB_REDIR_DEVICE_DISCONNECT: u32 = 2;
+pub const USB_REDIR_RESET: u32 = 3;
+pub const USB_REDIR_INTERFACE_INFO: u32 = 4;
+pub const USB_REDIR_EP_INFO: u32 = 5;
+pub const USB_REDIR_SET_CONFIGURATION: u32 = 6;
+pub const USB_REDIR_GET_CONFIGURATION: u32 = 7;
+pub const USB_REDIR_CONFIGURATION_STATUS: u32 = 8;
+pub const USB_REDIR_SET_ALT_SETTING: u32 = 9;
+pub const USB_REDIR_GET_ALT_SETTING: u32 = 10;
+pub const USB_REDIR_ALT_SETTING_STATUS: u32 = 11;
+pub const USB_REDIR_START_ISO_STREAM: u32 = 12;
+pub const USB_REDIR_STOP_ISO_STREAM: u32 = 13;
+pub const USB_REDIR_ISO_STREAM_STATUS: u32 = 14;
+pub const USB_REDIR_START_INTERRUPT_RECEIVING: u32 = 15;
+pub const USB_REDIR_STOP_INTERRUPT_RECEIVING: u32 = 16;
+pub const USB_REDIR_INTERRUPT_RECEIVING_STATUS: u32 = 17;
+pub const USB_REDIR_CANCEL_DATA_PACKET: u32 = 21;
+pub const USB_REDIR_FILTER_REJECT: u32 = 22;
+pub const USB_REDIR_FILTER_FILTER: u32 = 23;
+pub const USB_REDIR_DEVICE_DISCONNECT_ACK: u32 = 24;
+pub const USB_REDIR_CONTROL_PACKET: u32 = 100;
+pub const USB_REDIR_BULK_PACKET: u32 = 101;
+pub const USB_REDIR_ISO_PACKET: u32 = 102;
+pub const USB_REDIR_INTERRUPT_PACKET: u32 = 103;
+
+// Capabilities, which are used only if both sides have them.
+pub const USB_REDIR_CAP_CONNECT_DEVICE_VERSION: u32 = 1;
+pub const USB_REDIR_CAP_DEVICE_DISCONNECT_ACK: u32 = 3;
+pub const USB_REDIR_CAP_EP_INFO_MAX_PACKET_SIZE: u32 = 4;
+pub const USB_REDIR_CAP_32BITS_BULK_LENGTH: u32 = 6;
+/// Capabilities supported by us.
+pub const USB_REDIR_CAPS: u32 = 1 << USB_REDIR_CAP_CONNECT_DEVICE_VERSION
+    | 1 << USB_REDIR_CAP_DEVICE_DISCONNECT_ACK
+    | 1 << USB_REDIR_CAP_EP_INFO_MAX_PACKET_SIZE
+    | 1 << USB_REDIR_CAP_32BITS_BULK_LENGTH;
+
+// Transfer status.
+pub const USB_REDIR_SUCCESS: u8 = 0;
+pub const USB_REDIR_CANCELLED: u8 = 1;
+pub const USB_REDIR_INVAL: u8 = 2;
+pub const USB_REDIR_IOERROR: u8 = 3;
+pub const USB_REDIR_STALL: u8 = 4;
+pub const USB_REDIR_TIMEOUT: u8 = 5;
+pub const USB_REDIR_BABBLE: u8 = 6;
+
+// Device speed.
+pub const USB_REDIR_SPEED_LOW: u8 = 0;
+pub const USB_REDIR_SPEED_FULL: u8 = 1;
+pub const USB_REDIR_SPEED_HIGH: u8 = 2;
+pub const USB_REDIR_SPEED_SUPER: u8 = 3;
+
+/// Max number of endpoints, 16 OUT endpoints followed by 16 IN endpoints.
+pub const USB_REDIR_MAX_ENDPOINTS: usize = 32;
+pub const USB_REDIR_MAX_INTERFACES: usize = 32;
+/// Max payload length of packet accepted from peer.
+const USB_REDIR_MAX_PACKET_LEN: usize = 16 * 1024 * 1024;
+
+/// Convert the endpoint address to the index used by usbredir.
+pub fn ep_addr_to_index(ep: u8) -> usize {
+    (((ep & 0x80) >> 3) | (ep & 0x0f)) as usize
+}
+
+/// Convert the index used by usbredir to the endpoint address.
+pub fn ep_index_to_addr(index: usize) -> u8 {
+    (((index & 0x10) << 3) | (index & 0x0f)) as u8
+}
+
+/// Header of all packets, the 32 bits id is used as 64 bits ids capability is not supported.
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirHeader {
+    pub ptype: u32,
+    pub length: u32,
+    pub id: u32,
+}
+
+impl ByteCode for UsbRedirHeader {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirDeviceConnect {
+    pub speed: u8,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Only present with connect_device_version capability.
+    pub device_version_bcd: u16,
+}
+
+impl ByteCode for UsbRedirDeviceConnect {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirInterfaceInfo {
+    pub interface_count: u32,
+    pub interface: [u8; USB_REDIR_MAX_INTERFACES],
+    pub interface_class: [u8; USB_REDIR_MAX_INTERFACES],
+    pub interface_subclass: [u8; USB_REDIR_MAX_INTERFACES],
+    pub interface_protocol: [u8; USB_REDIR_MAX_INTERFACES],
+}
+
+impl ByteCode for UsbRedirInterfaceInfo {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirEpInfo {
+    pub ep_type: [u8; USB_REDIR_MAX_ENDPOINTS],
+    pub interval: [u8; USB_REDIR_MAX_ENDPOINTS],
+    pub interface: [u8; USB_REDIR_MAX_ENDPOINTS],
+    /// Only present with ep_info_max_packet_size capability.
+    pub max_packet_size: [u16; USB_REDIR_MAX_ENDPOINTS],
+}
+
+impl ByteCode for UsbRedirEpInfo {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirSetConfiguration {
+    pub configuration: u8,
+}
+
+impl ByteCode for UsbRedirSetConfiguration {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirConfigurationStatus {
+    pub status: u8,
+    pub configuration: u8,
+}
+
+impl ByteCode for UsbRedirConfigurationStatus {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirSetAltSetting {
+    pub interface: u8,
+    pub alt: u8,
+}
+
+impl ByteCode for UsbRedirSetAltSetting {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirAltSettingStatus {
+    pub status: u8,
+    pub interface: u8,
+    pub alt: u8,
+}
+
+impl ByteCode for UsbRedirAltSettingStatus {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirStartIsoStream {
+    pub endpoint: u8,
+    pub pkts_per_urb: u8,
+    pub no_urbs: u8,
+}
+
+impl ByteCode for UsbRedirStartIsoStream {}
+
+/// Used by stop_iso_stream, start_interrupt_receiving and stop_interrupt_receiving.
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirEndpoint {
+    pub endpoint: u8,
+}
+
+impl ByteCode for UsbRedirEndpoint {}
+
+/// Used by iso_stream_status and interrupt_receiving_status.
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirStreamStatus {
+    pub status: u8,
+    pub endpoint: u8,
+}
+
+impl ByteCode for UsbRedirStreamStatus {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirControlPacket {
+    pub endpoint: u8,
+    pub request: u8,
+    pub requesttype: u8,
+    pub status: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl ByteCode for UsbRedirControlPacket {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirBulkPacket {
+    pub endpoint: u8,
+    pub status: u8,
+    pub length: u16,
+    pub stream_id: u32,
+    /// Only present with 32bits_bulk_length capability.
+    pub length_high: u16,
+}
+
+impl ByteCode for UsbRedirBulkPacket {}
+
+/// Used by iso_packet and interrupt_packet.
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UsbRedirDataPacket {
+    pub endpoint: u8,
+    pub status: u8,
+    pub length: u16,
+}
+
+impl ByteCode for UsbRedirDataPacket {}
+
+/// The packet received from peer.
+pub struct UsbRedirPacket {
+    pub ptype: u32,
+    pub id: u32,
+    /// Type specific header.
+    pub header: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl UsbRedirPacket {
+    /// Get the type specific header, fields absent in the packet are zero.
+    pub fn header<T: ByteCode>(&self) -> T {
+        let mut header = T::default();
+        let len = std::cmp::min(self.header.len(), size_of::<T>());
+        header.as_mut_bytes()[..len].copy_from_slice(&self.header[..len]);
+        header
+    }
+}
+
+/// Parse the byte stream from peer into packets, and build the packets sent to peer.
+#[derive(Default)]
+pub struct UsbRedirParser {
+    /// Received bytes which are not parsed yet.
+    buf: Vec<u8>,
+    /// Capabilities of peer, known after receiving hello.
+    peer_caps: u32,
+}
+
+impl UsbRedirParser {
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.peer_caps = 0;
+    }
+
+    pub fn set_peer_caps(&mut self, caps: u32) {
+        self.peer_caps = caps;
+    }
+
+    /// Check whether both sides support the capability.
+    pub fn have_cap(&self, cap: u32) -> bool {
+        self.peer_caps & USB_REDIR_CAPS & (1 << cap) != 0
+    }
+
+    /// Get the length of type specific header, None means unknown packet type.
+    fn type_header_len(&self, ptype: u32) -> Option<usize> {
+        let len = match ptype {
+            USB_REDIR_HELLO => USB_REDIR_VERSION_LEN,
+            USB_REDIR_DEVICE_CONNECT => {
+                if self.have_cap(USB_REDIR_CAP_CONNECT_DEVICE_VERSION) {
+                    size_of::<UsbRedirDeviceConnect>()
+                } else {
+                    size_of::<UsbRedirDeviceConnect>() - size_of::<u16>()
+                }
+            }
+            USB_REDIR_DEVICE_DISCONNECT
+            | USB_REDIR_RESET
+            | USB_REDIR_GET_CONFIGURATION
+            | USB_REDIR_CANCEL_DATA_PACKET
+            | USB_REDIR_FILTER_REJECT
+            | USB_REDIR_FILTER_FILTER
+            | USB_REDIR_DEVICE_DISCONNECT_ACK => 0,
+            USB_REDIR_INTERFACE_INFO => size_of::<UsbRedirInterfaceInfo>(),
+            USB_REDIR_EP_INFO => {
+                if self.have_cap(USB_REDIR_CAP_EP_INFO_MAX_PACKET_SIZE) {
+                    size_of::<UsbRedirEpInfo>()
+                } else {
+                    size_of::<UsbRedirEpInfo>() - size_of::<[u16; USB_REDIR_MAX_ENDPOINTS]>()
+                }
+            }
+            USB_REDIR_SET_CONFIGURATION => size_of::<UsbRedirSetConfiguration>(),
+            USB_REDIR_CONFIGURATION_STATUS => size_of::<UsbRedirConfigurationStatus>(),
+            USB_REDIR_SET_ALT_SETTING => size_of::<UsbRedirSetAltSetting>(),
+            USB_REDIR_GET_ALT_SETTING
+            | USB_REDIR_STOP_ISO_STREAM
+            | USB_REDIR_START_INTERRUPT_RECEIVING
+            | USB_REDIR_STOP_INTERRUPT_RECEIVING => size_of::<UsbRedirEndpoint>(),
+            USB_REDIR_ALT_SETTING_STATUS => size_of::<UsbRedirAltSettingStatus>(),
+            USB_REDIR_START_ISO_STREAM => size_of::<UsbRedirStartIsoStream>(),
+            USB_REDIR_ISO_STREAM_STATUS | USB_REDIR_INTERRUPT_RECEIVING_STATUS => {
+                size_of::<UsbRedirStreamStatus>()
+            }
+            USB_REDIR_CONTROL_PACKET => size_of::<UsbRedirControlPacket>(),
+            USB_REDIR_BULK_PACKET => {
+                if self.have_cap(USB_REDIR_CAP_32BITS_BULK_LENGTH) {
+                    size_of::<UsbRedirBulkPacket>()
+                } else {
+                    size_of::<UsbRedirBulkPacket>() - size_of::<u16>()
+                }
+            }
+            USB_REDIR_ISO_PACKET | USB_REDIR_INTERRUPT_PACKET => size_of::<UsbRedirDataPacket>(),
+            _ => return None,
+        };
+        Some(len)
+    }
+
+    /// Append the bytes received from peer.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Get the next complete packet, return None if more bytes are needed.
+    pub fn next_packet(&mut self) -> Result<Option<UsbRedirPacket>> {
+        let hdr_len = size_of::<UsbRedirHeader>();
+        if self.buf.len() < hdr_len {
+            return Ok(None);
+        }
+        let mut hdr = UsbRedirHeader::default();
+        hdr.as_mut_bytes().copy_from_slice(&self.buf[..hdr_len]);
+        let (ptype, length, id) = (hdr.ptype, hdr.length as usize, hdr.id);
+        let type_hdr_len = match self.type_header_len(ptype) {
+            Some(len) => len,
+            None => bail!("Unknown usbredir packet type {}", ptype),
+        };
+        if length < type_hdr_len || length > USB_REDIR_MAX_PACKET_LEN {
+            bail!(
+                "Invalid length {} of usbredir packet type {}",
+                length,
+                ptype
+            );
+        }
+        if self.buf.len() < hdr_len + length {
+            return Ok(None);
+        }
+
+        let mut packet: Vec<u8> = self.buf.drain(..hdr_len + length).collect();
+        let data = packet.split_off(hdr_len + type_hdr_len);
+        let header = packet.split_off(hdr_len);
+        Ok(Some(UsbRedirPacket {
+            ptype,
+            id,
+            header,
+            data,
+        }))
+    }
+
+    /// Build the packet to be sent to peer, the type specific header is truncated
+    /// according to the capabilities.
+    pub fn build_packet(&self, ptype: u32, id: u32, header: &[u8], data: &[u8]) -> Vec<u8> {
+        let type_hdr_len = std::cmp::min(self.type_header_len(ptype).unwrap_or(0), header.len());
+        let hdr = UsbRedirHeader {
+            ptype,
+            length: (type_hdr_len + data.len()) as u32,
+            id,
+        };
+        let mut packet = Vec::with_capacity(size_of::<UsbRedirHeader>() + hdr.length as usize);
+        packet.extend_from_slice(hdr.as_bytes());
+        packet.extend_from_slice(&header[..type_hdr_len]);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// Build the hello packet, which is sent first after connected.
+    pub fn build_hello(&self) -> Vec<u8> {
+        let mut version = [0_u8; USB_REDIR_VERSION_LEN];
+        version[..USB_REDIR_VERSION.len()].copy_from_slice(USB_REDIR_VERSION.as_bytes());
+        self.build_packet(USB_REDIR_HELLO, 0, &version, &USB_REDIR_CAPS.to_le_bytes())
+    }
+}
+
+/// Get the version string and capabilities from hello packet.
+pub fn parse_hello(packet: &UsbRedirPacket) -> (String, u32) {
+    let end = packet
+        .header
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(packet.header.len());
+    let version = String::from_utf8_lossy(&packet.header[..end]).to_string();
+    let mut caps = [0_u8; 4];
+    let len = std::cmp::min(packet.data.len(), caps.len());
+    caps[..len].copy_from_slice(&packet.data[..len]);
+    (version, u32::from_le_bytes(caps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usbredir_parser() {
+        let mut peer = UsbRedirParser::default();
+        let mut parser = UsbRedirParser::default();
+
+        // Hello is parsed without any capability negotiated.
+        let hello = peer.build_hello();
+        assert_eq!(hello.len(), 12 + 64 + 4);
+        parser.feed(&hello[..10]);
+        assert!(parser.next_packet().unwrap().is_none());
+        parser.feed(&hello[10..]);
+        let packet = parser.next_packet().unwrap().unwrap();
+        assert_eq!(packet.ptype, USB_REDIR_HELLO);
+        let (version, caps) = parse_hello(&packet);
+        assert_eq!(version, USB_REDIR_VERSION);
+        assert_eq!(caps, USB_REDIR_CAPS);
+        assert!(!parser.have_cap(USB_REDIR_CAP_32BITS_BULK_LENGTH));
+        parser.set_peer_caps(caps);
+        peer.set_peer_caps(USB_REDIR_CAPS);
+        assert!(parser.have_cap(USB_REDIR_CAP_32BITS_BULK_LENGTH));
+
+        // Several packets in one read.
+        let connect = UsbRedirDeviceConnect {
+            speed: USB_REDIR_SPEED_HIGH,
+            vendor_id: 0x1234,
+            product_id: 0x5678,
+            device_version_bcd: 0x0100,
+            ..Default::default()
+        };
+        let bulk = UsbRedirBulkPacket {
+            endpoint: 0x81,
+            length: 0x10,
+            length_high: 0x1,
+            ..Default::default()
+        };
+        let mut stream = peer.build_packet(USB_REDIR_DEVICE_CONNECT, 0, connect.as_bytes(), &[]);
+        stream.extend(peer.build_packet(USB_REDIR_BULK_PACKET, 7, bulk.as_bytes(), &[1, 2, 3]));
+        assert_eq!(stream.len(), 12 + 10 + 12 + 10 + 3);
+        parser.feed(&stream);
+        let packet = parser.next_packet().unwrap().unwrap();
+        assert_eq!(packet.ptype, USB_REDIR_DEVICE_CONNECT);
+        let connect = packet.header::<UsbRedirDeviceConnect>();
+        assert_eq!({ connect.vendor_id }, 0x1234);
+        assert_eq!({ connect.device_version_bcd }, 0x0100);
+        let packet = parser.next_packet().unwrap().unwrap();
+        assert_eq!(packet.ptype, USB_REDIR_BULK_PACKET);
+        assert_eq!(packet.id, 7);
+        let bulk = packet.header::<UsbRedirBulkPacket>();
+        assert_eq!(
+            { bulk.length } as u32 | ({ bulk.length_high } as u32) << 16,
+            0x10010
+        );
+        assert_eq!(packet.data, vec![1, 2, 3]);
+        assert!(parser.next_packet().unwrap().is_none());
+
+        // Optional fields are dropped if the capability is not supported by peer.
+        parser.set_peer_caps(0);
+        let packet = parser.build_packet(USB_REDIR_BULK_PACKET, 1, bulk.as_bytes(), &[]);
+        assert_eq!(packet.len(), 12 + 8);
+
+        // Unknown packet type.
+        parser.feed(&parser.build_packet(0xff, 0, &[], &[]));
+        assert!(parser.next_packet().is_err());
+    }
+
+    #[test]
+    fn test_usbredir_ep_index() {
+        assert_eq!(ep_addr_to_index(0x81), 17);
+        assert_eq!(ep_addr_to_index(0x02), 2);
+        assert_eq!(ep_index_to_addr(17), 0x81);
+        assert_eq!(ep_index_to_addr(2), 0x02);
+    }
+}
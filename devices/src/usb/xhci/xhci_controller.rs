@@ -74,6 +74,7 @@ const EVENT_TRB_SLOT_ID_SHIFT: u32 = 24;
 const EVENT_TRB_EP_ID_SHIFT: u32 = 16;
 const PORT_EVENT_ID_SHIFT: u32 = 24;
 const SLOT_CTX_PORT_NUMBER_SHIFT: u32 = 16;
+const SLOT_CTX_ROUTE_STRING_MASK: u32 = 0xfffff;
 const ENDPOINT_ID_START: u32 = 1;
 const MAX_ENDPOINTS: u32 = 31;
 const TRANSFER_LEN_MASK: u32 = 0xffffff;
@@ -111,6 +112,17 @@ const EP_CONTEXT_EP_STATE_MASK: u32 = 0x7;
 const EP_CONTEXT_EP_STATE_SHIFT: u32 = 0;
 const EP_CONTEXT_EP_TYPE_MASK: u32 = 0x7;
 const EP_CONTEXT_EP_TYPE_SHIFT: u32 = 3;
+const EP_CTX_MAX_PSTREAMS_SHIFT: u32 = 10;
+const EP_CTX_MAX_PSTREAMS_MASK: u32 = 0x1f;
+const EP_CTX_LSA: u32 = 1 << 15;
+/// Max Primary Streams of endpoint, the Primary Stream Array has 2^(MaxPStreams + 1) entries.
+pub const MAX_PSTREAMS: u32 = 7;
+/// Stream Context.
+const STREAM_CTX_SIZE: u64 = 0x10;
+pub const STREAM_CTX_SCT_MASK: u32 = 0xe;
+const STREAM_CTX_SCT_SHIFT: u32 = 1;
+const STREAM_CTX_SCT_PRIMARY_RING: u32 = 1;
+const TRB_CR_STREAMID_SHIFT: u32 = 16;
 const ISO_BASE_TIME_INTERVAL: u64 = 125000;
 const MFINDEX_WRAP_NUM: u64 = 0x4000;
 
@@ -124,6 +136,7 @@ pub struct XhciTransfer {
     complete: bool,
     slotid: u32,
     epid: u32,
+    stream_id: u32,
     in_xfer: bool,
     iso_xfer: bool,
     timed_xfer: bool,
@@ -152,6 +165,7 @@ impl XhciTransfer {
             complete: false,
             slotid: ep_info.0,
             epid: ep_info.1,
+            stream_id: 0,
             in_xfer,
             iso_xfer: false,
             timed_xfer: false,
@@ -281,6 +295,9 @@ pub struct XhciEpContext {
     mfindex_last: u64,
     transfers: LinkedList<Arc<Mutex<XhciTransfer>>>,
     retry: Option<Arc<Mutex<XhciTransfer>>>,
+    /// Transfer rings of the streams indexed by stream id, which is empty if streams are
+    /// not used. The rings are loaded from the Primary Stream Array when used.
+    streams: Vec<Option<Arc<XhciTransferRing>>>,
 }
 
 impl XhciEpContext {
@@ -297,6 +314,7 @@ impl XhciEpContext {
             mfindex_last: 0,
             transfers: LinkedList::new(),
             retry: None,
+            streams: Vec::new(),
         }
     }
 
@@ -308,6 +326,72 @@ impl XhciEpContext {
         self.ring.init(dequeue);
         self.ring.set_cycle_bit((ctx.deq_lo & 1) == 1);
         self.interval = 1 << ((ctx.ep_info >> EP_CTX_INTERVAL_SHIFT) & EP_CTX_INTERVAL_MASK);
+        // The dequeue pointer is the Primary Stream Array if streams are used.
+        let max_pstreams = (ctx.ep_info >> EP_CTX_MAX_PSTREAMS_SHIFT) & EP_CTX_MAX_PSTREAMS_MASK;
+        self.streams = match max_pstreams {
+            0 => Vec::new(),
+            n => vec![None; 1 << (n + 1)],
+        };
+    }
+
+    /// Get the stream ids to kick when the endpoint is woken up. The stream of the
+    /// transfer to retry is preferred, otherwise all the loaded streams are kicked.
+    fn pending_stream_ids(&self) -> Vec<u32> {
+        if self.streams.is_empty() {
+            return vec![0];
+        }
+        if let Some(xfer) = &self.retry {
+            return vec![xfer.lock().unwrap().stream_id];
+        }
+        self.streams
+            .iter()
+            .enumerate()
+            .filter(|(_, ring)| ring.is_some())
+            .map(|(id, _)| id as u32)
+            .collect()
+    }
+
+    /// Get the transfer ring of the stream, and load it from the stream context if it
+    /// is not loaded.
+    fn get_stream_ring(&mut self, stream_id: u32) -> Result<Arc<XhciTransferRing>> {
+        // Stream id 0 is reserved.
+        let entry = self
+            .streams
+            .get_mut(stream_id as usize)
+            .filter(|_| stream_id != 0)
+            .with_context(|| format!("Invalid stream id {}", stream_id))?;
+        if let Some(ring) = entry {
+            return Ok(ring.clone());
+        }
+        let stream_array = self.ring.get_dequeue_ptr();
+        let ctx_addr = stream_array
+            .checked_add(stream_id as u64 * STREAM_CTX_SIZE)
+            .with_context(|| {
+                UsbError::MemoryAccessOverflow(stream_array, stream_id as u64 * STREAM_CTX_SIZE)
+            })?;
+        let mut stream_ctx = XhciStreamCtx::default();
+        dma_read_u32(
+            &self.ring.mem,
+            GuestAddress(ctx_addr),
+            stream_ctx.as_mut_dwords(),
+        )?;
+        let sct = (stream_ctx.deq_lo & STREAM_CTX_SCT_MASK) >> STREAM_CTX_SCT_SHIFT;
+        if sct != STREAM_CTX_SCT_PRIMARY_RING {
+            bail!(
+                "Unsupported stream context type {} of stream {}",
+                sct,
+                stream_id
+            );
+        }
+        let ring = Arc::new(XhciTransferRing::new_stream(
+            &self.ring.mem,
+            &self.output_ctx_addr,
+            ctx_addr,
+        ));
+        ring.init(addr64_from_u32(stream_ctx.deq_lo & !0xf, stream_ctx.deq_hi));
+        ring.set_cycle_bit((stream_ctx.deq_lo & 1) == 1);
+        *entry = Some(ring.clone());
+        Ok(ring)
     }
 
     fn get_ep_state(&self) -> u32 {
@@ -364,6 +448,9 @@ fn set_ep_state_helper(
     ep_ctx.ep_info |= state;
     ring.update_dequeue_to_ctx(&mut ep_ctx);
     dma_write_u32(mem, GuestAddress(output_addr), ep_ctx.as_dwords())?;
+    if ring.stream_ctx_addr.is_some() {
+        ring.refresh_dequeue_ptr()?;
+    }
     ep_state.store(state, Ordering::SeqCst);
     Ok(())
 }
@@ -464,6 +551,8 @@ pub struct UsbPort {
     pub dev: Option<Arc<Mutex<dyn UsbDeviceOps>>>,
     pub used: bool,
     pub slot_id: u32,
+    /// The hub which the port belongs to, None for the root port.
+    pub hub: Option<Weak<Mutex<dyn UsbDeviceOps>>>,
 }
 
 impl UsbPort {
@@ -476,6 +565,7 @@ impl UsbPort {
             dev: None,
             used: false,
             slot_id: INVALID_SLOT_ID,
+            hub: None,
         }
     }
 
@@ -662,6 +752,16 @@ impl XhciEpCtx {
 
 impl DwordOrder for XhciEpCtx {}
 
+/// Stream Context. See the spec 6.2.4.1 Stream Context.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct XhciStreamCtx {
+    pub deq_lo: u32,
+    pub deq_hi: u32,
+}
+
+impl DwordOrder for XhciStreamCtx {}
+
 pub trait DwordOrder: Default + Copy + Send + Sync {
     fn as_dwords(&self) -> &[u32] {
         unsafe { from_raw_parts(self as *const Self as *const u32, size_of::<Self>() / 4) }
@@ -882,10 +982,20 @@ impl XhciDevice {
 
     /// Update the xhci port status and then notify the driver.
     pub fn port_update(&mut self, port: &Arc<Mutex<UsbPort>>, detach: bool) -> Result<()> {
+        let hub = port.lock().unwrap().hub.clone();
+        if let Some(hub) = hub {
+            return self.hub_port_update(&hub, port, detach);
+        }
         let mut locked_port = port.lock().unwrap();
         locked_port.portsc = PORTSC_PP;
         let mut pls = PLS_RX_DETECT;
-        if !detach {
+        // The port stays empty if the device is not present, e.g. redirected
+        // device which is not connected by the remote side.
+        let connected = locked_port
+            .dev
+            .as_ref()
+            .map_or(false, |dev| dev.lock().unwrap().connected());
+        if !detach && connected {
             if let Some(dev) = &locked_port.dev {
                 let speed = dev.lock().unwrap().speed();
                 locked_port.portsc |= PORTSC_CCS;
@@ -916,6 +1026,23 @@ impl XhciDevice {
         Ok(())
     }
 
+    /// Update the downstream port of hub, and then notify the driver by the status
+    /// change endpoint of hub.
+    fn hub_port_update(
+        &mut self,
+        hub: &Weak<Mutex<dyn UsbDeviceOps>>,
+        port: &Arc<Mutex<UsbPort>>,
+        detach: bool,
+    ) -> Result<()> {
+        let hub = hub.upgrade().with_context(|| "USB hub not found")?;
+        let mut locked_hub = hub.lock().unwrap();
+        locked_hub.child_port_update(port, detach);
+        let slot_id = locked_hub.get_usb_device().addr as u32;
+        let ep = locked_hub.get_wakeup_endpoint().clone();
+        drop(locked_hub);
+        self.wakeup_endpoint(slot_id, &ep)
+    }
+
     fn get_slot_id(&self, evt: &mut XhciEvent, trb: &XhciTRB) -> u32 {
         let slot_id = (trb.control >> TRB_CR_SLOTID_SHIFT) & TRB_CR_SLOTID_MASK;
         if slot_id < 1 || slot_id > self.slots.len() as u32 {
@@ -936,10 +1063,26 @@ impl XhciDevice {
             error!("Invalid port: {}", port);
             return None;
         }
-        let usb_port = &self.usb_ports[(port - 1) as usize];
+        let mut usb_port = self.usb_ports[(port - 1) as usize].clone();
+        // Route string of the device behind hubs, 4 bits for each tier.
+        let mut route = slot_ctx.dev_info & SLOT_CTX_ROUTE_STRING_MASK;
+        while route & 0xf != 0 {
+            let child_port = usb_port.lock().unwrap().dev.as_ref().and_then(|dev| {
+                let ports = dev.lock().unwrap().get_child_ports();
+                ports.get((route & 0xf) as usize - 1).cloned()
+            });
+            usb_port = if let Some(child_port) = child_port {
+                child_port
+            } else {
+                error!("Invalid route string: {:x}", slot_ctx.dev_info);
+                return None;
+            };
+            route >>= 4;
+        }
         let locked_port = usb_port.lock().unwrap();
         if locked_port.used {
-            Some(usb_port.clone())
+            drop(locked_port);
+            Some(usb_port)
         } else {
             None
         }
@@ -1387,7 +1530,31 @@ impl XhciDevice {
             GuestAddress(input_ctx + EP_INPUT_CTX_OFFSET + entry_offset),
             ep_ctx.as_mut_dwords(),
         )?;
+        let max_pstreams = (ep_ctx.ep_info >> EP_CTX_MAX_PSTREAMS_SHIFT) & EP_CTX_MAX_PSTREAMS_MASK;
+        if max_pstreams > MAX_PSTREAMS
+            || (max_pstreams != 0 && ep_ctx.ep_info & EP_CTX_LSA != EP_CTX_LSA)
+        {
+            // Secondary Stream Arrays are not supported.
+            error!(
+                "Unsupported streams of endpoint, ep info {:x}",
+                ep_ctx.ep_info
+            );
+            return Ok(TRBCCode::ParameterError);
+        }
         self.disable_endpoint(slot_id, ep_id)?;
+        if max_pstreams != 0 {
+            let ep_addr = endpoint_id_to_addr(ep_id as u8);
+            let dev = self.get_usb_dev(slot_id, ep_id)?;
+            let streams = (1 << (max_pstreams + 1)) - 1;
+            let result = dev.lock().unwrap().alloc_streams(ep_addr, streams);
+            if let Err(e) = result {
+                error!(
+                    "Failed to alloc {} streams for endpoint {:#x}: {:?}",
+                    streams, ep_addr, e
+                );
+                return Ok(TRBCCode::ResourceError);
+            }
+        }
         let mut epctx = &mut self.slots[(slot_id - 1) as usize].endpoints[(ep_id - 1) as usize];
         epctx.epid = ep_id;
         epctx.enabled = true;
@@ -1420,6 +1587,14 @@ impl XhciDevice {
             epctx.set_state(EP_DISABLED)?;
         }
         epctx.enabled = false;
+        if !epctx.streams.is_empty() {
+            epctx.streams.clear();
+            if let Ok(dev) = self.get_usb_dev(slot_id, ep_id) {
+                dev.lock()
+                    .unwrap()
+                    .free_streams(endpoint_id_to_addr(ep_id as u8));
+            }
+        }
         Ok(TRBCCode::Success)
     }
 
@@ -1518,12 +1693,26 @@ impl XhciDevice {
             );
             return Ok(TRBCCode::ContextStateError);
         }
-        epctx.update_dequeue(&self.mem_space, Some(trb.parameter))?;
+        if epctx.streams.is_empty() {
+            epctx.update_dequeue(&self.mem_space, Some(trb.parameter))?;
+            return Ok(TRBCCode::Success);
+        }
+        let stream_id = trb.status >> TRB_CR_STREAMID_SHIFT;
+        let ring = match epctx.get_stream_ring(stream_id) {
+            Ok(ring) => ring,
+            Err(e) => {
+                error!("Failed to set TR dequeue pointer: {:?}", e);
+                return Ok(TRBCCode::InvalidStreamIdError);
+            }
+        };
+        ring.init(trb.parameter & EP_CTX_TR_DEQUEUE_POINTER_MASK);
+        ring.set_cycle_bit((trb.parameter & EP_CTX_DCS) == EP_CTX_DCS);
+        ring.refresh_dequeue_ptr()?;
         Ok(TRBCCode::Success)
     }
 
     /// Data plane
-    pub(crate) fn kick_endpoint(&mut self, slot_id: u32, ep_id: u32) -> Result<()> {
+    pub(crate) fn kick_endpoint(&mut self, slot_id: u32, ep_id: u32, stream_id: u32) -> Result<()> {
         let epctx = match self.get_endpoint_ctx(slot_id, ep_id) {
             Ok(epctx) => epctx,
             Err(e) => {
@@ -1560,19 +1749,29 @@ impl XhciDevice {
             info!("xhci: endpoint halted");
             return Ok(());
         }
+        let ring = if epctx.streams.is_empty() {
+            epctx.ring.clone()
+        } else {
+            match epctx.get_stream_ring(stream_id) {
+                Ok(ring) => ring,
+                Err(e) => {
+                    error!("Kick endpoint error: {:?}", e);
+                    return Ok(());
+                }
+            }
+        };
         epctx.set_state(EP_RUNNING)?;
         let ep_state = epctx.state.clone();
         const KICK_LIMIT: u32 = 256;
         let mut count = 0;
-        let ring = epctx.ring.clone();
         loop {
             let epctx = &mut self.slots[(slot_id - 1) as usize].endpoints[(ep_id - 1) as usize];
-            let td = match epctx.ring.fetch_td()? {
+            let td = match ring.fetch_td()? {
                 Some(td) => {
                     debug!(
                         "fetch transfer trb {:?} ring dequeue {:?}",
                         td,
-                        epctx.ring.get_dequeue_ptr(),
+                        ring.get_dequeue_ptr(),
                     );
                     td
                 }
@@ -1585,7 +1784,7 @@ impl XhciDevice {
                         let mut evt = XhciEvent::new(TRBType::ErTransfer, ccode);
                         evt.slot_id = slot_id as u8;
                         evt.ep_id = ep_id as u8;
-                        evt.ptr = epctx.ring.dequeue.load(Ordering::Acquire);
+                        evt.ptr = ring.dequeue.load(Ordering::Acquire);
                         if let Err(e) = self.intrs[0].lock().unwrap().send_event(&evt) {
                             error!("Failed to send event: {:?}", e);
                         }
@@ -1596,14 +1795,16 @@ impl XhciDevice {
             };
             let in_xfer = transfer_in_direction(ep_id as u8, &td, epctx.ep_type);
             // NOTE: Only support primary interrupter now.
-            let xfer = Arc::new(Mutex::new(XhciTransfer::new(
+            let mut xfer = XhciTransfer::new(
                 (slot_id, ep_id, epctx.ep_type),
                 in_xfer,
                 td,
                 &self.intrs[0],
                 &ring,
                 &ep_state,
-            )));
+            );
+            xfer.stream_id = stream_id;
+            let xfer = Arc::new(Mutex::new(xfer));
             let packet = match self.setup_usb_packet(&xfer) {
                 Ok(pkt) => pkt,
                 Err(e) => {
@@ -1618,7 +1819,7 @@ impl XhciDevice {
             self.endpoint_do_transfer(&mut locked_xfer)?;
             let mut epctx = &mut self.slots[(slot_id - 1) as usize].endpoints[(ep_id - 1) as usize];
             if locked_xfer.complete {
-                epctx.update_dequeue(&self.mem_space, None)?;
+                ring.refresh_dequeue_ptr()?;
             } else {
                 epctx.transfers.push_back(xfer.clone());
             }
@@ -1698,8 +1899,8 @@ impl XhciDevice {
 
         let epctx = &mut self.slots[(slot_id - 1) as usize].endpoints[(ep_id - 1) as usize];
         if locked_xfer.complete {
+            locked_xfer.ep_ring.refresh_dequeue_ptr()?;
             drop(locked_xfer);
-            epctx.update_dequeue(&self.mem_space, None)?;
             epctx.flush_transfer();
         }
         epctx.retry = None;
@@ -1794,6 +1995,7 @@ impl XhciDevice {
             let weak_xhci = self.usb_ports[0].lock().unwrap().xhci.clone();
             let slotid = xfer.slotid;
             let epid = xfer.epid;
+            let stream_id = xfer.stream_id;
             let xhci_ep_kick_timer = Box::new(move || {
                 let xhci = weak_xhci.upgrade().unwrap();
                 let mut locked_xhci = xhci.lock().unwrap();
@@ -1808,7 +2010,7 @@ impl XhciDevice {
                 if ep_state == EP_STOPPED && ep_state == EP_ERROR {
                     return;
                 }
-                if let Err(e) = locked_xhci.kick_endpoint(slotid, epid) {
+                if let Err(e) = locked_xhci.kick_endpoint(slotid, epid, stream_id) {
                     error!("Failed to kick endpoint: {:?}", e);
                 }
             });
@@ -1912,7 +2114,8 @@ impl XhciDevice {
         }
         let (_, ep_number) = endpoint_id_to_number(locked_xfer.epid as u8);
         let xfer_ops = Arc::downgrade(xfer) as Weak<Mutex<dyn TransferOps>>;
-        let packet = UsbPacket::new(dir as u32, ep_number, vec, Some(xfer_ops));
+        let mut packet = UsbPacket::new(dir as u32, ep_number, vec, Some(xfer_ops));
+        packet.stream = locked_xfer.stream_id;
         Ok(Arc::new(Mutex::new(packet)))
     }
 
@@ -2011,8 +2214,17 @@ impl XhciDevice {
             debug!("Invalid slot id, maybe device not activated.");
             return Ok(());
         }
-        let ep_id = endpoint_number_to_id(ep.in_direction, ep.ep_number);
-        self.kick_endpoint(slot_id, ep_id as u32)?;
+        let ep_id = endpoint_number_to_id(ep.in_direction, ep.ep_number) as u32;
+        let stream_ids = match self.get_endpoint_ctx(slot_id, ep_id) {
+            Ok(epctx) => epctx.pending_stream_ids(),
+            Err(e) => {
+                error!("Wakeup endpoint error: {:?}", e);
+                return Ok(());
+            }
+        };
+        for stream_id in stream_ids {
+            self.kick_endpoint(slot_id, ep_id, stream_id)?;
+        }
         Ok(())
     }
 
@@ -2051,6 +2263,25 @@ impl XhciDevice {
                 return Some(port.clone());
             }
         }
+        // Attach the device to the hub if all the root ports are used.
+        let mut ports = self.usb_ports.clone();
+        while let Some(port) = ports.pop() {
+            let child_ports = match port.lock().unwrap().dev.as_ref() {
+                Some(hub) => hub.lock().unwrap().get_child_ports(),
+                None => continue,
+            };
+            for child_port in &child_ports {
+                let mut locked_port = child_port.lock().unwrap();
+                if locked_port.speed_supported(speed) && !locked_port.used {
+                    locked_port.used = true;
+                    locked_port.dev = Some(dev.clone());
+                    let mut locked_dev = dev.lock().unwrap();
+                    locked_dev.set_usb_port(Some(Arc::downgrade(child_port)));
+                    return Some(child_port.clone());
+                }
+            }
+            ports.extend(child_ports);
+        }
         None
     }
 
@@ -2063,15 +2294,20 @@ impl XhciDevice {
     }
 
     pub fn find_usb_port_by_id(&mut self, id: &str) -> Option<Arc<Mutex<UsbPort>>> {
-        for port in &self.usb_ports {
+        let mut ports = self.usb_ports.clone();
+        while let Some(port) = ports.pop() {
             let locked_port = port.lock().unwrap();
             if !locked_port.used || locked_port.dev.is_none() {
                 continue;
             }
-            let dev = locked_port.dev.as_ref().unwrap();
-            if dev.lock().unwrap().device_id() == id {
-                return Some(port.clone());
+            let locked_dev = locked_port.dev.as_ref().unwrap().lock().unwrap();
+            if locked_dev.device_id() == id {
+                drop(locked_dev);
+                drop(locked_port);
+                return Some(port);
             }
+            // Search the devices behind the hub.
+            ports.extend(locked_dev.get_child_ports());
         }
         None
     }
@@ -2181,6 +2417,15 @@ fn endpoint_number_to_id(in_direction: bool, ep_number: u8) -> u8 {
     }
 }
 
+fn endpoint_id_to_addr(ep_id: u8) -> u8 {
+    let (in_direction, ep_number) = endpoint_id_to_number(ep_id);
+    if in_direction {
+        ep_number | USB_DIRECTION_DEVICE_TO_HOST
+    } else {
+        ep_number
+    }
+}
+
 fn transfer_in_direction(ep_id: u8, td: &[XhciTRB], ep_type: EpType) -> bool {
     if ep_id == 1 {
         let trb_setup = td[0];
@@ -179,6 +179,22 @@ impl XhciPciDevice {
             bail!("Failed to detach device: id {} not found", id);
         }
         let usb_port = usb_port.unwrap();
+        // Detach the devices behind the hub first.
+        let child_ports = match usb_port.lock().unwrap().dev.as_ref() {
+            Some(dev) => dev.lock().unwrap().get_child_ports(),
+            None => Vec::new(),
+        };
+        let mut child_ids = Vec::new();
+        for port in child_ports {
+            if let Some(dev) = port.lock().unwrap().dev.as_ref() {
+                child_ids.push(dev.lock().unwrap().device_id().to_string());
+            }
+        }
+        drop(locked_xhci);
+        for child_id in child_ids {
+            self.detach_device(child_id)?;
+        }
+        let mut locked_xhci = self.xhci.lock().unwrap();
         let slot_id = usb_port.lock().unwrap().slot_id;
         locked_xhci.detach_slot(slot_id)?;
         locked_xhci.port_update(&usb_port, true)?;
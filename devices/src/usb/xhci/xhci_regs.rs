@@ -23,7 +23,7 @@ use util::num_ops::{read_data_u32, read_u32, write_data_u32, write_u64_high, wri
 use super::{TRBCCode, TRBType, TRB_C, TRB_SIZE};
 
 use super::xhci_controller::dma_write_bytes;
-use super::xhci_controller::{UsbPort, XhciDevice, XhciEvent};
+use super::xhci_controller::{UsbPort, XhciDevice, XhciEvent, MAX_PSTREAMS};
 use super::xhci_ring::XhciTRB;
 use crate::usb::{config::*, UsbError};
 
@@ -105,6 +105,7 @@ const XHCI_INTR_REG_SHIFT: u64 = 5;
 /// Doorbell Register Bit Field.
 /// DB Target.
 const DB_TARGET_MASK: u32 = 0xff;
+const DB_STREAM_ID_SHIFT: u32 = 16;
 /// Port Registers.
 const XHCI_PORTSC: u64 = 0x0;
 const XHCI_PORTPMSC: u64 = 0x4;
@@ -381,7 +382,7 @@ pub fn build_cap_ops(xhci_dev: &Arc<Mutex<XhciDevice>>) -> RegionOps {
             }
             XHCI_CAP_REG_HCSPARAMS3 => 0x0,
             XHCI_CAP_REG_HCCPARAMS1 => {
-                0x8 << CAP_HCCP_EXCP_SHIFT | (0 << CAP_HCCP_MPSAS_SHIFT) | CAP_HCCP_AC64
+                0x8 << CAP_HCCP_EXCP_SHIFT | (MAX_PSTREAMS << CAP_HCCP_MPSAS_SHIFT) | CAP_HCCP_AC64
             }
             XHCI_CAP_REG_DBOFF => XHCI_OFF_DOORBELL,
             XHCI_CAP_REG_RTSOFF => XHCI_OFF_RUNTIME,
@@ -714,7 +715,8 @@ pub fn build_doorbell_ops(xhci_dev: &Arc<Mutex<XhciDevice>>) -> RegionOps {
             }
         } else {
             let ep_id = value & DB_TARGET_MASK;
-            if let Err(e) = xhci.kick_endpoint(slot_id, ep_id) {
+            let stream_id = value >> DB_STREAM_ID_SHIFT;
+            if let Err(e) = xhci.kick_endpoint(slot_id, ep_id, stream_id) {
                 error!("Failed to kick endpoint: {:?}", e);
                 xhci.host_controller_error();
                 return false;
@@ -20,7 +20,9 @@ use byteorder::{ByteOrder, LittleEndian};
 use log::debug;
 
 use super::super::UsbError;
-use super::xhci_controller::{dma_read_u32, dma_write_u32, DwordOrder, XhciEpCtx};
+use super::xhci_controller::{
+    dma_read_u32, dma_write_u32, DwordOrder, XhciEpCtx, XhciStreamCtx, STREAM_CTX_SCT_MASK,
+};
 use super::{TRBType, TRB_C, TRB_LK_TC, TRB_SIZE, TRB_TR_CH, TRB_TYPE_MASK, TRB_TYPE_SHIFT};
 use crate::usb::xhci::xhci_controller::dma_read_bytes;
 
@@ -116,6 +118,9 @@ pub struct XhciTransferRing {
     /// Consumer Cycle State
     pub ccs: AtomicBool,
     pub output_ctx_addr: Arc<AtomicU64>,
+    /// Address of the stream context if it is the transfer ring of a stream. The dequeue
+    /// pointer is saved in the stream context instead of the endpoint context.
+    pub stream_ctx_addr: Option<u64>,
 }
 
 impl XhciTransferRing {
@@ -125,6 +130,19 @@ impl XhciTransferRing {
             dequeue: AtomicU64::new(0),
             ccs: AtomicBool::new(true),
             output_ctx_addr: addr.clone(),
+            stream_ctx_addr: None,
+        }
+    }
+
+    /// Create the transfer ring of the stream whose context is at `stream_ctx_addr`.
+    pub fn new_stream(
+        mem: &Arc<AddressSpace>,
+        addr: &Arc<AtomicU64>,
+        stream_ctx_addr: u64,
+    ) -> Self {
+        Self {
+            stream_ctx_addr: Some(stream_ctx_addr),
+            ..Self::new(mem, addr)
         }
     }
 
@@ -201,6 +219,17 @@ impl XhciTransferRing {
 
     /// Refresh dequeue pointer to output context.
     pub fn refresh_dequeue_ptr(&self) -> Result<()> {
+        if let Some(addr) = self.stream_ctx_addr {
+            let mut stream_ctx = XhciStreamCtx::default();
+            dma_read_u32(&self.mem, GuestAddress(addr), stream_ctx.as_mut_dwords())?;
+            let dequeue = self.get_dequeue_ptr();
+            stream_ctx.deq_lo = dequeue as u32
+                | (stream_ctx.deq_lo & STREAM_CTX_SCT_MASK)
+                | self.get_cycle_bit() as u32;
+            stream_ctx.deq_hi = (dequeue >> 32) as u32;
+            dma_write_u32(&self.mem, GuestAddress(addr), stream_ctx.as_dwords())?;
+            return Ok(());
+        }
         let mut ep_ctx = XhciEpCtx::default();
         let output_addr = self.output_ctx_addr.load(Ordering::Acquire);
         dma_read_u32(&self.mem, GuestAddress(output_addr), ep_ctx.as_mut_dwords())?;
@@ -210,6 +239,10 @@ impl XhciTransferRing {
     }
 
     pub fn update_dequeue_to_ctx(&self, ep_ctx: &mut XhciEpCtx) {
+        // The dequeue pointer of endpoint context is the stream context array when streams are used.
+        if self.stream_ctx_addr.is_some() {
+            return;
+        }
         let dequeue = self.get_dequeue_ptr();
         ep_ctx.deq_lo = dequeue as u32 | self.get_cycle_bit() as u32;
         ep_ctx.deq_hi = (dequeue >> 32) as u32;
@@ -19,6 +19,7 @@ mod vm_state;
 pub use crate::error::MachineError;
 use std::collections::{BTreeMap, HashMap};
 use std::fs::{remove_file, File};
+use std::io::{Read, Write};
 use std::net::TcpListener;
 use std::ops::Deref;
 use std::os::unix::net::UnixListener;
@@ -57,10 +58,12 @@ use devices::InterruptController;
 
 #[cfg(not(target_env = "musl"))]
 use devices::usb::{
-    camera::UsbCamera, keyboard::UsbKeyboard, storage::UsbStorage, tablet::UsbTablet,
-    usbhost::UsbHost, xhci::xhci_pci::XhciPciDevice, UsbDeviceOps,
+    audio::UsbAudio, camera::UsbCamera, ehci::EhciPciDevice, hub::UsbHub, keyboard::UsbKeyboard,
+    serial::UsbSerial, storage::UsbStorage, tablet::UsbTablet, uhci::UhciPciDevice,
+    usbhost::UsbHost, usbredir::UsbRedir, xhci::xhci_pci::XhciPciDevice, UsbDeviceOps,
 };
-use devices::ScsiDisk::{ScsiDevice, SCSI_TYPE_DISK, SCSI_TYPE_ROM};
+use devices::ScsiDisk::{ScsiDevice, SCSI_TYPE_DISK, SCSI_TYPE_NOT_PRESENT, SCSI_TYPE_ROM};
+use devices::ScsiGeneric::ScsiPassthroughMode;
 use hypervisor::kvm::KVM_FDS;
 use machine_manager::config::{
     complete_numa_node, get_multi_function, get_pci_bdf, parse_balloon, parse_blk, parse_demo_dev,
@@ -73,8 +76,9 @@ use machine_manager::config::{
 };
 #[cfg(not(target_env = "musl"))]
 use machine_manager::config::{
-    parse_gpu, parse_usb_camera, parse_usb_host, parse_usb_keyboard, parse_usb_storage,
-    parse_usb_tablet, parse_xhci,
+    parse_ehci, parse_gpu, parse_sound, parse_uhci, parse_usb_audio, parse_usb_camera,
+    parse_usb_host, parse_usb_hub, parse_usb_keyboard, parse_usb_redir, parse_usb_serial,
+    parse_usb_storage, parse_usb_tablet, parse_xhci,
 };
 use machine_manager::machine::{KvmVmState, MachineInterface};
 use migration::MigrationManager;
@@ -89,15 +93,17 @@ use util::{
     seccomp::{BpfRule, SeccompOpt, SyscallFilter},
 };
 use vfio::{VfioDevice, VfioPciDevice};
-#[cfg(not(target_env = "musl"))]
-use virtio::Gpu;
 use virtio::{
     balloon_allow_list, find_port_by_nr, get_max_nr, vhost, Balloon, Block, BlockState, Rng,
     RngState,
     ScsiCntlr::{scsi_cntlr_create_scsi_bus, ScsiCntlr},
-    Serial, SerialPort, VhostKern, VhostUser, VirtioDevice, VirtioMmioDevice, VirtioMmioState,
-    VirtioNetState, VirtioPciDevice, VirtioSerialState, VIRTIO_TYPE_CONSOLE,
+    Serial, SerialPort, VhostKern, VhostUser,
+    VhostUser::VhostUserState,
+    VirtioDevice, VirtioMmioDevice, VirtioMmioState, VirtioNetState, VirtioPciDevice,
+    VirtioSerialState, VIRTIO_TYPE_CONSOLE,
 };
+#[cfg(not(target_env = "musl"))]
+use virtio::{Gpu, GpuState, Sound};
 
 pub trait MachineOps {
     fn build_smbios(
@@ -362,8 +368,8 @@ pub trait MachineOps {
 
     fn get_numa_nodes(&self) -> &Option<NumaNodes>;
 
-    /// Get migration mode and path from VM config. There are four modes in total:
-    /// Tcp, Unix, File and Unknown.
+    /// Get migration mode and path from VM config. There are six modes in total:
+    /// Tcp, Unix, Fd, Exec, File and Unknown.
     fn get_migrate_info(&self) -> Incoming;
 
     /// Add net device.
@@ -589,6 +595,10 @@ pub trait MachineOps {
             let virtio_mmio_device = VirtioMmioDevice::new(&sys_mem, device);
             self.realize_virtio_mmio_device(virtio_mmio_device)
                 .with_context(|| "Failed to add vhost user fs device")?;
+            MigrationManager::add_blocker(
+                &id_clone,
+                "virtio-mmio vhost-user-fs doesn't support migration",
+            );
         } else if cfg_args.contains("vhost-user-fs-pci") {
             let device = Arc::new(Mutex::new(vhost::user::Fs::new(
                 dev_cfg,
@@ -599,6 +609,11 @@ pub trait MachineOps {
             let multi_func = get_multi_function(cfg_args)?;
             let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
 
+            MigrationManager::register_device_instance(
+                VhostUserState::descriptor(),
+                device.clone(),
+                &id_clone,
+            );
             let mut vitio_pci_device =
                 VirtioPciDevice::new(id_clone, devfn, sys_mem, device, parent_bus, multi_func);
             vitio_pci_device.enable_need_irqfd();
@@ -659,6 +674,22 @@ pub trait MachineOps {
         Ok(port.is_some())
     }
 
+    #[cfg(not(target_env = "musl"))]
+    fn check_id_existed_in_ehci(&mut self, id: &str) -> Result<bool> {
+        let vm_config = self.get_vm_config();
+        let locked_vmconfig = vm_config.lock().unwrap();
+        let parent_dev = self
+            .get_pci_dev_by_id_and_type(&locked_vmconfig, None, "usb-ehci")
+            .with_context(|| "Can not find parent device from pci bus")?;
+        let locked_parent_dev = parent_dev.lock().unwrap();
+        let ehci_pci = locked_parent_dev
+            .as_any()
+            .downcast_ref::<EhciPciDevice>()
+            .with_context(|| "PciDevOps can not downcast to EhciPciDevice")?;
+        let port = ehci_pci.ehci.lock().unwrap().find_port_by_id(id);
+        Ok(port.is_some())
+    }
+
     fn check_device_id_existed(&mut self, name: &str) -> Result<()> {
         // If there is no pci bus, skip the id check, such as micro vm.
         if let Ok(pci_host) = self.get_pci_host() {
@@ -673,6 +704,10 @@ pub trait MachineOps {
             if self.check_id_existed_in_xhci(name).unwrap_or_default() {
                 bail!("Device id {} existed in xhci", name);
             }
+            #[cfg(not(target_env = "musl"))]
+            if self.check_id_existed_in_ehci(name).unwrap_or_default() {
+                bail!("Device id {} existed in ehci", name);
+            }
         }
         Ok(())
     }
@@ -826,17 +861,16 @@ pub trait MachineOps {
         vm_config: &mut VmConfig,
         cfg_args: &str,
         scsi_type: u32,
+        passthrough: Option<ScsiPassthroughMode>,
     ) -> Result<()> {
         let device_cfg = parse_scsi_device(vm_config, cfg_args)?;
         if let Some(bootindex) = device_cfg.boot_index {
             self.check_bootindex(bootindex)
                 .with_context(|| "Failed to add scsi device for invalid bootindex")?;
         }
-        let device = Arc::new(Mutex::new(ScsiDevice::new(
-            device_cfg.clone(),
-            scsi_type,
-            self.get_drive_files(),
-        )));
+        let mut scsi_dev = ScsiDevice::new(device_cfg.clone(), scsi_type, self.get_drive_files());
+        scsi_dev.passthrough = passthrough;
+        let device = Arc::new(Mutex::new(scsi_dev));
 
         let pci_dev = self
             .get_pci_dev_by_id_and_type(vm_config, Some(&device_cfg.cntlr), "virtio-scsi-pci")
@@ -900,10 +934,16 @@ pub trait MachineOps {
                     self.get_sys_mem(),
                 )))
             } else {
-                Arc::new(Mutex::new(VhostUser::Net::new(
+                let device = Arc::new(Mutex::new(VhostUser::Net::new(
                     &device_cfg,
                     self.get_sys_mem(),
-                )))
+                )));
+                MigrationManager::register_device_instance(
+                    VhostUserState::descriptor(),
+                    device.clone(),
+                    &device_cfg.id,
+                );
+                device
             }
         } else {
             let device = Arc::new(Mutex::new(virtio::Net::new(device_cfg.clone())));
@@ -928,10 +968,15 @@ pub trait MachineOps {
             MAX_VIRTIO_QUEUE,
         ));
         let device_cfg = parse_vhost_user_blk_pci(vm_config, cfg_args, queues_auto)?;
-        let device: Arc<Mutex<dyn VirtioDevice>> = Arc::new(Mutex::new(VhostUser::Block::new(
+        let device = Arc::new(Mutex::new(VhostUser::Block::new(
             &device_cfg,
             self.get_sys_mem(),
         )));
+        MigrationManager::register_device_instance(
+            VhostUserState::descriptor(),
+            device.clone(),
+            &device_cfg.id,
+        );
         let pci_dev = self
             .add_virtio_pci_device(&device_cfg.id, &bdf, device.clone(), multi_func, true)
             .with_context(|| {
@@ -974,6 +1019,7 @@ pub trait MachineOps {
             self.get_sys_mem().clone(),
         );
         VfioPciDevice::realize(vfio_pci).with_context(|| "Failed to realize vfio-pci device.")?;
+        MigrationManager::add_blocker(id, "vfio-pci device doesn't support migration");
         Ok(())
     }
 
@@ -998,6 +1044,17 @@ pub trait MachineOps {
         let multi_func = get_multi_function(cfg_args)?;
         let device_cfg = parse_gpu(cfg_args)?;
         let device = Arc::new(Mutex::new(Gpu::new(device_cfg.clone())));
+        self.add_virtio_pci_device(&device_cfg.id, &bdf, device.clone(), multi_func, false)?;
+        MigrationManager::register_device_instance(GpuState::descriptor(), device, &device_cfg.id);
+        Ok(())
+    }
+
+    #[cfg(not(target_env = "musl"))]
+    fn add_virtio_pci_sound(&mut self, cfg_args: &str) -> Result<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let multi_func = get_multi_function(cfg_args)?;
+        let device_cfg = parse_sound(cfg_args)?;
+        let device = Arc::new(Mutex::new(Sound::new(device_cfg.clone())));
         self.add_virtio_pci_device(&device_cfg.id, &bdf, device, multi_func, false)?;
         Ok(())
     }
@@ -1211,6 +1268,63 @@ pub trait MachineOps {
         Ok(())
     }
 
+    /// Add usb ehci controller.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg_args` - EHCI Configuration.
+    #[cfg(not(target_env = "musl"))]
+    fn add_usb_ehci(&mut self, cfg_args: &str) -> Result<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let device_cfg = parse_ehci(cfg_args)?;
+        let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+        let multi_func = device_cfg.multifunction;
+
+        let pcidev = EhciPciDevice::new(
+            &device_cfg,
+            devfn,
+            parent_bus,
+            self.get_sys_mem(),
+            multi_func,
+        );
+
+        pcidev
+            .realize()
+            .with_context(|| "Failed to realize usb ehci device")?;
+        Ok(())
+    }
+
+    /// Add usb uhci companion controller of the ehci controller.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - UHCI Configuration.
+    #[cfg(not(target_env = "musl"))]
+    fn add_usb_uhci(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let device_cfg = parse_uhci(cfg_args)?;
+        let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+
+        let parent_dev = self
+            .get_pci_dev_by_id_and_type(vm_config, Some(&device_cfg.masterbus), "usb-ehci")
+            .with_context(|| format!("Can not find ehci controller {}", device_cfg.masterbus))?;
+        let locked_parent_dev = parent_dev.lock().unwrap();
+        let ehci = locked_parent_dev
+            .as_any()
+            .downcast_ref::<EhciPciDevice>()
+            .with_context(|| "PciDevOps can not downcast to EhciPciDevice")?
+            .ehci
+            .clone();
+        drop(locked_parent_dev);
+
+        let pcidev = UhciPciDevice::new(&device_cfg, devfn, parent_bus, self.get_sys_mem(), &ehci)?;
+        pcidev
+            .realize()
+            .with_context(|| "Failed to realize usb uhci device")?;
+        Ok(())
+    }
+
     /// Add scream sound based on ivshmem.
     ///
     /// # Arguments
@@ -1283,52 +1397,77 @@ pub trait MachineOps {
         None
     }
 
-    /// Attach usb device to xhci controller.
+    /// Attach usb device to the usb controller, xhci is preferred if both
+    /// xhci and ehci exist.
     ///
     /// # Arguments
     ///
     /// * `vm_config` - VM configuration.
     /// * `usb_dev` - Usb device.
     #[cfg(not(target_env = "musl"))]
-    fn attach_usb_to_xhci_controller(
+    fn attach_usb_to_controller(
         &mut self,
         vm_config: &mut VmConfig,
         usb_dev: Arc<Mutex<dyn UsbDeviceOps>>,
     ) -> Result<()> {
+        if let Some(parent_dev) = self.get_pci_dev_by_id_and_type(vm_config, None, "nec-usb-xhci") {
+            let locked_parent_dev = parent_dev.lock().unwrap();
+            let xhci_pci = locked_parent_dev
+                .as_any()
+                .downcast_ref::<XhciPciDevice>()
+                .with_context(|| "PciDevOps can not downcast to XhciPciDevice")?;
+            xhci_pci.attach_device(&(usb_dev))?;
+            return Ok(());
+        }
+
         let parent_dev = self
-            .get_pci_dev_by_id_and_type(vm_config, None, "nec-usb-xhci")
-            .with_context(|| "Can not find parent device from pci bus")?;
+            .get_pci_dev_by_id_and_type(vm_config, None, "usb-ehci")
+            .with_context(|| "Can not find usb controller from pci bus")?;
         let locked_parent_dev = parent_dev.lock().unwrap();
-        let xhci_pci = locked_parent_dev
+        let ehci_pci = locked_parent_dev
             .as_any()
-            .downcast_ref::<XhciPciDevice>()
-            .with_context(|| "PciDevOps can not downcast to XhciPciDevice")?;
-        xhci_pci.attach_device(&(usb_dev))?;
+            .downcast_ref::<EhciPciDevice>()
+            .with_context(|| "PciDevOps can not downcast to EhciPciDevice")?;
+        ehci_pci.attach_device(&(usb_dev))?;
 
         Ok(())
     }
 
-    /// Detach usb device from xhci controller.
+    /// Detach usb device from the usb controller.
     ///
     /// # Arguments
     ///
     /// * `vm_config` - VM configuration.
     /// * `id` - id of the usb device.
     #[cfg(not(target_env = "musl"))]
-    fn detach_usb_from_xhci_controller(
-        &mut self,
-        vm_config: &mut VmConfig,
-        id: String,
-    ) -> Result<()> {
+    fn detach_usb_from_controller(&mut self, vm_config: &mut VmConfig, id: String) -> Result<()> {
+        if let Some(parent_dev) = self.get_pci_dev_by_id_and_type(vm_config, None, "nec-usb-xhci") {
+            let locked_parent_dev = parent_dev.lock().unwrap();
+            let xhci_pci = locked_parent_dev
+                .as_any()
+                .downcast_ref::<XhciPciDevice>()
+                .with_context(|| "PciDevOps can not downcast to XhciPciDevice")?;
+            if xhci_pci
+                .xhci
+                .lock()
+                .unwrap()
+                .find_usb_port_by_id(&id)
+                .is_some()
+            {
+                xhci_pci.detach_device(id)?;
+                return Ok(());
+            }
+        }
+
         let parent_dev = self
-            .get_pci_dev_by_id_and_type(vm_config, None, "nec-usb-xhci")
-            .with_context(|| "Can not find parent device from pci bus")?;
+            .get_pci_dev_by_id_and_type(vm_config, None, "usb-ehci")
+            .with_context(|| format!("Failed to detach device: id {} not found", id))?;
         let locked_parent_dev = parent_dev.lock().unwrap();
-        let xhci_pci = locked_parent_dev
+        let ehci_pci = locked_parent_dev
             .as_any()
-            .downcast_ref::<XhciPciDevice>()
-            .with_context(|| "PciDevOps can not downcast to XhciPciDevice")?;
-        xhci_pci.detach_device(id)?;
+            .downcast_ref::<EhciPciDevice>()
+            .with_context(|| "PciDevOps can not downcast to EhciPciDevice")?;
+        ehci_pci.detach_device(id)?;
 
         Ok(())
     }
@@ -1346,7 +1485,7 @@ pub trait MachineOps {
         let kbd = keyboard
             .realize()
             .with_context(|| "Failed to realize usb keyboard device")?;
-        self.attach_usb_to_xhci_controller(vm_config, kbd)?;
+        self.attach_usb_to_controller(vm_config, kbd)?;
         Ok(())
     }
 
@@ -1364,7 +1503,7 @@ pub trait MachineOps {
             .realize()
             .with_context(|| "Failed to realize usb tablet device")?;
 
-        self.attach_usb_to_xhci_controller(vm_config, tbt)?;
+        self.attach_usb_to_controller(vm_config, tbt)?;
 
         Ok(())
     }
@@ -1380,12 +1519,12 @@ pub trait MachineOps {
         let camera = UsbCamera::new(device_cfg)?;
         let camera = camera.realize()?;
 
-        self.attach_usb_to_xhci_controller(vm_config, camera)?;
+        self.attach_usb_to_controller(vm_config, camera)?;
 
         Ok(())
     }
 
-    /// Add usb storage.
+    /// Add usb storage, which uses UAS when the driver is "usb-uas".
     ///
     /// # Arguments
     ///
@@ -1398,7 +1537,7 @@ pub trait MachineOps {
             .realize()
             .with_context(|| "Failed to realize usb storage device")?;
 
-        self.attach_usb_to_xhci_controller(vm_config, stg)?;
+        self.attach_usb_to_controller(vm_config, stg)?;
 
         Ok(())
     }
@@ -1417,7 +1556,81 @@ pub trait MachineOps {
             .realize()
             .with_context(|| "Failed to realize usb host device")?;
 
-        self.attach_usb_to_xhci_controller(vm_config, usbhost)?;
+        self.attach_usb_to_controller(vm_config, usbhost)?;
+
+        Ok(())
+    }
+
+    /// Add usb redirection device.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg_args` - USB Redirection Configuration.
+    #[cfg(not(target_env = "musl"))]
+    fn add_usb_redir(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let device_cfg = parse_usb_redir(vm_config, cfg_args)?;
+        let usbredir = UsbRedir::new(device_cfg);
+
+        let usbredir = usbredir
+            .realize()
+            .with_context(|| "Failed to realize usb redirection device")?;
+
+        self.attach_usb_to_controller(vm_config, usbredir)?;
+
+        Ok(())
+    }
+
+    /// Add usb audio device.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg_args` - USB Audio Configuration.
+    #[cfg(not(target_env = "musl"))]
+    fn add_usb_audio(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let device_cfg = parse_usb_audio(cfg_args)?;
+        let audio = UsbAudio::new(device_cfg);
+
+        let audio = audio
+            .realize()
+            .with_context(|| "Failed to realize usb audio device")?;
+
+        self.attach_usb_to_controller(vm_config, audio)?;
+
+        Ok(())
+    }
+
+    /// Add usb hub.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg_args` - USB Hub Configuration.
+    #[cfg(not(target_env = "musl"))]
+    fn add_usb_hub(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let device_cfg = parse_usb_hub(cfg_args)?;
+        let hub = UsbHub::new(device_cfg);
+
+        let hub = hub.realize().with_context(|| "Failed to realize usb hub")?;
+
+        self.attach_usb_to_controller(vm_config, hub)?;
+
+        Ok(())
+    }
+
+    /// Add usb serial device.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg_args` - USB Serial Configuration.
+    #[cfg(not(target_env = "musl"))]
+    fn add_usb_serial(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let device_cfg = parse_usb_serial(vm_config, cfg_args)?;
+        let serial = UsbSerial::new(device_cfg);
+
+        let serial = serial
+            .realize()
+            .with_context(|| "Failed to realize usb serial device")?;
+
+        self.attach_usb_to_controller(vm_config, serial)?;
 
         Ok(())
     }
@@ -1466,10 +1679,27 @@ pub trait MachineOps {
                     self.add_virtio_pci_scsi(vm_config, cfg_args)?;
                 }
                 "scsi-hd" => {
-                    self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_DISK)?;
+                    self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_DISK, None)?;
                 }
                 "scsi-cd" => {
-                    self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_ROM)?;
+                    self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_ROM, None)?;
+                }
+                // The scsi type of passthrough device is got from the host device in realize.
+                "scsi-generic" => {
+                    self.add_scsi_device(
+                        vm_config,
+                        cfg_args,
+                        SCSI_TYPE_NOT_PRESENT,
+                        Some(ScsiPassthroughMode::Generic),
+                    )?;
+                }
+                "scsi-block" => {
+                    self.add_scsi_device(
+                        vm_config,
+                        cfg_args,
+                        SCSI_TYPE_NOT_PRESENT,
+                        Some(ScsiPassthroughMode::Block),
+                    )?;
                 }
                 "virtio-net-device" => {
                     self.add_virtio_mmio_net(vm_config, cfg_args)?;
@@ -1512,6 +1742,14 @@ pub trait MachineOps {
                     self.add_usb_xhci(cfg_args)?;
                 }
                 #[cfg(not(target_env = "musl"))]
+                "usb-ehci" => {
+                    self.add_usb_ehci(cfg_args)?;
+                }
+                #[cfg(not(target_env = "musl"))]
+                "ich9-usb-uhci" => {
+                    self.add_usb_uhci(vm_config, cfg_args)?;
+                }
+                #[cfg(not(target_env = "musl"))]
                 "usb-kbd" => {
                     self.add_usb_keyboard(vm_config, cfg_args)?;
                 }
@@ -1524,7 +1762,7 @@ pub trait MachineOps {
                     self.add_usb_camera(vm_config, cfg_args)?;
                 }
                 #[cfg(not(target_env = "musl"))]
-                "usb-storage" => {
+                "usb-storage" | "usb-uas" => {
                     self.add_usb_storage(vm_config, cfg_args)?;
                 }
                 #[cfg(not(target_env = "musl"))]
@@ -1532,10 +1770,30 @@ pub trait MachineOps {
                     self.add_usb_host(vm_config, cfg_args)?;
                 }
                 #[cfg(not(target_env = "musl"))]
+                "usb-redir" => {
+                    self.add_usb_redir(vm_config, cfg_args)?;
+                }
+                #[cfg(not(target_env = "musl"))]
+                "usb-hub" => {
+                    self.add_usb_hub(vm_config, cfg_args)?;
+                }
+                #[cfg(not(target_env = "musl"))]
+                "usb-serial" => {
+                    self.add_usb_serial(vm_config, cfg_args)?;
+                }
+                #[cfg(not(target_env = "musl"))]
+                "usb-audio" => {
+                    self.add_usb_audio(vm_config, cfg_args)?;
+                }
+                #[cfg(not(target_env = "musl"))]
                 "virtio-gpu-pci" => {
                     self.add_virtio_pci_gpu(cfg_args)?;
                 }
                 #[cfg(not(target_env = "musl"))]
+                "virtio-sound-pci" => {
+                    self.add_virtio_pci_sound(cfg_args)?;
+                }
+                #[cfg(not(target_env = "musl"))]
                 "ramfb" => {
                     self.add_ramfb(cfg_args)?;
                 }
@@ -1626,7 +1884,7 @@ pub trait MachineOps {
     ) -> Result<()> {
         let files = self.get_drive_files();
         let mut drive_files = files.lock().unwrap();
-        VmConfig::add_drive_file(&mut drive_files, id, path, read_only, direct)?;
+        VmConfig::add_drive_file(&mut drive_files, id, path, read_only, direct, false)?;
 
         // Lock the added file if VM is running.
         let drive_file = drive_files.get_mut(path).unwrap();
@@ -1654,7 +1912,12 @@ pub trait MachineOps {
             if drive_file.locked {
                 continue;
             }
-            lock_file(&drive_file.file, &drive_file.path, drive_file.read_only)?;
+            // Writable file shared with other processes only takes the shared lock.
+            lock_file(
+                &drive_file.file,
+                &drive_file.path,
+                drive_file.read_only || drive_file.share_rw,
+            )?;
             drive_file.locked = true;
         }
         Ok(())
@@ -1892,27 +2155,52 @@ fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Re
             let (mut sock, _) = listener.accept()?;
             remove_file(&path)?;
 
-            MigrationManager::recv_migration(&mut sock)
+            recv_incoming_migration(vm, &mut sock)
                 .with_context(|| "Failed to receive migration with unix mode")?;
-            vm.lock()
-                .unwrap()
-                .run(false)
-                .with_context(|| "Failed to start VM.")?;
-            MigrationManager::finish_migration(&mut sock)
-                .with_context(|| "Failed to finish migraton.")?;
         }
         MigrateMode::Tcp => {
+            let tls_creds = {
+                let locked_vm = vm.lock().unwrap();
+                let vm_config = locked_vm.get_vm_config();
+                let locked_config = vm_config.lock().unwrap();
+                match &locked_config.incoming_tls_creds {
+                    Some(id) => Some(
+                        locked_config
+                            .object
+                            .tls_object
+                            .get(id)
+                            .with_context(|| format!("tls-creds object {} is not found", id))?
+                            .clone(),
+                    ),
+                    None => None,
+                }
+            };
             let listener = TcpListener::bind(&path)?;
             let mut sock = listener.accept().map(|(stream, _)| stream)?;
 
-            MigrationManager::recv_migration(&mut sock)
-                .with_context(|| "Failed to receive migration with tcp mode")?;
-            vm.lock()
-                .unwrap()
-                .run(false)
-                .with_context(|| "Failed to start VM.")?;
-            MigrationManager::finish_migration(&mut sock)
-                .with_context(|| "Failed to finish migraton.")?;
+            match tls_creds {
+                Some(creds) => {
+                    let mut tls_sock = migration::tls::tls_server_stream(sock, &creds)?;
+                    recv_incoming_migration(vm, &mut tls_sock)
+                        .with_context(|| "Failed to receive migration with tls tcp mode")?;
+                }
+                None => {
+                    recv_incoming_migration(vm, &mut sock)
+                        .with_context(|| "Failed to receive migration with tcp mode")?;
+                }
+            }
+        }
+        MigrateMode::Fd => {
+            let mut file = migration::transport::fd_stream(&path)?;
+
+            recv_incoming_migration(vm, &mut file)
+                .with_context(|| "Failed to receive migration with fd mode")?;
+        }
+        MigrateMode::Exec => {
+            let mut stream = migration::transport::ExecStream::new(&path)?;
+
+            recv_incoming_migration(vm, &mut stream)
+                .with_context(|| "Failed to receive migration with exec mode")?;
         }
         MigrateMode::Unknown => {
             bail!("Unknown migration mode");
@@ -1929,6 +2217,24 @@ fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Re
     Ok(())
 }
 
+/// Receive migration from the connected stream and start VM.
+fn recv_incoming_migration<T>(
+    vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>,
+    sock: &mut T,
+) -> Result<()>
+where
+    T: Read + Write,
+{
+    MigrationManager::recv_migration(sock)?;
+    vm.lock()
+        .unwrap()
+        .run(false)
+        .with_context(|| "Failed to start VM.")?;
+    MigrationManager::finish_migration(sock).with_context(|| "Failed to finish migraton.")?;
+
+    Ok(())
+}
+
 fn coverage_allow_list(syscall_allow_list: &mut Vec<BpfRule>) {
     syscall_allow_list.extend(vec![
         BpfRule::new(libc::SYS_fcntl),
@@ -59,7 +59,7 @@ use cpu::{CPUBootConfig, CPUTopology, CpuLifecycleState, CpuTopology, CPU};
 use devices::legacy::PL031;
 #[cfg(target_arch = "x86_64")]
 use devices::legacy::SERIAL_ADDR;
-use devices::legacy::{FwCfgOps, Serial};
+use devices::legacy::{ringbuf_read, FwCfgOps, Serial};
 #[cfg(target_arch = "aarch64")]
 use devices::{ICGICConfig, ICGICv2Config, ICGICv3Config, InterruptController, GIC_IRQ_MAX};
 #[cfg(target_arch = "x86_64")]
@@ -1150,6 +1150,64 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    /// Light machine has no removable device.
+    fn eject(&self, _args: qmp_schema::EjectArgument) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("eject is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Light machine has no removable device.
+    fn blockdev_change_medium(&self, _args: qmp_schema::BlockdevChangeMediumArgument) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "blockdev-change-medium is not supported".to_string(),
+            ),
+            None,
+        )
+    }
+
+    /// Light machine has no display device.
+    fn screendump(&self, _args: qmp_schema::ScreendumpArgument) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Screendump is not supported".to_string()),
+            None,
+        )
+    }
+
+    fn record_start(&self, _args: qmp_schema::RecordStartArgument) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Recording is not supported".to_string()),
+            None,
+        )
+    }
+
+    fn record_stop(&self) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Recording is not supported".to_string()),
+            None,
+        )
+    }
+
+    fn set_password(&self, _args: qmp_schema::SetPasswordArgument) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "The service of VNC is not supported".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn expire_password(&self, _args: qmp_schema::ExpirePasswordArgument) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "The service of VNC is not supported".to_string(),
+            ),
+            None,
+        )
+    }
+
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
         // get slot of bus by addr or lun
         let mut slot = 0;
@@ -1365,6 +1423,17 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    fn ringbuf_read(&self, args: qmp_schema::RingbufReadArgument) -> Response {
+        let format = args.format.as_deref().unwrap_or("utf8");
+        match ringbuf_read(&args.device, args.size as usize, format) {
+            Ok(data) => Response::create_response(serde_json::to_value(data).unwrap(), None),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
     fn cameradev_add(&mut self, _args: qmp_schema::CameraDevAddArgument) -> Response {
         Response::create_error_response(
             qmp_schema::QmpErrorClass::GenericError(
@@ -1406,14 +1475,15 @@ impl MigrateInterface for LightMachine {
     fn migrate(&self, uri: String) -> Response {
         match parse_incoming_uri(&uri) {
             Ok((MigrateMode::File, path)) => migration::snapshot(path),
-            Ok((MigrateMode::Unix, _)) | Ok((MigrateMode::Tcp, _)) => {
-                Response::create_error_response(
-                    qmp_schema::QmpErrorClass::GenericError(
-                        "MicroVM does not support migration".to_string(),
-                    ),
-                    None,
-                )
-            }
+            Ok((MigrateMode::Unix, _))
+            | Ok((MigrateMode::Tcp, _))
+            | Ok((MigrateMode::Fd, _))
+            | Ok((MigrateMode::Exec, _)) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(
+                    "MicroVM does not support migration".to_string(),
+                ),
+                None,
+            ),
             _ => Response::create_error_response(
                 qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
                 None,
@@ -1424,6 +1494,18 @@ impl MigrateInterface for LightMachine {
     fn query_migrate(&self) -> Response {
         migration::query_migrate()
     }
+
+    fn migrate_set_parameters(&self, args: qmp_schema::MigrateParameters) -> Response {
+        migration::set_migrate_parameters(args)
+    }
+
+    fn query_migrate_parameters(&self) -> Response {
+        migration::query_migrate_parameters()
+    }
+
+    fn query_snapshots(&self, path: String) -> Response {
+        migration::query_snapshots(path)
+    }
 }
 
 impl MachineInterface for LightMachine {}
@@ -1229,6 +1229,8 @@ impl MigrateInterface for StdMachine {
             Ok((MigrateMode::File, path)) => migration::snapshot(path),
             Ok((MigrateMode::Unix, path)) => migration::migration_unix_mode(path),
             Ok((MigrateMode::Tcp, path)) => migration::migration_tcp_mode(path),
+            Ok((MigrateMode::Fd, name)) => migration::migration_fd_mode(name),
+            Ok((MigrateMode::Exec, cmd)) => migration::migration_exec_mode(cmd),
             _ => Response::create_error_response(
                 qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
                 None,
@@ -1243,6 +1245,18 @@ impl MigrateInterface for StdMachine {
     fn cancel_migrate(&self) -> Response {
         migration::cancel_migrate()
     }
+
+    fn migrate_set_parameters(&self, args: qmp_schema::MigrateParameters) -> Response {
+        migration::set_migrate_parameters(args)
+    }
+
+    fn query_migrate_parameters(&self) -> Response {
+        migration::query_migrate_parameters()
+    }
+
+    fn query_snapshots(&self, path: String) -> Response {
+        migration::query_snapshots(path)
+    }
 }
 
 impl MachineInterface for StdMachine {}
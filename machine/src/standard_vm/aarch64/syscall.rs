@@ -10,9 +10,11 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use devices::ScsiGeneric::SG_IO;
 use hypervisor::kvm::*;
 use util::seccomp::{BpfRule, SeccompCmpOpt};
 use util::tap::{TUNGETFEATURES, TUNSETIFF, TUNSETOFFLOAD, TUNSETQUEUE, TUNSETVNETHDRSZ};
+use util::userfaultfd::{UFFDIO_API, UFFDIO_REGISTER, UFFDIO_UNREGISTER, UFFDIO_WRITEPROTECT};
 use util::v4l2::{
     VIDIOC_DQBUF, VIDIOC_ENUM_FMT, VIDIOC_ENUM_FRAMEINTERVALS, VIDIOC_ENUM_FRAMESIZES,
     VIDIOC_G_FMT, VIDIOC_QBUF, VIDIOC_QUERYBUF, VIDIOC_QUERYCAP, VIDIOC_REQBUFS, VIDIOC_STREAMOFF,
@@ -61,8 +63,8 @@ const KVM_RUN: u32 = 0xae80;
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * aarch64-unknown-gnu: 98 syscalls
-/// * aarch64-unknown-musl: 60 syscalls
+/// * aarch64-unknown-gnu: 99 syscalls
+/// * aarch64-unknown-musl: 61 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 pub fn syscall_whitelist() -> Vec<BpfRule> {
     vec![
@@ -183,13 +185,13 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_pipe2),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_fcntl),
-        #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_memfd_create),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_ftruncate),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_futex),
         BpfRule::new(libc::SYS_fallocate),
+        BpfRule::new(libc::SYS_userfaultfd),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_getresuid),
         #[cfg(target_env = "gnu")]
@@ -249,6 +251,10 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETOFFLOAD() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETVNETHDRSZ() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETQUEUE() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_API() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_REGISTER() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_UNREGISTER() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_WRITEPROTECT() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_GSI_ROUTING() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_IRQFD() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VFIO_DEVICE_SET_IRQS() as u32)
@@ -288,6 +294,7 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, VIDIOC_S_PARM() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VIDIOC_ENUM_FRAMESIZES() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VIDIOC_ENUM_FRAMEINTERVALS() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, SG_IO() as u32)
 }
 
 fn madvise_rule() -> BpfRule {
@@ -27,7 +27,9 @@ use machine_manager::{config::get_cameradev_config, machine::MachineLifecycle};
 #[cfg(not(target_env = "musl"))]
 use ui::{
     input::{key_event, point_event},
-    vnc::qmp_query_vnc,
+    recorder::{record_start, record_stop},
+    screendump::{screendump, ScreendumpFormat},
+    vnc::{qmp_expire_password, qmp_query_vnc, qmp_set_password},
 };
 use util::aio::{AioEngine, WriteZeroesState};
 use util::loop_context::{read_fd, EventNotifier, NotifierCallback, NotifierOperation};
@@ -59,7 +61,10 @@ pub use anyhow::Result;
 use anyhow::{bail, Context};
 use block_backend::{qcow2::QCOW2_LIST, BlockStatus};
 use cpu::{CpuTopology, CPU};
-use devices::legacy::FwCfgOps;
+#[cfg(not(target_env = "musl"))]
+use devices::camera_backend::file::swap_file_camera_source;
+use devices::legacy::{ringbuf_read, FwCfgOps};
+use devices::ScsiDisk::get_removable_device;
 use machine_manager::config::{
     get_chardev_config, get_netdev_config, get_pci_df, memory_unit_conversion, BlkDevConfig,
     ChardevType, ConfigCheck, DiskFormat, DriveConfig, ExBool, NetworkInterfaceConfig, NumaNode,
@@ -74,7 +79,9 @@ use util::byte_code::ByteCode;
 use virtio::{
     qmp_balloon, qmp_query_balloon, Block, BlockState,
     ScsiCntlr::{scsi_cntlr_create_scsi_bus, ScsiCntlr},
-    VhostKern, VhostUser, VirtioDevice, VirtioNetState, VirtioPciDevice,
+    VhostKern, VhostUser,
+    VhostUser::VhostUserState,
+    VirtioDevice, VirtioNetState, VirtioPciDevice,
 };
 
 #[cfg(target_arch = "aarch64")]
@@ -919,8 +926,9 @@ impl StdMachine {
         drop(locked_vmconfig);
 
         let blk = Arc::new(Mutex::new(VhostUser::Block::new(&dev, self.get_sys_mem())));
-        self.add_virtio_pci_device(&args.id, pci_bdf, blk, multifunction, true)
+        self.add_virtio_pci_device(&args.id, pci_bdf, blk.clone(), multifunction, true)
             .with_context(|| "Failed to add vhost user blk pci device")?;
+        MigrationManager::register_device_instance(VhostUserState::descriptor(), blk, &args.id);
 
         Ok(())
     }
@@ -996,7 +1004,13 @@ impl StdMachine {
                 if dev.vhost_type == Some(String::from("vhost-kernel")) {
                     Arc::new(Mutex::new(VhostKern::Net::new(&dev, self.get_sys_mem())))
                 } else {
-                    Arc::new(Mutex::new(VhostUser::Net::new(&dev, self.get_sys_mem())))
+                    let net = Arc::new(Mutex::new(VhostUser::Net::new(&dev, self.get_sys_mem())));
+                    MigrationManager::register_device_instance(
+                        VhostUserState::descriptor(),
+                        net.clone(),
+                        &args.id,
+                    );
+                    net
                 };
             self.add_virtio_pci_device(&args.id, pci_bdf, net, multifunction, true)
                 .with_context(|| "Failed to add vhost-kernel/vhost-user net device")?;
@@ -1056,6 +1070,21 @@ impl StdMachine {
 
                 self.add_usb_host(&mut locked_vmconfig, &cfg_args)?;
             }
+            "usb-redir" => {
+                if let Some(chardev) = &args.chardev {
+                    cfg_args = format!("{},chardev={}", cfg_args, chardev);
+                }
+                self.add_usb_redir(&mut locked_vmconfig, &cfg_args)?;
+            }
+            "usb-hub" => {
+                self.add_usb_hub(&mut locked_vmconfig, &cfg_args)?;
+            }
+            "usb-serial" => {
+                if let Some(chardev) = &args.chardev {
+                    cfg_args = format!("{},chardev={}", cfg_args, chardev);
+                }
+                self.add_usb_serial(&mut locked_vmconfig, &cfg_args)?;
+            }
             _ => {
                 bail!("Invalid usb device driver '{}'", driver);
             }
@@ -1068,7 +1097,7 @@ impl StdMachine {
     fn handle_unplug_usb_request(&mut self, id: String) -> Result<()> {
         let vm_config = self.get_vm_config();
         let mut locked_vmconfig = vm_config.lock().unwrap();
-        self.detach_usb_from_xhci_controller(&mut locked_vmconfig, id)?;
+        self.detach_usb_from_controller(&mut locked_vmconfig, id)?;
 
         Ok(())
     }
@@ -1222,6 +1251,129 @@ impl DeviceInterface for StdMachine {
         )
     }
 
+    fn screendump(&self, args: qmp_schema::ScreendumpArgument) -> Response {
+        #[cfg(not(target_env = "musl"))]
+        {
+            let format = match args
+                .format
+                .as_deref()
+                .unwrap_or("png")
+                .parse::<ScreendumpFormat>()
+            {
+                Ok(f) => f,
+                Err(e) => {
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                        None,
+                    );
+                }
+            };
+            match screendump(&args.filename, args.device.as_deref(), format) {
+                Ok(()) => Response::create_empty_response(),
+                Err(e) => Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                    None,
+                ),
+            }
+        }
+        #[cfg(target_env = "musl")]
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Screendump is not supported".to_string()),
+            None,
+        )
+    }
+
+    fn record_start(&self, args: qmp_schema::RecordStartArgument) -> Response {
+        #[cfg(not(target_env = "musl"))]
+        match record_start(
+            &args.filename,
+            args.device.as_deref(),
+            args.max_size,
+            args.interval,
+        ) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+        #[cfg(target_env = "musl")]
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Recording is not supported".to_string()),
+            None,
+        )
+    }
+
+    fn record_stop(&self) -> Response {
+        #[cfg(not(target_env = "musl"))]
+        match record_stop() {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+        #[cfg(target_env = "musl")]
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Recording is not supported".to_string()),
+            None,
+        )
+    }
+
+    fn set_password(&self, args: qmp_schema::SetPasswordArgument) -> Response {
+        if args.protocol != "vnc" {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Unsupported protocol {}",
+                    args.protocol
+                )),
+                None,
+            );
+        }
+        #[cfg(not(target_env = "musl"))]
+        match qmp_set_password(&args.password, args.view_only.unwrap_or(false)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+        #[cfg(target_env = "musl")]
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "The service of VNC is not supported".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn expire_password(&self, args: qmp_schema::ExpirePasswordArgument) -> Response {
+        if args.protocol != "vnc" {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!(
+                    "Unsupported protocol {}",
+                    args.protocol
+                )),
+                None,
+            );
+        }
+        #[cfg(not(target_env = "musl"))]
+        match qmp_expire_password(&args.time) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+        #[cfg(target_env = "musl")]
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "The service of VNC is not supported".to_string(),
+            ),
+            None,
+        )
+    }
+
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
         if let Err(e) = self.check_device_id_existed(&args.id) {
             return Response::create_error_response(
@@ -1293,7 +1445,8 @@ impl DeviceInterface for StdMachine {
                 }
             }
             #[cfg(not(target_env = "musl"))]
-            "usb-kbd" | "usb-tablet" | "usb-camera" | "usb-host" => {
+            "usb-kbd" | "usb-tablet" | "usb-camera" | "usb-host" | "usb-redir" | "usb-hub"
+            | "usb-serial" => {
                 if let Err(e) = self.plug_usb_device(args.as_ref()) {
                     error!("{:?}", e);
                     return Response::create_error_response(
@@ -1358,6 +1511,7 @@ impl DeviceInterface for StdMachine {
                     let dev_id = locked_dev.name();
                     drop(locked_pci_host);
                     self.del_bootindex_devices(&dev_id);
+                    MigrationManager::del_blocker(&dev_id);
                     let vm_config = self.get_vm_config();
                     let mut locked_config = vm_config.lock().unwrap();
                     locked_config.del_device_by_id(device_id);
@@ -1452,6 +1606,92 @@ impl DeviceInterface for StdMachine {
         }
     }
 
+    fn eject(&self, args: qmp_schema::EjectArgument) -> Response {
+        let dev = match get_removable_device(&args.device) {
+            Some(dev) => dev,
+            None => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::DeviceNotFound(format!(
+                        "Removable device {} not found",
+                        args.device
+                    )),
+                    None,
+                );
+            }
+        };
+        let result = dev.lock().unwrap().eject(args.force);
+        match result {
+            Ok(path) => {
+                if let Some(path) = path {
+                    // It's safe to unwrap as the path has been registered.
+                    self.unregister_drive_file(&path).unwrap();
+                }
+                Response::create_empty_response()
+            }
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
+    fn blockdev_change_medium(&self, args: qmp_schema::BlockdevChangeMediumArgument) -> Response {
+        let format = match args
+            .format
+            .as_deref()
+            .unwrap_or("raw")
+            .parse::<DiskFormat>()
+        {
+            Ok(f) => f,
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                    None,
+                );
+            }
+        };
+        let dev = match get_removable_device(&args.device) {
+            Some(dev) => dev,
+            None => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::DeviceNotFound(format!(
+                        "Removable device {} not found",
+                        args.device
+                    )),
+                    None,
+                );
+            }
+        };
+
+        let mut locked_dev = dev.lock().unwrap();
+        let read_only = locked_dev.config.read_only;
+        // The old medium is kept if the new image file can't be opened.
+        let result = self
+            .register_drive_file(&args.device, &args.filename, read_only, false)
+            .and_then(|()| {
+                locked_dev
+                    .change_medium(&args.filename, format, args.force)
+                    .map_err(|e| {
+                        // It's safe to unwrap as the path has been registered.
+                        self.unregister_drive_file(&args.filename).unwrap();
+                        e
+                    })
+            });
+        match result {
+            Ok(old_path) => {
+                if let Some(path) = old_path {
+                    // It's safe to unwrap as the path has been registered.
+                    self.unregister_drive_file(&path).unwrap();
+                }
+                Response::create_empty_response()
+            }
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
     fn chardev_add(&mut self, args: qmp_schema::CharDevAddArgument) -> Response {
         let config = match get_chardev_config(args) {
             Ok(conf) => conf,
@@ -1494,6 +1734,17 @@ impl DeviceInterface for StdMachine {
         }
     }
 
+    fn ringbuf_read(&self, args: qmp_schema::RingbufReadArgument) -> Response {
+        let format = args.format.as_deref().unwrap_or("utf8");
+        match ringbuf_read(&args.device, args.size as usize, format) {
+            Ok(data) => Response::create_response(serde_json::to_value(data).unwrap(), None),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
     fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
         let config = match get_netdev_config(args) {
             Ok(conf) => conf,
@@ -1540,18 +1791,29 @@ impl DeviceInterface for StdMachine {
             }
         };
 
-        match self
-            .get_vm_config()
-            .lock()
-            .unwrap()
-            .add_cameradev_with_config(config)
-        {
-            Ok(()) => Response::create_empty_response(),
-            Err(e) => Response::create_error_response(
+        let vm_config = self.get_vm_config();
+        let mut locked_vmconfig = vm_config.lock().unwrap();
+        if let Err(e) = locked_vmconfig.add_cameradev_with_config(config.clone()) {
+            return Response::create_error_response(
                 qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                 None,
-            ),
+            );
+        }
+
+        // The file camera which is using the cameradev plays the new source.
+        #[cfg(not(target_env = "musl"))]
+        {
+            let id = config.id.as_ref().unwrap();
+            if let Err(e) = swap_file_camera_source(id, Some(&config)) {
+                // It's safe to unwrap as the cameradev has been added.
+                locked_vmconfig.del_cameradev_by_id(id).unwrap();
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                    None,
+                );
+            }
         }
+        Response::create_empty_response()
     }
 
     fn cameradev_del(&mut self, id: String) -> Response {
@@ -1561,7 +1823,14 @@ impl DeviceInterface for StdMachine {
             .unwrap()
             .del_cameradev_by_id(&id)
         {
-            Ok(()) => Response::create_empty_response(),
+            Ok(()) => {
+                // The file camera which is using the cameradev stops playing.
+                #[cfg(not(target_env = "musl"))]
+                if let Err(e) = swap_file_camera_source(&id, None) {
+                    error!("Failed to detach source of camera {}: {:?}", id, e);
+                }
+                Response::create_empty_response()
+            }
             Err(e) => Response::create_error_response(
                 qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                 None,
@@ -1967,6 +2236,7 @@ fn parse_blockdev(args: &BlockDevAddArgument) -> Result<DriveConfig> {
         format: DiskFormat::Raw,
         l2_cache_size: None,
         refcount_cache_size: None,
+        share_rw: false,
     };
     if args.cache.is_some() && !args.cache.as_ref().unwrap().direct.unwrap_or(true) {
         config.direct = false;
@@ -300,8 +300,10 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             Arg::with_name("incoming")
             .long("incoming")
             .value_name("<parameters>")
-            .help("\n\t\tdo the migration using tcp socket: -incoming tcp:<ip>:<port>; \
+            .help("\n\t\tdo the migration using tcp socket: -incoming tcp:<ip>:<port>[,tls-creds=<id>]; \
                    \n\t\tdo the migration using unix socket: -incoming unix:<socket path>; \
+                   \n\t\tdo the migration using inherited file descriptor: -incoming fd:<fd number>; \
+                   \n\t\tdo the migration using stdin/stdout of helper process: -incoming exec:<command>; \
                    \n\t\tdo the virtual machine snapshot: -incoming file:<file path>")
             .takes_value(true),
         )
@@ -619,8 +621,14 @@ pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<
             bail!("Argument \'mode\' of \'mon\' should be set to \'control\'.");
         }
 
-        if let Some(cfg) = vm_config.chardev.remove(&chardev) {
-            if let ChardevType::Socket {
+        if let Some(cfg) = vm_config.take_chardev(&chardev) {
+            if cfg.mux {
+                // Monitor shares the multiplexed chardev with other frontends.
+                if vm_config.monitor_chardev.is_some() {
+                    bail!("Only one multiplexed chardev can be used for monitor");
+                }
+                vm_config.monitor_chardev = Some(cfg);
+            } else if let ChardevType::Socket {
                 path,
                 server,
                 nowait,
@@ -641,7 +649,7 @@ pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<
         }
     }
 
-    if sock_paths.is_empty() {
+    if sock_paths.is_empty() && vm_config.monitor_chardev.is_none() {
         bail!("Please use \'-qmp\' or \'-mon\' to give a qmp path for Unix socket");
     }
     let mut listeners = Vec::new();
@@ -35,6 +35,8 @@ pub struct CameraDevConfig {
 pub enum CamBackendType {
     V4l2,
     Demo,
+    File,
+    TestPattern,
 }
 
 impl FromStr for CamBackendType {
@@ -51,7 +53,8 @@ impl FromStr for CamBackendType {
     }
 }
 
-pub const CAM_OPT_STR_BACKEND_TYPES: [&str; CamBackendType::COUNT] = ["v4l2", "demo"];
+pub const CAM_OPT_STR_BACKEND_TYPES: [&str; CamBackendType::COUNT] =
+    ["v4l2", "demo", "file", "testpattern"];
 
 impl CameraDevConfig {
     pub fn new() -> CameraDevConfig {
@@ -85,9 +88,18 @@ impl VmConfig {
         self.add_cameradev_with_config(camera_backend)
     }
 
-    fn camera_backend_repeated(&self, id: &str, path: &str, backend: CamBackendType) -> bool {
+    fn camera_backend_repeated(
+        &self,
+        id: &str,
+        path: &Option<String>,
+        backend: CamBackendType,
+    ) -> bool {
+        // Test pattern has no backend device, so it can be shared.
+        if backend == CamBackendType::TestPattern {
+            return false;
+        }
         for (key, cam) in self.camera_backend.iter() {
-            if key != id && cam.backend == backend && cam.path == Some(path.to_string()) {
+            if key != id && cam.backend == backend && &cam.path == path {
                 return true;
             }
         }
@@ -100,11 +112,11 @@ impl VmConfig {
             .id
             .clone()
             .with_context(|| "no id configured for cameradev")?;
-        let cameradev_path = conf
-            .path
-            .clone()
-            .with_context(|| "no path configured for cameradev")?;
+        let cameradev_path = conf.path.clone();
         let cameradev_backend = conf.backend;
+        if cameradev_path.is_none() && cameradev_backend != CamBackendType::TestPattern {
+            bail!("no path configured for cameradev");
+        }
 
         let cam = self.camera_backend.get(&cameradev_id);
 
@@ -135,6 +147,9 @@ impl ConfigCheck for CameraDevConfig {
     fn check(&self) -> Result<()> {
         // Note: backend has already been checked during args parsing.
         check_id(self.id.clone(), "cameradev")?;
+        if self.backend == CamBackendType::TestPattern {
+            return Ok(());
+        }
         check_camera_path(self.path.clone())
     }
 }
@@ -26,6 +26,10 @@ const MIN_GUEST_CID: u64 = 3;
 /// Default value of max ports for virtio-serial.
 const DEFAULT_SERIAL_PORTS_NUMBER: u32 = 31;
 
+/// Default and max size of ring buffer chardev.
+const DEFAULT_RINGBUF_SIZE: u64 = 64 * 1024;
+const MAX_RINGBUF_SIZE: u64 = 16 * 1024 * 1024;
+
 /// Character device options.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChardevType {
@@ -36,7 +40,21 @@ pub enum ChardevType {
         server: bool,
         nowait: bool,
     },
+    /// Tcp socket, with optional telnet negotiation. Client reconnects to the
+    /// server every `reconnect` seconds after disconnected, 0 means never.
+    TcpSocket {
+        host: String,
+        port: u16,
+        server: bool,
+        nowait: bool,
+        telnet: bool,
+        reconnect: u64,
+    },
     File(String),
+    /// Share clipboard between frontends and guest agent.
+    Clipboard,
+    /// Keep the latest output in a ring buffer of the given size.
+    Ringbuf(u64),
 }
 
 /// Config structure for virtio-serial-port.
@@ -59,11 +77,18 @@ impl ConfigCheck for VirtioSerialPort {
 pub struct ChardevConfig {
     pub id: String,
     pub backend: ChardevType,
+    /// Backend can be shared by multiple frontends, switched by escape keys.
+    pub mux: bool,
+    /// File which the output is also written to.
+    pub logfile: Option<String>,
 }
 
 impl ConfigCheck for ChardevConfig {
     fn check(&self) -> Result<()> {
         check_arg_too_long(&self.id, "chardev id")?;
+        if let ChardevType::TcpSocket { host, .. } = &self.backend {
+            check_arg_too_long(host, "chardev host")?;
+        }
 
         let len = match &self.backend {
             ChardevType::Socket { path, .. } => path.len(),
@@ -76,6 +101,25 @@ impl ConfigCheck for ChardevConfig {
                 MAX_PATH_LENGTH
             )));
         }
+        if let Some(logfile) = &self.logfile {
+            if logfile.len() > MAX_PATH_LENGTH {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "chardev logfile".to_string(),
+                    MAX_PATH_LENGTH
+                )));
+            }
+        }
+        if let ChardevType::Ringbuf(size) = self.backend {
+            if !size.is_power_of_two() || size > MAX_RINGBUF_SIZE {
+                bail!(
+                    "Size of ringbuf chardev should be power of 2 and not larger than {}",
+                    MAX_RINGBUF_SIZE
+                );
+            }
+        }
+        if self.mux && self.backend == ChardevType::Clipboard {
+            bail!("Clipboard chardev can not be multiplexed");
+        }
 
         Ok(())
     }
@@ -87,18 +131,15 @@ fn check_chardev_args(cmd_parser: CmdParser) -> Result<()> {
         let server = cmd_parser.get_value::<String>("server")?;
         let nowait = cmd_parser.get_value::<String>("nowait")?;
         match chardev_str {
-            "stdio" | "pty" | "file" => {
-                if server.is_some() {
-                    bail!(
-                        "Chardev of {}-type does not support \'server\' argument",
-                        chardev_str
-                    );
-                }
-                if nowait.is_some() {
-                    bail!(
-                        "Chardev of {}-type does not support \'nowait\' argument",
-                        chardev_str
-                    );
+            "stdio" | "pty" | "file" | "clipboard" | "ringbuf" => {
+                for arg in ["server", "nowait", "host", "port", "telnet", "reconnect"] {
+                    if cmd_parser.get_value::<String>(arg)?.is_some() {
+                        bail!(
+                            "Chardev of {}-type does not support \'{}\' argument",
+                            chardev_str,
+                            arg
+                        );
+                    }
                 }
             }
             "socket" => {
@@ -115,6 +156,15 @@ fn check_chardev_args(cmd_parser: CmdParser) -> Result<()> {
             }
             _ => (),
         }
+        if chardev_str != "ringbuf" && cmd_parser.get_value::<String>("size")?.is_some() {
+            bail!(
+                "Chardev of {}-type does not support \'size\' argument",
+                chardev_str
+            );
+        }
+        if chardev_str == "ringbuf" && cmd_parser.get_value::<String>("path")?.is_some() {
+            bail!("Chardev of ringbuf-type does not support \'path\' argument");
+        }
     }
     Ok(())
 }
@@ -141,12 +191,57 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
     } else {
         false
     };
+    let telnet = if let Some(telnet) = cmd_parser.get_value::<String>("telnet")? {
+        if telnet.ne("") {
+            bail!("No parameter needed for telnet");
+        }
+        true
+    } else {
+        false
+    };
+    let host = cmd_parser.get_value::<String>("host")?;
+    let port = cmd_parser.get_value::<u16>("port")?;
+    let reconnect = cmd_parser.get_value::<u64>("reconnect")?;
+    let size = cmd_parser.get_value::<u64>("size")?;
+    let mux = cmd_parser
+        .get_value::<ExBool>("mux")?
+        .map_or(false, |switch| switch.into());
+    let logfile = cmd_parser.get_value::<String>("logfile")?;
     check_chardev_args(cmd_parser)?;
     let chardev_type = if let Some(backend) = backend {
         match backend.as_str() {
             "stdio" => ChardevType::Stdio,
             "pty" => ChardevType::Pty,
+            "clipboard" => ChardevType::Clipboard,
+            "ringbuf" => ChardevType::Ringbuf(size.unwrap_or(DEFAULT_RINGBUF_SIZE)),
+            "socket" if path.is_none() && (host.is_some() || port.is_some()) => {
+                let port = port.with_context(|| {
+                    ConfigError::FieldIsMissing("port".to_string(), "tcp-type chardev".to_string())
+                })?;
+                if server && reconnect.is_some() {
+                    bail!("Argument \'reconnect\' is only supported by tcp client chardev");
+                }
+                if !server && nowait {
+                    bail!("Argument \'nowait\' is only supported by tcp server chardev");
+                }
+                ChardevType::TcpSocket {
+                    host: host.unwrap_or_else(|| "127.0.0.1".to_string()),
+                    port,
+                    server,
+                    nowait,
+                    telnet,
+                    reconnect: reconnect.unwrap_or(0),
+                }
+            }
             "socket" => {
+                if host.is_some() || port.is_some() {
+                    bail!("Argument \'path\' can not be used together with \'host\' or \'port\'");
+                }
+                if telnet || reconnect.is_some() {
+                    bail!(
+                        "Argument \'telnet\' and \'reconnect\' are only supported by tcp chardev"
+                    );
+                }
                 if let Some(path) = path {
                     ChardevType::Socket {
                         path,
@@ -187,6 +282,8 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
     Ok(ChardevConfig {
         id: chardev_id,
         backend: chardev_type,
+        mux,
+        logfile,
     })
 }
 
@@ -229,6 +326,8 @@ pub fn get_chardev_config(args: qmp_schema::CharDevAddArgument) -> Result<Charde
             server: data.server,
             nowait: false,
         },
+        mux: false,
+        logfile: None,
     })
 }
 
@@ -286,7 +385,7 @@ pub fn parse_virtserialport(
         bail!("Port number 0 on virtio-serial devices reserved for virtconsole device.");
     }
 
-    if let Some(chardev) = vm_config.chardev.remove(&chardev_name) {
+    if let Some(chardev) = vm_config.take_chardev(&chardev_name) {
         let port_cfg = VirtioSerialPort {
             id,
             chardev,
@@ -308,7 +407,14 @@ impl VmConfig {
             .push("id")
             .push("path")
             .push("server")
-            .push("nowait");
+            .push("nowait")
+            .push("host")
+            .push("port")
+            .push("telnet")
+            .push("reconnect")
+            .push("size")
+            .push("mux")
+            .push("logfile");
 
         cmd_parser.parse(chardev_config)?;
 
@@ -342,6 +448,19 @@ impl VmConfig {
         Ok(())
     }
 
+    /// Take chardev config for a frontend. Multiplexed chardev is kept for
+    /// other frontends.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The chardev id.
+    pub fn take_chardev(&mut self, id: &str) -> Option<ChardevConfig> {
+        match self.chardev.get(id) {
+            Some(chardev) if chardev.mux => Some(chardev.clone()),
+            _ => self.chardev.remove(id),
+        }
+    }
+
     /// Delete chardev config from vm config.
     ///
     /// # Arguments
@@ -365,8 +484,16 @@ pub struct SerialConfig {
 
 impl VmConfig {
     pub fn add_serial(&mut self, serial_config: &str) -> Result<()> {
+        // "mon:<backend>" shares the backend between serial and monitor.
+        if let Some(backend) = serial_config.strip_prefix("mon:") {
+            let chardev_config = backend.to_string() + ",id=serial_chardev,mux=on";
+            self.add_chardev(&chardev_config)
+                .with_context(|| "Failed to add chardev")?;
+            self.monitor_chardev = self.take_chardev("serial_chardev");
+        }
         let parse_vec: Vec<&str> = serial_config.split(':').collect();
         let chardev_id = match parse_vec[0] {
+            "mon" => "serial_chardev",
             "chardev" => {
                 if parse_vec.len() == 2 {
                     parse_vec[1]
@@ -384,7 +511,7 @@ impl VmConfig {
                 "serial_chardev"
             }
         };
-        if let Some(char_dev) = self.chardev.remove(chardev_id) {
+        if let Some(char_dev) = self.take_chardev(chardev_id) {
             self.serial = Some(SerialConfig { chardev: char_dev });
             return Ok(());
         }
@@ -660,5 +787,116 @@ mod tests {
         } else {
             assert!(false);
         }
+
+        assert!(vm_config.add_chardev("clipboard,id=clipboard0").is_ok());
+        let char_dev = vm_config.chardev.remove("clipboard0").unwrap();
+        assert_eq!(char_dev.backend, ChardevType::Clipboard);
+        assert!(vm_config
+            .add_chardev("clipboard,id=clipboard1,server")
+            .is_err());
+    }
+
+    #[test]
+    fn test_mux_ringbuf_chardev_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("stdio,id=mux0,mux=on,logfile=/path/to/log")
+            .is_ok());
+        let char_dev = vm_config.take_chardev("mux0").unwrap();
+        assert!(char_dev.mux);
+        assert_eq!(char_dev.logfile, Some("/path/to/log".to_string()));
+        // Multiplexed chardev can be taken by multiple frontends.
+        assert!(vm_config.take_chardev("mux0").is_some());
+        assert!(vm_config.add_serial("chardev:mux0").is_ok());
+        assert!(vm_config.chardev.contains_key("mux0"));
+
+        assert!(vm_config.add_chardev("pty,id=pty0,mux=off").is_ok());
+        assert!(!vm_config.take_chardev("pty0").unwrap().mux);
+        assert!(vm_config.take_chardev("pty0").is_none());
+        assert!(vm_config.add_chardev("clipboard,id=clip0,mux=on").is_err());
+
+        assert!(vm_config.add_chardev("ringbuf,id=ring0").is_ok());
+        let char_dev = vm_config.take_chardev("ring0").unwrap();
+        assert_eq!(char_dev.backend, ChardevType::Ringbuf(65536));
+        assert!(vm_config.add_chardev("ringbuf,id=ring1,size=1024").is_ok());
+        let char_dev = vm_config.take_chardev("ring1").unwrap();
+        assert_eq!(char_dev.backend, ChardevType::Ringbuf(1024));
+        assert!(vm_config.add_chardev("ringbuf,id=ring2,size=1000").is_err());
+        assert!(vm_config.add_chardev("ringbuf,id=ring3,size=0").is_err());
+        assert!(vm_config
+            .add_chardev("ringbuf,id=ring4,size=33554432")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("ringbuf,id=ring5,path=/path/to/file")
+            .is_err());
+        assert!(vm_config.add_chardev("pty,id=ring6,size=1024").is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_serial("mon:stdio").is_ok());
+        let serial = vm_config.serial.as_ref().unwrap();
+        assert_eq!(serial.chardev.backend, ChardevType::Stdio);
+        assert!(serial.chardev.mux);
+        assert_eq!(
+            vm_config.monitor_chardev.as_ref().unwrap().id,
+            "serial_chardev"
+        );
+    }
+
+    #[test]
+    fn test_tcp_chardev_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=tcp0,host=0.0.0.0,port=4444,server,nowait,telnet")
+            .is_ok());
+        let char_dev = vm_config.chardev.remove("tcp0").unwrap();
+        assert_eq!(
+            char_dev.backend,
+            ChardevType::TcpSocket {
+                host: "0.0.0.0".to_string(),
+                port: 4444,
+                server: true,
+                nowait: true,
+                telnet: true,
+                reconnect: 0,
+            }
+        );
+
+        assert!(vm_config
+            .add_chardev("socket,id=tcp1,port=4444,reconnect=5")
+            .is_ok());
+        let char_dev = vm_config.chardev.remove("tcp1").unwrap();
+        assert_eq!(
+            char_dev.backend,
+            ChardevType::TcpSocket {
+                host: "127.0.0.1".to_string(),
+                port: 4444,
+                server: false,
+                nowait: false,
+                telnet: false,
+                reconnect: 5,
+            }
+        );
+
+        // Port is required.
+        assert!(vm_config
+            .add_chardev("socket,id=tcp2,host=127.0.0.1")
+            .is_err());
+        // Reconnect is only for client.
+        assert!(vm_config
+            .add_chardev("socket,id=tcp3,port=4444,server,nowait,reconnect=1")
+            .is_err());
+        // Nowait is only for server.
+        assert!(vm_config
+            .add_chardev("socket,id=tcp4,port=4444,nowait")
+            .is_err());
+        // Unix socket does not support tcp arguments.
+        assert!(vm_config
+            .add_chardev("socket,id=tcp5,path=/path/to/socket,port=4444")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=tcp6,path=/path/to/socket,telnet")
+            .is_err());
+        assert!(vm_config.add_chardev("pty,id=tcp7,telnet").is_err());
+        assert!(vm_config.add_chardev("socket,id=tcp8,port=65536").is_err());
     }
 }
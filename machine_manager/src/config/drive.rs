@@ -47,6 +47,8 @@ pub struct DriveFile {
     pub path: String,
     /// File is read only or not.
     pub read_only: bool,
+    /// Writable file can be shared with other processes or not.
+    pub share_rw: bool,
     /// File lock status.
     pub locked: bool,
     /// The align requirement of request(offset/len).
@@ -145,6 +147,7 @@ pub struct DriveConfig {
     pub format: DiskFormat,
     pub l2_cache_size: Option<u64>,
     pub refcount_cache_size: Option<u64>,
+    pub share_rw: bool,
 }
 
 impl Default for DriveConfig {
@@ -162,6 +165,7 @@ impl Default for DriveConfig {
             format: DiskFormat::Raw,
             l2_cache_size: None,
             refcount_cache_size: None,
+            share_rw: false,
         }
     }
 }
@@ -172,8 +176,10 @@ impl DriveConfig {
         let blk = Path::new(&self.path_on_host);
         match metadata(blk) {
             Ok(meta) => {
+                // Character device is the host sg device(/dev/sgN) used by scsi-generic.
                 if ((meta.st_mode() & libc::S_IFREG) != libc::S_IFREG)
                     && ((meta.st_mode() & libc::S_IFBLK) != libc::S_IFBLK)
+                    && ((meta.st_mode() & libc::S_IFMT) != libc::S_IFCHR)
                 {
                     return Err(anyhow!(ConfigError::UnRegularFileOrBlk(
                         self.path_on_host.clone()
@@ -247,6 +253,13 @@ impl ConfigCheck for DriveConfig {
             )));
         }
 
+        if self.share_rw && self.format == DiskFormat::Qcow2 {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                "share-rw".to_string(),
+                "qcow2 image can not be shared with other writers".to_string(),
+            )));
+        }
+
         Ok(())
     }
 }
@@ -328,6 +341,9 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     if let Some(direct) = cmd_parser.get_value::<ExBool>("direct")? {
         drive.direct = direct.into();
     }
+    if let Some(share_rw) = cmd_parser.get_value::<ExBool>("share-rw")? {
+        drive.share_rw = share_rw.into();
+    }
     drive.iops = cmd_parser.get_value::<u64>("throttling.iops-total")?;
     drive.aio = cmd_parser.get_value::<AioEngine>("aio")?.unwrap_or({
         if drive.direct {
@@ -561,7 +577,8 @@ impl VmConfig {
             .push("detect-zeroes")
             .push("format")
             .push("l2-cache-size")
-            .push("refcount-cache-size");
+            .push("refcount-cache-size")
+            .push("share-rw");
 
         cmd_parser.parse(block_config)?;
         let drive_cfg = parse_drive(cmd_parser)?;
@@ -946,4 +963,31 @@ mod tests {
             .is_err();
         assert_eq!(ret, true);
     }
+
+    #[test]
+    fn test_drive_config_share_rw() {
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs")
+            .unwrap();
+        assert_eq!(drive_conf.share_rw, false);
+
+        let mut vm_config = VmConfig::default();
+        let drive_conf = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,share-rw=on")
+            .unwrap();
+        assert_eq!(drive_conf.share_rw, true);
+
+        let mut vm_config = VmConfig::default();
+        let ret = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,share-rw=invalid")
+            .is_err();
+        assert_eq!(ret, true);
+
+        let mut vm_config = VmConfig::default();
+        let ret = vm_config
+            .add_block_drive("id=rootfs,file=/path/to/rootfs,share-rw=on,format=qcow2")
+            .is_err();
+        assert_eq!(ret, true);
+    }
 }
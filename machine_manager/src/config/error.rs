@@ -52,7 +52,7 @@ pub enum ConfigError {
     UnknownVhostType,
     #[error("{0} is not a regular File.")]
     UnRegularFile(String),
-    #[error("{0} is not a regular file or block device.")]
+    #[error("{0} is not a regular file, block device or character device.")]
     UnRegularFileOrBlk(String),
     #[error("Failed to get metadata of file {0}: {1}.")]
     NoMetadata(String, String),
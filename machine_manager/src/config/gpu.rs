@@ -28,6 +28,7 @@ pub struct GpuDevConfig {
     pub xres: u32,
     pub yres: u32,
     pub max_hostmem: u64,
+    pub blob: bool,
 }
 
 impl Default for GpuDevConfig {
@@ -39,6 +40,7 @@ impl Default for GpuDevConfig {
             xres: 1024,
             yres: 768,
             max_hostmem: VIRTIO_GPU_MAX_HOSTMEM,
+            blob: false,
         }
     }
 }
@@ -87,6 +89,7 @@ pub fn parse_gpu(gpu_config: &str) -> Result<GpuDevConfig> {
         .push("xres")
         .push("yres")
         .push("max_hostmem")
+        .push("blob")
         .push("bus")
         .push("addr");
     cmd_parser.parse(gpu_config)?;
@@ -110,6 +113,9 @@ pub fn parse_gpu(gpu_config: &str) -> Result<GpuDevConfig> {
     if let Some(max_hostmem) = cmd_parser.get_value::<u64>("max_hostmem")? {
         gpu_cfg.max_hostmem = max_hostmem;
     }
+    if let Some(blob) = cmd_parser.get_value::<bool>("blob")? {
+        gpu_cfg.blob = blob;
+    }
     gpu_cfg.check()?;
 
     Ok(gpu_cfg)
@@ -137,6 +143,13 @@ mod tests {
         assert_eq!(gpu_cfg.xres, 1024);
         assert_eq!(gpu_cfg.yres, 768);
         assert_eq!(gpu_cfg.max_hostmem, max_hostmem);
+        assert_eq!(gpu_cfg.blob, false);
+
+        let gpu_cfg_cmdline = "virtio-gpu-pci,id=gpu_1,bus=pcie.0,addr=0x4.0x0,\
+            max_outputs=4,blob=true";
+        let gpu_cfg = parse_gpu(gpu_cfg_cmdline).unwrap();
+        assert_eq!(gpu_cfg.max_outputs, 4);
+        assert_eq!(gpu_cfg.blob, true);
 
         // max_outputs is illegal
         let gpu_cfg_cmdline = format!(
@@ -10,9 +10,9 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV6};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
 use super::VmConfig;
@@ -22,6 +22,8 @@ pub enum MigrateMode {
     File,
     Unix,
     Tcp,
+    Fd,
+    Exec,
     Unknown,
 }
 
@@ -31,6 +33,8 @@ impl From<&str> for MigrateMode {
             "file" | "File" | "FILE" => MigrateMode::File,
             "unix" | "Unix" | "UNIX" => MigrateMode::Unix,
             "tcp" | "Tcp" | "TCP" => MigrateMode::Tcp,
+            "fd" | "Fd" | "FD" => MigrateMode::Fd,
+            "exec" | "Exec" | "EXEC" => MigrateMode::Exec,
             _ => MigrateMode::Unknown,
         }
     }
@@ -38,6 +42,25 @@ impl From<&str> for MigrateMode {
 
 /// Parse `-incoming` cmdline to migrate mode and path.
 pub fn parse_incoming_uri(uri: &str) -> Result<(MigrateMode, String)> {
+    // The fd name or command may contain ':', only split the mode.
+    if let Some((mode, path)) = uri.split_once(':') {
+        match MigrateMode::from(mode) {
+            MigrateMode::Fd | MigrateMode::Exec if path.is_empty() => {
+                bail!("Invalid incoming uri {}", uri)
+            }
+            MigrateMode::Fd => return Ok((MigrateMode::Fd, String::from(path))),
+            MigrateMode::Exec => return Ok((MigrateMode::Exec, String::from(path))),
+            // IPv6 address is enclosed in brackets, as `tcp:[::1]:4446`.
+            MigrateMode::Tcp if path.starts_with('[') => {
+                let addr = path
+                    .parse::<SocketAddrV6>()
+                    .with_context(|| format!("Invalid incoming uri {}", uri))?;
+                return Ok((MigrateMode::Tcp, addr.to_string()));
+            }
+            _ => {}
+        }
+    }
+
     let parse_vec: Vec<&str> = uri.split(':').collect();
     if parse_vec.len() == 2 {
         match MigrateMode::from(parse_vec[0]) {
@@ -72,12 +95,35 @@ pub type Incoming = (MigrateMode, String);
 
 impl VmConfig {
     /// Add incoming mode and path.
+    ///
+    /// The uri can be followed by options, as `tcp:<ip>:<port>,tls-creds=<id>`.
+    /// The command of exec mode is taken as a whole, it can't be followed by options.
     pub fn add_incoming(&mut self, config: &str) -> Result<()> {
-        let (mode, uri) = parse_incoming_uri(config)?;
+        if let Ok((MigrateMode::Exec, cmd)) = parse_incoming_uri(config) {
+            self.incoming = Some((MigrateMode::Exec, cmd));
+            return Ok(());
+        }
+
+        let mut config_iter = config.split(',');
+        let (mode, uri) = parse_incoming_uri(config_iter.next().unwrap_or_default())?;
+        for option in config_iter {
+            match option.split_once('=') {
+                Some(("tls-creds", id)) if !id.is_empty() => {
+                    if mode != MigrateMode::Tcp {
+                        bail!("tls-creds is only supported by tcp incoming migration");
+                    }
+                    self.incoming_tls_creds = Some(id.to_string());
+                }
+                _ => bail!("Invalid incoming option {}", option),
+            }
+        }
+
         let incoming = match mode {
             MigrateMode::File => (MigrateMode::File, uri),
             MigrateMode::Unix => (MigrateMode::Unix, uri),
             MigrateMode::Tcp => (MigrateMode::Tcp, uri),
+            MigrateMode::Fd => (MigrateMode::Fd, uri),
+            MigrateMode::Exec => (MigrateMode::Exec, uri),
             MigrateMode::Unknown => {
                 bail!("Unsupported incoming unix path type")
             }
@@ -97,7 +143,9 @@ mod tests {
         assert_eq!(MigrateMode::from("File"), MigrateMode::File);
         assert_eq!(MigrateMode::from("UNIX"), MigrateMode::Unix);
         assert_eq!(MigrateMode::from("tcp"), MigrateMode::Tcp);
-        assert_eq!(MigrateMode::from("fd"), MigrateMode::Unknown);
+        assert_eq!(MigrateMode::from("fd"), MigrateMode::Fd);
+        assert_eq!(MigrateMode::from("exec"), MigrateMode::Exec);
+        assert_eq!(MigrateMode::from("rdma"), MigrateMode::Unknown);
     }
 
     #[test]
@@ -127,6 +175,28 @@ mod tests {
         let incoming_case5 = "tcp:192.168.1.2:65568";
         let result_5 = parse_incoming_uri(incoming_case5);
         assert!(result_5.is_err());
+
+        let result = parse_incoming_uri("tcp:[::1]:4446").unwrap();
+        assert_eq!(result, (MigrateMode::Tcp, "[::1]:4446".to_string()));
+        assert!(parse_incoming_uri("tcp:[::1]").is_err());
+        assert!(parse_incoming_uri("tcp:[::g]:4446").is_err());
+
+        let incoming_case6 = "fd:migrate-fd0";
+        let result_6 = parse_incoming_uri(incoming_case6).unwrap();
+        assert_eq!(result_6, (MigrateMode::Fd, "migrate-fd0".to_string()));
+
+        let incoming_case7 = "exec:socat - TCP:192.168.1.2:2022";
+        let result_7 = parse_incoming_uri(incoming_case7).unwrap();
+        assert_eq!(
+            result_7,
+            (
+                MigrateMode::Exec,
+                "socat - TCP:192.168.1.2:2022".to_string()
+            )
+        );
+
+        let incoming_case8 = "exec:";
+        assert!(parse_incoming_uri(incoming_case8).is_err());
     }
 
     #[test]
@@ -149,5 +219,34 @@ mod tests {
 
         let mut vm_config_case2 = VmConfig::default();
         assert!(vm_config_case2.add_incoming("unknown:/tmp/").is_err());
+
+        let mut vm_config_case3 = VmConfig::default();
+        assert!(vm_config_case3
+            .add_incoming("tcp:192.168.1.2:2022,tls-creds=migrate-tls0")
+            .is_ok());
+        assert_eq!(
+            vm_config_case3.incoming_tls_creds,
+            Some("migrate-tls0".to_string())
+        );
+
+        let mut vm_config_case4 = VmConfig::default();
+        assert!(vm_config_case4
+            .add_incoming("exec:socat - TCP-LISTEN:2022,reuseaddr")
+            .is_ok());
+        assert_eq!(
+            vm_config_case4.incoming.unwrap(),
+            (
+                MigrateMode::Exec,
+                "socat - TCP-LISTEN:2022,reuseaddr".to_string()
+            )
+        );
+
+        let mut vm_config_case5 = VmConfig::default();
+        assert!(vm_config_case5
+            .add_incoming("unix:/tmp/stratovirt.sock,tls-creds=migrate-tls0")
+            .is_err());
+        assert!(vm_config_case5
+            .add_incoming("tcp:192.168.1.2:2022,unknown=1")
+            .is_err());
     }
 }
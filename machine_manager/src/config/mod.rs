@@ -32,6 +32,7 @@ pub use rng::*;
 pub use sasl_auth::*;
 pub use scsi::*;
 pub use smbios::*;
+pub use sound::*;
 pub use tls_creds::*;
 pub use usb::*;
 pub use vfio::*;
@@ -60,12 +61,13 @@ mod sasl_auth;
 pub mod scream;
 mod scsi;
 mod smbios;
+mod sound;
 mod tls_creds;
 mod usb;
 mod vfio;
 pub mod vnc;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::str::FromStr;
 
@@ -113,6 +115,8 @@ pub struct VmConfig {
     pub drives: HashMap<String, DriveConfig>,
     pub netdevs: HashMap<String, NetDevcfg>,
     pub chardev: HashMap<String, ChardevConfig>,
+    /// Multiplexed chardev shared by monitor and other frontends.
+    pub monitor_chardev: Option<ChardevConfig>,
     pub virtio_serial: Option<VirtioSerialInfo>,
     pub devices: Vec<(String, String)>,
     pub serial: Option<SerialConfig>,
@@ -123,11 +127,14 @@ pub struct VmConfig {
     pub global_config: HashMap<String, String>,
     pub numa_nodes: Vec<(String, String)>,
     pub incoming: Option<Incoming>,
+    pub incoming_tls_creds: Option<String>,
     pub vnc: Option<VncConfig>,
     pub display: Option<DisplayConfig>,
     pub camera_backend: HashMap<String, CameraDevConfig>,
     pub windows_emu_pid: Option<String>,
     pub smbios: SmbiosConfig,
+    /// The (pr-file, pr-id) pairs of scsi devices, each of which is an I_T nexus.
+    pub scsi_pr_nexus: HashSet<(String, u64)>,
 }
 
 impl VmConfig {
@@ -152,17 +159,19 @@ impl VmConfig {
             bail!("Before Vm start, set a initrd or drive_file or vhost-user blk as rootfs");
         }
 
-        let mut stdio_count = 0;
+        // Multiplexed chardev may be shared by serial and kept in chardev map.
+        let mut stdio_chardevs = HashSet::new();
         if let Some(serial) = self.serial.as_ref() {
             if serial.chardev.backend == ChardevType::Stdio {
-                stdio_count += 1;
+                stdio_chardevs.insert(serial.chardev.id.clone());
             }
         }
-        for (_, char_dev) in self.chardev.clone() {
+        for (id, char_dev) in self.chardev.iter() {
             if char_dev.backend == ChardevType::Stdio {
-                stdio_count += 1;
+                stdio_chardevs.insert(id.clone());
             }
         }
+        let stdio_count = stdio_chardevs.len();
         if stdio_count > 0 && is_daemonize {
             bail!("Device redirected to stdio and daemonize can't be set together");
         }
@@ -170,6 +179,15 @@ impl VmConfig {
             bail!("Can't set multiple devices redirected to stdio");
         }
 
+        if let Some(id) = &self.incoming_tls_creds {
+            if !self.object.tls_object.contains_key(id) {
+                bail!(
+                    "tls-creds object {} for incoming migration is not found",
+                    id
+                );
+            }
+        }
+
         Ok(())
     }
 
@@ -274,15 +292,17 @@ impl VmConfig {
         path: &str,
         read_only: bool,
         direct: bool,
+        share_rw: bool,
     ) -> Result<()> {
         if let Some(drive_file) = drive_files.get_mut(path) {
-            if drive_file.read_only && read_only {
-                // File can be shared with read_only.
+            let shared_rw = !drive_file.read_only && !read_only && drive_file.share_rw && share_rw;
+            if (drive_file.read_only && read_only) || shared_rw {
+                // File can be shared with read_only or share-rw, e.g. multiple paths of a disk.
                 drive_file.count += 1;
                 return Ok(());
             } else {
                 return Err(anyhow!(
-                    "Failed to add drive {}, file can only be shared with read_only. \
+                    "Failed to add drive {}, file can only be shared with read_only or share-rw. \
                     Is it used more than once or another process using the same file?",
                     path
                 ));
@@ -301,6 +321,7 @@ impl VmConfig {
             file,
             count: 1,
             read_only,
+            share_rw,
             path: path.to_string(),
             locked: false,
             req_align,
@@ -369,6 +390,7 @@ impl VmConfig {
                 &drive.path_on_host,
                 drive.read_only,
                 drive.direct,
+                drive.share_rw,
             )?;
         }
         if let Some(pflashs) = self.pflashs.as_ref() {
@@ -379,6 +401,7 @@ impl VmConfig {
                     &pflash.path_on_host,
                     pflash.read_only,
                     false,
+                    false,
                 )?;
             }
         }
@@ -70,6 +70,14 @@ pub fn parse_scream(cfg_args: &str) -> Result<ScreamConfig> {
         dev_cfg.record = cmd_parser
             .get_value::<String>("record")?
             .with_context(|| "No record configured for interface")?;
+    } else if dev_cfg.interface.eq(&"Wav".to_string()) {
+        // Playback is discarded and record is silence if no file is configured.
+        dev_cfg.playback = cmd_parser
+            .get_value::<String>("playback")?
+            .unwrap_or_default();
+        dev_cfg.record = cmd_parser
+            .get_value::<String>("record")?
+            .unwrap_or_default();
     }
 
     Ok(dev_cfg)
@@ -14,7 +14,8 @@ use anyhow::{anyhow, bail, Context, Result};
 
 use super::{error::ConfigError, pci_args_check, DiskFormat};
 use crate::config::{
-    check_arg_too_long, CmdParser, ConfigCheck, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_VIRTIO_QUEUE,
+    check_arg_too_long, check_path_too_long, CmdParser, ConfigCheck, UnsignedInteger, VmConfig,
+    DEFAULT_VIRTQUEUE_SIZE, MAX_VIRTIO_QUEUE,
 };
 use util::aio::AioEngine;
 
@@ -165,6 +166,13 @@ pub struct ScsiDevConfig {
     pub format: DiskFormat,
     pub l2_cache_size: Option<u64>,
     pub refcount_cache_size: Option<u64>,
+    /// File which stores the persistent reservation state shared by all users of the image.
+    pub pr_file: Option<String>,
+    /// SAS address of the I_T nexus in the persistent reservation, unique among all the
+    /// users of `pr_file`.
+    pub pr_id: Option<u64>,
+    /// World wide name of the logical unit, reported as NAA designator.
+    pub wwn: Option<u64>,
 }
 
 impl Default for ScsiDevConfig {
@@ -184,6 +192,9 @@ impl Default for ScsiDevConfig {
             format: DiskFormat::Raw,
             l2_cache_size: None,
             refcount_cache_size: None,
+            pr_file: None,
+            pr_id: None,
+            wwn: None,
         }
     }
 }
@@ -198,7 +209,10 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         .push("lun")
         .push("serial")
         .push("bootindex")
-        .push("drive");
+        .push("drive")
+        .push("pr-file")
+        .push("pr-id")
+        .push("wwn");
 
     cmd_parser.parse(drive_config)?;
 
@@ -263,6 +277,37 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         scsi_dev_cfg.lun = lun;
     }
 
+    if let Some(pr_file) = cmd_parser.get_value::<String>("pr-file")? {
+        check_path_too_long(&pr_file, "pr-file of scsi device")?;
+        scsi_dev_cfg.pr_file = Some(pr_file);
+    }
+
+    if let Some(pr_id) = cmd_parser.get_value::<UnsignedInteger>("pr-id")? {
+        if pr_id.0 == 0 {
+            bail!("pr-id of scsi device can not be zero");
+        }
+        scsi_dev_cfg.pr_id = Some(pr_id.0 as u64);
+    }
+
+    if let Some(wwn) = cmd_parser.get_value::<UnsignedInteger>("wwn")? {
+        scsi_dev_cfg.wwn = Some(wwn.0 as u64);
+    }
+
+    let pr_nexus = match (&scsi_dev_cfg.pr_file, scsi_dev_cfg.pr_id) {
+        (Some(pr_file), Some(pr_id)) => Some((pr_file.clone(), pr_id)),
+        _ => None,
+    };
+    if let Some((pr_file, pr_id)) = &pr_nexus {
+        if vm_config.scsi_pr_nexus.contains(&(pr_file.clone(), *pr_id)) {
+            bail!(
+                "pr-id {:#x} with pr-file {} of scsi device {} is already used",
+                pr_id,
+                pr_file,
+                scsi_dev_cfg.id
+            );
+        }
+    }
+
     let drive_arg = &vm_config
         .drives
         .remove(&scsi_drive)
@@ -275,5 +320,43 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
     scsi_dev_cfg.l2_cache_size = drive_arg.l2_cache_size;
     scsi_dev_cfg.refcount_cache_size = drive_arg.refcount_cache_size;
 
+    if let Some(nexus) = pr_nexus {
+        vm_config.scsi_pr_nexus.insert(nexus);
+    }
+
     Ok(scsi_dev_cfg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scsi_device_pr_nexus() {
+        let mut vm_config = VmConfig::default();
+        for id in 0..4 {
+            vm_config
+                .add_drive(&format!("id=drive{},file=/path/to/disk{}", id, id))
+                .unwrap();
+        }
+
+        let dev_cfg = "scsi-hd,id=scsi-hd0,bus=scsi0.0,scsi-id=0,lun=0,drive=drive0,\
+            pr-file=/path/to/pr,pr-id=0x5000000000000001";
+        let config = parse_scsi_device(&mut vm_config, dev_cfg).unwrap();
+        assert_eq!(config.pr_file, Some("/path/to/pr".to_string()));
+        assert_eq!(config.pr_id, Some(0x5000000000000001));
+
+        // The same I_T nexus can not be used by two devices.
+        let dev_cfg = "scsi-hd,id=scsi-hd1,bus=scsi0.0,scsi-id=0,lun=1,drive=drive1,\
+            pr-file=/path/to/pr,pr-id=0x5000000000000001";
+        assert!(parse_scsi_device(&mut vm_config, dev_cfg).is_err());
+
+        let dev_cfg = "scsi-hd,id=scsi-hd2,bus=scsi0.0,scsi-id=0,lun=2,drive=drive2,\
+            pr-file=/path/to/pr,pr-id=0x5000000000000002";
+        assert!(parse_scsi_device(&mut vm_config, dev_cfg).is_ok());
+
+        let dev_cfg = "scsi-hd,id=scsi-hd3,bus=scsi0.0,scsi-id=0,lun=3,drive=drive3,\
+            pr-file=/path/to/pr2,pr-id=0x5000000000000001";
+        assert!(parse_scsi_device(&mut vm_config, dev_cfg).is_ok());
+    }
+}
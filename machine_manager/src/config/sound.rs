@@ -0,0 +1,142 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, Context, Result};
+
+use super::{error::ConfigError, pci_args_check};
+use crate::config::{check_arg_too_long, CmdParser, ConfigCheck};
+
+/// Config of virtio sound device.
+#[derive(Clone, Debug)]
+pub struct SoundConfig {
+    pub id: String,
+    /// Host audio interface: ALSA, PulseAudio, Demo, Wav or Null.
+    pub interface: String,
+    /// File to write the played audio for Demo and Wav interface.
+    pub playback: String,
+    /// File to read the recorded audio for Demo and Wav interface.
+    pub record: String,
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        SoundConfig {
+            id: "".to_string(),
+            interface: "ALSA".to_string(),
+            playback: "".to_string(),
+            record: "".to_string(),
+        }
+    }
+}
+
+impl ConfigCheck for SoundConfig {
+    fn check(&self) -> Result<()> {
+        check_arg_too_long(&self.id, "id")?;
+        match self.interface.as_str() {
+            "ALSA" | "PulseAudio" | "Null" => Ok(()),
+            "Demo" | "Wav" => {
+                check_arg_too_long(&self.playback, "playback")?;
+                check_arg_too_long(&self.record, "record")
+            }
+            _ => Err(anyhow!(ConfigError::InvalidParam(
+                self.interface.clone(),
+                "interface".to_string()
+            ))),
+        }
+    }
+}
+
+pub fn parse_sound(cfg_args: &str) -> Result<SoundConfig> {
+    let mut cmd_parser = CmdParser::new("virtio-sound-pci");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("interface")
+        .push("playback")
+        .push("record")
+        .push("bus")
+        .push("addr")
+        .push("multifunction");
+    cmd_parser.parse(cfg_args)?;
+
+    pci_args_check(&cmd_parser)?;
+
+    let mut sound_cfg = SoundConfig::default();
+    if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        sound_cfg.id = id;
+    }
+    if let Some(interface) = cmd_parser.get_value::<String>("interface")? {
+        sound_cfg.interface = interface;
+    }
+    if sound_cfg.interface == "Demo" {
+        sound_cfg.playback = cmd_parser
+            .get_value::<String>("playback")?
+            .with_context(|| {
+                ConfigError::FieldIsMissing("playback".to_string(), "Demo".to_string())
+            })?;
+        sound_cfg.record = cmd_parser.get_value::<String>("record")?.with_context(|| {
+            ConfigError::FieldIsMissing("record".to_string(), "Demo".to_string())
+        })?;
+    } else if sound_cfg.interface == "Wav" {
+        // Playback is discarded and record is silence if no file is configured.
+        sound_cfg.playback = cmd_parser
+            .get_value::<String>("playback")?
+            .unwrap_or_default();
+        sound_cfg.record = cmd_parser
+            .get_value::<String>("record")?
+            .unwrap_or_default();
+    }
+    sound_cfg.check()?;
+
+    Ok(sound_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sound_config_cmdline_parser() {
+        let sound_cfg =
+            parse_sound("virtio-sound-pci,id=snd0,bus=pcie.0,addr=0x5,interface=PulseAudio")
+                .unwrap();
+        assert_eq!(sound_cfg.id, "snd0");
+        assert_eq!(sound_cfg.interface, "PulseAudio");
+
+        let sound_cfg = parse_sound("virtio-sound-pci,id=snd0,bus=pcie.0,addr=0x5").unwrap();
+        assert_eq!(sound_cfg.interface, "ALSA");
+
+        let sound_cfg = parse_sound(
+            "virtio-sound-pci,id=snd0,bus=pcie.0,addr=0x5,interface=Demo,\
+            playback=/tmp/play.pcm,record=/tmp/record.pcm",
+        )
+        .unwrap();
+        assert_eq!(sound_cfg.playback, "/tmp/play.pcm");
+        assert_eq!(sound_cfg.record, "/tmp/record.pcm");
+
+        // Demo interface needs both playback and record files.
+        assert!(parse_sound(
+            "virtio-sound-pci,id=snd0,bus=pcie.0,addr=0x5,interface=Demo,playback=/tmp/play.pcm"
+        )
+        .is_err());
+        assert!(parse_sound("virtio-sound-pci,id=snd0,bus=pcie.0,addr=0x5,interface=OSS").is_err());
+
+        // Files are optional for Wav interface.
+        let sound_cfg = parse_sound(
+            "virtio-sound-pci,id=snd0,bus=pcie.0,addr=0x5,interface=Wav,playback=/tmp/play.wav",
+        )
+        .unwrap();
+        assert_eq!(sound_cfg.playback, "/tmp/play.wav");
+        assert!(sound_cfg.record.is_empty());
+        assert!(parse_sound("virtio-sound-pci,id=snd0,bus=pcie.0,addr=0x5,interface=Null").is_ok());
+    }
+}
@@ -10,12 +10,14 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::str::FromStr;
+
 use anyhow::{anyhow, bail, Context, Result};
 
 use super::{error::ConfigError, get_cameradev_by_id, UnsignedInteger};
 use crate::config::{
-    check_arg_nonexist, check_arg_too_long, CamBackendType, CameraDevConfig, CmdParser,
-    ConfigCheck, ScsiDevConfig, VmConfig,
+    check_arg_nonexist, check_arg_too_long, CamBackendType, CameraDevConfig, ChardevConfig,
+    ChardevType, CmdParser, ConfigCheck, ExBool, IntegerList, ScsiDevConfig, VmConfig,
 };
 use util::aio::AioEngine;
 
@@ -95,6 +97,89 @@ pub fn parse_xhci(conf: &str) -> Result<XhciConfig> {
     Ok(dev)
 }
 
+/// EHCI controller configuration.
+#[derive(Debug)]
+pub struct EhciConfig {
+    pub id: Option<String>,
+    pub multifunction: bool,
+}
+
+impl ConfigCheck for EhciConfig {
+    fn check(&self) -> Result<()> {
+        check_id(self.id.clone(), "ehci controller")
+    }
+}
+
+pub fn parse_ehci(conf: &str) -> Result<EhciConfig> {
+    let mut cmd_parser = CmdParser::new("usb-ehci");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("addr")
+        .push("multifunction");
+    cmd_parser.parse(conf)?;
+    let dev = EhciConfig {
+        id: cmd_parser.get_value::<String>("id")?,
+        multifunction: cmd_parser
+            .get_value::<ExBool>("multifunction")?
+            .map_or(false, bool::from),
+    };
+
+    dev.check()?;
+    Ok(dev)
+}
+
+/// UHCI companion controller configuration.
+#[derive(Debug)]
+pub struct UhciConfig {
+    pub id: Option<String>,
+    pub multifunction: bool,
+    /// Id of the EHCI controller which the companion belongs to.
+    pub masterbus: String,
+    /// The first EHCI port routed to the companion.
+    pub firstport: u8,
+}
+
+impl ConfigCheck for UhciConfig {
+    fn check(&self) -> Result<()> {
+        check_id(self.id.clone(), "uhci controller")?;
+        check_arg_too_long(&self.masterbus, "masterbus")
+    }
+}
+
+pub fn parse_uhci(conf: &str) -> Result<UhciConfig> {
+    let mut cmd_parser = CmdParser::new("ich9-usb-uhci");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("addr")
+        .push("multifunction")
+        .push("masterbus")
+        .push("firstport");
+    cmd_parser.parse(conf)?;
+    let masterbus = cmd_parser
+        .get_value::<String>("masterbus")?
+        .with_context(|| {
+            ConfigError::FieldIsMissing("masterbus".to_string(), "uhci".to_string())
+        })?;
+    let firstport = cmd_parser.get_value::<u8>("firstport")?.with_context(|| {
+        ConfigError::FieldIsMissing("firstport".to_string(), "uhci".to_string())
+    })?;
+    let dev = UhciConfig {
+        id: cmd_parser.get_value::<String>("id")?,
+        multifunction: cmd_parser
+            .get_value::<ExBool>("multifunction")?
+            .map_or(false, bool::from),
+        masterbus,
+        firstport,
+    };
+
+    dev.check()?;
+    Ok(dev)
+}
+
 #[derive(Debug)]
 pub struct UsbKeyboardConfig {
     pub id: Option<String>,
@@ -225,22 +310,33 @@ impl ConfigCheck for UsbCameraConfig {
     }
 }
 
+/// Max number of logical units of a usb storage device.
+const USB_STORAGE_MAX_LUNS: usize = 16;
+
 #[derive(Clone, Debug)]
-pub struct UsbStorageConfig {
-    /// USB Storage device id.
-    pub id: Option<String>,
+pub struct UsbStorageLunConfig {
     /// The scsi backend config.
     pub scsi_cfg: ScsiDevConfig,
     /// The backend scsi device type(Disk or CD-ROM).
     pub media: String,
 }
 
+#[derive(Clone, Debug)]
+pub struct UsbStorageConfig {
+    /// USB Storage device id.
+    pub id: Option<String>,
+    /// The logical units, LUN id is the index in the list.
+    pub luns: Vec<UsbStorageLunConfig>,
+    /// Use USB Attached SCSI(UAS) protocol besides Bulk-Only Transport.
+    pub uas: bool,
+}
+
 impl UsbStorageConfig {
     fn new() -> Self {
         Self {
             id: None,
-            scsi_cfg: ScsiDevConfig::default(),
-            media: "".to_string(),
+            luns: Vec::new(),
+            uas: false,
         }
     }
 }
@@ -255,8 +351,20 @@ impl ConfigCheck for UsbStorageConfig {
     fn check(&self) -> Result<()> {
         check_id(self.id.clone(), "usb-storage")?;
 
-        if self.scsi_cfg.aio_type != AioEngine::Off || self.scsi_cfg.direct {
-            bail!("USB-storage: \"aio=off,direct=false\" must be configured.");
+        if self.luns.is_empty() || self.luns.len() > USB_STORAGE_MAX_LUNS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "number of usb storage drives".to_string(),
+                1,
+                true,
+                USB_STORAGE_MAX_LUNS as u64,
+                true
+            )));
+        }
+
+        for lun in &self.luns {
+            if lun.scsi_cfg.aio_type != AioEngine::Off || lun.scsi_cfg.direct {
+                bail!("USB-storage: \"aio=off,direct=false\" must be configured.");
+            }
         }
 
         Ok(())
@@ -276,23 +384,37 @@ pub fn parse_usb_storage(vm_config: &mut VmConfig, drive_config: &str) -> Result
 
     let mut dev = UsbStorageConfig::new();
     dev.id = cmd_parser.get_value::<String>("id")?;
+    dev.uas = cmd_parser.get_value::<String>("")?.unwrap_or_default() == "usb-uas";
 
-    let storage_drive = cmd_parser.get_value::<String>("drive")?.with_context(|| {
+    let storage_drives = cmd_parser.get_value::<String>("drive")?.with_context(|| {
         ConfigError::FieldIsMissing("drive".to_string(), "usb storage device".to_string())
     })?;
 
-    let drive_arg = &vm_config
-        .drives
-        .remove(&storage_drive)
-        .with_context(|| "No drive configured matched for usb storage device.")?;
-    dev.scsi_cfg.path_on_host = drive_arg.path_on_host.clone();
-    dev.scsi_cfg.read_only = drive_arg.read_only;
-    dev.scsi_cfg.aio_type = drive_arg.aio;
-    dev.scsi_cfg.direct = drive_arg.direct;
-    dev.scsi_cfg.format = drive_arg.format;
-    dev.scsi_cfg.l2_cache_size = drive_arg.l2_cache_size;
-    dev.scsi_cfg.refcount_cache_size = drive_arg.refcount_cache_size;
-    dev.media = drive_arg.media.clone();
+    // Multiple drives are separated by colon, and each drive is a logical unit.
+    for (lun, storage_drive) in storage_drives.split(':').enumerate() {
+        let drive_arg = &vm_config.drives.remove(storage_drive).with_context(|| {
+            format!(
+                "No drive configured matched {} for usb storage device.",
+                storage_drive
+            )
+        })?;
+        let scsi_cfg = ScsiDevConfig {
+            id: storage_drive.to_string(),
+            path_on_host: drive_arg.path_on_host.clone(),
+            read_only: drive_arg.read_only,
+            aio_type: drive_arg.aio,
+            direct: drive_arg.direct,
+            lun: lun as u16,
+            format: drive_arg.format,
+            l2_cache_size: drive_arg.l2_cache_size,
+            refcount_cache_size: drive_arg.refcount_cache_size,
+            ..Default::default()
+        };
+        dev.luns.push(UsbStorageLunConfig {
+            scsi_cfg,
+            media: drive_arg.media.clone(),
+        });
+    }
 
     dev.check()?;
     Ok(dev)
@@ -367,3 +489,292 @@ pub fn parse_usb_host(cfg_args: &str) -> Result<UsbHostConfig> {
     dev.check()?;
     Ok(dev)
 }
+
+#[derive(Clone, Debug)]
+pub struct UsbRedirConfig {
+    /// USB redirection device id.
+    pub id: Option<String>,
+    /// The chardev which speaks usbredir protocol with the peer.
+    pub chardev: ChardevConfig,
+}
+
+impl ConfigCheck for UsbRedirConfig {
+    fn check(&self) -> Result<()> {
+        check_id(self.id.clone(), "usb-redir")?;
+        match self.chardev.backend {
+            ChardevType::Socket { .. } | ChardevType::TcpSocket { .. } => Ok(()),
+            _ => bail!("Chardev {} of usb-redir must be a socket", self.chardev.id),
+        }
+    }
+}
+
+pub fn parse_usb_redir(vm_config: &mut VmConfig, cfg_args: &str) -> Result<UsbRedirConfig> {
+    let mut cmd_parser = CmdParser::new("usb-redir");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("port")
+        .push("chardev");
+
+    cmd_parser.parse(cfg_args)?;
+
+    let chardev_name = cmd_parser
+        .get_value::<String>("chardev")?
+        .with_context(|| {
+            ConfigError::FieldIsMissing("chardev".to_string(), "usb-redir".to_string())
+        })?;
+    let chardev = vm_config
+        .take_chardev(&chardev_name)
+        .with_context(|| format!("Chardev {:?} not found or is in use", &chardev_name))?;
+    let dev = UsbRedirConfig {
+        id: cmd_parser.get_value::<String>("id")?,
+        chardev,
+    };
+
+    dev.check()?;
+    Ok(dev)
+}
+
+/// Max number of downstream ports of usb hub.
+pub const USB_HUB_MAX_PORTS: u8 = 15;
+const USB_HUB_DEFAULT_PORTS: u8 = 8;
+
+#[derive(Clone, Debug)]
+pub struct UsbHubConfig {
+    pub id: Option<String>,
+    /// Number of downstream ports.
+    pub ports: u8,
+}
+
+impl ConfigCheck for UsbHubConfig {
+    fn check(&self) -> Result<()> {
+        check_id(self.id.clone(), "usb-hub")?;
+        if self.ports == 0 || self.ports > USB_HUB_MAX_PORTS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "usb hub ports".to_string(),
+                1,
+                true,
+                USB_HUB_MAX_PORTS as u64,
+                true,
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_usb_hub(conf: &str) -> Result<UsbHubConfig> {
+    let mut cmd_parser = CmdParser::new("usb-hub");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("port")
+        .push("ports");
+    cmd_parser.parse(conf)?;
+    let dev = UsbHubConfig {
+        id: cmd_parser.get_value::<String>("id")?,
+        ports: cmd_parser
+            .get_value::<u8>("ports")?
+            .unwrap_or(USB_HUB_DEFAULT_PORTS),
+    };
+
+    dev.check()?;
+    Ok(dev)
+}
+
+/// Protocol of usb serial device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbSerialProtocol {
+    /// FTDI FT232 compatible adapter.
+    Ftdi,
+    /// Communications device class abstract control model.
+    CdcAcm,
+}
+
+impl FromStr for UsbSerialProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "ftdi" => Ok(UsbSerialProtocol::Ftdi),
+            "cdc-acm" => Ok(UsbSerialProtocol::CdcAcm),
+            _ => Err(anyhow!("Unknown usb serial protocol {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UsbSerialConfig {
+    pub id: Option<String>,
+    pub protocol: UsbSerialProtocol,
+    /// The chardev which the serial data is read from and written to.
+    pub chardev: ChardevConfig,
+}
+
+impl ConfigCheck for UsbSerialConfig {
+    fn check(&self) -> Result<()> {
+        check_id(self.id.clone(), "usb-serial")
+    }
+}
+
+pub fn parse_usb_serial(vm_config: &mut VmConfig, conf: &str) -> Result<UsbSerialConfig> {
+    let mut cmd_parser = CmdParser::new("usb-serial");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("port")
+        .push("protocol")
+        .push("chardev");
+    cmd_parser.parse(conf)?;
+
+    let chardev_name = cmd_parser
+        .get_value::<String>("chardev")?
+        .with_context(|| {
+            ConfigError::FieldIsMissing("chardev".to_string(), "usb-serial".to_string())
+        })?;
+    let chardev = vm_config
+        .take_chardev(&chardev_name)
+        .with_context(|| format!("Chardev {:?} not found or is in use", &chardev_name))?;
+    let dev = UsbSerialConfig {
+        id: cmd_parser.get_value::<String>("id")?,
+        protocol: cmd_parser
+            .get_value::<UsbSerialProtocol>("protocol")?
+            .unwrap_or(UsbSerialProtocol::Ftdi),
+        chardev,
+    };
+
+    dev.check()?;
+    Ok(dev)
+}
+
+/// Max number of channels of usb audio device.
+pub const USB_AUDIO_MAX_CHANNELS: u8 = 8;
+const USB_AUDIO_DEFAULT_CHANNELS: u8 = 2;
+const USB_AUDIO_DEFAULT_RATE: u32 = 48000;
+/// Max number of discrete sample rates which can be described by usb audio device.
+const USB_AUDIO_MAX_RATES: usize = 8;
+const USB_AUDIO_MAX_RATE: u32 = 96000;
+/// Max packet size of full speed isochronous endpoint.
+const USB_AUDIO_MAX_PACKET_SIZE: u32 = 1023;
+
+/// Usb audio device configuration.
+#[derive(Clone, Debug)]
+pub struct UsbAudioConfig {
+    pub id: Option<String>,
+    /// Host audio interface: ALSA, PulseAudio, Demo, Wav or Null.
+    pub interface: String,
+    /// File to write the played audio for Demo and Wav interface.
+    pub playback: String,
+    /// File to read the recorded audio for Demo and Wav interface.
+    pub record: String,
+    /// Number of channels of both playback and capture.
+    pub channels: u8,
+    /// Sample rates in Hz supported by the device.
+    pub rates: Vec<u32>,
+}
+
+impl ConfigCheck for UsbAudioConfig {
+    fn check(&self) -> Result<()> {
+        check_id(self.id.clone(), "usb-audio")?;
+        match self.interface.as_str() {
+            "ALSA" | "PulseAudio" | "Null" => {}
+            "Demo" | "Wav" => {
+                check_arg_too_long(&self.playback, "playback")?;
+                check_arg_too_long(&self.record, "record")?;
+            }
+            _ => {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    self.interface.clone(),
+                    "interface".to_string()
+                )))
+            }
+        }
+        if self.channels == 0 || self.channels > USB_AUDIO_MAX_CHANNELS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "usb audio channels".to_string(),
+                1,
+                true,
+                USB_AUDIO_MAX_CHANNELS as u64,
+                true,
+            )));
+        }
+        if self.rates.is_empty() || self.rates.len() > USB_AUDIO_MAX_RATES {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "usb audio number of rates".to_string(),
+                1,
+                true,
+                USB_AUDIO_MAX_RATES as u64,
+                true,
+            )));
+        }
+        for rate in self.rates.iter() {
+            if (rate % 44100 != 0 && rate % 48000 != 0) || *rate > USB_AUDIO_MAX_RATE {
+                bail!("Unsupported usb audio rate {}", rate);
+            }
+            // 16 bits per sample, and the frames of 1ms are transferred in one packet.
+            let packet_size = (rate + 999) / 1000 * self.channels as u32 * 2;
+            if packet_size > USB_AUDIO_MAX_PACKET_SIZE {
+                bail!(
+                    "Usb audio rate {} with {} channels exceeds the bandwidth of full speed",
+                    rate,
+                    self.channels
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_usb_audio(conf: &str) -> Result<UsbAudioConfig> {
+    let mut cmd_parser = CmdParser::new("usb-audio");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("port")
+        .push("interface")
+        .push("playback")
+        .push("record")
+        .push("channels")
+        .push("rates");
+    cmd_parser.parse(conf)?;
+
+    let interface = cmd_parser
+        .get_value::<String>("interface")?
+        .unwrap_or_else(|| "ALSA".to_string());
+    let (playback, record) = if interface == "Demo" || interface == "Wav" {
+        (
+            cmd_parser
+                .get_value::<String>("playback")?
+                .unwrap_or_default(),
+            cmd_parser
+                .get_value::<String>("record")?
+                .unwrap_or_default(),
+        )
+    } else {
+        (String::new(), String::new())
+    };
+    if interface == "Demo" && (playback.is_empty() || record.is_empty()) {
+        bail!("Both playback and record files are required for Demo interface");
+    }
+    let rates = cmd_parser
+        .get_value::<IntegerList>("rates")
+        .with_context(|| ConfigError::ConvertValueFailed(String::from("u32"), "rates".to_string()))?
+        .map(|v| v.0.iter().map(|e| *e as u32).collect::<Vec<u32>>())
+        .unwrap_or_else(|| vec![USB_AUDIO_DEFAULT_RATE]);
+    let dev = UsbAudioConfig {
+        id: cmd_parser.get_value::<String>("id")?,
+        interface,
+        playback,
+        record,
+        channels: cmd_parser
+            .get_value::<u8>("channels")?
+            .unwrap_or(USB_AUDIO_DEFAULT_CHANNELS),
+        rates,
+    };
+
+    dev.check()?;
+    Ok(dev)
+}
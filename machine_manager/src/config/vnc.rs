@@ -10,7 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use crate::config::{CmdParser, ConfigError, VmConfig};
+use crate::config::{CmdParser, ConfigError, ExBool, VmConfig, VIRTIO_GPU_MAX_OUTPUTS};
 
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
@@ -29,10 +29,20 @@ pub struct VncConfig {
     pub sasl: bool,
     /// Configuration of authentication.
     pub sasl_authz: String,
+    /// Listening port for websocket clients.
+    pub websocket: Option<u16>,
+    /// Number of displays, display N is served on listening port + N.
+    pub heads: u32,
+    /// Password authentication switch, password is set by qmp.
+    pub password: bool,
+    /// Share policy of clients: "allow-exclusive" or "force-shared", empty if
+    /// only one client is allowed.
+    pub share: String,
 }
 
 const VNC_MAX_PORT_NUM: i32 = 65535;
 const VNC_PORT_OFFSET: i32 = 5900;
+const VNC_SHARE_POLICIES: [&str; 2] = ["allow-exclusive", "force-shared"];
 
 impl VmConfig {
     /// Make configuration for vnc: "chardev" -> "vnc".
@@ -42,7 +52,11 @@ impl VmConfig {
             .push("")
             .push("tls-creds")
             .push("sasl")
-            .push("sasl-authz");
+            .push("sasl-authz")
+            .push("websocket")
+            .push("heads")
+            .push("password")
+            .push("share");
         cmd_parser.parse(vnc_config)?;
 
         let mut vnc_config = VncConfig::default();
@@ -68,6 +82,51 @@ impl VmConfig {
         if let Some(sasl_authz) = cmd_parser.get_value::<String>("sasl-authz")? {
             vnc_config.sasl_authz = sasl_authz;
         }
+        if let Some(password) = cmd_parser.get_value::<ExBool>("password")? {
+            vnc_config.password = password.into();
+            if vnc_config.password && vnc_config.sasl {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "password".to_string(),
+                    "vnc with sasl".to_string()
+                )));
+            }
+        }
+        if let Some(share) = cmd_parser.get_value::<String>("share")? {
+            if !VNC_SHARE_POLICIES.contains(&share.as_str()) {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    share,
+                    "share".to_string()
+                )));
+            }
+            vnc_config.share = share;
+        }
+        vnc_config.heads = 1;
+        if let Some(heads) = cmd_parser.get_value::<u32>("heads")? {
+            let port = vnc_config.port.parse::<u32>()?;
+            if heads == 0
+                || heads > VIRTIO_GPU_MAX_OUTPUTS as u32
+                || port + heads - 1 > VNC_MAX_PORT_NUM as u32
+            {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    "heads".to_string(),
+                    1,
+                    true,
+                    VIRTIO_GPU_MAX_OUTPUTS as u64,
+                    true
+                )));
+            }
+            vnc_config.heads = heads;
+        }
+        if let Some(ws_port) = cmd_parser.get_value::<u16>("websocket")? {
+            let port = vnc_config.port.parse::<u16>()?;
+            if (port..=port + (vnc_config.heads - 1) as u16).contains(&ws_port) {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    ws_port.to_string(),
+                    "websocket".to_string()
+                )));
+            }
+            vnc_config.websocket = Some(ws_port);
+        }
 
         self.vnc = Some(vnc_config);
         Ok(())
@@ -117,6 +176,8 @@ mod tests {
         assert_eq!(vnc_config.tls_creds, String::from("vnc-tls-creds0"));
         assert_eq!(vnc_config.sasl, true);
         assert_eq!(vnc_config.sasl_authz, String::from("authz0"));
+        assert_eq!(vnc_config.password, false);
+        assert_eq!(vnc_config.share, String::new());
 
         let mut vm_config = VmConfig::default();
         let config_line = "0.0.0.0:5900,tls-creds=vnc-tls-creds0";
@@ -130,21 +191,64 @@ mod tests {
         assert!(vm_config.add_vnc(config_line).is_ok());
         let vnc_config = vm_config.vnc.unwrap();
         assert_eq!(vnc_config.tls_creds, "".to_string());
+        assert_eq!(vnc_config.websocket, None);
+
+        let mut vm_config = VmConfig::default();
+        let config_line = "0.0.0.0:1,websocket=5701";
+        assert!(vm_config.add_vnc(config_line).is_ok());
+        let vnc_config = vm_config.vnc.unwrap();
+        assert_eq!(vnc_config.websocket, Some(5701));
+        assert_eq!(vnc_config.heads, 1);
+
+        let mut vm_config = VmConfig::default();
+        let config_line = "0.0.0.0:1,heads=2";
+        assert!(vm_config.add_vnc(config_line).is_ok());
+        let vnc_config = vm_config.vnc.unwrap();
+        assert_eq!(vnc_config.port, String::from("5901"));
+        assert_eq!(vnc_config.heads, 2);
+        // Websocket port conflicts with port of head 1.
+        let mut vm_config = VmConfig::default();
+        let config_line = "0.0.0.0:1,heads=2,websocket=5902";
+        assert!(vm_config.add_vnc(config_line).is_err());
+
+        let mut vm_config = VmConfig::default();
+        let config_line = "0.0.0.0:1,password=on,share=force-shared";
+        assert!(vm_config.add_vnc(config_line).is_ok());
+        let vnc_config = vm_config.vnc.unwrap();
+        assert_eq!(vnc_config.password, true);
+        assert_eq!(vnc_config.share, String::from("force-shared"));
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_vnc("0.0.0.0:1,password=off").is_ok());
+        assert_eq!(vm_config.vnc.unwrap().password, false);
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_vnc("0.0.0.0:1,password=maybe").is_err());
+        // Password can not be used together with sasl.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_vnc("0.0.0.0:1,sasl,password=on").is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_vnc("0.0.0.0:1,sasl,password=off").is_ok());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_vnc("0.0.0.0:1,share=ignore").is_err());
 
         // Invalie format of ip:port.
         let config_lines = [
-            "tls-creds=vnc-tls-creds0", // No ip:port.
-            "127.0.0.1",                // No port.
-            "1",                        // No ip.
-            "0.0.0.0:65536",            // Invalid port.
-            "0.0.0.0:59636",            // Invalid port.
-            "0.0.0.0:2147483647",       // Invalie port.
-            "0.0.0.0:-1",               // Invalid port.
-            "0.0.0.0:123ab",            // Invalid port.
-            "127.257.0.1:0",            // Invalid ip.
-            "127.0.0.0.1:0",            // Invalid ip.
-            "127.12ab.0.1:0",           // Invalid ip.
-            "127.0.1:0",                // Invalid ip.
+            "tls-creds=vnc-tls-creds0",  // No ip:port.
+            "127.0.0.1",                 // No port.
+            "1",                         // No ip.
+            "0.0.0.0:65536",             // Invalid port.
+            "0.0.0.0:59636",             // Invalid port.
+            "0.0.0.0:2147483647",        // Invalie port.
+            "0.0.0.0:-1",                // Invalid port.
+            "0.0.0.0:123ab",             // Invalid port.
+            "127.257.0.1:0",             // Invalid ip.
+            "127.0.0.0.1:0",             // Invalid ip.
+            "127.12ab.0.1:0",            // Invalid ip.
+            "127.0.1:0",                 // Invalid ip.
+            "0.0.0.0:1,websocket=5901",  // Websocket port conflicts with vnc port.
+            "0.0.0.0:1,websocket=65536", // Invalid websocket port.
+            "0.0.0.0:1,heads=0",         // Invalid heads.
+            "0.0.0.0:1,heads=17",        // Invalid heads.
+            "0.0.0.0:59635,heads=2",     // Port of head 1 is out of range.
         ];
         for config_line in config_lines {
             let mut vm_config = VmConfig::default();
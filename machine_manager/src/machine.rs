@@ -18,10 +18,12 @@ use strum::VariantNames;
 
 use crate::config::ShutdownAction;
 use crate::qmp::qmp_schema::{
-    BlockDevAddArgument, BlockdevSnapshotInternalArgument, CameraDevAddArgument,
-    CharDevAddArgument, ChardevInfo, Cmd, CmdLine, CmdParameter, DeviceAddArgument, DeviceProps,
-    Events, GicCap, HumanMonitorCmdArgument, IothreadInfo, KvmInfo, MachineInfo,
-    MigrateCapabilities, NetDevAddArgument, PropList, QmpCommand, QmpErrorClass, QmpEvent, Target,
+    BlockDevAddArgument, BlockdevChangeMediumArgument, BlockdevSnapshotInternalArgument,
+    CameraDevAddArgument, CharDevAddArgument, ChardevInfo, Cmd, CmdLine, CmdParameter,
+    DeviceAddArgument, DeviceProps, EjectArgument, Events, ExpirePasswordArgument, GicCap,
+    HumanMonitorCmdArgument, IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities,
+    MigrateParameters, NetDevAddArgument, PropList, QmpCommand, QmpErrorClass, QmpEvent,
+    RecordStartArgument, RingbufReadArgument, ScreendumpArgument, SetPasswordArgument, Target,
     TypeLists, UpdateRegionArgument,
 };
 use crate::qmp::{Response, Version};
@@ -171,6 +173,12 @@ pub trait DeviceInterface {
     /// Delete a block device.
     fn blockdev_del(&self, node_name: String) -> Response;
 
+    /// Eject the medium of a removable device.
+    fn eject(&self, args: EjectArgument) -> Response;
+
+    /// Change the medium of a removable device.
+    fn blockdev_change_medium(&self, args: BlockdevChangeMediumArgument) -> Response;
+
     /// Create a new network device.
     fn netdev_add(&mut self, args: Box<NetDevAddArgument>) -> Response;
 
@@ -182,6 +190,9 @@ pub trait DeviceInterface {
     /// Remove a chardev device.
     fn chardev_remove(&mut self, _id: String) -> Response;
 
+    /// Read data from a ringbuf chardev.
+    fn ringbuf_read(&self, args: RingbufReadArgument) -> Response;
+
     /// Creates a new camera device.
     fn cameradev_add(&mut self, args: CameraDevAddArgument) -> Response;
 
@@ -200,6 +211,21 @@ pub trait DeviceInterface {
     /// Query the info of vnc server.
     fn query_vnc(&self) -> Response;
 
+    /// Save the current image of display console to file.
+    fn screendump(&self, args: ScreendumpArgument) -> Response;
+
+    /// Start recording the image of display console to file.
+    fn record_start(&self, args: RecordStartArgument) -> Response;
+
+    /// Stop recording the image of display console.
+    fn record_stop(&self) -> Response;
+
+    /// Set the password of remote display.
+    fn set_password(&self, args: SetPasswordArgument) -> Response;
+
+    /// Set the expiration time of remote display passwords.
+    fn expire_password(&self, args: ExpirePasswordArgument) -> Response;
+
     /// Set balloon's size.
     fn balloon(&self, size: u64) -> Response;
 
@@ -502,6 +528,21 @@ pub trait MigrateInterface {
     fn cancel_migrate(&self) -> Response {
         Response::create_empty_response()
     }
+
+    /// Set parameters of migration.
+    fn migrate_set_parameters(&self, _args: MigrateParameters) -> Response {
+        Response::create_empty_response()
+    }
+
+    /// Returns the current parameters of migration.
+    fn query_migrate_parameters(&self) -> Response {
+        Response::create_empty_response()
+    }
+
+    /// Returns the snapshot chain of the given snapshot.
+    fn query_snapshots(&self, _path: String) -> Response {
+        Response::create_empty_response()
+    }
 }
 
 /// Machine interface which is exposed to inner hypervisor.
@@ -441,10 +441,12 @@ fn qmp_command_exec(
         (query_iothreads, query_iothreads),
         (query_migrate, query_migrate),
         (cancel_migrate, cancel_migrate),
+        (query_migrate_parameters, query_migrate_parameters),
         (query_cpus, query_cpus),
         (query_balloon, query_balloon),
         (query_mem, query_mem),
         (query_vnc, query_vnc),
+        (record_stop, record_stop),
         (list_type, list_type),
         (query_hotpluggable_cpus, query_hotpluggable_cpus);
         (input_event, input_event, key, value),
@@ -455,12 +457,21 @@ fn qmp_command_exec(
         (chardev_remove, chardev_remove, id),
         (cameradev_del, cameradev_del,id),
         (balloon, balloon, value),
-        (migrate, migrate, uri);
+        (migrate, migrate, uri),
+        (query_snapshots, query_snapshots, path);
         (device_add, device_add),
         (blockdev_add, blockdev_add),
         (netdev_add, netdev_add),
         (chardev_add, chardev_add),
+        (ringbuf_read, ringbuf_read),
         (cameradev_add, cameradev_add),
+        (migrate_set_parameters, migrate_set_parameters),
+        (screendump, screendump),
+        (eject, eject),
+        (blockdev_change_medium, blockdev_change_medium),
+        (record_start, record_start),
+        (set_password, set_password),
+        (expire_password, expire_password),
         (update_region, update_region),
         (human_monitor_command, human_monitor_command),
         (blockdev_snapshot_internal_sync, blockdev_snapshot_internal_sync),
@@ -550,6 +561,16 @@ impl QmpChannel {
         Self::inner().fds.read().unwrap().get(name).copied()
     }
 
+    /// Remove extern file descriptor restored in `QMP_CHANNEL`, the caller
+    /// takes the ownership of the returned file descriptor.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor.
+    pub fn remove_fd(name: &str) -> Option<RawFd> {
+        Self::inner().fds.write().unwrap().remove(name)
+    }
+
     /// Send a `QmpEvent` to client.
     ///
     /// # Arguments
@@ -114,6 +114,12 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "ringbuf-read")]
+    ringbuf_read {
+        arguments: ringbuf_read,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     netdev_add {
         arguments: Box<netdev_add>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -174,6 +180,19 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "eject")]
+    eject {
+        arguments: eject,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-change-medium")]
+    #[strum(serialize = "blockdev-change-medium")]
+    blockdev_change_medium {
+        arguments: blockdev_change_medium,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "balloon")]
     balloon {
         #[serde(default)]
@@ -195,6 +214,39 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "screendump")]
+    screendump {
+        arguments: screendump,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "record-start")]
+    #[strum(serialize = "record-start")]
+    record_start {
+        arguments: record_start,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "record-stop")]
+    #[strum(serialize = "record-stop")]
+    record_stop {
+        #[serde(default)]
+        arguments: record_stop,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "set_password")]
+    set_password {
+        arguments: set_password,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "expire_password")]
+    expire_password {
+        arguments: expire_password,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-vnc")]
     #[strum(serialize = "query-vnc")]
     query_vnc {
@@ -223,6 +275,29 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "migrate-set-parameters")]
+    #[strum(serialize = "migrate-set-parameters")]
+    migrate_set_parameters {
+        #[serde(default)]
+        arguments: migrate_set_parameters,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-migrate-parameters")]
+    #[strum(serialize = "query-migrate-parameters")]
+    query_migrate_parameters {
+        #[serde(default)]
+        arguments: query_migrate_parameters,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-snapshots")]
+    #[strum(serialize = "query-snapshots")]
+    query_snapshots {
+        arguments: query_snapshots,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-version")]
     query_version {
         #[serde(default)]
@@ -833,7 +908,7 @@ impl Command for netdev_add {
 ///
 /// * `id` - the device's ID, must be unique.
 /// * `path` - the backend camera file, eg. /dev/video0.
-/// * `driver` - the backend type, eg. v4l2.
+/// * `driver` - the backend type, eg. v4l2, file or testpattern.
 ///
 ///
 /// # Examples
@@ -959,6 +1034,42 @@ impl Command for chardev_remove {
     }
 }
 
+/// ringbuf-read
+///
+/// Read and remove the oldest data from a ringbuf chardev.
+///
+/// # Arguments
+///
+/// * `device` - The ID of the ringbuf chardev.
+/// * `size` - Max bytes to read.
+/// * `format` - "utf8" or "base64". Default is "utf8".
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "ringbuf-read",
+///      "arguments": { "device": "ringbuf0", "size": 1024, "format": "utf8" } }
+/// <- { "return": "login: " }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ringbuf_read {
+    pub device: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+pub type RingbufReadArgument = ringbuf_read;
+
+impl Command for ringbuf_read {
+    type Res = String;
+
+    fn back(self) -> String {
+        Default::default()
+    }
+}
+
 /// device_del
 ///
 /// Remove a device from a guest
@@ -1016,6 +1127,78 @@ impl Command for blockdev_del {
     }
 }
 
+/// eject:
+///
+/// Eject the medium of a removable device, such as the CD-ROM of usb-storage.
+///
+/// # Arguments
+///
+/// * `device` - The drive id of the removable device.
+/// * `force` - Eject the medium even if the guest has locked the tray. Default is false.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "eject", "arguments": { "device": "drive-cd0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct eject {
+    pub device: String,
+    #[serde(default)]
+    pub force: bool,
+}
+
+pub type EjectArgument = eject;
+
+impl Command for eject {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-change-medium:
+///
+/// Change the medium of a removable device, the old medium is ejected first.
+///
+/// # Arguments
+///
+/// * `device` - The drive id of the removable device.
+/// * `filename` - Path of the new medium image.
+/// * `format` - Image format of the new medium, "raw" or "qcow2". Default is "raw".
+/// * `force` - Eject the old medium even if the guest has locked the tray. Default is false.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-change-medium",
+///      "arguments": { "device": "drive-cd0", "filename": "/path/to/new.iso" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_change_medium {
+    pub device: String,
+    pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default)]
+    pub force: bool,
+}
+
+pub type BlockdevChangeMediumArgument = blockdev_change_medium;
+
+impl Command for blockdev_change_medium {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// netdev_del
 ///
 /// Remove a network backend.
@@ -1371,6 +1554,145 @@ impl Command for cancel_migrate {
 pub struct MigrationInfo {
     #[serde(rename = "status", default, skip_serializing_if = "Option::is_none")]
     pub status: Option<String>,
+    #[serde(rename = "ram", default, skip_serializing_if = "Option::is_none")]
+    pub ram: Option<MigrationStats>,
+    #[serde(
+        rename = "blocked-reasons",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub blocked_reasons: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrationStats {
+    #[serde(rename = "total")]
+    pub total: u64,
+    #[serde(rename = "transferred")]
+    pub transferred: u64,
+    #[serde(rename = "remaining")]
+    pub remaining: u64,
+}
+
+/// migrate-set-parameters
+///
+/// Set various migration parameters.
+///
+/// # Arguments
+///
+/// * `tls-creds` - Id of tls-creds object used to encrypt tcp migration, empty string disables tls.
+/// * `snapshot-incremental` - Take snapshot incrementally against the previous one.
+/// * `snapshot-compress` - Compress memory data of snapshot.
+/// * `snapshot-background` - Take snapshot in background without pausing VM.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "migrate-set-parameters",
+///      "arguments": { "tls-creds": "migrate-tls-creds0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct migrate_set_parameters {
+    #[serde(rename = "tls-creds", default, skip_serializing_if = "Option::is_none")]
+    pub tls_creds: Option<String>,
+    #[serde(
+        rename = "snapshot-incremental",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub snapshot_incremental: Option<bool>,
+    #[serde(
+        rename = "snapshot-compress",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub snapshot_compress: Option<bool>,
+    #[serde(
+        rename = "snapshot-background",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub snapshot_background: Option<bool>,
+}
+
+pub type MigrateParameters = migrate_set_parameters;
+
+impl Command for migrate_set_parameters {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-migrate-parameters
+///
+/// Returns information about the current migration parameters.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-migrate-parameters" }
+/// <- { "return": { "tls-creds": "migrate-tls-creds0", "snapshot-incremental": false,
+///      "snapshot-compress": false, "snapshot-background": false } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_migrate_parameters {}
+
+impl Command for query_migrate_parameters {
+    type Res = MigrateParameters;
+
+    fn back(self) -> MigrateParameters {
+        Default::default()
+    }
+}
+
+/// query-snapshots
+///
+/// Returns the snapshot chain from the given snapshot to the full snapshot it
+/// is based on.
+///
+/// # Arguments
+///
+/// * `path` - snapshot dir path.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-snapshots", "arguments": { "path": "/tmp/snap1" } }
+/// <- { "return": [ { "path": "/tmp/snap1", "type": "incremental", "parent": "/tmp/snap0",
+///                    "compressed": true, "created": 1684120000 },
+///                  { "path": "/tmp/snap0", "type": "full", "compressed": true,
+///                    "created": 1684110000 } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_snapshots {
+    pub path: String,
+}
+
+impl Command for query_snapshots {
+    type Res = Vec<VmSnapshotInfo>;
+
+    fn back(self) -> Vec<VmSnapshotInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct VmSnapshotInfo {
+    #[serde(rename = "path")]
+    pub path: String,
+    #[serde(rename = "type")]
+    pub snapshot_type: String,
+    #[serde(rename = "parent", default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(rename = "compressed")]
+    pub compressed: bool,
+    #[serde(rename = "created")]
+    pub created: u64,
 }
 
 /// getfd
@@ -1567,6 +1889,7 @@ pub struct BalloonInfo {
 ///             "host": "127.0.0.1",
 ///             "service": "50401",
 ///             "family": "ipv4",
+///             "websocket": false,
 ///         ]
 ///         }
 ///     }
@@ -1592,6 +1915,8 @@ pub struct VncInfo {
     pub auth: String,
     #[serde(rename = "family")]
     pub family: String,
+    #[serde(rename = "share-policy")]
+    pub share_policy: String,
     #[serde(rename = "clients")]
     pub clients: Vec<VncClientInfo>,
 }
@@ -1604,6 +1929,192 @@ pub struct VncClientInfo {
     pub service: String,
     #[serde(rename = "family")]
     pub family: String,
+    #[serde(rename = "websocket")]
+    pub websocket: bool,
+    #[serde(rename = "share")]
+    pub share: String,
+    #[serde(rename = "view-only")]
+    pub view_only: bool,
+    #[serde(rename = "audio")]
+    pub audio: bool,
+}
+
+/// screendump:
+///
+/// Save the current image of a display console to file.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the output file.
+/// * `device` - Device name of the console, such as "virtio-gpu0" and "ramfb".
+///              The activate console is used if not specified.
+/// * `format` - Image format of the output file, "png" or "ppm". Default is "png".
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "screendump",
+///      "arguments": { "filename": "/tmp/image.png", "device": "virtio-gpu0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct screendump {
+    #[serde(rename = "filename")]
+    pub filename: String,
+    #[serde(rename = "device", default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(rename = "format", default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+pub type ScreendumpArgument = screendump;
+
+impl Command for screendump {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// record-start:
+///
+/// Start recording the image of a display console to file.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the record file.
+/// * `device` - Device name of the console, the activate console is used if not specified.
+/// * `max-size` - Max size in bytes of the record files. Default is 256MiB.
+/// * `interval` - Min interval in millisecond between frames. Default is 100.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "record-start",
+///      "arguments": { "filename": "/tmp/screen.rec", "max-size": 67108864 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct record_start {
+    #[serde(rename = "filename")]
+    pub filename: String,
+    #[serde(rename = "device", default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(rename = "max-size", default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    #[serde(rename = "interval", default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+}
+
+pub type RecordStartArgument = record_start;
+
+impl Command for record_start {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// record-stop:
+///
+/// Stop recording the image of display console.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "record-stop" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct record_stop {}
+
+impl Command for record_stop {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// set_password:
+///
+/// Set the password of remote display. The vnc password authentication
+/// must be enabled by `password` option of vnc.
+///
+/// # Arguments
+///
+/// * `protocol` - Protocol of remote display, only "vnc" is supported.
+/// * `password` - The new password, only the first 8 characters are used by vnc.
+///                Empty password disables the login by it.
+/// * `view-only` - Set the view-only password, keyboard, pointer and clipboard of
+///                 clients logged in by it are ignored. Default is false.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "set_password",
+///      "arguments": { "protocol": "vnc", "password": "secret", "view-only": true } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct set_password {
+    #[serde(rename = "protocol")]
+    pub protocol: String,
+    #[serde(rename = "password")]
+    pub password: String,
+    #[serde(rename = "view-only", default, skip_serializing_if = "Option::is_none")]
+    pub view_only: Option<bool>,
+}
+
+pub type SetPasswordArgument = set_password;
+
+impl Command for set_password {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// expire_password:
+///
+/// Set the expiration time of remote display passwords.
+///
+/// # Arguments
+///
+/// * `protocol` - Protocol of remote display, only "vnc" is supported.
+/// * `time` - "now", "never", "+N" for N seconds from now, or "N" for N
+///            seconds since the epoch.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "expire_password",
+///      "arguments": { "protocol": "vnc", "time": "+60" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct expire_password {
+    #[serde(rename = "protocol")]
+    pub protocol: String,
+    #[serde(rename = "time")]
+    pub time: String,
+}
+
+pub type ExpirePasswordArgument = expire_password;
+
+impl Command for expire_password {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
 }
 
 /// balloon:
@@ -2952,4 +3463,236 @@ mod tests {
         let part_msg = r#"unknown field `invalid_key`, expected `command-line`"#;
         assert!(err_msg.contains(part_msg));
     }
+
+    #[test]
+    fn test_qmp_screendump() {
+        let json_msg = r#"
+        {
+            "execute": "screendump" ,
+            "arguments": {
+                "filename": "/tmp/screen.png",
+                "device": "virtio-gpu0",
+                "format": "png"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // Argument `filename` is required.
+        let json_msg = r#"
+        {
+            "execute": "screendump" ,
+            "arguments": {
+                "device": "ramfb"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"missing field `filename`"#;
+        assert!(err_msg.contains(part_msg));
+    }
+
+    #[test]
+    fn test_qmp_removable_medium() {
+        let json_msg = r#"
+        {
+            "execute": "eject" ,
+            "arguments": {
+                "device": "drive-cd0",
+                "force": true
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        let json_msg = r#"
+        {
+            "execute": "blockdev-change-medium" ,
+            "arguments": {
+                "device": "drive-cd0",
+                "filename": "/path/to/new.iso",
+                "format": "raw",
+                "force": true
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // Argument `filename` is required.
+        let json_msg = r#"
+        {
+            "execute": "blockdev-change-medium" ,
+            "arguments": {
+                "device": "drive-cd0"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"missing field `filename`"#;
+        assert!(err_msg.contains(part_msg));
+    }
+
+    #[test]
+    fn test_qmp_record() {
+        let json_msg = r#"
+        {
+            "execute": "record-start" ,
+            "arguments": {
+                "filename": "/tmp/screen.rec",
+                "max-size": 67108864,
+                "interval": 50
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        let json_msg = r#"
+        {
+            "execute": "record-start" ,
+            "arguments": {
+                "filename": "/tmp/screen.rec",
+                "max_size": 67108864
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"unknown field `max_size`"#;
+        assert!(err_msg.contains(part_msg));
+
+        let json_msg = r#"{ "execute": "record-stop" }"#;
+        assert!(serde_json::from_str::<QmpCommand>(json_msg).is_ok());
+    }
+
+    #[test]
+    fn test_qmp_vnc_password() {
+        let json_msg = r#"
+        {
+            "execute": "set_password" ,
+            "arguments": {
+                "protocol": "vnc",
+                "password": "secret",
+                "view-only": true
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        let json_msg = r#"
+        {
+            "execute": "set_password" ,
+            "arguments": {
+                "password": "secret"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"missing field `protocol`"#;
+        assert!(err_msg.contains(part_msg));
+
+        let json_msg = r#"
+        {
+            "execute": "expire_password" ,
+            "arguments": {
+                "protocol": "vnc",
+                "time": "+60"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        let json_msg = r#"
+        {
+            "execute": "expire_password" ,
+            "arguments": {
+                "protocol": "vnc",
+                "time": "now",
+                "connected": "keep"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"unknown field `connected`"#;
+        assert!(err_msg.contains(part_msg));
+    }
+
+    #[test]
+    fn test_qmp_ringbuf_read() {
+        let json_msg = r#"
+        {
+            "execute": "ringbuf-read" ,
+            "arguments": {
+                "device": "ringbuf0",
+                "size": 1024,
+                "format": "base64"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // Argument `size` is required.
+        let json_msg = r#"
+        {
+            "execute": "ringbuf-read" ,
+            "arguments": {
+                "device": "ringbuf0"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"missing field `size`"#;
+        assert!(err_msg.contains(part_msg));
+    }
 }
@@ -67,8 +67,8 @@ pub(crate) const LEAK_BUCKET_LIMIT: u64 = 100;
 pub struct Socket {
     /// Type for Socket
     sock_type: SocketType,
-    /// Socket listener tuple
-    listener: UnixListener,
+    /// Socket listener tuple, None if the stream is connected when created.
+    listener: Option<UnixListener>,
     /// Socket stream with RwLock
     stream: RwLock<Option<SocketStream>>,
     /// Perform socket command
@@ -88,15 +88,34 @@ impl Socket {
     ) -> Self {
         Socket {
             sock_type: SocketType::Unix,
-            listener,
+            listener: Some(listener),
             stream: RwLock::new(None),
             performer,
         }
     }
 
+    /// Allocates a new `Socket` with connected `UnixStream`, such as the
+    /// monitor on multiplexed chardev.
+    ///
+    /// # Arguments
+    ///
+    /// * `unix_stream` - The `UnixStream` bind to `Socket`.
+    /// * `performer` - The `VM` to perform socket command.
+    pub fn from_unix_stream(
+        unix_stream: UnixStream,
+        performer: Option<Arc<Mutex<dyn MachineExternalInterface>>>,
+    ) -> Self {
+        Socket {
+            sock_type: SocketType::Unix,
+            listener: None,
+            stream: RwLock::new(Some(SocketStream::from_unix_stream(unix_stream))),
+            performer,
+        }
+    }
+
     /// Get listener's fd from `Socket`.
-    pub fn get_listener_fd(&self) -> RawFd {
-        self.listener.as_raw_fd()
+    pub fn get_listener_fd(&self) -> Option<RawFd> {
+        self.listener.as_ref().map(|listener| listener.as_raw_fd())
     }
 
     /// Accept stream and bind to Socket.
@@ -111,7 +130,7 @@ impl Socket {
 
     /// Accept a new incoming connection unix stream from unix listener.
     pub fn accept_unix_stream(&self) -> UnixStream {
-        let (stream, _) = self.listener.accept().unwrap();
+        let (stream, _) = self.listener.as_ref().unwrap().accept().unwrap();
         stream
     }
 
@@ -186,7 +205,9 @@ impl Socket {
         let shared_leak_bucket = leak_bucket.clone();
         let leak_bucket_fd = leak_bucket.lock().unwrap().as_raw_fd();
 
-        self.accept();
+        if self.listener.is_some() {
+            self.accept();
+        }
         QmpChannel::bind_writer(SocketRWHandler::new(self.get_stream_fd()));
         if let Err(e) = self.send_response(true) {
             error!("{:?}", e);
@@ -220,7 +241,7 @@ impl Socket {
         let qmp_notifier = EventNotifier::new(
             NotifierOperation::AddShared,
             self.get_stream_fd(),
-            Some(self.get_listener_fd()),
+            self.get_listener_fd(),
             EventSet::IN | EventSet::HANG_UP,
             vec![handler],
         );
@@ -247,17 +268,27 @@ impl EventNotifierHelper for Socket {
     fn internal_notifiers(shared_socket: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut notifiers = Vec::new();
 
+        let listener_fd = shared_socket.lock().unwrap().get_listener_fd();
         let socket = shared_socket.clone();
-        let handler: Rc<NotifierCallback> =
-            Rc::new(move |_, _| Some(socket.lock().unwrap().create_event_notifier(socket.clone())));
-        let notifier = EventNotifier::new(
-            NotifierOperation::AddShared,
-            shared_socket.lock().unwrap().get_listener_fd(),
-            None,
-            EventSet::IN,
-            vec![handler],
-        );
-        notifiers.push(notifier);
+        match listener_fd {
+            Some(listener_fd) => {
+                let handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+                    Some(socket.lock().unwrap().create_event_notifier(socket.clone()))
+                });
+                let notifier = EventNotifier::new(
+                    NotifierOperation::AddShared,
+                    listener_fd,
+                    None,
+                    EventSet::IN,
+                    vec![handler],
+                );
+                notifiers.push(notifier);
+            }
+            // Stream is already connected.
+            None => {
+                notifiers.append(&mut socket.lock().unwrap().create_event_notifier(socket.clone()))
+            }
+        }
 
         notifiers
     }
@@ -22,6 +22,10 @@ const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 const ATTRIBUTE_NAME: &str = "desc_version";
 const CURRENT_VERSION: &str = "current_version";
 const COMPAT_VERSION: &str = "compat_version";
+/// Optional `var_size` in the version attribute, for example
+/// `#[desc_version(compat_version = "0.1.0", var_size = true)]`, tells that
+/// the state of device is followed by data of variable size.
+const VAR_SIZE: &str = "var_size";
 
 /// Attribute `alias` is used above `field` declaration.
 /// It can set a field name with a alias. If alias is not set, the default
@@ -31,13 +35,13 @@ const FIELD_ATTRIBUTE_NAME: &str = "alias";
 
 /// Parse attribute above a struct.
 /// Version attribute with `current_version` or `compat_version` will be parsed to
-/// two `u32` number.
+/// two `u32` number, and `var_size` will be parsed to a `bool`.
 ///
 /// # Output
 ///
-/// (current_version, compat_version)
-pub fn parse_struct_attributes(attributes: &[syn::Attribute]) -> (u32, u32) {
-    let (mut current_version, mut compat_version) = (0, 0);
+/// (current_version, compat_version, var_size)
+pub fn parse_struct_attributes(attributes: &[syn::Attribute]) -> (u32, u32, bool) {
+    let (mut current_version, mut compat_version, mut var_size) = (0, 0, false);
     for attribute in attributes {
         if attribute.path().is_ident(ATTRIBUTE_NAME) {
             let _ = attribute.parse_nested_meta(|meta| {
@@ -65,12 +69,23 @@ pub fn parse_struct_attributes(attributes: &[syn::Attribute]) -> (u32, u32) {
                     return Ok(());
                 }
 
+                if meta.path.is_ident(VAR_SIZE) {
+                    let value = meta.value()?;
+                    let lit = value.parse::<Lit>()?;
+                    var_size = match lit {
+                        syn::Lit::Bool(lit_bool) => lit_bool.value,
+                        _ => panic!("Unsupported var_size value."),
+                    };
+
+                    return Ok(());
+                }
+
                 Err(meta.error("unrecognized repr"))
             });
         }
     }
 
-    (current_version, compat_version)
+    (current_version, compat_version, var_size)
 }
 
 /// Parse attribute above fields.
@@ -156,9 +171,23 @@ mod test {
             pub struct MyStruct(u16, u32);
         };
 
-        let (current_version, compat_version) = parse_struct_attributes(input.attrs.as_slice());
+        let (current_version, compat_version, var_size) =
+            parse_struct_attributes(input.attrs.as_slice());
 
         assert_eq!(current_version, 1);
         assert_eq!(compat_version, 256);
+        assert!(!var_size);
+
+        let input: ItemStruct = parse_quote! {
+            #[desc_version(compat_version = "0.1.0", var_size = true)]
+            pub struct MyStruct(u16, u32);
+        };
+
+        let (current_version, compat_version, var_size) =
+            parse_struct_attributes(input.attrs.as_slice());
+
+        assert_eq!(current_version, 0);
+        assert_eq!(compat_version, 256);
+        assert!(var_size);
     }
 }
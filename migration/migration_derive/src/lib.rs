@@ -63,14 +63,18 @@ pub fn derive_desc(input: TokenStream) -> TokenStream {
     let ident = input.ident.clone();
 
     // Get attr info
-    let (mut current_version, mut compat_version) =
+    let (mut current_version, mut compat_version, var_size) =
         attr_parser::parse_struct_attributes(&input.attrs);
     attr_parser::validate_version(&mut current_version, &mut compat_version);
 
     let desc = match &input.data {
-        syn::Data::Struct(data_struct) => {
-            struct_parser::parse_struct(data_struct, &ident, current_version, compat_version)
-        }
+        syn::Data::Struct(data_struct) => struct_parser::parse_struct(
+            data_struct,
+            &ident,
+            current_version,
+            compat_version,
+            var_size,
+        ),
         _ => panic!("Only support struct."),
     };
 
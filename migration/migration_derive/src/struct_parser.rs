@@ -20,6 +20,7 @@ pub fn parse_struct(
     ident: &syn::Ident,
     current_version: u32,
     compat_version: u32,
+    var_size: bool,
 ) -> proc_macro2::TokenStream {
     let struct_ident = format_ident!("DeviceStateDesc");
     let name = format!("{}", ident);
@@ -43,6 +44,7 @@ pub fn parse_struct(
             current_version: #current_version,
             compat_version: #compat_version,
             fields: vec![#(#fields), *],
+            var_size: #var_size,
         }
     }
 }
@@ -0,0 +1,300 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{create_dir, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+
+use crate::general::Lifecycle;
+use crate::manager::{MigrationManager, MigrationProgress, MIGRATION_MANAGER};
+use crate::protocol::{FileFormat, MigrationStatus, RamRange};
+use crate::snapshot::{
+    remove_snapshot_files, SnapshotMeta, DEVICE_PATH_SUFFIX, MEMORY_PATH_SUFFIX,
+};
+use util::bitmap::Bitmap;
+use util::unix::host_page_size;
+use util::userfaultfd::UserfaultFd;
+
+/// Max number of pages saved at a time, pending write faults are handled
+/// between them.
+const PAGES_PER_CHUNK: u64 = 256;
+
+/// Snapshot which saves memory in background while VM keeps running.
+///
+/// # Notes
+///
+/// All guest memory is write-protected by userfaultfd when the snapshot starts.
+/// Pages are saved to memory file in order, and the page guest is going to write
+/// is saved first before its write-protection is removed. So the memory file is
+/// consistent to the moment the snapshot started.
+struct BackgroundSnapshot {
+    /// Snapshot dir path.
+    dir: PathBuf,
+    /// Snapshot memory file.
+    file: File,
+    /// Userfaultfd which write-protects guest memory.
+    uffd: UserfaultFd,
+    /// Ram ranges of guest memory.
+    ranges: Vec<RamRange>,
+    /// Bitmaps of saved pages, one for each ram range.
+    saved: Vec<Bitmap<u64>>,
+    /// Host page size.
+    page_size: u64,
+}
+
+impl BackgroundSnapshot {
+    /// Save device state and memory layout, then write-protect guest memory.
+    /// VM must be paused during this call.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - snapshot dir path.
+    fn new(dir: &Path) -> Result<Self> {
+        match File::create(dir.join(DEVICE_PATH_SUFFIX)) {
+            Ok(mut state_file) => {
+                MigrationManager::save_vmstate(Some(FileFormat::Device), &mut state_file)?;
+            }
+            Err(e) => {
+                bail!("Failed to create snapshot state file: {}", e);
+            }
+        }
+
+        let mut file = File::create(dir.join(MEMORY_PATH_SUFFIX))
+            .with_context(|| "Failed to create snapshot memory file")?;
+        MigrationManager::save_header(Some(FileFormat::MemoryFull), &mut file)?;
+        let ranges = MIGRATION_MANAGER
+            .vmm
+            .read()
+            .unwrap()
+            .memory
+            .as_ref()
+            .unwrap()
+            .save_memory_layout(&mut file)?;
+
+        let page_size = host_page_size();
+        let uffd = UserfaultFd::new()?;
+        let mut saved = Vec::new();
+        for range in ranges.iter() {
+            if range.hva == 0 {
+                bail!("Ram range 0x{:x} has no host memory", range.gpa);
+            }
+            // Populate the pages never touched, write to them can't be caught otherwise.
+            for addr in (range.hva..range.hva + range.len).step_by(page_size as usize) {
+                // SAFETY: addr is inside the host memory of ram range.
+                unsafe { std::ptr::read_volatile(addr as *const u8) };
+            }
+            uffd.register_wp(range.hva, range.len)
+                .with_context(|| "Background snapshot only supports anonymous private memory")?;
+            uffd.write_protect(range.hva, range.len, true)?;
+            saved.push(Bitmap::<u64>::new(
+                (range.len / page_size / u64::BITS as u64 + 1) as usize,
+            ));
+        }
+
+        *MIGRATION_MANAGER.progress.write().unwrap() = MigrationProgress {
+            total: ranges.iter().map(|r| r.len).sum(),
+            transferred: 0,
+        };
+
+        Ok(BackgroundSnapshot {
+            dir: dir.to_path_buf(),
+            file,
+            uffd,
+            ranges,
+            saved,
+            page_size,
+        })
+    }
+
+    /// Save all guest memory, and handle the pending write faults between chunks.
+    fn run(&mut self) -> Result<()> {
+        for idx in 0..self.ranges.len() {
+            let nr_pages = self.ranges[idx].len / self.page_size;
+            let mut page = 0;
+            while page < nr_pages {
+                if MigrationManager::is_canceled() {
+                    info!("Background snapshot is canceled");
+                    return Ok(());
+                }
+
+                self.handle_write_faults()?;
+                let count = PAGES_PER_CHUNK.min(nr_pages - page);
+                self.save_pages(idx, page, count)?;
+                page += count;
+            }
+        }
+
+        SnapshotMeta {
+            parent: None,
+            compressed: false,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        }
+        .save(&self.dir)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Save the pages guest is waiting to write.
+    fn handle_write_faults(&mut self) -> Result<()> {
+        while let Some(addr) = self.uffd.read_wp_fault()? {
+            let idx = self
+                .ranges
+                .iter()
+                .position(|r| addr >= r.hva && addr < r.hva + r.len)
+                .with_context(|| format!("Write fault 0x{:x} is out of guest memory", addr))?;
+            let page = (addr - self.ranges[idx].hva) / self.page_size;
+            self.save_pages(idx, page, 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Save the pages not saved yet and remove their write-protection.
+    ///
+    /// # Arguments
+    ///
+    /// * `idx` - Index of ram range.
+    /// * `page` - Index of the first page in ram range.
+    /// * `count` - Number of pages.
+    fn save_pages(&mut self, idx: usize, page: u64, count: u64) -> Result<()> {
+        let range = self.ranges[idx].clone();
+        let mut start = page;
+        while start < page + count {
+            if self.saved[idx].contain(start as usize)? {
+                start += 1;
+                continue;
+            }
+            let mut end = start + 1;
+            while end < page + count && !self.saved[idx].contain(end as usize)? {
+                end += 1;
+            }
+
+            let len = (end - start) * self.page_size;
+            let hva = range.hva + start * self.page_size;
+            // SAFETY: the pages are inside the host memory of ram range.
+            let data = unsafe { std::slice::from_raw_parts(hva as *const u8, len as usize) };
+            self.file
+                .write_all_at(data, range.offset + start * self.page_size)
+                .with_context(|| "Failed to save memory of background snapshot")?;
+            self.saved[idx].set_range(start as usize, (end - start) as usize)?;
+            MIGRATION_MANAGER.progress.write().unwrap().transferred += len;
+            start = end;
+        }
+
+        // Remove the protection even if pages were saved before, to wake up
+        // the threads blocked on them.
+        self.uffd.write_protect(
+            range.hva + page * self.page_size,
+            count * self.page_size,
+            false,
+        )
+    }
+}
+
+impl Drop for BackgroundSnapshot {
+    fn drop(&mut self) {
+        for range in self.ranges.iter() {
+            if let Err(e) = self
+                .uffd
+                .write_protect(range.hva, range.len, false)
+                .and_then(|_| self.uffd.unregister(range.hva, range.len))
+            {
+                error!("Failed to stop write-protecting guest memory: {:?}", e);
+            }
+        }
+    }
+}
+
+impl MigrationManager {
+    /// Save snapshot for `VM` in background.
+    ///
+    /// # Notes
+    ///
+    /// VM is paused only for saving device state and write-protecting guest
+    /// memory, memory is saved by a background thread while VM is running.
+    /// The snapshot has the same layout as the full one saved by `save_snapshot`.
+    ///
+    /// # Argument
+    ///
+    /// * `path` - snapshot dir path. If path dir not exists, will create it.
+    pub fn save_background_snapshot(path: &str) -> Result<()> {
+        let params = Self::params();
+        if params.snapshot_incremental || params.snapshot_compress {
+            bail!("Background snapshot can't be incremental or compressed");
+        }
+
+        // Set status to `Active`
+        MigrationManager::set_status(MigrationStatus::Active)?;
+        MigrationManager::reset_progress();
+
+        // Create snapshot dir.
+        if let Err(e) = create_dir(path) {
+            if e.kind() != std::io::ErrorKind::AlreadyExists {
+                bail!("Failed to create snapshot dir: {}", e);
+            }
+        }
+
+        if let Some(vm) = &MIGRATION_MANAGER.vmm.read().unwrap().vm {
+            if !vm.lock().unwrap().pause() {
+                bail!("Failed to pause VM, background snapshot needs a running VM");
+            }
+        }
+        let snapshot = BackgroundSnapshot::new(Path::new(path));
+        let restarted = MigrationManager::restart_backends();
+        if let Some(vm) = &MIGRATION_MANAGER.vmm.read().unwrap().vm {
+            if !vm.lock().unwrap().resume() {
+                remove_snapshot_files(Path::new(path));
+                bail!("Failed to resume VM");
+            }
+        }
+        let mut snapshot = match restarted.and(snapshot) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                remove_snapshot_files(Path::new(path));
+                return Err(e);
+            }
+        };
+
+        thread::Builder::new()
+            .name("bg_snapshot".to_string())
+            .spawn(move || {
+                let result = snapshot.run();
+                let dir = snapshot.dir.clone();
+                drop(snapshot);
+                // Partial snapshot must not be left behind, it would be restored as a full one.
+                let status = match result {
+                    Ok(()) if MigrationManager::is_canceled() => {
+                        remove_snapshot_files(&dir);
+                        return;
+                    }
+                    Ok(()) => MigrationStatus::Completed,
+                    Err(e) => {
+                        error!("Failed to save background snapshot: {:?}", e);
+                        remove_snapshot_files(&dir);
+                        MigrationStatus::Failed
+                    }
+                };
+                MigrationManager::set_status(status).unwrap_or_else(|e| error!("{:?}", e));
+            })
+            .with_context(|| "Failed to create background snapshot thread")?;
+
+        Ok(())
+    }
+}
@@ -68,4 +68,6 @@ pub enum MigrationError {
     MigrationConfigErr(String, String, String),
     #[error("Invalid snapshot path for restoring snapshot")]
     InvalidSnapshotPath,
+    #[error("Failed to make tls connection: {0}")]
+    TlsError(String),
 }
@@ -20,9 +20,13 @@ use crate::protocol::{
     DeviceStateDesc, FileFormat, MigrationHeader, MigrationStatus, VersionCheck, HEADER_LENGTH,
 };
 use crate::{MigrationError, MigrationManager};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use util::unix::host_page_size;
 
+/// Max length of variable state following a device state, to avoid allocating
+/// huge buffer for corrupted stream.
+const MAX_VAR_STATE_LEN: u64 = 64 * 1024 * 1024;
+
 impl MigrationManager {
     /// Write `MigrationHeader` to `Write` trait object as bytes.
     /// `MigrationHeader` will occupy the first 4096 bytes in snapshot file.
@@ -40,6 +44,7 @@ impl MigrationManager {
             header.desc_len = match format {
                 FileFormat::Device => Self::desc_db_len()?,
                 FileFormat::MemoryFull => (host_page_size() as usize) * 2 - HEADER_LENGTH,
+                FileFormat::MemoryIncremental => 0,
             };
         } else {
             header.desc_len = Self::desc_db_len()?;
@@ -193,6 +198,29 @@ impl MigrationManager {
             }
         }
 
+        if snap_desc.var_size != current_desc.var_size {
+            bail!("Variable size of {} state is mismatched", snap_desc.name);
+        }
+        if snap_desc.var_size {
+            let mut len = [0_u8; size_of::<u64>()];
+            fd.read_exact(&mut len)
+                .with_context(|| "Failed to read length of variable state")?;
+            let var_len = u64::from_le_bytes(len);
+            if var_len > MAX_VAR_STATE_LEN {
+                bail!(
+                    "Variable state length {} of {} exceeds the limit {}",
+                    var_len,
+                    snap_desc.name,
+                    MAX_VAR_STATE_LEN
+                );
+            }
+            let mut var_data = vec![0_u8; var_len as usize];
+            fd.read_exact(&mut var_data)
+                .with_context(|| "Failed to read variable state")?;
+            state_data.extend_from_slice(&len);
+            state_data.extend_from_slice(&var_data);
+        }
+
         Ok((state_data, instance.name))
     }
 
@@ -269,6 +297,16 @@ pub trait Lifecycle {
 
         Ok(())
     }
+
+    /// Restart device backends stopped by saving VM state.
+    fn restart_backends() -> Result<()> {
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        for (_, device) in locked_vmm.devices.iter() {
+            device.lock().unwrap().restart_backend()?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Lifecycle for MigrationManager {}
@@ -14,12 +14,15 @@
 //!
 //! Offer snapshot and migration interface for VM.
 
+pub mod background;
 pub mod error;
 pub mod general;
 pub mod manager;
 pub mod migration;
 pub mod protocol;
 pub mod snapshot;
+pub mod tls;
+pub mod transport;
 
 use std::time::Duration;
 use std::{net::TcpStream, os::unix::net::UnixStream, thread};
@@ -28,9 +31,13 @@ pub use anyhow::Result;
 use log::error;
 
 pub use error::MigrationError;
+use machine_manager::config::TlsCredObjConfig;
 use machine_manager::qmp::{qmp_schema, Response};
 pub use manager::{MigrationHook, MigrationManager};
-pub use protocol::{DeviceStateDesc, FieldDesc, MemBlock, MigrationStatus, StateTransfer};
+pub use protocol::{
+    build_var_state, split_var_state, DeviceStateDesc, FieldDesc, MemBlock, MigrationStatus,
+    RamRange, StateTransfer,
+};
 
 /// Start to snapshot VM.
 ///
@@ -38,7 +45,19 @@ pub use protocol::{DeviceStateDesc, FieldDesc, MemBlock, MigrationStatus, StateT
 ///
 /// * `path` - snapshot dir path. If path dir not exists, will create it.
 pub fn snapshot(path: String) -> Response {
-    if let Err(e) = MigrationManager::save_snapshot(&path) {
+    if let Err(e) = MigrationManager::check_blockers() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        );
+    }
+
+    let result = if MigrationManager::params().snapshot_background {
+        MigrationManager::save_background_snapshot(&path)
+    } else {
+        MigrationManager::save_snapshot(&path)
+    };
+    if let Err(e) = result {
         error!("Failed to migrate to path \'{:?}\': {:?}", path, e);
         let _ = MigrationManager::set_status(MigrationStatus::Failed);
         return Response::create_error_response(
@@ -56,6 +75,13 @@ pub fn snapshot(path: String) -> Response {
 ///
 /// * `path` - Unix socket path, as /tmp/migration.socket.
 pub fn migration_unix_mode(path: String) -> Response {
+    if let Err(e) = MigrationManager::check_blockers() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        );
+    }
+
     let mut socket = match UnixStream::connect(path) {
         Ok(_sock) => {
             // Specify the tcp receiving or send timeout.
@@ -76,24 +102,9 @@ pub fn migration_unix_mode(path: String) -> Response {
         }
     };
 
-    if let Err(e) = thread::Builder::new()
-        .name("unix_migrate".to_string())
-        .spawn(move || {
-            if let Err(e) = MigrationManager::send_migration(&mut socket) {
-                error!("Failed to send migration: {:?}", e);
-                let _ = MigrationManager::recover_from_migration();
-                let _ = MigrationManager::set_status(MigrationStatus::Failed)
-                    .map_err(|e| error!("{:?}", e));
-            }
-        })
-    {
-        return Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-            None,
-        );
-    }
-
-    Response::create_empty_response()
+    spawn_send_migration("unix_migrate", move || {
+        MigrationManager::send_migration(&mut socket)
+    })
 }
 
 /// Start to migrate VM with tcp mode.
@@ -102,7 +113,24 @@ pub fn migration_unix_mode(path: String) -> Response {
 ///
 /// * `path` - Tcp ip and port, as 192.168.1.1:4446.
 pub fn migration_tcp_mode(path: String) -> Response {
-    let mut socket = match TcpStream::connect(path) {
+    if let Err(e) = MigrationManager::check_blockers() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        );
+    }
+
+    let tls_creds = match MigrationManager::tls_creds() {
+        Ok(creds) => creds,
+        Err(e) => {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            )
+        }
+    };
+
+    let mut socket = match TcpStream::connect(&path) {
         Ok(_sock) => {
             // Specify the tcp receiving or send timeout.
             let time_out = Some(Duration::from_secs(30));
@@ -122,10 +150,95 @@ pub fn migration_tcp_mode(path: String) -> Response {
         }
     };
 
+    spawn_send_migration("tcp_migrate", move || match tls_creds {
+        Some(creds) => send_tls_migration(socket, &path, &creds),
+        None => MigrationManager::send_migration(&mut socket),
+    })
+}
+
+/// Send migration over tls stream, both sides verify certificate of peer.
+///
+/// # Arguments
+///
+/// * `socket` - Tcp stream connected to destination VM.
+/// * `path` - Tcp ip and port, as 192.168.1.1:4446 or [::1]:4446.
+/// * `creds` - tls-creds object configuration.
+fn send_tls_migration(socket: TcpStream, path: &str, creds: &TlsCredObjConfig) -> Result<()> {
+    let mut tls_socket = tls::tls_client_stream(socket, tls::uri_host(path), creds)?;
+    MigrationManager::send_migration(&mut tls_socket)
+}
+
+/// Start to migrate VM with fd mode.
+///
+/// # Arguments
+///
+/// * `name` - The name of file descriptor received by qmp command `getfd`.
+pub fn migration_fd_mode(name: String) -> Response {
+    if let Err(e) = MigrationManager::check_blockers() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        );
+    }
+
+    let mut file = match transport::fd_stream(&name) {
+        Ok(file) => file,
+        Err(e) => {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            )
+        }
+    };
+
+    spawn_send_migration("fd_migrate", move || {
+        MigrationManager::send_migration(&mut file)
+    })
+}
+
+/// Start to migrate VM with exec mode.
+///
+/// # Arguments
+///
+/// * `cmd` - The command of helper process, migration stream is written to
+///   its stdin and the response of destination is read from its stdout.
+pub fn migration_exec_mode(cmd: String) -> Response {
+    if let Err(e) = MigrationManager::check_blockers() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        );
+    }
+
+    let mut stream = match transport::ExecStream::new(&cmd) {
+        Ok(stream) => stream,
+        Err(e) => {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            )
+        }
+    };
+
+    spawn_send_migration("exec_migrate", move || {
+        MigrationManager::send_migration(&mut stream)
+    })
+}
+
+/// Send migration in a new thread, and recover VM if migration fails.
+///
+/// # Arguments
+///
+/// * `name` - Name of the migration thread.
+/// * `send` - The function to send migration.
+fn spawn_send_migration<F>(name: &str, send: F) -> Response
+where
+    F: FnOnce() -> Result<()> + Send + 'static,
+{
     if let Err(e) = thread::Builder::new()
-        .name("tcp_migrate".to_string())
+        .name(name.to_string())
         .spawn(move || {
-            if let Err(e) = MigrationManager::send_migration(&mut socket) {
+            if let Err(e) = send() {
                 error!("Failed to send migration: {:?}", e);
                 let _ = MigrationManager::recover_from_migration();
                 let _ = MigrationManager::set_status(MigrationStatus::Failed)
@@ -137,16 +250,118 @@ pub fn migration_tcp_mode(path: String) -> Response {
             qmp_schema::QmpErrorClass::GenericError(e.to_string()),
             None,
         );
-    };
+    }
+
+    Response::create_empty_response()
+}
+
+/// Set parameters of migration.
+///
+/// # Arguments
+///
+/// * `args` - Parameters to be set, the ones not given are kept unchanged.
+pub fn set_migrate_parameters(args: qmp_schema::MigrateParameters) -> Response {
+    if MigrationManager::is_active() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "Can't set parameters during migration".to_string(),
+            ),
+            None,
+        );
+    }
+
+    if args.tls_creds.is_some() {
+        if let Err(e) = MigrationManager::set_tls_creds(args.tls_creds) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+    }
+
+    if let Err(e) = MigrationManager::set_snapshot_params(
+        args.snapshot_incremental,
+        args.snapshot_compress,
+        args.snapshot_background,
+    ) {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        );
+    }
 
     Response::create_empty_response()
 }
 
+/// Query the current parameters of migration.
+pub fn query_migrate_parameters() -> Response {
+    let params = MigrationManager::params();
+    let migrate_params = qmp_schema::MigrateParameters {
+        tls_creds: Some(params.tls_creds.unwrap_or_default()),
+        snapshot_incremental: Some(params.snapshot_incremental),
+        snapshot_compress: Some(params.snapshot_compress),
+        snapshot_background: Some(params.snapshot_background),
+    };
+
+    Response::create_response(serde_json::to_value(migrate_params).unwrap(), None)
+}
+
+/// Query the snapshot chain from `path` to the full snapshot it is based on.
+///
+/// # Arguments
+///
+/// * `path` - snapshot dir path.
+pub fn query_snapshots(path: String) -> Response {
+    let chain = match MigrationManager::snapshot_chain(&path) {
+        Ok(chain) => chain,
+        Err(e) => {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            )
+        }
+    };
+
+    let snapshots: Vec<qmp_schema::VmSnapshotInfo> = chain
+        .into_iter()
+        .map(|(path, meta)| qmp_schema::VmSnapshotInfo {
+            path,
+            snapshot_type: if meta.is_incremental() {
+                "incremental".to_string()
+            } else {
+                "full".to_string()
+            },
+            parent: meta.parent,
+            compressed: meta.compressed,
+            created: meta.created,
+        })
+        .collect();
+
+    Response::create_response(serde_json::to_value(snapshots).unwrap(), None)
+}
+
 /// Query the current migration status.
 pub fn query_migrate() -> Response {
     let status_str = MigrationManager::status().to_string();
+    let progress = MigrationManager::progress();
+    let ram = if progress.total != 0 {
+        Some(qmp_schema::MigrationStats {
+            total: progress.total,
+            transferred: progress.transferred,
+            remaining: progress.total - progress.transferred,
+        })
+    } else {
+        None
+    };
+    let blocked_reasons = MigrationManager::blocked_reasons();
     let migration_info = qmp_schema::MigrationInfo {
         status: Some(status_str),
+        ram,
+        blocked_reasons: if blocked_reasons.is_empty() {
+            None
+        } else {
+            Some(blocked_reasons)
+        },
     };
 
     Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
@@ -10,7 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::hash::Hash;
 use std::io::{Read, Write};
@@ -22,9 +22,9 @@ use once_cell::sync::Lazy;
 
 use crate::general::translate_id;
 use crate::migration::DirtyBitmap;
-use crate::protocol::{DeviceStateDesc, MemBlock, MigrationStatus, StateTransfer};
-use anyhow::{Context, Result};
-use machine_manager::config::VmConfig;
+use crate::protocol::{DeviceStateDesc, MemBlock, MigrationStatus, RamRange, StateTransfer};
+use anyhow::{bail, Context, Result};
+use machine_manager::config::{TlsCredObjConfig, VmConfig};
 use machine_manager::machine::MachineLifecycle;
 use util::byte_code::ByteCode;
 
@@ -35,6 +35,10 @@ pub(crate) static MIGRATION_MANAGER: Lazy<MigrationManager> = Lazy::new(|| Migra
     status: Arc::new(RwLock::new(MigrationStatus::None)),
     vmm_bitmaps: Arc::new(RwLock::new(HashMap::new())),
     limit: Arc::new(RwLock::new(MigrationLimit::default())),
+    params: Arc::new(RwLock::new(MigrationParams::default())),
+    snapshot_base: Arc::new(RwLock::new(None)),
+    progress: Arc::new(RwLock::new(MigrationProgress::default())),
+    blockers: Arc::new(RwLock::new(BTreeMap::new())),
 });
 
 /// A hook for `Device` to save device state to `Write` object and load device
@@ -99,6 +103,16 @@ pub trait MigrationHook: StateTransfer {
         Ok(())
     }
 
+    /// Save memory state without memory data to `Write` trait, and get the ram
+    /// ranges whose data follows the state in the memory file.
+    ///
+    /// # Arguments
+    ///
+    /// * _fd - The `Write` trait object to save memory state.
+    fn save_memory_layout(&self, _fd: &mut dyn Write) -> Result<Vec<RamRange>> {
+        Ok(Vec::new())
+    }
+
     /// Restore memory state from memory.
     ///
     /// # Arguments
@@ -138,6 +152,33 @@ pub trait MigrationHook: StateTransfer {
     fn resume(&mut self) -> Result<()> {
         Ok(())
     }
+
+    /// Restart the device backend stopped by saving device state.
+    ///
+    /// # Notes
+    ///
+    /// For some device, such as vhost-user device, backend is stopped when
+    /// getting device state, it need to be restarted if source VM keeps running.
+    fn restart_backend(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Start logging the guest memory written by device backend outside of
+    /// the VMM, such as vhost-user backend.
+    fn start_dirty_log(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stop logging the guest memory written by device backend.
+    fn stop_dirty_log(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Mark the guest memory written by device backend since last sync to
+    /// vmm dirty bitmaps.
+    fn sync_dirty_log(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// The instance represents a single object in VM.
@@ -169,8 +210,8 @@ pub struct Vmm {
     pub memory: Option<Arc<dyn MigrationHook + Send + Sync>>,
     /// Trait to represent transports.
     pub transports: HashMap<u64, Arc<Mutex<dyn MigrationHook + Send + Sync>>>,
-    /// Trait to represent devices.
-    pub devices: HashMap<u64, Arc<Mutex<dyn MigrationHook + Send + Sync>>>,
+    /// Trait to represent devices, which are only accessed with the lock held.
+    pub devices: HashMap<u64, Arc<Mutex<dyn MigrationHook + Send>>>,
     #[cfg(target_arch = "aarch64")]
     /// Trait to represent GIC devices(GICv3, GICv3 ITS).
     pub gic_group: HashMap<u64, Arc<dyn MigrationHook + Send + Sync>>,
@@ -199,6 +240,28 @@ impl Default for MigrationLimit {
     }
 }
 
+/// Parameters of migration which can be set by user.
+#[derive(Default, Clone)]
+pub struct MigrationParams {
+    /// Id of tls-creds object used to encrypt the tcp migration stream.
+    pub tls_creds: Option<String>,
+    /// Take snapshot incrementally against the previous one.
+    pub snapshot_incremental: bool,
+    /// Compress memory data of snapshot.
+    pub snapshot_compress: bool,
+    /// Take snapshot in background without pausing VM.
+    pub snapshot_background: bool,
+}
+
+/// Progress of memory data transferred by migration.
+#[derive(Default, Clone, Copy)]
+pub struct MigrationProgress {
+    /// Total bytes of memory data.
+    pub total: u64,
+    /// Bytes of memory data which have been transferred.
+    pub transferred: u64,
+}
+
 /// This structure is to manage all resource during migration.
 /// It is also the only way to call on `MIGRATION_MANAGER`.
 pub struct MigrationManager {
@@ -212,6 +275,15 @@ pub struct MigrationManager {
     pub vmm_bitmaps: Arc<RwLock<HashMap<u32, DirtyBitmap>>>,
     /// Limiting elements of migration.
     pub limit: Arc<RwLock<MigrationLimit>>,
+    /// Parameters of migration.
+    pub params: Arc<RwLock<MigrationParams>>,
+    /// Path of the previous snapshot, dirty pages are tracked against it
+    /// for incremental snapshot.
+    pub snapshot_base: Arc<RwLock<Option<String>>>,
+    /// Progress of memory data transferred.
+    pub progress: Arc<RwLock<MigrationProgress>>,
+    /// Reasons of devices which block migration, indexed by device id.
+    pub blockers: Arc<RwLock<BTreeMap<String, String>>>,
 }
 
 impl MigrationManager {
@@ -316,7 +388,7 @@ impl MigrationManager {
         device: Arc<Mutex<T>>,
         id: &str,
     ) where
-        T: MigrationHook + Sync + Send + 'static,
+        T: MigrationHook + Send + 'static,
     {
         let name = device_desc.name.clone() + "/" + id;
         Self::register_device_desc(device_desc);
@@ -359,6 +431,97 @@ impl MigrationManager {
         locked_vmm.gic_group.insert(translate_id(id), gic);
     }
 
+    /// Set the tls-creds object used by tcp migration.
+    ///
+    /// # Arguments
+    ///
+    /// * `tls_creds` - Id of tls-creds object, `None` or empty string disables tls.
+    pub fn set_tls_creds(tls_creds: Option<String>) -> Result<()> {
+        let tls_creds = tls_creds.filter(|id| !id.is_empty());
+        if let Some(id) = &tls_creds {
+            Self::get_tls_creds_config(id)?;
+        }
+        MIGRATION_MANAGER.params.write().unwrap().tls_creds = tls_creds;
+
+        Ok(())
+    }
+
+    /// Get the tls-creds object used by tcp migration, `None` if tls is disabled.
+    pub fn tls_creds() -> Result<Option<TlsCredObjConfig>> {
+        match MIGRATION_MANAGER.params.read().unwrap().tls_creds.as_ref() {
+            Some(id) => Ok(Some(Self::get_tls_creds_config(id)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get current parameters of migration.
+    pub fn params() -> MigrationParams {
+        MIGRATION_MANAGER.params.read().unwrap().clone()
+    }
+
+    /// Get progress of memory data transferred.
+    pub fn progress() -> MigrationProgress {
+        *MIGRATION_MANAGER.progress.read().unwrap()
+    }
+
+    /// Clear progress left by the previous migration or snapshot.
+    pub fn reset_progress() {
+        *MIGRATION_MANAGER.progress.write().unwrap() = MigrationProgress::default();
+    }
+
+    /// Add a migration blocker, migration and snapshot are refused until the
+    /// blocker is deleted.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The unique id for device.
+    /// * `reason` - Why the device can't be migrated.
+    pub fn add_blocker(id: &str, reason: &str) {
+        MIGRATION_MANAGER
+            .blockers
+            .write()
+            .unwrap()
+            .insert(id.to_string(), reason.to_string());
+    }
+
+    /// Delete the migration blocker of device.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The unique id for device.
+    pub fn del_blocker(id: &str) {
+        MIGRATION_MANAGER.blockers.write().unwrap().remove(id);
+    }
+
+    /// Get reasons of all migration blockers.
+    pub fn blocked_reasons() -> Vec<String> {
+        MIGRATION_MANAGER
+            .blockers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, reason)| format!("{}: {}", id, reason))
+            .collect()
+    }
+
+    /// Check that no device blocks migration.
+    pub fn check_blockers() -> Result<()> {
+        let reasons = Self::blocked_reasons();
+        if !reasons.is_empty() {
+            bail!("Migration is blocked by devices: {}", reasons.join(", "));
+        }
+        Ok(())
+    }
+
+    fn get_tls_creds_config(id: &str) -> Result<TlsCredObjConfig> {
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        let locked_config = locked_vmm.config.lock().unwrap();
+        match locked_config.object.tls_object.get(id) {
+            Some(creds) => Ok(creds.clone()),
+            None => bail!("tls-creds object {} is not found", id),
+        }
+    }
+
     /// Unregister transport instance from vmm.
     ///
     /// # Arguments
@@ -423,4 +586,21 @@ mod tests {
             translate_id("DeviceV2State")
         );
     }
+
+    #[test]
+    fn test_migration_blocker() {
+        assert!(MigrationManager::check_blockers().is_ok());
+
+        MigrationManager::add_blocker("vfio0", "vfio device is not migratable");
+        MigrationManager::add_blocker("vfio0", "vfio device is not migratable");
+        assert_eq!(
+            MigrationManager::blocked_reasons(),
+            vec!["vfio0: vfio device is not migratable".to_string()]
+        );
+        assert!(MigrationManager::check_blockers().is_err());
+
+        MigrationManager::del_blocker("vfio0");
+        assert!(MigrationManager::blocked_reasons().is_empty());
+        assert!(MigrationManager::check_blockers().is_ok());
+    }
 }
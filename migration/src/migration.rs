@@ -17,7 +17,7 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 use kvm_bindings::kvm_userspace_memory_region as MemorySlot;
-use log::{info, warn};
+use log::{error, info, warn};
 
 use crate::general::Lifecycle;
 use crate::manager::MIGRATION_MANAGER;
@@ -46,7 +46,8 @@ impl MigrationManager {
         // Send source virtual machine configuration.
         Self::send_vm_config(fd).with_context(|| "Failed to send vm config")?;
 
-        // Start logging dirty pages.
+        // Start logging dirty pages, it takes over the dirty log of incremental snapshot.
+        MIGRATION_MANAGER.snapshot_base.write().unwrap().take();
         Self::start_dirty_log().with_context(|| "Failed to start logging dirty page")?;
 
         // Send all memory of virtual machine itself to destination.
@@ -565,6 +566,9 @@ impl MigrationManager {
 
     /// Recover the virtual machine if migration is failed.
     pub fn recover_from_migration() -> Result<()> {
+        if let Err(e) = Self::restart_backends() {
+            error!("Failed to restart device backends: {:?}", e);
+        }
         if let Some(locked_vm) = &MIGRATION_MANAGER.vmm.read().unwrap().vm {
             locked_vm.lock().unwrap().resume();
         }
@@ -665,11 +669,24 @@ pub trait Migratable {
         // Start logging dirty memory in kvm.
         KVM_FDS.load().start_dirty_log()?;
 
+        // Start logging dirty memory in device backends.
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        for (_, device) in locked_vmm.devices.iter() {
+            device.lock().unwrap().start_dirty_log()?;
+        }
+
         Ok(())
     }
 
     /// Stop the dirty log in the kvm and vmm.
     fn stop_dirty_log() -> Result<()> {
+        // Stop logging dirty memory in device backends.
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        for (_, device) in locked_vmm.devices.iter() {
+            device.lock().unwrap().stop_dirty_log()?;
+        }
+        drop(locked_vmm);
+
         // Clear dirty bitmaps from vmm.
         let mut vm_bitmaps = MIGRATION_MANAGER.vmm_bitmaps.write().unwrap();
         *vm_bitmaps = HashMap::new();
@@ -686,6 +703,13 @@ pub trait Migratable {
     ///
     /// * `slot` - The memory slot.
     fn get_dirty_log(slot: &MemorySlot) -> Result<Vec<MemBlock>> {
+        // Mark dirty memory of device backends into vmm.
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        for (_, device) in locked_vmm.devices.iter() {
+            device.lock().unwrap().sync_dirty_log()?;
+        }
+        drop(locked_vmm);
+
         // Get dirty memory from vmm.
         let mut vmm_dirty_bitmap = Vec::new();
         let bitmaps = MIGRATION_MANAGER.vmm_bitmaps.write().unwrap();
@@ -719,7 +743,7 @@ pub trait Migratable {
     /// * `addr` - Start address of dirty memory.
     /// * `len` - Length of dirty memory.
     fn mark_dirty_log(addr: u64, len: u64) {
-        if !MigrationManager::is_active() {
+        if !MigrationManager::is_active() && !MigrationManager::is_snapshot_tracking() {
             return;
         }
 
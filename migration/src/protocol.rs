@@ -282,6 +282,20 @@ pub struct MemBlock {
     pub len: u64,
 }
 
+/// Structure is used to save ram range of VM memory and the offset of its
+/// data in snapshot memory file.
+#[derive(Clone, Default, Debug)]
+pub struct RamRange {
+    /// Guest address.
+    pub gpa: u64,
+    /// Host address.
+    pub hva: u64,
+    /// Size of memory.
+    pub len: u64,
+    /// Offset of memory data in snapshot memory file.
+    pub offset: u64,
+}
+
 /// Magic number for migration header. Those bytes represent "STRATOVIRT".
 const MAGIC_NUMBER: [u8; 16] = [
     0x53, 0x54, 0x52, 0x41, 0x54, 0x4f, 0x56, 0x49, 0x52, 0x54, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
@@ -301,6 +315,7 @@ pub const HEADER_LENGTH: usize = 4096;
 pub enum FileFormat {
     Device,
     MemoryFull,
+    MemoryIncremental,
 }
 
 /// The endianness of byte order.
@@ -492,6 +507,44 @@ pub trait StateTransfer {
     fn get_device_alias(&self) -> u64;
 }
 
+/// Build state of variable size: `DeviceState` structure, length of the
+/// variable data as `u64` and the variable data.
+///
+/// # Arguments
+///
+/// * `state` - `DeviceState` structure as bytes.
+/// * `var_data` - Data of variable size.
+pub fn build_var_state(state: &[u8], var_data: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(state.len() + size_of::<u64>() + var_data.len());
+    data.extend_from_slice(state);
+    data.extend_from_slice(&(var_data.len() as u64).to_le_bytes());
+    data.extend_from_slice(var_data);
+    data
+}
+
+/// Split state of variable size built by `build_var_state`.
+///
+/// # Arguments
+///
+/// * `data` - State of variable size.
+/// * `size` - Size of `DeviceState` structure.
+///
+/// # Output
+///
+/// (`DeviceState` structure as bytes, data of variable size)
+pub fn split_var_state(data: &[u8], size: usize) -> Result<(&[u8], &[u8])> {
+    let var_start = size + size_of::<u64>();
+    if data.len() < var_start {
+        bail!("State of variable size is too short: {}", data.len());
+    }
+    let mut len = [0_u8; size_of::<u64>()];
+    len.copy_from_slice(&data[size..var_start]);
+    if (data.len() - var_start) as u64 != u64::from_le_bytes(len) {
+        bail!("Length of variable state is mismatched");
+    }
+    Ok((&data[..size], &data[var_start..]))
+}
+
 /// The structure to describe `DeviceState` structure with version message.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceStateDesc {
@@ -507,6 +560,10 @@ pub struct DeviceStateDesc {
     pub compat_version: u32,
     /// Field descriptor of `DeviceState` structure.
     pub fields: Vec<FieldDesc>,
+    /// `DeviceState` structure is followed by a `u64` length and data of
+    /// that length.
+    #[serde(default)]
+    pub var_size: bool,
 }
 
 /// The structure to describe struct field in `DeviceState` structure.
@@ -1115,4 +1172,19 @@ pub mod tests {
         let header = MigrationHeader::default();
         assert_eq!(header.check_header().is_ok(), true);
     }
+
+    #[test]
+    fn test_var_state() {
+        let state = [1_u8, 2, 3, 4];
+        let var_data = [5_u8, 6, 7];
+        let data = build_var_state(&state, &var_data);
+        assert_eq!(data.len(), state.len() + size_of::<u64>() + var_data.len());
+
+        let (fixed, var) = split_var_state(&data, state.len()).unwrap();
+        assert_eq!(fixed, &state);
+        assert_eq!(var, &var_data);
+
+        assert!(split_var_state(&data[..data.len() - 1], state.len()).is_err());
+        assert!(split_var_state(&data[..6], state.len()).is_err());
+    }
 }
@@ -12,13 +12,21 @@
 
 use crate::general::{translate_id, Lifecycle};
 use crate::manager::{MigrationManager, MIGRATION_MANAGER};
-use crate::protocol::{DeviceStateDesc, FileFormat, MigrationStatus, HEADER_LENGTH};
+use crate::migration::Migratable;
+use crate::protocol::{DeviceStateDesc, FileFormat, MemBlock, MigrationStatus, HEADER_LENGTH};
 use crate::MigrationError;
 use anyhow::{anyhow, bail, Context, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use hypervisor::kvm::KVM_FDS;
+use log::error;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::ffi::CString;
 use std::fs::{create_dir, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use util::unix::host_page_size;
 
 pub const SERIAL_SNAPSHOT_ID: &str = "serial";
@@ -29,9 +37,104 @@ pub const PL011_SNAPSHOT_ID: &str = "pl011";
 pub const PL031_SNAPSHOT_ID: &str = "pl031";
 
 /// The suffix used for snapshot memory storage.
-const MEMORY_PATH_SUFFIX: &str = "memory";
+pub(crate) const MEMORY_PATH_SUFFIX: &str = "memory";
 /// The suffix used for snapshot device state storage.
-const DEVICE_PATH_SUFFIX: &str = "state";
+pub(crate) const DEVICE_PATH_SUFFIX: &str = "state";
+/// The suffix used for snapshot metadata storage.
+const META_PATH_SUFFIX: &str = "meta";
+/// Max length of snapshot chain, to avoid looping on broken metadata.
+const MAX_SNAPSHOT_CHAIN_LEN: usize = 256;
+
+/// Metadata of snapshot, saved as json in snapshot dir.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    /// Absolute path of parent snapshot, `None` for full snapshot.
+    pub parent: Option<String>,
+    /// Whether memory data is compressed.
+    pub compressed: bool,
+    /// Time of taking snapshot, in seconds since unix epoch.
+    pub created: u64,
+}
+
+impl SnapshotMeta {
+    /// Load metadata from snapshot dir. Snapshot without metadata file
+    /// is treated as an uncompressed full snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - snapshot dir path.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let meta_path = dir.join(META_PATH_SUFFIX);
+        if !meta_path.exists() {
+            return Ok(SnapshotMeta::default());
+        }
+
+        let meta_file = File::open(&meta_path)
+            .with_context(|| format!("Failed to open snapshot metadata {:?}", meta_path))?;
+        let meta = serde_json::from_reader(meta_file)
+            .with_context(|| format!("Invalid snapshot metadata {:?}", meta_path))?;
+
+        Ok(meta)
+    }
+
+    /// Save metadata to snapshot dir.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - snapshot dir path.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let meta_file = File::create(dir.join(META_PATH_SUFFIX))
+            .with_context(|| "Failed to create snapshot metadata file")?;
+        serde_json::to_writer(meta_file, self)?;
+
+        Ok(())
+    }
+
+    /// Whether the snapshot only saves memory dirtied since its parent.
+    pub fn is_incremental(&self) -> bool {
+        self.parent.is_some()
+    }
+}
+
+/// Check the incremental snapshot dir `path` is not in the chain of its parent,
+/// otherwise the metadata of parent is overwritten and the chain is broken.
+///
+/// # Arguments
+///
+/// * `path` - snapshot dir path.
+/// * `parent` - parent snapshot dir path.
+fn check_snapshot_dir(path: &str, parent: &str) -> Result<()> {
+    let dir = std::fs::canonicalize(path)
+        .with_context(|| format!("Failed to get absolute path of {}", path))?;
+    for (chain_dir, _) in MigrationManager::snapshot_chain(parent)? {
+        if std::fs::canonicalize(&chain_dir).is_ok_and(|d| d == dir) {
+            bail!(
+                "Incremental snapshot dir {} is already used by snapshot chain of {}",
+                path,
+                parent
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the files of a cancelled or failed snapshot, so the partial snapshot
+/// can't be restored as a full one.
+///
+/// # Arguments
+///
+/// * `dir` - snapshot dir path.
+pub(crate) fn remove_snapshot_files(dir: &Path) {
+    for suffix in [META_PATH_SUFFIX, MEMORY_PATH_SUFFIX, DEVICE_PATH_SUFFIX] {
+        let file = dir.join(suffix);
+        if let Err(e) = std::fs::remove_file(&file) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("Failed to remove snapshot file {:?}: {:?}", file, e);
+            }
+        }
+    }
+}
 
 impl MigrationManager {
     /// Save snapshot for `VM`.
@@ -39,8 +142,11 @@ impl MigrationManager {
     /// # Notes
     ///
     /// Offers a interface for snapshot functions. This function will make a snapshot dir
-    /// for input path. It will create two file in snapshot dir - device state file `state`
-    /// and memory file `memory`.
+    /// for input path. It will create three file in snapshot dir - device state file `state`,
+    /// memory file `memory` and metadata file `meta`.
+    ///
+    /// If `snapshot-incremental` is set, dirty pages are tracked after the snapshot, and the
+    /// next snapshot only saves memory dirtied since this one.
     ///
     /// # Argument
     ///
@@ -48,6 +154,7 @@ impl MigrationManager {
     pub fn save_snapshot(path: &str) -> Result<()> {
         // Set status to `Active`
         MigrationManager::set_status(MigrationStatus::Active)?;
+        MigrationManager::reset_progress();
 
         // Create snapshot dir.
         if let Err(e) = create_dir(path) {
@@ -56,10 +163,61 @@ impl MigrationManager {
             }
         }
 
+        let params = Self::params();
+        let parent = if params.snapshot_incremental {
+            MIGRATION_MANAGER.snapshot_base.read().unwrap().clone()
+        } else {
+            None
+        };
+        if let Some(parent) = &parent {
+            check_snapshot_dir(path, parent)?;
+        }
+        let meta = SnapshotMeta {
+            parent,
+            compressed: params.snapshot_compress,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+
+        let result = Self::save_snapshot_files(Path::new(path), &meta, params.snapshot_incremental);
+        if result.is_err() {
+            remove_snapshot_files(Path::new(path));
+        }
+        // VM keeps running after snapshot.
+        Self::restart_backends()?;
+        if let Err(e) = result {
+            // Dirty pages since the previous snapshot are lost, the next one must be full.
+            if params.snapshot_incremental {
+                MIGRATION_MANAGER.snapshot_base.write().unwrap().take();
+                Self::stop_dirty_log()?;
+            }
+            return Err(e);
+        }
+
+        if params.snapshot_incremental {
+            let base = std::fs::canonicalize(path)
+                .with_context(|| format!("Failed to get absolute path of {}", path))?;
+            *MIGRATION_MANAGER.snapshot_base.write().unwrap() =
+                Some(base.to_string_lossy().to_string());
+        }
+
+        // Set status to `Completed`
+        MigrationManager::set_status(MigrationStatus::Completed)?;
+
+        Ok(())
+    }
+
+    /// Save device state, memory and metadata files into snapshot dir.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - snapshot dir path.
+    /// * `meta` - metadata of this snapshot.
+    /// * `track_dirty` - start tracking dirty pages for the next incremental snapshot.
+    fn save_snapshot_files(dir: &Path, meta: &SnapshotMeta, track_dirty: bool) -> Result<()> {
         // Save device state
-        let mut vm_state_path = PathBuf::from(path);
-        vm_state_path.push(DEVICE_PATH_SUFFIX);
-        match File::create(vm_state_path) {
+        match File::create(dir.join(DEVICE_PATH_SUFFIX)) {
             Ok(mut state_file) => {
                 Self::save_vmstate(Some(FileFormat::Device), &mut state_file)?;
             }
@@ -69,21 +227,28 @@ impl MigrationManager {
         }
 
         // Save memory data
-        let mut vm_memory_path = PathBuf::from(path);
-        vm_memory_path.push(MEMORY_PATH_SUFFIX);
-        match File::create(vm_memory_path) {
+        match File::create(dir.join(MEMORY_PATH_SUFFIX)) {
             Ok(mut memory_file) => {
-                Self::save_memory(Some(FileFormat::MemoryFull), &mut memory_file)?;
+                if meta.is_incremental() {
+                    Self::save_dirty_memory(meta.compressed, &mut memory_file)?;
+                } else {
+                    if track_dirty {
+                        Self::start_dirty_log()
+                            .with_context(|| "Failed to start logging dirty page")?;
+                    }
+                    Self::save_memory(
+                        Some(FileFormat::MemoryFull),
+                        meta.compressed,
+                        &mut memory_file,
+                    )?;
+                }
             }
             Err(e) => {
                 bail!("Failed to create snapshot memory file: {}", e);
             }
         }
 
-        // Set status to `Completed`
-        MigrationManager::set_status(MigrationStatus::Completed)?;
-
-        Ok(())
+        meta.save(dir)
     }
 
     /// Restore snapshot for `VM`.
@@ -92,6 +257,8 @@ impl MigrationManager {
     ///
     /// Offers a interface for restore snapshot functions. This function will make VM
     /// back to the state restored in snapshot file including both device and memory.
+    /// For incremental snapshot, memory of the full snapshot at the bottom of chain is
+    /// restored first, then the incremental ones are replayed in order.
     ///
     /// # Argument
     ///
@@ -100,22 +267,9 @@ impl MigrationManager {
         // Set status to `Active`
         MigrationManager::set_status(MigrationStatus::Active)?;
 
-        let mut snapshot_path = PathBuf::from(path);
-        if !snapshot_path.is_dir() {
-            return Err(anyhow!(MigrationError::InvalidSnapshotPath));
-        }
+        let chain = Self::snapshot_chain(path)?;
 
-        snapshot_path.push(MEMORY_PATH_SUFFIX);
-        let mut memory_file =
-            File::open(&snapshot_path).with_context(|| "Failed to open memory snapshot file")?;
-        let memory_header = Self::restore_header(&mut memory_file)?;
-        memory_header.check_header()?;
-        if memory_header.format != FileFormat::MemoryFull {
-            bail!("Invalid memory snapshot file");
-        }
-        snapshot_path.pop();
-        snapshot_path.push(DEVICE_PATH_SUFFIX);
-        let mut device_state_file = File::open(&snapshot_path)
+        let mut device_state_file = File::open(Path::new(path).join(DEVICE_PATH_SUFFIX))
             .with_context(|| "Failed to open device state snapshot file")?;
         let device_state_header = Self::restore_header(&mut device_state_file)?;
         device_state_header.check_header()?;
@@ -123,7 +277,29 @@ impl MigrationManager {
             bail!("Invalid device state snapshot file");
         }
 
-        Self::restore_memory(&mut memory_file).with_context(|| "Failed to load snapshot memory")?;
+        for (dir, meta) in chain.iter().rev() {
+            let mut memory_file = File::open(Path::new(dir).join(MEMORY_PATH_SUFFIX))
+                .with_context(|| format!("Failed to open memory snapshot file in {}", dir))?;
+            let memory_header = Self::restore_header(&mut memory_file)?;
+            memory_header.check_header()?;
+            let format = if meta.is_incremental() {
+                FileFormat::MemoryIncremental
+            } else {
+                FileFormat::MemoryFull
+            };
+            if memory_header.format != format {
+                bail!("Invalid memory snapshot file in {}", dir);
+            }
+
+            match (format, meta.compressed) {
+                (FileFormat::MemoryFull, false) => Self::restore_memory(&mut memory_file),
+                (FileFormat::MemoryFull, true) => Self::restore_compressed_memory(&mut memory_file),
+                (_, false) => Self::restore_mem_blocks(&mut memory_file),
+                (_, true) => Self::restore_mem_blocks(&mut ZlibDecoder::new(memory_file)),
+            }
+            .with_context(|| format!("Failed to load snapshot memory in {}", dir))?;
+        }
+
         let snapshot_desc_db =
             Self::restore_desc_db(&mut device_state_file, device_state_header.desc_len)
                 .with_context(|| "Failed to load device descriptor db")?;
@@ -137,16 +313,181 @@ impl MigrationManager {
         Ok(())
     }
 
+    /// Get the snapshot chain from `path` to the full snapshot it is based on,
+    /// the first one is `path` itself.
+    ///
+    /// # Argument
+    ///
+    /// * `path` - snapshot dir path.
+    pub fn snapshot_chain(path: &str) -> Result<Vec<(String, SnapshotMeta)>> {
+        let mut chain = Vec::new();
+        let mut next = Some(path.to_string());
+        while let Some(dir) = next {
+            if chain.len() >= MAX_SNAPSHOT_CHAIN_LEN {
+                bail!("Snapshot chain of {} is too long", path);
+            }
+            if !Path::new(&dir).is_dir() {
+                return Err(anyhow!(MigrationError::InvalidSnapshotPath));
+            }
+
+            let meta = SnapshotMeta::load(Path::new(&dir))?;
+            next = meta.parent.clone();
+            chain.push((dir, meta));
+        }
+
+        Ok(chain)
+    }
+
+    /// Set parameters of snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `incremental` - Take snapshot incrementally against the previous one.
+    /// * `compress` - Compress memory data of snapshot.
+    /// * `background` - Take snapshot in background without pausing VM.
+    pub fn set_snapshot_params(
+        incremental: Option<bool>,
+        compress: Option<bool>,
+        background: Option<bool>,
+    ) -> Result<()> {
+        if incremental == Some(false) {
+            Self::stop_snapshot_tracking()?;
+        }
+
+        let mut params = MIGRATION_MANAGER.params.write().unwrap();
+        if let Some(incremental) = incremental {
+            params.snapshot_incremental = incremental;
+        }
+        if let Some(compress) = compress {
+            params.snapshot_compress = compress;
+        }
+        if let Some(background) = background {
+            params.snapshot_background = background;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether dirty pages are tracked for incremental snapshot.
+    pub fn is_snapshot_tracking() -> bool {
+        MIGRATION_MANAGER.snapshot_base.read().unwrap().is_some()
+    }
+
+    /// Stop tracking dirty pages for incremental snapshot, the next snapshot
+    /// will be a full one.
+    pub fn stop_snapshot_tracking() -> Result<()> {
+        if MIGRATION_MANAGER
+            .snapshot_base
+            .write()
+            .unwrap()
+            .take()
+            .is_some()
+        {
+            Self::stop_dirty_log().with_context(|| "Failed to stop logging dirty page")?;
+        }
+
+        Ok(())
+    }
+
     /// Save memory state and data to `Write` trait object.
     ///
     /// # Arguments
     ///
+    /// * `compressed` - Compress the memory data after header.
     /// * `fd` - The `Write` trait object to save memory data.
-    fn save_memory(file_format: Option<FileFormat>, fd: &mut dyn Write) -> Result<()> {
+    fn save_memory(
+        file_format: Option<FileFormat>,
+        compressed: bool,
+        fd: &mut dyn Write,
+    ) -> Result<()> {
         Self::save_header(file_format, fd)?;
 
         let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
-        locked_vmm.memory.as_ref().unwrap().save_memory(fd)?;
+        let memory = locked_vmm.memory.as_ref().unwrap();
+        if compressed {
+            let mut encoder = ZlibEncoder::new(fd, Compression::default());
+            memory.save_memory(&mut encoder)?;
+            encoder.finish()?;
+        } else {
+            memory.save_memory(fd)?;
+        }
+
+        Ok(())
+    }
+
+    /// Save memory dirtied since the previous snapshot to `Write` trait object.
+    ///
+    /// # Arguments
+    ///
+    /// * `compressed` - Compress the memory data after header.
+    /// * `fd` - The `Write` trait object to save memory data.
+    fn save_dirty_memory(compressed: bool, fd: &mut dyn Write) -> Result<()> {
+        Self::save_header(Some(FileFormat::MemoryIncremental), fd)?;
+
+        let mut blocks: Vec<MemBlock> = Vec::new();
+        let mem_slots = KVM_FDS.load().get_mem_slots();
+        for (_, slot) in mem_slots.lock().unwrap().iter() {
+            blocks.extend(Self::get_dirty_log(slot)?);
+        }
+
+        if compressed {
+            let mut encoder = ZlibEncoder::new(fd, Compression::default());
+            Self::save_mem_blocks(&mut encoder, &blocks)?;
+            encoder.finish()?;
+        } else {
+            Self::save_mem_blocks(fd, &blocks)?;
+        }
+
+        Ok(())
+    }
+
+    /// Save memory blocks, the number of blocks and their ranges are followed
+    /// by the data of each block.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The `Write` trait object to save memory data.
+    /// * `blocks` - The memory blocks need to be saved.
+    fn save_mem_blocks(fd: &mut dyn Write, blocks: &[MemBlock]) -> Result<()> {
+        fd.write_all(&(blocks.len() as u64).to_le_bytes())?;
+        for block in blocks.iter() {
+            fd.write_all(&block.gpa.to_le_bytes())?;
+            fd.write_all(&block.len.to_le_bytes())?;
+        }
+
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        let memory = locked_vmm.memory.as_ref().unwrap();
+        for block in blocks.iter() {
+            memory.send_memory(fd, block.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Load memory blocks saved by `save_mem_blocks` into VM memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The `Read` trait object to restore memory data.
+    fn restore_mem_blocks(fd: &mut dyn Read) -> Result<()> {
+        let mut bytes = [0_u8; 8];
+        fd.read_exact(&mut bytes)?;
+        let nr_blocks = u64::from_le_bytes(bytes);
+
+        let mut blocks: Vec<MemBlock> = Vec::new();
+        for _ in 0..nr_blocks {
+            fd.read_exact(&mut bytes)?;
+            let gpa = u64::from_le_bytes(bytes);
+            fd.read_exact(&mut bytes)?;
+            let len = u64::from_le_bytes(bytes);
+            blocks.push(MemBlock { gpa, len });
+        }
+
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        let memory = locked_vmm.memory.as_ref().unwrap();
+        for block in blocks {
+            memory.recv_memory(fd, block)?;
+        }
 
         Ok(())
     }
@@ -169,6 +510,30 @@ impl MigrationManager {
         Ok(())
     }
 
+    /// Decompress snapshot memory file into memfd, and restore memory from it.
+    /// The layout of memfd is the same as uncompressed memory file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - compressed snapshot memory file.
+    fn restore_compressed_memory(file: &mut File) -> Result<()> {
+        let name = CString::new("stratovirt_snapshot_memory")?;
+        // SAFETY: name is a valid C string.
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), 0) } as RawFd;
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| "Failed to create memfd");
+        }
+        // SAFETY: fd is valid and its ownership is taken by memfd.
+        let mut memfd = unsafe { File::from_raw_fd(fd) };
+
+        memfd.seek(SeekFrom::Start(HEADER_LENGTH as u64))?;
+        std::io::copy(&mut ZlibDecoder::new(file), &mut memfd)
+            .with_context(|| "Failed to decompress memory snapshot file")?;
+        memfd.seek(SeekFrom::Start(HEADER_LENGTH as u64))?;
+
+        Self::restore_memory(&mut memfd)
+    }
+
     /// Save vm state to `Write` trait object as bytes..
     ///
     /// # Arguments
@@ -303,3 +668,97 @@ impl MigrationManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_chain() {
+        let root = std::env::temp_dir().join("stratovirt-snapshot-chain");
+        let _ = std::fs::remove_dir_all(&root);
+        let full = root.join("full");
+        let incr = root.join("incr");
+        std::fs::create_dir_all(&full).unwrap();
+        std::fs::create_dir_all(&incr).unwrap();
+
+        // Snapshot without metadata file is a full one.
+        let chain = MigrationManager::snapshot_chain(full.to_str().unwrap()).unwrap();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].1, SnapshotMeta::default());
+
+        let incr_meta = SnapshotMeta {
+            parent: Some(full.to_str().unwrap().to_string()),
+            compressed: true,
+            created: 1,
+        };
+        incr_meta.save(&incr).unwrap();
+        assert_eq!(SnapshotMeta::load(&incr).unwrap(), incr_meta);
+
+        let chain = MigrationManager::snapshot_chain(incr.to_str().unwrap()).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert!(chain[0].1.is_incremental());
+        assert_eq!(chain[1].0, full.to_str().unwrap());
+        assert!(!chain[1].1.is_incremental());
+
+        // Parent is missing.
+        std::fs::remove_dir_all(&full).unwrap();
+        assert!(MigrationManager::snapshot_chain(incr.to_str().unwrap()).is_err());
+
+        // Snapshot refers to itself.
+        let loop_meta = SnapshotMeta {
+            parent: Some(incr.to_str().unwrap().to_string()),
+            compressed: false,
+            created: 2,
+        };
+        loop_meta.save(&incr).unwrap();
+        assert!(MigrationManager::snapshot_chain(incr.to_str().unwrap()).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_check_snapshot_dir() {
+        let root = std::env::temp_dir().join("stratovirt-snapshot-dir");
+        let _ = std::fs::remove_dir_all(&root);
+        let full = root.join("full");
+        let incr = root.join("incr");
+        let next = root.join("next");
+        std::fs::create_dir_all(&full).unwrap();
+        std::fs::create_dir_all(&incr).unwrap();
+        std::fs::create_dir_all(&next).unwrap();
+        SnapshotMeta {
+            parent: Some(full.to_str().unwrap().to_string()),
+            compressed: false,
+            created: 1,
+        }
+        .save(&incr)
+        .unwrap();
+
+        let incr_path = incr.to_str().unwrap();
+        assert!(check_snapshot_dir(next.to_str().unwrap(), incr_path).is_ok());
+        assert!(check_snapshot_dir(incr_path, incr_path).is_err());
+        assert!(check_snapshot_dir(full.to_str().unwrap(), incr_path).is_err());
+        // The same dir with a different spelling.
+        let alias = root.join("next/../full");
+        assert!(check_snapshot_dir(alias.to_str().unwrap(), incr_path).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_remove_snapshot_files() {
+        let dir = std::env::temp_dir().join("stratovirt-snapshot-remove");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join(DEVICE_PATH_SUFFIX)).unwrap();
+        File::create(dir.join(MEMORY_PATH_SUFFIX)).unwrap();
+
+        // Meta file of partial snapshot is missing.
+        remove_snapshot_files(&dir);
+        assert!(!dir.join(DEVICE_PATH_SUFFIX).exists());
+        assert!(!dir.join(MEMORY_PATH_SUFFIX).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientConnection, PrivateKey,
+    RootCertStore, ServerConfig, ServerConnection, ServerName, StreamOwned,
+};
+
+use crate::MigrationError;
+use machine_manager::config::TlsCredObjConfig;
+
+const TLS_CREDS_CACERT: &str = "cacert.pem";
+const TLS_CREDS_SERVERCERT: &str = "servercert.pem";
+const TLS_CREDS_SERVERKEY: &str = "serverkey.pem";
+const TLS_CREDS_CLIENTCERT: &str = "clientcert.pem";
+const TLS_CREDS_CLIENTKEY: &str = "clientkey.pem";
+const TLS_ENDPOINT_SERVER: &str = "server";
+const TLS_ENDPOINT_CLIENT: &str = "client";
+
+/// Tls stream used by the source VM.
+pub type TlsClientStream = StreamOwned<ClientConnection, TcpStream>;
+/// Tls stream used by the destination VM.
+pub type TlsServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Build the tls configuration of destination VM. The source VM must present a
+/// certificate signed by `cacert.pem` in the credential directory.
+///
+/// # Arguments
+///
+/// * `creds` - tls-creds object configuration.
+pub fn make_server_config(creds: &TlsCredObjConfig) -> Result<Arc<ServerConfig>> {
+    check_endpoint(creds, TLS_ENDPOINT_SERVER)?;
+
+    let client_auth = AllowAnyAuthenticatedClient::new(load_root_store(&creds.dir)?).boxed();
+    let certs = load_certs(&format!("{}/{}", creds.dir, TLS_CREDS_SERVERCERT))?;
+    let key = load_private_key(&format!("{}/{}", creds.dir, TLS_CREDS_SERVERKEY))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!(MigrationError::TlsError(e.to_string())))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Build the tls configuration of source VM. The destination VM must present a
+/// certificate signed by `cacert.pem` in the credential directory.
+///
+/// # Arguments
+///
+/// * `creds` - tls-creds object configuration.
+pub fn make_client_config(creds: &TlsCredObjConfig) -> Result<Arc<ClientConfig>> {
+    check_endpoint(creds, TLS_ENDPOINT_CLIENT)?;
+
+    let certs = load_certs(&format!("{}/{}", creds.dir, TLS_CREDS_CLIENTCERT))?;
+    let key = load_private_key(&format!("{}/{}", creds.dir, TLS_CREDS_CLIENTKEY))?;
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_root_store(&creds.dir)?)
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!(MigrationError::TlsError(e.to_string())))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Wrap a connected tcp stream of source VM into tls stream.
+///
+/// # Arguments
+///
+/// * `sock` - Tcp stream connected to destination VM.
+/// * `host` - Host name or ip address of destination VM, used to verify its certificate.
+/// * `creds` - tls-creds object configuration.
+pub fn tls_client_stream(
+    sock: TcpStream,
+    host: &str,
+    creds: &TlsCredObjConfig,
+) -> Result<TlsClientStream> {
+    let server_name = ServerName::try_from(host)
+        .map_err(|_| anyhow!(MigrationError::TlsError(format!("Invalid host {}", host))))?;
+    let conn = ClientConnection::new(make_client_config(creds)?, server_name)
+        .map_err(|e| anyhow!(MigrationError::TlsError(e.to_string())))?;
+
+    Ok(StreamOwned::new(conn, sock))
+}
+
+/// Get the host of destination VM from tcp uri `path`, as 192.168.1.1:4446 or [::1]:4446.
+pub fn uri_host(path: &str) -> &str {
+    let host = path.rsplit_once(':').map_or(path, |(host, _)| host);
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Wrap an accepted tcp stream of destination VM into tls stream.
+///
+/// # Arguments
+///
+/// * `sock` - Tcp stream accepted from source VM.
+/// * `creds` - tls-creds object configuration.
+pub fn tls_server_stream(sock: TcpStream, creds: &TlsCredObjConfig) -> Result<TlsServerStream> {
+    let conn = ServerConnection::new(make_server_config(creds)?)
+        .map_err(|e| anyhow!(MigrationError::TlsError(e.to_string())))?;
+
+    Ok(StreamOwned::new(conn, sock))
+}
+
+/// Check the endpoint of tls-creds matches the migration side and the peer is verified.
+fn check_endpoint(creds: &TlsCredObjConfig, expect: &str) -> Result<()> {
+    if creds.cred_type != "x509" {
+        bail!(
+            "Migration only supports x509 tls-creds, not {}",
+            creds.cred_type
+        );
+    }
+    if !creds.verifypeer {
+        bail!(
+            "tls-creds {} must set verify-peer=true for migration",
+            creds.id
+        );
+    }
+    if let Some(endpoint) = &creds.endpoint {
+        if endpoint != expect {
+            bail!(
+                "tls-creds {} has endpoint {}, but {} is required for migration",
+                creds.id,
+                endpoint,
+                expect
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Load `cacert.pem` used to verify the certificate of peer.
+fn load_root_store(dir: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for root in load_certs(&format!("{}/{}", dir, TLS_CREDS_CACERT))? {
+        roots
+            .add(&root)
+            .map_err(|e| anyhow!(MigrationError::TlsError(e.to_string())))?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!(MigrationError::TlsError(format!(
+            "No ca certificate found in {}",
+            dir
+        ))));
+    }
+
+    Ok(roots)
+}
+
+/// Load certificate chain from pem file.
+fn load_certs(filepath: &str) -> Result<Vec<Certificate>> {
+    let certfile = File::open(filepath).with_context(|| format!("Failed to open {}", filepath))?;
+    let mut reader = BufReader::new(certfile);
+    let certs = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    Ok(certs)
+}
+
+/// Load private key from pem file.
+fn load_private_key(filepath: &str) -> Result<PrivateKey> {
+    let keyfile = File::open(filepath).with_context(|| format!("Failed to open {}", filepath))?;
+    let mut reader = BufReader::new(keyfile);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(PrivateKey(key)),
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(PrivateKey(key)),
+            Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            None => break,
+            _ => {}
+        }
+    }
+
+    Err(anyhow!(MigrationError::TlsError(format!(
+        "No private key found in {}",
+        filepath
+    ))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_endpoint() {
+        let mut creds = TlsCredObjConfig {
+            id: "tls0".to_string(),
+            dir: "/tmp".to_string(),
+            cred_type: "x509".to_string(),
+            endpoint: None,
+            verifypeer: true,
+        };
+        assert!(check_endpoint(&creds, TLS_ENDPOINT_SERVER).is_ok());
+        assert!(check_endpoint(&creds, TLS_ENDPOINT_CLIENT).is_ok());
+
+        creds.endpoint = Some("server".to_string());
+        assert!(check_endpoint(&creds, TLS_ENDPOINT_SERVER).is_ok());
+        assert!(check_endpoint(&creds, TLS_ENDPOINT_CLIENT).is_err());
+
+        creds.verifypeer = false;
+        assert!(check_endpoint(&creds, TLS_ENDPOINT_SERVER).is_err());
+
+        creds.verifypeer = true;
+        creds.cred_type = "anon".to_string();
+        assert!(check_endpoint(&creds, TLS_ENDPOINT_SERVER).is_err());
+    }
+
+    #[test]
+    fn test_uri_host() {
+        assert_eq!(uri_host("192.168.1.1:4446"), "192.168.1.1");
+        assert_eq!(uri_host("[::1]:4446"), "::1");
+        assert_eq!(uri_host("[fe80::1:2]:4446"), "fe80::1:2");
+        assert_eq!(uri_host("localhost:4446"), "localhost");
+        assert_eq!(uri_host("localhost"), "localhost");
+    }
+
+    #[test]
+    fn test_missing_creds_files() {
+        let creds = TlsCredObjConfig {
+            id: "tls0".to_string(),
+            dir: "/tmp/stratovirt-no-such-pki".to_string(),
+            cred_type: "x509".to_string(),
+            endpoint: None,
+            verifypeer: true,
+        };
+        assert!(make_server_config(&creds).is_err());
+        assert!(make_client_config(&creds).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{read_to_string, File};
+use std::io::{Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+
+use machine_manager::qmp::QmpChannel;
+
+/// Seccomp mode of process which is in filter mode.
+const SECCOMP_MODE_FILTER: &str = "2";
+/// Time to wait for helper process exiting after migration.
+const HELPER_EXIT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Get the migration stream from file descriptor.
+///
+/// # Arguments
+///
+/// * `name` - The name of file descriptor received by qmp command `getfd`,
+///   or the number of file descriptor inherited from parent process.
+pub fn fd_stream(name: &str) -> Result<File> {
+    let fd: RawFd = match name.parse::<RawFd>() {
+        Ok(fd) => fd,
+        Err(_) => QmpChannel::remove_fd(name)
+            .with_context(|| format!("File descriptor named {} is not found", name))?,
+    };
+
+    // SAFETY: fcntl is only used to check whether fd is valid.
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        bail!("Invalid file descriptor {} for migration", name);
+    }
+
+    // SAFETY: the fd is valid and the ownership is taken by the returned file.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Migration stream through a helper process, data is written to its stdin
+/// and read from its stdout.
+pub struct ExecStream {
+    /// The helper process.
+    child: Child,
+    /// Stdin of helper process.
+    stdin: Option<ChildStdin>,
+    /// Stdout of helper process.
+    stdout: ChildStdout,
+}
+
+impl ExecStream {
+    /// Spawn the helper process with `sh -c`.
+    ///
+    /// # Arguments
+    ///
+    /// * `cmd` - The command line of helper process.
+    pub fn new(cmd: &str) -> Result<Self> {
+        if is_seccomp_enabled() {
+            bail!("Exec migration can't spawn helper process with seccomp, use -disable-seccomp");
+        }
+
+        info!("Spawn migration helper: {}", cmd);
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn migration helper {}", cmd))?;
+        let stdin = child.stdin.take().with_context(|| "Failed to get stdin")?;
+        let stdout = child
+            .stdout
+            .take()
+            .with_context(|| "Failed to get stdout")?;
+
+        Ok(ExecStream {
+            child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+}
+
+impl Read for ExecStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for ExecStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.stdin.as_mut() {
+            Some(stdin) => stdin.write(buf),
+            None => Err(std::io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.stdin.as_mut() {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ExecStream {
+    fn drop(&mut self) {
+        // Close stdin, the helper will get EOF and exit. Kill it if it still
+        // holds on after timeout.
+        self.stdin.take();
+        let start = Instant::now();
+        while start.elapsed() < HELPER_EXIT_TIMEOUT {
+            match self.child.try_wait() {
+                Ok(Some(status)) => {
+                    info!("Migration helper exits with {}", status);
+                    return;
+                }
+                Ok(None) => sleep(Duration::from_millis(10)),
+                Err(_) => break,
+            }
+        }
+
+        warn!("Migration helper is still running, kill it");
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Check whether the seccomp filter is enabled in this process.
+fn is_seccomp_enabled() -> bool {
+    if let Ok(status) = read_to_string("/proc/self/status") {
+        for line in status.lines() {
+            if let Some(mode) = line.strip_prefix("Seccomp:") {
+                return mode.trim() == SECCOMP_MODE_FILTER;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exec_stream() {
+        let mut stream = ExecStream::new("cat").unwrap();
+        let data = b"stratovirt migration";
+        stream.write_all(data).unwrap();
+
+        let mut buf = vec![0_u8; data.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, data);
+    }
+
+    #[test]
+    fn test_fd_stream() {
+        assert!(fd_stream("-1").is_err());
+        assert!(fd_stream("65535").is_err());
+
+        let file = File::open("/dev/null").unwrap();
+        let fd = std::os::unix::io::IntoRawFd::into_raw_fd(file);
+        assert!(fd_stream(&fd.to_string()).is_ok());
+    }
+}
@@ -300,6 +300,11 @@ pub const PCI_EXP_HP_EV_CCI: u16 = PCI_EXP_SLTCTL_CCIE;
 // XHCI device id
 pub const PCI_DEVICE_ID_REDHAT_XHCI: u16 = 0x000d;
 
+// ICH9 USB controllers, which are emulated as EHCI with UHCI companions.
+pub const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
+pub const PCI_DEVICE_ID_INTEL_ICH9_EHCI1: u16 = 0x293a;
+pub const PCI_DEVICE_ID_INTEL_ICH9_UHCI1: u16 = 0x2934;
+
 /* Device classes and subclasses */
 pub const PCI_CLASS_MEMORY_RAM: u16 = 0x0500;
 pub const PCI_CLASS_SERIAL_USB: u16 = 0x0c03;
@@ -11,16 +11,19 @@
 // See the Mulan PSL v2 for more details.
 
 use std::io::Write;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::{Arc, Mutex};
 
 use anyhow::{bail, Context, Result};
+use devices::legacy::mux_attach_monitor;
 use log::{error, info};
 use machine::{LightMachine, MachineOps, StdMachine};
 use machine_manager::{
     cmdline::{check_api_channel, create_args_parser, create_vmconfig},
     config::MachineType,
-    config::VmConfig,
+    config::{ChardevConfig, VmConfig},
     event_loop::EventLoop,
+    machine::MachineExternalInterface,
     qmp::QmpChannel,
     signal_handler::{exit_with_code, register_kill_signal, VM_EXIT_GENE_ERR},
     socket::Socket,
@@ -160,7 +163,7 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
     register_kill_signal();
 
     let listeners = check_api_channel(cmd_args, vm_config)?;
-    let mut sockets = Vec::new();
+    let sockets;
     let vm: Arc<Mutex<dyn MachineOps + Send + Sync>> = match vm_config.machine_config.mach_type {
         MachineType::MicroVm => {
             if is_test_enabled() {
@@ -172,9 +175,7 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
             MachineOps::realize(&vm, vm_config).with_context(|| "Failed to realize micro VM.")?;
             EventLoop::set_manager(vm.clone(), None);
 
-            for listener in listeners {
-                sockets.push(Socket::from_unix_listener(listener, Some(vm.clone())));
-            }
+            sockets = create_api_sockets(listeners, vm_config.monitor_chardev.take(), vm.clone())?;
             vm
         }
         MachineType::StandardVm => {
@@ -197,9 +198,7 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
                 .with_context(|| "Failed to add test socket to MainLoop")?;
             }
 
-            for listener in listeners {
-                sockets.push(Socket::from_unix_listener(listener, Some(vm.clone())));
-            }
+            sockets = create_api_sockets(listeners, vm_config.monitor_chardev.take(), vm.clone())?;
             vm
         }
         MachineType::None => {
@@ -211,9 +210,7 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
             ));
             EventLoop::set_manager(vm.clone(), None);
 
-            for listener in listeners {
-                sockets.push(Socket::from_unix_listener(listener, Some(vm.clone())));
-            }
+            sockets = create_api_sockets(listeners, vm_config.monitor_chardev.take(), vm.clone())?;
             vm
         }
     };
@@ -239,3 +236,23 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
     EventLoop::loop_run().with_context(|| "MainLoop exits unexpectedly: error occurs")?;
     Ok(())
 }
+
+/// Create qmp sockets on the listeners, and the monitor on multiplexed chardev.
+fn create_api_sockets(
+    listeners: Vec<UnixListener>,
+    monitor_chardev: Option<ChardevConfig>,
+    vm: Arc<Mutex<dyn MachineExternalInterface>>,
+) -> Result<Vec<Socket>> {
+    let mut sockets = Vec::new();
+    for listener in listeners {
+        sockets.push(Socket::from_unix_listener(listener, Some(vm.clone())));
+    }
+    if let Some(chardev) = monitor_chardev {
+        let (qmp_stream, mux_stream) =
+            UnixStream::pair().with_context(|| "Failed to create stream for monitor")?;
+        mux_attach_monitor(chardev, mux_stream)
+            .with_context(|| "Failed to attach monitor to chardev")?;
+        sockets.push(Socket::from_unix_stream(qmp_stream, Some(vm)));
+    }
+    Ok(sockets)
+}
@@ -56,6 +56,7 @@ fn get_audio_file_name() -> (String, String) {
 fn set_up(
     size: u32,
     pci_slot: u8,
+    interface: &str,
     playback_path: String,
     record_path: String,
 ) -> (Rc<RefCell<TestIvshmemDev>>, Rc<RefCell<TestState>>) {
@@ -65,8 +66,8 @@ fn set_up(
     extra_args.append(&mut args);
 
     let scream_device = format!(
-        "-device ivshmem-scream,memdev=scream,id=scream,interface=Demo,playback={},record={},bus=pcie.0,addr={}",
-        playback_path, record_path, pci_slot,
+        "-device ivshmem-scream,memdev=scream,id=scream,interface={},playback={},record={},bus=pcie.0,addr={}",
+        interface, playback_path, record_path, pci_slot,
     );
     args = scream_device.split(' ').collect();
     extra_args.append(&mut args);
@@ -86,7 +87,7 @@ fn set_up(
     (ivshmem, test_state)
 }
 
-fn stream_header_init(ivshmem: &mut TestIvshmemDev, base: u64, offset: u64) {
+fn stream_header_init(ivshmem: &mut TestIvshmemDev, base: u64, offset: u64, rate: u8) {
     // set chunk_idx
     ivshmem.writew(base + offset_of!(ShmemStreamHeader, chunk_idx) as u64, 0);
     // set max_chunks
@@ -112,7 +113,7 @@ fn stream_header_init(ivshmem: &mut TestIvshmemDev, base: u64, offset: u64) {
         1,
     );
     // set rate
-    ivshmem.writeb(fmt_base + offset_of!(ShmemStreamFmt, rate) as u64, 128);
+    ivshmem.writeb(fmt_base + offset_of!(ShmemStreamFmt, rate) as u64, rate);
     // set size
     ivshmem.writeb(fmt_base + offset_of!(ShmemStreamFmt, size) as u64, 16);
     // set channel
@@ -128,7 +129,7 @@ fn play_header_init(ivshmem: &mut TestIvshmemDev) {
     // set magic
     ivshmem.writeq(0, SCREAM_MAGIC);
     let base = PLAY_BASE;
-    stream_header_init(ivshmem, base, PLAY_DADA_OFFSET);
+    stream_header_init(ivshmem, base, PLAY_DADA_OFFSET, 128);
 }
 
 fn play_audio_data_init(playback: String) {
@@ -147,7 +148,7 @@ fn play_audio_data_init(playback: String) {
 fn record_header_init(ivshmem: &mut TestIvshmemDev) {
     play_header_init(ivshmem);
     let base = RECORD_BASE;
-    stream_header_init(ivshmem, base, RECORD_DATA_OFFSET);
+    stream_header_init(ivshmem, base, RECORD_DATA_OFFSET, 128);
 }
 
 fn record_audio_data_init(record: String) {
@@ -224,6 +225,7 @@ fn scream_playback_basic_test() {
     let (ivshmem, test_state) = set_up(
         IVSHMEM_DEFAULT_SIZE,
         pci_slot,
+        "Demo",
         playback_path.clone(),
         record_path.clone(),
     );
@@ -333,6 +335,7 @@ fn scream_record_basic_test() {
     let (ivshmem, test_state) = set_up(
         IVSHMEM_DEFAULT_SIZE,
         pci_slot,
+        "Demo",
         playback_path.clone(),
         record_path.clone(),
     );
@@ -402,6 +405,7 @@ fn scream_exception_001() {
     let (ivshmem, test_state) = set_up(
         IVSHMEM_DEFAULT_SIZE,
         pci_slot,
+        "Demo",
         playback_path.clone(),
         record_path.clone(),
     );
@@ -471,6 +475,7 @@ fn scream_exception_002() {
     let (ivshmem, test_state) = set_up(
         IVSHMEM_DEFAULT_SIZE,
         pci_slot,
+        "Demo",
         playback_path.clone(),
         record_path.clone(),
     );
@@ -525,3 +530,69 @@ fn scream_exception_002() {
 
     scream_tmp_clear(playback_path, record_path);
 }
+
+/// scream device playback audio to WAV file.
+/// TestStep:
+///   1. Init scream device with Wav interface.
+///   2. Send one audio frame.
+///   3. Stop VM.
+///   4. Check WAV header and audio frame from WAV file.
+/// Expect:
+///   1/2/3/4: success.
+#[test]
+fn scream_playback_wav_test() {
+    let pci_slot = 0x1;
+    let (playback_path, record_path) = get_audio_file_name();
+    let (ivshmem, test_state) = set_up(
+        IVSHMEM_DEFAULT_SIZE,
+        pci_slot,
+        "Wav",
+        playback_path.clone(),
+        record_path.clone(),
+    );
+    ivshmem.borrow_mut().init(pci_slot);
+
+    // Wait for 1s until the scream device is initialized and enters the polling state to
+    // prevent subsequent audio frame data loss.
+    thread::sleep(time::Duration::from_millis(1000));
+
+    // set magic and play header with rate 44.1kHz
+    ivshmem.borrow_mut().writeq(0, SCREAM_MAGIC);
+    stream_header_init(&mut ivshmem.borrow_mut(), PLAY_BASE, PLAY_DADA_OFFSET, 129);
+
+    thread::sleep(time::Duration::from_millis(POLL_DELAY_MS));
+
+    // write one audio chunk
+    for i in 0..AUDIO_CHUNK_SIZE {
+        ivshmem.borrow_mut().writeb(
+            PLAY_DADA_OFFSET + (AUDIO_CHUNK_SIZE + i) as u64,
+            AUDIO_DEFAULT_DATA[i as usize],
+        );
+    }
+
+    // update play header chunk_idx
+    ivshmem.borrow_mut().writew(
+        PLAY_BASE + offset_of!(ShmemStreamHeader, chunk_idx) as u64,
+        1,
+    );
+
+    thread::sleep(time::Duration::from_millis(1000));
+
+    test_state.borrow_mut().stop();
+
+    let data = fs::read(playback_path.clone()).unwrap();
+    assert_eq!(data.len(), 44 + AUDIO_CHUNK_SIZE as usize);
+    assert_eq!(&data[0..16], b"RIFF\x28\0\0\0WAVEfmt ");
+    // 2 channels, 44.1kHz and 16 bits.
+    let mut value = 0;
+    assert!(read_data_u32(&data[22..24], &mut value));
+    assert_eq!(value, 2);
+    assert!(read_data_u32(&data[24..28], &mut value));
+    assert_eq!(value, 44100);
+    assert!(read_data_u32(&data[34..36], &mut value));
+    assert_eq!(value, 16);
+    assert_eq!(&data[36..44], b"data\x04\0\0\0");
+    assert_eq!(data[44..], AUDIO_DEFAULT_DATA[0..AUDIO_CHUNK_SIZE as usize]);
+
+    scream_tmp_clear(playback_path, record_path);
+}
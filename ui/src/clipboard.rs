@@ -0,0 +1,180 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+use log::error;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Max size of clipboard text shared with guest.
+pub const CLIPBOARD_MAX_SIZE: usize = 1024 * 1024;
+
+static CLIPBOARD: Lazy<Arc<Mutex<Clipboard>>> =
+    Lazy::new(|| Arc::new(Mutex::new(Clipboard::default())));
+
+/// Guest side of the clipboard, such as the agent channel in guest.
+pub trait ClipboardAgentOpts: Send {
+    /// Send the clipboard text of host to guest.
+    fn set_guest_clipboard(&mut self, text: &str) -> Result<()>;
+}
+
+/// Host side of the clipboard, such as vnc clients and gtk.
+pub trait ClipboardFrontendOpts: Send {
+    /// Update the clipboard of frontend with the text from guest.
+    fn set_host_clipboard(&mut self, text: &str) -> Result<()>;
+}
+
+#[derive(Default)]
+struct Clipboard {
+    /// Name and operations of the guest agent.
+    agent: Option<(String, Arc<Mutex<dyn ClipboardAgentOpts>>)>,
+    /// Display frontends sharing the clipboard.
+    frontends: HashMap<String, Arc<Mutex<dyn ClipboardFrontendOpts>>>,
+    /// The latest clipboard text, from either host or guest.
+    text: String,
+}
+
+pub fn register_clipboard_agent(device: &str, agent: Arc<Mutex<dyn ClipboardAgentOpts>>) {
+    CLIPBOARD.lock().unwrap().agent = Some((device.to_string(), agent));
+}
+
+pub fn unregister_clipboard_agent(device: &str) {
+    let mut locked_clipboard = CLIPBOARD.lock().unwrap();
+    if matches!(&locked_clipboard.agent, Some((name, _)) if name == device) {
+        locked_clipboard.agent = None;
+    }
+}
+
+pub fn register_clipboard_frontend(name: &str, frontend: Arc<Mutex<dyn ClipboardFrontendOpts>>) {
+    CLIPBOARD
+        .lock()
+        .unwrap()
+        .frontends
+        .insert(name.to_string(), frontend);
+}
+
+pub fn unregister_clipboard_frontend(name: &str) {
+    CLIPBOARD.lock().unwrap().frontends.remove(name);
+}
+
+/// Get the latest clipboard text.
+pub fn get_clipboard_text() -> String {
+    CLIPBOARD.lock().unwrap().text.clone()
+}
+
+/// The clipboard of host is changed by the user, send it to guest.
+pub fn clipboard_update_from_host(text: &str) -> Result<()> {
+    if text.len() > CLIPBOARD_MAX_SIZE {
+        bail!("Clipboard text is too large: {}", text.len());
+    }
+    let mut locked_clipboard = CLIPBOARD.lock().unwrap();
+    // Frontends may report the text set by guest back.
+    if locked_clipboard.text == text {
+        return Ok(());
+    }
+    locked_clipboard.text = text.to_string();
+    let agent = match &locked_clipboard.agent {
+        Some((_, agent)) => agent.clone(),
+        None => return Ok(()),
+    };
+    drop(locked_clipboard);
+
+    let mut locked_agent = agent.lock().unwrap();
+    locked_agent.set_guest_clipboard(text)
+}
+
+/// The clipboard of guest is changed, send it to all the frontends.
+pub fn clipboard_update_from_guest(text: &str) -> Result<()> {
+    if text.len() > CLIPBOARD_MAX_SIZE {
+        bail!("Clipboard text is too large: {}", text.len());
+    }
+    let mut locked_clipboard = CLIPBOARD.lock().unwrap();
+    if locked_clipboard.text == text {
+        return Ok(());
+    }
+    locked_clipboard.text = text.to_string();
+    let frontends: Vec<Arc<Mutex<dyn ClipboardFrontendOpts>>> =
+        locked_clipboard.frontends.values().cloned().collect();
+    drop(locked_clipboard);
+
+    for frontend in frontends {
+        frontend
+            .lock()
+            .unwrap()
+            .set_host_clipboard(text)
+            .unwrap_or_else(|e| error!("Failed to update clipboard of frontend: {:?}", e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct TestClipboard {
+        text: String,
+        count: usize,
+    }
+
+    impl ClipboardAgentOpts for TestClipboard {
+        fn set_guest_clipboard(&mut self, text: &str) -> Result<()> {
+            self.text = text.to_string();
+            self.count += 1;
+            Ok(())
+        }
+    }
+
+    impl ClipboardFrontendOpts for TestClipboard {
+        fn set_host_clipboard(&mut self, text: &str) -> Result<()> {
+            self.text = text.to_string();
+            self.count += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_clipboard_basic() {
+        let agent = Arc::new(Mutex::new(TestClipboard::default()));
+        let frontend = Arc::new(Mutex::new(TestClipboard::default()));
+        register_clipboard_agent("TestAgent", agent.clone());
+        register_clipboard_frontend("TestFrontend", frontend.clone());
+
+        // Host to guest.
+        assert!(clipboard_update_from_host("host text").is_ok());
+        assert_eq!(agent.lock().unwrap().text, "host text");
+        assert_eq!(get_clipboard_text(), "host text");
+
+        // Guest to host.
+        assert!(clipboard_update_from_guest("guest text").is_ok());
+        assert_eq!(frontend.lock().unwrap().text, "guest text");
+        assert_eq!(get_clipboard_text(), "guest text");
+
+        // The text from guest is reported back by frontend.
+        assert!(clipboard_update_from_host("guest text").is_ok());
+        assert_eq!(agent.lock().unwrap().count, 1);
+
+        let large_text = "a".repeat(CLIPBOARD_MAX_SIZE + 1);
+        assert!(clipboard_update_from_host(&large_text).is_err());
+        assert!(clipboard_update_from_guest(&large_text).is_err());
+
+        unregister_clipboard_agent("TestAgent");
+        unregister_clipboard_frontend("TestFrontend");
+        assert!(clipboard_update_from_host("new text").is_ok());
+        assert_eq!(agent.lock().unwrap().count, 1);
+        assert!(clipboard_update_from_guest("other text").is_ok());
+        assert_eq!(frontend.lock().unwrap().count, 1);
+    }
+}
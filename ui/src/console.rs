@@ -163,6 +163,10 @@ pub trait HardWareOperations {
     fn hw_update(&self, _con: Arc<Mutex<DisplayConsole>>) {}
     /// Ui configuration changed.
     fn hw_ui_info(&self, _con: Arc<Mutex<DisplayConsole>>, _width: u32, _height: u32) {}
+    /// Whether the device follows the ui configuration change.
+    fn hw_ui_info_supported(&self) -> bool {
+        false
+    }
 }
 
 /// Listen to the change of image and call the related
@@ -369,6 +373,11 @@ pub fn display_refresh() {
     }
 
     let mut locked_state = DISPLAY_STATE.lock().unwrap();
+    // Stop refreshing if all the listeners have been unregistered.
+    if locked_state.refresh_num <= 0 {
+        locked_state.is_refresh = false;
+        return;
+    }
     locked_state.interval = interval;
     if locked_state.interval != 0 {
         locked_state.is_refresh = true;
@@ -550,6 +559,22 @@ pub fn graphic_hardware_ui_info(
     Ok(())
 }
 
+/// Get the console by device name.
+/// If no device name is specified, the activate console will be used.
+pub fn get_console_by_name(dev_name: Option<&str>) -> Option<Arc<Mutex<DisplayConsole>>> {
+    let mut locked_consoles = CONSOLES.lock().unwrap();
+    match dev_name {
+        Some(name) => locked_consoles.get_console_by_dev_name(name.to_string()),
+        None => locked_consoles.get_console_by_id(None),
+    }
+}
+
+/// Get the console by console id.
+/// If no console id is specified, the activate console will be used.
+pub fn get_console_by_id(con_id: Option<usize>) -> Option<Arc<Mutex<DisplayConsole>>> {
+    CONSOLES.lock().unwrap().get_console_by_id(con_id)
+}
+
 /// Get the weak reference of all active consoles from the console lists.
 pub fn get_active_console() -> Vec<Weak<Mutex<DisplayConsole>>> {
     let mut res: Vec<Weak<Mutex<DisplayConsole>>> = vec![];
@@ -30,16 +30,17 @@ use gtk::{
     gdk::{self, Geometry, Gravity, WindowHints},
     gdk_pixbuf::Colorspace,
     glib::{self, Priority, SyncSender},
-    prelude::{ApplicationExt, ApplicationExtManual, Continue, NotebookExtManual},
+    prelude::{ApplicationExt, ApplicationExtManual, Continue, NotebookExtManual, ObjectExt},
     traits::{
         CheckMenuItemExt, GtkMenuItemExt, GtkWindowExt, HeaderBarExt, MenuShellExt,
         RadioMenuItemExt, WidgetExt,
     },
-    Application, ApplicationWindow, DrawingArea, HeaderBar, RadioMenuItem,
+    Application, ApplicationWindow, Clipboard, DrawingArea, HeaderBar, RadioMenuItem,
 };
 use log::{debug, error};
 
 use crate::{
+    clipboard::{clipboard_update_from_host, register_clipboard_frontend, ClipboardFrontendOpts},
     console::{
         create_msg_surface, get_active_console, graphic_hardware_update, register_display,
         DisplayChangeListener, DisplayChangeListenerOperations, DisplayConsole, DisplayMouse,
@@ -201,6 +202,18 @@ impl DisplayChangeListenerOperations for GtkInterface {
     }
 }
 
+/// Share the clipboard of guest with gtk.
+struct GtkClipboard {
+    text_sender: SyncSender<String>,
+}
+
+impl ClipboardFrontendOpts for GtkClipboard {
+    fn set_host_clipboard(&mut self, text: &str) -> Result<()> {
+        self.text_sender.send(text.to_string())?;
+        Ok(())
+    }
+}
+
 pub(crate) struct GtkDisplay {
     gtk_menu: GtkMenu,
     scale_mode: Rc<RefCell<ScaleMode>>,
@@ -615,9 +628,39 @@ fn graphic_display_init(gd: Rc<RefCell<GtkDisplay>>) -> Result<()> {
         }),
     );
 
+    gtk_clipboard_init();
     Ok(())
 }
 
+/// Share the clipboard between gtk and guest.
+fn gtk_clipboard_init() {
+    let clipboard = Clipboard::get(&gdk::SELECTION_CLIPBOARD);
+    let (text_sender, text_receiver) =
+        glib::MainContext::sync_channel::<String>(Priority::default(), CHANNEL_BOUND);
+    register_clipboard_frontend("gtk", Arc::new(Mutex::new(GtkClipboard { text_sender })));
+
+    // Text of guest clipboard.
+    text_receiver.attach(
+        None,
+        glib::clone!(@strong clipboard => @default-return Continue(true), move |text| {
+            clipboard.set_text(&text);
+            Continue(true)
+        }),
+    );
+
+    // Text of host clipboard is changed by user.
+    clipboard.connect_local("owner-change", false, |values| {
+        let clipboard = values[0].get::<Clipboard>().ok()?;
+        clipboard.request_text(|_, text| {
+            if let Some(text) = text {
+                clipboard_update_from_host(text)
+                    .unwrap_or_else(|e| error!("Failed to update clipboard: {:?}", e));
+            }
+        });
+        None
+    });
+}
+
 /// Receive display update events from the mainloop of Stratovirt ,
 /// assigns the event to the corresponding draw display by the field
 /// of device name. And then update the specific gtk display.
@@ -10,11 +10,14 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+pub mod clipboard;
 pub mod console;
 mod data;
 pub mod error;
 pub mod gtk;
 pub mod input;
 pub mod pixman;
+pub mod recorder;
+pub mod screendump;
 pub mod utils;
 pub mod vnc;
@@ -236,6 +236,43 @@ pub fn pixman_image_linebuf_fill(
     };
 }
 
+/// Read the pixels in the area of image, and convert them to x8r8g8b8.
+/// Return None if the area is out of the image.
+pub fn get_image_region_x8r8g8b8(
+    image: *mut pixman_image_t,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+) -> Option<Vec<u32>> {
+    if image.is_null()
+        || x < 0
+        || y < 0
+        || w <= 0
+        || h <= 0
+        || x + w > get_image_width(image)
+        || y + h > get_image_height(image)
+    {
+        return None;
+    }
+
+    let line_buf = pixman_image_linebuf_create(pixman_format_code_t::PIXMAN_x8r8g8b8, w);
+    if line_buf.is_null() {
+        return None;
+    }
+    let mut data = Vec::with_capacity(w as usize * h as usize);
+    for i in 0..h {
+        pixman_image_linebuf_fill(line_buf, image, w, x, y + i);
+        // SAFETY: the line buffer is created with w pixels of u32.
+        let line = unsafe {
+            std::slice::from_raw_parts(get_image_data(line_buf) as *const u32, w as usize)
+        };
+        data.extend_from_slice(line);
+    }
+    unref_pixman_image(line_buf);
+    Some(data)
+}
+
 pub enum ColorNames {
     ColorBlack = 0,
     ColorBlue = 1,
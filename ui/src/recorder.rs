@@ -0,0 +1,484 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Record the image of console to file.
+//!
+//! The record file is a lossless frame-diff stream. It starts with the magic
+//! `SVRECORD` and a u32 version, followed by frames. All the integers are in
+//! little endian. Each frame is made of:
+//!
+//! | timestamp(u64, us) | type(u32) | x(u32) | y(u32) | w(u32) | h(u32) | len(u32) | data |
+//!
+//! Data is the zlib compressed x8r8g8b8 pixels of the area. A keyframe contains
+//! the whole image and defines the resolution of the following update frames.
+
+use std::{
+    cmp,
+    fs::{rename, File},
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::{write::ZlibEncoder, Compression};
+use log::error;
+use once_cell::sync::Lazy;
+
+use crate::{
+    console::{
+        get_console_by_name, graphic_hardware_update, register_display, unregister_display,
+        DisplayChangeListener, DisplayChangeListenerOperations, DisplayMouse, DisplaySurface,
+    },
+    pixman::{get_image_region_x8r8g8b8, ref_pixman_image, unref_pixman_image},
+};
+
+const RECORD_MAGIC: &[u8; 8] = b"SVRECORD";
+const RECORD_VERSION: u32 = 1;
+/// Frame contains the whole image.
+const RECORD_FRAME_KEY: u32 = 1;
+/// Frame contains an updated area of image.
+const RECORD_FRAME_UPDATE: u32 = 2;
+/// Default max size of the record files.
+pub const RECORD_DEFAULT_MAX_SIZE: u64 = 256 * 1024 * 1024;
+/// Default interval of frames in millisecond.
+pub const RECORD_DEFAULT_INTERVAL: u64 = 100;
+/// Min size of the record files.
+const RECORD_MIN_SIZE: u64 = 1024 * 1024;
+/// Dirty areas will be merged if there are too many of them.
+const RECORD_MAX_DIRTY_RECTS: usize = 32;
+
+static RECORDER: Lazy<Mutex<RecorderHandle>> =
+    Lazy::new(|| Mutex::new(RecorderHandle { dcl: None }));
+
+struct RecorderHandle {
+    dcl: Option<Arc<Mutex<DisplayChangeListener>>>,
+}
+
+// SAFETY: The Arc<dyn ...> in rust doesn't impl Send, the recorder is only started
+// and stopped in the main loop thread, and the display listener is called with its
+// inner state protected by lock. So implement Send is safe.
+unsafe impl Send for RecorderHandle {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rect {
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+}
+
+impl Rect {
+    fn union(&self, other: &Rect) -> Rect {
+        let x = cmp::min(self.x, other.x);
+        let y = cmp::min(self.y, other.y);
+        let right = cmp::max(self.x + self.w, other.x + other.w);
+        let bottom = cmp::max(self.y + self.h, other.y + other.h);
+        Rect {
+            x,
+            y,
+            w: right - x,
+            h: bottom - y,
+        }
+    }
+
+    /// Clip the area with the image of `width` and `height`.
+    fn clip(&self, width: i32, height: i32) -> Option<Rect> {
+        let x = cmp::max(self.x, 0);
+        let y = cmp::max(self.y, 0);
+        let right = cmp::min(self.x + self.w, width);
+        let bottom = cmp::min(self.y + self.h, height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Rect {
+            x,
+            y,
+            w: right - x,
+            h: bottom - y,
+        })
+    }
+}
+
+/// The record file in rotation. When the current file is up to half of the max size,
+/// it is renamed with suffix `.1`, and a new file is created with a keyframe. So that
+/// the latest part of recording is kept and disk usage is bounded by the max size.
+struct RecordFile {
+    filename: String,
+    max_size: u64,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl RecordFile {
+    fn new(filename: &str, max_size: u64) -> Result<Self> {
+        let file = File::create(filename)
+            .with_context(|| format!("Failed to create record file {}", filename))?;
+        let mut record_file = RecordFile {
+            filename: filename.to_string(),
+            max_size,
+            writer: BufWriter::new(file),
+            written: 0,
+        };
+        record_file.write(RECORD_MAGIC)?;
+        record_file.write(&RECORD_VERSION.to_le_bytes())?;
+        Ok(record_file)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn need_rotate(&self) -> bool {
+        self.written >= self.max_size / 2
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        rename(&self.filename, format!("{}.1", self.filename))
+            .with_context(|| format!("Failed to rotate record file {}", self.filename))?;
+        *self = RecordFile::new(&self.filename, self.max_size)?;
+        Ok(())
+    }
+}
+
+struct RecorderState {
+    file: Option<RecordFile>,
+    /// The surface being recorded, holding a reference of the image.
+    surface: Option<DisplaySurface>,
+    /// Areas updated since the last frame.
+    dirty: Vec<Rect>,
+    /// The whole image should be written in the next frame.
+    keyframe: bool,
+    start: Instant,
+    last_frame: Option<Instant>,
+    interval: Duration,
+}
+
+impl RecorderState {
+    fn add_dirty(&mut self, rect: Rect) {
+        if self.keyframe {
+            return;
+        }
+        if self.dirty.len() >= RECORD_MAX_DIRTY_RECTS {
+            let merged = self.dirty.iter().fold(rect, |acc, r| acc.union(r));
+            self.dirty = vec![merged];
+            return;
+        }
+        self.dirty.push(rect);
+    }
+
+    fn write_frame(&mut self, frame_type: u32, rect: &Rect) -> Result<()> {
+        let surface = match self.surface {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let pixels = match get_image_region_x8r8g8b8(surface.image, rect.x, rect.y, rect.w, rect.h)
+        {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        for pixel in pixels {
+            encoder.write_all(&pixel.to_le_bytes())?;
+        }
+        let data = encoder.finish()?;
+
+        let timestamp = self.start.elapsed().as_micros() as u64;
+        let mut header = Vec::with_capacity(32);
+        header.extend_from_slice(&timestamp.to_le_bytes());
+        for val in [
+            frame_type,
+            rect.x as u32,
+            rect.y as u32,
+            rect.w as u32,
+            rect.h as u32,
+            data.len() as u32,
+        ] {
+            header.extend_from_slice(&val.to_le_bytes());
+        }
+
+        let file = match self.file.as_mut() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        file.write(&header)?;
+        file.write(&data)
+    }
+
+    /// Write the updated areas since the last frame.
+    fn flush_frames(&mut self) -> Result<()> {
+        let surface = match self.surface {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        if let Some(file) = self.file.as_mut() {
+            if file.need_rotate() {
+                file.rotate()?;
+                self.keyframe = true;
+            }
+        }
+
+        let (width, height) = (surface.width(), surface.height());
+        if self.keyframe {
+            self.keyframe = false;
+            self.dirty.clear();
+            let rect = Rect {
+                x: 0,
+                y: 0,
+                w: width,
+                h: height,
+            };
+            self.write_frame(RECORD_FRAME_KEY, &rect)?;
+        } else {
+            let dirty: Vec<Rect> = self.dirty.drain(..).collect();
+            for rect in dirty.iter().filter_map(|r| r.clip(width, height)) {
+                self.write_frame(RECORD_FRAME_UPDATE, &rect)?;
+            }
+        }
+        match self.file.as_mut() {
+            Some(f) => f
+                .writer
+                .flush()
+                .with_context(|| "Failed to flush record file"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RecorderState {
+    fn drop(&mut self) {
+        if let Some(s) = self.surface.take() {
+            unref_pixman_image(s.image);
+        }
+    }
+}
+
+struct Recorder {
+    state: Mutex<RecorderState>,
+}
+
+impl DisplayChangeListenerOperations for Recorder {
+    fn dpy_switch(&self, surface: &DisplaySurface) -> Result<()> {
+        let mut locked_state = self.state.lock().unwrap();
+        let new_surface = DisplaySurface {
+            format: surface.format,
+            image: ref_pixman_image(surface.image),
+        };
+        if let Some(old) = locked_state.surface.replace(new_surface) {
+            unref_pixman_image(old.image);
+        }
+        locked_state.keyframe = true;
+        Ok(())
+    }
+
+    fn dpy_refresh(&self, dcl: &Arc<Mutex<DisplayChangeListener>>) -> Result<()> {
+        let mut locked_state = self.state.lock().unwrap();
+        if locked_state.file.is_none() {
+            return Ok(());
+        }
+        if let Some(last) = locked_state.last_frame {
+            if last.elapsed() < locked_state.interval {
+                return Ok(());
+            }
+        }
+        locked_state.last_frame = Some(Instant::now());
+        drop(locked_state);
+
+        let con_id = dcl.lock().unwrap().con_id;
+        graphic_hardware_update(con_id);
+
+        let mut locked_state = self.state.lock().unwrap();
+        if let Err(e) = locked_state.flush_frames() {
+            // Stop writing, but keep the display refreshing for others.
+            error!("Failed to record display, recording stopped: {:?}", e);
+            locked_state.file = None;
+        }
+        Ok(())
+    }
+
+    fn dpy_image_update(&self, x: i32, y: i32, w: i32, h: i32) -> Result<()> {
+        self.state.lock().unwrap().add_dirty(Rect { x, y, w, h });
+        Ok(())
+    }
+
+    fn dpy_cursor_update(&self, _cursor: &DisplayMouse) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Start recording the image of console.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the record file.
+/// * `device` - Device name of the console, the activate console is used if not specified.
+/// * `max_size` - Max size in bytes of the record files, including the rotated one.
+/// * `interval` - Min interval in millisecond between frames.
+pub fn record_start(
+    filename: &str,
+    device: Option<&str>,
+    max_size: Option<u64>,
+    interval: Option<u64>,
+) -> Result<()> {
+    let mut locked_recorder = RECORDER.lock().unwrap();
+    if locked_recorder.dcl.is_some() {
+        bail!("Display recording is already started");
+    }
+    let con = match get_console_by_name(device) {
+        Some(c) => c,
+        None => bail!("No console found for {}", device.unwrap_or("recording")),
+    };
+    let max_size = max_size.unwrap_or(RECORD_DEFAULT_MAX_SIZE);
+    if max_size < RECORD_MIN_SIZE {
+        bail!(
+            "Max size of record file {} is less than {}",
+            max_size,
+            RECORD_MIN_SIZE
+        );
+    }
+    let interval = interval.unwrap_or(RECORD_DEFAULT_INTERVAL);
+    if interval == 0 {
+        bail!("Interval of recording should not be 0");
+    }
+
+    let recorder = Recorder {
+        state: Mutex::new(RecorderState {
+            file: Some(RecordFile::new(filename, max_size)?),
+            surface: None,
+            dirty: Vec::new(),
+            keyframe: true,
+            start: Instant::now(),
+            last_frame: None,
+            interval: Duration::from_millis(interval),
+        }),
+    };
+    let con_id = con.lock().unwrap().con_id;
+    let dcl = Arc::new(Mutex::new(DisplayChangeListener::new(
+        Some(con_id),
+        Arc::new(recorder),
+    )));
+    dcl.lock().unwrap().update_interval = interval;
+    register_display(&dcl)?;
+    locked_recorder.dcl = Some(dcl);
+    Ok(())
+}
+
+/// Stop recording and close the record file.
+pub fn record_stop() -> Result<()> {
+    let dcl = match RECORDER.lock().unwrap().dcl.take() {
+        Some(d) => d,
+        None => bail!("Display recording is not started"),
+    };
+    // The record file is closed when the listener is dropped.
+    unregister_display(&Some(Arc::downgrade(&dcl)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_dirty_rect() {
+        let rect = Rect {
+            x: 10,
+            y: 10,
+            w: 20,
+            h: 20,
+        };
+        assert_eq!(
+            rect.union(&Rect {
+                x: 0,
+                y: 20,
+                w: 5,
+                h: 30
+            }),
+            Rect {
+                x: 0,
+                y: 10,
+                w: 30,
+                h: 40
+            }
+        );
+        assert_eq!(
+            rect.clip(25, 100),
+            Some(Rect {
+                x: 10,
+                y: 10,
+                w: 15,
+                h: 20
+            })
+        );
+        assert_eq!(rect.clip(10, 10), None);
+
+        let mut state = RecorderState {
+            file: None,
+            surface: None,
+            dirty: Vec::new(),
+            keyframe: false,
+            start: Instant::now(),
+            last_frame: None,
+            interval: Duration::from_millis(RECORD_DEFAULT_INTERVAL),
+        };
+        for i in 0..RECORD_MAX_DIRTY_RECTS as i32 {
+            state.add_dirty(Rect {
+                x: i,
+                y: i,
+                w: 1,
+                h: 1,
+            });
+        }
+        assert_eq!(state.dirty.len(), RECORD_MAX_DIRTY_RECTS);
+        state.add_dirty(Rect {
+            x: 100,
+            y: 100,
+            w: 1,
+            h: 1,
+        });
+        assert_eq!(
+            state.dirty,
+            vec![Rect {
+                x: 0,
+                y: 0,
+                w: 101,
+                h: 101
+            }]
+        );
+    }
+
+    #[test]
+    fn test_record_file_rotate() {
+        let filename = "/tmp/test_record_file_rotate.rec";
+        let mut file = RecordFile::new(filename, RECORD_MIN_SIZE).unwrap();
+        assert_eq!(file.written, 12);
+        assert!(!file.need_rotate());
+        file.write(&vec![0_u8; RECORD_MIN_SIZE as usize / 2])
+            .unwrap();
+        assert!(file.need_rotate());
+        file.rotate().unwrap();
+        assert_eq!(file.written, 12);
+        drop(file);
+
+        let rotated = format!("{}.1", filename);
+        assert_eq!(
+            std::fs::metadata(&rotated).unwrap().len(),
+            RECORD_MIN_SIZE / 2 + 12
+        );
+        let data = std::fs::read(filename).unwrap();
+        assert_eq!(&data[..8], RECORD_MAGIC);
+        assert_eq!(data[8..12], RECORD_VERSION.to_le_bytes());
+        std::fs::remove_file(filename).unwrap();
+        std::fs::remove_file(rotated).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    console::{get_console_by_name, graphic_hardware_update, DisplaySurface},
+    pixman::get_image_region_x8r8g8b8,
+};
+
+/// Image format of the screen dump file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScreendumpFormat {
+    Png,
+    Ppm,
+}
+
+impl std::str::FromStr for ScreendumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "png" => Ok(ScreendumpFormat::Png),
+            "ppm" => Ok(ScreendumpFormat::Ppm),
+            _ => Err(anyhow!("Unsupported screendump format: {}", s)),
+        }
+    }
+}
+
+/// Save the current image of console to file.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the output file.
+/// * `device` - Device name of the console, the activate console is used if not specified.
+/// * `format` - Image format of the output file.
+pub fn screendump(filename: &str, device: Option<&str>, format: ScreendumpFormat) -> Result<()> {
+    let con = match get_console_by_name(device) {
+        Some(c) => c,
+        None => bail!("No console found for {}", device.unwrap_or("screendump")),
+    };
+    // Let the graphic device flush its latest frame into the surface.
+    let con_id = con.lock().unwrap().con_id;
+    graphic_hardware_update(Some(con_id));
+
+    // Hold the lock of console, so that the surface will not be released while reading.
+    let locked_con = con.lock().unwrap();
+    let surface = match locked_con.surface {
+        Some(s) => s,
+        None => bail!("Console {} has no surface", locked_con.dev_name),
+    };
+    let (width, height, rgb) = surface_to_rgb(&surface)?;
+    drop(locked_con);
+
+    let file =
+        File::create(filename).with_context(|| format!("Failed to create file {}", filename))?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        ScreendumpFormat::Png => write_png(&mut writer, width, height, &rgb)?,
+        ScreendumpFormat::Ppm => write_ppm(&mut writer, width, height, &rgb)?,
+    }
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write file {}", filename))
+}
+
+/// Convert the image of surface to packed RGB888 data.
+fn surface_to_rgb(surface: &DisplaySurface) -> Result<(u32, u32, Vec<u8>)> {
+    let width = surface.width();
+    let height = surface.height();
+    let data = match get_image_region_x8r8g8b8(surface.image, 0, 0, width, height) {
+        Some(d) => d,
+        None => bail!("Invalid surface {}x{}", width, height),
+    };
+    let mut rgb = Vec::with_capacity(data.len() * 3);
+    for pixel in data {
+        rgb.push((pixel >> 16) as u8);
+        rgb.push((pixel >> 8) as u8);
+        rgb.push(pixel as u8);
+    }
+    Ok((width as u32, height as u32, rgb))
+}
+
+fn write_png<W: Write>(writer: &mut W, width: u32, height: u32, rgb: &[u8]) -> Result<()> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut png_writer = encoder
+        .write_header()
+        .with_context(|| "Failed to write png header")?;
+    png_writer
+        .write_image_data(rgb)
+        .with_context(|| "Failed to write png data")?;
+    Ok(())
+}
+
+fn write_ppm<W: Write>(writer: &mut W, width: u32, height: u32, rgb: &[u8]) -> Result<()> {
+    writer.write_all(format!("P6\n{} {}\n255\n", width, height).as_bytes())?;
+    writer.write_all(rgb)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_screendump_format() {
+        assert_eq!(
+            "png".parse::<ScreendumpFormat>().unwrap(),
+            ScreendumpFormat::Png
+        );
+        assert_eq!(
+            "ppm".parse::<ScreendumpFormat>().unwrap(),
+            ScreendumpFormat::Ppm
+        );
+        assert!("bmp".parse::<ScreendumpFormat>().is_err());
+
+        let rgb = vec![0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        let mut ppm = Vec::new();
+        write_ppm(&mut ppm, 2, 2, &rgb).unwrap();
+        assert!(ppm.starts_with(b"P6\n2 2\n255\n"));
+        assert!(ppm.ends_with(&rgb));
+
+        let mut png = Vec::new();
+        write_png(&mut png, 2, 2, &rgb).unwrap();
+        assert!(png.starts_with(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']));
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!((info.width, info.height), (2, 2));
+        assert_eq!(&buf[..info.buffer_size()], rgb.as_slice());
+        // Invalid length of image data.
+        assert!(write_png(&mut Vec::new(), 2, 2, &rgb[..6]).is_err());
+    }
+}
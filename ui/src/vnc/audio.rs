@@ -0,0 +1,235 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+
+/// QEMU message type in both directions, audio is one of its sub-messages.
+pub const QEMU_MSG: u8 = 255;
+pub const QEMU_MSG_AUDIO: u8 = 1;
+
+// Operations of audio message from client.
+pub const AUDIO_CLIENT_ENABLE: u16 = 0;
+pub const AUDIO_CLIENT_DISABLE: u16 = 1;
+pub const AUDIO_CLIENT_SET_FORMAT: u16 = 2;
+
+// Operations of audio message from server.
+const AUDIO_SERVER_END: u16 = 0;
+const AUDIO_SERVER_BEGIN: u16 = 1;
+const AUDIO_SERVER_DATA: u16 = 2;
+
+const MAX_AUDIO_CHANNELS: u8 = 8;
+const MAX_AUDIO_FREQ: u32 = 192000;
+
+/// Sample format of audio data sent to client, all in little endian.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioSampleFormat {
+    U8 = 0,
+    S8 = 1,
+    U16 = 2,
+    S16 = 3,
+    U32 = 4,
+    S32 = 5,
+}
+
+impl AudioSampleFormat {
+    fn bytes(&self) -> usize {
+        match self {
+            AudioSampleFormat::U8 | AudioSampleFormat::S8 => 1,
+            AudioSampleFormat::U16 | AudioSampleFormat::S16 => 2,
+            AudioSampleFormat::U32 | AudioSampleFormat::S32 => 4,
+        }
+    }
+
+    /// Encode the sample, which is scaled to 32 bits.
+    fn encode(&self, sample: i32, buf: &mut Vec<u8>) {
+        match self {
+            AudioSampleFormat::U8 => buf.push(((sample >> 24) as u8) ^ 0x80),
+            AudioSampleFormat::S8 => buf.push((sample >> 24) as u8),
+            AudioSampleFormat::U16 => {
+                buf.extend_from_slice(&(((sample >> 16) as u16) ^ 0x8000).to_le_bytes())
+            }
+            AudioSampleFormat::S16 => buf.extend_from_slice(&((sample >> 16) as i16).to_le_bytes()),
+            AudioSampleFormat::U32 => {
+                buf.extend_from_slice(&((sample as u32) ^ 0x8000_0000).to_le_bytes())
+            }
+            AudioSampleFormat::S32 => buf.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+}
+
+impl TryFrom<u8> for AudioSampleFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(v: u8) -> Result<Self> {
+        let fmt = match v {
+            0 => AudioSampleFormat::U8,
+            1 => AudioSampleFormat::S8,
+            2 => AudioSampleFormat::U16,
+            3 => AudioSampleFormat::S16,
+            4 => AudioSampleFormat::U32,
+            5 => AudioSampleFormat::S32,
+            _ => bail!("Invalid audio sample format {}", v),
+        };
+        Ok(fmt)
+    }
+}
+
+/// Audio stream of a vnc client.
+pub struct VncAudio {
+    /// Whether the client enables audio.
+    pub enabled: bool,
+    /// Sample format requested by client.
+    pub sample: AudioSampleFormat,
+    /// Number of channels requested by client.
+    pub channels: u8,
+    /// Frequency requested by client.
+    pub freq: u32,
+    /// Accumulator of resampling, carried between the played chunks.
+    resample_acc: u64,
+}
+
+impl Default for VncAudio {
+    fn default() -> Self {
+        VncAudio {
+            enabled: false,
+            sample: AudioSampleFormat::S16,
+            channels: 2,
+            freq: 44100,
+            resample_acc: 0,
+        }
+    }
+}
+
+impl VncAudio {
+    /// Set the format of audio stream sent to client.
+    pub fn set_format(&mut self, sample: u8, channels: u8, freq: u32) -> Result<()> {
+        if channels == 0 || channels > MAX_AUDIO_CHANNELS {
+            bail!("Invalid audio channels {}", channels);
+        }
+        if freq == 0 || freq > MAX_AUDIO_FREQ {
+            bail!("Invalid audio frequency {}", freq);
+        }
+        self.sample = AudioSampleFormat::try_from(sample)?;
+        self.channels = channels;
+        self.freq = freq;
+        self.resample_acc = 0;
+        Ok(())
+    }
+
+    /// Convert the pcm data played by guest into the format of client.
+    ///
+    /// # Arguments
+    ///
+    /// * `freq` - Frequency of the pcm data.
+    /// * `bits` - Bits of each sample, which is signed integer in little endian.
+    /// * `channels` - Number of channels of the pcm data.
+    /// * `data` - Interleaved pcm data.
+    pub fn convert(&mut self, freq: u32, bits: u8, channels: u8, data: &[u8]) -> Vec<u8> {
+        let sample_bytes = bits as usize / 8;
+        if freq == 0 || channels == 0 || !(2..=4).contains(&sample_bytes) {
+            return Vec::new();
+        }
+        let frame_bytes = sample_bytes * channels as usize;
+        let frames = data.len() / frame_bytes;
+        let out_frames = (frames as u64 * self.freq as u64 / freq as u64 + 1) as usize;
+        let mut buf = Vec::with_capacity(out_frames * self.channels as usize * self.sample.bytes());
+
+        for frame in data.chunks_exact(frame_bytes) {
+            // Nearest neighbour resampling: each input frame is repeated as
+            // many times as the output frames fall in it.
+            self.resample_acc += self.freq as u64;
+            while self.resample_acc >= freq as u64 {
+                self.resample_acc -= freq as u64;
+                for ch in 0..self.channels as usize {
+                    // Missing channels are copied from the last one.
+                    let offset = std::cmp::min(ch, channels as usize - 1) * sample_bytes;
+                    let mut bytes = [0_u8; 4];
+                    bytes[4 - sample_bytes..]
+                        .copy_from_slice(&frame[offset..offset + sample_bytes]);
+                    self.sample.encode(i32::from_le_bytes(bytes), &mut buf);
+                }
+            }
+        }
+        buf
+    }
+}
+
+/// Audio message from server to client.
+fn audio_msg(op: u16, buf: &mut Vec<u8>) {
+    buf.push(QEMU_MSG);
+    buf.push(QEMU_MSG_AUDIO);
+    buf.extend_from_slice(&op.to_be_bytes());
+}
+
+pub fn audio_begin_msg(buf: &mut Vec<u8>) {
+    audio_msg(AUDIO_SERVER_BEGIN, buf);
+}
+
+pub fn audio_end_msg(buf: &mut Vec<u8>) {
+    audio_msg(AUDIO_SERVER_END, buf);
+}
+
+pub fn audio_data_msg(data: &[u8], buf: &mut Vec<u8>) {
+    audio_msg(AUDIO_SERVER_DATA, buf);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_convert() {
+        let mut audio = VncAudio::default();
+        assert!(audio.set_format(6, 2, 44100).is_err());
+        assert!(audio.set_format(3, 0, 44100).is_err());
+        assert!(audio.set_format(3, 2, 0).is_err());
+
+        // Same format is not changed.
+        let data: Vec<u8> = [1_i16, -2, 0x7fff, -0x8000]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        audio.set_format(3, 2, 48000).unwrap();
+        assert_eq!(audio.convert(48000, 16, 2, &data), data);
+
+        // 32 bits to unsigned 8 bits, stereo to mono, half frequency.
+        // The second frame is picked.
+        let data: Vec<u8> = [-0x8000_0000_i32, 0, 0x7fff_ffff, 0]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        audio.set_format(0, 1, 24000).unwrap();
+        assert_eq!(audio.convert(48000, 32, 2, &data), vec![0xff]);
+        // Accumulator is carried to the next chunk.
+        assert_eq!(audio.convert(48000, 32, 2, &data), vec![0xff]);
+
+        // 24 bits mono to signed 16 bits stereo, double frequency.
+        let data = vec![0x00, 0x80, 0xff, 0x00, 0x00, 0x80];
+        audio.set_format(3, 2, 96000).unwrap();
+        let expected: Vec<u8> = [
+            -128_i16, -128, -128, -128, -0x8000, -0x8000, -0x8000, -0x8000,
+        ]
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect();
+        assert_eq!(audio.convert(48000, 24, 1, &data), expected);
+
+        // Unsupported sample size.
+        assert!(audio.convert(48000, 8, 2, &[0; 4]).is_empty());
+
+        let mut buf = Vec::new();
+        audio_data_msg(&[1, 2], &mut buf);
+        assert_eq!(buf, vec![255, 1, 0, 2, 0, 0, 0, 2, 1, 2]);
+    }
+}
@@ -53,8 +53,12 @@ pub enum SubAuthState {
     VncAuthVencryptPlain = 256,
     /// Tls vencry with anon + no auth.
     VncAuthVencryptTlNone = 257,
+    /// Tls vencrypt with anon + vnc auth.
+    VncAuthVencryptTlsVnc = 258,
     /// Tls vencrypt with x509 + no auth.
     VncAuthVencryptX509None = 260,
+    /// Tls vencrypt with x509 + vnc auth.
+    VncAuthVencryptX509Vnc = 261,
     /// Tls vencrypt with x509 + sasl.
     VncAuthVencryptX509Sasl = 263,
     /// Tls vencrypt + sasl.
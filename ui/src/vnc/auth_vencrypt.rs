@@ -237,6 +237,9 @@ impl ClientIoHandler {
                 self.expect = 1;
                 self.msg_handler = ClientIoHandler::handle_client_init;
             }
+            SubAuthState::VncAuthVencryptX509Vnc | SubAuthState::VncAuthVencryptTlsVnc => {
+                self.start_vnc_auth()?;
+            }
             _ => {
                 let mut buf: Vec<u8> = Vec::new();
                 buf.append(&mut (0_u8).to_be_bytes().to_vec());
@@ -417,6 +420,11 @@ impl IoOperations for TlsIoChannel {
         self.tls_conn.read_tls(&mut self.stream)?;
 
         let io_state = self.tls_conn.process_new_packets()?;
+        // Tls handshake may be not finished if the channel is used directly
+        // after connection, such as websocket over tls.
+        if self.tls_conn.wants_write() {
+            self.tls_conn.write_tls(&mut self.stream)?;
+        }
         if io_state.plaintext_bytes_to_read() > 0 {
             len = io_state.plaintext_bytes_to_read();
             buf.resize(len, 0u8);
@@ -0,0 +1,256 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use des::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Des,
+};
+use log::info;
+use once_cell::sync::Lazy;
+
+use crate::{
+    error::VncError,
+    vnc::client_io::{vnc_flush, vnc_write, ClientIoHandler},
+};
+
+/// Size of the challenge of VNC authentication.
+pub const VNC_AUTH_CHALLENGE_SIZE: usize = 16;
+/// Only the first 8 characters of password are used as DES key.
+const VNC_AUTH_KEY_SIZE: usize = 8;
+const DES_BLOCK_SIZE: usize = 8;
+
+/// Access right granted by the password.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VncAccess {
+    /// Keyboard, pointer and clipboard of the client are accepted.
+    Full,
+    /// Client can only watch the display.
+    ViewOnly,
+}
+
+/// Passwords of VNC authentication, shared by all heads.
+#[derive(Default)]
+struct VncPassword {
+    full: Option<String>,
+    view_only: Option<String>,
+    /// Seconds since the epoch when the passwords expire, None means never.
+    expire: Option<u64>,
+}
+
+static VNC_PASSWORD: Lazy<Mutex<VncPassword>> = Lazy::new(|| Mutex::new(VncPassword::default()));
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Set the password for the access right. Empty password disables the access.
+pub fn vnc_set_password(password: &str, access: VncAccess) {
+    let password = match password.is_empty() {
+        true => None,
+        false => Some(password.to_string()),
+    };
+    let mut locked_password = VNC_PASSWORD.lock().unwrap();
+    match access {
+        VncAccess::Full => locked_password.full = password,
+        VncAccess::ViewOnly => locked_password.view_only = password,
+    }
+}
+
+/// Set the expiration time of passwords.
+///
+/// # Arguments
+///
+/// * `time` - "now", "never", "+N" seconds from now or "N" seconds since the epoch.
+pub fn vnc_expire_password(time: &str) -> Result<()> {
+    let expire = parse_expire_time(time, now_secs())?;
+    VNC_PASSWORD.lock().unwrap().expire = expire;
+    Ok(())
+}
+
+fn parse_expire_time(time: &str, now: u64) -> Result<Option<u64>> {
+    let expire = match time {
+        "now" => Some(now),
+        "never" => None,
+        _ => {
+            let (relative, secs) = match time.strip_prefix('+') {
+                Some(secs) => (true, secs),
+                None => (false, time),
+            };
+            let secs = secs
+                .parse::<u64>()
+                .with_context(|| format!("Invalid expire time {}", time))?;
+            match relative {
+                true => Some(now.saturating_add(secs)),
+                false => Some(secs),
+            }
+        }
+    };
+    Ok(expire)
+}
+
+/// Encrypt the challenge with password as DES key. Bits of each key byte are
+/// reversed, as required by VNC authentication.
+fn vnc_auth_response(password: &str, challenge: &[u8]) -> Vec<u8> {
+    let mut key = [0_u8; VNC_AUTH_KEY_SIZE];
+    for (k, p) in key.iter_mut().zip(password.as_bytes()) {
+        *k = p.reverse_bits();
+    }
+    let cipher = Des::new(GenericArray::from_slice(&key));
+    let mut response = Vec::with_capacity(challenge.len());
+    for chunk in challenge.chunks(DES_BLOCK_SIZE) {
+        let mut block = GenericArray::clone_from_slice(chunk);
+        cipher.encrypt_block(&mut block);
+        response.extend_from_slice(&block);
+    }
+    response
+}
+
+/// Check the response of client, and return the access right of the matched password.
+fn vnc_auth_check(challenge: &[u8], response: &[u8], now: u64) -> Result<VncAccess> {
+    let locked_password = VNC_PASSWORD.lock().unwrap();
+    if locked_password.expire.is_some_and(|t| now >= t) {
+        bail!("Password is expired");
+    }
+    let passwords = [
+        (&locked_password.full, VncAccess::Full),
+        (&locked_password.view_only, VncAccess::ViewOnly),
+    ];
+    for (password, access) in passwords {
+        if let Some(p) = password {
+            if vnc_auth_response(p, challenge) == response {
+                return Ok(access);
+            }
+        }
+    }
+    bail!("Password is incorrect")
+}
+
+impl ClientIoHandler {
+    /// Send the random challenge to client.
+    pub fn start_vnc_auth(&mut self) -> Result<()> {
+        let mut challenge = [0_u8; VNC_AUTH_CHALLENGE_SIZE];
+        // SAFETY: the buffer is valid and the length is correct.
+        let ret = unsafe {
+            libc::getrandom(
+                challenge.as_mut_ptr() as *mut libc::c_void,
+                challenge.len(),
+                0,
+            )
+        };
+        if ret != challenge.len() as isize {
+            bail!("Failed to generate challenge for vnc auth");
+        }
+        self.vnc_challenge = challenge.to_vec();
+
+        let client = self.client.clone();
+        vnc_write(&client, challenge.to_vec());
+        vnc_flush(&client);
+        self.update_event_handler(VNC_AUTH_CHALLENGE_SIZE, ClientIoHandler::handle_vnc_auth);
+        Ok(())
+    }
+
+    /// Check the response of challenge from client.
+    fn handle_vnc_auth(&mut self) -> Result<()> {
+        let response = self.read_incoming_msg();
+        let client = self.client.clone();
+        let challenge = std::mem::take(&mut self.vnc_challenge);
+        let mut buf = Vec::new();
+        match vnc_auth_check(&challenge, &response, now_secs()) {
+            Ok(access) => {
+                info!(
+                    "Vnc client {} authenticated, access {:?}",
+                    client.addr, access
+                );
+                client.conn_state.lock().unwrap().view_only = access == VncAccess::ViewOnly;
+                // Accept auth.
+                buf.append(&mut (0_u32).to_be_bytes().to_vec());
+                vnc_write(&client, buf);
+                vnc_flush(&client);
+                self.update_event_handler(1, ClientIoHandler::handle_client_init);
+                Ok(())
+            }
+            Err(e) => {
+                // Reject auth.
+                buf.append(&mut (1_u32).to_be_bytes().to_vec());
+                if client.conn_state.lock().unwrap().version.minor >= 8 {
+                    let err_msg = "Authentication failed";
+                    buf.append(&mut (err_msg.len() as u32).to_be_bytes().to_vec());
+                    buf.append(&mut err_msg.as_bytes().to_vec());
+                }
+                vnc_write(&client, buf);
+                vnc_flush(&client);
+                Err(anyhow!(VncError::AuthFailed(
+                    "handle_vnc_auth".to_string(),
+                    e.to_string()
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vnc_auth() {
+        // Response is generated by openssl with reversed key.
+        let challenge = b"0123456789abcdef";
+        let expected = [
+            0x56, 0x45, 0xab, 0xeb, 0x5f, 0x1e, 0x64, 0x75, 0xe8, 0xfe, 0xb1, 0x1b, 0xeb, 0x66,
+            0xea, 0x19,
+        ];
+        assert_eq!(vnc_auth_response("password", challenge), expected);
+        // Only the first 8 characters are used.
+        assert_eq!(vnc_auth_response("password123", challenge), expected);
+
+        // No password is set.
+        assert!(vnc_auth_check(challenge, &expected, 100).is_err());
+        vnc_set_password("password", VncAccess::Full);
+        vnc_set_password("viewer", VncAccess::ViewOnly);
+        assert_eq!(
+            vnc_auth_check(challenge, &expected, 100).unwrap(),
+            VncAccess::Full
+        );
+        let response = vnc_auth_response("viewer", challenge);
+        assert_eq!(
+            vnc_auth_check(challenge, &response, 100).unwrap(),
+            VncAccess::ViewOnly
+        );
+        let response = vnc_auth_response("wrong", challenge);
+        assert!(vnc_auth_check(challenge, &response, 100).is_err());
+
+        // Expiration of password.
+        assert_eq!(parse_expire_time("now", 100).unwrap(), Some(100));
+        assert_eq!(parse_expire_time("never", 100).unwrap(), None);
+        assert_eq!(parse_expire_time("+60", 100).unwrap(), Some(160));
+        assert_eq!(parse_expire_time("1000", 100).unwrap(), Some(1000));
+        assert!(parse_expire_time("-1", 100).is_err());
+        assert!(parse_expire_time("+", 100).is_err());
+        VNC_PASSWORD.lock().unwrap().expire = Some(200);
+        assert!(vnc_auth_check(challenge, &expected, 199).is_ok());
+        assert!(vnc_auth_check(challenge, &expected, 200).is_err());
+
+        VNC_PASSWORD.lock().unwrap().expire = None;
+        vnc_set_password("", VncAccess::ViewOnly);
+        let response = vnc_auth_response("viewer", challenge);
+        assert!(vnc_auth_check(challenge, &response, 100).is_err());
+    }
+}
@@ -11,7 +11,8 @@
 // See the Mulan PSL v2 for more details.
 
 use crate::{
-    console::console_select,
+    clipboard::{clipboard_update_from_host, get_clipboard_text, CLIPBOARD_MAX_SIZE},
+    console::{console_select, get_console_by_id, graphic_hardware_ui_info},
     error::VncError,
     input::{
         key_event, keyboard_modifier_get, keyboard_state_reset, point_event, update_key_state,
@@ -21,12 +22,21 @@ use crate::{
     pixman::{bytes_per_pixel, get_image_height, get_image_width, PixelFormat},
     utils::BuffPool,
     vnc::{
-        auth_sasl::AuthState, framebuffer_update, round_up_div, server_io::VncServer,
+        audio::{
+            audio_begin_msg, audio_end_msg, VncAudio, AUDIO_CLIENT_DISABLE, AUDIO_CLIENT_ENABLE,
+            AUDIO_CLIENT_SET_FORMAT, QEMU_MSG_AUDIO,
+        },
+        auth_sasl::AuthState,
+        encoding::ZlibStreams,
+        framebuffer_update, round_up_div,
+        server_io::{VncServer, VncSharePolicy},
         set_area_dirty, write_pixel, BIT_PER_BYTE, DIRTY_PIXELS_NUM, DIRTY_WIDTH_BITS,
-        MAX_IMAGE_SIZE, MAX_WINDOW_HEIGHT, MIN_OUTPUT_LIMIT, OUTPUT_THROTTLE_SCALE,
+        MAX_IMAGE_SIZE, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH, MIN_OUTPUT_LIMIT,
+        OUTPUT_THROTTLE_SCALE,
     },
 };
 use anyhow::{anyhow, bail, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 use log::error;
 use sscanf::scanf;
 use std::{
@@ -55,17 +65,40 @@ const NUM_OF_COLORMAP: u16 = 256;
 // VNC encodings types.
 pub const ENCODING_RAW: i32 = 0;
 pub const ENCODING_HEXTILE: i32 = 5;
-const ENCODING_ZLIB: i32 = 6;
-const ENCODING_TIGHT: i32 = 7;
-const ENCODING_ZRLE: i32 = 16;
-const ENCODING_ZYWRLE: i32 = 17;
+pub const ENCODING_ZLIB: i32 = 6;
+pub const ENCODING_TIGHT: i32 = 7;
+pub const ENCODING_ZRLE: i32 = 16;
+pub const ENCODING_ZYWRLE: i32 = 17;
 const ENCODING_DESKTOPRESIZE: i32 = -223;
 pub const ENCODING_RICH_CURSOR: i32 = -239;
 const ENCODING_POINTER_TYPE_CHANGE: i32 = -257;
+const ENCODING_AUDIO: i32 = -259;
 const ENCODING_LED_STATE: i32 = -261;
 const ENCODING_DESKTOP_RESIZE_EXT: i32 = -308;
 pub const ENCODING_ALPHA_CURSOR: i32 = -314;
 const ENCODING_WMVI: i32 = 1464686185;
+const ENCODING_COMPRESS_LEVEL0: i32 = -256;
+const ENCODING_COMPRESS_LEVEL9: i32 = -247;
+const ENCODING_QUALITY_LEVEL0: i32 = -32;
+const ENCODING_QUALITY_LEVEL9: i32 = -23;
+const ENCODING_CLIPBOARD_EXT: i32 = -1063131698;
+
+// Flags of extended clipboard message.
+const CLIPBOARD_FORMAT_TEXT: u32 = 1 << 0;
+const CLIPBOARD_ACTION_CAPS: u32 = 1 << 24;
+const CLIPBOARD_ACTION_REQUEST: u32 = 1 << 25;
+const CLIPBOARD_ACTION_PEEK: u32 = 1 << 26;
+const CLIPBOARD_ACTION_NOTIFY: u32 = 1 << 27;
+const CLIPBOARD_ACTION_PROVIDE: u32 = 1 << 28;
+// Reason and status of extended desktop size.
+const DESKTOP_SIZE_REASON_SERVER: u16 = 0;
+const DESKTOP_SIZE_REASON_CLIENT: u16 = 1;
+const DESKTOP_SIZE_STATUS_OK: u16 = 0;
+const DESKTOP_SIZE_STATUS_PROHIBITED: u16 = 1;
+const DESKTOP_SIZE_STATUS_INVALID: u16 = 3;
+const DESKTOP_SIZE_STATUS_FORWARDED: u16 = 4;
+/// Default compression level of zlib.
+const DEFAULT_COMPRESS_LEVEL: u8 = 6;
 
 /// This trait is used to send bytes,
 /// the return is the total number of bytes sented.
@@ -91,6 +124,7 @@ pub enum VncFeatures {
     VncFeatureLedState,
     VncFeatureXvp,
     VncFeatureClipboardExt,
+    VncFeatureAudio,
 }
 
 /// Client to server message in Remote Framebuffer Protocol.
@@ -101,6 +135,8 @@ pub enum ClientMsg {
     KeyEvent = 4,
     PointerEvent = 5,
     ClientCutText = 6,
+    SetDesktopSize = 251,
+    QemuMsg = 255,
     InvalidMsg,
 }
 
@@ -108,6 +144,7 @@ pub enum ClientMsg {
 pub enum ServerMsg {
     FramebufferUpdate = 0,
     SetColourMapEntries = 1,
+    ServerCutText = 3,
 }
 
 impl From<u8> for ClientMsg {
@@ -119,6 +156,8 @@ impl From<u8> for ClientMsg {
             4 => ClientMsg::KeyEvent,
             5 => ClientMsg::PointerEvent,
             6 => ClientMsg::ClientCutText,
+            251 => ClientMsg::SetDesktopSize,
+            255 => ClientMsg::QemuMsg,
             _ => ClientMsg::InvalidMsg,
         }
     }
@@ -182,6 +221,10 @@ pub struct DisplayMode {
     pub convert: bool,
     /// Image pixel format in pixman.
     pub pf: PixelFormat,
+    /// Compression level of zlib streams.
+    pub compress_level: u8,
+    /// Jpeg quality level of tight encoding, None means jpeg is disabled.
+    pub quality_level: Option<u8>,
 }
 
 impl DisplayMode {
@@ -194,6 +237,8 @@ impl DisplayMode {
             client_be,
             convert,
             pf,
+            compress_level: DEFAULT_COMPRESS_LEVEL,
+            quality_level: None,
         }
     }
 
@@ -310,6 +355,12 @@ pub struct ConnState {
     pub version: VncVersion,
     /// Point to Client Io handler.
     pub client_io: Option<Weak<Mutex<ClientIoHandler>>>,
+    /// Whether the client initialization is finished.
+    pub initialized: bool,
+    /// Whether the client has exclusive access to the display.
+    pub exclusive: bool,
+    /// Whether the client is authenticated by the view-only password.
+    pub view_only: bool,
 }
 
 impl Default for ConnState {
@@ -320,6 +371,9 @@ impl Default for ConnState {
             update_state: UpdateState::No,
             version: VncVersion::default(),
             client_io: None,
+            initialized: false,
+            exclusive: false,
+            view_only: false,
         }
     }
 }
@@ -352,6 +406,8 @@ impl ConnState {
 pub struct ClientState {
     /// Tcp listening address.
     pub addr: String,
+    /// Whether the client connects with websocket.
+    pub websocket: bool,
     /// Disconnect event fd.
     pub disconn_evt: Arc<Mutex<EventFd>>,
     /// Write event fd.
@@ -366,12 +422,17 @@ pub struct ClientState {
     pub conn_state: Arc<Mutex<ConnState>>,
     /// Identify the image update area.
     pub dirty_bitmap: Arc<Mutex<Bitmap<u64>>>,
+    /// Zlib streams used by compressed encodings.
+    pub zlib_streams: Arc<Mutex<ZlibStreams>>,
+    /// Audio stream sent to client.
+    pub audio: Arc<Mutex<VncAudio>>,
 }
 
 impl ClientState {
-    pub fn new(addr: String) -> Self {
+    pub fn new(addr: String, websocket: bool) -> Self {
         ClientState {
             addr,
+            websocket,
             disconn_evt: Arc::new(Mutex::new(EventFd::new(libc::EFD_NONBLOCK).unwrap())),
             write_fd: Arc::new(Mutex::new(EventFd::new(libc::EFD_NONBLOCK).unwrap())),
             in_buffer: Arc::new(Mutex::new(BuffPool::new())),
@@ -382,6 +443,8 @@ impl ClientState {
                 MAX_WINDOW_HEIGHT as usize
                     * round_up_div(DIRTY_WIDTH_BITS as u64, u64::BITS as u64) as usize,
             ))),
+            zlib_streams: Arc::new(Mutex::new(ZlibStreams::default())),
+            audio: Arc::new(Mutex::new(VncAudio::default())),
         }
     }
 }
@@ -404,6 +467,8 @@ pub struct ClientIoHandler {
     pub client: Arc<ClientState>,
     /// Configure for vnc server.
     pub server: Arc<VncServer>,
+    /// Challenge sent to client in vnc authentication.
+    pub vnc_challenge: Vec<u8>,
 }
 
 impl ClientIoHandler {
@@ -422,6 +487,7 @@ impl ClientIoHandler {
             expect: 12,
             client,
             server,
+            vnc_challenge: Vec::new(),
         }
     }
 }
@@ -528,7 +594,7 @@ impl ClientIoHandler {
             version.minor = 3;
         }
         self.client.conn_state.lock().unwrap().version = version;
-        let auth = self.server.security_type.borrow().auth;
+        let auth = self.client_auth();
 
         if self.client.conn_state.lock().unwrap().version.minor == 3 {
             match auth {
@@ -538,6 +604,12 @@ impl ClientIoHandler {
                     vnc_write(&client, buf);
                     self.update_event_handler(1, ClientIoHandler::handle_client_init);
                 }
+                AuthState::Vnc => {
+                    let mut buf = Vec::new();
+                    buf.append(&mut (AuthState::Vnc as u32).to_be_bytes().to_vec());
+                    vnc_write(&client, buf);
+                    self.start_vnc_auth()?;
+                }
                 _ => {
                     self.auth_failed("Unsupported auth method");
                     return Err(anyhow!(VncError::AuthFailed(
@@ -559,25 +631,16 @@ impl ClientIoHandler {
 
     /// Initialize the connection of vnc client.
     pub fn handle_client_init(&mut self) -> Result<()> {
-        let mut buf = Vec::new();
-        // If the total number of connection exceeds the limit,
-        // then the old client will be disconnected.
+        let shared_flag = self.read_incoming_msg()[0];
         let server = self.server.clone();
         let client = self.client.clone();
-        let addr = client.addr.clone();
-        let mut locked_clients = server.client_handlers.lock().unwrap();
-        let mut len = locked_clients.len() as i32;
-        for client in locked_clients.values_mut() {
-            if len <= server.conn_limits as i32 {
-                break;
-            }
-            if client.addr != addr {
-                vnc_disconnect_start(client);
-                len -= 1;
-            }
-        }
-        drop(locked_clients);
+        // Shared flag of client is ignored if clients are forced to share the display.
+        let exclusive = shared_flag == 0 && server.share_policy == VncSharePolicy::AllowExclusive;
+        // The new client replaces the old one if only one client is allowed.
+        self.check_share(exclusive || server.share_policy == VncSharePolicy::SingleClient)?;
+        client.conn_state.lock().unwrap().exclusive = exclusive;
 
+        let mut buf = Vec::new();
         // Send server framebuffer info.
         let locked_surface = self.server.vnc_surface.lock().unwrap();
         let width = get_image_width(locked_surface.server_image);
@@ -599,14 +662,56 @@ impl ClientIoHandler {
         buf.append(&mut APP_NAME.to_string().as_bytes().to_vec());
         vnc_write(&client, buf);
         vnc_flush(&client);
+        client.conn_state.lock().unwrap().initialized = true;
         self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
         Ok(())
     }
 
+    /// Apply the share policy to the new client. Exclusive client disconnects
+    /// all the others, and the shared client is rejected if there is an
+    /// exclusive one or the number of connections exceeds the limit.
+    fn check_share(&mut self, exclusive: bool) -> Result<()> {
+        let server = self.server.clone();
+        let addr = self.client.addr.clone();
+        let mut locked_clients = server.client_handlers.lock().unwrap();
+        let mut shared_num = 0;
+        let mut reason = None;
+        for client in locked_clients.values_mut() {
+            if client.addr == addr {
+                continue;
+            }
+            if exclusive {
+                vnc_disconnect_start(client);
+                continue;
+            }
+            let locked_state = client.conn_state.lock().unwrap();
+            if !locked_state.initialized || locked_state.dis_conn {
+                continue;
+            }
+            if locked_state.exclusive {
+                reason = Some("the display is used exclusively");
+                break;
+            }
+            shared_num += 1;
+        }
+        drop(locked_clients);
+
+        if shared_num >= server.conn_limits {
+            reason = Some("the number of connections exceeds the limit");
+        }
+        if let Some(reason) = reason {
+            return Err(anyhow!(VncError::MakeConnectionFailed(format!(
+                "Client {} is rejected, {}",
+                addr, reason
+            ))));
+        }
+        Ok(())
+    }
+
     /// Authentication
     fn handle_auth(&mut self) -> Result<()> {
         let buf = self.read_incoming_msg();
-        let auth = self.server.security_type.borrow().auth;
+        let auth = self.client_auth();
         let client = self.client.clone();
         let version = client.conn_state.lock().unwrap().version.clone();
 
@@ -635,6 +740,9 @@ impl ClientIoHandler {
                 vnc_write(&client, buf.to_vec());
                 self.update_event_handler(2, ClientIoHandler::client_vencrypt_init);
             }
+            AuthState::Vnc => {
+                self.start_vnc_auth()?;
+            }
             _ => {
                 self.auth_failed("Unhandled auth method");
                 return Err(anyhow!(VncError::AuthFailed(
@@ -670,7 +778,13 @@ impl ClientIoHandler {
                     .unwrap_or_else(|e| error!("Point event error: {:?}", e));
             }
             ClientMsg::ClientCutText => {
-                self.client_cut_event();
+                self.client_cut_event()?;
+            }
+            ClientMsg::SetDesktopSize => {
+                self.set_desktop_size()?;
+            }
+            ClientMsg::QemuMsg => {
+                self.qemu_msg()?;
             }
             _ => {
                 self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
@@ -796,6 +910,8 @@ impl ClientIoHandler {
         let mut locked_dpm = self.client.client_dpm.lock().unwrap();
         locked_dpm.feature = 0;
         locked_dpm.enc = 0;
+        locked_dpm.compress_level = DEFAULT_COMPRESS_LEVEL;
+        locked_dpm.quality_level = None;
         while num_encoding > 0 {
             let offset = (4 * num_encoding) as usize;
             let enc = i32::from_be_bytes([
@@ -852,18 +968,66 @@ impl ClientIoHandler {
                 ENCODING_LED_STATE => {
                     locked_dpm.feature |= 1 << VncFeatures::VncFeatureLedState as usize;
                 }
+                ENCODING_COMPRESS_LEVEL0..=ENCODING_COMPRESS_LEVEL9 => {
+                    locked_dpm.compress_level = (enc - ENCODING_COMPRESS_LEVEL0) as u8;
+                }
+                ENCODING_QUALITY_LEVEL0..=ENCODING_QUALITY_LEVEL9 => {
+                    locked_dpm.quality_level = Some((enc - ENCODING_QUALITY_LEVEL0) as u8);
+                }
+                ENCODING_CLIPBOARD_EXT => {
+                    locked_dpm.feature |= 1 << VncFeatures::VncFeatureClipboardExt as usize;
+                }
+                ENCODING_AUDIO => {
+                    locked_dpm.feature |= 1 << VncFeatures::VncFeatureAudio as usize;
+                }
                 _ => {}
             }
 
             num_encoding -= 1;
         }
 
+        let clipboard_ext = locked_dpm.has_feature(VncFeatures::VncFeatureClipboardExt);
+        let resize_ext = locked_dpm.has_feature(VncFeatures::VncFeatureResizeExt);
+        let audio = locked_dpm.has_feature(VncFeatures::VncFeatureAudio);
         drop(locked_dpm);
         let mut buf: Vec<u8> = Vec::new();
-        // VNC desktop resize.
-        desktop_resize(&client, &server, &mut buf)?;
+        // VNC desktop resize. Client supporting extended desktop size is
+        // always informed, so that it knows SetDesktopSize is accepted.
+        if resize_ext {
+            let (width, height) = desktop_size(&server)?;
+            desktop_size_ext(
+                &client,
+                width,
+                height,
+                DESKTOP_SIZE_REASON_SERVER,
+                DESKTOP_SIZE_STATUS_OK,
+                &mut buf,
+            );
+        } else {
+            desktop_resize(&client, &server, &mut buf)?;
+        }
         // VNC display cursor define.
         display_cursor_define(&client, &server, &mut buf);
+        // Extended clipboard capabilities.
+        if clipboard_ext {
+            let caps = CLIPBOARD_ACTION_CAPS
+                | CLIPBOARD_ACTION_REQUEST
+                | CLIPBOARD_ACTION_PEEK
+                | CLIPBOARD_ACTION_NOTIFY
+                | CLIPBOARD_ACTION_PROVIDE
+                | CLIPBOARD_FORMAT_TEXT;
+            // Max size of text format.
+            let size = (CLIPBOARD_MAX_SIZE as u32).to_be_bytes();
+            server_cut_text_ext(caps, &size, &mut buf);
+        }
+        // Acknowledge the audio pseudo-encoding.
+        if audio {
+            let (width, height) = desktop_size(&server)?;
+            buf.append(&mut (ServerMsg::FramebufferUpdate as u8).to_be_bytes().to_vec());
+            buf.append(&mut (0_u8).to_be_bytes().to_vec());
+            buf.append(&mut (1_u16).to_be_bytes().to_vec());
+            framebuffer_update(0, 0, width, height, ENCODING_AUDIO, &mut buf);
+        }
         vnc_write(&client, buf);
         vnc_flush(&client);
         self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
@@ -915,6 +1079,11 @@ impl ClientIoHandler {
             return Ok(());
         }
         let buf = self.read_incoming_msg();
+        // Input of view-only client is ignored.
+        if self.client.conn_state.lock().unwrap().view_only {
+            self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
+            return Ok(());
+        }
         let down: bool = buf[1] != 0;
         let org_keysym = i32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
         let mut keysym = org_keysym;
@@ -931,10 +1100,17 @@ impl ClientIoHandler {
         };
 
         // Ctr + Alt + Num(1~9)
-        // Switch to the corresponding display device.
+        // Switch to the corresponding display device, except for the heads
+        // which show a fixed console.
+        let follow_active = self
+            .server
+            .display_listener
+            .as_ref()
+            .and_then(|dcl| dcl.upgrade())
+            .map_or(false, |dcl| dcl.lock().unwrap().con_id.is_none());
         if (KEYCODE_1..KEYCODE_9 + 1).contains(&keycode)
             && down
-            && self.server.display_listener.is_some()
+            && follow_active
             && keyboard_modifier_get(KeyboardModifier::KeyModCtrl)
             && keyboard_modifier_get(KeyboardModifier::KeyModAlt)
         {
@@ -957,6 +1133,10 @@ impl ClientIoHandler {
         }
 
         let buf = self.read_incoming_msg();
+        if self.client.conn_state.lock().unwrap().view_only {
+            self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
+            return Ok(());
+        }
         let mut x = ((buf[2] as u16) << 8) + buf[3] as u16;
         let mut y = ((buf[4] as u16) << 8) + buf[5] as u16;
 
@@ -982,22 +1162,201 @@ impl ClientIoHandler {
     }
 
     /// Client cut text.
-    pub fn client_cut_event(&mut self) {
+    pub fn client_cut_event(&mut self) -> Result<()> {
         let buf = self.read_incoming_msg();
         if self.expect == 1 {
             self.expect = 8;
-            return;
+            return Ok(());
         }
-        if self.expect == 8 {
-            let buf = [buf[4], buf[5], buf[6], buf[7]];
-            let len = u32::from_be_bytes(buf);
-            if len > 0 {
-                self.expect += len as usize;
-                return;
+        // Negative length means the extended clipboard message.
+        let len = i32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let size = len.unsigned_abs() as usize;
+        if self.expect == 8 && size > 0 {
+            if size > CLIPBOARD_MAX_SIZE {
+                bail!("Client cut text is too large: {}", size);
             }
+            self.expect += size;
+            return Ok(());
         }
 
+        // Clipboard of view-only client is not shared with host.
+        if self.client.conn_state.lock().unwrap().view_only {
+            self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
+            return Ok(());
+        }
+        if len < 0 {
+            self.client_cut_text_ext(&buf[8..])?;
+        } else {
+            // Standard cut text is encoded with Latin-1.
+            let text: String = buf[8..].iter().map(|&c| c as char).collect();
+            clipboard_update_from_host(&text)
+                .unwrap_or_else(|e| error!("Failed to update clipboard: {:?}", e));
+        }
         self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
+        Ok(())
+    }
+
+    /// Client requests to change the desktop size.
+    fn set_desktop_size(&mut self) -> Result<()> {
+        let client = self.client.clone();
+        let server = self.server.clone();
+        let buf = self.read_incoming_msg();
+        if self.expect == 1 {
+            self.expect = 8;
+            return Ok(());
+        }
+        let num_screens = buf[6] as usize;
+        if self.expect == 8 && num_screens > 0 {
+            self.expect += num_screens * 16;
+            return Ok(());
+        }
+
+        let width = u16::from_be_bytes([buf[2], buf[3]]);
+        let height = u16::from_be_bytes([buf[4], buf[5]]);
+        let status = self.forward_desktop_size(width, height, num_screens);
+        let (width, height) = desktop_size(&server)?;
+        let mut buf = Vec::new();
+        desktop_size_ext(
+            &client,
+            width,
+            height,
+            DESKTOP_SIZE_REASON_CLIENT,
+            status,
+            &mut buf,
+        );
+        vnc_write(&client, buf);
+        vnc_flush(&client);
+        self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
+        Ok(())
+    }
+
+    /// Forward the desktop size requested by client to the graphic device.
+    /// Only the total size is used, the layout of screens is ignored.
+    fn forward_desktop_size(&self, width: u16, height: u16, num_screens: usize) -> u16 {
+        if num_screens == 0
+            || !(1..=MAX_WINDOW_WIDTH).contains(&width)
+            || !(1..=MAX_WINDOW_HEIGHT).contains(&height)
+        {
+            return DESKTOP_SIZE_STATUS_INVALID;
+        }
+        if self.client.conn_state.lock().unwrap().view_only {
+            return DESKTOP_SIZE_STATUS_PROHIBITED;
+        }
+
+        let con_id = self
+            .server
+            .display_listener
+            .as_ref()
+            .and_then(|dcl| dcl.upgrade())
+            .and_then(|dcl| dcl.lock().unwrap().con_id);
+        let con = match get_console_by_id(con_id) {
+            Some(c) => c,
+            None => return DESKTOP_SIZE_STATUS_PROHIBITED,
+        };
+        let dev_opts = con.lock().unwrap().dev_opts.clone();
+        if !dev_opts.hw_ui_info_supported() {
+            return DESKTOP_SIZE_STATUS_PROHIBITED;
+        }
+        match graphic_hardware_ui_info(con, width as u32, height as u32) {
+            Ok(()) => DESKTOP_SIZE_STATUS_FORWARDED,
+            Err(e) => {
+                error!("Failed to forward desktop size: {:?}", e);
+                DESKTOP_SIZE_STATUS_PROHIBITED
+            }
+        }
+    }
+
+    /// QEMU client message, only the audio sub-message is supported.
+    fn qemu_msg(&mut self) -> Result<()> {
+        let buf = self.read_incoming_msg();
+        if self.expect == 1 {
+            self.expect = 4;
+            return Ok(());
+        }
+        if buf[1] != QEMU_MSG_AUDIO {
+            bail!("Unsupported qemu message {}", buf[1]);
+        }
+
+        let client = self.client.clone();
+        let mut msg = Vec::new();
+        let op = u16::from_be_bytes([buf[2], buf[3]]);
+        match op {
+            AUDIO_CLIENT_ENABLE => {
+                client.audio.lock().unwrap().enabled = true;
+                audio_begin_msg(&mut msg);
+            }
+            AUDIO_CLIENT_DISABLE => {
+                client.audio.lock().unwrap().enabled = false;
+                audio_end_msg(&mut msg);
+            }
+            AUDIO_CLIENT_SET_FORMAT => {
+                if self.expect == 4 {
+                    self.expect = 10;
+                    return Ok(());
+                }
+                let freq = u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]);
+                client
+                    .audio
+                    .lock()
+                    .unwrap()
+                    .set_format(buf[4], buf[5], freq)?;
+            }
+            _ => bail!("Invalid audio operation {}", op),
+        }
+        if !msg.is_empty() {
+            vnc_write(&client, msg);
+            vnc_flush(&client);
+        }
+        self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
+        Ok(())
+    }
+
+    /// Extended clipboard message from client.
+    fn client_cut_text_ext(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() < 4 {
+            bail!("Invalid extended clipboard message");
+        }
+        let flags = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        if flags & CLIPBOARD_FORMAT_TEXT == 0 || flags & CLIPBOARD_ACTION_CAPS != 0 {
+            // Only text is supported, and the capabilities of client are not used.
+            return Ok(());
+        }
+
+        let client = self.client.clone();
+        let mut buf = Vec::new();
+        if flags & CLIPBOARD_ACTION_REQUEST != 0 {
+            let text = clipboard_text_ext_encode(&get_clipboard_text())?;
+            server_cut_text_ext(
+                CLIPBOARD_ACTION_PROVIDE | CLIPBOARD_FORMAT_TEXT,
+                &text,
+                &mut buf,
+            );
+        }
+        if flags & CLIPBOARD_ACTION_PEEK != 0 {
+            server_cut_text_ext(
+                CLIPBOARD_ACTION_NOTIFY | CLIPBOARD_FORMAT_TEXT,
+                &[],
+                &mut buf,
+            );
+        }
+        if flags & CLIPBOARD_ACTION_NOTIFY != 0 {
+            // Text of client is changed, request for it.
+            server_cut_text_ext(
+                CLIPBOARD_ACTION_REQUEST | CLIPBOARD_FORMAT_TEXT,
+                &[],
+                &mut buf,
+            );
+        }
+        if flags & CLIPBOARD_ACTION_PROVIDE != 0 {
+            let text = clipboard_text_ext_decode(&data[4..])?;
+            clipboard_update_from_host(&text)
+                .unwrap_or_else(|e| error!("Failed to update clipboard: {:?}", e));
+        }
+        if !buf.is_empty() {
+            vnc_write(&client, buf);
+            vnc_flush(&client);
+        }
+        Ok(())
     }
 
     /// Invalid authentication, send 1 to reject.
@@ -1016,6 +1375,19 @@ impl ClientIoHandler {
         vnc_flush(&client);
     }
 
+    /// Security type of the client. The websocket is already encrypted by tls
+    /// when tls-creds is configured, so no more VeNCrypt is needed.
+    fn client_auth(&self) -> AuthState {
+        let security = self.server.security_type.borrow();
+        if self.client.websocket && security.tls_config.is_some() {
+            if security.password {
+                return AuthState::Vnc;
+            }
+            return AuthState::No;
+        }
+        security.auth
+    }
+
     /// Read the data from the receiver buffer.
     pub fn read_incoming_msg(&mut self) -> Vec<u8> {
         let mut buf: Vec<u8> = vec![0_u8; self.expect];
@@ -1242,19 +1614,60 @@ fn pixel_format_message(client: &Arc<ClientState>, buf: &mut Vec<u8>) {
     drop(locked_dpm);
 }
 
-/// Set Desktop Size.
-pub fn desktop_resize(
-    client: &Arc<ClientState>,
-    server: &Arc<VncServer>,
-    buf: &mut Vec<u8>,
-) -> Result<()> {
+/// Get the size of server image.
+fn desktop_size(server: &Arc<VncServer>) -> Result<(i32, i32)> {
     let locked_surface = server.vnc_surface.lock().unwrap();
     let width = get_image_width(locked_surface.server_image);
     let height = get_image_height(locked_surface.server_image);
     if !(0..=MAX_IMAGE_SIZE).contains(&width) || !(0..=MAX_IMAGE_SIZE).contains(&height) {
         return Err(anyhow!(VncError::InvalidImageSize(width, height)));
     }
-    drop(locked_surface);
+    Ok((width, height))
+}
+
+/// Extended desktop size with a single screen.
+fn desktop_size_ext(
+    client: &Arc<ClientState>,
+    width: i32,
+    height: i32,
+    reason: u16,
+    status: u16,
+    buf: &mut Vec<u8>,
+) {
+    let mut locked_dpm = client.client_dpm.lock().unwrap();
+    locked_dpm.client_width = width;
+    locked_dpm.client_height = height;
+    drop(locked_dpm);
+
+    buf.append(&mut (ServerMsg::FramebufferUpdate as u8).to_be_bytes().to_vec());
+    buf.append(&mut (0_u8).to_be_bytes().to_vec());
+    buf.append(&mut (1_u16).to_be_bytes().to_vec());
+    framebuffer_update(
+        reason as i32,
+        status as i32,
+        width,
+        height,
+        ENCODING_DESKTOP_RESIZE_EXT,
+        buf,
+    );
+    // Number of screens and padding.
+    buf.append(&mut [1_u8, 0, 0, 0].to_vec());
+    // Screen id, x, y, width, height and flags.
+    buf.append(&mut (0_u32).to_be_bytes().to_vec());
+    buf.append(&mut (0_u16).to_be_bytes().to_vec());
+    buf.append(&mut (0_u16).to_be_bytes().to_vec());
+    buf.append(&mut (width as u16).to_be_bytes().to_vec());
+    buf.append(&mut (height as u16).to_be_bytes().to_vec());
+    buf.append(&mut (0_u32).to_be_bytes().to_vec());
+}
+
+/// Set Desktop Size.
+pub fn desktop_resize(
+    client: &Arc<ClientState>,
+    server: &Arc<VncServer>,
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let (width, height) = desktop_size(server)?;
     let mut locked_dpm = client.client_dpm.lock().unwrap();
     if (!locked_dpm.has_feature(VncFeatures::VncFeatureResizeExt)
         && !locked_dpm.has_feature(VncFeatures::VncFeatureResize))
@@ -1262,6 +1675,18 @@ pub fn desktop_resize(
     {
         return Ok(());
     }
+    if locked_dpm.has_feature(VncFeatures::VncFeatureResizeExt) {
+        drop(locked_dpm);
+        desktop_size_ext(
+            client,
+            width,
+            height,
+            DESKTOP_SIZE_REASON_SERVER,
+            DESKTOP_SIZE_STATUS_OK,
+            buf,
+        );
+        return Ok(());
+    }
     locked_dpm.client_width = width;
     locked_dpm.client_height = height;
     drop(locked_dpm);
@@ -1413,3 +1838,78 @@ pub fn vnc_disconnect_start(client: &Arc<ClientState>) {
         .write(1)
         .unwrap_or_else(|e| error!("Error occurs during disconnection: {:?}", e));
 }
+
+/// Write the extended clipboard message to buffer.
+fn server_cut_text_ext(flags: u32, data: &[u8], buf: &mut Vec<u8>) {
+    buf.append(&mut (ServerMsg::ServerCutText as u8).to_be_bytes().to_vec());
+    buf.append(&mut [0_u8; 3].to_vec());
+    // Negative length identifies the extended message.
+    let len = -((data.len() + 4) as i32);
+    buf.append(&mut len.to_be_bytes().to_vec());
+    buf.append(&mut flags.to_be_bytes().to_vec());
+    buf.extend_from_slice(data);
+}
+
+/// Compress the text for extended clipboard provide message.
+fn clipboard_text_ext_encode(text: &str) -> Result<Vec<u8>> {
+    // Text is null-terminated with CRLF line endings.
+    let mut bytes = text
+        .replace("\r\n", "\n")
+        .replace('\n', "\r\n")
+        .into_bytes();
+    bytes.push(0);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    encoder.write_all(&bytes)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompress the text from extended clipboard provide message.
+fn clipboard_text_ext_decode(data: &[u8]) -> Result<String> {
+    let mut decoder = ZlibDecoder::new(data).take(CLIPBOARD_MAX_SIZE as u64 + 4);
+    let mut size = [0_u8; 4];
+    decoder.read_exact(&mut size)?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size > CLIPBOARD_MAX_SIZE {
+        bail!("Clipboard text is too large: {}", size);
+    }
+    let mut bytes = vec![0_u8; size];
+    decoder.read_exact(&mut bytes)?;
+    if let Some(pos) = bytes.iter().position(|&c| c == 0) {
+        bytes.truncate(pos);
+    }
+    Ok(String::from_utf8_lossy(&bytes).replace("\r\n", "\n"))
+}
+
+/// Send the clipboard text of guest to client.
+pub fn vnc_clipboard_update(client: &Arc<ClientState>) {
+    if !client.conn_state.lock().unwrap().initialized {
+        return;
+    }
+    let mut buf = Vec::new();
+    let clipboard_ext = client
+        .client_dpm
+        .lock()
+        .unwrap()
+        .has_feature(VncFeatures::VncFeatureClipboardExt);
+    if clipboard_ext {
+        // Client will request for the text after notification.
+        server_cut_text_ext(
+            CLIPBOARD_ACTION_NOTIFY | CLIPBOARD_FORMAT_TEXT,
+            &[],
+            &mut buf,
+        );
+    } else {
+        // Characters out of Latin-1 can not be sent by standard cut text.
+        let text: Vec<u8> = get_clipboard_text()
+            .chars()
+            .map(|c| if (c as u32) < 256 { c as u8 } else { b'?' })
+            .collect();
+        buf.append(&mut (ServerMsg::ServerCutText as u8).to_be_bytes().to_vec());
+        buf.append(&mut [0_u8; 3].to_vec());
+        buf.append(&mut (text.len() as u32).to_be_bytes().to_vec());
+        buf.extend_from_slice(&text);
+    }
+    vnc_write(client, buf);
+    vnc_flush(client);
+}
@@ -0,0 +1,462 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use crate::vnc::{
+    client_io::{DisplayMode, Rectangle, ENCODING_TIGHT},
+    client_pixel_value, convert_pixel,
+    encoding::{get_rect_pixels, zlib_compress, ZlibStreams},
+    framebuffer_update,
+};
+use anyhow::{anyhow, Result};
+use flate2::{Compress, Compression};
+use jpeg_encoder::{ColorType, Encoder};
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+};
+use util::pixman::pixman_image_t;
+
+/// Max size and width of rectangle in tight encoding.
+const TIGHT_MAX_RECT_SIZE: i32 = 65536;
+const TIGHT_MAX_RECT_WIDTH: i32 = 2048;
+/// Data shorter than this is sent without compression.
+const TIGHT_MIN_TO_COMPRESS: usize = 12;
+/// Compression control of tight.
+const TIGHT_FILL: u8 = 0x80;
+const TIGHT_JPEG: u8 = 0x90;
+const TIGHT_EXPLICIT_FILTER: u8 = 0x40;
+const TIGHT_FILTER_PALETTE: u8 = 0x01;
+/// Zlib streams used for different type of data.
+const TIGHT_STREAM_FULL_COLOR: usize = 0;
+const TIGHT_STREAM_MONO: usize = 1;
+const TIGHT_STREAM_INDEXED: usize = 2;
+/// Max number of colors in palette.
+const TIGHT_MAX_PALETTE: usize = 256;
+/// Max number of colors in palette if jpeg is enabled, more colors are
+/// regarded as photographic area.
+const TIGHT_JPEG_MAX_PALETTE: usize = 24;
+/// Min number of pixels to be compressed by jpeg.
+const TIGHT_JPEG_MIN_PIXELS: usize = 4096;
+/// Jpeg quality of each quality level.
+const TIGHT_JPEG_QUALITY: [u8; 10] = [15, 29, 41, 42, 62, 77, 79, 86, 92, 100];
+
+/// Compress data by tight algorithm before sending.
+/// Rectangles are split up by the size limit of tight, each of them
+/// is sent as one rectangle by fill, palette, full color or jpeg.
+///
+/// # Arguments
+///
+/// * `image` - pointer to the data need to be send.
+/// * `rect` - dirty area of image.
+/// * `client_dpm` - Output mode information of client display.
+/// * `streams` - zlib streams of client.
+/// * `buf` - send buffer.
+pub fn tight_send_framebuffer_update(
+    image: *mut pixman_image_t,
+    rect: &Rectangle,
+    client_dpm: &DisplayMode,
+    streams: &mut ZlibStreams,
+    buf: &mut Vec<u8>,
+) -> Result<i32> {
+    let max_w = cmp::min(rect.w, TIGHT_MAX_RECT_WIDTH);
+    let max_h = cmp::max(TIGHT_MAX_RECT_SIZE / max_w, 1);
+    let mut n_rects = 0;
+    let mut data: Vec<u8> = Vec::new();
+    for j in (0..rect.h).step_by(max_h as usize) {
+        for i in (0..rect.w).step_by(max_w as usize) {
+            let sub_rect = Rectangle::new(
+                rect.x + i,
+                rect.y + j,
+                cmp::min(max_w, rect.w - i),
+                cmp::min(max_h, rect.h - j),
+            );
+            let pixels = get_rect_pixels(image, &sub_rect);
+            framebuffer_update(
+                sub_rect.x,
+                sub_rect.y,
+                sub_rect.w,
+                sub_rect.h,
+                ENCODING_TIGHT,
+                &mut data,
+            );
+            compress_each_rect(&pixels, &sub_rect, client_dpm, streams, &mut data)?;
+            n_rects += 1;
+        }
+    }
+    buf.append(&mut data);
+    Ok(n_rects)
+}
+
+/// Compress each rectangle by the number of colors in it.
+///
+/// # Arguments
+///
+/// * `pixels` - pixels of rectangle.
+/// * `sub_rect` - area of rectangle.
+/// * `client_dpm` - Output mode information of client display.
+/// * `streams` - zlib streams of client.
+/// * `buf` - send buffer.
+fn compress_each_rect(
+    pixels: &[u32],
+    sub_rect: &Rectangle,
+    client_dpm: &DisplayMode,
+    streams: &mut ZlibStreams,
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let jpeg_quality = match client_dpm.quality_level {
+        Some(level) if client_dpm.pf.pixel_bytes > 1 && pixels.len() >= TIGHT_JPEG_MIN_PIXELS => {
+            Some(TIGHT_JPEG_QUALITY[level as usize])
+        }
+        _ => None,
+    };
+    let max_palette = match jpeg_quality {
+        Some(_) => TIGHT_JPEG_MAX_PALETTE,
+        None => TIGHT_MAX_PALETTE,
+    };
+
+    let mut palette: Vec<u32> = Vec::new();
+    let mut colors: HashSet<u32> = HashSet::new();
+    for &value in pixels {
+        if palette.len() > max_palette {
+            break;
+        }
+        if colors.insert(value) {
+            palette.push(value);
+        }
+    }
+
+    match palette.len() {
+        1 => {
+            buf.push(TIGHT_FILL);
+            write_tpixel(palette[0], client_dpm, buf);
+            Ok(())
+        }
+        n if n <= max_palette => {
+            tight_compress_basic(pixels, sub_rect, &palette, client_dpm, streams, buf)
+        }
+        _ => match jpeg_quality {
+            Some(quality) => tight_compress_jpeg(pixels, sub_rect, quality, buf),
+            None => tight_compress_basic(pixels, sub_rect, &[], client_dpm, streams, buf),
+        },
+    }
+}
+
+/// Write the pixel in TPIXEL format, which is 3 bytes of red, green and blue if
+/// client uses 32 bits pixel with depth of 24. The pixel is converted to the pixel
+/// format of client in both cases.
+fn write_tpixel(color: u32, client_dpm: &DisplayMode, buf: &mut Vec<u8>) {
+    let pf = &client_dpm.pf;
+    if pf.pixel_bits == 32
+        && pf.depth == 24
+        && pf.red.max == 0xff
+        && pf.green.max == 0xff
+        && pf.blue.max == 0xff
+    {
+        let value = client_pixel_value(client_dpm, color);
+        buf.push((value >> pf.red.shift) as u8 & pf.red.max);
+        buf.push((value >> pf.green.shift) as u8 & pf.green.max);
+        buf.push((value >> pf.blue.shift) as u8 & pf.blue.max);
+    } else {
+        convert_pixel(client_dpm, buf, color);
+    }
+}
+
+/// Write the length of compressed data in 1 to 3 bytes, with 7 bits in each byte.
+fn write_compact_len(len: usize, buf: &mut Vec<u8>) {
+    let mut len = len;
+    while len > 0x7f {
+        buf.push((len & 0x7f) as u8 | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+}
+
+/// Basic compression of tight, data of pixels is compressed by zlib streams.
+/// Rectangle with palette uses the palette filter.
+///
+/// # Arguments
+///
+/// * `pixels` - pixels of rectangle.
+/// * `sub_rect` - area of rectangle.
+/// * `palette` - colors of rectangle, empty means full color.
+/// * `client_dpm` - Output mode information of client display.
+/// * `streams` - zlib streams of client.
+/// * `buf` - send buffer.
+fn tight_compress_basic(
+    pixels: &[u32],
+    sub_rect: &Rectangle,
+    palette: &[u32],
+    client_dpm: &DisplayMode,
+    streams: &mut ZlibStreams,
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let mut data: Vec<u8> = Vec::new();
+    let stream_id = match palette.len() {
+        0 => {
+            for &value in pixels {
+                write_tpixel(value, client_dpm, &mut data);
+            }
+            TIGHT_STREAM_FULL_COLOR
+        }
+        2 => {
+            // One bit for each pixel, and each row is padded to byte.
+            for row in pixels.chunks(sub_rect.w as usize) {
+                for bits in row.chunks(8) {
+                    let mut byte: u8 = 0;
+                    for (i, value) in bits.iter().enumerate() {
+                        if *value == palette[1] {
+                            byte |= 0x80 >> i;
+                        }
+                    }
+                    data.push(byte);
+                }
+            }
+            TIGHT_STREAM_MONO
+        }
+        _ => {
+            let index: HashMap<u32, u8> = palette
+                .iter()
+                .enumerate()
+                .map(|(i, color)| (*color, i as u8))
+                .collect();
+            for value in pixels {
+                data.push(index[value]);
+            }
+            TIGHT_STREAM_INDEXED
+        }
+    };
+
+    let mut control = (stream_id as u8) << 4;
+    if !palette.is_empty() {
+        control |= TIGHT_EXPLICIT_FILTER;
+    }
+    let zlib_data = if data.len() < TIGHT_MIN_TO_COMPRESS {
+        None
+    } else {
+        let level = client_dpm.compress_level;
+        let stream = &mut streams.tight[stream_id];
+        match stream {
+            Some((_, stream_level)) if *stream_level == level => {}
+            _ => {
+                // Client should reset the stream if compression level changes.
+                if stream.is_some() {
+                    control |= 1 << stream_id;
+                }
+                *stream = Some((Compress::new(Compression::new(level as u32), true), level));
+            }
+        }
+        let (compress, _) = stream.as_mut().unwrap();
+        Some(zlib_compress(compress, &data)?)
+    };
+
+    buf.push(control);
+    if !palette.is_empty() {
+        buf.push(TIGHT_FILTER_PALETTE);
+        buf.push((palette.len() - 1) as u8);
+        for &color in palette {
+            write_tpixel(color, client_dpm, buf);
+        }
+    }
+    match zlib_data {
+        Some(zlib_data) => {
+            write_compact_len(zlib_data.len(), buf);
+            buf.extend_from_slice(&zlib_data);
+        }
+        None => buf.append(&mut data),
+    }
+    Ok(())
+}
+
+/// Compress the rectangle by jpeg, it's used for photographic area.
+///
+/// # Arguments
+///
+/// * `pixels` - pixels of rectangle.
+/// * `sub_rect` - area of rectangle.
+/// * `quality` - jpeg quality.
+/// * `buf` - send buffer.
+fn tight_compress_jpeg(
+    pixels: &[u32],
+    sub_rect: &Rectangle,
+    quality: u8,
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let mut rgb: Vec<u8> = Vec::with_capacity(pixels.len() * 3);
+    for value in pixels {
+        rgb.push((value >> 16) as u8);
+        rgb.push((value >> 8) as u8);
+        rgb.push(*value as u8);
+    }
+    let mut jpeg_data: Vec<u8> = Vec::new();
+    Encoder::new(&mut jpeg_data, quality)
+        .encode(&rgb, sub_rect.w as u16, sub_rect.h as u16, ColorType::Rgb)
+        .map_err(|e| anyhow!("Failed to compress jpeg: {}", e))?;
+
+    buf.push(TIGHT_JPEG);
+    write_compact_len(jpeg_data.len(), buf);
+    buf.append(&mut jpeg_data);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tight_send_framebuffer_update;
+    use crate::{
+        pixman::{create_pixman_image, PixelFormat},
+        vnc::{
+            client_io::{DisplayMode, Rectangle, ENCODING_TIGHT},
+            encoding::{
+                test_hextile_image_data::{IMAGE_DATA_SINGLE_PIXEL, IMAGE_DATA_TWO_PIXEL},
+                ZlibStreams,
+            },
+        },
+    };
+    use flate2::{Decompress, FlushDecompress};
+    use util::pixman::pixman_format_code_t;
+
+    fn tight_encode_image(
+        image_data: &[u8],
+        width: i32,
+        height: i32,
+        quality_level: Option<u8>,
+    ) -> Vec<u8> {
+        let mut pf = PixelFormat::default();
+        pf.init_pixelformat();
+        let mut client_dpm = DisplayMode::new(ENCODING_TIGHT, false, false, pf);
+        client_dpm.quality_level = quality_level;
+        tight_encode_client_image(image_data, width, height, &client_dpm)
+    }
+
+    fn tight_encode_client_image(
+        image_data: &[u8],
+        width: i32,
+        height: i32,
+        client_dpm: &DisplayMode,
+    ) -> Vec<u8> {
+        // Pixels of image must be aligned.
+        let mut pixels: Vec<u32> = image_data
+            .chunks(4)
+            .map(|p| u32::from_ne_bytes([p[0], p[1], p[2], p[3]]))
+            .collect();
+        let image = create_pixman_image(
+            pixman_format_code_t::PIXMAN_x8r8g8b8,
+            width,
+            height,
+            pixels.as_mut_ptr(),
+            width * 4,
+        );
+        let rect = Rectangle::new(0, 0, width, height);
+        let mut streams = ZlibStreams::default();
+        let mut buf: Vec<u8> = Vec::new();
+        let n = tight_send_framebuffer_update(image, &rect, client_dpm, &mut streams, &mut buf)
+            .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(buf[8..12], ENCODING_TIGHT.to_be_bytes());
+        buf[12..].to_vec()
+    }
+
+    #[test]
+    fn test_tight_send_framebuffer_fill() {
+        let data = tight_encode_image(&IMAGE_DATA_SINGLE_PIXEL, 32, 32, None);
+        assert_eq!(data, [0x80, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_tight_send_framebuffer_client_format() {
+        let image_data: Vec<u8> = [0x33, 0x22, 0x11, 0x00].repeat(16 * 16);
+
+        // Blue is at the lowest byte of client pixel, TPIXEL is still red, green and blue.
+        let mut pf = PixelFormat::default();
+        pf.init_pixelformat();
+        pf.red.set_color_info(0, 0xff);
+        pf.green.set_color_info(8, 0xff);
+        pf.blue.set_color_info(16, 0xff);
+        let client_dpm = DisplayMode::new(ENCODING_TIGHT, false, true, pf);
+        let data = tight_encode_client_image(&image_data, 16, 16, &client_dpm);
+        assert_eq!(data, [0x80, 0x11, 0x22, 0x33]);
+
+        // 16 bits pixel of RGB565 in little endian.
+        let mut pf = PixelFormat::default();
+        pf.init_pixelformat();
+        pf.red.set_color_info(11, 0x1f);
+        pf.green.set_color_info(5, 0x3f);
+        pf.blue.set_color_info(0, 0x1f);
+        pf.pixel_bits = 16;
+        pf.pixel_bytes = 2;
+        pf.depth = 16;
+        let client_dpm = DisplayMode::new(ENCODING_TIGHT, false, true, pf);
+        let data = tight_encode_client_image(&image_data, 16, 16, &client_dpm);
+        assert_eq!(data, [0x80, 0x06, 0x11]);
+    }
+
+    #[test]
+    fn test_tight_send_framebuffer_mono() {
+        let data = tight_encode_image(&IMAGE_DATA_TWO_PIXEL, 40, 40, None);
+        // Mono stream with palette filter, and two colors in palette.
+        assert_eq!(
+            data[..9],
+            [0x50, 0x01, 0x01, 0x00, 0x00, 0x00, 0xaa, 0xaa, 0xaa]
+        );
+
+        // Compact length of compressed data.
+        let mut len = (data[9] & 0x7f) as usize;
+        let mut offset = 10;
+        if data[9] & 0x80 != 0 {
+            len |= (data[10] as usize) << 7;
+            offset = 11;
+        }
+        assert_eq!(data.len(), offset + len);
+
+        let mut decompress = Decompress::new(true);
+        let mut bitmap = Vec::with_capacity(400);
+        decompress
+            .decompress_vec(&data[offset..], &mut bitmap, FlushDecompress::Sync)
+            .unwrap();
+        // 40 pixels in each row take 5 bytes.
+        assert_eq!(bitmap.len(), 200);
+        for (i, pixel) in IMAGE_DATA_TWO_PIXEL.chunks(4).enumerate() {
+            let bit = bitmap[i / 8] & (0x80 >> (i % 8)) != 0;
+            assert_eq!(bit, pixel[0] == 0xaa);
+        }
+    }
+
+    #[test]
+    fn test_tight_send_framebuffer_jpeg() {
+        // Gradient image has too many colors for palette.
+        let mut image_data: Vec<u8> = Vec::new();
+        for j in 0..64_u32 {
+            for i in 0..64_u32 {
+                image_data
+                    .extend_from_slice(&((i * 4) | ((j * 4) << 8) | ((i + j) << 16)).to_le_bytes());
+            }
+        }
+        let data = tight_encode_image(&image_data, 64, 64, Some(9));
+        assert_eq!(data[0], 0x90);
+        let mut len = (data[1] & 0x7f) as usize;
+        let mut offset = 2;
+        if data[1] & 0x80 != 0 {
+            len |= ((data[2] & 0x7f) as usize) << 7;
+            offset = 3;
+            if data[2] & 0x80 != 0 {
+                len |= (data[3] as usize) << 14;
+                offset = 4;
+            }
+        }
+        assert_eq!(data.len(), offset + len);
+        // Start of image marker of jpeg.
+        assert_eq!(data[offset..offset + 2], [0xff, 0xd8]);
+
+        // Full color is compressed by zlib without jpeg.
+        let data = tight_encode_image(&image_data, 64, 64, None);
+        assert_eq!(data[0], 0x00);
+    }
+}
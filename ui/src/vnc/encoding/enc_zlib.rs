@@ -0,0 +1,99 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use crate::vnc::{
+    client_io::{DisplayMode, Rectangle, ENCODING_ZLIB},
+    encoding::zlib_compress,
+    framebuffer_update, raw_send_framebuffer_update,
+};
+use anyhow::Result;
+use flate2::Compress;
+use util::pixman::pixman_image_t;
+
+/// Compress raw pixel data by the zlib stream of client before sending.
+///
+/// # Arguments
+///
+/// * `image` - pointer to the data need to be send.
+/// * `rect` - dirty area of image.
+/// * `client_dpm` - Output mode information of client display.
+/// * `stream` - zlib stream of zlib encoding.
+/// * `buf` - send buffer.
+pub fn zlib_send_framebuffer_update(
+    image: *mut pixman_image_t,
+    rect: &Rectangle,
+    client_dpm: &DisplayMode,
+    stream: &mut Compress,
+    buf: &mut Vec<u8>,
+) -> Result<i32> {
+    let mut data: Vec<u8> = Vec::new();
+    raw_send_framebuffer_update(image, rect, client_dpm, &mut data);
+    let zlib_data = zlib_compress(stream, &data)?;
+
+    framebuffer_update(rect.x, rect.y, rect.w, rect.h, ENCODING_ZLIB, buf);
+    buf.append(&mut (zlib_data.len() as u32).to_be_bytes().to_vec());
+    buf.extend_from_slice(&zlib_data);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::zlib_send_framebuffer_update;
+    use crate::{
+        pixman::{create_pixman_image, PixelFormat},
+        vnc::{
+            client_io::{DisplayMode, Rectangle, ENCODING_ZLIB},
+            encoding::test_hextile_image_data::{IMAGE_DATA_MULTI_PIXELS, IMAGE_DATA_TWO_PIXEL},
+        },
+    };
+    use flate2::{Compress, Compression, Decompress, FlushDecompress};
+    use util::pixman::pixman_format_code_t;
+
+    #[test]
+    fn test_zlib_send_framebuffer_persistent_stream() {
+        let mut pf = PixelFormat::default();
+        pf.init_pixelformat();
+        let client_dpm = DisplayMode::new(ENCODING_ZLIB, false, false, pf);
+        let rect = Rectangle::new(0, 0, 40, 40);
+        let mut stream = Compress::new(Compression::default(), true);
+        let mut decompress = Decompress::new(true);
+
+        // The second update is decompressed with the dictionary of the first one.
+        for image_data in [IMAGE_DATA_TWO_PIXEL, IMAGE_DATA_MULTI_PIXELS] {
+            // Pixels of image must be aligned.
+            let mut pixels: Vec<u32> = image_data
+                .chunks(4)
+                .map(|p| u32::from_ne_bytes([p[0], p[1], p[2], p[3]]))
+                .collect();
+            let image = create_pixman_image(
+                pixman_format_code_t::PIXMAN_x8r8g8b8,
+                40,
+                40,
+                pixels.as_mut_ptr(),
+                160,
+            );
+            let mut buf: Vec<u8> = Vec::new();
+            let n = zlib_send_framebuffer_update(image, &rect, &client_dpm, &mut stream, &mut buf)
+                .unwrap();
+            assert_eq!(n, 1);
+            assert_eq!(buf[8..12], ENCODING_ZLIB.to_be_bytes());
+            let len = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]) as usize;
+            assert_eq!(buf.len(), 16 + len);
+
+            let mut data = Vec::with_capacity(image_data.len());
+            decompress
+                .decompress_vec(&buf[16..], &mut data, FlushDecompress::Sync)
+                .unwrap();
+            assert_eq!(data, image_data);
+        }
+    }
+}
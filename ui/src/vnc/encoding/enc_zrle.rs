@@ -0,0 +1,342 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use crate::{
+    pixman::bytes_per_pixel,
+    vnc::{
+        client_io::{DisplayMode, Rectangle, ENCODING_ZRLE},
+        encoding::{get_rect_pixels, zlib_compress},
+        framebuffer_update, round_up_div, write_pixel,
+    },
+};
+use anyhow::Result;
+use flate2::Compress;
+use std::{cmp, collections::HashMap, ops::Range};
+use util::pixman::pixman_image_t;
+
+/// Size of tile.
+const ZRLE_TILE_SIZE: usize = 64;
+/// SubEncoding type of zrle.
+const ZRLE_RAW: u8 = 0;
+const ZRLE_SOLID: u8 = 1;
+const ZRLE_PLAIN_RLE: u8 = 128;
+/// Max number of colors in packed palette.
+const ZRLE_MAX_PACKED_PALETTE: usize = 16;
+/// Max number of colors in palette RLE.
+const ZRLE_MAX_PALETTE: usize = 127;
+
+/// Compress data by zrle algorithm before sending.
+/// Rectangles are split up into 64 * 64 tiles, and all tiles
+/// are compressed with the zlib stream of client.
+///
+/// # Arguments
+///
+/// * `image` - pointer to the data need to be send.
+/// * `rect` - dirty area of image.
+/// * `client_dpm` - Output mode information of client display.
+/// * `stream` - zlib stream of zrle encoding.
+/// * `buf` - send buffer.
+pub fn zrle_send_framebuffer_update(
+    image: *mut pixman_image_t,
+    rect: &Rectangle,
+    client_dpm: &DisplayMode,
+    stream: &mut Compress,
+    buf: &mut Vec<u8>,
+) -> Result<i32> {
+    let cpixel = cpixel_range(client_dpm);
+    let mut data: Vec<u8> = Vec::new();
+    for j in (0..rect.h).step_by(ZRLE_TILE_SIZE) {
+        for i in (0..rect.w).step_by(ZRLE_TILE_SIZE) {
+            let sub_rect = Rectangle::new(
+                rect.x + i,
+                rect.y + j,
+                cmp::min(ZRLE_TILE_SIZE as i32, rect.w - i),
+                cmp::min(ZRLE_TILE_SIZE as i32, rect.h - j),
+            );
+            let pixels = get_rect_pixels(image, &sub_rect);
+            compress_each_tile(&pixels, &sub_rect, client_dpm, &cpixel, &mut data);
+        }
+    }
+
+    let zlib_data = zlib_compress(stream, &data)?;
+    framebuffer_update(rect.x, rect.y, rect.w, rect.h, ENCODING_ZRLE, buf);
+    buf.append(&mut (zlib_data.len() as u32).to_be_bytes().to_vec());
+    buf.extend_from_slice(&zlib_data);
+    Ok(1)
+}
+
+/// Range of bytes in pixel which make up the CPIXEL. Pixel of 32 bits with
+/// depth of 24 or less is sent in 3 bytes, if all the color bits fit in the
+/// least or most significant 3 bytes.
+fn cpixel_range(client_dpm: &DisplayMode) -> Range<usize> {
+    let (pixel_bytes, big_endian) = if client_dpm.convert {
+        (client_dpm.pf.pixel_bytes as usize, client_dpm.client_be)
+    } else {
+        (bytes_per_pixel(), cfg!(target_endian = "big"))
+    };
+    let pf = &client_dpm.pf;
+    if pixel_bytes != 4 || pf.depth > 24 {
+        return 0..pixel_bytes;
+    }
+
+    let mask = ((pf.red.max as u32) << pf.red.shift)
+        | ((pf.green.max as u32) << pf.green.shift)
+        | ((pf.blue.max as u32) << pf.blue.shift);
+    if mask & 0xff00_0000 == 0 {
+        if big_endian {
+            1..4
+        } else {
+            0..3
+        }
+    } else if mask & 0x0000_00ff == 0 {
+        if big_endian {
+            0..3
+        } else {
+            1..4
+        }
+    } else {
+        0..4
+    }
+}
+
+/// Write the CPIXEL of color.
+fn write_cpixel(color: u32, client_dpm: &DisplayMode, cpixel: &Range<usize>, buf: &mut Vec<u8>) {
+    let mut pixel: Vec<u8> = Vec::with_capacity(4);
+    write_pixel(
+        color.to_ne_bytes().as_ptr() as *mut u8,
+        bytes_per_pixel(),
+        client_dpm,
+        &mut pixel,
+    );
+    buf.extend_from_slice(&pixel[cpixel.clone()]);
+}
+
+/// Write the length of run, every 255 means the length continues in next byte.
+fn write_run_length(len: usize, buf: &mut Vec<u8>) {
+    let mut len = len - 1;
+    while len >= 255 {
+        buf.push(255);
+        len -= 255;
+    }
+    buf.push(len as u8);
+}
+
+/// Number of bytes of the length of run.
+fn run_length_bytes(len: usize) -> usize {
+    (len - 1) / 255 + 1
+}
+
+/// Compress each tile by choosing the smallest subencoding.
+///
+/// # Arguments
+///
+/// * `pixels` - pixels of tile.
+/// * `sub_rect` - area of tile.
+/// * `client_dpm` - Output mode information of client display.
+/// * `cpixel` - range of bytes in pixel which make up the CPIXEL.
+/// * `buf` - send buffer.
+fn compress_each_tile(
+    pixels: &[u32],
+    sub_rect: &Rectangle,
+    client_dpm: &DisplayMode,
+    cpixel: &Range<usize>,
+    buf: &mut Vec<u8>,
+) {
+    let cpixel_bytes = cpixel.len();
+    // Colors of palette, stop counting if there are too many colors.
+    let mut palette: Vec<u32> = Vec::new();
+    let mut index: HashMap<u32, u8> = HashMap::new();
+    // Runs of the same color.
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+    for &value in pixels {
+        match runs.last_mut() {
+            Some((color, len)) if *color == value => *len += 1,
+            _ => runs.push((value, 1)),
+        }
+        if palette.len() <= ZRLE_MAX_PALETTE && !index.contains_key(&value) {
+            index.insert(value, palette.len() as u8);
+            palette.push(value);
+        }
+    }
+
+    if palette.len() == 1 {
+        buf.push(ZRLE_SOLID);
+        write_cpixel(palette[0], client_dpm, cpixel, buf);
+        return;
+    }
+
+    let n_colors = palette.len();
+    let bits = match n_colors {
+        2 => 1,
+        3..=4 => 2,
+        _ => 4,
+    };
+    let row_bytes = round_up_div((sub_rect.w as usize * bits) as u64, 8) as usize;
+    let raw_size = pixels.len() * cpixel_bytes;
+    let plain_rle_size = runs
+        .iter()
+        .map(|(_, len)| cpixel_bytes + run_length_bytes(*len))
+        .sum();
+    let packed_size = if n_colors <= ZRLE_MAX_PACKED_PALETTE {
+        n_colors * cpixel_bytes + sub_rect.h as usize * row_bytes
+    } else {
+        usize::MAX
+    };
+    let palette_rle_size = if n_colors <= ZRLE_MAX_PALETTE {
+        n_colors * cpixel_bytes
+            + runs
+                .iter()
+                .map(|(_, len)| match len {
+                    1 => 1,
+                    _ => 1 + run_length_bytes(*len),
+                })
+                .sum::<usize>()
+    } else {
+        usize::MAX
+    };
+
+    let min_size = *[raw_size, packed_size, palette_rle_size, plain_rle_size]
+        .iter()
+        .min()
+        .unwrap();
+    if min_size == raw_size {
+        buf.push(ZRLE_RAW);
+        for &value in pixels {
+            write_cpixel(value, client_dpm, cpixel, buf);
+        }
+    } else if min_size == packed_size {
+        buf.push(n_colors as u8);
+        for &color in &palette {
+            write_cpixel(color, client_dpm, cpixel, buf);
+        }
+        for row in pixels.chunks(sub_rect.w as usize) {
+            let mut byte: u8 = 0;
+            let mut n_bits = 0;
+            for value in row {
+                byte = byte << bits | index[value];
+                n_bits += bits;
+                if n_bits == 8 {
+                    buf.push(byte);
+                    byte = 0;
+                    n_bits = 0;
+                }
+            }
+            if n_bits != 0 {
+                buf.push(byte << (8 - n_bits));
+            }
+        }
+    } else if min_size == palette_rle_size {
+        buf.push(ZRLE_PLAIN_RLE + n_colors as u8);
+        for &color in &palette {
+            write_cpixel(color, client_dpm, cpixel, buf);
+        }
+        for (color, len) in runs {
+            if len == 1 {
+                buf.push(index[&color]);
+            } else {
+                buf.push(index[&color] | 0x80);
+                write_run_length(len, buf);
+            }
+        }
+    } else {
+        buf.push(ZRLE_PLAIN_RLE);
+        for (color, len) in runs {
+            write_cpixel(color, client_dpm, cpixel, buf);
+            write_run_length(len, buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::zrle_send_framebuffer_update;
+    use crate::{
+        pixman::{create_pixman_image, PixelFormat},
+        vnc::{
+            client_io::{DisplayMode, Rectangle, ENCODING_ZRLE},
+            encoding::{
+                test_hextile_image_data::{
+                    IMAGE_DATA_MULTI_PIXELS, IMAGE_DATA_SINGLE_PIXEL, IMAGE_DATA_TWO_PIXEL,
+                },
+                test_zrle_image_data::{
+                    TARGET_DATA_MULTI_PIXELS, TARGET_DATA_SINGLE_PIXEL, TARGET_DATA_TWO_PIXEL,
+                },
+            },
+        },
+    };
+    use flate2::{Compress, Compression, Decompress, FlushDecompress};
+    use util::pixman::pixman_format_code_t;
+
+    fn color_init() -> PixelFormat {
+        let mut pf = PixelFormat::default();
+        pf.red.set_color_info(16, 255);
+        pf.green.set_color_info(8, 255);
+        pf.blue.set_color_info(0, 255);
+        pf.pixel_bits = 32;
+        pf.pixel_bytes = 4;
+        pf.depth = 24;
+        pf
+    }
+
+    fn zrle_encode_image(image_data: &[u8], width: i32, height: i32) -> Vec<u8> {
+        let pf = color_init();
+        let client_dpm = DisplayMode::new(ENCODING_ZRLE, false, false, pf);
+        // Pixels of image must be aligned.
+        let mut pixels: Vec<u32> = image_data
+            .chunks(4)
+            .map(|p| u32::from_ne_bytes([p[0], p[1], p[2], p[3]]))
+            .collect();
+        let image = create_pixman_image(
+            pixman_format_code_t::PIXMAN_x8r8g8b8,
+            width,
+            height,
+            pixels.as_mut_ptr(),
+            width * 4,
+        );
+        let rect = Rectangle::new(0, 0, width, height);
+        let mut stream = Compress::new(Compression::default(), true);
+        let mut buf: Vec<u8> = Vec::new();
+        let n =
+            zrle_send_framebuffer_update(image, &rect, &client_dpm, &mut stream, &mut buf).unwrap();
+        assert_eq!(n, 1);
+
+        // Rectangle header is followed by the length of zlib data.
+        assert_eq!(buf[8..12], ENCODING_ZRLE.to_be_bytes());
+        let len = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]) as usize;
+        assert_eq!(buf.len(), 16 + len);
+
+        let mut decompress = Decompress::new(true);
+        let mut data = Vec::with_capacity(image_data.len() * 2);
+        decompress
+            .decompress_vec(&buf[16..], &mut data, FlushDecompress::Sync)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_zrle_send_framebuffer_single_pixel() {
+        let data = zrle_encode_image(&IMAGE_DATA_SINGLE_PIXEL, 32, 32);
+        assert_eq!(data, TARGET_DATA_SINGLE_PIXEL);
+    }
+
+    #[test]
+    fn test_zrle_send_framebuffer_two_pixels() {
+        let data = zrle_encode_image(&IMAGE_DATA_TWO_PIXEL, 40, 40);
+        assert_eq!(data, TARGET_DATA_TWO_PIXEL);
+    }
+
+    #[test]
+    fn test_zrle_send_framebuffer_multi_pixels() {
+        let data = zrle_encode_image(&IMAGE_DATA_MULTI_PIXELS, 40, 40);
+        assert_eq!(data, TARGET_DATA_MULTI_PIXELS);
+    }
+}
@@ -11,5 +11,106 @@
 // See the Mulan PSL v2 for more details.
 
 pub mod enc_hextile;
+pub mod enc_tight;
+pub mod enc_zlib;
+pub mod enc_zrle;
 #[cfg(test)]
 mod test_hextile_image_data;
+#[cfg(test)]
+mod test_zrle_image_data;
+
+use crate::{
+    pixman::{bytes_per_pixel, get_image_data, get_image_stride},
+    vnc::client_io::Rectangle,
+};
+use anyhow::{anyhow, Result};
+use flate2::{Compress, Compression, FlushCompress};
+use util::pixman::pixman_image_t;
+
+/// Number of zlib streams used by tight encoding.
+pub const TIGHT_STREAM_NUM: usize = 4;
+/// Mask of color bits in pixel of server image.
+const PIXEL_COLOR_MASK: u32 = 0x00ff_ffff;
+
+/// Zlib streams of a vnc client. The client decompresses all the data of an
+/// encoding with the same stream, so streams are kept during the connection.
+#[derive(Default)]
+pub struct ZlibStreams {
+    /// Stream of zlib encoding.
+    pub zlib: Option<Compress>,
+    /// Stream of zrle encoding.
+    pub zrle: Option<Compress>,
+    /// Streams of tight encoding, with the compression level of each stream.
+    pub tight: [Option<(Compress, u8)>; TIGHT_STREAM_NUM],
+    /// Streams are broken if encoding fails after data has been fed to them,
+    /// the client can't decompress later data, so raw encoding is used instead.
+    pub broken: bool,
+}
+
+impl ZlibStreams {
+    /// Get the stream, it's created with the compression level when used for the first time.
+    pub fn get_stream(stream: &mut Option<Compress>, level: u8) -> &mut Compress {
+        stream.get_or_insert_with(|| Compress::new(Compression::new(level as u32), true))
+    }
+
+    /// Total bytes fed to and produced by all streams, used to check whether
+    /// streams are advanced.
+    pub fn total_bytes(&self) -> u64 {
+        self.zlib
+            .iter()
+            .chain(self.zrle.iter())
+            .chain(self.tight.iter().flatten().map(|(stream, _)| stream))
+            .map(|stream| stream.total_in() + stream.total_out())
+            .sum()
+    }
+}
+
+/// Compress data by the stream. The output is flushed at the end, so that the
+/// client can decompress it without any later data.
+///
+/// # Arguments
+///
+/// * `stream` - zlib stream.
+/// * `input` - data to be compressed.
+pub fn zlib_compress(stream: &mut Compress, input: &[u8]) -> Result<Vec<u8>> {
+    let mut output: Vec<u8> = Vec::with_capacity(input.len() / 2 + 64);
+    let mut offset = 0;
+    loop {
+        if output.len() == output.capacity() {
+            output.reserve(output.capacity());
+        }
+        let total_in = stream.total_in();
+        stream
+            .compress_vec(&input[offset..], &mut output, FlushCompress::Sync)
+            .map_err(|e| anyhow!("Failed to compress data: {}", e))?;
+        offset += (stream.total_in() - total_in) as usize;
+        // Flushing is finished if there is space left in output buffer.
+        if offset == input.len() && output.len() < output.capacity() {
+            break;
+        }
+    }
+    Ok(output)
+}
+
+/// Get pixels of the rectangle in image, the unused alpha byte is cleared.
+///
+/// # Arguments
+///
+/// * `image` - pointer to the image.
+/// * `rect` - area of image.
+pub fn get_rect_pixels(image: *mut pixman_image_t, rect: &Rectangle) -> Vec<u32> {
+    let stride = get_image_stride(image);
+    let data_ptr = get_image_data(image) as *mut u8;
+    let mut pixels = Vec::with_capacity((rect.w * rect.h) as usize);
+    for j in 0..rect.h {
+        let ptr = (data_ptr as usize
+            + ((rect.y + j) * stride) as usize
+            + rect.x as usize * bytes_per_pixel()) as *mut u32;
+        for i in 0..rect.w {
+            // SAFETY: it can be ensure the raw pointer will not exceed the range.
+            let value = unsafe { *ptr.add(i as usize) };
+            pixels.push(value & PIXEL_COLOR_MASK);
+        }
+    }
+    pixels
+}
@@ -0,0 +1,64 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+/// ZRLE data before compression: one solid tile.
+/// Width of image = 32
+/// Height of image = 32
+/// Total length is 4 Byte.
+pub const TARGET_DATA_SINGLE_PIXEL: [u8; 4] = [0x01, 0x00, 0x00, 0x00];
+
+/// ZRLE data before compression: one tile with two colors.
+/// Width of image = 40
+/// Height of image = 40
+/// Total length is 207 Byte.
+pub const TARGET_DATA_TWO_PIXEL: [u8; 207] = [
+    0x02, 0x00, 0x00, 0x00, 0xaa, 0xaa, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xff, 0x00, 0x00, 0xe0, 0x00, 0xdb, 0x00, 0x00, 0x60, 0x00, 0x99, 0x00, 0x00, 0x60, 0x00,
+    0x18, 0x78, 0x7c, 0x66, 0x7c, 0x18, 0x0c, 0xc6, 0x6c, 0xc6, 0x18, 0x7c, 0x60, 0x78, 0x60, 0x18,
+    0xcc, 0x38, 0x78, 0x38, 0x18, 0xcc, 0x0c, 0x6c, 0x0c, 0x18, 0xcc, 0xc6, 0x66, 0xc6, 0x3c, 0x76,
+    0x7c, 0xe6, 0x7c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x3c, 0x00, 0x00, 0x0c, 0x00, 0x66, 0x00, 0x00, 0x18, 0xc2, 0xc2, 0x00, 0x00, 0x30,
+    0xc6, 0xc0, 0xdc, 0xcc, 0x30, 0x0c, 0xc0, 0x66, 0xcc, 0x30, 0x18, 0xc0, 0x66, 0xcc, 0x30, 0x30,
+    0xc0, 0x66, 0xcc, 0x30, 0x60, 0xc2, 0x66, 0xcc, 0x30, 0xc6, 0x66, 0x66, 0xcc, 0x18, 0x86, 0x3c,
+    0x7c, 0x76, 0x0c, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0xf0,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xc3, 0x18, 0xfc, 0x00, 0xc3, 0xe7, 0x18, 0x66, 0x00, 0xe7, 0xff, 0x00, 0x66, 0x00, 0xff,
+    0xff, 0x38, 0x66, 0x00, 0xff, 0xdb, 0x18, 0x7c, 0x00, 0xdb, 0xc3, 0x18, 0x66, 0x00, 0xc3,
+];
+
+/// ZRLE data before compression: one tile with multiple colors.
+/// Width of image = 40
+/// Height of image = 40
+/// Total length is 305 Byte.
+pub const TARGET_DATA_MULTI_PIXELS: [u8; 305] = [
+    0x84, 0x8a, 0x72, 0x7b, 0x8a, 0x71, 0x7b, 0x8a, 0x71, 0x7a, 0x8a, 0x70, 0x7a, 0x80, 0x05, 0x81,
+    0x07, 0x82, 0x13, 0x83, 0x05, 0x80, 0x05, 0x81, 0x07, 0x82, 0x13, 0x83, 0x05, 0x80, 0x05, 0x81,
+    0x07, 0x82, 0x13, 0x83, 0x05, 0x80, 0x05, 0x81, 0x07, 0x82, 0x13, 0x83, 0x05, 0x80, 0x05, 0x81,
+    0x07, 0x82, 0x13, 0x83, 0x05, 0x80, 0x05, 0x81, 0x07, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x13, 0x83, 0x05, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x0f, 0x83, 0x09, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x0f, 0x83, 0x09, 0x80, 0x01, 0x81,
+    0x0b, 0x82, 0x0f, 0x83, 0x09, 0x80, 0x01, 0x81, 0x0b, 0x82, 0x0f, 0x83, 0x09, 0x80, 0x01, 0x82,
+    0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1b, 0x83, 0x09, 0x80,
+    0x01, 0x82, 0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1b, 0x83,
+    0x09, 0x80, 0x01, 0x82, 0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1b, 0x83, 0x09, 0x80, 0x01, 0x82,
+    0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1b, 0x83, 0x09, 0x80,
+    0x01, 0x82, 0x1b, 0x83, 0x09, 0x80, 0x01, 0x82, 0x1f, 0x83, 0x05, 0x80, 0x01, 0x82, 0x1f, 0x83,
+    0x05,
+];
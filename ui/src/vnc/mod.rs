@@ -10,15 +10,19 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+pub mod audio;
 pub mod auth_sasl;
 pub mod auth_vencrypt;
+pub mod auth_vnc;
 pub mod client_io;
 pub mod encoding;
 pub mod server_io;
+pub mod websocket;
 
 use crate::{
+    clipboard::{register_clipboard_frontend, ClipboardFrontendOpts},
     console::{
-        graphic_hardware_update, register_display, DisplayChangeListener,
+        get_console_by_name, graphic_hardware_update, register_display, DisplayChangeListener,
         DisplayChangeListenerOperations, DisplayMouse, DisplaySurface,
         DISPLAY_UPDATE_INTERVAL_DEFAULT, DISPLAY_UPDATE_INTERVAL_INC, DISPLAY_UPDATE_INTERVAL_MAX,
     },
@@ -29,17 +33,26 @@ use crate::{
         get_image_width, ref_pixman_image, unref_pixman_image,
     },
     vnc::{
+        audio::audio_data_msg,
+        auth_sasl::AuthState,
+        auth_vnc::{vnc_expire_password, vnc_set_password, VncAccess},
         client_io::{
-            desktop_resize, display_cursor_define, get_rects, set_color_depth, vnc_flush,
-            vnc_update_output_throttle, vnc_write, DisplayMode, Rectangle, ServerMsg,
-            ENCODING_HEXTILE, ENCODING_RAW,
+            desktop_resize, display_cursor_define, get_rects, set_color_depth,
+            vnc_clipboard_update, vnc_flush, vnc_update_output_throttle, vnc_write, DisplayMode,
+            Rectangle, ServerMsg, VncFeatures, ENCODING_HEXTILE, ENCODING_RAW, ENCODING_TIGHT,
+            ENCODING_ZLIB, ENCODING_ZRLE, ENCODING_ZYWRLE,
         },
-        encoding::enc_hextile::hextile_send_framebuffer_update,
-        server_io::{make_server_config, VncConnHandler, VncServer, VncSurface},
+        encoding::{
+            enc_hextile::hextile_send_framebuffer_update, enc_tight::tight_send_framebuffer_update,
+            enc_zlib::zlib_send_framebuffer_update, enc_zrle::zrle_send_framebuffer_update,
+            ZlibStreams,
+        },
+        server_io::{make_server_config, VncConnHandler, VncServer, VncSharePolicy, VncSurface},
     },
 };
 use anyhow::{anyhow, Result};
 use core::time;
+use log::error;
 use machine_manager::{
     config::{ObjectConfig, VncConfig},
     event_loop::EventLoop,
@@ -87,15 +100,19 @@ pub const fn round_up(n: u64, d: u64) -> u64 {
 }
 
 #[derive(Default)]
-pub struct VncInterface {}
+pub struct VncInterface {
+    /// Index of the vnc server in VNC_SERVERS.
+    server_id: usize,
+}
+
 impl DisplayChangeListenerOperations for VncInterface {
     /// Update guest_image
     /// Send a resize command to the client based on whether the image size has changed
     fn dpy_switch(&self, surface: &DisplaySurface) -> Result<()> {
-        if VNC_SERVERS.lock().unwrap().is_empty() {
-            return Ok(());
-        }
-        let server = VNC_SERVERS.lock().unwrap()[0].clone();
+        let server = match get_vnc_server(self.server_id) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
         let mut locked_vnc_surface = server.vnc_surface.lock().unwrap();
         let need_resize = check_surface(&mut locked_vnc_surface, surface);
         unref_pixman_image(locked_vnc_surface.guest_image);
@@ -151,10 +168,10 @@ impl DisplayChangeListenerOperations for VncInterface {
 
     /// Refresh server_image to guest_image.
     fn dpy_refresh(&self, dcl: &Arc<Mutex<DisplayChangeListener>>) -> Result<()> {
-        if VNC_SERVERS.lock().unwrap().is_empty() {
-            return Ok(());
-        }
-        let server = VNC_SERVERS.lock().unwrap()[0].clone();
+        let server = match get_vnc_server(self.server_id) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
         if server.client_handlers.lock().unwrap().is_empty() {
             return Ok(());
         }
@@ -163,7 +180,11 @@ impl DisplayChangeListenerOperations for VncInterface {
 
         // Update refresh interval.
         let mut update_interval = dcl.lock().unwrap().update_interval;
-        let dirty_num = server.vnc_surface.lock().unwrap().update_server_image()?;
+        let dirty_num = server
+            .vnc_surface
+            .lock()
+            .unwrap()
+            .update_server_image(&server)?;
         if dirty_num != 0 {
             update_interval /= 2;
             if update_interval < DISPLAY_UPDATE_INTERVAL_DEFAULT {
@@ -185,10 +206,10 @@ impl DisplayChangeListenerOperations for VncInterface {
     }
 
     fn dpy_image_update(&self, x: i32, y: i32, w: i32, h: i32) -> Result<()> {
-        if VNC_SERVERS.lock().unwrap().is_empty() {
-            return Ok(());
-        }
-        let server = VNC_SERVERS.lock().unwrap()[0].clone();
+        let server = match get_vnc_server(self.server_id) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
         let mut locked_vnc_surface = server.vnc_surface.lock().unwrap();
         let g_w = get_image_width(locked_vnc_surface.guest_image);
         let g_h = get_image_height(locked_vnc_surface.guest_image);
@@ -206,10 +227,10 @@ impl DisplayChangeListenerOperations for VncInterface {
     }
 
     fn dpy_cursor_update(&self, cursor: &DisplayMouse) -> Result<()> {
-        if VNC_SERVERS.lock().unwrap().is_empty() {
-            return Ok(());
-        }
-        let server = VNC_SERVERS.lock().unwrap()[0].clone();
+        let server = match get_vnc_server(self.server_id) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
         let width = cursor.width as u64;
         let height = cursor.height as u64;
         let bpl = round_up_div(width, BIT_PER_BYTE as u64);
@@ -254,6 +275,23 @@ impl DisplayChangeListenerOperations for VncInterface {
     }
 }
 
+/// Share the clipboard of guest with vnc clients.
+#[derive(Default)]
+struct VncClipboard {}
+
+impl ClipboardFrontendOpts for VncClipboard {
+    fn set_host_clipboard(&mut self, _text: &str) -> Result<()> {
+        let servers = VNC_SERVERS.lock().unwrap().clone();
+        for server in servers {
+            let locked_handlers = server.client_handlers.lock().unwrap();
+            for client in locked_handlers.values() {
+                vnc_clipboard_update(client);
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Initizlization function of vnc
 ///
 /// # Arguments
@@ -265,56 +303,114 @@ pub fn vnc_init(vnc: &Option<VncConfig>, object: &ObjectConfig) -> Result<()> {
         None => return Ok(()),
     };
 
-    let addr = format!("{}:{}", vnc_cfg.ip, vnc_cfg.port);
-    let listener: TcpListener = match TcpListener::bind(addr.as_str()) {
-        Ok(l) => l,
-        Err(e) => {
-            let msg = format!("Bind {} failed {}", addr, e);
-            return Err(anyhow!(VncError::TcpBindFailed(msg)));
+    // Head N is served on the listening port + N.
+    let port = vnc_cfg.port.parse::<u32>()?;
+    let mut listeners = Vec::new();
+    for head in 0..cmp::max(vnc_cfg.heads, 1) {
+        listeners.push(vnc_listen(&format!("{}:{}", vnc_cfg.ip, port + head))?);
+    }
+    let mut ws_listener = match vnc_cfg.websocket {
+        Some(port) => {
+            if !vnc_cfg.sasl_authz.is_empty() {
+                return Err(anyhow!(VncError::MakeConnectionFailed(
+                    "sasl is not supported for websocket".to_string()
+                )));
+            }
+            Some(vnc_listen(&format!("{}:{}", vnc_cfg.ip, port))?)
         }
+        None => None,
     };
 
-    listener
-        .set_nonblocking(true)
-        .expect("Set noblocking for vnc socket failed");
-
     let mut keysym2keycode: HashMap<u16, u16> = HashMap::new();
     // Mapping ASCII to keycode.
     for &(k, v) in KEYSYM2KEYCODE.iter() {
         keysym2keycode.insert(k, v);
     }
 
-    let vnc_opts = Arc::new(VncInterface::default());
-    let dcl = Arc::new(Mutex::new(DisplayChangeListener::new(None, vnc_opts)));
+    // Share clipboard with vnc clients.
+    register_clipboard_frontend("vnc", Arc::new(Mutex::new(VncClipboard::default())));
 
-    let server = Arc::new(VncServer::new(
-        get_client_image(),
-        keysym2keycode,
-        Some(Arc::downgrade(&dcl)),
-    ));
-
-    // Parameter configuration for VncServeer.
-    make_server_config(&server, vnc_cfg, object)?;
-
-    // Add an VncServer.
-    add_vnc_server(server.clone());
+    for (head, listener) in listeners.into_iter().enumerate() {
+        // The first head follows the active console, and head N shows the
+        // output N of virtio-gpu.
+        let con_id = match head {
+            0 => None,
+            _ => Some(get_head_console(head)?),
+        };
+        let vnc_opts = Arc::new(VncInterface { server_id: head });
+        let dcl = Arc::new(Mutex::new(DisplayChangeListener::new(con_id, vnc_opts)));
+
+        let server = Arc::new(VncServer::new(
+            get_client_image(),
+            keysym2keycode.clone(),
+            Some(Arc::downgrade(&dcl)),
+            VncSharePolicy::from(vnc_cfg.share.as_str()),
+        ));
+
+        // Parameter configuration for VncServeer.
+        make_server_config(&server, vnc_cfg, object)?;
+
+        // Add an VncServer.
+        add_vnc_server(server.clone());
+
+        // Register in display console.
+        register_display(&dcl)?;
+
+        // Register the event to listen for client's connection.
+        let vnc_io = Arc::new(Mutex::new(VncConnHandler::new(
+            listener,
+            server.clone(),
+            false,
+        )));
+
+        // Vnc_thread: a thread to send the framebuffer
+        start_vnc_thread(server.clone())?;
+
+        EventLoop::update_event(EventNotifierHelper::internal_notifiers(vnc_io), None)?;
+        // Websocket clients are served by the first head.
+        if let Some(ws_listener) = ws_listener.take() {
+            let ws_io = Arc::new(Mutex::new(VncConnHandler::new(ws_listener, server, true)));
+            EventLoop::update_event(EventNotifierHelper::internal_notifiers(ws_io), None)?;
+        }
+    }
+    Ok(())
+}
 
-    // Register in display console.
-    register_display(&dcl)?;
+/// Get the id of console displayed by the head.
+fn get_head_console(head: usize) -> Result<usize> {
+    let dev_name = format!("virtio-gpu{}", head);
+    match get_console_by_name(Some(&dev_name)) {
+        Some(con) => Ok(con.lock().unwrap().con_id),
+        None => Err(anyhow!(VncError::MakeConnectionFailed(format!(
+            "no console {} for vnc head {}",
+            dev_name, head
+        )))),
+    }
+}
 
-    // Register the event to listen for client's connection.
-    let vnc_io = Arc::new(Mutex::new(VncConnHandler::new(listener, server)));
+/// Get the vnc server by index.
+fn get_vnc_server(server_id: usize) -> Option<Arc<VncServer>> {
+    VNC_SERVERS.lock().unwrap().get(server_id).cloned()
+}
 
-    // Vnc_thread: a thread to send the framebuffer
-    start_vnc_thread()?;
+/// Bind the listening address for vnc clients.
+fn vnc_listen(addr: &str) -> Result<TcpListener> {
+    let listener: TcpListener = match TcpListener::bind(addr) {
+        Ok(l) => l,
+        Err(e) => {
+            let msg = format!("Bind {} failed {}", addr, e);
+            return Err(anyhow!(VncError::TcpBindFailed(msg)));
+        }
+    };
 
-    EventLoop::update_event(EventNotifierHelper::internal_notifiers(vnc_io), None)?;
-    Ok(())
+    listener
+        .set_nonblocking(true)
+        .expect("Set noblocking for vnc socket failed");
+    Ok(listener)
 }
 
-fn start_vnc_thread() -> Result<()> {
+fn start_vnc_thread(server: Arc<VncServer>) -> Result<()> {
     let interval = DEFAULT_REFRESH_INTERVAL;
-    let server = VNC_SERVERS.lock().unwrap()[0].clone();
     let _handle = thread::Builder::new()
         .name("vnc_worker".to_string())
         .spawn(move || loop {
@@ -342,11 +438,17 @@ fn start_vnc_thread() -> Result<()> {
             for rect in rect_info.rects.iter_mut() {
                 let locked_surface = server.vnc_surface.lock().unwrap();
                 let dpm = rect_info.client.client_dpm.lock().unwrap().clone();
+                let mut locked_streams = rect_info.client.zlib_streams.lock().unwrap();
                 let width = dpm.client_width;
                 let height = dpm.client_height;
                 if check_rect(rect, width, height) {
-                    let n =
-                        send_framebuffer_update(locked_surface.server_image, rect, &dpm, &mut buf);
+                    let n = send_framebuffer_update(
+                        locked_surface.server_image,
+                        rect,
+                        &dpm,
+                        &mut locked_streams,
+                        &mut buf,
+                    );
                     if n >= 0 {
                         num_rects += n;
                     }
@@ -370,27 +472,110 @@ fn add_vnc_server(server: Arc<VncServer>) {
 /// Qmp: return the information about current VNC server.
 pub fn qmp_query_vnc() -> Option<VncInfo> {
     let mut vnc_info = VncInfo::default();
-    if VNC_SERVERS.lock().unwrap().is_empty() {
+    let servers = VNC_SERVERS.lock().unwrap().clone();
+    if servers.is_empty() {
         vnc_info.enabled = false;
         return Some(vnc_info);
     }
     vnc_info.enabled = true;
-    let server = VNC_SERVERS.lock().unwrap()[0].clone();
     vnc_info.family = "ipv4".to_string();
+    vnc_info.auth = match servers[0].security_type.borrow().auth {
+        AuthState::No => "none",
+        AuthState::Vnc => "vnc",
+        AuthState::Vencrypt => "vencrypt",
+        AuthState::Sasl => "sasl",
+        AuthState::Invalid => "invalid",
+    }
+    .to_string();
+    vnc_info.share_policy = servers[0].share_policy.as_str().to_string();
 
-    let mut locked_handler = server.client_handlers.lock().unwrap();
-    for client in locked_handler.values_mut() {
-        let mut client_info = VncClientInfo {
-            host: client.addr.clone(),
-            ..Default::default()
-        };
-        client_info.family = "ipv4".to_string();
-        vnc_info.clients.push(client_info);
+    // Clients of all heads are reported.
+    for server in servers {
+        let mut locked_handler = server.client_handlers.lock().unwrap();
+        for client in locked_handler.values_mut() {
+            let locked_state = client.conn_state.lock().unwrap();
+            let share = match locked_state.exclusive {
+                true => "exclusive",
+                false => "shared",
+            };
+            let mut client_info = VncClientInfo {
+                host: client.addr.clone(),
+                websocket: client.websocket,
+                share: share.to_string(),
+                view_only: locked_state.view_only,
+                audio: client.audio.lock().unwrap().enabled,
+                ..Default::default()
+            };
+            drop(locked_state);
+            client_info.family = "ipv4".to_string();
+            vnc_info.clients.push(client_info);
+        }
     }
 
     Some(vnc_info)
 }
 
+/// Qmp: set the password of vnc authentication.
+///
+/// # Arguments
+///
+/// * `password` - The new password, empty password disables the access.
+/// * `view_only` - Whether to set the view-only password.
+pub fn qmp_set_password(password: &str, view_only: bool) -> Result<()> {
+    vnc_password_enabled()?;
+    let access = match view_only {
+        true => VncAccess::ViewOnly,
+        false => VncAccess::Full,
+    };
+    vnc_set_password(password, access);
+    Ok(())
+}
+
+/// Qmp: set the expiration time of vnc passwords.
+pub fn qmp_expire_password(time: &str) -> Result<()> {
+    vnc_password_enabled()?;
+    vnc_expire_password(time)
+}
+
+fn vnc_password_enabled() -> Result<()> {
+    let servers = VNC_SERVERS.lock().unwrap();
+    match servers.first() {
+        Some(server) if server.security_type.borrow().password => Ok(()),
+        Some(_) => Err(anyhow!("Password authentication of vnc is not enabled")),
+        None => Err(anyhow!("Vnc is not enabled")),
+    }
+}
+
+/// Send the audio played by guest to the clients which enable audio.
+///
+/// # Arguments
+///
+/// * `freq` - Frequency of the pcm data.
+/// * `bits` - Bits of each sample, which is signed integer in little endian.
+/// * `channels` - Number of channels of the pcm data.
+/// * `data` - Interleaved pcm data.
+pub fn vnc_audio_play(freq: u32, bits: u8, channels: u8, data: &[u8]) {
+    let servers = VNC_SERVERS.lock().unwrap().clone();
+    for server in servers {
+        let locked_handlers = server.client_handlers.lock().unwrap();
+        for client in locked_handlers.values() {
+            let mut locked_audio = client.audio.lock().unwrap();
+            if !locked_audio.enabled {
+                continue;
+            }
+            let pcm = locked_audio.convert(freq, bits, channels, data);
+            drop(locked_audio);
+            if pcm.is_empty() {
+                continue;
+            }
+            let mut buf = Vec::new();
+            audio_data_msg(&pcm, &mut buf);
+            vnc_write(client, buf);
+            vnc_flush(client);
+        }
+    }
+}
+
 /// Set dirty in bitmap.
 pub fn set_area_dirty(
     dirty: &mut Bitmap<u64>,
@@ -552,6 +737,21 @@ pub fn write_pixel(
     }
 }
 
+/// Get the value of pixel in the pixel format of client.
+///
+/// # Arguments
+///
+/// * `client_dpm` - Output mod of client display.
+/// * `color` - the pixel value need to be convert.
+pub fn client_pixel_value(client_dpm: &DisplayMode, color: u32) -> u32 {
+    let r = ((color & 0x00ff0000) >> 16) << client_dpm.pf.red.bits >> 8;
+    let g = ((color & 0x0000ff00) >> 8) << client_dpm.pf.green.bits >> 8;
+    let b = (color & 0x000000ff) << client_dpm.pf.blue.bits >> 8;
+    (r << client_dpm.pf.red.shift)
+        | (g << client_dpm.pf.green.shift)
+        | (b << client_dpm.pf.blue.shift)
+}
+
 /// Convert the sent information to a format supported  
 /// by the client depend on byte arrangement
 ///
@@ -562,12 +762,7 @@ pub fn write_pixel(
 /// * `color` - the pixel value need to be convert.
 pub fn convert_pixel(client_dpm: &DisplayMode, buf: &mut Vec<u8>, color: u32) {
     let mut ret = [0u8; 4];
-    let r = ((color & 0x00ff0000) >> 16) << client_dpm.pf.red.bits >> 8;
-    let g = ((color & 0x0000ff00) >> 8) << client_dpm.pf.green.bits >> 8;
-    let b = (color & 0x000000ff) << client_dpm.pf.blue.bits >> 8;
-    let v = (r << client_dpm.pf.red.shift)
-        | (g << client_dpm.pf.green.shift)
-        | (b << client_dpm.pf.blue.shift);
+    let v = client_pixel_value(client_dpm, color);
     match client_dpm.pf.pixel_bytes {
         1 => {
             ret[0] = v as u8;
@@ -636,19 +831,54 @@ pub fn raw_send_framebuffer_update(
 /// * `image` = pointer to the data need to be send.
 /// * `rect` - dirty area of image.
 /// * `client_dpm` - Output mod information of client display.
+/// * `streams` - zlib streams of client.
 /// * `buf` - send buffer.
 fn send_framebuffer_update(
     image: *mut pixman_image_t,
     rect: &Rectangle,
     client_dpm: &DisplayMode,
+    streams: &mut ZlibStreams,
     buf: &mut Vec<u8>,
 ) -> i32 {
-    match client_dpm.enc {
+    let level = client_dpm.compress_level;
+    let total_bytes = streams.total_bytes();
+    let result = match client_dpm.enc {
         ENCODING_HEXTILE => {
             framebuffer_update(rect.x, rect.y, rect.w, rect.h, ENCODING_HEXTILE, buf);
-            hextile_send_framebuffer_update(image, rect, client_dpm, buf)
+            return hextile_send_framebuffer_update(image, rect, client_dpm, buf);
+        }
+        ENCODING_ZLIB | ENCODING_ZRLE | ENCODING_ZYWRLE | ENCODING_TIGHT if streams.broken => {
+            framebuffer_update(rect.x, rect.y, rect.w, rect.h, ENCODING_RAW, buf);
+            return raw_send_framebuffer_update(image, rect, client_dpm, buf);
+        }
+        ENCODING_ZLIB => {
+            let stream = ZlibStreams::get_stream(&mut streams.zlib, level);
+            zlib_send_framebuffer_update(image, rect, client_dpm, stream, buf)
+        }
+        // ZYWRLE is not supported, use ZRLE instead if client supports it.
+        ENCODING_ZRLE | ENCODING_ZYWRLE if client_dpm.has_feature(VncFeatures::VncFeatureZrle) => {
+            let stream = ZlibStreams::get_stream(&mut streams.zrle, level);
+            zrle_send_framebuffer_update(image, rect, client_dpm, stream, buf)
         }
+        ENCODING_TIGHT => tight_send_framebuffer_update(image, rect, client_dpm, streams, buf),
         _ => {
+            framebuffer_update(rect.x, rect.y, rect.w, rect.h, ENCODING_RAW, buf);
+            return raw_send_framebuffer_update(image, rect, client_dpm, buf);
+        }
+    };
+
+    match result {
+        Ok(n) => n,
+        Err(e) => {
+            error!(
+                "Failed to encode framebuffer: {:?}, send raw data instead",
+                e
+            );
+            // Compressed data is never sent, but the client would decompress the
+            // later data with a dictionary missing the data fed to streams.
+            if streams.total_bytes() != total_bytes {
+                streams.broken = true;
+            }
             framebuffer_update(rect.x, rect.y, rect.w, rect.h, ENCODING_RAW, buf);
             raw_send_framebuffer_update(image, rect, client_dpm, buf)
         }
@@ -20,10 +20,13 @@ use crate::{
     },
     vnc::{
         auth_sasl::{AuthState, SaslAuth, SaslConfig, SubAuthState},
-        auth_vencrypt::{make_vencrypt_config, TlsCreds, ANON_CERT, X509_CERT},
-        client_io::{vnc_flush, vnc_write, ClientIoHandler, ClientState, IoChannel, RectInfo},
-        round_up_div, update_server_surface, DIRTY_PIXELS_NUM, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
-        VNC_BITMAP_WIDTH, VNC_SERVERS,
+        auth_vencrypt::{make_vencrypt_config, TlsCreds, TlsIoChannel, ANON_CERT, X509_CERT},
+        client_io::{
+            vnc_flush, vnc_write, ClientIoHandler, ClientState, IoChannel, IoOperations, RectInfo,
+        },
+        round_up_div, update_server_surface,
+        websocket::WsIoChannel,
+        DIRTY_PIXELS_NUM, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH, VNC_BITMAP_WIDTH,
     },
 };
 use anyhow::{anyhow, Result};
@@ -51,7 +54,39 @@ use util::{
 };
 use vmm_sys_util::epoll::EventSet;
 
-const CONNECTION_LIMIT: usize = 1;
+const CONNECTION_LIMIT: usize = 16;
+
+/// Share policy of clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VncSharePolicy {
+    /// Only one client is connected, the new client disconnects the old one.
+    /// It's used if share policy is not configured.
+    SingleClient,
+    /// Client can request exclusive access, which disconnects the others.
+    AllowExclusive,
+    /// Exclusive requests are ignored, all clients share the display.
+    ForceShared,
+}
+
+impl VncSharePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VncSharePolicy::SingleClient => "single-client",
+            VncSharePolicy::AllowExclusive => "allow-exclusive",
+            VncSharePolicy::ForceShared => "force-shared",
+        }
+    }
+}
+
+impl From<&str> for VncSharePolicy {
+    fn from(s: &str) -> Self {
+        match s {
+            "allow-exclusive" => VncSharePolicy::AllowExclusive,
+            "force-shared" => VncSharePolicy::ForceShared,
+            _ => VncSharePolicy::SingleClient,
+        }
+    }
+}
 
 /// Information of VncServer.
 pub struct VncServer {
@@ -73,6 +108,8 @@ pub struct VncServer {
     pub rect_jobs: Arc<Mutex<Vec<RectInfo>>>,
     /// Connection limit.
     pub conn_limits: usize,
+    /// Share policy of clients.
+    pub share_policy: VncSharePolicy,
 }
 
 // SAFETY:
@@ -88,6 +125,7 @@ impl VncServer {
         guest_image: *mut pixman_image_t,
         keysym2keycode: HashMap<u16, u16>,
         display_listener: Option<Weak<Mutex<DisplayChangeListener>>>,
+        share_policy: VncSharePolicy,
     ) -> Self {
         VncServer {
             client_handlers: Arc::new(Mutex::new(HashMap::new())),
@@ -97,7 +135,11 @@ impl VncServer {
             vnc_cursor: Arc::new(Mutex::new(VncCursor::default())),
             display_listener,
             rect_jobs: Arc::new(Mutex::new(Vec::new())),
-            conn_limits: CONNECTION_LIMIT,
+            conn_limits: match share_policy {
+                VncSharePolicy::SingleClient => 1,
+                _ => CONNECTION_LIMIT,
+            },
+            share_policy,
         }
     }
 }
@@ -107,11 +149,17 @@ pub struct VncConnHandler {
     listener: TcpListener,
     /// VncServer.
     server: Arc<VncServer>,
+    /// Whether the clients connect with websocket.
+    websocket: bool,
 }
 
 impl VncConnHandler {
-    pub fn new(listener: TcpListener, server: Arc<VncServer>) -> Self {
-        VncConnHandler { listener, server }
+    pub fn new(listener: TcpListener, server: Arc<VncServer>, websocket: bool) -> Self {
+        VncConnHandler {
+            listener,
+            server,
+            websocket,
+        }
     }
 }
 
@@ -120,12 +168,13 @@ impl EventNotifierHelper for VncConnHandler {
     fn internal_notifiers(vnc_io: Arc<Mutex<VncConnHandler>>) -> Vec<EventNotifier> {
         let vnc_io_clone = vnc_io.clone();
         let server = vnc_io.lock().unwrap().server.clone();
+        let websocket = vnc_io.lock().unwrap().websocket;
         // Register event notifier for connection.
         let handler: Rc<NotifierCallback> = Rc::new(move |_event, fd: RawFd| {
             read_fd(fd);
             match vnc_io_clone.clone().lock().unwrap().listener.accept() {
                 Ok((stream, addr)) => {
-                    if let Err(e) = handle_connection(&server, stream, addr) {
+                    if let Err(e) = handle_connection(&server, stream, addr, websocket) {
                         error!("{:?}", e);
                     }
                 }
@@ -185,6 +234,8 @@ pub struct SecurityType {
     pub auth: AuthState,
     /// Subauth type.
     pub subauth: SubAuthState,
+    /// Whether password authentication is used.
+    pub password: bool,
 }
 
 impl Default for SecurityType {
@@ -196,6 +247,7 @@ impl Default for SecurityType {
             tls_config: None,
             auth: AuthState::No,
             subauth: SubAuthState::VncAuthVencryptPlain,
+            password: false,
         }
     }
 }
@@ -223,6 +275,8 @@ impl SecurityType {
             self.tlscreds = Some(tlscred);
         }
 
+        self.password = vnc_cfg.password;
+
         // Sasl configuration.
         if let Some(sasl_auth) = object.sasl_object.get(&vnc_cfg.sasl_authz) {
             self.saslauth = Some(SaslAuth::new(sasl_auth.identity.clone()));
@@ -242,7 +296,10 @@ impl SecurityType {
             is_anon = tlscred.cred_type == *ANON_CERT;
             self.auth = AuthState::Vencrypt;
         } else {
-            self.auth = AuthState::No;
+            self.auth = match self.password {
+                true => AuthState::Vnc,
+                false => AuthState::No,
+            };
             self.subauth = SubAuthState::VncAuthVencryptPlain;
             return Ok(());
         }
@@ -252,7 +309,13 @@ impl SecurityType {
                 "Unsupported tls cred type",
             ))));
         }
-        if is_sasl {
+        if self.password {
+            if is_x509 {
+                self.subauth = SubAuthState::VncAuthVencryptX509Vnc;
+            } else {
+                self.subauth = SubAuthState::VncAuthVencryptTlsVnc;
+            }
+        } else if is_sasl {
             if is_x509 {
                 self.subauth = SubAuthState::VncAuthVencryptX509Sasl;
             } else {
@@ -320,9 +383,10 @@ impl VncSurface {
         )
     }
 
-    /// Flush dirty data from guest_image to server_image.
+    /// Flush dirty data from guest_image to server_image, and mark the
+    /// updated area dirty for clients of `server`.
     /// Return the number of dirty area.
-    pub fn update_server_image(&mut self) -> Result<i32> {
+    pub fn update_server_image(&mut self, server: &VncServer) -> Result<i32> {
         let mut dirty_num = 0;
         let height = self.get_min_height() as usize;
         let g_bpl = self.guest_dirty_bitmap.vol() / MAX_WINDOW_HEIGHT as usize;
@@ -375,7 +439,7 @@ impl VncSurface {
                 g_info.ptr = (g_info.data as usize + y * g_info.stride as usize) as *mut u8;
             }
             g_info.ptr = (g_info.ptr as usize + x * cmp_bytes) as *mut u8;
-            dirty_num += self.update_one_line(x, y, &mut s_info, &mut g_info, cmp_bytes)?;
+            dirty_num += self.update_one_line(server, x, y, &mut s_info, &mut g_info, cmp_bytes)?;
             y += 1;
             offset = self
                 .guest_dirty_bitmap
@@ -394,11 +458,13 @@ impl VncSurface {
     ///
     /// # Arguments
     ///
+    /// * `server` - Vnc server of the surface.
     /// * `x` `y` - start coordinate in image to refresh
     /// * `s_info` - Info of Server image.
     /// * `g_info` - Info of Guest image.
     fn update_one_line(
         &mut self,
+        server: &VncServer,
         mut x: usize,
         y: usize,
         s_info: &mut ImageInfo,
@@ -444,7 +510,7 @@ impl VncSurface {
                 ptr::copy(g_info.ptr, s_info.ptr, _cmp_bytes);
             };
 
-            set_dirty_for_each_clients(x, y)?;
+            set_dirty_for_each_clients(server, x, y)?;
             count += 1;
 
             x += 1;
@@ -460,9 +526,9 @@ impl VncSurface {
 ///
 /// # Arguments
 ///
+/// * `server` - Vnc server of the clients.
 /// * `x` `y`- coordinates of dirty area.
-fn set_dirty_for_each_clients(x: usize, y: usize) -> Result<()> {
-    let server = VNC_SERVERS.lock().unwrap()[0].clone();
+fn set_dirty_for_each_clients(server: &VncServer, x: usize, y: usize) -> Result<()> {
     let mut locked_handlers = server.client_handlers.lock().unwrap();
     for client in locked_handlers.values_mut() {
         client
@@ -480,19 +546,34 @@ fn set_dirty_for_each_clients(x: usize, y: usize) -> Result<()> {
 ///
 /// * `stream` - TcpStream.
 /// * `addr`- SocketAddr.
+/// * `websocket` - whether the client connects with websocket.
 pub fn handle_connection(
     server: &Arc<VncServer>,
     stream: TcpStream,
     addr: SocketAddr,
+    websocket: bool,
 ) -> Result<()> {
     info!("New Connection: {:?}", stream);
     stream
         .set_nonblocking(true)
         .expect("set nonblocking failed");
 
-    let io_channel = Rc::new(RefCell::new(IoChannel::new(stream.try_clone().unwrap())));
+    let io_channel: Rc<RefCell<dyn IoOperations>> = if websocket {
+        // Websocket is wrapped in tls if tls-creds is configured.
+        let tls_config = server.security_type.borrow().tls_config.clone();
+        let inner: Box<dyn IoOperations> = match tls_config {
+            Some(config) => Box::new(TlsIoChannel::new(
+                stream.try_clone().unwrap(),
+                rustls::ServerConnection::new(config)?,
+            )),
+            None => Box::new(IoChannel::new(stream.try_clone().unwrap())),
+        };
+        Rc::new(RefCell::new(WsIoChannel::new(inner)))
+    } else {
+        Rc::new(RefCell::new(IoChannel::new(stream.try_clone().unwrap())))
+    };
     // Register event notifier for vnc client.
-    let client = Arc::new(ClientState::new(addr.to_string()));
+    let client = Arc::new(ClientState::new(addr.to_string(), websocket));
     let client_io = Arc::new(Mutex::new(ClientIoHandler::new(
         stream,
         io_channel,
@@ -0,0 +1,509 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use crate::vnc::client_io::IoOperations;
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+/// Magic string used to compute Sec-WebSocket-Accept, defined in RFC 6455.
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Max size of http upgrade request from client.
+const WS_MAX_HANDSHAKE_LEN: usize = 4096;
+/// Max payload size of a frame sent by client.
+const WS_MAX_PAYLOAD_LEN: u64 = 16 * 1024 * 1024;
+/// Max payload size of control frame.
+const WS_MAX_CONTROL_PAYLOAD_LEN: usize = 125;
+/// Websocket subprotocol used by noVNC for binary data.
+const WS_PROTOCOL_BINARY: &str = "binary";
+
+const WS_OPCODE_CONTINUATION: u8 = 0x0;
+const WS_OPCODE_TEXT: u8 = 0x1;
+const WS_OPCODE_BINARY: u8 = 0x2;
+const WS_OPCODE_CLOSE: u8 = 0x8;
+const WS_OPCODE_PING: u8 = 0x9;
+const WS_OPCODE_PONG: u8 = 0xA;
+
+const WS_FIN_BIT: u8 = 0x80;
+const WS_MASK_BIT: u8 = 0x80;
+const WS_OPCODE_MASK: u8 = 0x0F;
+const WS_PAYLOAD_LEN_MASK: u8 = 0x7F;
+const WS_PAYLOAD_LEN_16: u8 = 126;
+const WS_PAYLOAD_LEN_64: u8 = 127;
+
+/// Frame received from websocket client.
+#[derive(Debug, PartialEq, Eq)]
+struct WsFrame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Io channel which transfers the RFB protocol over websocket. The
+/// websocket handshake and framing is done on top of the inner channel,
+/// which is a plain tcp channel or a tls channel.
+pub struct WsIoChannel {
+    /// Inner channel to transfer websocket frames.
+    inner: Box<dyn IoOperations>,
+    /// Whether the http upgrade handshake is finished.
+    handshake_done: bool,
+    /// Data received from inner channel but not decoded.
+    raw_in: Vec<u8>,
+    /// Frame data which must be sent before the payload of next frame.
+    pending: Vec<u8>,
+    /// Frames generated before handshake, or control frames generated while
+    /// the payload of a data frame is being sent.
+    deferred: Vec<u8>,
+    /// Bytes of payload of current data frame which have not been sent.
+    payload_remaining: usize,
+}
+
+impl WsIoChannel {
+    pub fn new(inner: Box<dyn IoOperations>) -> Self {
+        Self {
+            inner,
+            handshake_done: false,
+            raw_in: Vec::new(),
+            pending: Vec::new(),
+            deferred: Vec::new(),
+            payload_remaining: 0,
+        }
+    }
+
+    /// Try to send all pending data, return whether it is sent completely.
+    fn flush_pending(&mut self) -> Result<bool> {
+        if self.pending.is_empty() {
+            return Ok(true);
+        }
+        let len = self.inner.channel_write(&self.pending)?;
+        self.pending.drain(..len);
+        Ok(self.pending.is_empty())
+    }
+
+    /// Send a control frame, it can not be inserted into the payload of data frame.
+    fn send_control(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let mut frame = ws_frame_header(opcode, payload.len());
+        frame.extend_from_slice(payload);
+        if !self.handshake_done || self.payload_remaining != 0 {
+            self.deferred.append(&mut frame);
+            return Ok(());
+        }
+        self.pending.append(&mut frame);
+        self.flush_pending()?;
+        Ok(())
+    }
+
+    /// Process the http upgrade request, return whether the handshake is finished.
+    fn handle_handshake(&mut self) -> Result<bool> {
+        let end = match self.raw_in.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => {
+                if self.raw_in.len() > WS_MAX_HANDSHAKE_LEN {
+                    bail!("Websocket handshake request is too long");
+                }
+                return Ok(false);
+            }
+        };
+        let request: Vec<u8> = self.raw_in.drain(..end).collect();
+        let response = match ws_handshake_response(&String::from_utf8_lossy(&request)) {
+            Ok(resp) => resp,
+            Err(e) => {
+                let resp = "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+                self.inner.channel_write(resp.as_bytes())?;
+                return Err(e);
+            }
+        };
+
+        self.handshake_done = true;
+        self.pending.extend_from_slice(response.as_bytes());
+        self.pending.append(&mut self.deferred);
+        self.flush_pending()?;
+        Ok(true)
+    }
+}
+
+impl IoOperations for WsIoChannel {
+    fn channel_write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.handshake_done {
+            let mut frame = ws_frame_header(WS_OPCODE_BINARY, buf.len());
+            frame.extend_from_slice(buf);
+            self.deferred.append(&mut frame);
+            return Ok(buf.len());
+        }
+
+        // The whole buffer is the payload of a new frame, and the remaining part of it
+        // will be sent in next call if it is not sent completely.
+        if self.payload_remaining == 0 {
+            let mut header = ws_frame_header(WS_OPCODE_BINARY, buf.len());
+            self.pending.append(&mut header);
+            self.payload_remaining = buf.len();
+        }
+        if !self.flush_pending()? {
+            return Ok(0);
+        }
+
+        let len = std::cmp::min(self.payload_remaining, buf.len());
+        let sent = self.inner.channel_write(&buf[..len])?;
+        self.payload_remaining -= sent;
+        if self.payload_remaining == 0 && !self.deferred.is_empty() {
+            self.pending.append(&mut self.deferred);
+            self.flush_pending()?;
+        }
+        Ok(sent)
+    }
+
+    fn channel_read(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let mut data: Vec<u8> = Vec::new();
+        let len = self.inner.channel_read(&mut data)?;
+        self.raw_in.extend_from_slice(&data[..len]);
+
+        if !self.handshake_done && !self.handle_handshake()? {
+            return Ok(0);
+        }
+
+        let mut read_len = 0_usize;
+        while let Some((frame, frame_len)) = ws_decode_frame(&self.raw_in)? {
+            self.raw_in.drain(..frame_len);
+            match frame.opcode {
+                WS_OPCODE_BINARY | WS_OPCODE_CONTINUATION => {
+                    read_len += frame.payload.len();
+                    buf.extend_from_slice(&frame.payload);
+                }
+                WS_OPCODE_PING => {
+                    self.send_control(WS_OPCODE_PONG, &frame.payload)?;
+                }
+                WS_OPCODE_PONG => {}
+                WS_OPCODE_CLOSE => {
+                    // Echo the status code back and shutdown the connection.
+                    let len = std::cmp::min(frame.payload.len(), 2);
+                    self.send_control(WS_OPCODE_CLOSE, &frame.payload[..len])?;
+                    bail!("Websocket connection is closed by client");
+                }
+                WS_OPCODE_TEXT => {
+                    bail!("Websocket text frame is not supported");
+                }
+                _ => {
+                    bail!("Unsupported websocket opcode {}", frame.opcode);
+                }
+            }
+        }
+
+        Ok(read_len)
+    }
+}
+
+/// Compute the value of Sec-WebSocket-Accept for the key from client.
+fn ws_accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WS_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Check the http upgrade request from client, and generate the response.
+fn ws_handshake_response(request: &str) -> Result<String> {
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    if parts.next() != Some("GET") || parts.nth(1) != Some("HTTP/1.1") {
+        bail!("Invalid websocket request line: {}", request_line);
+    }
+
+    let mut upgrade = false;
+    let mut connection = false;
+    let mut version = false;
+    let mut key = None;
+    let mut binary_protocol = false;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((n, v)) => (n.trim().to_ascii_lowercase(), v.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "connection" => {
+                connection = value
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case("upgrade"))
+            }
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value.to_string()),
+            "sec-websocket-protocol" => {
+                binary_protocol = value.split(',').any(|v| v.trim() == WS_PROTOCOL_BINARY)
+            }
+            _ => {}
+        }
+    }
+
+    if !upgrade || !connection {
+        bail!("Missing websocket upgrade header");
+    }
+    if !version {
+        bail!("Unsupported websocket version");
+    }
+    let key = match key {
+        Some(k) if !k.is_empty() => k,
+        _ => bail!("Missing websocket key"),
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n",
+        ws_accept_key(&key)
+    );
+    if binary_protocol {
+        response += &format!("Sec-WebSocket-Protocol: {}\r\n", WS_PROTOCOL_BINARY);
+    }
+    response += "\r\n";
+    Ok(response)
+}
+
+/// Generate the header of unmasked frame sent by server.
+fn ws_frame_header(opcode: u8, len: usize) -> Vec<u8> {
+    let mut header = vec![WS_FIN_BIT | opcode];
+    if len < WS_PAYLOAD_LEN_16 as usize {
+        header.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        header.push(WS_PAYLOAD_LEN_16);
+        header.append(&mut (len as u16).to_be_bytes().to_vec());
+    } else {
+        header.push(WS_PAYLOAD_LEN_64);
+        header.append(&mut (len as u64).to_be_bytes().to_vec());
+    }
+    header
+}
+
+/// Decode one frame from the buffer. Return the frame and the size of bytes
+/// it takes, or None if the frame is not received completely.
+fn ws_decode_frame(buf: &[u8]) -> Result<Option<(WsFrame, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & WS_FIN_BIT != 0;
+    let opcode = buf[0] & WS_OPCODE_MASK;
+    if buf[0] & !(WS_FIN_BIT | WS_OPCODE_MASK) != 0 {
+        bail!("Websocket extensions are not supported");
+    }
+    // Frames sent from client to server must be masked.
+    if buf[1] & WS_MASK_BIT == 0 {
+        bail!("Websocket frame from client is not masked");
+    }
+
+    let mut offset = 2;
+    let payload_len = match buf[1] & WS_PAYLOAD_LEN_MASK {
+        WS_PAYLOAD_LEN_16 => {
+            if buf.len() < offset + 2 {
+                return Ok(None);
+            }
+            let len = u16::from_be_bytes([buf[2], buf[3]]) as u64;
+            offset += 2;
+            len
+        }
+        WS_PAYLOAD_LEN_64 => {
+            if buf.len() < offset + 8 {
+                return Ok(None);
+            }
+            let mut bytes = [0_u8; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            offset += 8;
+            u64::from_be_bytes(bytes)
+        }
+        len => len as u64,
+    };
+    if payload_len > WS_MAX_PAYLOAD_LEN {
+        bail!("Websocket frame is too large: {}", payload_len);
+    }
+    let payload_len = payload_len as usize;
+    if opcode & 0x8 != 0 && (!fin || payload_len > WS_MAX_CONTROL_PAYLOAD_LEN) {
+        bail!("Invalid websocket control frame");
+    }
+
+    if buf.len() < offset + 4 + payload_len {
+        return Ok(None);
+    }
+    let mask = &buf[offset..offset + 4];
+    offset += 4;
+    let payload: Vec<u8> = buf[offset..offset + payload_len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((WsFrame { opcode, payload }, offset + payload_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Memory channel to simulate the tcp stream.
+    struct MemChannel {
+        input: Rc<RefCell<Vec<u8>>>,
+        output: Rc<RefCell<Vec<u8>>>,
+        write_limit: usize,
+    }
+
+    impl IoOperations for MemChannel {
+        fn channel_write(&mut self, buf: &[u8]) -> Result<usize> {
+            let len = std::cmp::min(buf.len(), self.write_limit);
+            self.output.borrow_mut().extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn channel_read(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+            let len = self.input.borrow().len();
+            buf.append(&mut self.input.borrow_mut());
+            Ok(len)
+        }
+    }
+
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12_u8, 0x34, 0x56, 0x78];
+        let mut frame = ws_frame_header(opcode, payload.len());
+        frame[1] |= WS_MASK_BIT;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    const HANDSHAKE_REQUEST: &str = "GET /websockify HTTP/1.1\r\n\
+        Host: 127.0.0.1:5700\r\n\
+        Upgrade: websocket\r\n\
+        Connection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Protocol: binary\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    #[test]
+    fn test_ws_handshake_response() {
+        // Example from RFC 6455.
+        assert_eq!(
+            ws_accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let resp = ws_handshake_response(HANDSHAKE_REQUEST).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(resp.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(resp.contains("Sec-WebSocket-Protocol: binary\r\n"));
+        assert!(resp.ends_with("\r\n\r\n"));
+
+        let invalid_requests = [
+            HANDSHAKE_REQUEST.replace("GET", "POST"),
+            HANDSHAKE_REQUEST.replace("Upgrade: websocket", "Upgrade: h2c"),
+            HANDSHAKE_REQUEST.replace("Version: 13", "Version: 8"),
+            HANDSHAKE_REQUEST.replace("Sec-WebSocket-Key", "Sec-WebSocket-Nonce"),
+        ];
+        for req in invalid_requests {
+            assert!(ws_handshake_response(&req).is_err());
+        }
+    }
+
+    #[test]
+    fn test_ws_decode_frame() {
+        for len in [0, 5, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = client_frame(WS_OPCODE_BINARY, &payload);
+            let (decoded, size) = ws_decode_frame(&frame).unwrap().unwrap();
+            assert_eq!(size, frame.len());
+            assert_eq!(decoded.opcode, WS_OPCODE_BINARY);
+            assert_eq!(decoded.payload, payload);
+            // Incomplete frame.
+            assert!(ws_decode_frame(&frame[..frame.len() - 1])
+                .unwrap()
+                .is_none());
+        }
+
+        // Unmasked frame.
+        let frame = ws_frame_header(WS_OPCODE_BINARY, 0);
+        assert!(ws_decode_frame(&frame).is_err());
+        // Fragmented control frame.
+        let mut frame = client_frame(WS_OPCODE_PING, &[]);
+        frame[0] &= !WS_FIN_BIT;
+        assert!(ws_decode_frame(&frame).is_err());
+    }
+
+    #[test]
+    fn test_ws_io_channel() {
+        let input = Rc::new(RefCell::new(Vec::new()));
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mem_channel = MemChannel {
+            input: input.clone(),
+            output: output.clone(),
+            write_limit: usize::MAX,
+        };
+        let mut ws_channel = WsIoChannel::new(Box::new(mem_channel));
+
+        // Data written before handshake is delayed.
+        assert_eq!(ws_channel.channel_write(b"RFB 003.008\n").unwrap(), 12);
+        assert!(output.borrow().is_empty());
+
+        // Handshake request is received in two parts.
+        let (part1, part2) = HANDSHAKE_REQUEST.split_at(20);
+        let mut buf = Vec::new();
+        input.borrow_mut().extend_from_slice(part1.as_bytes());
+        assert_eq!(ws_channel.channel_read(&mut buf).unwrap(), 0);
+        input.borrow_mut().extend_from_slice(part2.as_bytes());
+        input
+            .borrow_mut()
+            .append(&mut client_frame(WS_OPCODE_BINARY, b"RFB 003.008\n"));
+        input
+            .borrow_mut()
+            .append(&mut client_frame(WS_OPCODE_PING, b"hi"));
+        assert_eq!(ws_channel.channel_read(&mut buf).unwrap(), 12);
+        assert_eq!(buf, b"RFB 003.008\n");
+
+        let mut expect = ws_handshake_response(HANDSHAKE_REQUEST)
+            .unwrap()
+            .into_bytes();
+        expect.append(&mut ws_frame_header(WS_OPCODE_BINARY, 12));
+        expect.extend_from_slice(b"RFB 003.008\n");
+        expect.append(&mut ws_frame_header(WS_OPCODE_PONG, 2));
+        expect.extend_from_slice(b"hi");
+        assert_eq!(*output.borrow(), expect);
+
+        // Close frame.
+        input
+            .borrow_mut()
+            .append(&mut client_frame(WS_OPCODE_CLOSE, &1000_u16.to_be_bytes()));
+        assert!(ws_channel.channel_read(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_ws_io_channel_partial_write() {
+        let input = Rc::new(RefCell::new(Vec::new()));
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mem_channel = MemChannel {
+            input: input.clone(),
+            output: output.clone(),
+            write_limit: 1,
+        };
+        let mut ws_channel = WsIoChannel::new(Box::new(mem_channel));
+        ws_channel.handshake_done = true;
+
+        // The remaining part of buffer is sent in later calls, just as the
+        // client io handler does.
+        let data = vec![0xab_u8; 200];
+        let mut offset = 0;
+        while offset < data.len() {
+            offset += ws_channel.channel_write(&data[offset..]).unwrap();
+        }
+        let mut expect = ws_frame_header(WS_OPCODE_BINARY, 200);
+        expect.extend_from_slice(&data);
+        assert_eq!(*output.borrow(), expect);
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use anyhow::{bail, Context, Result};
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iowr_nr};
+
+/// See: https://elixir.bootlin.com/linux/v5.10/source/include/uapi/linux/userfaultfd.h
+const UFFDIO: u32 = 0xAA;
+const UFFD_API: u64 = 0xAA;
+const UFFD_FEATURE_PAGEFAULT_FLAG_WP: u64 = 1 << 0;
+const UFFDIO_REGISTER_MODE_WP: u64 = 1 << 1;
+const UFFDIO_WRITEPROTECT_MODE_WP: u64 = 1 << 0;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_PAGEFAULT_FLAG_WP: u64 = 1 << 1;
+
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, UffdioRange);
+ioctl_iowr_nr!(UFFDIO_WRITEPROTECT, UFFDIO, 0x06, UffdioWriteprotect);
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3F, UffdioApi);
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioWriteprotect {
+    range: UffdioRange,
+    mode: u64,
+}
+
+/// Message read from userfaultfd, only the page fault event is used.
+#[repr(C)]
+#[derive(Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    ptid: u32,
+    reserved4: u32,
+}
+
+/// Userfaultfd in write-protect mode, used to catch the first write to
+/// anonymous memory.
+pub struct UserfaultFd {
+    file: File,
+}
+
+impl UserfaultFd {
+    /// Create a non-blocking userfaultfd which supports write-protect mode.
+    pub fn new() -> Result<Self> {
+        // SAFETY: no pointer is passed to the syscall.
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) }
+            as RawFd;
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| "Failed to create userfaultfd");
+        }
+        // SAFETY: fd is valid and its ownership is taken by the file.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: UFFD_FEATURE_PAGEFAULT_FLAG_WP,
+            ioctls: 0,
+        };
+        // SAFETY: file is a valid userfaultfd and api is a valid uffdio_api.
+        let ret = unsafe { ioctl_with_mut_ref(&file, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| "Userfaultfd write-protect is not supported");
+        }
+        if api.features & UFFD_FEATURE_PAGEFAULT_FLAG_WP == 0 {
+            bail!("Userfaultfd write-protect is not supported");
+        }
+
+        Ok(UserfaultFd { file })
+    }
+
+    /// Register memory range in write-protect mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start host address of range, aligned with page size.
+    /// * `len` - Length of range, aligned with page size.
+    pub fn register_wp(&self, addr: u64, len: u64) -> Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange { start: addr, len },
+            mode: UFFDIO_REGISTER_MODE_WP,
+            ioctls: 0,
+        };
+        // SAFETY: file is a valid userfaultfd and register is a valid uffdio_register.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_REGISTER(), &mut register) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!(
+                    "Failed to register userfaultfd range 0x{:x}, len 0x{:x}",
+                    addr, len
+                )
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Unregister memory range, write-protect faults of it are not caught any more.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start host address of range, aligned with page size.
+    /// * `len` - Length of range, aligned with page size.
+    pub fn unregister(&self, addr: u64, len: u64) -> Result<()> {
+        let mut range = UffdioRange { start: addr, len };
+        // SAFETY: file is a valid userfaultfd and range is a valid uffdio_range.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_UNREGISTER(), &mut range) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!(
+                    "Failed to unregister userfaultfd range 0x{:x}, len 0x{:x}",
+                    addr, len
+                )
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Write-protect memory range, or remove the protection and wake up the
+    /// threads blocked on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start host address of range, aligned with page size.
+    /// * `len` - Length of range, aligned with page size.
+    /// * `protect` - Protect the range or not.
+    pub fn write_protect(&self, addr: u64, len: u64, protect: bool) -> Result<()> {
+        let mut wp = UffdioWriteprotect {
+            range: UffdioRange { start: addr, len },
+            mode: if protect {
+                UFFDIO_WRITEPROTECT_MODE_WP
+            } else {
+                0
+            },
+        };
+        // SAFETY: file is a valid userfaultfd and wp is a valid uffdio_writeprotect.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_WRITEPROTECT(), &mut wp) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!(
+                    "Failed to write-protect userfaultfd range 0x{:x}, len 0x{:x}",
+                    addr, len
+                )
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Read a pending write-protect fault, and return the faulting host address.
+    /// `None` is returned if no fault is pending.
+    pub fn read_wp_fault(&self) -> Result<Option<u64>> {
+        let mut msg = UffdMsg::default();
+        loop {
+            // SAFETY: UffdMsg is plain old data with the layout of uffd_msg.
+            let buf = unsafe {
+                std::slice::from_raw_parts_mut(
+                    &mut msg as *mut UffdMsg as *mut u8,
+                    size_of::<UffdMsg>(),
+                )
+            };
+            match (&self.file).read(buf) {
+                Ok(len) if len == size_of::<UffdMsg>() => {}
+                Ok(len) => bail!("Invalid userfaultfd message length {}", len),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).with_context(|| "Failed to read userfaultfd"),
+            }
+
+            if msg.event == UFFD_EVENT_PAGEFAULT && msg.flags & UFFD_PAGEFAULT_FLAG_WP != 0 {
+                return Ok(Some(msg.address));
+            }
+        }
+    }
+}
+
+impl AsRawFd for UserfaultFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uffd_msg_layout() {
+        assert_eq!(size_of::<UffdMsg>(), 32);
+        assert_eq!(size_of::<UffdioRegister>(), 32);
+        assert_eq!(size_of::<UffdioWriteprotect>(), 24);
+        assert_eq!(UFFDIO_API(), 0xc018aa3f);
+        assert_eq!(UFFDIO_REGISTER(), 0xc020aa00);
+        assert_eq!(UFFDIO_UNREGISTER(), 0x8010aa01);
+        assert_eq!(UFFDIO_WRITEPROTECT(), 0xc018aa06);
+    }
+}
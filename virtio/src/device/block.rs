@@ -1397,6 +1397,7 @@ mod tests {
             &block.blk_cfg.path_on_host,
             block.blk_cfg.read_only,
             block.blk_cfg.direct,
+            false,
         )
         .unwrap();
         assert!(block.realize().is_ok());
@@ -1536,6 +1537,7 @@ mod tests {
             &block.blk_cfg.path_on_host,
             block.blk_cfg.read_only,
             block.blk_cfg.direct,
+            false,
         )
         .unwrap();
 
@@ -15,7 +15,7 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
 use std::slice::from_raw_parts_mut;
 use std::sync::{Arc, Mutex, Weak};
-use std::{ptr, vec};
+use std::{cmp, ptr, vec};
 
 use anyhow::{anyhow, bail, Context, Result};
 use log::{error, warn};
@@ -24,7 +24,11 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 use address_space::{AddressSpace, GuestAddress};
 use machine_manager::config::{GpuDevConfig, DEFAULT_VIRTQUEUE_SIZE, VIRTIO_GPU_MAX_OUTPUTS};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
-use migration_derive::ByteCode;
+use migration::{
+    build_var_state, split_var_state, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
+    StateTransfer,
+};
+use migration_derive::{ByteCode, Desc};
 use ui::console::{
     console_close, console_init, display_cursor_define, display_graphic_update,
     display_replace_surface, display_set_major_screen, get_run_stage, set_run_stage, ConsoleType,
@@ -52,13 +56,14 @@ use crate::{
     VirtioInterruptType, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
     VIRTIO_GPU_CMD_GET_DISPLAY_INFO, VIRTIO_GPU_CMD_GET_EDID, VIRTIO_GPU_CMD_MOVE_CURSOR,
     VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING, VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
-    VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING, VIRTIO_GPU_CMD_RESOURCE_FLUSH,
-    VIRTIO_GPU_CMD_RESOURCE_UNREF, VIRTIO_GPU_CMD_SET_SCANOUT, VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+    VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB, VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING,
+    VIRTIO_GPU_CMD_RESOURCE_FLUSH, VIRTIO_GPU_CMD_RESOURCE_UNREF, VIRTIO_GPU_CMD_SET_SCANOUT,
+    VIRTIO_GPU_CMD_SET_SCANOUT_BLOB, VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
     VIRTIO_GPU_CMD_UPDATE_CURSOR, VIRTIO_GPU_FLAG_FENCE, VIRTIO_GPU_F_EDID,
-    VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
-    VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID, VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY,
-    VIRTIO_GPU_RESP_ERR_UNSPEC, VIRTIO_GPU_RESP_OK_DISPLAY_INFO, VIRTIO_GPU_RESP_OK_EDID,
-    VIRTIO_GPU_RESP_OK_NODATA, VIRTIO_TYPE_GPU,
+    VIRTIO_GPU_F_RESOURCE_BLOB, VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER,
+    VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID, VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID,
+    VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY, VIRTIO_GPU_RESP_ERR_UNSPEC, VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+    VIRTIO_GPU_RESP_OK_EDID, VIRTIO_GPU_RESP_OK_NODATA, VIRTIO_TYPE_GPU,
 };
 
 /// Number of virtqueues
@@ -69,6 +74,9 @@ const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1 << 0;
 /// The flag indicates that the frame buffer only used in windows.
 const VIRTIO_GPU_RES_WIN_FRAMEBUF: u32 = 0x80000000;
 
+/// The blob resource is backed by guest memory.
+const VIRTIO_GPU_BLOB_MEM_GUEST: u32 = 1;
+
 #[derive(Debug)]
 struct GpuResource {
     resource_id: u32,
@@ -76,9 +84,18 @@ struct GpuResource {
     height: u32,
     format: u32,
     iov: Vec<Iovec>,
+    /// Guest memory entries of backing, used for migration.
+    backing: Vec<VirtioGpuMemEntry>,
     scanouts_bitmask: u32,
     host_mem: u64,
     pixman_image: *mut pixman_image_t,
+    /// Size of blob resource, it's 0 for 2D resource.
+    blob_size: u64,
+    /// Host address of blob data. It points to guest memory directly if the
+    /// backing is contiguous in host, otherwise it points to `blob_shadow`.
+    blob: *mut u8,
+    /// Copy of blob data whose backing is not contiguous in host.
+    blob_shadow: Vec<u8>,
 }
 
 impl Default for GpuResource {
@@ -89,9 +106,13 @@ impl Default for GpuResource {
             height: 0,
             format: 0,
             iov: Vec::new(),
+            backing: Vec::new(),
             scanouts_bitmask: 0,
             host_mem: 0,
             pixman_image: ptr::null_mut(),
+            blob_size: 0,
+            blob: ptr::null_mut(),
+            blob_shadow: Vec::new(),
         }
     }
 }
@@ -262,7 +283,7 @@ struct VirtioGpuResourceAttachBacking {
 impl ByteCode for VirtioGpuResourceAttachBacking {}
 
 #[repr(C)]
-#[derive(Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy)]
 struct VirtioGpuMemEntry {
     addr: u64,
     length: u32,
@@ -280,6 +301,104 @@ struct VirtioGpuResourceDetachBacking {
 
 impl ByteCode for VirtioGpuResourceDetachBacking {}
 
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct VirtioGpuResourceCreateBlob {
+    resource_id: u32,
+    blob_mem: u32,
+    blob_flags: u32,
+    nr_entries: u32,
+    blob_id: u64,
+    size: u64,
+}
+
+impl ByteCode for VirtioGpuResourceCreateBlob {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct VirtioGpuSetScanoutBlob {
+    rect: VirtioGpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    format: u32,
+    padding: u32,
+    strides: [u32; 4],
+    offsets: [u32; 4],
+}
+
+impl ByteCode for VirtioGpuSetScanoutBlob {}
+
+/// State of GPU device for migration, it's followed by `GpuScanoutState` of
+/// all outputs, and then `GpuResourceState` of each resource with its backing
+/// entries.
+#[repr(C)]
+#[derive(Clone, Copy, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0", var_size = true)]
+pub struct GpuState {
+    /// The bit mask of whether scanout is enabled or not.
+    enable_output_bitmask: u32,
+    /// Display events to be read by driver.
+    events_read: u32,
+    /// The number of outputs.
+    nr_outputs: u32,
+    /// The number of resources.
+    nr_resources: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct GpuScanoutState {
+    resource_id: u32,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    output_width: u32,
+    output_height: u32,
+    /// Format, stride and offset of framebuffer, only used for blob resource.
+    format: u32,
+    stride: u32,
+    offset: u32,
+}
+
+impl ByteCode for GpuScanoutState {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct GpuResourceState {
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+    nr_entries: u32,
+    padding: u32,
+    /// Size of blob resource, it's 0 for 2D resource.
+    blob_size: u64,
+}
+
+impl ByteCode for GpuResourceState {}
+
+/// GPU state restored from migration, it's applied when activating.
+#[derive(Default)]
+struct GpuMigratedState {
+    enable_output_bitmask: u32,
+    scanouts: Vec<GpuScanoutState>,
+    resources: Vec<(GpuResourceState, Vec<VirtioGpuMemEntry>)>,
+}
+
+/// Read a `ByteCode` object from the front of `data`, and move `data` behind it.
+fn read_state<T: ByteCode>(data: &mut &[u8]) -> Result<T> {
+    if data.len() < size_of::<T>() {
+        bail!("No enough data for gpu state");
+    }
+    let mut obj = T::default();
+    obj.as_mut_bytes().copy_from_slice(&data[..size_of::<T>()]);
+    *data = &data[size_of::<T>()..];
+    Ok(obj)
+}
+
 pub struct GpuOpts {
     /// Status of the emulated physical outputs.
     output_states: Arc<Mutex<[VirtioGpuOutputState; VIRTIO_GPU_MAX_OUTPUTS]>>,
@@ -318,6 +437,10 @@ impl HardWareOperations for GpuOpts {
             );
         }
     }
+
+    fn hw_ui_info_supported(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Default, Clone)]
@@ -407,6 +530,10 @@ struct GpuScanout {
     y: u32,
     resource_id: u32,
     cursor_visible: bool,
+    /// Format, stride and offset of framebuffer in blob resource.
+    blob_format: u32,
+    blob_stride: u32,
+    blob_offset: u32,
 }
 
 impl GpuScanout {
@@ -454,7 +581,7 @@ struct GpuIoHandler {
 fn create_surface(
     scanout: &mut GpuScanout,
     info_set_scanout: VirtioGpuSetScanout,
-    res: &GpuResource,
+    res_image: *mut pixman_image_t,
     pixman_format: pixman_format_code_t,
     pixman_stride: libc::c_int,
     res_data_offset: *mut u32,
@@ -468,12 +595,15 @@ fn create_surface(
             res_data_offset,
             pixman_stride,
         );
-        pixman_image_ref(res.pixman_image);
-        pixman_image_set_destroy_function(
-            rect,
-            Some(virtio_gpu_unref_resource_callback),
-            res.pixman_image.cast(),
-        );
+        // Blob resource has no image, its data lives until the scanout is disabled.
+        if !res_image.is_null() {
+            pixman_image_ref(res_image);
+            pixman_image_set_destroy_function(
+                rect,
+                Some(virtio_gpu_unref_resource_callback),
+                res_image.cast(),
+            );
+        }
         surface.format = pixman_image_get_format(rect);
         surface.image = pixman_image_ref(rect);
         if !surface.image.is_null() {
@@ -521,15 +651,19 @@ pub fn get_image_hostmem(format: pixman_format_code_t, width: u32, height: u32)
 }
 
 fn is_rect_in_resource(rect: &VirtioGpuRect, res: &GpuResource) -> bool {
+    is_rect_in_size(rect, res.width, res.height)
+}
+
+fn is_rect_in_size(rect: &VirtioGpuRect, width: u32, height: u32) -> bool {
     let x_in = rect
         .x_coord
         .checked_add(rect.width)
-        .filter(|&sum| sum <= res.width)
+        .filter(|&sum| sum <= width)
         .is_some();
     let y_in = rect
         .y_coord
         .checked_add(rect.height)
-        .filter(|&sum| sum <= res.height)
+        .filter(|&sum| sum <= height)
         .is_some();
     x_in && y_in
 }
@@ -814,9 +948,14 @@ impl GpuIoHandler {
         let mut info_create_2d = VirtioGpuResourceCreate2d::default();
         self.get_request(req, &mut info_create_2d)?;
 
+        let resp_head_type = self.resource_create_2d(&info_create_2d);
+        self.response_nodata(resp_head_type, req)
+    }
+
+    fn resource_create_2d(&mut self, info_create_2d: &VirtioGpuResourceCreate2d) -> u32 {
         if info_create_2d.resource_id == 0 {
             error!("GuestError: resource id 0 is not allowed.");
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID, req);
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
         }
 
         if self.get_resource_idx(info_create_2d.resource_id).is_some() {
@@ -824,7 +963,7 @@ impl GpuIoHandler {
                 "GuestError: resource {} already exists.",
                 info_create_2d.resource_id
             );
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID, req);
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
         }
 
         let mut res = GpuResource {
@@ -838,7 +977,7 @@ impl GpuIoHandler {
             Ok(f) => f,
             Err(e) => {
                 error!("GuestError: {:?}", e);
-                return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, req);
+                return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
             }
         };
 
@@ -866,12 +1005,12 @@ impl GpuIoHandler {
                 "GuestError: Fail to create resource(id {}, width {}, height {}) on host.",
                 res.resource_id, res.width, res.height
             );
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY, req);
+            return VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY;
         }
 
         self.used_hostmem += res.host_mem;
         self.resources_list.push(res);
-        self.response_nodata(VIRTIO_GPU_RESP_OK_NODATA, req)
+        VIRTIO_GPU_RESP_OK_NODATA
     }
 
     fn resource_destroy(&mut self, res_index: usize) {
@@ -911,25 +1050,30 @@ impl GpuIoHandler {
         let mut info_set_scanout = VirtioGpuSetScanout::default();
         self.get_request(req, &mut info_set_scanout)?;
 
+        let resp_head_type = self.set_scanout(&info_set_scanout);
+        self.response_nodata(resp_head_type, req)
+    }
+
+    fn set_scanout(&mut self, info_set_scanout: &VirtioGpuSetScanout) -> u32 {
         if info_set_scanout.scanout_id >= self.num_scanouts {
             error!(
                 "GuestError: The scanout id {} is out of range.",
                 info_set_scanout.scanout_id
             );
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID, req);
+            return VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID;
         }
 
         if info_set_scanout.resource_id == 0 {
             // Set resource_id to 0 means disable the scanout.
             self.disable_scanout(info_set_scanout.scanout_id as usize);
-            return self.response_nodata(VIRTIO_GPU_RESP_OK_NODATA, req);
+            return VIRTIO_GPU_RESP_OK_NODATA;
         }
 
         // Check if resource is valid.
         let (res_idx, error) =
             self.get_backed_resource_idx(info_set_scanout.resource_id, "cmd_set_scanout");
         if res_idx.is_none() {
-            return self.response_nodata(error, req);
+            return error;
         }
 
         let res = &mut self.resources_list[res_idx.unwrap()];
@@ -948,7 +1092,7 @@ impl GpuIoHandler {
                 info_set_scanout.rect.x_coord,
                 info_set_scanout.rect.y_coord,
             );
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, req);
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
         }
 
         let pixman_format = unsafe { pixman_image_get_format(res.pixman_image) };
@@ -972,26 +1116,31 @@ impl GpuIoHandler {
         {
             let surface = create_surface(
                 scanout,
-                info_set_scanout,
-                res,
+                *info_set_scanout,
+                res.pixman_image,
                 pixman_format,
                 pixman_stride,
                 res_data_offset,
             );
             if surface.image.is_null() {
                 error!("HostError: surface image create failed, check pixman library.");
-                return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req);
+                return VIRTIO_GPU_RESP_ERR_UNSPEC;
             }
         }
 
+        self.link_scanout_resource(info_set_scanout, res_idx.unwrap());
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn link_scanout_resource(&mut self, info_set_scanout: &VirtioGpuSetScanout, res_idx: usize) {
         // Unlink old resource.
-        let old_res_id = scanout.resource_id;
+        let old_res_id = self.scanouts[info_set_scanout.scanout_id as usize].resource_id;
         if let Some(old_res_idx) = self.get_resource_idx(old_res_id) {
             let old_res = &mut self.resources_list[old_res_idx];
             old_res.scanouts_bitmask &= !(1 << info_set_scanout.scanout_id);
         }
         // Link new resource.
-        let res = &mut self.resources_list[res_idx.unwrap()];
+        let res = &mut self.resources_list[res_idx];
         res.scanouts_bitmask |= 1 << info_set_scanout.scanout_id;
         let scanout = &mut self.scanouts[info_set_scanout.scanout_id as usize];
         scanout.resource_id = info_set_scanout.resource_id;
@@ -999,8 +1148,154 @@ impl GpuIoHandler {
         scanout.y = info_set_scanout.rect.y_coord;
         scanout.width = info_set_scanout.rect.width;
         scanout.height = info_set_scanout.rect.height;
+    }
 
-        self.response_nodata(VIRTIO_GPU_RESP_OK_NODATA, req)
+    fn cmd_set_scanout_blob(&mut self, req: &VirtioGpuRequest) -> Result<()> {
+        let mut info_set_scanout = VirtioGpuSetScanoutBlob::default();
+        self.get_request(req, &mut info_set_scanout)?;
+
+        let resp_head_type = self.set_scanout_blob(&info_set_scanout);
+        self.response_nodata(resp_head_type, req)
+    }
+
+    fn set_scanout_blob(&mut self, info_set_scanout: &VirtioGpuSetScanoutBlob) -> u32 {
+        if info_set_scanout.scanout_id >= self.num_scanouts {
+            error!(
+                "GuestError: The scanout id {} is out of range.",
+                info_set_scanout.scanout_id
+            );
+            return VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID;
+        }
+
+        if info_set_scanout.resource_id == 0 {
+            self.disable_scanout(info_set_scanout.scanout_id as usize);
+            return VIRTIO_GPU_RESP_OK_NODATA;
+        }
+
+        let res_idx = match self.get_resource_idx(info_set_scanout.resource_id) {
+            Some(idx) if !self.resources_list[idx].blob.is_null() => idx,
+            _ => {
+                error!(
+                    "GuestError: The resource_id {} in set scanout blob request is not a blob resource.",
+                    info_set_scanout.resource_id
+                );
+                return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+            }
+        };
+        let pixman_format = match get_pixman_format(info_set_scanout.format) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("GuestError: {:?}", e);
+                return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+            }
+        };
+
+        let res = &self.resources_list[res_idx];
+        let width = info_set_scanout.width;
+        let height = info_set_scanout.height;
+        let bpp = (pixman_format_bpp(pixman_format as u32) as u64 + 8 - 1) / 8;
+        let stride = info_set_scanout.strides[0] as u64;
+        let offset = info_set_scanout.offsets[0] as u64;
+        // End of framebuffer in blob, `None` if it's empty or overflows.
+        let fb_end = (height as u64)
+            .checked_sub(1)
+            .and_then(|h| (stride * h).checked_add(width as u64 * bpp))
+            .and_then(|size| size.checked_add(offset));
+        if info_set_scanout.rect.width < 16
+            || info_set_scanout.rect.height < 16
+            || !is_rect_in_size(&info_set_scanout.rect, width, height)
+            || stride % 4 != 0
+            || offset % 4 != 0
+            || stride < width as u64 * bpp
+            || fb_end.filter(|&end| end <= res.blob_size).is_none()
+        {
+            error!(
+                "GuestError: The blob resource (id: {} size: {}) is outfit for scanout (id: {} width: {} height: {} stride: {} offset: {}).",
+                res.resource_id,
+                res.blob_size,
+                info_set_scanout.scanout_id,
+                width,
+                height,
+                stride,
+                offset,
+            );
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        let data_offset = offset
+            + info_set_scanout.rect.y_coord as u64 * stride
+            + info_set_scanout.rect.x_coord as u64 * bpp;
+        // SAFETY: The framebuffer has been checked to be in the blob.
+        let res_data_offset = unsafe { res.blob.add(data_offset as usize) } as *mut u32;
+
+        // Create surface on blob data directly, so it is not copied for each frame.
+        let scanout = &mut self.scanouts[info_set_scanout.scanout_id as usize];
+        if scanout.surface.is_none()
+            || unsafe { pixman_image_get_data(scanout.surface.unwrap().image) } != res_data_offset
+            || scanout.width != info_set_scanout.rect.width
+            || scanout.height != info_set_scanout.rect.height
+            || scanout.blob_format != info_set_scanout.format
+            || scanout.blob_stride != info_set_scanout.strides[0]
+        {
+            let set_scanout = VirtioGpuSetScanout {
+                rect: info_set_scanout.rect,
+                scanout_id: info_set_scanout.scanout_id,
+                resource_id: info_set_scanout.resource_id,
+            };
+            let surface = create_surface(
+                scanout,
+                set_scanout,
+                ptr::null_mut(),
+                pixman_format,
+                stride as libc::c_int,
+                res_data_offset,
+            );
+            if surface.image.is_null() {
+                error!("HostError: surface image create failed, check pixman library.");
+                return VIRTIO_GPU_RESP_ERR_UNSPEC;
+            }
+        }
+        scanout.blob_format = info_set_scanout.format;
+        scanout.blob_stride = info_set_scanout.strides[0];
+        scanout.blob_offset = info_set_scanout.offsets[0];
+
+        // Framebuffer is valid, it's the layout of blob resource from now on.
+        let res = &mut self.resources_list[res_idx];
+        res.width = width;
+        res.height = height;
+        res.format = info_set_scanout.format;
+
+        let set_scanout = VirtioGpuSetScanout {
+            rect: info_set_scanout.rect,
+            scanout_id: info_set_scanout.scanout_id,
+            resource_id: info_set_scanout.resource_id,
+        };
+        self.link_scanout_resource(&set_scanout, res_idx);
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    /// Copy the flushed lines of blob from guest memory to its shadow buffer.
+    fn update_blob_shadow(&mut self, res_idx: usize, rect: &VirtioGpuRect) {
+        let res = &mut self.resources_list[res_idx];
+        for i in 0..self.num_scanouts {
+            if res.scanouts_bitmask & (1 << i) == 0 {
+                continue;
+            }
+            let scanout = &self.scanouts[i as usize];
+            let stride = scanout.blob_stride as u64;
+            let start = scanout.blob_offset as u64 + rect.y_coord as u64 * stride;
+            let end = cmp::min(start + rect.height as u64 * stride, res.blob_size);
+            if start >= end {
+                continue;
+            }
+            let dst = &mut res.blob_shadow[start as usize..end as usize];
+            if let Err(e) = iov_to_buf_direct(&res.iov, start, dst) {
+                error!(
+                    "Failed to update blob resource {}: {:?}",
+                    res.resource_id, e
+                );
+            }
+        }
     }
 
     fn cmd_resource_flush(&mut self, req: &VirtioGpuRequest) -> Result<()> {
@@ -1026,7 +1321,11 @@ impl GpuIoHandler {
             );
             return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, req);
         }
+        if !res.blob_shadow.is_empty() {
+            self.update_blob_shadow(res_index.unwrap(), &info_res_flush.rect);
+        }
 
+        let res = &self.resources_list[res_index.unwrap()];
         unsafe {
             let mut flush_reg = pixman_region16_t::default();
             let flush_reg_ptr = &mut flush_reg as *mut pixman_region16_t;
@@ -1079,6 +1378,12 @@ impl GpuIoHandler {
         &mut self,
         info_transfer: &VirtioGpuTransferToHost2d,
     ) -> (Option<usize>, u32) {
+        // Blob resource shares data with guest, nothing needs to be transferred.
+        if let Some(res_idx) = self.get_resource_idx(info_transfer.resource_id) {
+            if !self.resources_list[res_idx].blob.is_null() {
+                return (None, VIRTIO_GPU_RESP_OK_NODATA);
+            }
+        }
         let (res_idx, error) =
             self.get_backed_resource_idx(info_transfer.resource_id, "cmd_transfer_to_host_2d");
         if res_idx.is_none() {
@@ -1188,7 +1493,7 @@ impl GpuIoHandler {
             return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID, req);
         }
 
-        let res = &mut self.resources_list[res_idx.unwrap()];
+        let res = &self.resources_list[res_idx.unwrap()];
         if !res.iov.is_empty() {
             error!(
                 "GuestError: The resource_id {} in resource attach backing request already has iov.",
@@ -1197,39 +1502,75 @@ impl GpuIoHandler {
             return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req);
         }
 
-        if info_attach_backing.nr_entries > 16384 {
+        let head_size = size_of::<VirtioGpuResourceAttachBacking>() as u64;
+        let ents = match self.get_mem_entries(req, head_size, info_attach_backing.nr_entries)? {
+            Some(ents) => ents,
+            None => return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req),
+        };
+
+        let res_idx = res_idx.unwrap();
+        if let Err(e) = self.resource_attach_backing(res_idx, ents) {
+            error!("Virtio-GPU: Map entry base failed, {:?}", e);
+            return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req);
+        }
+        // Blob resource whose backing was detached is mapped again.
+        if self.resources_list[res_idx].blob_size != 0 {
+            let resp_head_type = self.resource_map_blob(res_idx);
+            if resp_head_type != VIRTIO_GPU_RESP_OK_NODATA {
+                let res = &mut self.resources_list[res_idx];
+                res.iov.clear();
+                res.backing.clear();
+                return self.response_nodata(resp_head_type, req);
+            }
+        }
+        self.response_nodata(VIRTIO_GPU_RESP_OK_NODATA, req)
+    }
+
+    /// Read memory entries which follow the request structure of `head_size`.
+    fn get_mem_entries(
+        &self,
+        req: &VirtioGpuRequest,
+        head_size: u64,
+        nr_entries: u32,
+    ) -> Result<Option<Vec<VirtioGpuMemEntry>>> {
+        if nr_entries > 16384 {
             error!(
-                "GuestError: The nr_entries in resource attach backing request is too large ( {} > 16384).",
-                info_attach_backing.nr_entries
+                "GuestError: The nr_entries in request is too large ( {} > 16384).",
+                nr_entries
             );
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req);
+            return Ok(None);
         }
 
-        let entries = info_attach_backing.nr_entries;
-        let ents_size = size_of::<VirtioGpuMemEntry>() as u64 * entries as u64;
-        let head_size = size_of::<VirtioGpuResourceAttachBacking>() as u64;
+        let ents_size = size_of::<VirtioGpuMemEntry>() as u64 * nr_entries as u64;
         if (req.out_len as u64) < (ents_size + head_size) {
             error!(
-                "GuestError: The nr_entries {} in resource attach backing request is larger than total len {}.",
-                info_attach_backing.nr_entries, req.out_len,
+                "GuestError: The nr_entries {} in request is larger than total len {}.",
+                nr_entries, req.out_len,
             );
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req);
+            return Ok(None);
         }
 
         // Start reading and parsing.
         let mut ents = Vec::<VirtioGpuMemEntry>::new();
-        ents.resize(entries as usize, VirtioGpuMemEntry::default());
+        ents.resize(nr_entries as usize, VirtioGpuMemEntry::default());
         let ents_buf =
             unsafe { from_raw_parts_mut(ents.as_mut_ptr() as *mut u8, ents_size as usize) };
         let v = iov_to_buf_direct(&req.out_iovec, head_size, ents_buf)?;
         if v as u64 != ents_size {
             error!(
-                "Virtio-GPU: Load no enough ents buf when attach backing, {} vs {}",
+                "Virtio-GPU: Load no enough ents buf, {} vs {}",
                 v, ents_size
             );
-            return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req);
+            return Ok(None);
         }
+        Ok(Some(ents))
+    }
 
+    fn resource_attach_backing(
+        &mut self,
+        res_idx: usize,
+        ents: Vec<VirtioGpuMemEntry>,
+    ) -> Result<()> {
         let mut elemiovec = Vec::with_capacity(ents.len());
         for ent in ents.iter() {
             elemiovec.push(ElemIovec {
@@ -1237,22 +1578,161 @@ impl GpuIoHandler {
                 len: ent.length,
             });
         }
-        match gpa_hva_iovec_map(&elemiovec, &self.mem_space) {
-            Ok((_, iov)) => {
-                res.iov = iov;
-                self.response_nodata(VIRTIO_GPU_RESP_OK_NODATA, req)
-            }
+        let (_, iov) = gpa_hva_iovec_map(&elemiovec, &self.mem_space)?;
+        let res = &mut self.resources_list[res_idx];
+        res.iov = iov;
+        res.backing = ents;
+        Ok(())
+    }
+
+    fn cmd_resource_create_blob(&mut self, req: &VirtioGpuRequest) -> Result<()> {
+        let mut info_create_blob = VirtioGpuResourceCreateBlob::default();
+        self.get_request(req, &mut info_create_blob)?;
+
+        if self.driver_features & (1 << VIRTIO_GPU_F_RESOURCE_BLOB) == 0 {
+            error!("GuestError: The resource blob feature is not negotiated.");
+            return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, req);
+        }
+        if info_create_blob.blob_mem != VIRTIO_GPU_BLOB_MEM_GUEST {
+            error!(
+                "GuestError: The blob_mem {} of resource {} is not supported.",
+                info_create_blob.blob_mem, info_create_blob.resource_id
+            );
+            return self.response_nodata(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, req);
+        }
+
+        let head_size = size_of::<VirtioGpuResourceCreateBlob>() as u64;
+        let ents = match self.get_mem_entries(req, head_size, info_create_blob.nr_entries)? {
+            Some(ents) => ents,
+            None => return self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req),
+        };
+
+        let resp_head_type = self.resource_create_blob(&info_create_blob, ents);
+        self.response_nodata(resp_head_type, req)
+    }
+
+    fn resource_create_blob(
+        &mut self,
+        info_create_blob: &VirtioGpuResourceCreateBlob,
+        ents: Vec<VirtioGpuMemEntry>,
+    ) -> u32 {
+        if info_create_blob.resource_id == 0 {
+            error!("GuestError: resource id 0 is not allowed.");
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        }
+        if self
+            .get_resource_idx(info_create_blob.resource_id)
+            .is_some()
+        {
+            error!(
+                "GuestError: resource {} already exists.",
+                info_create_blob.resource_id
+            );
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        }
+        if info_create_blob.size == 0 || ents.is_empty() {
+            error!(
+                "GuestError: The blob resource {} has no backing.",
+                info_create_blob.resource_id
+            );
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        self.resources_list.push(GpuResource {
+            resource_id: info_create_blob.resource_id,
+            blob_size: info_create_blob.size,
+            ..Default::default()
+        });
+        let res_idx = self.resources_list.len() - 1;
+        let resp_head_type = match self.resource_attach_backing(res_idx, ents) {
+            Ok(()) => self.resource_map_blob(res_idx),
             Err(e) => {
                 error!("Virtio-GPU: Map entry base failed, {:?}", e);
-                self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req)
+                VIRTIO_GPU_RESP_ERR_UNSPEC
+            }
+        };
+        if resp_head_type != VIRTIO_GPU_RESP_OK_NODATA {
+            self.resources_list.pop();
+        }
+        resp_head_type
+    }
+
+    /// Map blob data to host. Guest memory is used directly if the backing is
+    /// contiguous in host, otherwise it's copied to a shadow buffer and
+    /// synchronized when flushing.
+    fn resource_map_blob(&mut self, res_idx: usize) -> u32 {
+        let res = &mut self.resources_list[res_idx];
+        let backing_size: u64 = res.iov.iter().map(|iov| iov.iov_len).sum();
+        if backing_size < res.blob_size {
+            error!(
+                "GuestError: The backing size {} of blob resource {} is less than {}.",
+                backing_size, res.resource_id, res.blob_size
+            );
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        let contiguous = res
+            .iov
+            .windows(2)
+            .all(|iov| iov[0].iov_base + iov[0].iov_len == iov[1].iov_base);
+        if contiguous {
+            res.blob = res.iov[0].iov_base as *mut u8;
+            return VIRTIO_GPU_RESP_OK_NODATA;
+        }
+
+        if res
+            .blob_size
+            .checked_add(self.used_hostmem)
+            .filter(|&sum| sum <= self.max_hostmem)
+            .is_none()
+        {
+            error!(
+                "GuestError: Fail to create shadow of blob resource(id {}, size {}) on host.",
+                res.resource_id, res.blob_size
+            );
+            return VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY;
+        }
+        res.blob_shadow = vec![0; res.blob_size as usize];
+        if let Err(e) = iov_to_buf_direct(&res.iov, 0, &mut res.blob_shadow) {
+            error!("Failed to copy blob resource {}: {:?}", res.resource_id, e);
+            return VIRTIO_GPU_RESP_ERR_UNSPEC;
+        }
+        res.blob = res.blob_shadow.as_mut_ptr();
+        res.host_mem = res.blob_size;
+        self.used_hostmem += res.host_mem;
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    /// Unmap blob data, and disable the scanouts showing it.
+    fn resource_unmap_blob(&mut self, res_idx: usize) {
+        let scanouts_bitmask = self.resources_list[res_idx].scanouts_bitmask;
+        for i in 0..self.num_scanouts {
+            if (scanouts_bitmask & (1 << i)) != 0 {
+                self.disable_scanout(i as usize);
             }
         }
+
+        let res = &mut self.resources_list[res_idx];
+        res.blob = ptr::null_mut();
+        res.blob_shadow = Vec::new();
+        self.used_hostmem -= res.host_mem;
+        res.host_mem = 0;
     }
 
     fn cmd_resource_detach_backing(&mut self, req: &VirtioGpuRequest) -> Result<()> {
         let mut info_detach_backing = VirtioGpuResourceDetachBacking::default();
         self.get_request(req, &mut info_detach_backing)?;
 
+        if let Some(res_idx) = self.get_resource_idx(info_detach_backing.resource_id) {
+            if !self.resources_list[res_idx].blob.is_null() {
+                self.resource_unmap_blob(res_idx);
+                let res = &mut self.resources_list[res_idx];
+                res.iov.clear();
+                res.backing.clear();
+                return self.response_nodata(VIRTIO_GPU_RESP_OK_NODATA, req);
+            }
+        }
+
         let (res_idx, error) = self.get_backed_resource_idx(
             info_detach_backing.resource_id,
             "cmd_resource_detach_backing",
@@ -1261,10 +1741,161 @@ impl GpuIoHandler {
             return self.response_nodata(error, req);
         }
 
-        self.resources_list[res_idx.unwrap()].iov.clear();
+        let res = &mut self.resources_list[res_idx.unwrap()];
+        res.iov.clear();
+        res.backing.clear();
         self.response_nodata(VIRTIO_GPU_RESP_OK_NODATA, req)
     }
 
+    fn save_state(&self, var_data: &mut Vec<u8>) {
+        for res in self.resources_list.iter() {
+            let res_state = GpuResourceState {
+                resource_id: res.resource_id,
+                format: res.format,
+                width: res.width,
+                height: res.height,
+                nr_entries: res.backing.len() as u32,
+                blob_size: res.blob_size,
+                ..Default::default()
+            };
+            var_data.extend_from_slice(res_state.as_bytes());
+            for ent in res.backing.iter() {
+                var_data.extend_from_slice(ent.as_bytes());
+            }
+        }
+    }
+
+    /// Recreate resources and scanouts from migrated state, content of
+    /// resources is transferred from backing again.
+    fn restore_state(&mut self, state: GpuMigratedState) -> Result<()> {
+        self.enable_output_bitmask = state.enable_output_bitmask;
+
+        for (res_state, backing) in state.resources {
+            // Blob resource whose backing is detached is not mapped.
+            if res_state.blob_size != 0 && backing.is_empty() {
+                self.resources_list.push(GpuResource {
+                    resource_id: res_state.resource_id,
+                    format: res_state.format,
+                    width: res_state.width,
+                    height: res_state.height,
+                    blob_size: res_state.blob_size,
+                    ..Default::default()
+                });
+                continue;
+            }
+            if res_state.blob_size != 0 {
+                let info_create_blob = VirtioGpuResourceCreateBlob {
+                    resource_id: res_state.resource_id,
+                    blob_mem: VIRTIO_GPU_BLOB_MEM_GUEST,
+                    nr_entries: backing.len() as u32,
+                    size: res_state.blob_size,
+                    ..Default::default()
+                };
+                let resp_head_type = self.resource_create_blob(&info_create_blob, backing);
+                if resp_head_type != VIRTIO_GPU_RESP_OK_NODATA {
+                    bail!(
+                        "Failed to restore blob resource {}, error {:#x}",
+                        res_state.resource_id,
+                        resp_head_type
+                    );
+                }
+                let res = self.resources_list.last_mut().unwrap();
+                res.width = res_state.width;
+                res.height = res_state.height;
+                res.format = res_state.format;
+                continue;
+            }
+
+            let info_create_2d = VirtioGpuResourceCreate2d {
+                resource_id: res_state.resource_id,
+                format: res_state.format,
+                width: res_state.width,
+                height: res_state.height,
+            };
+            let resp_head_type = self.resource_create_2d(&info_create_2d);
+            if resp_head_type != VIRTIO_GPU_RESP_OK_NODATA {
+                bail!(
+                    "Failed to restore resource {}, error {:#x}",
+                    res_state.resource_id,
+                    resp_head_type
+                );
+            }
+            if backing.is_empty() {
+                continue;
+            }
+
+            let res_idx = self.resources_list.len() - 1;
+            self.resource_attach_backing(res_idx, backing)?;
+            if res_state.resource_id & VIRTIO_GPU_RES_WIN_FRAMEBUF == 0 {
+                let trans_info = VirtioGpuTransferToHost2d {
+                    rect: VirtioGpuRect {
+                        x_coord: 0,
+                        y_coord: 0,
+                        width: res_state.width,
+                        height: res_state.height,
+                    },
+                    resource_id: res_state.resource_id,
+                    ..Default::default()
+                };
+                self.cmd_transfer_to_host_2d_update_resource(&trans_info, res_idx)?;
+            }
+        }
+
+        for (scanout_id, scanout) in state.scanouts.iter().enumerate() {
+            if scanout.resource_id == 0 || scanout_id >= self.scanouts.len() {
+                continue;
+            }
+            let rect = VirtioGpuRect {
+                x_coord: scanout.x,
+                y_coord: scanout.y,
+                width: scanout.width,
+                height: scanout.height,
+            };
+            let blob_res = self
+                .get_resource_idx(scanout.resource_id)
+                .map(|idx| &self.resources_list[idx])
+                .filter(|res| res.blob_size != 0)
+                .map(|res| (res.width, res.height));
+            let resp_head_type = if let Some((width, height)) = blob_res {
+                let info_set_scanout = VirtioGpuSetScanoutBlob {
+                    rect,
+                    scanout_id: scanout_id as u32,
+                    resource_id: scanout.resource_id,
+                    width,
+                    height,
+                    format: scanout.format,
+                    strides: [scanout.stride, 0, 0, 0],
+                    offsets: [scanout.offset, 0, 0, 0],
+                    ..Default::default()
+                };
+                self.set_scanout_blob(&info_set_scanout)
+            } else {
+                let info_set_scanout = VirtioGpuSetScanout {
+                    rect,
+                    scanout_id: scanout_id as u32,
+                    resource_id: scanout.resource_id,
+                };
+                self.set_scanout(&info_set_scanout)
+            };
+            if resp_head_type != VIRTIO_GPU_RESP_OK_NODATA {
+                bail!(
+                    "Failed to restore scanout {}, error {:#x}",
+                    scanout_id,
+                    resp_head_type
+                );
+            }
+            display_graphic_update(
+                &self.scanouts[scanout_id].con,
+                0,
+                0,
+                scanout.width as i32,
+                scanout.height as i32,
+            )?;
+        }
+
+        Ok(())
+    }
+
     fn process_control_queue(&mut self, mut req_queue: Vec<VirtioGpuRequest>) -> Result<()> {
         for req in req_queue.iter_mut() {
             if let Err(e) = match req.header.hdr_type {
@@ -1277,6 +1908,8 @@ impl GpuIoHandler {
                 VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => self.cmd_resource_attach_backing(req),
                 VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => self.cmd_resource_detach_backing(req),
                 VIRTIO_GPU_CMD_GET_EDID => self.cmd_get_edid(req),
+                VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB => self.cmd_resource_create_blob(req),
+                VIRTIO_GPU_CMD_SET_SCANOUT_BLOB => self.cmd_set_scanout_blob(req),
                 _ => self.response_nodata(VIRTIO_GPU_RESP_ERR_UNSPEC, req),
             } {
                 error!("Fail to handle GPU request, {:?}.", e);
@@ -1436,6 +2069,10 @@ pub struct Gpu {
     output_states: Arc<Mutex<[VirtioGpuOutputState; VIRTIO_GPU_MAX_OUTPUTS]>>,
     /// Each console corresponds to a display.
     consoles: Vec<Option<Weak<Mutex<DisplayConsole>>>>,
+    /// The handler of activated device.
+    handler: Option<Arc<Mutex<GpuIoHandler>>>,
+    /// State restored from migration, it's applied when activating.
+    migrated_state: Option<GpuMigratedState>,
 }
 
 /// SAFETY: The raw pointer in rust doesn't impl Send, all write operations
@@ -1476,8 +2113,10 @@ impl VirtioDevice for Gpu {
         }
 
         let mut output_states = self.output_states.lock().unwrap();
-        output_states[0].width = self.cfg.xres;
-        output_states[0].height = self.cfg.yres;
+        for output_state in output_states.iter_mut().take(self.cfg.max_outputs as usize) {
+            output_state.width = self.cfg.xres;
+            output_state.height = self.cfg.yres;
+        }
 
         let gpu_opts = Arc::new(GpuOpts {
             output_states: self.output_states.clone(),
@@ -1505,6 +2144,9 @@ impl VirtioDevice for Gpu {
         if self.cfg.edid {
             self.base.device_features |= 1 << VIRTIO_GPU_F_EDID;
         }
+        if self.cfg.blob {
+            self.base.device_features |= 1 << VIRTIO_GPU_F_RESOURCE_BLOB;
+        }
         self.build_device_config_space();
         Ok(())
     }
@@ -1514,7 +2156,7 @@ impl VirtioDevice for Gpu {
             console_close(con)?;
         }
 
-        // TODO: support migration
+        MigrationManager::unregister_device_instance(GpuState::descriptor(), &self.cfg.id);
         Ok(())
     }
 
@@ -1569,7 +2211,7 @@ impl VirtioDevice for Gpu {
             scanouts.push(scanout);
         }
 
-        let handler = GpuIoHandler {
+        let mut handler = GpuIoHandler {
             ctrl_queue: queues[0].clone(),
             cursor_queue: queues[1].clone(),
             mem_space,
@@ -1578,7 +2220,8 @@ impl VirtioDevice for Gpu {
             interrupt_cb,
             driver_features: self.base.driver_features,
             resources_list: Vec::new(),
-            enable_output_bitmask: 1,
+            // Each output is a console which can be displayed separately.
+            enable_output_bitmask: (1 << self.cfg.max_outputs) - 1,
             num_scanouts: self.cfg.max_outputs,
             output_states: self.output_states.clone(),
             scanouts,
@@ -1586,8 +2229,16 @@ impl VirtioDevice for Gpu {
             used_hostmem: 0,
         };
 
-        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        if let Some(state) = self.migrated_state.take() {
+            handler
+                .restore_state(state)
+                .with_context(|| "Failed to restore gpu state")?;
+        }
+
+        let handler = Arc::new(Mutex::new(handler));
+        let notifiers = EventNotifierHelper::internal_notifiers(handler.clone());
         register_event_helper(notifiers, None, &mut self.base.deactivate_evts)?;
+        self.handler = Some(handler);
 
         Ok(())
     }
@@ -1597,6 +2248,317 @@ impl VirtioDevice for Gpu {
             display_set_major_screen("ramfb")?;
             set_run_stage(VmRunningStage::Bios);
         }
+        self.handler = None;
         unregister_event_helper(None, &mut self.base.deactivate_evts)
     }
 }
+
+impl StateTransfer for Gpu {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        let handler = self.handler.as_ref().map(|h| h.lock().unwrap());
+        let output_states = self.output_states.lock().unwrap();
+        let mut state = GpuState {
+            events_read: self.config_space.lock().unwrap().events_read,
+            nr_outputs: self.cfg.max_outputs,
+            ..Default::default()
+        };
+
+        let mut var_data = Vec::new();
+        for (scanout_id, output_state) in output_states
+            .iter()
+            .enumerate()
+            .take(self.cfg.max_outputs as usize)
+        {
+            let mut scanout_state = GpuScanoutState {
+                output_width: output_state.width,
+                output_height: output_state.height,
+                ..Default::default()
+            };
+            if let Some(scanout) = handler.as_ref().and_then(|h| h.scanouts.get(scanout_id)) {
+                scanout_state.resource_id = scanout.resource_id;
+                scanout_state.x = scanout.x;
+                scanout_state.y = scanout.y;
+                scanout_state.width = scanout.width;
+                scanout_state.height = scanout.height;
+                scanout_state.format = scanout.blob_format;
+                scanout_state.stride = scanout.blob_stride;
+                scanout_state.offset = scanout.blob_offset;
+            }
+            var_data.extend_from_slice(scanout_state.as_bytes());
+        }
+        if let Some(handler) = &handler {
+            state.enable_output_bitmask = handler.enable_output_bitmask;
+            state.nr_resources = handler.resources_list.len() as u32;
+            handler.save_state(&mut var_data);
+        }
+
+        Ok(build_var_state(state.as_bytes(), &var_data))
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        let (state, mut var_data) = split_var_state(state, size_of::<GpuState>())?;
+        let state = *GpuState::from_bytes(state)
+            .with_context(|| migration::error::MigrationError::FromBytesError("GPU"))?;
+        if state.nr_outputs != self.cfg.max_outputs {
+            bail!(
+                "Migrated gpu outputs {} mismatch with {}",
+                state.nr_outputs,
+                self.cfg.max_outputs
+            );
+        }
+
+        let mut migrated_state = GpuMigratedState {
+            enable_output_bitmask: state.enable_output_bitmask,
+            ..Default::default()
+        };
+        let mut output_states = self.output_states.lock().unwrap();
+        for output_state in output_states.iter_mut().take(state.nr_outputs as usize) {
+            let scanout_state = read_state::<GpuScanoutState>(&mut var_data)?;
+            output_state.width = scanout_state.output_width;
+            output_state.height = scanout_state.output_height;
+            migrated_state.scanouts.push(scanout_state);
+        }
+        drop(output_states);
+        for _ in 0..state.nr_resources {
+            let res_state = read_state::<GpuResourceState>(&mut var_data)?;
+            if res_state.nr_entries > 16384 {
+                bail!("Too many backing entries {}", res_state.nr_entries);
+            }
+            let mut backing = Vec::with_capacity(res_state.nr_entries as usize);
+            for _ in 0..res_state.nr_entries {
+                backing.push(read_state::<VirtioGpuMemEntry>(&mut var_data)?);
+            }
+            migrated_state.resources.push((res_state, backing));
+        }
+        self.config_space.lock().unwrap().events_read = state.events_read;
+        self.migrated_state = Some(migrated_state);
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        MigrationManager::get_desc_alias(&GpuState::descriptor().name).unwrap_or(!0)
+    }
+}
+
+impl MigrationHook for Gpu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{QueueConfig, QUEUE_TYPE_SPLIT_VRING};
+    use address_space::{HostMemMapping, Region};
+
+    const MEM_SIZE: u64 = 1 << 20;
+    const BLOB_SIZE: u64 = 64 * 64 * 4;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36, "sysmem");
+        let sys_space = AddressSpace::new(root, "sysmem").unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, MEM_SIZE, None, false, false, false)
+                .unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone(), "sysmem"),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    fn gpu_handler_init() -> GpuIoHandler {
+        let queue = || {
+            let queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
+            Arc::new(Mutex::new(
+                Queue::new(queue_config, QUEUE_TYPE_SPLIT_VRING).unwrap(),
+            ))
+        };
+        let interrupt_cb = Arc::new(Box::new(
+            |_int_type: &VirtioInterruptType, _queue: Option<&Queue>, _needs_reset: bool| Ok(()),
+        ) as VirtioInterrupt);
+
+        GpuIoHandler {
+            ctrl_queue: queue(),
+            cursor_queue: queue(),
+            mem_space: address_space_init(),
+            ctrl_queue_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()),
+            cursor_queue_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()),
+            interrupt_cb,
+            driver_features: 1 << VIRTIO_GPU_F_RESOURCE_BLOB,
+            resources_list: Vec::new(),
+            enable_output_bitmask: 1,
+            num_scanouts: 1,
+            output_states: Arc::new(Mutex::new(
+                [VirtioGpuOutputState::default(); VIRTIO_GPU_MAX_OUTPUTS],
+            )),
+            scanouts: vec![GpuScanout::default()],
+            max_hostmem: MEM_SIZE,
+            used_hostmem: 0,
+        }
+    }
+
+    fn blob_resource(resource_id: u32) -> GpuResource {
+        let mut res = GpuResource {
+            resource_id,
+            blob_size: BLOB_SIZE,
+            blob_shadow: vec![0; BLOB_SIZE as usize],
+            ..Default::default()
+        };
+        res.blob = res.blob_shadow.as_mut_ptr();
+        res
+    }
+
+    fn mem_entry(addr: u64, length: u32) -> VirtioGpuMemEntry {
+        VirtioGpuMemEntry {
+            addr,
+            length,
+            padding: 0,
+        }
+    }
+
+    #[test]
+    fn test_resource_create_blob_rejected() {
+        let mut handler = gpu_handler_init();
+        handler.resources_list.push(blob_resource(1));
+        let mut info = VirtioGpuResourceCreateBlob {
+            resource_id: 0,
+            blob_mem: VIRTIO_GPU_BLOB_MEM_GUEST,
+            nr_entries: 1,
+            size: BLOB_SIZE,
+            ..Default::default()
+        };
+
+        // Resource id 0 is reserved.
+        let ents = vec![mem_entry(0, BLOB_SIZE as u32)];
+        assert_eq!(
+            handler.resource_create_blob(&info, ents.clone()),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+
+        // Resource id is used.
+        info.resource_id = 1;
+        assert_eq!(
+            handler.resource_create_blob(&info, ents.clone()),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+
+        // No backing.
+        info.resource_id = 2;
+        assert_eq!(
+            handler.resource_create_blob(&info, Vec::new()),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        info.size = 0;
+        assert_eq!(
+            handler.resource_create_blob(&info, ents),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+
+        // Backing is smaller than blob.
+        info.size = BLOB_SIZE;
+        let ents = vec![mem_entry(0, BLOB_SIZE as u32 - 4)];
+        assert_eq!(
+            handler.resource_create_blob(&info, ents),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+
+        // Backing is out of guest memory.
+        let ents = vec![mem_entry(MEM_SIZE, BLOB_SIZE as u32)];
+        assert_eq!(
+            handler.resource_create_blob(&info, ents),
+            VIRTIO_GPU_RESP_ERR_UNSPEC
+        );
+
+        assert_eq!(handler.resources_list.len(), 1);
+        assert_eq!(handler.used_hostmem, 0);
+    }
+
+    #[test]
+    fn test_set_scanout_blob_rejected() {
+        let mut handler = gpu_handler_init();
+        handler.resources_list.push(blob_resource(1));
+        handler.resources_list.push(GpuResource {
+            resource_id: 2,
+            width: 64,
+            height: 64,
+            ..Default::default()
+        });
+        let valid = VirtioGpuSetScanoutBlob {
+            rect: VirtioGpuRect {
+                x_coord: 0,
+                y_coord: 0,
+                width: 64,
+                height: 64,
+            },
+            scanout_id: 0,
+            resource_id: 1,
+            width: 64,
+            height: 64,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            strides: [64 * 4, 0, 0, 0],
+            ..Default::default()
+        };
+
+        let mut info = valid;
+        info.scanout_id = 1;
+        assert_eq!(
+            handler.set_scanout_blob(&info),
+            VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID
+        );
+
+        // Resource doesn't exist or is not a blob.
+        for resource_id in [2, 3] {
+            let mut info = valid;
+            info.resource_id = resource_id;
+            assert_eq!(
+                handler.set_scanout_blob(&info),
+                VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+            );
+        }
+
+        let mut invalid = Vec::new();
+        let mut info = valid;
+        info.format = 0;
+        invalid.push(info);
+        // Rectangle is too small or out of framebuffer.
+        let mut info = valid;
+        info.rect.width = 8;
+        invalid.push(info);
+        let mut info = valid;
+        info.rect.x_coord = 16;
+        invalid.push(info);
+        // Stride is unaligned or too small.
+        let mut info = valid;
+        info.strides[0] = 64 * 4 + 2;
+        invalid.push(info);
+        let mut info = valid;
+        info.strides[0] = 32 * 4;
+        invalid.push(info);
+        // Framebuffer is out of blob.
+        let mut info = valid;
+        info.offsets[0] = 4;
+        invalid.push(info);
+        let mut info = valid;
+        info.height = 65;
+        info.rect.height = 65;
+        invalid.push(info);
+        let mut info = valid;
+        info.height = u32::MAX;
+        info.strides[0] = u32::MAX - 3;
+        invalid.push(info);
+
+        for info in invalid.iter() {
+            assert_eq!(
+                handler.set_scanout_blob(info),
+                VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+            );
+            // Layout of blob resource is not changed by rejected request.
+            let res = &handler.resources_list[0];
+            assert_eq!((res.width, res.height, res.format), (0, 0, 0));
+            assert_eq!(res.scanouts_bitmask, 0);
+        }
+    }
+}
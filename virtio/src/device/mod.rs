@@ -18,3 +18,5 @@ pub mod net;
 pub mod rng;
 pub mod scsi_cntlr;
 pub mod serial;
+#[cfg(not(target_env = "musl"))]
+pub mod sound;
@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::cmp;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
@@ -273,12 +274,16 @@ impl VirtioDevice for ScsiCntlr {
         let bus = self.bus.as_ref().unwrap();
         let locked_bus = bus.lock().unwrap();
         for device in locked_bus.devices.values() {
-            let locked_device = device.lock().unwrap();
-            let err_cb = self.gen_error_cb(interrupt_cb.clone());
-            // SAFETY: the disk_image is assigned after device realized.
-            let disk_image = locked_device.block_backend.as_ref().unwrap();
-            let mut locked_backend = disk_image.lock().unwrap();
-            locked_backend.register_io_event(self.base.broken.clone(), err_cb)?;
+            let mut locked_device = device.lock().unwrap();
+            if let Some(sg) = locked_device.sg.as_mut() {
+                sg.register_io_event(self.config.iothread.as_ref())?;
+            }
+            // The disk image is absent for scsi-generic device and the ejected medium.
+            if let Some(disk_image) = locked_device.block_backend.as_ref() {
+                let err_cb = self.gen_error_cb(interrupt_cb.clone());
+                let mut locked_backend = disk_image.lock().unwrap();
+                locked_backend.register_io_event(self.base.broken.clone(), err_cb)?;
+            }
         }
         Ok(())
     }
@@ -291,11 +296,14 @@ impl VirtioDevice for ScsiCntlr {
         let bus = self.bus.as_ref().unwrap();
         let locked_bus = bus.lock().unwrap();
         for device in locked_bus.devices.values() {
-            let locked_dev = device.lock().unwrap();
-            // SAFETY: the disk_image is assigned after device realized.
-            let disk_image = locked_dev.block_backend.as_ref().unwrap();
-            let mut locked_backend = disk_image.lock().unwrap();
-            locked_backend.unregister_io_event()?;
+            let mut locked_dev = device.lock().unwrap();
+            if let Some(sg) = locked_dev.sg.as_mut() {
+                sg.unregister_io_event()?;
+            }
+            if let Some(disk_image) = locked_dev.block_backend.as_ref() {
+                let mut locked_backend = disk_image.lock().unwrap();
+                locked_backend.unregister_io_event()?;
+            }
         }
         Ok(())
     }
@@ -409,6 +417,12 @@ impl VirtioScsiCmdResp {
         self.sense[13] = sense.ascq;
         self.sense_len = SCSI_SENSE_LEN;
     }
+
+    fn set_raw_sense(&mut self, sense: &[u8]) {
+        let len = cmp::min(sense.len(), VIRTIO_SCSI_SENSE_DEFAULT_SIZE);
+        self.sense[..len].copy_from_slice(&sense[..len]);
+        self.sense_len = len as u32;
+    }
 }
 
 impl ByteCode for VirtioScsiCmdResp {}
@@ -730,6 +744,16 @@ impl ScsiRequestOps for CmdQueueRequest {
 
         Ok(())
     }
+
+    fn scsi_request_complete_raw_cb(&mut self, status: u8, sense: &[u8], resid: u32) -> Result<()> {
+        self.resp.set_raw_sense(sense);
+        self.resp.resid = resid;
+        self.resp.response = VIRTIO_SCSI_S_OK;
+        self.resp.status = status;
+        self.complete()?;
+
+        Ok(())
+    }
 }
 
 //   lun: [u8, 8]
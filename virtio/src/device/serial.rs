@@ -353,7 +353,10 @@ pub struct SerialPort {
 impl SerialPort {
     pub fn new(port_cfg: VirtioSerialPort) -> Self {
         // Console is default host connected. And pty chardev has opened by default in realize() function.
-        let host_connected = port_cfg.is_console || port_cfg.chardev.backend == ChardevType::Pty;
+        // Clipboard chardev is inside stratovirt, so it is always connected.
+        let host_connected = port_cfg.is_console
+            || port_cfg.chardev.backend == ChardevType::Pty
+            || port_cfg.chardev.backend == ChardevType::Clipboard;
 
         SerialPort {
             name: Some(port_cfg.id),
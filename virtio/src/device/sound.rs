@@ -0,0 +1,1119 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, warn};
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+
+use address_space::AddressSpace;
+use devices::misc::scream::{
+    create_audio_interface, AudioInterface, ScreamDirection, ShmemStreamFmt, StreamData,
+};
+use machine_manager::config::{SoundConfig, DEFAULT_VIRTQUEUE_SIZE};
+use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use ui::vnc::vnc_audio_play;
+use util::aio::{iov_discard_front_direct, iov_from_buf_direct, Iovec};
+use util::byte_code::ByteCode;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+
+use crate::{
+    gpa_hva_iovec_map, iov_discard_front, iov_to_buf, read_config_default, Element, Queue,
+    VirtioBase, VirtioDevice, VirtioError, VirtioInterrupt, VirtioInterruptType,
+    VIRTIO_F_VERSION_1, VIRTIO_TYPE_SOUND,
+};
+
+/// Number of virtqueues: control, event, tx and rx.
+const QUEUE_NUM_SOUND: usize = 4;
+const SND_CTRL_QUEUE: usize = 0;
+const SND_TX_QUEUE: usize = 2;
+const SND_RX_QUEUE: usize = 3;
+
+/// Jack control request types.
+const VIRTIO_SND_R_JACK_INFO: u32 = 1;
+const VIRTIO_SND_R_JACK_REMAP: u32 = 2;
+/// PCM control request types.
+const VIRTIO_SND_R_PCM_INFO: u32 = 0x0100;
+const VIRTIO_SND_R_PCM_SET_PARAMS: u32 = 0x0101;
+const VIRTIO_SND_R_PCM_PREPARE: u32 = 0x0102;
+const VIRTIO_SND_R_PCM_RELEASE: u32 = 0x0103;
+const VIRTIO_SND_R_PCM_START: u32 = 0x0104;
+const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
+/// Channel map control request types.
+const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
+
+/// Common status codes.
+const VIRTIO_SND_S_OK: u32 = 0x8000;
+const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
+const VIRTIO_SND_S_NOT_SUPP: u32 = 0x8002;
+const VIRTIO_SND_S_IO_ERR: u32 = 0x8003;
+
+/// Data flow directions.
+const VIRTIO_SND_D_OUTPUT: u8 = 0;
+const VIRTIO_SND_D_INPUT: u8 = 1;
+
+/// Supported PCM sample formats, and the number of sampling bits of them.
+const VIRTIO_SND_PCM_FMT_S16: u8 = 5;
+const VIRTIO_SND_PCM_FMT_S24_3: u8 = 11;
+const VIRTIO_SND_PCM_FMT_S32: u8 = 17;
+const SND_PCM_FORMATS: [(u8, u8); 3] = [
+    (VIRTIO_SND_PCM_FMT_S16, 16),
+    (VIRTIO_SND_PCM_FMT_S24_3, 24),
+    (VIRTIO_SND_PCM_FMT_S32, 32),
+];
+
+/// Supported PCM frame rates, which can be handled by the host audio interfaces.
+const VIRTIO_SND_PCM_RATE_44100: u8 = 6;
+const VIRTIO_SND_PCM_RATE_48000: u8 = 7;
+const VIRTIO_SND_PCM_RATE_88200: u8 = 9;
+const VIRTIO_SND_PCM_RATE_96000: u8 = 10;
+const VIRTIO_SND_PCM_RATE_176400: u8 = 11;
+const VIRTIO_SND_PCM_RATE_192000: u8 = 12;
+const SND_PCM_RATES: [(u8, u32); 6] = [
+    (VIRTIO_SND_PCM_RATE_44100, 44100),
+    (VIRTIO_SND_PCM_RATE_48000, 48000),
+    (VIRTIO_SND_PCM_RATE_88200, 88200),
+    (VIRTIO_SND_PCM_RATE_96000, 96000),
+    (VIRTIO_SND_PCM_RATE_176400, 176400),
+    (VIRTIO_SND_PCM_RATE_192000, 192000),
+];
+
+const SND_PCM_CHANNELS_MIN: u8 = 1;
+const SND_PCM_CHANNELS_MAX: u8 = 2;
+/// Max bytes of the PCM buffer, which bounds the host buffer of one PCM I/O message.
+const SND_PCM_BUFFER_BYTES_MAX: u32 = 4 * 1024 * 1024;
+
+/// Standard channel positions.
+const VIRTIO_SND_CHMAP_FL: u8 = 3;
+const VIRTIO_SND_CHMAP_FR: u8 = 4;
+const VIRTIO_SND_CHMAP_MAX_SIZE: usize = 18;
+
+/// Pin default configuration of jacks: line out (green) and mic in (pink),
+/// both are 1/8" jacks located at external rear.
+const SND_JACK_DEFCONF_LINE_OUT: u32 = 0x0101_4010;
+const SND_JACK_DEFCONF_MIC_IN: u32 = 0x01a1_9020;
+/// Pin capabilities of jacks: presence detect and output/input capable.
+const SND_JACK_CAPS_OUT: u32 = 0x14;
+const SND_JACK_CAPS_IN: u32 = 0x24;
+
+/// Directions of the PCM streams, jacks and channel maps. Stream 0 is
+/// playback and stream 1 is capture.
+const SND_STREAM_DIRECTIONS: [u8; 2] = [VIRTIO_SND_D_OUTPUT, VIRTIO_SND_D_INPUT];
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndConfig {
+    /// Number of available jacks.
+    jacks: u32,
+    /// Number of available PCM streams.
+    streams: u32,
+    /// Number of available channel maps.
+    chmaps: u32,
+}
+
+impl ByteCode for VirtioSndConfig {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndHdr {
+    code: u32,
+}
+
+impl ByteCode for VirtioSndHdr {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndQueryInfo {
+    hdr: VirtioSndHdr,
+    start_id: u32,
+    count: u32,
+    size: u32,
+}
+
+impl ByteCode for VirtioSndQueryInfo {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndInfo {
+    hda_fn_nid: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndJackInfo {
+    hdr: VirtioSndInfo,
+    features: u32,
+    hda_reg_defconf: u32,
+    hda_reg_caps: u32,
+    connected: u8,
+    padding: [u8; 7],
+}
+
+impl ByteCode for VirtioSndJackInfo {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndPcmInfo {
+    hdr: VirtioSndInfo,
+    features: u32,
+    formats: u64,
+    rates: u64,
+    direction: u8,
+    channels_min: u8,
+    channels_max: u8,
+    padding: [u8; 5],
+}
+
+impl ByteCode for VirtioSndPcmInfo {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndChmapInfo {
+    hdr: VirtioSndInfo,
+    direction: u8,
+    channels: u8,
+    positions: [u8; VIRTIO_SND_CHMAP_MAX_SIZE],
+}
+
+impl ByteCode for VirtioSndChmapInfo {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndPcmHdr {
+    hdr: VirtioSndHdr,
+    stream_id: u32,
+}
+
+impl ByteCode for VirtioSndPcmHdr {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndPcmSetParams {
+    hdr: VirtioSndPcmHdr,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: u8,
+    rate: u8,
+    padding: u8,
+}
+
+impl ByteCode for VirtioSndPcmSetParams {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndPcmXfer {
+    stream_id: u32,
+}
+
+impl ByteCode for VirtioSndPcmXfer {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioSndPcmStatus {
+    status: u32,
+    latency_bytes: u32,
+}
+
+impl ByteCode for VirtioSndPcmStatus {}
+
+/// Virtqueue which is shared by the io handler and the PCM stream workers.
+#[derive(Clone)]
+struct SndQueue {
+    queue: Arc<Mutex<Queue>>,
+    mem_space: Arc<AddressSpace>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    driver_features: u64,
+}
+
+impl SndQueue {
+    /// Write the response to the device writable buffers, and return the buffers to guest.
+    fn complete(&self, index: u16, in_iovec: &[Iovec], resp: &[u8]) -> Result<()> {
+        let len = iov_from_buf_direct(in_iovec, resp)?;
+        let mut queue_lock = self.queue.lock().unwrap();
+        queue_lock
+            .vring
+            .add_used(&self.mem_space, index, len as u32)
+            .with_context(|| {
+                format!(
+                    "Failed to add used ring(sound), index {}, len {}",
+                    index, len
+                )
+            })?;
+
+        if queue_lock
+            .vring
+            .should_notify(&self.mem_space, self.driver_features)
+        {
+            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue_lock), false)
+                .with_context(|| {
+                    VirtioError::InterruptTrigger("sound", VirtioInterruptType::Vring)
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Build the response of PCM I/O message, which is the captured data followed by the status.
+fn pcm_status_resp(data: &[u8], status: u32) -> Vec<u8> {
+    let status = VirtioSndPcmStatus {
+        status,
+        latency_bytes: 0,
+    };
+    let mut resp = Vec::with_capacity(data.len() + size_of::<VirtioSndPcmStatus>());
+    resp.extend_from_slice(data);
+    resp.extend_from_slice(status.as_bytes());
+    resp
+}
+
+/// PCM I/O message, which contains the audio data for playback or the buffer for capture.
+struct SndPcmXfer {
+    index: u16,
+    data: Vec<u8>,
+    in_iovec: Vec<Iovec>,
+}
+
+enum SndPcmMsg {
+    Xfer(SndPcmXfer),
+    Start(ShmemStreamFmt),
+    Stop,
+    Release,
+}
+
+/// Worker of PCM stream, which transfers the audio data between the guest and the host
+/// audio interface in a separate thread, as the interface may block.
+struct SndPcmWorker {
+    direction: u8,
+    queue: SndQueue,
+    interface: Arc<Mutex<dyn AudioInterface>>,
+    receiver: Receiver<SndPcmMsg>,
+    pending: VecDeque<SndPcmXfer>,
+    /// Format of the stream, which is set while the stream is started.
+    fmt: Option<ShmemStreamFmt>,
+}
+
+impl SndPcmWorker {
+    fn run(mut self) {
+        loop {
+            let msg = if self.fmt.is_some() && !self.pending.is_empty() {
+                match self.receiver.try_recv() {
+                    Ok(msg) => Some(msg),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match self.receiver.recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => break,
+                }
+            };
+
+            match msg {
+                Some(SndPcmMsg::Xfer(xfer)) => self.pending.push_back(xfer),
+                Some(SndPcmMsg::Start(fmt)) => self.fmt = Some(fmt),
+                Some(SndPcmMsg::Stop) => self.fmt = None,
+                Some(SndPcmMsg::Release) => self.release(),
+                None => self.transfer(),
+            }
+        }
+        self.interface.lock().unwrap().destroy();
+    }
+
+    fn transfer(&mut self) {
+        let Some(fmt) = self.fmt else {
+            return;
+        };
+        let Some(mut xfer) = self.pending.pop_front() else {
+            return;
+        };
+        let mut stream_data = StreamData::default();
+        stream_data.fmt = fmt;
+        stream_data.audio_size = xfer.data.len() as u32;
+        stream_data.audio_base = xfer.data.as_mut_ptr() as u64;
+
+        let mut status = VIRTIO_SND_S_OK;
+        if self.direction == VIRTIO_SND_D_OUTPUT {
+            self.interface.lock().unwrap().send(&stream_data);
+            vnc_audio_play(fmt.get_rate(), fmt.size, fmt.channels, &xfer.data);
+        } else if !self.interface.lock().unwrap().receive(&stream_data) {
+            status = VIRTIO_SND_S_IO_ERR;
+        }
+        self.complete(&xfer, status);
+    }
+
+    /// Return all pending buffers to guest.
+    fn release(&mut self) {
+        while let Some(xfer) = self.pending.pop_front() {
+            self.complete(&xfer, VIRTIO_SND_S_OK);
+        }
+        self.fmt = None;
+        self.interface.lock().unwrap().destroy();
+    }
+
+    fn complete(&self, xfer: &SndPcmXfer, status: u32) {
+        let data = match self.direction {
+            VIRTIO_SND_D_OUTPUT => &[],
+            _ => xfer.data.as_slice(),
+        };
+        if let Err(e) =
+            self.queue
+                .complete(xfer.index, &xfer.in_iovec, &pcm_status_resp(data, status))
+        {
+            error!("Failed to complete sound PCM I/O message: {:?}", e);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SndPcmState {
+    /// The initial state, or the stream is released.
+    Idle,
+    ParamsSet,
+    Prepared,
+    Started,
+    Stopped,
+}
+
+/// Get the next state of PCM stream after the control request, or None if the
+/// request is not allowed in current state.
+fn pcm_next_state(state: SndPcmState, code: u32, params_set: bool) -> Option<SndPcmState> {
+    match (code, state) {
+        (
+            VIRTIO_SND_R_PCM_SET_PARAMS,
+            SndPcmState::Idle | SndPcmState::ParamsSet | SndPcmState::Prepared,
+        ) => Some(SndPcmState::ParamsSet),
+        (
+            VIRTIO_SND_R_PCM_PREPARE,
+            SndPcmState::Idle | SndPcmState::ParamsSet | SndPcmState::Prepared,
+        ) if params_set => Some(SndPcmState::Prepared),
+        (VIRTIO_SND_R_PCM_START, SndPcmState::Prepared | SndPcmState::Stopped) => {
+            Some(SndPcmState::Started)
+        }
+        (VIRTIO_SND_R_PCM_STOP, SndPcmState::Started) => Some(SndPcmState::Stopped),
+        (VIRTIO_SND_R_PCM_RELEASE, SndPcmState::Prepared | SndPcmState::Stopped) => {
+            Some(SndPcmState::Idle)
+        }
+        _ => None,
+    }
+}
+
+struct SndPcmStream {
+    direction: u8,
+    state: SndPcmState,
+    params: Option<VirtioSndPcmSetParams>,
+    /// Generation of the stream format, which is changed when the stream is prepared.
+    fmt_generation: u32,
+    sender: Option<Sender<SndPcmMsg>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SndPcmStream {
+    fn send(&self, msg: SndPcmMsg) -> Result<()> {
+        self.sender
+            .as_ref()
+            .with_context(|| "Worker of sound PCM stream is stopped")?
+            .send(msg)
+            .map_err(|_| anyhow!("Worker of sound PCM stream exits"))
+    }
+
+    fn stream_fmt(&self) -> Result<ShmemStreamFmt> {
+        let params = self
+            .params
+            .as_ref()
+            .with_context(|| "No parameters for sound PCM stream")?;
+        let (_, size) = SND_PCM_FORMATS
+            .iter()
+            .find(|(format, _)| *format == params.format)
+            .with_context(|| format!("Unsupported PCM format {}", params.format))?;
+        let (_, rate) = SND_PCM_RATES
+            .iter()
+            .find(|(rate, _)| *rate == params.rate)
+            .with_context(|| format!("Unsupported PCM rate {}", params.rate))?;
+        ShmemStreamFmt::new(self.fmt_generation, *rate, *size, params.channels)
+    }
+
+    fn stop_worker(&mut self) {
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Worker of sound PCM stream panicked");
+            }
+        }
+    }
+}
+
+struct SoundIoHandler {
+    ctrl_queue: SndQueue,
+    ctrl_queue_evt: Arc<EventFd>,
+    tx_queue: SndQueue,
+    tx_queue_evt: Arc<EventFd>,
+    rx_queue: SndQueue,
+    rx_queue_evt: Arc<EventFd>,
+    mem_space: Arc<AddressSpace>,
+    driver_features: u64,
+    streams: Vec<SndPcmStream>,
+}
+
+impl SoundIoHandler {
+    fn get_request<T: ByteCode>(&self, elem: &Element) -> Result<T> {
+        let mut req = T::default();
+        let size = iov_to_buf(&self.mem_space, &elem.out_iovec, req.as_mut_bytes())?;
+        if size < size_of::<T>() {
+            bail!("Invalid sound request: len {}", size);
+        }
+        Ok(req)
+    }
+
+    fn process_ctrl_queue(&mut self) -> Result<()> {
+        loop {
+            let elem = self
+                .ctrl_queue
+                .queue
+                .lock()
+                .unwrap()
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)?;
+            if elem.desc_num == 0 {
+                break;
+            }
+
+            let resp = self.handle_ctrl_request(&elem).unwrap_or_else(|e| {
+                error!("Failed to handle sound control request: {:?}", e);
+                VirtioSndHdr {
+                    code: VIRTIO_SND_S_BAD_MSG,
+                }
+                .as_bytes()
+                .to_vec()
+            });
+            let (_, in_iovec) = gpa_hva_iovec_map(&elem.in_iovec, &self.mem_space)?;
+            self.ctrl_queue.complete(elem.index, &in_iovec, &resp)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the control request, and return the response.
+    fn handle_ctrl_request(&mut self, elem: &Element) -> Result<Vec<u8>> {
+        let hdr = self.get_request::<VirtioSndHdr>(elem)?;
+        match hdr.code {
+            VIRTIO_SND_R_JACK_INFO => self.query_info(elem, &jack_infos()),
+            VIRTIO_SND_R_PCM_INFO => self.query_info(elem, &pcm_infos()),
+            VIRTIO_SND_R_CHMAP_INFO => self.query_info(elem, &chmap_infos()),
+            VIRTIO_SND_R_PCM_SET_PARAMS
+            | VIRTIO_SND_R_PCM_PREPARE
+            | VIRTIO_SND_R_PCM_RELEASE
+            | VIRTIO_SND_R_PCM_START
+            | VIRTIO_SND_R_PCM_STOP => {
+                let status = self.handle_pcm_request(elem, hdr.code)?;
+                Ok(VirtioSndHdr { code: status }.as_bytes().to_vec())
+            }
+            VIRTIO_SND_R_JACK_REMAP => Ok(VirtioSndHdr {
+                code: VIRTIO_SND_S_NOT_SUPP,
+            }
+            .as_bytes()
+            .to_vec()),
+            _ => bail!("Unknown sound control request {:#x}", hdr.code),
+        }
+    }
+
+    fn query_info<T: ByteCode>(&self, elem: &Element, items: &[T]) -> Result<Vec<u8>> {
+        let req = self.get_request::<VirtioSndQueryInfo>(elem)?;
+        let end = req
+            .start_id
+            .checked_add(req.count)
+            .filter(|end| *end as usize <= items.len())
+            .with_context(|| {
+                format!(
+                    "Invalid sound query: start id {}, count {}",
+                    req.start_id, req.count
+                )
+            })?;
+        if req.size as usize != size_of::<T>() {
+            bail!("Invalid size {} of sound query item", req.size);
+        }
+
+        let mut resp = VirtioSndHdr {
+            code: VIRTIO_SND_S_OK,
+        }
+        .as_bytes()
+        .to_vec();
+        for item in &items[req.start_id as usize..end as usize] {
+            resp.extend_from_slice(item.as_bytes());
+        }
+        Ok(resp)
+    }
+
+    /// Handle the PCM control request, and return the status.
+    fn handle_pcm_request(&mut self, elem: &Element, code: u32) -> Result<u32> {
+        let hdr = self.get_request::<VirtioSndPcmHdr>(elem)?;
+        let params = match code {
+            VIRTIO_SND_R_PCM_SET_PARAMS => {
+                let params = self.get_request::<VirtioSndPcmSetParams>(elem)?;
+                if let Err(e) = check_pcm_params(&params) {
+                    warn!("Invalid sound PCM parameters: {:?}", e);
+                    return Ok(VIRTIO_SND_S_NOT_SUPP);
+                }
+                Some(params)
+            }
+            _ => None,
+        };
+        let stream = self
+            .streams
+            .get_mut(hdr.stream_id as usize)
+            .with_context(|| format!("Invalid sound PCM stream id {}", hdr.stream_id))?;
+        let next_state = match pcm_next_state(stream.state, code, stream.params.is_some()) {
+            Some(state) => state,
+            None => {
+                warn!(
+                    "Sound PCM request {:#x} is not allowed in state {:?}",
+                    code, stream.state
+                );
+                return Ok(VIRTIO_SND_S_BAD_MSG);
+            }
+        };
+
+        match code {
+            VIRTIO_SND_R_PCM_SET_PARAMS => {
+                if stream.state == SndPcmState::Prepared {
+                    stream.send(SndPcmMsg::Release)?;
+                }
+                stream.params = params;
+            }
+            VIRTIO_SND_R_PCM_PREPARE => {
+                if stream.state == SndPcmState::Prepared {
+                    stream.send(SndPcmMsg::Release)?;
+                }
+                stream.fmt_generation = stream.fmt_generation.wrapping_add(1);
+            }
+            VIRTIO_SND_R_PCM_START => stream.send(SndPcmMsg::Start(stream.stream_fmt()?))?,
+            VIRTIO_SND_R_PCM_STOP => stream.send(SndPcmMsg::Stop)?,
+            VIRTIO_SND_R_PCM_RELEASE => stream.send(SndPcmMsg::Release)?,
+            _ => (),
+        }
+        stream.state = next_state;
+        Ok(VIRTIO_SND_S_OK)
+    }
+
+    fn process_pcm_queue(&mut self, direction: u8) -> Result<()> {
+        let queue = match direction {
+            VIRTIO_SND_D_OUTPUT => self.tx_queue.clone(),
+            _ => self.rx_queue.clone(),
+        };
+        loop {
+            let mut elem = queue
+                .queue
+                .lock()
+                .unwrap()
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)?;
+            if elem.desc_num == 0 {
+                break;
+            }
+
+            let (in_len, in_iovec) = gpa_hva_iovec_map(&elem.in_iovec, &self.mem_space)?;
+            let status_len = size_of::<VirtioSndPcmStatus>() as u64;
+            if in_len < status_len {
+                error!("Invalid sound PCM I/O message: no room for status");
+                queue.complete(elem.index, &[], &[])?;
+                continue;
+            }
+            let data_len = in_len - status_len;
+
+            match self.get_pcm_xfer(&mut elem, direction, data_len) {
+                Ok((stream_id, data)) => {
+                    let xfer = SndPcmXfer {
+                        index: elem.index,
+                        data,
+                        in_iovec,
+                    };
+                    self.streams[stream_id].send(SndPcmMsg::Xfer(xfer))?;
+                }
+                Err(e) => {
+                    error!("Invalid sound PCM I/O message: {:?}", e);
+                    // Only write the status, which is at the end of the writable buffers.
+                    let mut status_iovec = in_iovec;
+                    let status_iovec =
+                        iov_discard_front_direct(&mut status_iovec, data_len).unwrap_or_default();
+                    let resp = pcm_status_resp(&[], VIRTIO_SND_S_BAD_MSG);
+                    queue.complete(elem.index, status_iovec, &resp)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the stream id and the audio data of PCM I/O message. For capture, the
+    /// data is the buffer to be filled.
+    fn get_pcm_xfer(
+        &self,
+        elem: &mut Element,
+        direction: u8,
+        in_data_len: u64,
+    ) -> Result<(usize, Vec<u8>)> {
+        let xfer = self.get_request::<VirtioSndPcmXfer>(elem)?;
+        let stream_id = xfer.stream_id as usize;
+        let stream = self
+            .streams
+            .get(stream_id)
+            .filter(|stream| stream.direction == direction)
+            .with_context(|| format!("Invalid stream id {}", xfer.stream_id))?;
+        if !matches!(
+            stream.state,
+            SndPcmState::Prepared | SndPcmState::Started | SndPcmState::Stopped
+        ) {
+            bail!("Stream {} is not prepared", stream_id);
+        }
+        let buffer_bytes = stream.params.as_ref().map_or(0, |p| p.buffer_bytes) as u64;
+
+        let data = if direction == VIRTIO_SND_D_OUTPUT {
+            let data_iovec =
+                iov_discard_front(&mut elem.out_iovec, size_of::<VirtioSndPcmXfer>() as u64)
+                    .unwrap_or_default();
+            let out_len: u64 = data_iovec.iter().map(|iov| iov.len as u64).sum();
+            if out_len > buffer_bytes {
+                bail!("Size {} exceeds buffer bytes {}", out_len, buffer_bytes);
+            }
+            let mut data = vec![0_u8; out_len as usize];
+            iov_to_buf(&self.mem_space, data_iovec, &mut data)?;
+            data
+        } else {
+            if in_data_len > buffer_bytes {
+                bail!("Size {} exceeds buffer bytes {}", in_data_len, buffer_bytes);
+            }
+            vec![0_u8; in_data_len as usize]
+        };
+        Ok((stream_id, data))
+    }
+
+    fn stop_workers(&mut self) {
+        for stream in self.streams.iter_mut() {
+            stream.stop_worker();
+        }
+    }
+}
+
+fn check_pcm_params(params: &VirtioSndPcmSetParams) -> Result<()> {
+    if params.features != 0 {
+        bail!("Unsupported features {:#x}", params.features);
+    }
+    if params.channels < SND_PCM_CHANNELS_MIN || params.channels > SND_PCM_CHANNELS_MAX {
+        bail!("Unsupported channels {}", params.channels);
+    }
+    if !SND_PCM_FORMATS.iter().any(|(f, _)| *f == params.format) {
+        bail!("Unsupported format {}", params.format);
+    }
+    if !SND_PCM_RATES.iter().any(|(r, _)| *r == params.rate) {
+        bail!("Unsupported rate {}", params.rate);
+    }
+    if params.buffer_bytes > SND_PCM_BUFFER_BYTES_MAX || params.period_bytes > params.buffer_bytes {
+        bail!(
+            "Unsupported buffer bytes {} or period bytes {}",
+            params.buffer_bytes,
+            params.period_bytes
+        );
+    }
+    if params.period_bytes == 0 || params.buffer_bytes % params.period_bytes != 0 {
+        bail!(
+            "Buffer bytes {} is not a multiple of period bytes {}",
+            params.buffer_bytes,
+            params.period_bytes
+        );
+    }
+    Ok(())
+}
+
+fn jack_infos() -> Vec<VirtioSndJackInfo> {
+    SND_STREAM_DIRECTIONS
+        .iter()
+        .map(|direction| {
+            let (hda_reg_defconf, hda_reg_caps) = match *direction {
+                VIRTIO_SND_D_OUTPUT => (SND_JACK_DEFCONF_LINE_OUT, SND_JACK_CAPS_OUT),
+                _ => (SND_JACK_DEFCONF_MIC_IN, SND_JACK_CAPS_IN),
+            };
+            VirtioSndJackInfo {
+                hda_reg_defconf,
+                hda_reg_caps,
+                connected: 1,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+fn pcm_infos() -> Vec<VirtioSndPcmInfo> {
+    let formats = SND_PCM_FORMATS.iter().fold(0, |f, (fmt, _)| f | 1 << fmt);
+    let rates = SND_PCM_RATES.iter().fold(0, |r, (rate, _)| r | 1 << rate);
+    SND_STREAM_DIRECTIONS
+        .iter()
+        .map(|direction| VirtioSndPcmInfo {
+            formats,
+            rates,
+            direction: *direction,
+            channels_min: SND_PCM_CHANNELS_MIN,
+            channels_max: SND_PCM_CHANNELS_MAX,
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn chmap_infos() -> Vec<VirtioSndChmapInfo> {
+    SND_STREAM_DIRECTIONS
+        .iter()
+        .map(|direction| {
+            let mut positions = [0; VIRTIO_SND_CHMAP_MAX_SIZE];
+            positions[0] = VIRTIO_SND_CHMAP_FL;
+            positions[1] = VIRTIO_SND_CHMAP_FR;
+            VirtioSndChmapInfo {
+                direction: *direction,
+                channels: SND_PCM_CHANNELS_MAX,
+                positions,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+impl EventNotifierHelper for SoundIoHandler {
+    fn internal_notifiers(handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_handler = handler.lock().unwrap();
+        let queue_evts = [
+            (locked_handler.ctrl_queue_evt.as_raw_fd(), None),
+            (
+                locked_handler.tx_queue_evt.as_raw_fd(),
+                Some(VIRTIO_SND_D_OUTPUT),
+            ),
+            (
+                locked_handler.rx_queue_evt.as_raw_fd(),
+                Some(VIRTIO_SND_D_INPUT),
+            ),
+        ];
+        drop(locked_handler);
+
+        for (queue_evt, direction) in queue_evts {
+            let handler_clone = handler.clone();
+            let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                let mut locked_handler = handler_clone.lock().unwrap();
+                let result = match direction {
+                    None => locked_handler.process_ctrl_queue(),
+                    Some(direction) => locked_handler.process_pcm_queue(direction),
+                };
+                if let Err(e) = result {
+                    error!("Failed to process queue for virtio sound, err: {:?}", e);
+                }
+                None
+            });
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::AddShared,
+                queue_evt,
+                None,
+                EventSet::IN,
+                vec![h],
+            ));
+        }
+
+        notifiers
+    }
+}
+
+/// Virtio sound device structure.
+pub struct Sound {
+    /// Virtio device base property.
+    base: VirtioBase,
+    /// Configuration of the sound device.
+    cfg: SoundConfig,
+    /// Config space of the sound device.
+    config_space: VirtioSndConfig,
+    /// Host audio interfaces of the PCM streams.
+    interfaces: Vec<Arc<Mutex<dyn AudioInterface>>>,
+    /// Io handler of the sound device.
+    handler: Option<Arc<Mutex<SoundIoHandler>>>,
+}
+
+impl Sound {
+    pub fn new(cfg: SoundConfig) -> Self {
+        Sound {
+            base: VirtioBase::new(VIRTIO_TYPE_SOUND, QUEUE_NUM_SOUND, DEFAULT_VIRTQUEUE_SIZE),
+            cfg,
+            config_space: VirtioSndConfig::default(),
+            interfaces: Vec::new(),
+            handler: None,
+        }
+    }
+}
+
+impl VirtioDevice for Sound {
+    fn virtio_base(&self) -> &VirtioBase {
+        &self.base
+    }
+
+    fn virtio_base_mut(&mut self) -> &mut VirtioBase {
+        &mut self.base
+    }
+
+    fn realize(&mut self) -> Result<()> {
+        self.interfaces = SND_STREAM_DIRECTIONS
+            .iter()
+            .map(|direction| {
+                let (name, dir) = match *direction {
+                    VIRTIO_SND_D_OUTPUT => ("VirtioSoundPlay", ScreamDirection::Playback),
+                    _ => ("VirtioSoundCapt", ScreamDirection::Record),
+                };
+                create_audio_interface(
+                    &self.cfg.interface,
+                    name,
+                    dir,
+                    &self.cfg.playback,
+                    &self.cfg.record,
+                )
+            })
+            .collect();
+        self.init_config_features()
+    }
+
+    fn init_config_features(&mut self) -> Result<()> {
+        self.base.device_features = 1u64 << VIRTIO_F_VERSION_1;
+        self.config_space = VirtioSndConfig {
+            jacks: SND_STREAM_DIRECTIONS.len() as u32,
+            streams: SND_STREAM_DIRECTIONS.len() as u32,
+            chmaps: SND_STREAM_DIRECTIONS.len() as u32,
+        };
+        Ok(())
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        read_config_default(self.config_space.as_bytes(), offset, data)
+    }
+
+    fn write_config(&mut self, offset: u64, _data: &[u8]) -> Result<()> {
+        bail!(
+            "Writing device config space for sound is not supported, offset: {}",
+            offset
+        );
+    }
+
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queue_evts: Vec<Arc<EventFd>>,
+    ) -> Result<()> {
+        let queues = &self.base.queues;
+        if queues.len() != QUEUE_NUM_SOUND {
+            return Err(anyhow!(VirtioError::IncorrectQueueNum(
+                QUEUE_NUM_SOUND,
+                queues.len()
+            )));
+        }
+        let driver_features = self.base.driver_features;
+        let snd_queue = |index: usize| SndQueue {
+            queue: queues[index].clone(),
+            mem_space: mem_space.clone(),
+            interrupt_cb: interrupt_cb.clone(),
+            driver_features,
+        };
+
+        let mut streams = Vec::new();
+        for (id, direction) in SND_STREAM_DIRECTIONS.iter().enumerate() {
+            let (sender, receiver) = channel();
+            let worker = SndPcmWorker {
+                direction: *direction,
+                queue: match *direction {
+                    VIRTIO_SND_D_OUTPUT => snd_queue(SND_TX_QUEUE),
+                    _ => snd_queue(SND_RX_QUEUE),
+                },
+                interface: self.interfaces[id].clone(),
+                receiver,
+                pending: VecDeque::new(),
+                fmt: None,
+            };
+            let worker = thread::Builder::new()
+                .name(format!("virtio-sound pcm{}", id))
+                .spawn(move || worker.run())
+                .with_context(|| "Failed to create thread for virtio sound")?;
+            streams.push(SndPcmStream {
+                direction: *direction,
+                state: SndPcmState::Idle,
+                params: None,
+                fmt_generation: 0,
+                sender: Some(sender),
+                worker: Some(worker),
+            });
+        }
+
+        let handler = Arc::new(Mutex::new(SoundIoHandler {
+            ctrl_queue: snd_queue(SND_CTRL_QUEUE),
+            ctrl_queue_evt: queue_evts[SND_CTRL_QUEUE].clone(),
+            tx_queue: snd_queue(SND_TX_QUEUE),
+            tx_queue_evt: queue_evts[SND_TX_QUEUE].clone(),
+            rx_queue: snd_queue(SND_RX_QUEUE),
+            rx_queue_evt: queue_evts[SND_RX_QUEUE].clone(),
+            mem_space: mem_space.clone(),
+            driver_features,
+            streams,
+        }));
+        let notifiers = EventNotifierHelper::internal_notifiers(handler.clone());
+        register_event_helper(notifiers, None, &mut self.base.deactivate_evts)?;
+        self.handler = Some(handler);
+
+        Ok(())
+    }
+
+    fn deactivate(&mut self) -> Result<()> {
+        unregister_event_helper(None, &mut self.base.deactivate_evts)?;
+        if let Some(handler) = self.handler.take() {
+            handler.lock().unwrap().stop_workers();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sound_init() {
+        let mut sound = Sound::new(SoundConfig::default());
+        assert_eq!(sound.device_type(), VIRTIO_TYPE_SOUND);
+        assert_eq!(sound.queue_num(), QUEUE_NUM_SOUND);
+        assert_eq!(sound.queue_size_max(), DEFAULT_VIRTQUEUE_SIZE);
+
+        sound.init_config_features().unwrap();
+        assert_eq!(sound.base.device_features, 1u64 << VIRTIO_F_VERSION_1);
+        let mut config = [0_u8; 12];
+        sound.read_config(0, &mut config).unwrap();
+        assert_eq!(config, [2, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0]);
+        assert!(sound.read_config(8, &mut config).is_err());
+        assert!(sound.write_config(0, &config).is_err());
+    }
+
+    #[test]
+    fn test_sound_pcm_state() {
+        use SndPcmState::*;
+
+        assert_eq!(pcm_next_state(Idle, VIRTIO_SND_R_PCM_PREPARE, false), None);
+        assert_eq!(
+            pcm_next_state(Idle, VIRTIO_SND_R_PCM_SET_PARAMS, false),
+            Some(ParamsSet)
+        );
+        assert_eq!(
+            pcm_next_state(ParamsSet, VIRTIO_SND_R_PCM_PREPARE, true),
+            Some(Prepared)
+        );
+        assert_eq!(
+            pcm_next_state(ParamsSet, VIRTIO_SND_R_PCM_START, true),
+            None
+        );
+        assert_eq!(
+            pcm_next_state(Prepared, VIRTIO_SND_R_PCM_START, true),
+            Some(Started)
+        );
+        assert_eq!(
+            pcm_next_state(Started, VIRTIO_SND_R_PCM_SET_PARAMS, true),
+            None
+        );
+        assert_eq!(
+            pcm_next_state(Started, VIRTIO_SND_R_PCM_RELEASE, true),
+            None
+        );
+        assert_eq!(
+            pcm_next_state(Started, VIRTIO_SND_R_PCM_STOP, true),
+            Some(Stopped)
+        );
+        assert_eq!(
+            pcm_next_state(Stopped, VIRTIO_SND_R_PCM_START, true),
+            Some(Started)
+        );
+        assert_eq!(
+            pcm_next_state(Stopped, VIRTIO_SND_R_PCM_RELEASE, true),
+            Some(Idle)
+        );
+        // Parameters are kept after the stream is released.
+        assert_eq!(
+            pcm_next_state(Idle, VIRTIO_SND_R_PCM_PREPARE, true),
+            Some(Prepared)
+        );
+    }
+
+    #[test]
+    fn test_sound_pcm_params() {
+        let mut params = VirtioSndPcmSetParams {
+            buffer_bytes: 8192,
+            period_bytes: 1024,
+            channels: 2,
+            format: VIRTIO_SND_PCM_FMT_S16,
+            rate: VIRTIO_SND_PCM_RATE_48000,
+            ..Default::default()
+        };
+        assert!(check_pcm_params(&params).is_ok());
+
+        let stream = SndPcmStream {
+            direction: VIRTIO_SND_D_OUTPUT,
+            state: SndPcmState::ParamsSet,
+            params: Some(params),
+            fmt_generation: 1,
+            sender: None,
+            worker: None,
+        };
+        let fmt = stream.stream_fmt().unwrap();
+        assert_eq!(fmt.get_rate(), 48000);
+        assert_eq!(fmt.size, 16);
+        assert_eq!(fmt.channels, 2);
+        assert_eq!(fmt.channel_map, 0x3);
+
+        params.channels = 3;
+        assert!(check_pcm_params(&params).is_err());
+        params.channels = 1;
+        params.rate = 1;
+        assert!(check_pcm_params(&params).is_err());
+        params.rate = VIRTIO_SND_PCM_RATE_44100;
+        params.format = 0;
+        assert!(check_pcm_params(&params).is_err());
+        params.format = VIRTIO_SND_PCM_FMT_S24_3;
+        params.period_bytes = 3000;
+        assert!(check_pcm_params(&params).is_err());
+        params.period_bytes = SND_PCM_BUFFER_BYTES_MAX;
+        params.buffer_bytes = SND_PCM_BUFFER_BYTES_MAX * 2;
+        assert!(check_pcm_params(&params).is_err());
+        params.buffer_bytes = 1024;
+        params.period_bytes = 2048;
+        assert!(check_pcm_params(&params).is_err());
+    }
+
+    #[test]
+    fn test_sound_info() {
+        let pcm_infos = pcm_infos();
+        assert_eq!(size_of::<VirtioSndPcmInfo>(), 32);
+        assert_eq!(pcm_infos.len(), 2);
+        assert_eq!(pcm_infos[0].direction, VIRTIO_SND_D_OUTPUT);
+        assert_eq!(pcm_infos[1].direction, VIRTIO_SND_D_INPUT);
+        assert_eq!(pcm_infos[0].formats, 1 << 5 | 1 << 11 | 1 << 17);
+        assert_eq!(pcm_infos[0].rates, 0b1_1110_1100_0000);
+
+        assert_eq!(size_of::<VirtioSndJackInfo>(), 24);
+        assert_eq!(jack_infos()[1].hda_reg_defconf, SND_JACK_DEFCONF_MIC_IN);
+        assert_eq!(size_of::<VirtioSndChmapInfo>(), 24);
+        assert_eq!(chmap_infos()[0].positions[..2], [3, 4]);
+        assert_eq!(size_of::<VirtioSndPcmSetParams>(), 24);
+    }
+}
@@ -56,6 +56,8 @@ pub use device::net::*;
 pub use device::rng::{Rng, RngState};
 pub use device::scsi_cntlr as ScsiCntlr;
 pub use device::serial::{find_port_by_nr, get_max_nr, Serial, SerialPort, VirtioSerialState};
+#[cfg(not(target_env = "musl"))]
+pub use device::sound::Sound;
 pub use error::VirtioError;
 pub use error::*;
 pub use queue::*;
@@ -78,6 +80,7 @@ pub const VIRTIO_TYPE_BALLOON: u32 = 5;
 pub const VIRTIO_TYPE_SCSI: u32 = 8;
 pub const VIRTIO_TYPE_GPU: u32 = 16;
 pub const VIRTIO_TYPE_VSOCK: u32 = 19;
+pub const VIRTIO_TYPE_SOUND: u32 = 25;
 pub const VIRTIO_TYPE_FS: u32 = 26;
 
 // The Status of Virtio Device.
@@ -166,6 +169,8 @@ pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
 /// GPU EDID feature is supported.
 pub const VIRTIO_GPU_F_EDID: u32 = 1;
+/// GPU resource blob feature is supported.
+pub const VIRTIO_GPU_F_RESOURCE_BLOB: u32 = 3;
 
 /// The device sets control ok status to driver.
 pub const VIRTIO_NET_OK: u8 = 0;
@@ -265,6 +270,10 @@ pub const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
 pub const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
 //// Retrieve the EDID data for a given scanout.
 pub const VIRTIO_GPU_CMD_GET_EDID: u32 = 0x010a;
+/// Create a blob resource on the host.
+pub const VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB: u32 = 0x010c;
+/// Set the scanout parameters for a single output with blob resource.
+pub const VIRTIO_GPU_CMD_SET_SCANOUT_BLOB: u32 = 0x010d;
 /// update cursor
 pub const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32 = 0x0300;
 /// move cursor
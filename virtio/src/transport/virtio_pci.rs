@@ -48,7 +48,7 @@ use crate::{
     CONFIG_STATUS_FEATURES_OK, CONFIG_STATUS_NEEDS_RESET, INVALID_VECTOR_NUM,
     QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING, VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1,
     VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_BLOCK, VIRTIO_TYPE_CONSOLE,
-    VIRTIO_TYPE_FS, VIRTIO_TYPE_GPU, VIRTIO_TYPE_NET, VIRTIO_TYPE_SCSI,
+    VIRTIO_TYPE_FS, VIRTIO_TYPE_GPU, VIRTIO_TYPE_NET, VIRTIO_TYPE_SCSI, VIRTIO_TYPE_SOUND,
 };
 
 const VIRTIO_QUEUE_MAX: u32 = 1024;
@@ -64,6 +64,7 @@ const VIRTIO_PCI_CLASS_ID_COMMUNICATION_OTHER: u16 = 0x0780;
 const VIRTIO_PCI_CLASS_ID_DISPLAY_OTHER: u16 = 0x0380;
 #[cfg(target_arch = "x86_64")]
 const VIRTIO_PCI_CLASS_ID_DISPLAY_VGA: u16 = 0x0300;
+const VIRTIO_PCI_CLASS_ID_MULTIMEDIA_AUDIO: u16 = 0x0401;
 const VIRTIO_PCI_CLASS_ID_OTHERS: u16 = 0x00ff;
 
 const VIRTIO_PCI_CAP_COMMON_OFFSET: u32 = 0x0;
@@ -143,6 +144,7 @@ fn get_virtio_class_id(device_type: u32) -> u16 {
         VIRTIO_TYPE_GPU => VIRTIO_PCI_CLASS_ID_DISPLAY_VGA,
         #[cfg(target_arch = "aarch64")]
         VIRTIO_TYPE_GPU => VIRTIO_PCI_CLASS_ID_DISPLAY_OTHER,
+        VIRTIO_TYPE_SOUND => VIRTIO_PCI_CLASS_ID_MULTIMEDIA_AUDIO,
         _ => {
             warn!("Unknown device type, please make sure it is supported.");
             VIRTIO_PCI_CLASS_ID_OTHERS
@@ -433,20 +435,8 @@ impl VirtioPciDevice {
 
         update_dev_id(&self.parent_bus, self.devfn, &self.dev_id);
         if self.need_irqfd {
-            let mut queue_num = locked_dev.queue_num();
-            // No need to create call event for control queue.
-            // It will be polled in StratoVirt when activating the device.
-            if locked_dev.has_control_queue() && queue_num % 2 != 0 {
-                queue_num -= 1;
-            }
-            let call_evts = NotifyEventFds::new(queue_num);
-            if let Err(e) = locked_dev.set_guest_notifiers(&call_evts.events) {
-                error!("Failed to set guest notifiers, error is {:?}", e);
-                return false;
-            }
             drop(locked_dev);
-            if !self.queues_register_irqfd(&call_evts.events) {
-                error!("Failed to register queues irqfd.");
+            if !self.register_guest_notifiers() {
                 return false;
             }
             locked_dev = self.device.lock().unwrap();
@@ -466,6 +456,28 @@ impl VirtioPciDevice {
         true
     }
 
+    /// Create call events of queues for device, and register them as irqfd.
+    fn register_guest_notifiers(&self) -> bool {
+        let mut locked_dev = self.device.lock().unwrap();
+        let mut queue_num = locked_dev.queue_num();
+        // No need to create call event for control queue.
+        // It will be polled in StratoVirt when activating the device.
+        if locked_dev.has_control_queue() && queue_num % 2 != 0 {
+            queue_num -= 1;
+        }
+        let call_evts = NotifyEventFds::new(queue_num);
+        if let Err(e) = locked_dev.set_guest_notifiers(&call_evts.events) {
+            error!("Failed to set guest notifiers, error is {:?}", e);
+            return false;
+        }
+        drop(locked_dev);
+        if !self.queues_register_irqfd(&call_evts.events) {
+            error!("Failed to register queues irqfd.");
+            return false;
+        }
+        true
+    }
+
     fn deactivate_device(&self) -> bool {
         if self.need_irqfd && self.config.msix.is_some() {
             let msix = self.config.msix.as_ref().unwrap();
@@ -1258,6 +1270,13 @@ impl MigrationHook for VirtioPciDevice {
         ) {
             bail!("Failed to update bar, error is {:?}", e);
         }
+        drop(locked_parent_bus);
+
+        // Devices handled outside of StratoVirt, such as vhost-user devices,
+        // need call events before activating.
+        if self.need_irqfd && !self.register_guest_notifiers() {
+            bail!("Failed to register guest notifiers");
+        }
 
         let queue_evts = (*self.notify_eventfds).clone().events;
         if let Some(cb) = self.interrupt_cb.clone() {
@@ -18,6 +18,7 @@ use address_space::AddressSpace;
 use anyhow::{anyhow, bail, Context, Result};
 use machine_manager::config::NetworkInterfaceConfig;
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use migration::MigrationManager;
 use util::byte_code::ByteCode;
 use util::loop_context::EventNotifierHelper;
 use util::tap::Tap;
@@ -152,6 +153,10 @@ impl VirtioDevice for Net {
         self.taps = create_tap(self.net_cfg.tap_fds.as_ref(), host_dev_name, queue_pairs)
             .with_context(|| "Failed to create tap for vhost net")?;
         self.backends = Some(backends);
+        MigrationManager::add_blocker(
+            &self.net_cfg.id,
+            "vhost-kernel net doesn't support logging dirty pages",
+        );
 
         self.init_config_features()?;
 
@@ -197,6 +202,7 @@ impl VirtioDevice for Net {
     }
 
     fn unrealize(&mut self) -> Result<()> {
+        MigrationManager::del_blocker(&self.net_cfg.id);
         Ok(())
     }
 
@@ -15,13 +15,15 @@ use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use machine_manager::config::BlkDevConfig;
+use migration::{MigrationHook, MigrationManager, StateTransfer};
 use util::byte_code::ByteCode;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::client::VhostUserClient;
 use crate::vhost::VhostOps;
 use crate::VhostUser::client::{
-    VhostBackendType, VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_PROTOCOL_F_MQ,
+    VhostBackendType, VhostUserState, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_LOG_SHMFD, VHOST_USER_PROTOCOL_F_MQ,
 };
 use crate::VhostUser::message::VHOST_USER_F_PROTOCOL_FEATURES;
 use crate::{
@@ -109,20 +111,18 @@ impl VirtioDevice for Block {
     }
 
     fn init_config_features(&mut self) -> Result<()> {
-        let locked_client = self.client.as_ref().unwrap().lock().unwrap();
+        let mut locked_client = self.client.as_ref().unwrap().lock().unwrap();
         let features = locked_client
             .get_features()
             .with_context(|| "Failed to get features for vhost-user blk")?;
 
         if virtio_has_feature(features, VHOST_USER_F_PROTOCOL_FEATURES) {
+            let supported_protocol_features = 1 << VHOST_USER_PROTOCOL_F_MQ
+                | 1 << VHOST_USER_PROTOCOL_F_LOG_SHMFD
+                | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
             let protocol_features = locked_client
-                .get_protocol_features()
-                .with_context(|| "Failed to get protocol features for vhost-user blk")?;
-            let supported_protocol_features =
-                1 << VHOST_USER_PROTOCOL_F_MQ | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
-            locked_client
-                .set_protocol_features(supported_protocol_features & protocol_features)
-                .with_context(|| "Failed to set protocol features for vhost-user blk")?;
+                .negotiate_protocol_features(supported_protocol_features)
+                .with_context(|| "Failed to negotiate protocol features for vhost-user blk")?;
 
             if virtio_has_feature(protocol_features, VHOST_USER_PROTOCOL_F_CONFIG as u32) {
                 let config = locked_client
@@ -159,6 +159,7 @@ impl VirtioDevice for Block {
         } else {
             bail!("Bad spdk feature: {:#b}", features);
         }
+        locked_client.check_migration_blocker(features, &self.blk_cfg.id);
         drop(locked_client);
 
         self.base.device_features = 1_u64 << VIRTIO_F_VERSION_1
@@ -233,6 +234,11 @@ impl VirtioDevice for Block {
     fn unrealize(&mut self) -> Result<()> {
         self.delete_event()?;
         self.client = None;
+        MigrationManager::unregister_device_instance(
+            VhostUserState::descriptor(),
+            &self.blk_cfg.id,
+        );
+        MigrationManager::del_blocker(&self.blk_cfg.id);
         Ok(())
     }
 
@@ -245,3 +251,61 @@ impl VirtioDevice for Block {
         Ok(())
     }
 }
+
+impl StateTransfer for Block {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user blk")?
+            .lock()
+            .unwrap()
+            .get_state_vec()
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user blk")?
+            .lock()
+            .unwrap()
+            .set_state(state)
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        MigrationManager::get_desc_alias(&VhostUserState::descriptor().name).unwrap_or(!0)
+    }
+}
+
+impl MigrationHook for Block {
+    fn restart_backend(&mut self) -> Result<()> {
+        match &self.client {
+            Some(client) => client.lock().unwrap().restart_vrings(),
+            None => Ok(()),
+        }
+    }
+
+    fn start_dirty_log(&mut self) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user blk")?
+            .lock()
+            .unwrap()
+            .start_dirty_log()
+    }
+
+    fn stop_dirty_log(&mut self) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user blk")?
+            .lock()
+            .unwrap()
+            .stop_dirty_log()
+    }
+
+    fn sync_dirty_log(&mut self) -> Result<()> {
+        if let Some(client) = &self.client {
+            client.lock().unwrap().sync_dirty_log();
+        }
+        Ok(())
+    }
+}
@@ -10,11 +10,13 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::ffi::CString;
 use std::fs::File;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::rc::Rc;
-use std::slice::from_raw_parts;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -26,6 +28,12 @@ use address_space::{
 };
 use log::{error, info, warn};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper, EventLoop};
+use migration::{
+    build_var_state, migration::Migratable, split_var_state, DeviceStateDesc, FieldDesc,
+    MigrationManager,
+};
+use migration_derive::{ByteCode, Desc};
+use util::byte_code::ByteCode;
 use util::loop_context::{
     gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
@@ -33,8 +41,8 @@ use util::unix::do_mmap;
 
 use super::super::VhostOps;
 use super::message::{
-    RegionMemInfo, VhostUserHdrFlag, VhostUserMemContext, VhostUserMemHdr, VhostUserMsgHdr,
-    VhostUserMsgReq, VhostUserVringAddr, VhostUserVringState,
+    RegionMemInfo, VhostUserHdrFlag, VhostUserLog, VhostUserMemContext, VhostUserMemHdr,
+    VhostUserMsgHdr, VhostUserMsgReq, VhostUserVringAddr, VhostUserVringState,
 };
 use super::sock::VhostUserSock;
 use crate::device::block::VirtioBlkConfig;
@@ -43,10 +51,18 @@ use crate::{virtio_has_feature, Queue, QueueConfig};
 
 /// Vhost supports multiple queue
 pub const VHOST_USER_PROTOCOL_F_MQ: u8 = 0;
+/// Vhost supports logging dirty pages in shared memory set by `VHOST_USER_SET_LOG_BASE` msg.
+pub const VHOST_USER_PROTOCOL_F_LOG_SHMFD: u8 = 1;
 /// Vhost supports `VHOST_USER_SET_CONFIG` and `VHOST_USER_GET_CONFIG` msg.
 pub const VHOST_USER_PROTOCOL_F_CONFIG: u8 = 9;
 /// Vhost supports `VHOST_USER_SET_INFLIGHT_FD` and `VHOST_USER_GET_INFLIGHT_FD` msg.
 pub const VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD: u8 = 12;
+/// Vhost logs all the writes to guest memory.
+pub const VHOST_F_LOG_ALL: u32 = 26;
+/// Vhost logs the writes to used ring of the vring.
+const VHOST_VRING_F_LOG: u32 = 1 << 0;
+/// Each bit of the dirty log represents a page of this size.
+const VHOST_LOG_PAGE: u64 = 0x1000;
 
 struct ClientInternal {
     // Used to send requests to the vhost user backend in userspace.
@@ -123,6 +139,10 @@ fn vhost_user_reconnect(client: &Arc<Mutex<VhostUserClient>>) {
     if let Err(e) = VhostUserClient::add_event(client) {
         error!("Failed to update event for client sock, {:?}", e);
     }
+    // Vrings stopped for migration are activated when restarting them.
+    if client.lock().unwrap().vrings_stopped {
+        return;
+    }
 
     if let Err(e) = client.lock().unwrap().activate_vhost_user() {
         error!("Failed to reactivate vhost-user net, {:?}", e);
@@ -344,6 +364,82 @@ pub struct VhostInflight {
     pub inner: VhostUserInflight,
 }
 
+/// Shared memory for vhost user backend to log the dirty pages of guest memory.
+struct VhostUserDirtyLog {
+    file: File,
+    addr: u64,
+    size: u64,
+}
+
+impl VhostUserDirtyLog {
+    /// Create dirty log which covers guest memory below `mem_end`.
+    fn new(mem_end: u64) -> Result<Self> {
+        let pages = (mem_end + VHOST_LOG_PAGE - 1) / VHOST_LOG_PAGE;
+        // The log is accessed by u64.
+        let size = (pages + 63) / 64 * size_of::<u64>() as u64;
+
+        let name = CString::new("vhost_user_dirty_log")?;
+        // SAFETY: name is a valid C string.
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), 0) } as RawFd;
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| "Failed to create memfd for dirty log");
+        }
+        // SAFETY: fd is valid and its ownership is taken by file.
+        let file = unsafe { File::from_raw_fd(fd) };
+        // SAFETY: fd is valid.
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| "Failed to allocate memory for dirty log");
+        }
+        let addr = do_mmap(&Some(&file), size, 0, false, true, false)?;
+
+        Ok(VhostUserDirtyLog { file, addr, size })
+    }
+
+    /// Get and clear the dirty log, return guest physical address of dirty pages.
+    fn get_and_clear_dirty(&self) -> Vec<u64> {
+        // SAFETY: addr and size are from the mapping created in `new`, the
+        // memory is only accessed atomically as it's shared with backend.
+        let map = unsafe {
+            from_raw_parts(
+                self.addr as *const AtomicU64,
+                self.size as usize / size_of::<u64>(),
+            )
+        };
+        let mut pages = Vec::new();
+        for (idx, m) in map.iter().enumerate() {
+            let mut bits = m.swap(0, Ordering::SeqCst);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as u64;
+                pages.push((idx as u64 * 64 + bit) * VHOST_LOG_PAGE);
+                bits &= bits - 1;
+            }
+        }
+        pages
+    }
+}
+
+impl Drop for VhostUserDirtyLog {
+    fn drop(&mut self) {
+        // SAFETY: addr and size are from the mapping created in `new`.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.size as libc::size_t) };
+    }
+}
+
+/// State of vhost user device for migration, it's followed by the inflight
+/// region of backend.
+#[repr(C)]
+#[derive(Clone, Copy, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0", var_size = true)]
+pub struct VhostUserState {
+    /// The number of vrings handled by backend.
+    vring_num: u32,
+    /// Last avail index of vrings got from backend.
+    vring_bases: [u16; 32],
+}
+
 #[derive(PartialEq, Eq)]
 pub enum VhostBackendType {
     TypeNet,
@@ -364,6 +460,16 @@ pub struct VhostUserClient {
     reconnecting: bool,
     inflight: Option<VhostInflight>,
     backend_type: VhostBackendType,
+    /// Protocol features negotiated with backend.
+    protocol_features: u64,
+    /// Log of dirty pages written by backend during migration.
+    dirty_log: Option<VhostUserDirtyLog>,
+    /// Vring bases used instead of avail index of vrings when activating.
+    vring_bases: Vec<u16>,
+    /// Inflight region restored from migration.
+    migrated_inflight: Vec<u8>,
+    /// Vrings are stopped by saving state, and wait for being restarted.
+    vrings_stopped: bool,
 }
 
 impl VhostUserClient {
@@ -399,6 +505,11 @@ impl VhostUserClient {
             reconnecting: false,
             inflight: None,
             backend_type,
+            protocol_features: 0,
+            dirty_log: None,
+            vring_bases: Vec::new(),
+            vrings_stopped: false,
+            migrated_inflight: Vec::new(),
         })
     }
 
@@ -445,10 +556,25 @@ impl VhostUserClient {
                     &Some(file.as_ref()),
                     vhost_user_inflight.mmap_size,
                     vhost_user_inflight.mmap_offset,
-                    true,
+                    false,
                     true,
                     false,
                 )?;
+                if !self.migrated_inflight.is_empty() {
+                    if self.migrated_inflight.len() as u64 != vhost_user_inflight.mmap_size {
+                        bail!(
+                            "Migrated inflight size {} mismatches with backend {}",
+                            self.migrated_inflight.len(),
+                            vhost_user_inflight.mmap_size
+                        );
+                    }
+                    // SAFETY: hva is mapped above with mmap_size.
+                    unsafe {
+                        from_raw_parts_mut(hva as *mut u8, self.migrated_inflight.len())
+                            .copy_from_slice(&self.migrated_inflight);
+                    }
+                    self.migrated_inflight.clear();
+                }
                 let inflight = VhostInflight {
                     file,
                     addr: hva,
@@ -472,12 +598,17 @@ impl VhostUserClient {
         self.set_owner()
             .with_context(|| "Failed to set owner for vhost-user")?;
 
-        self.set_features(self.features)
+        self.set_features(self.acked_features())
             .with_context(|| "Failed to set features for vhost-user")?;
 
         self.set_mem_table()
             .with_context(|| "Failed to set mem table for vhost-user")?;
 
+        if let Some(log) = &self.dirty_log {
+            self.set_log_base(log)
+                .with_context(|| "Failed to set log base for vhost-user")?;
+        }
+
         let queue_size = self
             .queues
             .first()
@@ -504,14 +635,17 @@ impl VhostUserClient {
             let queue = queue_mutex.lock().unwrap();
             let queue_config = queue.vring.get_queue_config();
 
-            self.set_vring_addr(&queue_config, queue_index, 0)
+            self.set_vring_addr(&queue_config, queue_index, self.vring_flags())
                 .with_context(|| {
                     format!(
                         "Failed to set vring addr for vhost-user, index: {}",
                         queue_index,
                     )
                 })?;
-            let last_avail_idx = queue.vring.get_avail_idx(&self.mem_space)?;
+            let last_avail_idx = match self.vring_bases.get(queue_index) {
+                Some(base) => *base,
+                None => queue.vring.get_avail_idx(&self.mem_space)?,
+            };
             self.set_vring_base(queue_index, last_avail_idx)
                 .with_context(|| {
                     format!(
@@ -534,6 +668,8 @@ impl VhostUserClient {
                     )
                 })?;
         }
+        self.vring_bases.clear();
+        self.vrings_stopped = false;
 
         for (queue_index, queue) in self.queues.iter().enumerate() {
             let enabled = queue.lock().unwrap().is_enabled();
@@ -560,6 +696,206 @@ impl VhostUserClient {
         self.queue_evts.clear();
         self.call_events.clear();
         self.queues.clear();
+        self.vrings_stopped = false;
+
+        Ok(())
+    }
+
+    /// Features acked to backend, `VHOST_F_LOG_ALL` is added when logging dirty pages.
+    fn acked_features(&self) -> u64 {
+        match self.dirty_log {
+            Some(_) => self.features | 1 << VHOST_F_LOG_ALL,
+            None => self.features,
+        }
+    }
+
+    /// Flags of vring address, used ring is logged when logging dirty pages.
+    fn vring_flags(&self) -> u32 {
+        match self.dirty_log {
+            Some(_) => VHOST_VRING_F_LOG,
+            None => 0,
+        }
+    }
+
+    /// Block migration of device if backend can't log dirty pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - Features got from backend.
+    /// * `id` - The unique id for device.
+    pub fn check_migration_blocker(&self, features: u64, id: &str) {
+        if !virtio_has_feature(features, VHOST_F_LOG_ALL)
+            || !virtio_has_feature(
+                self.protocol_features,
+                VHOST_USER_PROTOCOL_F_LOG_SHMFD as u32,
+            )
+        {
+            MigrationManager::add_blocker(
+                id,
+                "vhost-user backend doesn't support logging dirty pages",
+            );
+        }
+    }
+
+    /// Negotiate protocol features with backend, return the features both supported.
+    ///
+    /// # Arguments
+    ///
+    /// * `supported` - Protocol features supported by device.
+    pub fn negotiate_protocol_features(&mut self, supported: u64) -> Result<u64> {
+        let protocol_features = self
+            .get_protocol_features()
+            .with_context(|| "Failed to get protocol features")?;
+        self.set_protocol_features(supported & protocol_features)
+            .with_context(|| "Failed to set protocol features")?;
+        Ok(protocol_features)
+    }
+
+    /// Enable or disable dirty log of backend for the activated vrings.
+    fn update_dirty_log(&self) -> Result<()> {
+        if self.queues.is_empty() {
+            return Ok(());
+        }
+        if let Some(log) = &self.dirty_log {
+            self.set_log_base(log)
+                .with_context(|| "Failed to set log base for vhost-user")?;
+        }
+        self.set_features(self.acked_features())
+            .with_context(|| "Failed to set features for vhost-user")?;
+        for (queue_index, queue_mutex) in self.queues.iter().enumerate() {
+            let queue_config = queue_mutex.lock().unwrap().vring.get_queue_config();
+            self.set_vring_addr(&queue_config, queue_index, self.vring_flags())
+                .with_context(|| {
+                    format!(
+                        "Failed to set vring addr for vhost-user, index: {}",
+                        queue_index,
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Start logging the dirty pages written by backend.
+    pub fn start_dirty_log(&mut self) -> Result<()> {
+        let mem_end = self
+            .mem_info
+            .regions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|info| info.region.guest_phys_addr + info.region.memory_size)
+            .max()
+            .unwrap_or(0);
+        self.dirty_log = Some(VhostUserDirtyLog::new(mem_end)?);
+        self.update_dirty_log()
+    }
+
+    /// Stop logging the dirty pages written by backend.
+    pub fn stop_dirty_log(&mut self) -> Result<()> {
+        if self.dirty_log.take().is_none() {
+            return Ok(());
+        }
+        self.update_dirty_log()
+    }
+
+    /// Mark the dirty pages logged by backend to vmm dirty bitmaps.
+    pub fn sync_dirty_log(&self) {
+        if let Some(log) = &self.dirty_log {
+            for gpa in log.get_and_clear_dirty() {
+                if let Some(hva) = self.mem_info.addr_to_host(GuestAddress(gpa)) {
+                    MigrationManager::mark_dirty_log(hva, VHOST_LOG_PAGE);
+                }
+            }
+        }
+    }
+
+    /// Get state of vrings and inflight region from backend. Vrings are
+    /// stopped by getting vring base, and keep stopped until `restart_vrings`,
+    /// otherwise backend may consume requests after the saved vring base.
+    pub fn get_state_vec(&mut self) -> Result<Vec<u8>> {
+        let mut state = VhostUserState::default();
+        if self.queues.len() > state.vring_bases.len() {
+            bail!("Too many vrings {} to migrate", self.queues.len());
+        }
+        if self.queues.is_empty() {
+            return Ok(build_var_state(state.as_bytes(), &[]));
+        }
+
+        state.vring_num = self.queues.len() as u32;
+        for queue_index in 0..self.queues.len() {
+            state.vring_bases[queue_index] = self
+                .get_vring_base(queue_index)
+                .with_context(|| format!("Failed to get vring base, index: {}", queue_index))?;
+        }
+        let inflight = match &self.inflight {
+            // SAFETY: addr is mapped with mmap_size in `set_inflight`.
+            Some(inflight) => unsafe {
+                from_raw_parts(
+                    inflight.addr as *const u8,
+                    inflight.inner.mmap_size as usize,
+                )
+            }
+            .to_vec(),
+            None => Vec::new(),
+        };
+
+        self.vring_bases = state.vring_bases[..self.queues.len()].to_vec();
+        self.vrings_stopped = true;
+
+        Ok(build_var_state(state.as_bytes(), &inflight))
+    }
+
+    /// Restart vrings stopped by `get_state_vec`, used if VM keeps running
+    /// after snapshot, or migration is canceled or failed.
+    pub fn restart_vrings(&mut self) -> Result<()> {
+        if !self.vrings_stopped {
+            return Ok(());
+        }
+        if self.reconnecting {
+            // Vrings are activated after reconnecting.
+            self.vrings_stopped = false;
+            return Ok(());
+        }
+        self.activate_vhost_user()
+            .with_context(|| "Failed to restart vhost-user")
+    }
+
+    /// Set state of vrings and inflight region, which are sent to backend
+    /// when activating.
+    pub fn set_state(&mut self, state: &[u8]) -> Result<()> {
+        let (state, inflight) = split_var_state(state, size_of::<VhostUserState>())?;
+        let state =
+            VhostUserState::from_bytes(state).with_context(|| "Failed to get vhost-user state")?;
+        if state.vring_num as usize > state.vring_bases.len() {
+            bail!("Invalid vring num {} of vhost-user state", state.vring_num);
+        }
+        self.vring_bases = state.vring_bases[..state.vring_num as usize].to_vec();
+        self.migrated_inflight = inflight.to_vec();
+        Ok(())
+    }
+
+    /// Send the dirty log to backend.
+    fn set_log_base(&self, log: &VhostUserDirtyLog) -> Result<()> {
+        let request = VhostUserMsgReq::SetLogBase as u32;
+        let hdr = VhostUserMsgHdr::new(request, 0, size_of::<VhostUserLog>() as u32);
+        let payload_opt: Option<&[u8]> = None;
+        let log_region = VhostUserLog {
+            mmap_size: log.size,
+            mmap_offset: 0,
+        };
+        let client = self.client.lock().unwrap();
+        client
+            .sock
+            .send_msg(
+                Some(&hdr),
+                Some(&log_region),
+                payload_opt,
+                &[log.file.as_raw_fd()],
+            )
+            .with_context(|| "Failed to send msg for setting log base")?;
+        client
+            .wait_ack_msg::<()>(request)
+            .with_context(|| "Failed to wait ack msg for setting log base")?;
 
         Ok(())
     }
@@ -608,8 +944,10 @@ impl VhostUserClient {
     }
 
     /// Set protocol features to vhost.
-    pub fn set_protocol_features(&self, features: u64) -> Result<()> {
-        self.set_value(VhostUserMsgReq::SetProtocolFeatures, features)
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.set_value(VhostUserMsgReq::SetProtocolFeatures, features)?;
+        self.protocol_features = features;
+        Ok(())
     }
 
     /// Get virtio blk config from vhost.
@@ -858,7 +1196,7 @@ impl VhostOps for VhostUserClient {
             desc_user_addr,
             used_user_addr,
             avail_user_addr,
-            log_guest_addr: 0_u64,
+            log_guest_addr: queue.used_ring.raw_value(),
         };
         self.client
             .lock()
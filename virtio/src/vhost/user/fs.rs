@@ -28,6 +28,7 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 use address_space::AddressSpace;
 use machine_manager::config::{FsConfig, MAX_TAG_LENGTH};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use migration::{MigrationHook, MigrationManager, StateTransfer};
 use util::byte_code::ByteCode;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
@@ -35,8 +36,13 @@ use util::loop_context::{
 
 use super::super::super::{VirtioDevice, VIRTIO_TYPE_FS};
 use super::super::{VhostNotify, VhostOps};
-use super::{VhostBackendType, VhostUserClient};
-use crate::{read_config_default, VirtioBase, VirtioInterrupt, VirtioInterruptType};
+use super::{
+    VhostBackendType, VhostUserClient, VhostUserState, VHOST_USER_F_PROTOCOL_FEATURES,
+    VHOST_USER_PROTOCOL_F_LOG_SHMFD,
+};
+use crate::{
+    read_config_default, virtio_has_feature, VirtioBase, VirtioInterrupt, VirtioInterruptType,
+};
 
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
@@ -165,12 +171,16 @@ impl VirtioDevice for Fs {
         self.config_space.tag[..tag_bytes_vec.len()].copy_from_slice(tag_bytes_vec.as_slice());
         self.config_space.num_request_queues = VIRTIO_FS_REQ_QUEUES_NUM as u32;
 
-        let client = self.client.as_ref().unwrap();
-        self.base.device_features = client
-            .lock()
-            .unwrap()
+        let mut locked_client = self.client.as_ref().unwrap().lock().unwrap();
+        self.base.device_features = locked_client
             .get_features()
             .with_context(|| "Failed to get features for virtio fs")?;
+        if virtio_has_feature(self.base.device_features, VHOST_USER_F_PROTOCOL_FEATURES) {
+            locked_client
+                .negotiate_protocol_features(1 << VHOST_USER_PROTOCOL_F_LOG_SHMFD)
+                .with_context(|| "Failed to negotiate protocol features for virtio fs")?;
+        }
+        locked_client.check_migration_blocker(self.base.device_features, &self.fs_cfg.id);
 
         Ok(())
     }
@@ -258,3 +268,61 @@ impl VirtioDevice for Fs {
         self.realize()
     }
 }
+
+impl StateTransfer for Fs {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for virtio fs")?
+            .lock()
+            .unwrap()
+            .get_state_vec()
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for virtio fs")?
+            .lock()
+            .unwrap()
+            .set_state(state)
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        MigrationManager::get_desc_alias(&VhostUserState::descriptor().name).unwrap_or(!0)
+    }
+}
+
+impl MigrationHook for Fs {
+    fn restart_backend(&mut self) -> Result<()> {
+        match &self.client {
+            Some(client) => client.lock().unwrap().restart_vrings(),
+            None => Ok(()),
+        }
+    }
+
+    fn start_dirty_log(&mut self) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for virtio fs")?
+            .lock()
+            .unwrap()
+            .start_dirty_log()
+    }
+
+    fn stop_dirty_log(&mut self) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for virtio fs")?
+            .lock()
+            .unwrap()
+            .stop_dirty_log()
+    }
+
+    fn sync_dirty_log(&mut self) -> Result<()> {
+        if let Some(client) = &self.client {
+            client.lock().unwrap().sync_dirty_log();
+        }
+        Ok(())
+    }
+}
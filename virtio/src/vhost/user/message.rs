@@ -284,3 +284,13 @@ pub struct VhostUserVringAddr {
     /// Guest address for logging.
     pub log_guest_addr: u64,
 }
+
+/// The shared memory region for vhost user backend to log dirty pages.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VhostUserLog {
+    /// Size of the shared memory region.
+    pub mmap_size: u64,
+    /// Offset from the start of the supplied file descriptor.
+    pub mmap_offset: u64,
+}
@@ -16,12 +16,16 @@ use std::sync::{Arc, Mutex};
 use address_space::AddressSpace;
 use machine_manager::config::NetworkInterfaceConfig;
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use migration::{MigrationHook, MigrationManager, StateTransfer};
 use util::byte_code::ByteCode;
 use util::loop_context::EventNotifierHelper;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::super::VhostOps;
-use super::{VhostBackendType, VhostUserClient};
+use super::{
+    VhostBackendType, VhostUserClient, VhostUserState, VHOST_USER_F_PROTOCOL_FEATURES,
+    VHOST_USER_PROTOCOL_F_LOG_SHMFD,
+};
 use crate::{
     device::net::{build_device_config_space, CtrlInfo, MAC_ADDR_LEN},
     read_config_default, virtio_has_feature, CtrlVirtio, NetCtrlHandler, VirtioBase, VirtioDevice,
@@ -136,12 +140,17 @@ impl VirtioDevice for Net {
     }
 
     fn init_config_features(&mut self) -> Result<()> {
-        let client = self.client.as_ref().unwrap();
-        self.base.device_features = client
-            .lock()
-            .unwrap()
+        let mut locked_client = self.client.as_ref().unwrap().lock().unwrap();
+        self.base.device_features = locked_client
             .get_features()
             .with_context(|| "Failed to get features for vhost-user net")?;
+        if virtio_has_feature(self.base.device_features, VHOST_USER_F_PROTOCOL_FEATURES) {
+            locked_client
+                .negotiate_protocol_features(1 << VHOST_USER_PROTOCOL_F_LOG_SHMFD)
+                .with_context(|| "Failed to negotiate protocol features for vhost-user net")?;
+        }
+        locked_client.check_migration_blocker(self.base.device_features, &self.net_cfg.id);
+        drop(locked_client);
 
         let features = 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_NET_F_GUEST_CSUM
@@ -271,6 +280,11 @@ impl VirtioDevice for Net {
     fn unrealize(&mut self) -> Result<()> {
         self.delete_event()?;
         self.client = None;
+        MigrationManager::unregister_device_instance(
+            VhostUserState::descriptor(),
+            &self.net_cfg.id,
+        );
+        MigrationManager::del_blocker(&self.net_cfg.id);
 
         Ok(())
     }
@@ -279,3 +293,61 @@ impl VirtioDevice for Net {
         virtio_has_feature(self.base.device_features, VIRTIO_NET_F_CTRL_VQ)
     }
 }
+
+impl StateTransfer for Net {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user net")?
+            .lock()
+            .unwrap()
+            .get_state_vec()
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user net")?
+            .lock()
+            .unwrap()
+            .set_state(state)
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        MigrationManager::get_desc_alias(&VhostUserState::descriptor().name).unwrap_or(!0)
+    }
+}
+
+impl MigrationHook for Net {
+    fn restart_backend(&mut self) -> Result<()> {
+        match &self.client {
+            Some(client) => client.lock().unwrap().restart_vrings(),
+            None => Ok(()),
+        }
+    }
+
+    fn start_dirty_log(&mut self) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user net")?
+            .lock()
+            .unwrap()
+            .start_dirty_log()
+    }
+
+    fn stop_dirty_log(&mut self) -> Result<()> {
+        self.client
+            .as_ref()
+            .with_context(|| "Failed to get client for vhost-user net")?
+            .lock()
+            .unwrap()
+            .stop_dirty_log()
+    }
+
+    fn sync_dirty_log(&mut self) -> Result<()> {
+        if let Some(client) = &self.client {
+            client.lock().unwrap().sync_dirty_log();
+        }
+        Ok(())
+    }
+}